@@ -0,0 +1,319 @@
+use crate::geoip::CountryAcl;
+use crate::ip::{IpRange, IpRangeVec};
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_server::server::Protocol;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Listener of the server, named like its command line option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listener {
+  Udp,
+  Tls,
+  H2,
+}
+
+impl Listener {
+  /// Listener receiving the requests of a protocol.
+  pub fn from_protocol(protocol: Protocol) -> Option<Self> {
+    match protocol {
+      // TCP is where the clients of the UDP listener retry the truncated responses.
+      Protocol::Udp | Protocol::Tcp => Some(Listener::Udp),
+      Protocol::Tls => Some(Listener::Tls),
+      Protocol::Https => Some(Listener::H2),
+      _ => None,
+    }
+  }
+}
+
+impl FromStr for Listener {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "udp" => Ok(Listener::Udp),
+      "tls" => Ok(Listener::Tls),
+      "h2" => Ok(Listener::H2),
+      _ => bail!("unknown listener `{s}`, expected udp, tls or h2"),
+    }
+  }
+}
+
+impl fmt::Display for Listener {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Listener::Udp => write!(f, "udp"),
+      Listener::Tls => write!(f, "tls"),
+      Listener::H2 => write!(f, "h2"),
+    }
+  }
+}
+
+/// What is done with the requests of denied clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DenyAction {
+  /// Do not answer, the clients time out.
+  #[default]
+  Drop,
+  /// Answer REFUSED right away.
+  Refused,
+}
+
+impl FromStr for DenyAction {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "drop" => Ok(DenyAction::Drop),
+      "refused" => Ok(DenyAction::Refused),
+      _ => bail!("unknown deny action `{s}`, expected drop or refused"),
+    }
+  }
+}
+
+impl fmt::Display for DenyAction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DenyAction::Drop => write!(f, "drop"),
+      DenyAction::Refused => write!(f, "refused"),
+    }
+  }
+}
+
+impl Serialize for DenyAction {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for DenyAction {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    DenyAction::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Networks allowed to query the server. Without allowed networks every client is allowed, denied
+/// networks take precedence over the allowed ones.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+  allow: Option<IpRangeVec>,
+  deny: Option<IpRangeVec>,
+}
+
+impl Acl {
+  pub fn new(allow: Vec<IpRange>, deny: Vec<IpRange>) -> Self {
+    Self {
+      allow: (!allow.is_empty()).then(|| IpRangeVec::new(allow)),
+      deny: (!deny.is_empty()).then(|| IpRangeVec::new(deny)),
+    }
+  }
+
+  pub fn allows(&self, ip: IpAddr) -> bool {
+    self.allow.as_ref().is_none_or(|allow| allow.contains(ip))
+      && !self.deny.as_ref().is_some_and(|deny| deny.contains(ip))
+  }
+
+  /// This ACL where the unset lists are taken from `default`.
+  fn or(&self, default: &Acl) -> Acl {
+    Acl {
+      allow: self.allow.clone().or_else(|| default.allow.clone()),
+      deny: self.deny.clone().or_else(|| default.deny.clone()),
+    }
+  }
+}
+
+/// ACL of the server and the ACLs replacing it on some listeners, then the countries allowed on
+/// every listener.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+  default: Acl,
+  listeners: Vec<(Listener, Acl)>,
+  pub countries: Option<CountryAcl>,
+  pub deny_action: DenyAction,
+}
+
+impl Access {
+  pub fn new(default: Acl) -> Self {
+    Self {
+      default,
+      listeners: vec![],
+      countries: None,
+      deny_action: DenyAction::default(),
+    }
+  }
+
+  /// Use `acl` for the requests of `listener`, its unset lists are inherited from the server ACL.
+  pub fn add_listener(&mut self, listener: Listener, acl: Acl) {
+    let acl = acl.or(&self.default);
+    self.listeners.retain(|(other, _)| *other != listener);
+    self.listeners.push((listener, acl));
+  }
+
+  pub fn allows(&self, protocol: Protocol, ip: IpAddr) -> bool {
+    let listener = Listener::from_protocol(protocol);
+    self
+      .listeners
+      .iter()
+      .find(|(other, _)| Some(*other) == listener)
+      .map_or(&self.default, |(_, acl)| acl)
+      .allows(ip)
+      && self
+        .countries
+        .as_ref()
+        .is_none_or(|countries| countries.allows(ip))
+  }
+}
+
+/// ACL of a listener as written on the command line:
+/// `listener:allow=cidr[,cidr...][;deny=cidr[,cidr...]]`, at least one of the lists is required.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerAcl {
+  pub listener: Listener,
+  pub allow: Vec<String>,
+  pub deny: Vec<String>,
+}
+
+impl ListenerAcl {
+  pub fn acl(&self) -> Result<Acl> {
+    Ok(Acl::new(ip_ranges(&self.allow)?, ip_ranges(&self.deny)?))
+  }
+}
+
+/// Parse the networks of the command line, e.g. `192.168.1.0/24`.
+pub fn ip_ranges(networks: &[String]) -> Result<Vec<IpRange>> {
+  networks
+    .iter()
+    .map(|network| {
+      IpRange::try_from(network.as_str()).with_context(|| format!("invalid IP range {network}"))
+    })
+    .collect()
+}
+
+impl FromStr for ListenerAcl {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (listener, lists) = s
+      .split_once(':')
+      .ok_or_else(|| anyhow!("expected listener:allow=cidr[,cidr...], got `{s}`"))?;
+    let mut acl = ListenerAcl {
+      listener: Listener::from_str(listener.trim())?,
+      allow: vec![],
+      deny: vec![],
+    };
+    for list in lists
+      .split(';')
+      .map(str::trim)
+      .filter(|list| !list.is_empty())
+    {
+      let (key, networks) = list
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected key=cidr[,cidr...], got `{list}`"))?;
+      let networks = networks
+        .split(',')
+        .map(|network| network.trim().to_string())
+        .filter(|network| !network.is_empty());
+      match key.trim() {
+        "allow" => acl.allow.extend(networks),
+        "deny" => acl.deny.extend(networks),
+        key => bail!("unknown listener ACL option `{key}`"),
+      }
+    }
+    if acl.allow.is_empty() && acl.deny.is_empty() {
+      bail!("the ACL of the {} listener has no network", acl.listener);
+    }
+    acl.acl()?;
+    Ok(acl)
+  }
+}
+
+impl fmt::Display for ListenerAcl {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:", self.listener)?;
+    let lists: Vec<String> = [("allow", &self.allow), ("deny", &self.deny)]
+      .into_iter()
+      .filter(|(_, networks)| !networks.is_empty())
+      .map(|(key, networks)| format!("{key}={}", networks.join(",")))
+      .collect();
+    write!(f, "{}", lists.join(";"))
+  }
+}
+
+impl Serialize for ListenerAcl {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for ListenerAcl {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ListenerAcl::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn ranges(networks: &[&str]) -> Vec<IpRange> {
+    networks
+      .iter()
+      .map(|network| IpRange::try_from(*network).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn parse_listener_acl() {
+    let value = "udp:allow=192.168.0.0/16,fd00::/8;deny=192.168.1.66/32";
+    let acl = ListenerAcl::from_str(value).unwrap();
+    assert_eq!(acl.listener, Listener::Udp);
+    assert_eq!(acl.allow, ["192.168.0.0/16", "fd00::/8"]);
+    assert_eq!(acl.deny, ["192.168.1.66/32"]);
+    assert_eq!(acl.to_string(), value);
+    assert_eq!(
+      ListenerAcl::from_str("h2:deny=10.0.0.0/8")
+        .unwrap()
+        .to_string(),
+      "h2:deny=10.0.0.0/8"
+    );
+    assert!(ListenerAcl::from_str("udp").is_err());
+    assert!(ListenerAcl::from_str("udp:").is_err());
+    assert!(ListenerAcl::from_str("quic:allow=10.0.0.0/8").is_err());
+    assert!(ListenerAcl::from_str("udp:allow=10.0.0.300/8").is_err());
+    assert!(ListenerAcl::from_str("udp:only=10.0.0.0/8").is_err());
+  }
+
+  #[test]
+  fn listener_access() {
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+    let mut access = Access::new(Acl::new(
+      ranges(&["192.168.0.0/16"]),
+      ranges(&["192.168.1.66/32"]),
+    ));
+    access.add_listener(Listener::H2, Acl::new(ranges(&["0.0.0.0/0"]), vec![]));
+
+    assert!(access.allows(Protocol::Udp, ip("192.168.1.10")));
+    assert!(!access.allows(Protocol::Udp, ip("192.168.1.66")));
+    assert!(!access.allows(Protocol::Udp, ip("203.0.113.7")));
+    assert!(!access.allows(Protocol::Tls, ip("203.0.113.7")));
+    assert!(access.allows(Protocol::Https, ip("203.0.113.7")));
+    // The denied networks of the server are inherited.
+    assert!(!access.allows(Protocol::Https, ip("192.168.1.66")));
+    assert!(Access::default().allows(Protocol::Udp, ip("203.0.113.7")));
+  }
+
+  #[test]
+  fn parse_deny_action() {
+    assert_eq!(DenyAction::from_str("drop").unwrap(), DenyAction::Drop);
+    assert_eq!(
+      DenyAction::from_str("refused").unwrap(),
+      DenyAction::Refused
+    );
+    assert_eq!(DenyAction::Refused.to_string(), "refused");
+    assert!(DenyAction::from_str("reject").is_err());
+  }
+}
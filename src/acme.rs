@@ -0,0 +1,943 @@
+use crate::client::tls::client_config;
+use crate::tls::CertificateStore;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{
+  engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+  Engine,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hickory_rustls::ServerName;
+use hickory_server::proto::rustls::tls_server::read_cert;
+use ring::{
+  digest::{digest, SHA256},
+  rand::SystemRandom,
+  signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+  },
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+/// Production directory of Let's Encrypt.
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Staging directory of Let's Encrypt, its certificates are not trusted but its rate limits are
+/// higher.
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// The certificate is renewed when it expires within this duration.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+const BAD_NONCE_ATTEMPTS: usize = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_CHALLENGE_REQUEST_SIZE: usize = 4096;
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+const ACCOUNT_KEY: &str = "account.pem";
+const CERTIFICATE: &str = "certificate.pem";
+const PRIVATE_KEY: &str = "private_key.pem";
+/// Domains of the stored certificate, it is ordered again when they change.
+const DOMAINS: &str = "domains";
+
+/// Certificate obtained and renewed from an ACME certificate authority (RFC 8555), like Let's
+/// Encrypt, validating the domains with the HTTP-01 challenge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcmeOptions {
+  /// Domains of the certificate, they must resolve to this server.
+  pub domains: Vec<String>,
+  /// Contact of the account, warned by the certificate authority about expiring certificates.
+  pub email: Option<String>,
+  /// Directory URL of the certificate authority.
+  pub directory: String,
+  /// Where the account key, the certificate and its private key are kept.
+  pub storage: PathBuf,
+  /// Listener of the HTTP-01 challenges, the certificate authority queries port 80.
+  pub http_listen: SocketAddr,
+}
+
+impl AcmeOptions {
+  pub fn new(domains: Vec<String>, storage: impl Into<PathBuf>) -> Self {
+    Self {
+      domains,
+      email: None,
+      directory: LETS_ENCRYPT.to_string(),
+      storage: storage.into(),
+      http_listen: SocketAddr::from(([0, 0, 0, 0], 80)),
+    }
+  }
+}
+
+/// Keys of the HTTP-01 challenges being validated, by token.
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+/// Load the stored certificate into `certificates`, serve the HTTP-01 challenges and keep the
+/// certificate renewed in the background.
+pub async fn start(options: AcmeOptions, certificates: Arc<CertificateStore>) -> Result<()> {
+  if options.domains.is_empty() {
+    bail!("ACME requires at least one domain");
+  }
+  std::fs::create_dir_all(&options.storage)
+    .with_context(|| format!("could not create {}", options.storage.display()))?;
+  let manager = Manager {
+    options,
+    certificates,
+    challenges: Challenges::default(),
+  };
+  if manager.stored_domains_match() {
+    match manager.load() {
+      Ok(()) => info!("Loaded the ACME certificate of {}", manager.domains()),
+      Err(err) => warn!("Could not load the ACME certificate: {:#}", err),
+    }
+  }
+
+  let listener = TcpListener::bind(manager.options.http_listen)
+    .await
+    .with_context(|| {
+      format!(
+        "could not bind to TCP socket {}",
+        manager.options.http_listen
+      )
+    })?;
+  info!(
+    "Will answer ACME HTTP-01 challenges on http://{}",
+    manager.options.http_listen
+  );
+  serve_challenges(listener, manager.challenges.clone());
+  tokio::spawn(manager.run());
+  Ok(())
+}
+
+struct Manager {
+  options: AcmeOptions,
+  certificates: Arc<CertificateStore>,
+  challenges: Challenges,
+}
+
+impl Manager {
+  async fn run(self) {
+    loop {
+      if self.renewal_due() {
+        info!("Ordering a certificate for {}", self.domains());
+        match self.renew().await {
+          Ok(()) => info!("Obtained a new certificate for {}", self.domains()),
+          Err(err) => {
+            error!(
+              "Could not obtain a certificate for {}: {:#}",
+              self.domains(),
+              err
+            );
+            tokio::time::sleep(RETRY_INTERVAL).await;
+            continue;
+          }
+        }
+      }
+      tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+  }
+
+  /// Whether there is no valid certificate of the domains for the next `RENEW_BEFORE`.
+  fn renewal_due(&self) -> bool {
+    let expires_soon = self.expiry().is_none_or(|expiry| {
+      expiry
+        .duration_since(SystemTime::now())
+        .map_or(true, |left| left < RENEW_BEFORE)
+    });
+    !self.certificates.is_loaded() || !self.stored_domains_match() || expires_soon
+  }
+
+  fn domains(&self) -> String {
+    self.options.domains.join(", ")
+  }
+
+  fn path(&self, file: &str) -> PathBuf {
+    self.options.storage.join(file)
+  }
+
+  fn stored_domains_match(&self) -> bool {
+    std::fs::read_to_string(self.path(DOMAINS))
+      .is_ok_and(|domains| domains.lines().eq(self.options.domains.iter()))
+  }
+
+  fn load(&self) -> Result<()> {
+    self
+      .certificates
+      .load(&self.path(CERTIFICATE), &self.path(PRIVATE_KEY))
+  }
+
+  /// Expiry of the stored certificate.
+  fn expiry(&self) -> Option<SystemTime> {
+    let certs = read_cert(&self.path(CERTIFICATE)).ok()?;
+    not_after(&certs.first()?.0)
+  }
+
+  async fn renew(&self) -> Result<()> {
+    let account_key = self.account_key()?;
+    let mut client = Client::new(&self.options.directory, account_key).await?;
+    client.register(self.options.email.as_deref()).await?;
+    let (certificate, private_key) = client
+      .order(&self.options.domains, &self.challenges)
+      .await?;
+
+    write_private(&self.path(PRIVATE_KEY), &private_key)?;
+    std::fs::write(self.path(CERTIFICATE), certificate)
+      .with_context(|| format!("could not write {}", self.path(CERTIFICATE).display()))?;
+    std::fs::write(self.path(DOMAINS), self.options.domains.join("\n"))
+      .with_context(|| format!("could not write {}", self.path(DOMAINS).display()))?;
+    self.load()
+  }
+
+  /// Key of the ACME account, created on the first order.
+  fn account_key(&self) -> Result<EcdsaKeyPair> {
+    let path = self.path(ACCOUNT_KEY);
+    let pkcs8 = match std::fs::read_to_string(&path) {
+      Ok(pem) => pem_decode(&pem).with_context(|| format!("invalid key {}", path.display()))?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        let pkcs8 = generate_key()?;
+        write_private(&path, &pem_encode("PRIVATE KEY", &pkcs8))?;
+        pkcs8
+      }
+      Err(err) => return Err(err).with_context(|| format!("could not read {}", path.display())),
+    };
+    EcdsaKeyPair::from_pkcs8(
+      &ECDSA_P256_SHA256_FIXED_SIGNING,
+      &pkcs8,
+      &SystemRandom::new(),
+    )
+    .map_err(|err| anyhow!("invalid key {}: {}", path.display(), err))
+  }
+}
+
+/// Write a file only readable by its owner.
+pub(crate) fn write_private(path: &Path, content: &str) -> Result<()> {
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  let mut file = options
+    .open(path)
+    .with_context(|| format!("could not write {}", path.display()))?;
+  std::io::Write::write_all(&mut file, content.as_bytes())
+    .with_context(|| format!("could not write {}", path.display()))
+}
+
+fn generate_key() -> Result<Vec<u8>> {
+  let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+    .map_err(|err| anyhow!("could not generate a key: {}", err))?;
+  Ok(pkcs8.as_ref().to_vec())
+}
+
+/// Client of an ACME directory, signing its requests with the account key.
+struct Client {
+  directory: Directory,
+  key: EcdsaKeyPair,
+  account: Option<String>,
+  nonce: Option<String>,
+}
+
+struct Directory {
+  new_nonce: Url,
+  new_account: Url,
+  new_order: Url,
+}
+
+impl Client {
+  async fn new(directory: &str, key: EcdsaKeyPair) -> Result<Self> {
+    let response = request("GET", &url_of(directory)?, None).await?;
+    let directory: Value = response.json()?;
+    let endpoint = |name: &str| -> Result<Url> {
+      let url = directory[name]
+        .as_str()
+        .with_context(|| format!("no {name} in the ACME directory"))?;
+      url_of(url)
+    };
+    Ok(Self {
+      directory: Directory {
+        new_nonce: endpoint("newNonce")?,
+        new_account: endpoint("newAccount")?,
+        new_order: endpoint("newOrder")?,
+      },
+      key,
+      account: None,
+      nonce: None,
+    })
+  }
+
+  /// Find or create the account of the key.
+  async fn register(&mut self, email: Option<&str>) -> Result<()> {
+    let mut payload = json!({ "termsOfServiceAgreed": true });
+    if let Some(email) = email {
+      payload["contact"] = json!([format!("mailto:{email}")]);
+    }
+    let url = self.directory.new_account.clone();
+    let response = self.post(&url, Some(&payload)).await?;
+    let account = response
+      .header("location")
+      .context("no account URL in the ACME response")?;
+    self.account = Some(account.to_string());
+    Ok(())
+  }
+
+  /// Order a certificate of `domains`, returns the certificate chain and its private key (PEM).
+  async fn order(
+    &mut self,
+    domains: &[String],
+    challenges: &Challenges,
+  ) -> Result<(String, String)> {
+    let identifiers: Vec<Value> = domains
+      .iter()
+      .map(|domain| json!({ "type": "dns", "value": domain }))
+      .collect();
+    let url = self.directory.new_order.clone();
+    let response = self
+      .post(&url, Some(&json!({ "identifiers": identifiers })))
+      .await?;
+    let order_url = url_of(response.header("location").context("no order URL")?)?;
+    let order: Value = response.json()?;
+
+    for authorization in order["authorizations"].as_array().into_iter().flatten() {
+      let authorization = url_of(authorization.as_str().context("invalid authorization")?)?;
+      self.authorize(&authorization, challenges).await?;
+    }
+
+    let pkcs8 = generate_key()?;
+    let csr = csr(domains, &pkcs8)?;
+    let finalize = url_of(order["finalize"].as_str().context("no finalize URL")?)?;
+    self
+      .post(
+        &finalize,
+        Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+      )
+      .await?;
+    let order = self.poll(&order_url, "order").await?;
+    let certificate = url_of(
+      order["certificate"]
+        .as_str()
+        .context("no certificate URL")?,
+    )?;
+    let response = self.post(&certificate, None).await?;
+    let certificate = String::from_utf8(response.body).context("invalid certificate")?;
+    Ok((certificate, pem_encode("PRIVATE KEY", &pkcs8)))
+  }
+
+  /// Answer the HTTP-01 challenge of an authorization and wait until it is validated.
+  async fn authorize(&mut self, url: &Url, challenges: &Challenges) -> Result<()> {
+    let authorization: Value = self.post(url, None).await?.json()?;
+    let domain = authorization["identifier"]["value"].as_str().unwrap_or("");
+    if authorization["status"] == "valid" {
+      debug!("{} is already validated", domain);
+      return Ok(());
+    }
+    let challenge = authorization["challenges"]
+      .as_array()
+      .into_iter()
+      .flatten()
+      .find(|challenge| challenge["type"] == "http-01")
+      .with_context(|| format!("no HTTP-01 challenge for {domain}"))?;
+    let token = challenge["token"].as_str().context("no challenge token")?;
+    let challenge_url = url_of(challenge["url"].as_str().context("no challenge URL")?)?;
+
+    challenges.lock().unwrap().insert(
+      token.to_string(),
+      key_authorization(token, self.key.public_key().as_ref()),
+    );
+    let result = async {
+      self.post(&challenge_url, Some(&json!({}))).await?;
+      self.poll(url, "authorization").await
+    }
+    .await;
+    challenges.lock().unwrap().remove(token);
+    result.with_context(|| format!("could not validate {domain}"))?;
+    Ok(())
+  }
+
+  /// Wait until the object at `url` is valid.
+  async fn poll(&mut self, url: &Url, kind: &str) -> Result<Value> {
+    for _ in 0..POLL_ATTEMPTS {
+      let object: Value = self.post(url, None).await?.json()?;
+      match object["status"].as_str() {
+        Some("valid") => return Ok(object),
+        Some("invalid") => bail!("{} is invalid: {}", kind, object),
+        _ => tokio::time::sleep(POLL_INTERVAL).await,
+      }
+    }
+    bail!("the {kind} is still not valid")
+  }
+
+  /// Signed request, POST-as-GET when there is no payload.
+  async fn post(&mut self, url: &Url, payload: Option<&Value>) -> Result<Response> {
+    for _ in 0..BAD_NONCE_ATTEMPTS {
+      let nonce = match self.nonce.take() {
+        Some(nonce) => nonce,
+        None => self.new_nonce().await?,
+      };
+      let body = self.sign(url, &nonce, payload)?;
+      let response = request("POST", url, Some(body.to_string().into_bytes())).await?;
+      self.nonce = response.header("replay-nonce").map(str::to_string);
+      if response.status < 400 {
+        return Ok(response);
+      }
+      let problem: Value = response.json().unwrap_or_default();
+      if problem["type"] != "urn:ietf:params:acme:error:badNonce" {
+        bail!(
+          "{} answered {}: {}",
+          url,
+          response.status,
+          problem["detail"].as_str().unwrap_or_default()
+        );
+      }
+    }
+    bail!("{url} kept refusing the nonces")
+  }
+
+  async fn new_nonce(&self) -> Result<String> {
+    let response = request("HEAD", &self.directory.new_nonce, None).await?;
+    response
+      .header("replay-nonce")
+      .map(str::to_string)
+      .context("no nonce in the ACME response")
+  }
+
+  /// Flattened JWS of the request, identified by the account once registered.
+  fn sign(&self, url: &Url, nonce: &str, payload: Option<&Value>) -> Result<Value> {
+    let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url.as_str() });
+    match &self.account {
+      Some(account) => protected["kid"] = json!(account),
+      None => protected["jwk"] = serde_json::from_str(&jwk(self.key.public_key().as_ref()))?,
+    }
+    let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+    let payload = payload
+      .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+      .unwrap_or_default();
+    let signature = self
+      .key
+      .sign(
+        &SystemRandom::new(),
+        format!("{protected}.{payload}").as_bytes(),
+      )
+      .map_err(|err| anyhow!("could not sign the ACME request: {}", err))?;
+    Ok(json!({
+      "protected": protected,
+      "payload": payload,
+      "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+    }))
+  }
+}
+
+fn url_of(url: &str) -> Result<Url> {
+  Url::parse(url).with_context(|| format!("invalid URL {url}"))
+}
+
+/// JSON Web Key of a P-256 public key (uncompressed point), with its members in the order of its
+/// thumbprint (RFC 7638).
+fn jwk(public_key: &[u8]) -> String {
+  let (x, y) = public_key[1..].split_at(32);
+  format!(
+    r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+    URL_SAFE_NO_PAD.encode(x),
+    URL_SAFE_NO_PAD.encode(y)
+  )
+}
+
+/// Content served for the challenge `token`.
+fn key_authorization(token: &str, public_key: &[u8]) -> String {
+  let thumbprint = digest(&SHA256, jwk(public_key).as_bytes());
+  format!("{}.{}", token, URL_SAFE_NO_PAD.encode(thumbprint))
+}
+
+/// PKCS#10 certificate request of `domains` for the P-256 key `pkcs8`.
+fn csr(domains: &[String], pkcs8: &[u8]) -> Result<Vec<u8>> {
+  let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8, &SystemRandom::new())
+    .map_err(|err| anyhow!("invalid key: {}", err))?;
+  let names: Vec<u8> = domains
+    .iter()
+    .flat_map(|domain| der(0x82, domain.as_bytes()))
+    .collect();
+  let extension = [
+    der(0x06, OID_SUBJECT_ALT_NAME),
+    der(0x04, &der(0x30, &names)),
+  ]
+  .concat();
+  let attribute = [
+    der(0x06, OID_EXTENSION_REQUEST),
+    der(0x31, &der(0x30, &der(0x30, &extension))),
+  ]
+  .concat();
+  let common_name = [der(0x06, OID_COMMON_NAME), der(0x0c, domains[0].as_bytes())].concat();
+  let public_key = [
+    der(
+      0x30,
+      &[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_PRIME256V1)].concat(),
+    ),
+    der(0x03, &[&[0], key.public_key().as_ref()].concat()),
+  ]
+  .concat();
+  let info = der(
+    0x30,
+    &[
+      der(0x02, &[0]),
+      der(0x30, &der(0x31, &der(0x30, &common_name))),
+      der(0x30, &public_key),
+      der(0xa0, &der(0x30, &attribute)),
+    ]
+    .concat(),
+  );
+  let signature = key
+    .sign(&SystemRandom::new(), &info)
+    .map_err(|err| anyhow!("could not sign the certificate request: {}", err))?;
+  Ok(der(
+    0x30,
+    &[
+      info,
+      der(0x30, &der(0x06, OID_ECDSA_WITH_SHA256)),
+      der(0x03, &[&[0], signature.as_ref()].concat()),
+    ]
+    .concat(),
+  ))
+}
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// DER element of `tag` holding `content`.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+  let mut element = vec![tag];
+  let length = content.len().to_be_bytes();
+  let length = &length[length
+    .iter()
+    .position(|byte| *byte != 0)
+    .unwrap_or(length.len())..];
+  match content.len() {
+    0..=127 => element.push(content.len() as u8),
+    _ => {
+      element.push(0x80 | length.len() as u8);
+      element.extend_from_slice(length);
+    }
+  }
+  element.extend_from_slice(content);
+  element
+}
+
+/// Split the first DER element of `input`: its tag, its content and what follows.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+  let (&tag, input) = input.split_first()?;
+  let (&first, mut input) = input.split_first()?;
+  let length = match first {
+    0..=127 => first as usize,
+    _ => {
+      let (length, rest) = input.split_at_checked((first & 0x7f) as usize)?;
+      input = rest;
+      length.iter().try_fold(0usize, |length, byte| {
+        length.checked_mul(256)?.checked_add(*byte as usize)
+      })?
+    }
+  };
+  let (content, rest) = input.split_at_checked(length)?;
+  Some((tag, content, rest))
+}
+
+/// End of validity of a DER certificate.
+fn not_after(certificate: &[u8]) -> Option<SystemTime> {
+  let (_, certificate, _) = der_element(certificate)?;
+  let (_, mut tbs, _) = der_element(certificate)?;
+  // Skip the version, when present, the serial number, the signature algorithm and the issuer.
+  let (tag, _, rest) = der_element(tbs)?;
+  if tag == 0xa0 {
+    tbs = rest;
+  }
+  for _ in 0..3 {
+    tbs = der_element(tbs)?.2;
+  }
+  let (_, validity, _) = der_element(tbs)?;
+  // Skip the start of validity.
+  let (_, _, end) = der_element(validity)?;
+  let (tag, time, _) = der_element(end)?;
+  let time = std::str::from_utf8(time).ok()?;
+  let time = match tag {
+    // UTCTime, years from 1950 to 2049.
+    0x17 if time.get(..2)? < "50" => format!("20{time}"),
+    0x17 => format!("19{time}"),
+    0x18 => time.to_string(),
+    _ => return None,
+  };
+  let time = NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").ok()?;
+  Some(DateTime::<Utc>::from_naive_utc_and_offset(time, Utc).into())
+}
+
+pub(crate) fn pem_encode(label: &str, der: &[u8]) -> String {
+  let encoded = STANDARD.encode(der);
+  let mut pem = format!("-----BEGIN {label}-----\n");
+  for line in encoded.as_bytes().chunks(64) {
+    pem.push_str(std::str::from_utf8(line).unwrap());
+    pem.push('\n');
+  }
+  pem.push_str(&format!("-----END {label}-----\n"));
+  pem
+}
+
+pub(crate) fn pem_decode(pem: &str) -> Result<Vec<u8>> {
+  let encoded: String = pem
+    .lines()
+    .filter(|line| !line.starts_with("-----"))
+    .collect();
+  Ok(STANDARD.decode(encoded.trim())?)
+}
+
+struct Response {
+  status: u16,
+  headers: Vec<(String, String)>,
+  body: Vec<u8>,
+}
+
+impl Response {
+  fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(header, _)| header.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  fn json(&self) -> Result<Value> {
+    serde_json::from_slice(&self.body).context("invalid ACME response")
+  }
+}
+
+/// HTTPS/1.1 request, ACME bodies are always JOSE.
+async fn request(method: &str, url: &Url, body: Option<Vec<u8>>) -> Result<Response> {
+  let host = url.host_str().context("no host in the ACME URL")?;
+  let port = url.port_or_known_default().unwrap_or(443);
+  let mut head = format!(
+    "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: dns-server/{}\r\nAccept: */*\r\nConnection: close\r\n",
+    method,
+    &url[url::Position::BeforePath..url::Position::AfterQuery],
+    host,
+    env!("CARGO_PKG_VERSION")
+  );
+  if let Some(body) = &body {
+    head.push_str(&format!(
+      "Content-Type: application/jose+json\r\nContent-Length: {}\r\n",
+      body.len()
+    ));
+  }
+  head.push_str("\r\n");
+  let mut message = head.into_bytes();
+  message.extend(body.unwrap_or_default());
+
+  let exchange = async {
+    let stream = TcpStream::connect((host, port)).await?;
+    let server_name = ServerName::try_from(host)?;
+    let connector = TlsConnector::from(client_config(None, &[])?);
+    let stream = connector.connect(server_name, stream).await?;
+    exchange(stream, &message).await
+  };
+  let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+    .await
+    .map_err(|_| anyhow!("{} timed out", url))?
+    .with_context(|| format!("{method} {url} failed"))?;
+  parse_response(&response, method == "HEAD").with_context(|| format!("{method} {url} failed"))
+}
+
+async fn exchange(
+  mut stream: impl AsyncRead + AsyncWrite + Unpin,
+  message: &[u8],
+) -> Result<Vec<u8>> {
+  stream.write_all(message).await?;
+  let mut response = vec![];
+  match stream.read_to_end(&mut response).await {
+    Ok(_) => Ok(response),
+    // Some servers close the connection without a TLS close_notify.
+    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => {
+      Ok(response)
+    }
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn parse_response(bytes: &[u8], head_only: bool) -> Result<Response> {
+  let end = bytes
+    .windows(4)
+    .position(|window| window == b"\r\n\r\n")
+    .context("incomplete HTTP response")?;
+  let head = String::from_utf8_lossy(&bytes[..end]);
+  let mut lines = head.split("\r\n");
+  let status = lines
+    .next()
+    .and_then(|line| line.split_whitespace().nth(1))
+    .and_then(|status| status.parse().ok())
+    .context("invalid HTTP status line")?;
+  let headers: Vec<(String, String)> = lines
+    .filter_map(|line| line.split_once(':'))
+    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+    .collect();
+  let mut response = Response {
+    status,
+    headers,
+    body: vec![],
+  };
+  if head_only {
+    return Ok(response);
+  }
+  let body = &bytes[end + 4..];
+  let chunked = response
+    .header("transfer-encoding")
+    .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+  let length = response
+    .header("content-length")
+    .and_then(|length| length.parse::<usize>().ok());
+  response.body = match (chunked, length) {
+    (true, _) => dechunk(body)?,
+    (false, Some(length)) => body.get(..length).context("incomplete HTTP body")?.to_vec(),
+    (false, None) => body.to_vec(),
+  };
+  Ok(response)
+}
+
+/// Body of a chunked response.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+  let mut decoded = vec![];
+  loop {
+    let line = body
+      .windows(2)
+      .position(|window| window == b"\r\n")
+      .context("incomplete HTTP chunk")?;
+    let size = String::from_utf8_lossy(&body[..line]);
+    let size = size.split(';').next().unwrap_or_default().trim();
+    let size = usize::from_str_radix(size, 16).context("invalid HTTP chunk size")?;
+    body = &body[line + 2..];
+    if size == 0 {
+      return Ok(decoded);
+    }
+    decoded.extend_from_slice(body.get(..size).context("incomplete HTTP chunk")?);
+    body = body.get(size + 2..).context("incomplete HTTP chunk")?;
+  }
+}
+
+/// Answer the HTTP-01 challenges of the certificate authority.
+fn serve_challenges(listener: TcpListener, challenges: Challenges) {
+  tokio::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, src)) => {
+          let challenges = challenges.clone();
+          tokio::spawn(async move {
+            if let Err(err) = answer_challenge(stream, &challenges).await {
+              debug!("ACME challenge connection of {} closed: {}", src, err);
+            }
+          });
+        }
+        Err(err) => error!("Could not accept ACME challenge connection: {}", err),
+      }
+    }
+  });
+}
+
+async fn answer_challenge(mut stream: TcpStream, challenges: &Challenges) -> Result<()> {
+  let mut buffer = Vec::with_capacity(1024);
+  let head = tokio::time::timeout(REQUEST_TIMEOUT, async {
+    loop {
+      let mut chunk = [0; 1024];
+      let read = stream.read(&mut chunk).await?;
+      buffer.extend_from_slice(&chunk[..read]);
+      if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        return Ok(String::from_utf8_lossy(&buffer[..end]).to_string());
+      }
+      if read == 0 || buffer.len() > MAX_CHALLENGE_REQUEST_SIZE {
+        bail!("incomplete request");
+      }
+    }
+  })
+  .await??;
+
+  let target = head.split_whitespace().nth(1).unwrap_or_default();
+  let key_authorization = target
+    .strip_prefix(CHALLENGE_PATH)
+    .and_then(|token| challenges.lock().unwrap().get(token).cloned());
+  let (status, body) = match key_authorization {
+    Some(key_authorization) => ("200 OK", key_authorization),
+    None => ("404 Not Found", String::new()),
+  };
+  let message = format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body
+  );
+  stream.write_all(message.as_bytes()).await?;
+  stream.shutdown().await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_FIXED};
+
+  fn key() -> EcdsaKeyPair {
+    let pkcs8 = generate_key().unwrap();
+    EcdsaKeyPair::from_pkcs8(
+      &ECDSA_P256_SHA256_FIXED_SIGNING,
+      &pkcs8,
+      &SystemRandom::new(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn der_lengths() {
+    for length in [0, 127, 128, 300, 70000] {
+      let content = vec![7; length];
+      let element = der(0x04, &content);
+      let (tag, parsed, rest) = der_element(&element).unwrap();
+      assert_eq!((tag, parsed, rest), (0x04, content.as_slice(), &[][..]));
+    }
+    assert_eq!(der(0x02, &[0]), [0x02, 0x01, 0x00]);
+    assert_eq!(&der(0x04, &[0; 200])[..3], [0x04, 0x81, 200]);
+    assert!(der_element(&[0x04, 0x05, 0x00]).is_none());
+  }
+
+  #[test]
+  fn certificate_expiry() {
+    let validity = [der(0x17, b"250101000000Z"), der(0x18, b"20260704120000Z")].concat();
+    let tbs = [
+      der(0xa0, &der(0x02, &[2])),
+      der(0x02, &[1]),
+      der(0x30, &[]),
+      der(0x30, &[]),
+      der(0x30, &validity),
+    ]
+    .concat();
+    let certificate = der(0x30, &der(0x30, &tbs));
+    let expected: SystemTime = DateTime::parse_from_rfc3339("2026-07-04T12:00:00Z")
+      .unwrap()
+      .into();
+    assert_eq!(not_after(&certificate), Some(expected));
+    assert_eq!(not_after(&certificate[..20]), None);
+  }
+
+  #[test]
+  fn signed_request() {
+    let mut client = Client {
+      directory: Directory {
+        new_nonce: url_of("https://acme.test/nonce").unwrap(),
+        new_account: url_of("https://acme.test/account").unwrap(),
+        new_order: url_of("https://acme.test/order").unwrap(),
+      },
+      key: key(),
+      account: None,
+      nonce: None,
+    };
+    let url = url_of("https://acme.test/account").unwrap();
+    let decode = |value: &Value| -> Value {
+      serde_json::from_slice(&URL_SAFE_NO_PAD.decode(value.as_str().unwrap()).unwrap()).unwrap()
+    };
+
+    let jws = client.sign(&url, "nonce", Some(&json!({"a": 1}))).unwrap();
+    let protected = decode(&jws["protected"]);
+    assert_eq!(protected["alg"], "ES256");
+    assert_eq!(protected["nonce"], "nonce");
+    assert_eq!(protected["jwk"]["kty"], "EC");
+    assert_eq!(decode(&jws["payload"]), json!({"a": 1}));
+    let message = format!(
+      "{}.{}",
+      jws["protected"].as_str().unwrap(),
+      jws["payload"].as_str().unwrap()
+    );
+    let signature = URL_SAFE_NO_PAD
+      .decode(jws["signature"].as_str().unwrap())
+      .unwrap();
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, client.key.public_key().as_ref())
+      .verify(message.as_bytes(), &signature)
+      .unwrap();
+
+    client.account = Some("https://acme.test/account/1".to_string());
+    let jws = client.sign(&url, "nonce", None).unwrap();
+    let protected = decode(&jws["protected"]);
+    assert_eq!(protected["kid"], "https://acme.test/account/1");
+    assert!(protected.get("jwk").is_none());
+    assert_eq!(jws["payload"], "");
+  }
+
+  #[test]
+  fn certificate_request() {
+    let pkcs8 = generate_key().unwrap();
+    let domains = ["dns.example.com".to_string(), "doh.example.com".to_string()];
+    let request = csr(&domains, &pkcs8).unwrap();
+    let (_, request, _) = der_element(&request).unwrap();
+    let (_, _, rest) = der_element(request).unwrap();
+    let info = &request[..request.len() - rest.len()];
+    let (_, _, rest) = der_element(rest).unwrap();
+    let (tag, signature, _) = der_element(rest).unwrap();
+    assert_eq!(tag, 0x03);
+
+    let key = EcdsaKeyPair::from_pkcs8(
+      &ECDSA_P256_SHA256_ASN1_SIGNING,
+      &pkcs8,
+      &SystemRandom::new(),
+    )
+    .unwrap();
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key.public_key().as_ref())
+      .verify(info, &signature[1..])
+      .unwrap();
+    for domain in domains {
+      let name = der(0x82, domain.as_bytes());
+      assert!(info.windows(name.len()).any(|window| window == name));
+    }
+  }
+
+  #[test]
+  fn http_responses() {
+    let response = parse_response(
+      b"HTTP/1.1 201 Created\r\nLocation: https://acme.test/order/1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n",
+      false,
+    )
+    .unwrap();
+    assert_eq!(response.status, 201);
+    assert_eq!(
+      response.header("location"),
+      Some("https://acme.test/order/1")
+    );
+    assert_eq!(response.json().unwrap(), json!({"a": 1}));
+
+    let response = parse_response(
+      b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}extra",
+      false,
+    )
+    .unwrap();
+    assert_eq!(response.body, b"{}");
+
+    let response = parse_response(
+      b"HTTP/1.1 200 OK\r\nReplay-Nonce: abc\r\nContent-Length: 10\r\n\r\n",
+      true,
+    )
+    .unwrap();
+    assert_eq!(response.header("replay-nonce"), Some("abc"));
+    assert!(parse_response(b"HTTP/1.1 200 OK\r\n", false).is_err());
+  }
+
+  #[test]
+  fn challenge_key_authorization() {
+    let key = key();
+    let jwk = jwk(key.public_key().as_ref());
+    let thumbprint = URL_SAFE_NO_PAD.encode(digest(&SHA256, jwk.as_bytes()));
+    assert_eq!(
+      key_authorization("token", key.public_key().as_ref()),
+      format!("token.{thumbprint}")
+    );
+    assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+  }
+}
@@ -1,51 +1,426 @@
 use crate::{
-  authority::{forge_ip_record, ipv4_to_prefixed_ipv6_records},
+  authority::{forge_ip_record, ipv4_to_prefixed_ipv6_records, SyntheticZone, DEFAULT_BLOCK_TTL},
+  category::Category,
+  control::Control,
+  domain_set::DomainSet,
+  events,
+  filter_list::FilterList,
   ip::IpRangeVec,
+  nat64::Nat64,
+  schedule::ScheduledBlacklist,
+  upstream::Upstreams,
 };
-use hickory_resolver::{config::NameServerConfigGroup, Name};
 use hickory_server::{
   authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
   proto::{
     op::{Query, ResponseCode},
-    rr::{LowerName, RecordType},
+    rr::{LowerName, Name, RData, Record, RecordType},
   },
+  resolver::{error::ResolveErrorKind, lookup::Lookup as ResolverLookup},
   server::RequestInfo,
-  store::forwarder::{ForwardAuthority, ForwardConfig, ForwardLookup},
+  store::forwarder::ForwardLookup,
 };
-use std::collections::HashSet;
-use std::net::Ipv4Addr;
-use tracing::{info, warn};
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// Everything the root authority blocks: rules of the filter lists, exact domains, zones,
+/// categories of exact domains, scheduled zones, zones blocked at runtime through the control
+/// socket, zones of DNS over HTTPS resolvers and answers with blacklisted IPs.
+pub struct Blocklist {
+  pub filters: Arc<FilterList>,
+  pub domains: Arc<DomainSet>,
+  pub zones: Arc<DomainSet>,
+  /// Zones unblocked from `zones` and the scheduled zones, e.g. `graph.facebook.com` when
+  /// `facebook.com` is blocked.
+  pub zone_exceptions: Arc<DomainSet>,
+  pub categories: Arc<Vec<Category>>,
+  pub scheduled: Vec<ScheduledBlacklist>,
+  pub doh_servers: Arc<DomainSet>,
+  pub ips: IpRangeVec,
+  pub control: Arc<Control>,
+}
+
+impl Blocklist {
+  pub fn blocks(&self, name: &LowerName) -> bool {
+    self.blocked_by(name).is_some()
+  }
+
+  /// Name of the list blocking `name`, if any.
+  pub fn blocked_by(&self, name: &LowerName) -> Option<Cow<'static, str>> {
+    if !self.control.is_blocking() {
+      return None;
+    }
+    if self.domains.contains(name) {
+      return Some("blacklist".into());
+    }
+    let excepted = || self.zone_exceptions.contains_zone(name);
+    if self.zones.contains_zone(name) && !excepted() {
+      return Some("zone blacklist".into());
+    }
+    let category = self.categories.iter().find(|category| {
+      category.domains.contains(name) && self.control.is_category_enabled(&category.name)
+    });
+    if let Some(category) = category {
+      Some(format!("{} category", category.name).into())
+    } else if self.control.blocks(name) {
+      Some("control socket blocklist".into())
+    } else if self.scheduled.iter().any(|list| list.blocks(name)) && !excepted() {
+      Some("scheduled zone blacklist".into())
+    } else if self.doh_servers.contains_zone(name) {
+      Some("DoH bypass list".into())
+    } else {
+      None
+    }
+  }
+
+  /// Name of the list blocking `name` for `client`. The filter lists come first, their exception
+  /// rules unblock the names of the other lists.
+  pub fn blocked_for(&self, name: &LowerName, client: IpAddr) -> Option<Cow<'static, str>> {
+    if !self.control.is_blocking() {
+      return None;
+    }
+    match self.filters.verdict(name, client) {
+      Some(verdict) if verdict.blocked => Some(format!("filter list rule {}", verdict.rule).into()),
+      Some(verdict) => {
+        debug!("{} allowed by the filter list rule {}", name, verdict.rule);
+        None
+      }
+      None => self.blocked_by(name),
+    }
+  }
+
+  /// Whether an IP of the A and AAAA records of a forwarded answer is blacklisted.
+  pub fn blocks_answer(&self, lookup: &ForwardLookup) -> bool {
+    self.control.is_blocking()
+      && lookup.0.record_iter().any(|record| match record.data() {
+        Some(RData::A(ip)) => self.ips.contains(ip.0.into()),
+        Some(RData::AAAA(ip)) => self.ips.contains(ip.0.into()),
+        _ => false,
+      })
+  }
+}
 
 pub struct BlacklistAuthority {
-  blacklisted: HashSet<LowerName>,
-  inner: ForwardAuthority,
+  blocklist: Blocklist,
+  upstreams: Arc<Upstreams>,
   default_ip: Option<Ipv4Addr>,
+  block_ttl: u32,
+  synthetic_zone: Arc<SyntheticZone>,
   rfc8215_ips: IpRangeVec,
+  local_only: Arc<DomainSet>,
+  nat64: Arc<Nat64>,
+  routes: Vec<(IpRangeVec, Arc<Upstreams>)>,
+  /// Upstreams of some zones, the most specific zones first.
+  forward_zones: Vec<(LowerName, Arc<Upstreams>)>,
+  rotation: Option<AtomicUsize>,
 }
 
 impl BlacklistAuthority {
   pub fn new(
-    name: Name,
-    blacklisted: HashSet<LowerName>,
-    name_servers: NameServerConfigGroup,
+    blocklist: Blocklist,
+    upstreams: Arc<Upstreams>,
     default_ip: Option<Ipv4Addr>,
     rfc8215_ips: IpRangeVec,
   ) -> Self {
-    info!("Domains {:?} will be ingnored", blacklisted);
-    let authority_config = ForwardConfig {
-      name_servers,
-      options: None,
-    };
-    let forward_authority =
-      ForwardAuthority::try_from_config(name.clone(), ZoneType::Primary, &authority_config)
-        .unwrap();
+    info!(
+      "{} domains and {} zones will be ignored",
+      blocklist.domains.len(),
+      blocklist.zones.len()
+    );
     Self {
-      blacklisted,
-      inner: forward_authority,
+      blocklist,
+      upstreams,
       default_ip,
+      block_ttl: DEFAULT_BLOCK_TTL,
+      synthetic_zone: Default::default(),
       rfc8215_ips,
+      local_only: Default::default(),
+      nat64: Default::default(),
+      routes: vec![],
+      forward_zones: vec![],
+      rotation: None,
     }
   }
+
+  /// TTL of the default IP answered to the blocked queries.
+  pub fn block_ttl(mut self, ttl: u32) -> Self {
+    self.block_ttl = ttl;
+    self
+  }
+
+  /// SOA and NS records answered for the blocked names.
+  pub fn synthetic_zone(mut self, zone: Arc<SyntheticZone>) -> Self {
+    self.synthetic_zone = zone;
+    self
+  }
+
+  /// Zones answered NXDOMAIN instead of being forwarded, see [`crate::authority::local_only_zones`].
+  pub fn local_only(mut self, zones: Arc<DomainSet>) -> Self {
+    self.local_only = zones;
+    self
+  }
+
+  /// NAT64 prefixes of the AAAA records synthesized for the `rfc8215_ips` clients.
+  pub fn nat64(mut self, nat64: Arc<Nat64>) -> Self {
+    self.nat64 = nat64;
+    self
+  }
+
+  /// Forward the queries of the clients of these networks to their upstreams, the first route
+  /// containing the client is used.
+  pub fn routes(mut self, routes: Vec<(IpRangeVec, Arc<Upstreams>)>) -> Self {
+    self.routes = routes;
+    self
+  }
+
+  /// Forward the names of these zones to their upstreams, whatever the client and even when they
+  /// are local-only zones. The most specific zone of a name is used.
+  pub fn forward_zones(mut self, mut zones: Vec<(LowerName, Arc<Upstreams>)>) -> Self {
+    zones.sort_by_key(|(zone, _)| std::cmp::Reverse(zone.num_labels()));
+    self.forward_zones = zones;
+    self
+  }
+
+  /// Rotate the order of the IPs of the forwarded answers having several of them on each query,
+  /// cached answers included.
+  pub fn rotate_answers(mut self, rotate: bool) -> Self {
+    self.rotation = rotate.then(|| AtomicUsize::new(0));
+    self
+  }
+
+  /// Upstreams of the forward zone of `name`, if any.
+  fn forward_zone(&self, name: &LowerName) -> Option<&Upstreams> {
+    self
+      .forward_zones
+      .iter()
+      .find(|(zone, _)| zone.zone_of(name))
+      .map(|(_, upstreams)| upstreams.as_ref())
+  }
+
+  /// Upstreams of `name` asked by the client `src`: the ones of its forward zone, then the ones
+  /// of the route of the client.
+  fn upstreams(&self, src: SocketAddr, name: &LowerName) -> &Upstreams {
+    self.forward_zone(name).unwrap_or_else(|| {
+      self
+        .routes
+        .iter()
+        .find(|(ips, _)| ips.contains_sock_addr(src))
+        .map_or(&self.upstreams, |(_, upstreams)| upstreams)
+    })
+  }
+}
+
+impl BlacklistAuthority {
+  fn block(
+    &self,
+    request_info: RequestInfo<'_>,
+    list: impl Into<Arc<str>>,
+  ) -> Result<ForwardLookup, LookupError> {
+    events::set_blocked(list);
+    let query = request_info.query;
+    let synthetic = self
+      .synthetic_zone
+      .answer(query.name(), query.query_type(), self.block_ttl);
+    if let Some(lookup) = synthetic {
+      Ok(lookup)
+    } else if let Some(ip) = self.default_ip {
+      Ok(forge_ip_record(ip, self.block_ttl, request_info))
+    } else {
+      Err(LookupError::ResponseCode(ResponseCode::NoError))
+    }
+  }
+
+  async fn forward(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<ForwardLookup, LookupError> {
+    if request_info.query.query_type() == RecordType::PTR {
+      if let Some(ipv4) = self.nat64.reverse_ipv4(&request_info.query.name().into()) {
+        return self.reverse_nat64(request_info, lookup_options, ipv4).await;
+      }
+    }
+    let lookup = self
+      .upstreams(request_info.src, request_info.query.name())
+      .search(request_info.clone(), lookup_options)
+      .await;
+    if request_info.query.query_type() == RecordType::AAAA
+      && self.rfc8215_ips.contains_sock_addr(request_info.src)
+    {
+      self.dns64(request_info, lookup_options, lookup).await
+    } else {
+      lookup
+    }
+  }
+
+  /// AAAA answer of a client behind NAT64 (RFC 6147): the IPv6 of the name without the excluded
+  /// ones, or when there is none the IPv6 synthesized from its IPv4. Names that do not exist are
+  /// not synthesized.
+  async fn dns64(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+    aaaa: Result<ForwardLookup, LookupError>,
+  ) -> Result<ForwardLookup, LookupError> {
+    match &aaaa {
+      Ok(lookup) => {
+        if let Some(lookup) = without_excluded(lookup, &self.nat64) {
+          return Ok(lookup);
+        }
+      }
+      Err(err) if is_nx_domain(err) => return aaaa,
+      Err(_) => {}
+    }
+    let mut query = Query::query(request_info.query.name().into(), RecordType::A);
+    query.set_query_class(request_info.query.query_class());
+    let lower_query = query.into();
+    let a_request = RequestInfo::new(
+      request_info.src,
+      request_info.protocol,
+      request_info.header,
+      &lower_query,
+    );
+    match self
+      .upstreams(request_info.src, request_info.query.name())
+      .search(a_request, lookup_options)
+      .await
+    {
+      Ok(a)
+        if a
+          .0
+          .record_iter()
+          .any(|record| record.record_type() == RecordType::A) =>
+      {
+        let prefix = self.nat64.prefix(request_info.src.ip());
+        Ok(ipv4_to_prefixed_ipv6_records(a.0, prefix))
+      }
+      _ => aaaa,
+    }
+  }
+
+  /// PTR answer of an IPv6 synthesized by DNS64, the one of its IPv4 under the name of the IPv6.
+  async fn reverse_nat64(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+    ipv4: Ipv4Addr,
+  ) -> Result<ForwardLookup, LookupError> {
+    let mut query = Query::query(Name::from(IpAddr::V4(ipv4)), RecordType::PTR);
+    query.set_query_class(request_info.query.query_class());
+    let lower_query = query.into();
+    let ptr_request = RequestInfo::new(
+      request_info.src,
+      request_info.protocol,
+      request_info.header,
+      &lower_query,
+    );
+    let lookup = self
+      .upstreams(request_info.src, lower_query.name())
+      .search(ptr_request, lookup_options)
+      .await?;
+    // Only the PTR records of the end of the CNAME chain (RFC 2317) are renamed, the
+    // intermediates would not make sense under the name of the IPv6.
+    let mut owner = Name::from(lower_query.name());
+    for _ in 0..lookup.0.records().len() {
+      let target = lookup
+        .0
+        .record_iter()
+        .find_map(|record| match record.data() {
+          Some(RData::CNAME(cname)) if record.name() == &owner => Some(cname.0.clone()),
+          _ => None,
+        });
+      match target {
+        Some(target) => owner = target,
+        None => break,
+      }
+    }
+    let name = Name::from(request_info.query.name());
+    let records: Vec<Record> = lookup
+      .0
+      .record_iter()
+      .filter(|record| record.record_type() == RecordType::PTR && record.name() == &owner)
+      .map(|record| {
+        let mut record = record.clone();
+        record.set_name(name.clone());
+        record
+      })
+      .collect();
+    Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+      request_info.query.original().clone(),
+      records.into(),
+    )))
+  }
+}
+
+/// AAAA answer without the records of the NAT64 excluded networks, `None` when no AAAA record
+/// is left.
+fn without_excluded(lookup: &ForwardLookup, nat64: &Nat64) -> Option<ForwardLookup> {
+  let excluded = |record: &Record| match record.data() {
+    Some(RData::AAAA(ip)) => nat64.is_excluded(ip.0),
+    _ => false,
+  };
+  let records: Vec<Record> = lookup
+    .0
+    .record_iter()
+    .filter(|record| !excluded(record))
+    .cloned()
+    .collect();
+  if !records
+    .iter()
+    .any(|record| record.record_type() == RecordType::AAAA)
+  {
+    return None;
+  }
+  if records.len() == lookup.0.records().len() {
+    return Some(ForwardLookup(lookup.0.clone()));
+  }
+  Some(ForwardLookup(ResolverLookup::new_with_max_ttl(
+    lookup.0.query().clone(),
+    records.into(),
+  )))
+}
+
+/// `lookup` with its A and AAAA records rotated left by `shift` positions, the other records
+/// (e.g. the CNAME chain) keep their place.
+fn rotate_addresses(lookup: ForwardLookup, shift: usize) -> ForwardLookup {
+  let records = lookup.0.records();
+  let is_address =
+    |record: &Record| matches!(record.record_type(), RecordType::A | RecordType::AAAA);
+  let mut addresses: Vec<Record> = records.iter().filter(|r| is_address(r)).cloned().collect();
+  if addresses.len() < 2 {
+    return lookup;
+  }
+  let len = addresses.len();
+  addresses.rotate_left(shift % len);
+  let mut addresses = addresses.into_iter();
+  let records: Vec<Record> = records
+    .iter()
+    .map(|record| match is_address(record) {
+      true => addresses.next().unwrap(),
+      false => record.clone(),
+    })
+    .collect();
+  ForwardLookup(ResolverLookup::new_with_max_ttl(
+    lookup.0.query().clone(),
+    records.into(),
+  ))
+}
+
+/// Whether the name does not exist, answered by a resolver or an authority.
+fn is_nx_domain(err: &LookupError) -> bool {
+  match err {
+    LookupError::ResolveError(err) => matches!(
+      err.kind(),
+      ResolveErrorKind::NoRecordsFound {
+        response_code: ResponseCode::NXDomain,
+        ..
+      }
+    ),
+    err => err.is_nx_domain(),
+  }
 }
 
 #[async_trait::async_trait]
@@ -53,28 +428,28 @@ impl Authority for BlacklistAuthority {
   type Lookup = ForwardLookup;
 
   fn zone_type(&self) -> ZoneType {
-    self.inner.zone_type()
+    ZoneType::Forward
   }
 
   fn is_axfr_allowed(&self) -> bool {
-    self.inner.is_axfr_allowed()
+    false
   }
 
-  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
-    self.inner.update(update).await
+  async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+    Err(ResponseCode::NotImp)
   }
 
   fn origin(&self) -> &LowerName {
-    self.inner.origin()
+    self.upstreams.origin()
   }
 
   async fn lookup(
     &self,
     name: &LowerName,
     query_type: RecordType,
-    lookup_options: LookupOptions,
+    _lookup_options: LookupOptions,
   ) -> Result<Self::Lookup, LookupError> {
-    self.inner.lookup(name, query_type, lookup_options).await
+    self.upstreams.select().lookup(name, query_type).await
   }
 
   async fn search(
@@ -82,49 +457,290 @@ impl Authority for BlacklistAuthority {
     request_info: RequestInfo<'_>,
     lookup_options: LookupOptions,
   ) -> Result<Self::Lookup, LookupError> {
-    if self.blacklisted.contains(request_info.query.name()) {
+    if let Some(list) = self
+      .blocklist
+      .blocked_for(request_info.query.name(), request_info.src.ip())
+    {
       warn!("Domain name ignored {}", request_info.query.name());
-      if let Some(ip) = self.default_ip {
-        Ok(forge_ip_record(ip, request_info))
-      } else {
-        Err(LookupError::ResponseCode(ResponseCode::NoError))
-      }
-    } else {
-      match self
-        .inner
-        .search(request_info.clone(), lookup_options)
-        .await
-      {
-        Ok(res) => Ok(res),
-        Err(err) => {
-          if request_info.query.query_type() == RecordType::AAAA
-            && self.rfc8215_ips.contains_sock_addr(request_info.src)
-          {
-            let mut query = Query::query(request_info.query.name().into(), RecordType::A);
-            query.set_query_class(request_info.query.query_class());
-            let lower_query = query.into();
-            let a_request = RequestInfo::new(
-              request_info.src,
-              request_info.protocol,
-              request_info.header,
-              &lower_query,
-            );
-            if let Ok(a_res) = self.inner.search(a_request, lookup_options).await {
-              return Ok(ipv4_to_prefixed_ipv6_records(a_res.0));
-            }
-          }
-
-          Err(err)
-        }
-      }
+      return self.block(request_info, list);
+    }
+    if self.local_only.contains_zone(request_info.query.name())
+      && self.forward_zone(request_info.query.name()).is_none()
+    {
+      debug!(
+        "Local-only name {} not forwarded",
+        request_info.query.name()
+      );
+      return Err(LookupError::ResponseCode(ResponseCode::NXDomain));
+    }
+    let lookup = self.forward(request_info.clone(), lookup_options).await?;
+    if self.blocklist.blocks_answer(&lookup) {
+      warn!(
+        "Answer of {} has a blacklisted IP",
+        request_info.query.name()
+      );
+      return self.block(request_info, "IP blacklist");
+    }
+    match &self.rotation {
+      Some(next) => Ok(rotate_addresses(
+        lookup,
+        next.fetch_add(1, Ordering::Relaxed),
+      )),
+      None => Ok(lookup),
     }
   }
 
   async fn get_nsec_records(
     &self,
-    name: &LowerName,
-    lookup_options: LookupOptions,
+    _name: &LowerName,
+    _lookup_options: LookupOptions,
   ) -> Result<Self::Lookup, LookupError> {
-    self.inner.get_nsec_records(name, lookup_options).await
+    Err(LookupError::from(std::io::Error::other(
+      "Getting NSEC records is unimplemented for the forwarder",
+    )))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::ip::IpRange;
+  use hickory_server::{
+    proto::rr::{rdata::CNAME, Name, Record},
+    resolver::lookup::Lookup,
+  };
+  use std::net::IpAddr;
+  use std::str::FromStr;
+
+  fn lookup(ips: &[&str]) -> ForwardLookup {
+    let name = Name::from_str("fast-flux.example.").unwrap();
+    let records: Vec<Record> = ips
+      .iter()
+      .map(|ip| match IpAddr::from_str(ip).unwrap() {
+        IpAddr::V4(ip) => Record::from_rdata(name.clone(), 60, RData::A(ip.into())),
+        IpAddr::V6(ip) => Record::from_rdata(name.clone(), 60, RData::AAAA(ip.into())),
+      })
+      .collect();
+    let query = Query::query(name, RecordType::A);
+    ForwardLookup(Lookup::new_with_max_ttl(query, records.into()))
+  }
+
+  #[test]
+  fn blocked_names() {
+    let control = Arc::new(Control::new());
+    let name = |name: &str| LowerName::from_str(name).unwrap();
+    let blocklist = Blocklist {
+      filters: Arc::new(FilterList::new()),
+      domains: Arc::new(DomainSet::from_iter([name("tracker.example.")])),
+      zones: Arc::new(DomainSet::from_iter([name("social.example.")])),
+      zone_exceptions: Arc::new(DomainSet::from_iter([name("api.social.example.")])),
+      categories: Arc::new(vec![Category {
+        name: "ads".to_string(),
+        domains: DomainSet::from_iter([name("ads.example.")]),
+      }]),
+      scheduled: vec![],
+      doh_servers: Arc::new(DomainSet::new()),
+      ips: IpRangeVec::new(vec![]),
+      control: control.clone(),
+    };
+    control.set_categories(["ads"]);
+    assert_eq!(
+      blocklist.blocked_by(&name("ads.example.")).as_deref(),
+      Some("ads category")
+    );
+    assert_eq!(
+      blocklist.blocked_by(&name("tracker.example.")).as_deref(),
+      Some("blacklist")
+    );
+    assert_eq!(
+      blocklist
+        .blocked_by(&name("www.social.example."))
+        .as_deref(),
+      Some("zone blacklist")
+    );
+    assert_eq!(blocklist.blocked_by(&name("v2.api.social.example.")), None);
+    control.set_category_enabled("ads", false).unwrap();
+    assert_eq!(blocklist.blocked_by(&name("ads.example.")), None);
+    assert!(blocklist.blocks(&name("tracker.example.")));
+  }
+
+  #[test]
+  fn blocked_answer() {
+    let control = Arc::new(Control::new());
+    let blocklist = Blocklist {
+      filters: Arc::new(FilterList::new()),
+      domains: Arc::new(DomainSet::new()),
+      zones: Arc::new(DomainSet::new()),
+      zone_exceptions: Arc::new(DomainSet::new()),
+      categories: Arc::new(vec![]),
+      scheduled: vec![],
+      doh_servers: Arc::new(DomainSet::new()),
+      ips: IpRangeVec::new(vec![
+        IpRange::try_from("203.0.113.0/24").unwrap(),
+        IpRange::try_from("2001:db8::66").unwrap(),
+      ]),
+      control: control.clone(),
+    };
+    assert!(blocklist.blocks_answer(&lookup(&["192.0.2.1", "203.0.113.7"])));
+    assert!(blocklist.blocks_answer(&lookup(&["2001:db8::66"])));
+    assert!(!blocklist.blocks_answer(&lookup(&["192.0.2.1", "2001:db8::1"])));
+    control.disable(std::time::Duration::from_secs(60));
+    assert!(!blocklist.blocks_answer(&lookup(&["203.0.113.7"])));
+  }
+
+  #[test]
+  fn rotated_addresses() {
+    let ips = |lookup: ForwardLookup| -> Vec<String> {
+      lookup
+        .0
+        .records()
+        .iter()
+        .map(|record| record.data().unwrap().to_string())
+        .collect()
+    };
+    let answer = || lookup(&["192.0.2.1", "192.0.2.2", "192.0.2.3"]);
+    assert_eq!(
+      ips(rotate_addresses(answer(), 1)),
+      ["192.0.2.2", "192.0.2.3", "192.0.2.1"]
+    );
+    assert_eq!(
+      ips(rotate_addresses(answer(), 5)),
+      ["192.0.2.3", "192.0.2.1", "192.0.2.2"]
+    );
+
+    let name = Name::from_str("www.example.").unwrap();
+    let mut records = vec![Record::from_rdata(
+      name.clone(),
+      60,
+      RData::CNAME(CNAME(Name::from_str("fast-flux.example.").unwrap())),
+    )];
+    records.extend(
+      lookup(&["192.0.2.1", "192.0.2.2"])
+        .0
+        .records()
+        .iter()
+        .cloned(),
+    );
+    let answer = ForwardLookup(Lookup::new_with_max_ttl(
+      Query::query(name, RecordType::A),
+      records.into(),
+    ));
+    assert_eq!(
+      ips(rotate_addresses(answer, 1)),
+      ["fast-flux.example.", "192.0.2.2", "192.0.2.1"]
+    );
+  }
+
+  #[test]
+  fn dns64_answers() {
+    let nat64 = Nat64::default();
+    let ips = |lookup: Option<ForwardLookup>| -> Vec<String> {
+      lookup
+        .map(|lookup| {
+          lookup
+            .0
+            .record_iter()
+            .filter_map(|record| record.data().map(ToString::to_string))
+            .collect()
+        })
+        .unwrap_or_default()
+    };
+    assert_eq!(
+      ips(without_excluded(
+        &lookup(&["2001:db8::1", "::ffff:192.0.2.1"]),
+        &nat64
+      )),
+      ["2001:db8::1"]
+    );
+    assert!(without_excluded(&lookup(&["::ffff:192.0.2.1"]), &nat64).is_none());
+    assert!(without_excluded(&lookup(&["192.0.2.1"]), &nat64).is_none());
+
+    assert!(is_nx_domain(&LookupError::ResponseCode(
+      ResponseCode::NXDomain
+    )));
+    assert!(!is_nx_domain(&LookupError::ResponseCode(
+      ResponseCode::NoError
+    )));
+  }
+
+  #[tokio::test]
+  async fn dns64_reverse() {
+    use crate::client::ClientType;
+    use crate::events::Stats;
+    use crate::upstream::UpstreamOptions;
+    use hickory_server::proto::op::{Header, Message, MessageType};
+    use hickory_server::proto::rr::rdata::PTR;
+    use hickory_server::server::Protocol as RequestProtocol;
+    use tokio::net::UdpSocket;
+
+    // RFC 2317 delegation of 192.0.2.0/26: the PTR is behind a CNAME.
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+      let mut buffer = [0; 512];
+      let (length, client) = socket.recv_from(&mut buffer).await.unwrap();
+      let mut message = Message::from_vec(&buffer[..length]).unwrap();
+      let owner = message.queries()[0].name().clone();
+      assert_eq!(owner.to_string(), "33.2.0.192.in-addr.arpa.");
+      let target = Name::from_str("33.0-63.2.0.192.in-addr.arpa.").unwrap();
+      message.set_message_type(MessageType::Response);
+      message.add_answer(Record::from_rdata(
+        owner,
+        60,
+        RData::CNAME(CNAME(target.clone())),
+      ));
+      message.add_answer(Record::from_rdata(
+        target,
+        60,
+        RData::PTR(PTR(Name::from_str("host.example.").unwrap())),
+      ));
+      socket
+        .send_to(&message.to_vec().unwrap(), client)
+        .await
+        .unwrap();
+    });
+
+    let upstreams = Upstreams::new(
+      &Name::root(),
+      &[ClientType::from_str(&server.to_string()).unwrap()],
+      &UpstreamOptions::default(),
+      Arc::new(Stats::new()),
+    );
+    let blocklist = Blocklist {
+      filters: Arc::new(FilterList::new()),
+      domains: Arc::new(DomainSet::new()),
+      zones: Arc::new(DomainSet::new()),
+      zone_exceptions: Arc::new(DomainSet::new()),
+      categories: Arc::new(vec![]),
+      scheduled: vec![],
+      doh_servers: Arc::new(DomainSet::new()),
+      ips: IpRangeVec::new(vec![]),
+      control: Arc::new(Control::new()),
+    };
+    let authority = BlacklistAuthority::new(blocklist, upstreams, None, IpRangeVec::new(vec![]));
+    let name = Name::from(IpAddr::from_str("64:ff9b::192.0.2.33").unwrap());
+    let query = Query::query(name.clone(), RecordType::PTR).into();
+    let header = Header::new();
+    let request_info = RequestInfo::new(
+      "127.0.0.1:53".parse().unwrap(),
+      RequestProtocol::Udp,
+      &header,
+      &query,
+    );
+    let ipv4 = authority.nat64.reverse_ipv4(&name).unwrap();
+    let (answer, _) = events::with_query_context(None, async {
+      authority
+        .reverse_nat64(request_info, LookupOptions::default(), ipv4)
+        .await
+    })
+    .await;
+    let answer = answer.unwrap();
+    let records = answer.0.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].name(), &name);
+    assert_eq!(
+      records[0].data(),
+      Some(&RData::PTR(PTR(Name::from_str("host.example.").unwrap())))
+    );
   }
 }
@@ -0,0 +1,70 @@
+use hickory_server::proto::rr::LowerName;
+use std::str::FromStr;
+
+/// Zones of well-known public DNS over HTTPS resolvers, blocked with `--block-doh-bypass`.
+const DOH_SERVERS: &[&str] = &[
+  "dns.google.",
+  "dns64.dns.google.",
+  "cloudflare-dns.com.",
+  "one.one.one.one.",
+  "1dot1dot1dot1.cloudflare-dns.com.",
+  "dns.quad9.net.",
+  "dns9.quad9.net.",
+  "dns10.quad9.net.",
+  "dns11.quad9.net.",
+  "doh.opendns.com.",
+  "doh.familyshield.opendns.com.",
+  "dns.adguard.com.",
+  "dns.adguard-dns.com.",
+  "doh.cleanbrowsing.org.",
+  "dns.nextdns.io.",
+  "doh.dns.sb.",
+  "dns.alidns.com.",
+  "doh.pub.",
+  "doh.mullvad.net.",
+  "dns.mullvad.net.",
+  "freedns.controld.com.",
+  "dns0.eu.",
+  "doh.libredns.gr.",
+  "dns.switch.ch.",
+  "doh.applied-privacy.net.",
+];
+
+/// Domains checked by browsers and operating systems before using their own resolver, they are
+/// answered with NXDOMAIN to keep it disabled.
+const CANARY_DOMAINS: &[&str] = &[
+  // Firefox
+  "use-application-dns.net.",
+  // iCloud Private Relay
+  "mask.icloud.com.",
+  "mask-h2.icloud.com.",
+];
+
+/// Built-in list of the public DNS over HTTPS resolvers.
+pub fn doh_servers() -> Vec<LowerName> {
+  zones(DOH_SERVERS)
+}
+
+/// Canary domains answered with NXDOMAIN.
+pub fn canary_domains() -> Vec<LowerName> {
+  zones(CANARY_DOMAINS)
+}
+
+fn zones(list: &[&str]) -> Vec<LowerName> {
+  list
+    .iter()
+    .map(|zone| LowerName::from_str(zone).unwrap())
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn built_in_zones() {
+    assert_eq!(doh_servers().len(), DOH_SERVERS.len());
+    let canary = LowerName::from_str("use-application-dns.net.").unwrap();
+    assert!(canary_domains().contains(&canary));
+  }
+}
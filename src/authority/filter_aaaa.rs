@@ -0,0 +1,133 @@
+use crate::domain_set::DomainSet;
+use hickory_server::{
+  authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
+  proto::{
+    op::ResponseCode,
+    rr::{LowerName, Record, RecordType},
+  },
+  resolver::lookup::Lookup as ResolverLookup,
+  server::RequestInfo,
+  store::forwarder::ForwardLookup,
+};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Answers the AAAA queries of some zones with NODATA, and removes the AAAA records of the other
+/// answers of these zones, for networks where IPv6 is advertised but broken. The root zone
+/// filters every name.
+pub struct FilterAaaaAuthority<A> {
+  zones: Arc<DomainSet>,
+  inner: Arc<A>,
+}
+
+impl<A> FilterAaaaAuthority<A> {
+  pub fn new(zones: Arc<DomainSet>, inner: Arc<A>) -> Self {
+    Self { zones, inner }
+  }
+}
+
+/// `lookup` without its AAAA records.
+fn without_aaaa(lookup: ForwardLookup) -> ForwardLookup {
+  let records = lookup.0.records();
+  if !records
+    .iter()
+    .any(|record| record.record_type() == RecordType::AAAA)
+  {
+    return lookup;
+  }
+  let records: Vec<Record> = records
+    .iter()
+    .filter(|record| record.record_type() != RecordType::AAAA)
+    .cloned()
+    .collect();
+  ForwardLookup(ResolverLookup::new_with_max_ttl(
+    lookup.0.query().clone(),
+    records.into(),
+  ))
+}
+
+#[async_trait::async_trait]
+impl<A: Authority<Lookup = ForwardLookup>> Authority for FilterAaaaAuthority<A> {
+  type Lookup = ForwardLookup;
+
+  fn zone_type(&self) -> ZoneType {
+    self.inner.zone_type()
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    self.inner.is_axfr_allowed()
+  }
+
+  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+    self.inner.update(update).await
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.inner.origin()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.lookup(name, query_type, lookup_options).await
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let name = request_info.query.name();
+    if self.zones.is_empty() || !self.zones.contains_zone(name) {
+      return self.inner.search(request_info, lookup_options).await;
+    }
+    if request_info.query.query_type() == RecordType::AAAA {
+      debug!("AAAA of {} filtered", name);
+      return Err(LookupError::ResponseCode(ResponseCode::NoError));
+    }
+    let lookup = self.inner.search(request_info, lookup_options).await?;
+    Ok(without_aaaa(lookup))
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.get_nsec_records(name, lookup_options).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::{
+    op::Query,
+    rr::{
+      rdata::{A, AAAA},
+      Name, RData,
+    },
+  };
+  use std::str::FromStr;
+
+  #[test]
+  fn aaaa_records_removed() {
+    let name = Name::from_str("example.com.").unwrap();
+    let records = vec![
+      Record::from_rdata(name.clone(), 60, RData::A(A::new(192, 0, 2, 1))),
+      Record::from_rdata(
+        name.clone(),
+        60,
+        RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+      ),
+    ];
+    let query = Query::query(name, RecordType::ANY);
+    let lookup = ForwardLookup(ResolverLookup::new_with_max_ttl(query, records.into()));
+    let lookup = without_aaaa(lookup);
+    let types: Vec<RecordType> = lookup.0.records().iter().map(Record::record_type).collect();
+    assert_eq!(types, vec![RecordType::A]);
+  }
+}
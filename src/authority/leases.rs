@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hickory_server::proto::rr::Name;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Hostnames of the active leases of a DHCP server lease file: dnsmasq, ISC dhcpd or Kea (CSV).
+/// Hostnames without a dot are completed with `domain`, invalid ones are ignored.
+pub fn parse_leases(
+  content: &str,
+  domain: Option<&Name>,
+  now: DateTime<Utc>,
+) -> Result<Vec<(IpAddr, Name)>> {
+  let first_line = content.lines().map(str::trim).find(|line| !line.is_empty());
+  let leases = if first_line.is_some_and(|line| line.starts_with("address,")) {
+    kea(content, now)?
+  } else if content
+    .lines()
+    .map(str::trim)
+    .any(|line| line.starts_with("lease ") && line.ends_with('{'))
+  {
+    isc(content, now)?
+  } else {
+    dnsmasq(content, now)?
+  };
+  Ok(
+    leases
+      .into_iter()
+      .filter_map(|(ip, hostname)| Some((ip, hostname_to_name(&hostname, domain)?)))
+      .collect(),
+  )
+}
+
+fn hostname_to_name(hostname: &str, domain: Option<&Name>) -> Option<Name> {
+  let mut name = Name::from_str(hostname).ok()?;
+  if name.is_root() {
+    return None;
+  }
+  if name.num_labels() == 1 {
+    if let Some(domain) = domain {
+      name = name.append_domain(domain).ok()?;
+    }
+  }
+  name.set_fqdn(true);
+  Some(name)
+}
+
+fn ip(field: &str) -> Result<IpAddr> {
+  IpAddr::from_str(field).with_context(|| format!("invalid IP `{field}`"))
+}
+
+/// Keep the last lease of each IP, lease files are appended when a lease changes.
+fn last_leases(leases: Vec<(IpAddr, Option<String>)>) -> Vec<(IpAddr, String)> {
+  let mut seen = HashSet::new();
+  let mut leases: Vec<(IpAddr, String)> = leases
+    .into_iter()
+    .rev()
+    .filter(|(ip, _)| seen.insert(*ip))
+    .filter_map(|(ip, hostname)| Some((ip, hostname?)))
+    .collect();
+  leases.reverse();
+  leases
+}
+
+/// `expiry mac ip hostname client-id` lines, IPv6 leases follow a `duid` line.
+fn dnsmasq(content: &str, now: DateTime<Utc>) -> Result<Vec<(IpAddr, String)>> {
+  let mut leases = vec![];
+  for (number, line) in content.lines().enumerate() {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.is_empty() || fields[0] == "duid" {
+      continue;
+    }
+    let [expiry, _, ip_field, hostname, ..] = fields[..] else {
+      return Err(anyhow!(
+        "line {}: expected expiry mac ip hostname",
+        number + 1
+      ));
+    };
+    let expiry: i64 = expiry
+      .parse()
+      .with_context(|| format!("line {}: invalid expiry `{expiry}`", number + 1))?;
+    let ip = ip(ip_field).with_context(|| format!("line {}", number + 1))?;
+    let active = expiry == 0 || expiry > now.timestamp();
+    let hostname = (active && hostname != "*").then(|| hostname.to_string());
+    leases.push((ip, hostname));
+  }
+  Ok(last_leases(leases))
+}
+
+/// `lease ip { ... }` blocks of dhcpd.leases.
+fn isc(content: &str, now: DateTime<Utc>) -> Result<Vec<(IpAddr, String)>> {
+  let mut leases = vec![];
+  let mut lease: Option<(IpAddr, bool, Option<String>)> = None;
+  for (number, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if let Some(ip_field) = line
+      .strip_prefix("lease ")
+      .and_then(|line| line.strip_suffix('{'))
+    {
+      let ip = ip(ip_field.trim()).with_context(|| format!("line {}", number + 1))?;
+      lease = Some((ip, true, None));
+      continue;
+    }
+    let Some((_, active, hostname)) = lease.as_mut() else {
+      continue;
+    };
+    let statement = line.trim_end_matches(';');
+    if line == "}" {
+      let (ip, active, hostname) = lease.take().unwrap();
+      leases.push((ip, hostname.filter(|_| active)));
+    } else if let Some(state) = statement.strip_prefix("binding state ") {
+      *active &= state == "active";
+    } else if let Some(ends) = statement.strip_prefix("ends ") {
+      *active &= isc_end(ends).is_none_or(|ends| ends > now);
+    } else if let Some(name) = statement.strip_prefix("client-hostname ") {
+      *hostname = Some(name.trim_matches('"').to_string());
+    }
+  }
+  Ok(last_leases(leases))
+}
+
+/// `never`, `epoch <seconds>` or `<weekday> <yyyy/mm/dd> <hh:mm:ss>` in UTC.
+fn isc_end(ends: &str) -> Option<DateTime<Utc>> {
+  if let Some(epoch) = ends.strip_prefix("epoch ") {
+    let seconds = epoch.split_whitespace().next()?.parse().ok()?;
+    return DateTime::from_timestamp(seconds, 0);
+  }
+  let (_, date) = ends.split_once(' ')?;
+  NaiveDateTime::parse_from_str(date, "%Y/%m/%d %H:%M:%S")
+    .ok()
+    .map(|date| date.and_utc())
+}
+
+/// CSV files of Kea (kea-leases4.csv, kea-leases6.csv), with a header line.
+fn kea(content: &str, now: DateTime<Utc>) -> Result<Vec<(IpAddr, String)>> {
+  let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+  let header: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+  let column = |name: &str| {
+    header
+      .iter()
+      .position(|column| column.trim() == name)
+      .ok_or_else(|| anyhow!("missing column {name}"))
+  };
+  let (address, expire, hostname) = (column("address")?, column("expire")?, column("hostname")?);
+  let state = column("state").ok();
+
+  let mut leases = vec![];
+  for line in lines {
+    let fields: Vec<&str> = line.split(',').collect();
+    let field = |index: usize| fields.get(index).copied().unwrap_or_default().trim();
+    let ip = ip(field(address))?;
+    let active = field(expire)
+      .parse::<i64>()
+      .is_ok_and(|expire| expire > now.timestamp())
+      && state.is_none_or(|state| matches!(field(state), "" | "0"));
+    let hostname =
+      Some(field(hostname).replace("&#x2c", ",")).filter(|hostname| active && !hostname.is_empty());
+    leases.push((ip, hostname));
+  }
+  Ok(last_leases(leases))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn leases(content: &str, domain: Option<&str>) -> Vec<String> {
+    let domain = domain.map(|domain| Name::from_str(domain).unwrap());
+    let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+    parse_leases(content, domain.as_ref(), now)
+      .unwrap()
+      .into_iter()
+      .map(|(ip, name)| format!("{ip} {name}"))
+      .collect()
+  }
+
+  #[test]
+  fn dnsmasq_leases() {
+    let content = "
+1700003600 aa:bb:cc:dd:ee:01 192.168.1.50 laptop 01:aa:bb:cc:dd:ee:01
+1600000000 aa:bb:cc:dd:ee:02 192.168.1.51 expired *
+0 aa:bb:cc:dd:ee:03 192.168.1.52 printer.lan *
+1700003600 aa:bb:cc:dd:ee:04 192.168.1.53 * *
+duid 00:01:00:01:2c:aa:bb:cc:dd:ee:ff:00:11
+1700003600 1234 fd00::50 phone 00:01:00:01
+";
+    assert_eq!(
+      leases(content, Some("lan.")),
+      [
+        "192.168.1.50 laptop.lan.",
+        "192.168.1.52 printer.lan.",
+        "fd00::50 phone.lan."
+      ]
+    );
+    assert_eq!(leases(content, None)[0], "192.168.1.50 laptop.");
+  }
+
+  #[test]
+  fn isc_leases() {
+    let content = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+lease 192.168.1.50 {
+  starts 2 2023/11/14 21:00:00;
+  ends 3 2023/11/15 21:00:00;
+  binding state active;
+  next binding state free;
+  client-hostname "laptop";
+}
+lease 192.168.1.51 {
+  ends 3 2023/11/15 21:00:00;
+  binding state free;
+  client-hostname "old";
+}
+lease 192.168.1.52 {
+  ends never;
+  binding state active;
+  client-hostname "nas";
+}
+lease 192.168.1.52 {
+  ends 1 2023/01/02 00:00:00;
+  binding state active;
+  client-hostname "nas";
+}
+"#;
+    assert_eq!(leases(content, Some("lan")), ["192.168.1.50 laptop.lan."]);
+  }
+
+  #[test]
+  fn kea_leases() {
+    let content = "
+address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context
+192.168.1.50,aa:bb:cc:dd:ee:01,,3600,1700003600,1,0,0,laptop,0,
+192.168.1.51,aa:bb:cc:dd:ee:02,,3600,1700003600,1,0,0,declined,1,
+192.168.1.52,aa:bb:cc:dd:ee:03,,3600,1700003600,1,0,0,tv.lan.,0,
+192.168.1.52,aa:bb:cc:dd:ee:03,,0,1699990000,1,0,0,tv.lan.,0,
+";
+    assert_eq!(leases(content, Some("lan")), ["192.168.1.50 laptop.lan."]);
+    assert!(parse_leases("address,expire\n", None, Utc::now()).is_err());
+  }
+}
@@ -0,0 +1,1237 @@
+use crate::authority::leases::parse_leases;
+use crate::authority::SyntheticZone;
+use crate::ip::{IpRange, IpRangeVec};
+use crate::snapshot::Snapshot;
+use crate::tsig::{self, Tsig, TsigKey};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use hickory_server::{
+  authority::{
+    Authority, LookupError, LookupOptions, MessageRequest, MessageResponseBuilder, UpdateRequest,
+    UpdateResult, ZoneType,
+  },
+  proto::{
+    op::{Header, Message, Query, ResponseCode},
+    rr::{
+      rdata::{A, AAAA, MX, PTR, SRV, TXT},
+      DNSClass, LowerName, Name, RData, Record, RecordType,
+    },
+  },
+  resolver::lookup::Lookup as ResolverLookup,
+  server::{Request, RequestInfo, ResponseHandler, ResponseInfo},
+  store::forwarder::ForwardLookup,
+};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::future;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc, Mutex, Weak,
+};
+use std::time::{Duration, SystemTime};
+use tokio::{sync::mpsc, time::Interval};
+use tracing::{error, info, warn};
+
+/// Default TTL of the local names, see [`LocalDnsAuthority::ttl`].
+pub const DEFAULT_LOCAL_TTL: u32 = 300;
+/// Hosts file of the system, loaded with `--use-system-hosts`.
+#[cfg(not(windows))]
+pub const SYSTEM_HOSTS: &str = "/etc/hosts";
+#[cfg(windows)]
+pub const SYSTEM_HOSTS: &str = r"C:\Windows\System32\drivers\etc\hosts";
+/// Editors often write a file in several steps, changes are applied once it is quiet.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+type Records = HashMap<LowerName, Vec<Record>>;
+
+/// Records of a local DNS file, one entry per line. IPs get a PTR record to their first name.
+/// Lines starting with `@network[,network...]` are only answered to the clients of these
+/// networks, they hide the records of the same name and type of the other lines.
+///
+/// ```text
+/// # comment
+/// 192.168.1.10 nas.lan nas.home
+/// txt _acme-challenge.example.lan "token"
+/// mx example.lan 10 mail.example.lan
+/// srv _ldap._tcp.example.lan 0 0 389 dc1.example.lan
+/// 192.168.1.20 service.lan
+/// @100.64.0.0/10 100.64.0.5 service.lan
+/// ```
+/// A file of local names.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalSource {
+  /// Local DNS file.
+  File(PathBuf),
+  /// Lease file of a DHCP server, hostnames without a dot are completed with the domain.
+  Leases(PathBuf, Option<Name>),
+}
+
+impl LocalSource {
+  pub fn path(&self) -> &Path {
+    match self {
+      LocalSource::File(path) | LocalSource::Leases(path, _) => path,
+    }
+  }
+
+  fn records(&self) -> Result<(Records, Vec<View>)> {
+    let path = self.path();
+    let content = std::fs::read_to_string(path)
+      .with_context(|| format!("could not open {}", path.display()))?;
+    let records = match self {
+      LocalSource::File(_) => parse_with_views(&content),
+      LocalSource::Leases(_, domain) => {
+        let mut records = Records::new();
+        for (ip, name) in parse_leases(&content, domain.as_ref(), Utc::now())? {
+          for record in entry(&[ip.to_string(), name.to_string()])? {
+            insert(&mut records, record);
+          }
+        }
+        Ok((records, vec![]))
+      }
+    };
+    records.with_context(|| format!("invalid local DNS file {}", path.display()))
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct LocalDns {
+  sources: Vec<LocalSource>,
+  state: Snapshot<State>,
+  updating: Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  modified: Vec<Option<SystemTime>>,
+  records: Records,
+  views: Vec<View>,
+}
+
+/// Records of the `@network` lines, answered to the clients of `networks` only.
+#[derive(Debug)]
+struct View {
+  /// Networks as written in the file, the lines with the same ones share the view.
+  source: String,
+  networks: IpRangeVec,
+  records: Records,
+}
+
+impl View {
+  fn new(source: &str) -> Result<Self> {
+    let ranges = source
+      .split(',')
+      .map(|network| {
+        IpRange::try_from(network).with_context(|| format!("invalid network `{network}`"))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    Ok(Self {
+      source: source.to_string(),
+      networks: IpRangeVec::new(ranges),
+      records: Records::new(),
+    })
+  }
+}
+
+impl LocalDns {
+  /// Merge local DNS files, a name and type defined in a file hides the records of the same
+  /// name and type of the following files.
+  pub fn load(sources: &[LocalSource]) -> Result<Self> {
+    Ok(Self {
+      sources: sources.to_vec(),
+      state: Snapshot::new(read(sources)?),
+      updating: Mutex::new(()),
+    })
+  }
+
+  pub fn parse(content: &str) -> Result<Self> {
+    Ok(Self {
+      sources: vec![],
+      state: Snapshot::new({
+        let (records, views) = parse_with_views(content)?;
+        State {
+          modified: vec![],
+          records,
+          views,
+        }
+      }),
+      updating: Mutex::new(()),
+    })
+  }
+
+  /// Read the files again if one of them was modified since they were loaded, returns whether
+  /// they were. The current records are kept when a file is invalid.
+  pub fn refresh(&self) -> Result<bool> {
+    if modified(&self.sources) == self.state.load().modified {
+      return Ok(false);
+    }
+    self.state.store(read(&self.sources)?);
+    Ok(true)
+  }
+
+  /// Refresh the records every `interval` (`0` disables it) and when the files change, as long
+  /// as they are used.
+  pub fn watch(self: &Arc<Self>, interval: Duration) {
+    if !self.sources.is_empty() {
+      let paths = self
+        .sources
+        .iter()
+        .map(|source| source.path().to_path_buf())
+        .collect();
+      tokio::spawn(Self::watch_changes(Arc::downgrade(self), paths, interval));
+    }
+  }
+
+  async fn watch_changes(local: Weak<Self>, paths: Vec<PathBuf>, interval: Duration) {
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let file_names: HashSet<OsString> = paths
+      .iter()
+      .filter_map(|path| path.file_name().map(ToOwned::to_owned))
+      .collect();
+    // Directories are watched since editors usually replace the files instead of writing them.
+    let directories: HashSet<&Path> = paths
+      .iter()
+      .map(|path| {
+        path
+          .parent()
+          .filter(|directory| !directory.as_os_str().is_empty())
+          .unwrap_or(Path::new("."))
+      })
+      .collect();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+      let Ok(event) = event else {
+        return;
+      };
+      let changed = !matches!(event.kind, EventKind::Access(_))
+        && event.paths.iter().any(|path| {
+          path
+            .file_name()
+            .is_some_and(|file_name| file_names.contains(file_name))
+        });
+      if changed {
+        let _ = sender.send(());
+      }
+    })
+    .and_then(|mut watcher| {
+      for directory in directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+      }
+      Ok(watcher)
+    });
+    let _watcher = watcher
+      .map_err(|err| warn!("Could not watch the local DNS files: {}", err))
+      .ok();
+
+    let mut ticker = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+    loop {
+      tokio::select! {
+        Some(()) = changes.recv() => {
+          tokio::time::sleep(WATCH_DEBOUNCE).await;
+          while changes.try_recv().is_ok() {}
+        }
+        _ = tick(&mut ticker) => {}
+        else => break,
+      }
+      let Some(local) = local.upgrade() else {
+        break;
+      };
+      if let Err(err) = local.refresh() {
+        error!("Local DNS files not reloaded: {err:#}");
+      }
+    }
+  }
+
+  /// Apply a dynamic update (RFC 2136) of `zone` to the first file, then reload the files.
+  fn update(
+    &self,
+    zone: &LowerName,
+    prerequisites: &[Record],
+    updates: &[Record],
+  ) -> Result<(), ResponseCode> {
+    let _updating = self.updating.lock().unwrap();
+    let Some(LocalSource::File(path)) = self.sources.first() else {
+      return Err(ResponseCode::Refused);
+    };
+    for require in prerequisites {
+      self.check_prerequisite(zone, require)?;
+    }
+    for record in updates {
+      check_update(zone, record)?;
+    }
+
+    let server_failure = |err: anyhow::Error| {
+      error!("Could not update {}: {err:#}", path.display());
+      ResponseCode::ServFail
+    };
+    let content = std::fs::read_to_string(path)
+      .with_context(|| format!("could not open {}", path.display()))
+      .map_err(server_failure)?;
+    let file = parse(&content).map_err(server_failure)?;
+    let mut changed: Records = HashMap::new();
+    for record in updates {
+      let name = LowerName::from(record.name());
+      let records = changed.entry(name.clone()).or_insert_with(|| {
+        let records = file.get(&name).cloned().unwrap_or_default();
+        records
+          .into_iter()
+          .filter(|record| record.record_type() != RecordType::PTR)
+          .collect()
+      });
+      match record.dns_class() {
+        DNSClass::ANY if record.record_type() == RecordType::ANY => records.clear(),
+        DNSClass::ANY => records.retain(|current| current.record_type() != record.record_type()),
+        DNSClass::NONE => records.retain(|current| {
+          current.record_type() != record.record_type() || current.data() != record.data()
+        }),
+        _ => {
+          let exists = records.iter().any(|current| {
+            current.record_type() == record.record_type() && current.data() == record.data()
+          });
+          if !exists {
+            let mut record = record.clone();
+            record.set_ttl(DEFAULT_LOCAL_TTL);
+            records.push(record);
+          }
+        }
+      }
+    }
+    if changed.is_empty() {
+      return Ok(());
+    }
+
+    let content = rewrite(&content, &changed).map_err(server_failure)?;
+    let temporary = path.with_extension("update");
+    std::fs::write(&temporary, content)
+      .and_then(|_| std::fs::rename(&temporary, path))
+      .with_context(|| format!("could not write {}", path.display()))
+      .map_err(server_failure)?;
+    self
+      .state
+      .store(read(&self.sources).map_err(server_failure)?);
+    info!(
+      "Updated {} in {}",
+      changed
+        .keys()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", "),
+      path.display()
+    );
+    Ok(())
+  }
+
+  fn check_prerequisite(&self, zone: &LowerName, require: &Record) -> Result<(), ResponseCode> {
+    if require.ttl() != 0 {
+      return Err(ResponseCode::FormErr);
+    }
+    if !zone.zone_of(&require.name().into()) {
+      return Err(ResponseCode::NotZone);
+    }
+    let records = self.find(&require.name().into()).unwrap_or_default();
+    let rrset: Vec<&Record> = records
+      .iter()
+      .filter(|record| {
+        require.record_type() == RecordType::ANY || record.record_type() == require.record_type()
+      })
+      .collect();
+    let empty = matches!(require.data(), None | Some(RData::NULL(..)));
+    match (require.dns_class(), require.record_type()) {
+      (DNSClass::ANY, _) if !empty => Err(ResponseCode::FormErr),
+      (DNSClass::ANY, RecordType::ANY) if rrset.is_empty() => Err(ResponseCode::NXDomain),
+      (DNSClass::ANY, _) if rrset.is_empty() => Err(ResponseCode::NXRRSet),
+      (DNSClass::NONE, _) if !empty => Err(ResponseCode::FormErr),
+      (DNSClass::NONE, RecordType::ANY) if !rrset.is_empty() => Err(ResponseCode::YXDomain),
+      (DNSClass::NONE, _) if !rrset.is_empty() => Err(ResponseCode::YXRRSet),
+      (DNSClass::ANY | DNSClass::NONE, _) => Ok(()),
+      // The RRset must exist with this value, RFC 2136 compares whole RRsets which is not done
+      // here.
+      _ if rrset.iter().any(|record| record.data() == require.data()) => Ok(()),
+      _ => Err(ResponseCode::NXRRSet),
+    }
+  }
+
+  /// Records of `name` answered to every client, `None` when the name is not local.
+  pub fn find(&self, name: &LowerName) -> Option<Vec<Record>> {
+    self.find_for(name, None)
+  }
+
+  /// Records of `name` answered to `client`: the ones of its networks hide the ones of the same
+  /// type answered to every client. `None` when the name is not local for this client.
+  pub fn find_for(&self, name: &LowerName, client: Option<IpAddr>) -> Option<Vec<Record>> {
+    let state = self.state.load();
+    let client = client.map(|ip| ip.to_canonical());
+    let views = state
+      .views
+      .iter()
+      .filter(|view| client.is_some_and(|ip| view.networks.contains(ip)))
+      .map(|view| &view.records);
+    let mut found: Option<Records> = None;
+    for records in views.chain([&state.records]) {
+      if let Some(records) = records.get(name) {
+        let found = found.get_or_insert_with(Records::new);
+        merge(found, Records::from([(name.clone(), records.clone())]));
+      }
+    }
+    found.and_then(|mut found| found.remove(name))
+  }
+
+  /// Answer of a local name to `client`, `None` when the name is not local. With `rotation`, the
+  /// IPs of a name are rotated by one position on each answer.
+  fn answer(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    rotation: Option<&AtomicUsize>,
+    client: Option<IpAddr>,
+    ttl: u32,
+  ) -> Option<Result<ForwardLookup, LookupError>> {
+    let records = self.find_for(name, client)?;
+    let mut answers: Vec<Record> = records
+      .into_iter()
+      .filter(|record| record.record_type() == query_type || query_type == RecordType::ANY)
+      .map(|mut record| {
+        record.set_ttl(ttl);
+        record
+      })
+      .collect();
+    if answers.is_empty() {
+      return Some(Err(LookupError::ResponseCode(ResponseCode::NoError)));
+    }
+    if let Some(next) = rotation.filter(|_| matches!(query_type, RecordType::A | RecordType::AAAA))
+    {
+      let shift = next.fetch_add(1, Ordering::Relaxed) % answers.len();
+      answers.rotate_left(shift);
+    }
+    let query = Query::query(name.into(), query_type);
+    Some(Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+      query,
+      answers.into(),
+    ))))
+  }
+}
+
+async fn tick(ticker: &mut Option<Interval>) {
+  match ticker {
+    Some(ticker) => {
+      ticker.tick().await;
+    }
+    None => future::pending().await,
+  }
+}
+
+fn modified(sources: &[LocalSource]) -> Vec<Option<SystemTime>> {
+  sources
+    .iter()
+    .map(|source| {
+      std::fs::metadata(source.path())
+        .and_then(|metadata| metadata.modified())
+        .ok()
+    })
+    .collect()
+}
+
+fn read(sources: &[LocalSource]) -> Result<State> {
+  let modified = modified(sources);
+  let mut records = Records::new();
+  let mut views: Vec<View> = vec![];
+  for source in sources {
+    let (file, file_views) = source.records()?;
+    info!(
+      "Loaded {} local names from {}",
+      file.len()
+        + file_views
+          .iter()
+          .map(|view| view.records.len())
+          .sum::<usize>(),
+      source.path().display()
+    );
+    merge(&mut records, file);
+    for file_view in file_views {
+      match views
+        .iter_mut()
+        .find(|view| view.source == file_view.source)
+      {
+        Some(view) => merge(&mut view.records, file_view.records),
+        None => views.push(file_view),
+      }
+    }
+  }
+  Ok(State {
+    modified,
+    records,
+    views,
+  })
+}
+
+/// Add the records of a file, except the types already defined for a name by previous files.
+fn merge(records: &mut Records, file: Records) {
+  for (name, file_records) in file {
+    let records = records.entry(name).or_default();
+    let defined: HashSet<RecordType> = records.iter().map(Record::record_type).collect();
+    records.extend(
+      file_records
+        .into_iter()
+        .filter(|record| !defined.contains(&record.record_type())),
+    );
+  }
+}
+
+/// Records of the lines answered to every client.
+fn parse(content: &str) -> Result<Records> {
+  Ok(parse_with_views(content)?.0)
+}
+
+/// Records of the lines answered to every client, and of the `@network` lines.
+fn parse_with_views(content: &str) -> Result<(Records, Vec<View>)> {
+  let mut local = Records::new();
+  let mut views: Vec<View> = vec![];
+  for (number, line) in content.lines().enumerate() {
+    let line_number = || format!("line {}", number + 1);
+    let mut fields = fields(line).with_context(line_number)?;
+    let records = match fields.first().and_then(|field| field.strip_prefix('@')) {
+      Some(networks) => {
+        let index = match views.iter().position(|view| view.source == networks) {
+          Some(index) => index,
+          None => {
+            views.push(View::new(networks).with_context(line_number)?);
+            views.len() - 1
+          }
+        };
+        fields.remove(0);
+        &mut views[index].records
+      }
+      None => &mut local,
+    };
+    if fields.is_empty() {
+      continue;
+    }
+    for record in entry(&fields).with_context(line_number)? {
+      insert(records, record);
+    }
+  }
+  Ok((local, views))
+}
+
+fn insert(local: &mut Records, record: Record) {
+  let records = local.entry(LowerName::new(record.name())).or_default();
+  // Like /etc/hosts, the first line of an IP gives its reverse name.
+  let reversed = record.record_type() == RecordType::PTR
+    && records
+      .iter()
+      .any(|record| record.record_type() == RecordType::PTR);
+  if !reversed && !records.contains(&record) {
+    records.push(record);
+  }
+}
+
+/// Split a line on whitespaces until a `#` comment, double quoted strings are kept as one field
+/// without the quotes.
+fn fields(line: &str) -> Result<Vec<String>> {
+  let mut fields = vec![];
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    if c == '#' {
+      break;
+    } else if c.is_whitespace() {
+      continue;
+    }
+    let mut field = String::new();
+    if c == '"' {
+      loop {
+        match chars.next() {
+          Some('"') => break,
+          Some('\\') => field.extend(chars.next()),
+          Some(c) => field.push(c),
+          None => bail!("unterminated string"),
+        }
+      }
+    } else {
+      field.push(c);
+      while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+        field.push(c);
+      }
+    }
+    fields.push(field);
+  }
+  Ok(fields)
+}
+
+fn name(field: &str) -> Result<Name> {
+  let mut name = Name::from_str(field).with_context(|| format!("invalid name `{field}`"))?;
+  name.set_fqdn(true);
+  Ok(name)
+}
+
+fn number(field: &str) -> Result<u16> {
+  field
+    .parse()
+    .with_context(|| format!("invalid number `{field}`"))
+}
+
+fn entry(fields: &[String]) -> Result<Vec<Record>> {
+  // Hosts files may contain link-local addresses with their scope (`fe80::1%lo0`).
+  if let Some((ip, _)) = fields[0].split_once('%') {
+    if Ipv6Addr::from_str(ip).is_ok() {
+      return Ok(vec![]);
+    }
+  }
+  let record =
+    |owner: &str, rdata: RData| Ok(Record::from_rdata(name(owner)?, DEFAULT_LOCAL_TTL, rdata));
+  if let Ok(ip) = IpAddr::from_str(&fields[0]) {
+    if fields.len() < 2 {
+      bail!("missing name for {ip}");
+    }
+    let rdata = match ip {
+      IpAddr::V4(ip) => RData::A(A(ip)),
+      IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+    };
+    let mut records = fields[1..]
+      .iter()
+      .map(|owner| record(owner, rdata.clone()))
+      .collect::<Result<Vec<_>>>()?;
+    // Reverse lookups get the first name, like /etc/hosts.
+    let ptr = RData::PTR(PTR(records[0].name().clone()));
+    records.push(Record::from_rdata(Name::from(ip), DEFAULT_LOCAL_TTL, ptr));
+    return Ok(records);
+  }
+  let record = match (fields[0].to_ascii_lowercase().as_str(), &fields[1..]) {
+    ("txt", [owner, text @ ..]) if !text.is_empty() => {
+      record(owner, RData::TXT(TXT::new(text.to_vec())))
+    }
+    ("mx", [owner, preference, exchange]) => record(
+      owner,
+      RData::MX(MX::new(number(preference)?, name(exchange)?)),
+    ),
+    ("srv", [owner, priority, weight, port, target]) => record(
+      owner,
+      RData::SRV(SRV::new(
+        number(priority)?,
+        number(weight)?,
+        number(port)?,
+        name(target)?,
+      )),
+    ),
+    ("txt", _) => Err(anyhow!("expected txt <name> \"<text>\"")),
+    ("mx", _) => Err(anyhow!("expected mx <name> <preference> <exchange>")),
+    ("srv", _) => Err(anyhow!(
+      "expected srv <name> <priority> <weight> <port> <target>"
+    )),
+    (kind, _) => Err(anyhow!(
+      "unknown entry `{kind}`, expected an IP, txt, mx or srv"
+    )),
+  }?;
+  Ok(vec![record])
+}
+
+/// Record types of the local DNS files, the ones accepted in updates.
+const UPDATE_TYPES: [RecordType; 5] = [
+  RecordType::A,
+  RecordType::AAAA,
+  RecordType::TXT,
+  RecordType::MX,
+  RecordType::SRV,
+];
+
+fn check_update(zone: &LowerName, record: &Record) -> Result<(), ResponseCode> {
+  if !zone.zone_of(&record.name().into()) {
+    return Err(ResponseCode::NotZone);
+  }
+  let empty = matches!(record.data(), None | Some(RData::NULL(..)));
+  let supported = UPDATE_TYPES.contains(&record.record_type());
+  match record.dns_class() {
+    DNSClass::IN if supported && !empty => Ok(()),
+    DNSClass::ANY if record.ttl() == 0 && empty => {
+      if supported || record.record_type() == RecordType::ANY {
+        Ok(())
+      } else {
+        Err(ResponseCode::Refused)
+      }
+    }
+    DNSClass::NONE if supported && record.ttl() == 0 => Ok(()),
+    DNSClass::IN | DNSClass::NONE if !supported => {
+      warn!(
+        "Refusing the update of {} {}, only {:?} records can be updated",
+        record.name(),
+        record.record_type(),
+        UPDATE_TYPES
+      );
+      Err(ResponseCode::Refused)
+    }
+    _ => Err(ResponseCode::FormErr),
+  }
+}
+
+/// The content of a local DNS file where the `changed` names are replaced by their records.
+fn rewrite(content: &str, changed: &Records) -> Result<String> {
+  let is_changed =
+    |field: &str| -> Result<bool> { Ok(changed.contains_key(&LowerName::new(&name(field)?))) };
+  let mut lines = vec![];
+  for line in content.lines() {
+    let fields = fields(line)?;
+    // Lines of networks are not updated.
+    if fields.is_empty() || fields[0].starts_with('@') {
+      lines.push(line.to_string());
+    } else if IpAddr::from_str(&fields[0]).is_ok() {
+      let mut names = vec![];
+      for field in fields[1..].iter() {
+        if !is_changed(field)? {
+          names.push(field.as_str());
+        }
+      }
+      if names.len() == fields.len() - 1 {
+        lines.push(line.to_string());
+      } else if !names.is_empty() {
+        lines.push(format!("{} {}", fields[0], names.join(" ")));
+      }
+    } else if fields.len() < 2 || !is_changed(&fields[1])? {
+      lines.push(line.to_string());
+    }
+  }
+  let mut names: Vec<&LowerName> = changed.keys().collect();
+  names.sort();
+  for name in names {
+    lines.extend(changed[name].iter().filter_map(format_record));
+  }
+  lines.push(String::new());
+  Ok(lines.join("\n"))
+}
+
+/// A record written in the syntax of the local DNS files.
+fn format_record(record: &Record) -> Option<String> {
+  let name = record.name();
+  let line = match record.data()? {
+    RData::A(A(ip)) => format!("{ip} {name}"),
+    RData::AAAA(AAAA(ip)) => format!("{ip} {name}"),
+    RData::TXT(txt) => {
+      let texts: Vec<String> = txt
+        .iter()
+        .map(|text| {
+          let text = String::from_utf8_lossy(text);
+          format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+        })
+        .collect();
+      format!("txt {name} {}", texts.join(" "))
+    }
+    RData::MX(mx) => format!("mx {name} {} {}", mx.preference(), mx.exchange()),
+    RData::SRV(srv) => format!(
+      "srv {name} {} {} {} {}",
+      srv.priority(),
+      srv.weight(),
+      srv.port(),
+      srv.target()
+    ),
+    _ => return None,
+  };
+  Some(line)
+}
+
+/// Applies the dynamic updates (RFC 2136) of the local zones sent by the allowed clients. With
+/// keys, the updates must be signed with one of them (TSIG) and the responses are signed.
+pub struct LocalDnsUpdater {
+  local: Arc<LocalDns>,
+  zones: Vec<LowerName>,
+  clients: IpRangeVec,
+  keys: Vec<TsigKey>,
+}
+
+impl LocalDnsUpdater {
+  pub fn new(
+    local: Arc<LocalDns>,
+    zones: Vec<LowerName>,
+    clients: IpRangeVec,
+    keys: Vec<TsigKey>,
+  ) -> Self {
+    Self {
+      local,
+      zones,
+      clients,
+      keys,
+    }
+  }
+
+  pub async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    mut response_handle: R,
+  ) -> ResponseInfo {
+    let signature = self.verify(request);
+    let response_code = match &signature {
+      Signature::Invalid(response_code, _) => *response_code,
+      _ => match self.update(request) {
+        Ok(()) => ResponseCode::NoError,
+        Err(response_code) => response_code,
+      },
+    };
+    let mut header = Header::response_from_request(request.header());
+    header.set_response_code(response_code);
+    let response = MessageResponseBuilder::from_message_request(request);
+    let tsig = match signature {
+      Signature::Unsigned => None,
+      Signature::Valid(tsig, key) => Some(sign_response(key, &tsig, request, header)),
+      Signature::Invalid(_, tsig) => tsig,
+    };
+    let record = tsig.map(|tsig| tsig.record());
+    let result = response_handle
+      .send_response(response.build(header, &[], &[], &[], record.as_slice()))
+      .await;
+    result.unwrap_or_else(|err| {
+      error!("failed to send response: {}", err);
+      header.set_response_code(ResponseCode::ServFail);
+      header.into()
+    })
+  }
+
+  fn verify(&self, request: &Request) -> Signature<'_> {
+    let (message, tsig) = match tsig::split_request(request) {
+      Ok((_, None)) if self.keys.is_empty() => return Signature::Unsigned,
+      Ok((_, None)) => {
+        warn!("Refusing the unsigned update from {}", request.src());
+        return Signature::Invalid(ResponseCode::Refused, None);
+      }
+      Ok((message, Some(tsig))) => (message, tsig),
+      Err(err) => {
+        warn!("Invalid update from {}: {:#}", request.src(), err);
+        return Signature::Invalid(ResponseCode::FormErr, None);
+      }
+    };
+    let key = self.keys.iter().find(|key| key.name == tsig.key);
+    let verified = match key {
+      Some(key) => key.verify(&message, &tsig, None, false).map(|()| key),
+      None => Err(tsig::BADKEY),
+    };
+    match verified {
+      Ok(key) => Signature::Valid(tsig, key),
+      Err(error) => {
+        warn!(
+          "Refusing the update from {} signed with the key {}: {}",
+          request.src(),
+          tsig.key,
+          tsig::error_name(error)
+        );
+        Signature::Invalid(ResponseCode::NotAuth, Some(tsig.error(error)))
+      }
+    }
+  }
+
+  fn update(&self, request: &Request) -> Result<(), ResponseCode> {
+    if !self.clients.contains_sock_addr(request.src()) {
+      warn!("Refusing the update from {}", request.src());
+      return Err(ResponseCode::Refused);
+    }
+    let zone = request.zone();
+    if zone.query_type() != RecordType::SOA {
+      return Err(ResponseCode::FormErr);
+    }
+    if !self.zones.contains(zone.name()) {
+      return Err(ResponseCode::NotAuth);
+    }
+    self
+      .local
+      .update(zone.name(), request.prerequisites(), request.updates())
+  }
+}
+
+/// Signature of an update. Updates failing the verification are answered with the TSIG error
+/// and without MAC.
+enum Signature<'a> {
+  Unsigned,
+  Valid(Tsig, &'a TsigKey),
+  Invalid(ResponseCode, Option<Tsig>),
+}
+
+/// Signature of the response to an update signed with `tsig`, over the header and the zone
+/// section the response is made of.
+fn sign_response(key: &TsigKey, tsig: &Tsig, request: &Request, header: Header) -> Tsig {
+  let mut response = Message::new();
+  response
+    .set_header(header)
+    .add_query(request.query().original().clone());
+  match response.to_vec() {
+    Ok(bytes) => key.sign(&bytes, Some(&tsig.mac), false),
+    Err(_) => tsig.error(tsig::BADSIG),
+  }
+}
+
+/// Answers the names of the local DNS file, the other ones are given to the wrapped authority.
+pub struct LocalDnsAuthority<A> {
+  local: Arc<LocalDns>,
+  rotate: bool,
+  next: AtomicUsize,
+  ttl: u32,
+  synthetic_zone: Arc<SyntheticZone>,
+  zones: Vec<LowerName>,
+  inner: Arc<A>,
+}
+
+impl<A> LocalDnsAuthority<A> {
+  /// With `rotate`, names with several IPs get them in a different order on each query.
+  pub fn new(local: Arc<LocalDns>, rotate: bool, inner: Arc<A>) -> Self {
+    Self {
+      local,
+      rotate,
+      next: AtomicUsize::new(0),
+      ttl: DEFAULT_LOCAL_TTL,
+      synthetic_zone: Default::default(),
+      zones: vec![],
+      inner,
+    }
+  }
+
+  /// TTL of the answered records, whatever their source.
+  pub fn ttl(mut self, ttl: u32) -> Self {
+    self.ttl = ttl;
+    self
+  }
+
+  /// SOA and NS records answered for the local names without their own, and for `zones`. The
+  /// zones of the dynamic updates must be answered, the clients look for their primary server.
+  pub fn synthetic_zone(mut self, zone: Arc<SyntheticZone>, zones: Vec<LowerName>) -> Self {
+    self.synthetic_zone = zone;
+    self.zones = zones;
+    self
+  }
+
+  fn rotation(&self) -> Option<&AtomicUsize> {
+    self.rotate.then_some(&self.next)
+  }
+
+  /// Answer of a local name, `None` when the name is not local.
+  fn answer(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    client: Option<IpAddr>,
+  ) -> Option<Result<ForwardLookup, LookupError>> {
+    let answer = self
+      .local
+      .answer(name, query_type, self.rotation(), client, self.ttl);
+    // Local names are the apex of their own zone when they have no SOA or NS records.
+    if matches!(answer, Some(Err(_))) || (answer.is_none() && self.zones.contains(name)) {
+      if let Some(lookup) = self.synthetic_zone.answer(name, query_type, self.ttl) {
+        return Some(Ok(lookup));
+      }
+    }
+    answer
+  }
+}
+
+#[async_trait::async_trait]
+impl<A: Authority<Lookup = ForwardLookup>> Authority for LocalDnsAuthority<A> {
+  type Lookup = ForwardLookup;
+
+  fn zone_type(&self) -> ZoneType {
+    self.inner.zone_type()
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    self.inner.is_axfr_allowed()
+  }
+
+  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+    self.inner.update(update).await
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.inner.origin()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    match self.answer(name, query_type, None) {
+      Some(answer) => answer,
+      None => self.inner.lookup(name, query_type, lookup_options).await,
+    }
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let query = request_info.query;
+    let client = Some(request_info.src.ip());
+    match self.answer(query.name(), query.query_type(), client) {
+      Some(answer) => answer,
+      None => self.inner.search(request_info, lookup_options).await,
+    }
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.get_nsec_records(name, lookup_options).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::rr::rdata::NS;
+
+  const LOCAL: &str = r#"
+# LAN hosts
+192.168.1.10 nas.lan NAS.home
+fd00::10     nas.lan
+txt _acme-challenge.example.lan "token # with spaces" second
+TXT example.lan "v=spf1 mx -all"  # trailing comment
+mx example.lan 10 mail.example.lan
+srv _ldap._tcp.example.lan 0 0 389 dc1.example.lan
+"#;
+
+  fn types(local: &LocalDns, name: &str) -> Vec<RecordType> {
+    local
+      .find(&LowerName::from_str(name).unwrap())
+      .unwrap_or_default()
+      .iter()
+      .map(Record::record_type)
+      .collect()
+  }
+
+  #[test]
+  fn local_dns() {
+    let local = LocalDns::parse(LOCAL).unwrap();
+    assert_eq!(types(&local, "nas.lan."), [RecordType::A, RecordType::AAAA]);
+    assert_eq!(types(&local, "nas.home."), [RecordType::A]);
+    assert_eq!(
+      types(&local, "example.lan."),
+      [RecordType::TXT, RecordType::MX]
+    );
+    assert_eq!(types(&local, "_ldap._tcp.example.lan."), [RecordType::SRV]);
+    assert!(types(&local, "www.example.lan.").is_empty());
+
+    let name = LowerName::from_str("_acme-challenge.example.lan.").unwrap();
+    let records = local.find(&name).unwrap();
+    let Some(RData::TXT(txt)) = records[0].data() else {
+      panic!("expected a TXT record");
+    };
+    assert_eq!(
+      txt.iter().map(|text| &text[..]).collect::<Vec<_>>(),
+      [&b"token # with spaces"[..], b"second"]
+    );
+
+    assert!(LocalDns::parse("192.168.1.10").is_err());
+    assert!(LocalDns::parse("mx example.lan mail.example.lan").is_err());
+    assert!(LocalDns::parse("txt example.lan \"unterminated").is_err());
+    assert!(LocalDns::parse("cname www.lan nas.lan").is_err());
+  }
+
+  #[test]
+  fn reverse_names() {
+    let local = LocalDns::parse(&format!("{LOCAL}\n192.168.1.10 other.lan")).unwrap();
+    let ptr = |name: &str| -> Vec<String> {
+      local
+        .find(&LowerName::from_str(name).unwrap())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|record| record.data().map(ToString::to_string))
+        .collect()
+    };
+    assert_eq!(ptr("10.1.168.192.in-addr.arpa."), ["nas.lan."]);
+    assert_eq!(
+      ptr("0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa."),
+      ["nas.lan."]
+    );
+  }
+
+  #[test]
+  fn networks() {
+    let local = LocalDns::parse(
+      "192.168.1.20 service.lan\ntxt service.lan \"all\"\n@100.64.0.0/10,fd7a::/48 100.64.0.5 service.lan",
+    )
+    .unwrap();
+    let name = LowerName::from_str("service.lan.").unwrap();
+    let answer = |client: Option<&str>| -> Vec<String> {
+      local
+        .find_for(&name, client.map(|ip| ip.parse().unwrap()))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|record| record.data().map(ToString::to_string))
+        .collect()
+    };
+    assert_eq!(answer(Some("100.64.1.2")), ["100.64.0.5", "all"]);
+    assert_eq!(answer(Some("::ffff:100.64.1.2")), ["100.64.0.5", "all"]);
+    assert_eq!(answer(Some("192.168.1.2")), ["192.168.1.20", "all"]);
+    assert_eq!(answer(None), ["192.168.1.20", "all"]);
+
+    let ptr = LowerName::from_str("5.0.64.100.in-addr.arpa.").unwrap();
+    assert!(local.find(&ptr).is_none());
+    assert!(local
+      .find_for(&ptr, Some("100.64.1.2".parse().unwrap()))
+      .is_some());
+    assert!(LocalDns::parse("@100.64.0.0/33 100.64.0.5 service.lan").is_err());
+  }
+
+  #[test]
+  fn precedence() {
+    let mut records = parse("192.168.1.10 nas.lan\ntxt nas.lan \"first\"").unwrap();
+    merge(
+      &mut records,
+      parse("192.168.1.20 nas.lan\nfd00::20 nas.lan\n127.0.0.1 localhost\nfe80::1%lo0 localhost")
+        .unwrap(),
+    );
+    let data = |name: &str| -> Vec<String> {
+      records[&LowerName::from_str(name).unwrap()]
+        .iter()
+        .filter_map(|record| record.data().map(ToString::to_string))
+        .collect()
+    };
+    assert_eq!(data("nas.lan."), ["192.168.1.10", "first", "fd00::20"]);
+    assert_eq!(data("localhost."), ["127.0.0.1"]);
+    assert_eq!(data("20.1.168.192.in-addr.arpa."), ["nas.lan."]);
+  }
+
+  #[test]
+  fn dynamic_update() {
+    let path = std::env::temp_dir().join(format!("local-dns-update-{}", std::process::id()));
+    std::fs::write(
+      &path,
+      "# LAN\n192.168.1.10 nas.lan files.lan # NAS\ntxt nas.lan \"backup\"\n",
+    )
+    .unwrap();
+    let local = LocalDns::load(&[LocalSource::File(path.clone())]).unwrap();
+    let zone = LowerName::from_str("lan.").unwrap();
+    let record = |name: &str, class, ttl, data: Option<RData>| {
+      let name = Name::from_str(name).unwrap();
+      let mut record = match data {
+        Some(data) => Record::from_rdata(name, ttl, data),
+        None => Record::with(name, RecordType::A, ttl),
+      };
+      record.set_dns_class(class);
+      record
+    };
+    let laptop = record(
+      "laptop.lan.",
+      DNSClass::IN,
+      60,
+      Some(RData::A(A::new(192, 168, 1, 50))),
+    );
+    let not_in_use = record("laptop.lan.", DNSClass::NONE, 0, None);
+
+    local
+      .update(
+        &zone,
+        std::slice::from_ref(&not_in_use),
+        std::slice::from_ref(&laptop),
+      )
+      .unwrap();
+    assert_eq!(types(&local, "laptop.lan."), [RecordType::A]);
+    assert_eq!(
+      types(&local, "50.1.168.192.in-addr.arpa."),
+      [RecordType::PTR]
+    );
+    assert_eq!(
+      local.update(&zone, &[not_in_use], &[laptop]),
+      Err(ResponseCode::YXRRSet)
+    );
+
+    let delete_nas = record("nas.lan.", DNSClass::ANY, 0, None);
+    local.update(&zone, &[], &[delete_nas]).unwrap();
+    assert_eq!(types(&local, "nas.lan."), [RecordType::TXT]);
+    assert_eq!(
+      std::fs::read_to_string(&path).unwrap(),
+      "# LAN\n192.168.1.10 files.lan\n192.168.1.50 laptop.lan.\ntxt nas.lan. \"backup\"\n"
+    );
+
+    let outside = record(
+      "example.com.",
+      DNSClass::IN,
+      60,
+      Some(RData::A(A::new(192, 168, 1, 60))),
+    );
+    assert_eq!(
+      local.update(&zone, &[], &[outside]),
+      Err(ResponseCode::NotZone)
+    );
+    let ns = record(
+      "lan.",
+      DNSClass::IN,
+      60,
+      Some(RData::NS(NS(Name::from_str("ns.lan.").unwrap()))),
+    );
+    assert_eq!(local.update(&zone, &[], &[ns]), Err(ResponseCode::Refused));
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn refresh() {
+    let path = std::env::temp_dir().join(format!("local-dns-{}", std::process::id()));
+    std::fs::write(&path, "192.168.1.10 nas.lan").unwrap();
+    let local = LocalDns::load(&[LocalSource::File(path.clone())]).unwrap();
+    assert!(!local.refresh().unwrap());
+
+    let modified = SystemTime::now() + Duration::from_secs(1);
+    std::fs::write(&path, "192.168.1.11 printer.lan").unwrap();
+    std::fs::File::options()
+      .write(true)
+      .open(&path)
+      .and_then(|file| file.set_modified(modified))
+      .unwrap();
+    assert!(local.refresh().unwrap());
+    assert!(types(&local, "nas.lan.").is_empty());
+    assert_eq!(types(&local, "printer.lan."), [RecordType::A]);
+
+    std::fs::write(&path, "192.168.1.12").unwrap();
+    std::fs::File::options()
+      .write(true)
+      .open(&path)
+      .and_then(|file| file.set_modified(modified + Duration::from_secs(1)))
+      .unwrap();
+    assert!(local.refresh().is_err());
+    assert_eq!(types(&local, "printer.lan."), [RecordType::A]);
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn answers() {
+    let local = LocalDns::parse(LOCAL).unwrap();
+    let answer = |name: &str, query_type| {
+      local
+        .answer(
+          &LowerName::from_str(name).unwrap(),
+          query_type,
+          None,
+          None,
+          DEFAULT_LOCAL_TTL,
+        )
+        .map(|answer| answer.map(|lookup| lookup.0.records().len()))
+    };
+    assert!(matches!(
+      answer("example.lan.", RecordType::MX),
+      Some(Ok(1))
+    ));
+    assert!(matches!(
+      answer("example.lan.", RecordType::ANY),
+      Some(Ok(2))
+    ));
+    assert!(matches!(
+      answer("example.lan.", RecordType::A),
+      Some(Err(LookupError::ResponseCode(ResponseCode::NoError)))
+    ));
+    assert!(answer("example.com.", RecordType::A).is_none());
+
+    let local = LocalDns::parse("10.0.0.1 web.lan\n10.0.0.2 web.lan\n10.0.0.3 web.lan").unwrap();
+    let next = AtomicUsize::new(0);
+    let first = |local: &LocalDns| {
+      let name = LowerName::from_str("web.lan.").unwrap();
+      let Some(Ok(lookup)) = local.answer(&name, RecordType::A, Some(&next), None, 60) else {
+        panic!("expected an answer");
+      };
+      assert_eq!(lookup.0.records().len(), 3);
+      assert_eq!(lookup.0.records()[0].ttl(), 60);
+      lookup.0.records()[0].data().unwrap().to_string()
+    };
+    assert_eq!(first(&local), "10.0.0.1");
+    assert_eq!(first(&local), "10.0.0.2");
+    assert_eq!(first(&local), "10.0.0.3");
+    assert_eq!(first(&local), "10.0.0.1");
+  }
+}
@@ -0,0 +1,74 @@
+use hickory_server::proto::rr::LowerName;
+use std::str::FromStr;
+
+/// Zones that only make sense on the local network, answered NXDOMAIN instead of being
+/// forwarded: the reverse zones of the private and special addresses (RFC 6303), the local
+/// names of home networks (RFC 8375) and multicast DNS (RFC 6762).
+const LOCAL_ONLY_ZONES: &[&str] = &[
+  // RFC 1918
+  "10.in-addr.arpa.",
+  "16.172.in-addr.arpa.",
+  "17.172.in-addr.arpa.",
+  "18.172.in-addr.arpa.",
+  "19.172.in-addr.arpa.",
+  "20.172.in-addr.arpa.",
+  "21.172.in-addr.arpa.",
+  "22.172.in-addr.arpa.",
+  "23.172.in-addr.arpa.",
+  "24.172.in-addr.arpa.",
+  "25.172.in-addr.arpa.",
+  "26.172.in-addr.arpa.",
+  "27.172.in-addr.arpa.",
+  "28.172.in-addr.arpa.",
+  "29.172.in-addr.arpa.",
+  "30.172.in-addr.arpa.",
+  "31.172.in-addr.arpa.",
+  "168.192.in-addr.arpa.",
+  // RFC 5735, this network, loopback, link local, documentation and broadcast
+  "0.in-addr.arpa.",
+  "127.in-addr.arpa.",
+  "254.169.in-addr.arpa.",
+  "2.0.192.in-addr.arpa.",
+  "100.51.198.in-addr.arpa.",
+  "113.0.203.in-addr.arpa.",
+  "255.255.255.255.in-addr.arpa.",
+  // RFC 4291, unspecified and loopback addresses
+  "0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa.",
+  "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa.",
+  // RFC 4193, unique local addresses
+  "d.f.ip6.arpa.",
+  // RFC 4291, link local addresses
+  "8.e.f.ip6.arpa.",
+  "9.e.f.ip6.arpa.",
+  "a.e.f.ip6.arpa.",
+  "b.e.f.ip6.arpa.",
+  // RFC 3849, documentation
+  "8.b.d.0.1.0.0.2.ip6.arpa.",
+  "home.arpa.",
+  "local.",
+];
+
+/// Built-in list of the local-only zones.
+pub fn local_only_zones() -> Vec<LowerName> {
+  LOCAL_ONLY_ZONES
+    .iter()
+    .map(|zone| LowerName::from_str(zone).unwrap())
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::domain_set::DomainSet;
+
+  #[test]
+  fn built_in_zones() {
+    let zones: DomainSet = local_only_zones().into_iter().collect();
+    let name = |name: &str| LowerName::from_str(name).unwrap();
+    assert!(zones.contains_zone(&name("1.1.168.192.in-addr.arpa.")));
+    assert!(zones.contains_zone(&name("4.3.2.1.31.172.in-addr.arpa.")));
+    assert!(zones.contains_zone(&name("printer.home.arpa.")));
+    assert!(!zones.contains_zone(&name("1.32.172.in-addr.arpa.")));
+    assert!(!zones.contains_zone(&name("example.com.")));
+  }
+}
@@ -1,36 +1,96 @@
+use crate::nat64::Nat64Prefix;
 use hickory_client::rr::rdata::AAAA;
 use hickory_server::{
-  proto::rr::{rdata::A, RData, Record, RecordType},
+  authority::{Authority, LookupOptions},
+  proto::{
+    op::Query,
+    rr::{
+      rdata::{A, CNAME},
+      Name, RData, Record, RecordType,
+    },
+  },
   resolver::lookup::Lookup as ResolverLookup,
   server::RequestInfo,
   store::forwarder::ForwardLookup,
 };
-use std::{
-  net::{Ipv4Addr, Ipv6Addr},
-  sync::Arc,
-};
+use std::{net::Ipv4Addr, sync::Arc};
 mod blacklist;
+mod doh_bypass;
+mod filter_aaaa;
+mod leases;
+mod local;
+mod local_only;
 mod none;
+mod rewrite;
+mod rpz;
+mod safe_search;
+mod secondary;
+mod synthetic;
+mod zone;
+
+pub use crate::authority::blacklist::{BlacklistAuthority, Blocklist};
+pub use crate::authority::doh_bypass::{canary_domains, doh_servers};
+pub use crate::authority::filter_aaaa::FilterAaaaAuthority;
+pub use crate::authority::local::{
+  LocalDns, LocalDnsAuthority, LocalDnsUpdater, LocalSource, DEFAULT_LOCAL_TTL, SYSTEM_HOSTS,
+};
+pub use crate::authority::local_only::local_only_zones;
+pub use crate::authority::none::NoneAuthority;
+pub use crate::authority::rewrite::{Rewrite, RewriteAction, RewriteAuthority};
+pub use crate::authority::rpz::{Policy, ResponsePolicyZone, RpzAuthority};
+pub use crate::authority::safe_search::SafeSearchAuthority;
+pub use crate::authority::secondary::{SecondaryAuthority, SecondaryZone};
+pub use crate::authority::synthetic::SyntheticZone;
+pub use crate::authority::zone::{watch_signatures, DnssecZone, ZoneFile};
 
-pub(crate) use crate::authority::blacklist::BlacklistAuthority;
-pub(crate) use crate::authority::none::NoneAuthority;
+/// TTL of the CNAME records of the rewritten names.
+const CNAME_TTL: u32 = 300;
+/// Default TTL of the answers of the blocked names, see [`forge_ip_record`].
+pub const DEFAULT_BLOCK_TTL: u32 = 600;
 
-pub fn forge_ip_record(ip: Ipv4Addr, request_info: RequestInfo<'_>) -> ForwardLookup {
-  let mut record = Record::with(request_info.query.name().into(), RecordType::A, u32::MAX);
+pub fn forge_ip_record(ip: Ipv4Addr, ttl: u32, request_info: RequestInfo<'_>) -> ForwardLookup {
+  let mut record = Record::with(request_info.query.name().into(), RecordType::A, ttl);
   record.set_data(Some(RData::A(A(ip))));
   let lookup =
     ResolverLookup::new_with_max_ttl(request_info.query.original().clone(), Arc::new([record]));
-  return ForwardLookup(lookup);
+  ForwardLookup(lookup)
 }
 
-fn ipv4_to_prefixed_ipv6(ip: &Ipv4Addr) -> Ipv6Addr {
-  let [a, b, c, d] = ip.octets();
-  let g = ((a as u16) << 8) + (b as u16);
-  let h = ((c as u16) << 8) + (d as u16);
-  Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, g, h)
+/// Answer of `target` behind a CNAME from the queried name, `target` is resolved by `inner`.
+pub async fn cname_lookup<A: Authority<Lookup = ForwardLookup>>(
+  inner: &A,
+  request_info: &RequestInfo<'_>,
+  target: &Name,
+  lookup_options: LookupOptions,
+) -> ForwardLookup {
+  let mut answers = vec![Record::from_rdata(
+    request_info.query.name().into(),
+    CNAME_TTL,
+    RData::CNAME(CNAME(target.clone())),
+  )];
+  let mut query = Query::query(target.clone(), request_info.query.query_type());
+  query.set_query_class(request_info.query.query_class());
+  let lower_query = query.into();
+  let target_request = RequestInfo::new(
+    request_info.src,
+    request_info.protocol,
+    request_info.header,
+    &lower_query,
+  );
+  if let Ok(target_lookup) = inner.search(target_request, lookup_options).await {
+    answers.extend(target_lookup.0.records().iter().cloned());
+  }
+  ForwardLookup(ResolverLookup::new_with_max_ttl(
+    request_info.query.original().clone(),
+    answers.into(),
+  ))
 }
 
-pub fn ipv4_to_prefixed_ipv6_records(ipv4_records: ResolverLookup) -> ForwardLookup {
+/// AAAA records of the A records behind the NAT64 `prefix`.
+pub fn ipv4_to_prefixed_ipv6_records(
+  ipv4_records: ResolverLookup,
+  prefix: &Nat64Prefix,
+) -> ForwardLookup {
   let records: Vec<Record> = ipv4_records
     .records()
     .iter()
@@ -38,7 +98,7 @@ pub fn ipv4_to_prefixed_ipv6_records(ipv4_records: ResolverLookup) -> ForwardLoo
       let mut record = Record::with(r.name().clone(), r.record_type(), r.ttl());
       if let Some(data) = r.data() {
         if let Ok(a) = data.clone().into_a() {
-          record.set_data(Some(RData::AAAA(AAAA(ipv4_to_prefixed_ipv6(&a)))));
+          record.set_data(Some(RData::AAAA(AAAA(prefix.embed(a.0)))));
           record.set_record_type(RecordType::AAAA);
         } else {
           record.set_data(Some(data.clone()));
@@ -52,5 +112,5 @@ pub fn ipv4_to_prefixed_ipv6_records(ipv4_records: ResolverLookup) -> ForwardLoo
     ipv4_records.query().clone(),
     records.into_boxed_slice().into(),
   );
-  return ForwardLookup(lookup);
+  ForwardLookup(lookup)
 }
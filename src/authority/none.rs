@@ -1,4 +1,7 @@
-use crate::authority::forge_ip_record;
+use crate::{
+  authority::{forge_ip_record, DEFAULT_BLOCK_TTL},
+  events,
+};
 use hickory_server::{
   authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
   proto::{
@@ -14,6 +17,9 @@ use tracing::{info, warn};
 pub struct NoneAuthority {
   origin: LowerName,
   default_ip: Option<Ipv4Addr>,
+  list: &'static str,
+  nxdomain: bool,
+  ttl: u32,
 }
 
 impl NoneAuthority {
@@ -22,8 +28,29 @@ impl NoneAuthority {
     Self {
       origin: name,
       default_ip,
+      list: "zone blacklist",
+      nxdomain: false,
+      ttl: DEFAULT_BLOCK_TTL,
     }
   }
+
+  /// Name of the list blocking the zone, given in the Extended DNS Error.
+  pub fn list(mut self, list: &'static str) -> Self {
+    self.list = list;
+    self
+  }
+
+  /// Answer NXDOMAIN instead of the default IP or an empty response.
+  pub fn nxdomain(mut self) -> Self {
+    self.nxdomain = true;
+    self
+  }
+
+  /// TTL of the default IP answers.
+  pub fn ttl(mut self, ttl: u32) -> Self {
+    self.ttl = ttl;
+    self
+  }
 }
 
 #[async_trait::async_trait]
@@ -61,10 +88,11 @@ impl Authority for NoneAuthority {
     _lookup_options: LookupOptions,
   ) -> Result<Self::Lookup, LookupError> {
     warn!("Domain name ignored {}", request_info.query.name());
-    if let Some(ip) = self.default_ip {
-      Ok(forge_ip_record(ip, request_info))
-    } else {
-      Err(LookupError::ResponseCode(ResponseCode::NoError))
+    events::set_blocked(self.list);
+    match self.default_ip {
+      _ if self.nxdomain => Err(LookupError::ResponseCode(ResponseCode::NXDomain)),
+      Some(ip) => Ok(forge_ip_record(ip, self.ttl, request_info)),
+      None => Err(LookupError::ResponseCode(ResponseCode::NoError)),
     }
   }
 
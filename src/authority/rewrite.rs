@@ -0,0 +1,348 @@
+use crate::authority::cname_lookup;
+use anyhow::{bail, Context, Result};
+use hickory_server::{
+  authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
+  proto::{
+    op::ResponseCode,
+    rr::{
+      rdata::{A, AAAA, CNAME},
+      LowerName, Name, RData, Record, RecordType,
+    },
+  },
+  resolver::lookup::Lookup as ResolverLookup,
+  server::RequestInfo,
+  store::forwarder::ForwardLookup,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::info;
+
+/// TTL of the records answered by a rewrite rule.
+const REWRITE_TTL: u32 = 300;
+
+/// What a rewrite rule answers for its names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RewriteAction {
+  /// `to=<name>`, the answer of `<name>` is returned behind a CNAME.
+  To(Name),
+  /// `answer=<ip>[,<ip>...]`, the A and AAAA records of the names, also when they are the
+  /// target of a CNAME in a forwarded answer.
+  Answer(Vec<IpAddr>),
+}
+
+/// Rule rewriting a name, or all the names of a zone with `*.zone`:
+/// `name=old.example.com;to=new.example.com` or `name=*.corp;answer=10.0.0.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+  pub name: Name,
+  pub action: RewriteAction,
+}
+
+impl Rewrite {
+  pub fn matches(&self, name: &Name) -> bool {
+    if self.name.is_wildcard() {
+      let zone = self.name.base_name();
+      name.num_labels() > zone.num_labels() && zone.zone_of(name)
+    } else {
+      self.name == *name
+    }
+  }
+
+  /// Records answering `query_type` for `name`, empty when the rule has no IP of this type.
+  fn records(ips: &[IpAddr], name: &Name, query_type: RecordType) -> Vec<Record> {
+    ips
+      .iter()
+      .filter_map(|ip| match (ip, query_type) {
+        (IpAddr::V4(ip), RecordType::A | RecordType::ANY) => Some(RData::A(A(*ip))),
+        (IpAddr::V6(ip), RecordType::AAAA | RecordType::ANY) => Some(RData::AAAA(AAAA(*ip))),
+        _ => None,
+      })
+      .map(|data| Record::from_rdata(name.clone(), REWRITE_TTL, data))
+      .collect()
+  }
+}
+
+impl FromStr for Rewrite {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut name = None;
+    let mut action = None;
+    for option in s
+      .split(';')
+      .map(str::trim)
+      .filter(|option| !option.is_empty())
+    {
+      let (key, value) = option
+        .split_once('=')
+        .with_context(|| format!("invalid rewrite option `{option}`, expected key=value"))?;
+      let value = value.trim();
+      match key.trim() {
+        "name" => {
+          let parsed =
+            Name::from_str(value).with_context(|| format!("invalid rewritten name `{value}`"))?;
+          name = Some(parsed.to_lowercase().append_domain(&Name::root())?);
+        }
+        "to" => {
+          let target =
+            Name::from_str(value).with_context(|| format!("invalid rewrite target `{value}`"))?;
+          action = Some(RewriteAction::To(target.append_domain(&Name::root())?));
+        }
+        "answer" => {
+          let ips = value
+            .split(',')
+            .map(|ip| {
+              ip.trim()
+                .parse::<IpAddr>()
+                .with_context(|| format!("invalid rewrite answer `{ip}`, expected an IP"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+          action = Some(RewriteAction::Answer(ips));
+        }
+        key => bail!("unknown rewrite option `{key}`, expected name, to or answer"),
+      }
+    }
+    let Some(name) = name else {
+      bail!("the rewrite `{s}` has no name, expected name=<name>");
+    };
+    let Some(action) = action else {
+      bail!("the rewrite `{s}` has neither to=<name> nor answer=<ip>");
+    };
+    Ok(Self { name, action })
+  }
+}
+
+impl fmt::Display for Rewrite {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = self.name.to_string();
+    write!(f, "name={}", name.trim_end_matches('.'))?;
+    match &self.action {
+      RewriteAction::To(target) => {
+        write!(f, ";to={}", target.to_string().trim_end_matches('.'))
+      }
+      RewriteAction::Answer(ips) => {
+        let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+        write!(f, ";answer={}", ips.join(","))
+      }
+    }
+  }
+}
+
+impl Serialize for Rewrite {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Rewrite {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Rewrite::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Rewrites the questions matching a rule before they reach the wrapped authority, and the A and
+/// AAAA records of its answers whose names match an `answer=` rule. The first matching rule wins.
+pub struct RewriteAuthority<A> {
+  rules: Arc<[Rewrite]>,
+  inner: Arc<A>,
+}
+
+impl<A> RewriteAuthority<A> {
+  pub fn new(rules: Arc<[Rewrite]>, inner: Arc<A>) -> Self {
+    Self { rules, inner }
+  }
+
+  fn find(&self, name: &Name) -> Option<&Rewrite> {
+    self.rules.iter().find(|rule| rule.matches(name))
+  }
+
+  /// `lookup` with the A and AAAA records of the names matching an `answer=` rule replaced.
+  fn rewrite_answers(&self, lookup: ForwardLookup) -> ForwardLookup {
+    let records = lookup.0.records();
+    let rewritten = |record: &Record| match record.record_type() {
+      RecordType::A | RecordType::AAAA => {
+        self
+          .find(record.name())
+          .and_then(|rule| match &rule.action {
+            RewriteAction::Answer(ips) => Some(ips),
+            RewriteAction::To(_) => None,
+          })
+      }
+      _ => None,
+    };
+    if !records.iter().any(|record| rewritten(record).is_some()) {
+      return lookup;
+    }
+    let mut answers: Vec<Record> = vec![];
+    for record in records {
+      let Some(ips) = rewritten(record) else {
+        answers.push(record.clone());
+        continue;
+      };
+      let done = answers.iter().any(|answer| {
+        answer.name() == record.name() && answer.record_type() == record.record_type()
+      });
+      if !done {
+        info!("Answer of {} rewritten", record.name());
+        answers.extend(Rewrite::records(ips, record.name(), record.record_type()));
+      }
+    }
+    ForwardLookup(ResolverLookup::new_with_max_ttl(
+      lookup.0.query().clone(),
+      answers.into(),
+    ))
+  }
+}
+
+#[async_trait::async_trait]
+impl<A: Authority<Lookup = ForwardLookup>> Authority for RewriteAuthority<A> {
+  type Lookup = ForwardLookup;
+
+  fn zone_type(&self) -> ZoneType {
+    self.inner.zone_type()
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    self.inner.is_axfr_allowed()
+  }
+
+  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+    self.inner.update(update).await
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.inner.origin()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.lookup(name, query_type, lookup_options).await
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    if self.rules.is_empty() {
+      return self.inner.search(request_info, lookup_options).await;
+    }
+    let name = Name::from(request_info.query.name());
+    let Some(rule) = self.find(&name) else {
+      let lookup = self.inner.search(request_info, lookup_options).await?;
+      return Ok(self.rewrite_answers(lookup));
+    };
+    info!("Domain name rewritten by {}: {}", rule, name);
+    match &rule.action {
+      RewriteAction::To(target) => {
+        // A target rewritten with its own IPs is answered without being forwarded.
+        if let Some(RewriteAction::Answer(ips)) = self.find(target).map(|rule| &rule.action) {
+          let cname = Record::from_rdata(
+            name.clone(),
+            REWRITE_TTL,
+            RData::CNAME(CNAME(target.clone())),
+          );
+          let answers = std::iter::once(cname)
+            .chain(Rewrite::records(
+              ips,
+              target,
+              request_info.query.query_type(),
+            ))
+            .collect::<Vec<_>>();
+          return Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+            request_info.query.original().clone(),
+            answers.into(),
+          )));
+        }
+        let lookup = cname_lookup(self.inner.as_ref(), &request_info, target, lookup_options).await;
+        Ok(self.rewrite_answers(lookup))
+      }
+      RewriteAction::Answer(ips) => {
+        let answers = Rewrite::records(ips, &name, request_info.query.query_type());
+        if answers.is_empty() {
+          return Err(LookupError::ResponseCode(ResponseCode::NoError));
+        }
+        Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+          request_info.query.original().clone(),
+          answers.into(),
+        )))
+      }
+    }
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.get_nsec_records(name, lookup_options).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn name(name: &str) -> Name {
+    Name::from_str(name).unwrap()
+  }
+
+  #[test]
+  fn parse_rewrites() {
+    let rewrite = Rewrite::from_str("name=Old.Example.com;to=new.example.com").unwrap();
+    assert_eq!(rewrite.name, name("old.example.com."));
+    assert_eq!(rewrite.action, RewriteAction::To(name("new.example.com.")));
+    assert_eq!(
+      rewrite.to_string(),
+      "name=old.example.com;to=new.example.com"
+    );
+
+    let rewrite = Rewrite::from_str("name=*.corp; answer=10.0.0.1, fd00::1").unwrap();
+    assert_eq!(
+      rewrite.action,
+      RewriteAction::Answer(vec![
+        "10.0.0.1".parse().unwrap(),
+        "fd00::1".parse().unwrap()
+      ])
+    );
+    assert_eq!(rewrite.to_string(), "name=*.corp;answer=10.0.0.1,fd00::1");
+
+    assert!(Rewrite::from_str("name=example.com").is_err());
+    assert!(Rewrite::from_str("to=example.com").is_err());
+    assert!(Rewrite::from_str("name=example.com;answer=10.0.0").is_err());
+    assert!(Rewrite::from_str("name=example.com;ttl=60").is_err());
+  }
+
+  #[test]
+  fn matching_names() {
+    let exact = Rewrite::from_str("name=old.example.com;to=new.example.com").unwrap();
+    assert!(exact.matches(&name("old.example.com.")));
+    assert!(exact.matches(&name("OLD.example.com.")));
+    assert!(!exact.matches(&name("www.old.example.com.")));
+    let wildcard = Rewrite::from_str("name=*.corp;answer=10.0.0.1").unwrap();
+    assert!(wildcard.matches(&name("intranet.corp.")));
+    assert!(wildcard.matches(&name("a.b.Corp.")));
+    assert!(!wildcard.matches(&name("corp.")));
+    assert!(!wildcard.matches(&name("corp.example.")));
+  }
+
+  #[test]
+  fn answer_records() {
+    let ips = ["10.0.0.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+    let host = name("intranet.corp.");
+    let records = Rewrite::records(&ips, &host, RecordType::A);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].data(), Some(&RData::A(A::new(10, 0, 0, 1))));
+    assert_eq!(Rewrite::records(&ips, &host, RecordType::AAAA).len(), 1);
+    assert_eq!(Rewrite::records(&ips, &host, RecordType::ANY).len(), 2);
+    assert!(Rewrite::records(&ips, &host, RecordType::MX).is_empty());
+  }
+}
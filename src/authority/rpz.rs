@@ -0,0 +1,322 @@
+use crate::authority::cname_lookup;
+use crate::events;
+use anyhow::{Context, Result};
+use hickory_server::{
+  authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
+  proto::{
+    op::ResponseCode,
+    rr::{rdata::CNAME, LowerName, Name, RData, Record, RecordType},
+    serialize::txt::Parser,
+  },
+  resolver::lookup::Lookup as ResolverLookup,
+  server::RequestInfo,
+  store::forwarder::ForwardLookup,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// What to do with a query matching a trigger of a response policy zone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+  /// `CNAME .`
+  NxDomain,
+  /// `CNAME *.`
+  NoData,
+  /// `CNAME rpz-passthru.`, the query is answered as if there was no policy.
+  Passthru,
+  /// `CNAME rpz-drop.`, the listeners always send a response so it is an empty one.
+  Drop,
+  /// `CNAME <name>`, the answer of `<name>` is returned behind a CNAME.
+  Rewrite(Name),
+  /// Any other records, returned instead of the real answer.
+  LocalData(Vec<Record>),
+}
+
+impl fmt::Display for Policy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Policy::NxDomain => write!(f, "NXDOMAIN"),
+      Policy::NoData => write!(f, "NODATA"),
+      Policy::Passthru => write!(f, "PASSTHRU"),
+      Policy::Drop => write!(f, "DROP"),
+      Policy::Rewrite(target) => write!(f, "CNAME {}", target),
+      Policy::LocalData(_) => write!(f, "Local-Data"),
+    }
+  }
+}
+
+/// Policies of a Response Policy Zone, only QNAME triggers are supported.
+#[derive(Debug, Default)]
+pub struct ResponsePolicyZone {
+  exact: HashMap<LowerName, Policy>,
+  wildcards: HashMap<LowerName, Policy>,
+}
+
+impl ResponsePolicyZone {
+  pub fn load(path: &Path) -> Result<Self> {
+    let content = std::fs::read_to_string(path)
+      .with_context(|| format!("could not open {}", path.display()))?;
+    let zone = Self::parse(&content, Some(path))
+      .with_context(|| format!("invalid response policy zone {}", path.display()))?;
+    info!(
+      "Loaded {} policies from {}",
+      zone.exact.len() + zone.wildcards.len(),
+      path.display()
+    );
+    Ok(zone)
+  }
+
+  pub fn parse(content: &str, path: Option<&Path>) -> Result<Self> {
+    let (origin, records) = Parser::new(content, path.map(Path::to_path_buf), None).parse()?;
+    let origin_labels = origin.iter().count();
+
+    let mut triggers: HashMap<Name, Vec<Record>> = HashMap::new();
+    for record_set in records.values() {
+      let owner = record_set.name();
+      let labels = owner.iter().count();
+      if !origin.zone_of(owner) || labels == origin_labels {
+        continue;
+      }
+      let mut trigger = Name::from_labels(owner.iter().take(labels - origin_labels))?;
+      trigger.set_fqdn(true);
+      triggers
+        .entry(trigger)
+        .or_default()
+        .extend(record_set.records_without_rrsigs().cloned());
+    }
+
+    let mut zone = Self::default();
+    for (trigger, records) in triggers {
+      let last_label = trigger
+        .iter()
+        .next_back()
+        .map(|label| label.to_ascii_lowercase());
+      if let Some(label) = last_label
+        .as_deref()
+        .filter(|label| label.starts_with(b"rpz-"))
+      {
+        warn!(
+          "Ignoring {} trigger {}, only QNAME triggers are supported",
+          String::from_utf8_lossy(label),
+          trigger
+        );
+        continue;
+      }
+      let Some(policy) = Self::policy(&trigger, records) else {
+        continue;
+      };
+      if trigger.is_wildcard() {
+        zone
+          .wildcards
+          .insert(LowerName::new(&trigger.base_name()), policy);
+      } else {
+        zone.exact.insert(LowerName::new(&trigger), policy);
+      }
+    }
+    Ok(zone)
+  }
+
+  fn policy(trigger: &Name, records: Vec<Record>) -> Option<Policy> {
+    let cname = records.iter().find_map(|record| match record.data() {
+      Some(RData::CNAME(CNAME(target))) => Some(target.clone()),
+      _ => None,
+    });
+    let Some(target) = cname else {
+      return Some(Policy::LocalData(records));
+    };
+
+    let special = target.iter().count() == 1 && !target.is_wildcard();
+    let policy = if target.is_root() {
+      Policy::NxDomain
+    } else if target.is_wildcard() && target.iter().count() == 1 {
+      Policy::NoData
+    } else if special && target == Name::from_str("rpz-passthru.").unwrap() {
+      Policy::Passthru
+    } else if special && target == Name::from_str("rpz-drop.").unwrap() {
+      Policy::Drop
+    } else if special && target.to_ascii().starts_with("rpz-") {
+      warn!(
+        "Ignoring trigger {}, unsupported action {}",
+        trigger, target
+      );
+      return None;
+    } else {
+      Policy::Rewrite(target)
+    };
+    Some(policy)
+  }
+
+  /// Policy of the most specific trigger matching `name`, exact triggers first.
+  pub fn find(&self, name: &LowerName) -> Option<&Policy> {
+    if let Some(policy) = self.exact.get(name) {
+      return Some(policy);
+    }
+    let mut name = name.clone();
+    while !name.is_root() {
+      name = name.base_name();
+      if let Some(policy) = self.wildcards.get(&name) {
+        return Some(policy);
+      }
+    }
+    None
+  }
+}
+
+/// Applies response policy zones before the wrapped authority, the first zone with a matching
+/// trigger wins.
+pub struct RpzAuthority<A> {
+  zones: Vec<Arc<ResponsePolicyZone>>,
+  inner: Arc<A>,
+}
+
+impl<A> RpzAuthority<A> {
+  pub fn new(zones: Vec<Arc<ResponsePolicyZone>>, inner: Arc<A>) -> Self {
+    Self { zones, inner }
+  }
+}
+
+#[async_trait::async_trait]
+impl<A: Authority<Lookup = ForwardLookup>> Authority for RpzAuthority<A> {
+  type Lookup = ForwardLookup;
+
+  fn zone_type(&self) -> ZoneType {
+    self.inner.zone_type()
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    self.inner.is_axfr_allowed()
+  }
+
+  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+    self.inner.update(update).await
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.inner.origin()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.lookup(name, query_type, lookup_options).await
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let name = request_info.query.name();
+    let policy = self.zones.iter().find_map(|zone| zone.find(name));
+    let Some(policy) = policy.filter(|policy| **policy != Policy::Passthru) else {
+      return self.inner.search(request_info, lookup_options).await;
+    };
+
+    warn!(
+      "Domain name rewritten by response policy {} {}",
+      policy, name
+    );
+    events::set_blocked("response policy zone");
+    let query_type = request_info.query.query_type();
+    match policy {
+      Policy::NxDomain => Err(LookupError::ResponseCode(ResponseCode::NXDomain)),
+      Policy::NoData | Policy::Drop => Err(LookupError::ResponseCode(ResponseCode::NoError)),
+      Policy::Passthru => unreachable!(),
+      Policy::LocalData(records) => {
+        let answers: Vec<Record> = records
+          .iter()
+          .filter(|record| {
+            record.record_type() == query_type
+              || record.record_type() == RecordType::CNAME
+              || query_type == RecordType::ANY
+          })
+          .map(|record| {
+            let mut record = record.clone();
+            record.set_name(name.into());
+            record
+          })
+          .collect();
+        if answers.is_empty() {
+          return Err(LookupError::ResponseCode(ResponseCode::NoError));
+        }
+        Ok(lookup(&request_info, answers))
+      }
+      Policy::Rewrite(target) => {
+        Ok(cname_lookup(self.inner.as_ref(), &request_info, target, lookup_options).await)
+      }
+    }
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.get_nsec_records(name, lookup_options).await
+  }
+}
+
+fn lookup(request_info: &RequestInfo<'_>, records: Vec<Record>) -> ForwardLookup {
+  ForwardLookup(ResolverLookup::new_with_max_ttl(
+    request_info.query.original().clone(),
+    records.into(),
+  ))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const ZONE: &str = "
+$ORIGIN rpz.example.
+$TTL 300
+@ SOA localhost. root.localhost. 1 3600 600 86400 300
+@ NS localhost.
+nxdomain.test CNAME .
+*.nxdomain.test CNAME .
+nodata.test CNAME *.
+allowed.nxdomain.test CNAME rpz-passthru.
+drop.test CNAME rpz-drop.
+rewrite.test CNAME safe.example.com.
+local.test A 10.0.0.1
+local.test TXT \"blocked\"
+32.1.0.0.10.rpz-ip CNAME .
+";
+
+  fn find(zone: &ResponsePolicyZone, name: &str) -> Option<Policy> {
+    zone.find(&LowerName::from_str(name).unwrap()).cloned()
+  }
+
+  #[test]
+  fn policies() {
+    let zone = ResponsePolicyZone::parse(ZONE, None).unwrap();
+    assert_eq!(find(&zone, "nxdomain.test."), Some(Policy::NxDomain));
+    assert_eq!(find(&zone, "www.nxdomain.test."), Some(Policy::NxDomain));
+    assert_eq!(
+      find(&zone, "allowed.nxdomain.test."),
+      Some(Policy::Passthru)
+    );
+    assert_eq!(find(&zone, "nodata.test."), Some(Policy::NoData));
+    assert_eq!(find(&zone, "www.nodata.test."), None);
+    assert_eq!(find(&zone, "drop.test."), Some(Policy::Drop));
+    assert_eq!(
+      find(&zone, "rewrite.test."),
+      Some(Policy::Rewrite(
+        Name::from_str("safe.example.com.").unwrap()
+      ))
+    );
+    let Some(Policy::LocalData(records)) = find(&zone, "local.test.") else {
+      panic!("expected local data");
+    };
+    assert_eq!(records.len(), 2);
+    assert_eq!(find(&zone, "rpz.example."), None);
+    assert_eq!(find(&zone, "32.1.0.0.10.rpz-ip."), None);
+  }
+}
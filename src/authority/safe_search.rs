@@ -0,0 +1,134 @@
+use crate::authority::cname_lookup;
+use hickory_server::{
+  authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
+  proto::rr::{LowerName, Name, RecordType},
+  server::RequestInfo,
+  store::forwarder::ForwardLookup,
+};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+const GOOGLE: &str = "forcesafesearch.google.com.";
+const YOUTUBE: &str = "restrict.youtube.com.";
+const BING: &str = "strict.bing.com.";
+const DUCKDUCKGO: &str = "safe.duckduckgo.com.";
+
+/// Names of the search engines answered with their safe search servers.
+const REWRITES: &[(&str, &str)] = &[
+  ("www.youtube.com.", YOUTUBE),
+  ("m.youtube.com.", YOUTUBE),
+  ("youtubei.googleapis.com.", YOUTUBE),
+  ("youtube.googleapis.com.", YOUTUBE),
+  ("www.youtube-nocookie.com.", YOUTUBE),
+  ("bing.com.", BING),
+  ("www.bing.com.", BING),
+  ("duckduckgo.com.", DUCKDUCKGO),
+  ("www.duckduckgo.com.", DUCKDUCKGO),
+  ("start.duckduckgo.com.", DUCKDUCKGO),
+];
+
+/// Safe search server of a search engine name, if any.
+fn safe_search_target(name: &LowerName) -> Option<&'static str> {
+  let name = Name::from(name).to_lowercase().to_ascii();
+  if let Some((_, target)) = REWRITES.iter().find(|(source, _)| *source == name) {
+    return Some(target);
+  }
+  // google.<tld>, google.com.<tld> and google.co.<tld>, with or without www.
+  let name = name.strip_prefix("www.").unwrap_or(&name);
+  let labels: Vec<&str> = name.trim_end_matches('.').split('.').collect();
+  match labels[..] {
+    ["google", _] => Some(GOOGLE),
+    ["google", "com" | "co", _] => Some(GOOGLE),
+    _ => None,
+  }
+}
+
+/// Answers the names of the search engines with their safe search servers behind a CNAME, the
+/// servers are resolved by the wrapped authority.
+pub struct SafeSearchAuthority<A> {
+  enabled: bool,
+  inner: Arc<A>,
+}
+
+impl<A> SafeSearchAuthority<A> {
+  pub fn new(enabled: bool, inner: Arc<A>) -> Self {
+    Self { enabled, inner }
+  }
+}
+
+#[async_trait::async_trait]
+impl<A: Authority<Lookup = ForwardLookup>> Authority for SafeSearchAuthority<A> {
+  type Lookup = ForwardLookup;
+
+  fn zone_type(&self) -> ZoneType {
+    self.inner.zone_type()
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    self.inner.is_axfr_allowed()
+  }
+
+  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+    self.inner.update(update).await
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.inner.origin()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.lookup(name, query_type, lookup_options).await
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let target = match self.enabled {
+      true => safe_search_target(request_info.query.name()),
+      false => None,
+    };
+    let Some(target) = target else {
+      return self.inner.search(request_info, lookup_options).await;
+    };
+    debug!("Safe search {} -> {}", request_info.query.name(), target);
+    let target = Name::from_str(target).unwrap();
+    Ok(cname_lookup(self.inner.as_ref(), &request_info, &target, lookup_options).await)
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    self.inner.get_nsec_records(name, lookup_options).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn safe_search_targets() {
+    let target = |name: &str| safe_search_target(&LowerName::from_str(name).unwrap());
+    assert_eq!(target("www.google.com."), Some(GOOGLE));
+    assert_eq!(target("Google.FR."), Some(GOOGLE));
+    assert_eq!(target("www.google.co.uk."), Some(GOOGLE));
+    assert_eq!(target("www.google.com.br."), Some(GOOGLE));
+    assert_eq!(target("www.youtube.com."), Some(YOUTUBE));
+    assert_eq!(target("www.bing.com."), Some(BING));
+    assert_eq!(target("duckduckgo.com."), Some(DUCKDUCKGO));
+    assert_eq!(target("mail.google.com."), None);
+    assert_eq!(target("google.evil.example."), None);
+    assert_eq!(target("forcesafesearch.google.com."), None);
+    assert_eq!(target("example.com."), None);
+  }
+}
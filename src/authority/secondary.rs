@@ -0,0 +1,565 @@
+use crate::tsig::{TransferVerifier, TsigKey};
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_server::{
+  authority::{
+    AuthLookup, Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType,
+  },
+  proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{LowerName, Name, RData, Record, RecordSet, RecordType, RrKey},
+  },
+  server::RequestInfo,
+  store::in_memory::InMemoryAuthority,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{hash_map::RandomState, BTreeMap};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// Port of the primaries written without port.
+const DNS_PORT: u16 = 53;
+
+/// Time to wait for the connection and for each message of a transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time between the attempts until the zone is transferred for the first time.
+const FIRST_TRANSFER_RETRY: Duration = Duration::from_secs(60);
+
+/// Lower bound of the SOA refresh and retry timers.
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// Zone transferred from a primary as written on the command line: `zone:ip[:port][;key=name]`,
+/// the transfers being signed with the TSIG key `name`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecondaryZone {
+  pub origin: Name,
+  pub primary: SocketAddr,
+  pub key: Option<Name>,
+}
+
+impl FromStr for SecondaryZone {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (s, key) = match s.split_once(';') {
+      Some((s, option)) => {
+        let key = option
+          .trim()
+          .strip_prefix("key=")
+          .ok_or_else(|| anyhow!("expected key=name, got `{option}`"))?;
+        let key = Name::from_str(key).with_context(|| format!("invalid key name `{key}`"))?;
+        (s, Some(key))
+      }
+      None => (s, None),
+    };
+    let (origin, primary) = s
+      .split_once(':')
+      .ok_or_else(|| anyhow!("expected zone:primary, got `{s}`"))?;
+    let mut origin =
+      Name::from_str(origin.trim()).with_context(|| format!("invalid zone name `{origin}`"))?;
+    origin.set_fqdn(true);
+    let primary = primary.trim();
+    let primary = match primary.parse::<SocketAddr>() {
+      Ok(primary) => primary,
+      Err(_) => {
+        let ip = primary.trim_start_matches('[').trim_end_matches(']');
+        let ip = IpAddr::from_str(ip).with_context(|| format!("invalid primary `{primary}`"))?;
+        SocketAddr::new(ip, DNS_PORT)
+      }
+    };
+    Ok(Self {
+      origin,
+      primary,
+      key,
+    })
+  }
+}
+
+impl fmt::Display for SecondaryZone {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.origin, self.primary)?;
+    match &self.key {
+      Some(key) => write!(f, ";key={key}"),
+      None => Ok(()),
+    }
+  }
+}
+
+impl Serialize for SecondaryZone {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for SecondaryZone {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    SecondaryZone::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Records of a transferred zone.
+struct Zone {
+  authority: Arc<InMemoryAuthority>,
+  records: Vec<Record>,
+  serial: u32,
+  refresh: Duration,
+  retry: Duration,
+  expire: Duration,
+  /// Last time the primary confirmed the records.
+  checked: Instant,
+}
+
+impl Zone {
+  fn new(origin: &Name, records: Vec<Record>) -> Result<Self> {
+    let soa = records
+      .iter()
+      .find_map(|record| record.data().and_then(RData::as_soa))
+      .context("the zone has no SOA")?
+      .clone();
+    let serial = soa.serial();
+    let mut sets: BTreeMap<RrKey, RecordSet> = BTreeMap::new();
+    for record in records.iter() {
+      sets
+        .entry(RrKey::new(record.name().into(), record.record_type()))
+        .or_insert_with(|| RecordSet::new(record.name(), record.record_type(), serial))
+        .insert(record.clone(), serial);
+    }
+    let authority = InMemoryAuthority::new(origin.clone(), sets, ZoneType::Secondary, false)
+      .map_err(|err| anyhow!(err))?;
+    let seconds = |value: i32| Duration::from_secs(value.max(0) as u64);
+    Ok(Self {
+      authority: Arc::new(authority),
+      records,
+      serial,
+      refresh: seconds(soa.refresh()).max(MIN_REFRESH),
+      retry: seconds(soa.retry()).max(MIN_REFRESH),
+      expire: seconds(soa.expire()),
+      checked: Instant::now(),
+    })
+  }
+}
+
+/// Answers authoritatively for a zone transferred from a primary with AXFR, then kept up to date
+/// with IXFR (or AXFR when the primary does not support it) according to the SOA timers. Queries
+/// are answered SERVFAIL until the first transfer and once the zone expired.
+pub struct SecondaryAuthority {
+  origin: LowerName,
+  primary: SocketAddr,
+  key: Option<TsigKey>,
+  zone: RwLock<Option<Zone>>,
+}
+
+impl SecondaryAuthority {
+  /// Transfers signed with `key`, the one named by the zone.
+  pub fn new(zone: &SecondaryZone, key: Option<TsigKey>) -> Self {
+    Self {
+      origin: LowerName::new(&zone.origin),
+      primary: zone.primary,
+      key,
+      zone: RwLock::new(None),
+    }
+  }
+
+  /// Serial and number of records of the zone, `None` before the first transfer.
+  pub fn serial(&self) -> Option<(u32, usize)> {
+    let zone = self.zone.read().unwrap();
+    zone.as_ref().map(|zone| (zone.serial, zone.records.len()))
+  }
+
+  /// Transfer the zone from the primary, incrementally when it is already loaded.
+  pub async fn refresh(&self) -> Result<()> {
+    let origin = Name::from(&self.origin);
+    let current = {
+      let zone = self.zone.read().unwrap();
+      zone.as_ref().map(|zone| zone.records.clone())
+    };
+    let key = self.key.as_ref();
+    let transfer = match &current {
+      Some(records) => match transfer(self.primary, &origin, key, Some(records)).await {
+        Ok(transfer) => Ok(transfer),
+        Err(err) => {
+          debug!("IXFR of {} failed, trying AXFR: {:#}", origin, err);
+          transfer(self.primary, &origin, key, None).await
+        }
+      },
+      None => transfer(self.primary, &origin, key, None).await,
+    }
+    .with_context(|| format!("could not transfer {} from {}", origin, self.primary))?;
+    let mut zone = self.zone.write().unwrap();
+    match (transfer, zone.as_mut()) {
+      (Transfer::UpToDate, Some(zone)) => {
+        debug!("Zone {} is up to date (serial {})", origin, zone.serial);
+        zone.checked = Instant::now();
+      }
+      (Transfer::UpToDate, None) => bail!("the primary has no changes for an empty zone"),
+      (Transfer::Records(records), _) => {
+        let transferred = Zone::new(&origin, records)?;
+        info!(
+          "Transferred zone {} from {} (serial {}, {} records)",
+          origin,
+          self.primary,
+          transferred.serial,
+          transferred.records.len()
+        );
+        *zone = Some(transferred);
+      }
+    }
+    Ok(())
+  }
+
+  /// Refresh the zone according to its SOA timers, as long as it is used.
+  pub fn watch(self: &Arc<Self>) {
+    tokio::spawn(Self::refresh_periodically(Arc::downgrade(self)));
+  }
+
+  async fn refresh_periodically(authority: Weak<Self>) {
+    let mut delay = match authority.upgrade().map(|authority| authority.delay(true)) {
+      Some(delay) => delay,
+      None => return,
+    };
+    loop {
+      tokio::time::sleep(delay).await;
+      let Some(authority) = authority.upgrade() else {
+        return;
+      };
+      let refreshed = authority.refresh().await;
+      if let Err(err) = &refreshed {
+        warn!("{:#}", err);
+        authority.expire();
+      }
+      delay = authority.delay(refreshed.is_ok());
+    }
+  }
+
+  /// Time until the next refresh, after a successful one or a failure.
+  fn delay(&self, refreshed: bool) -> Duration {
+    let zone = self.zone.read().unwrap();
+    match (zone.as_ref(), refreshed) {
+      (Some(zone), true) => zone.refresh,
+      (Some(zone), false) => zone.retry,
+      (None, _) => FIRST_TRANSFER_RETRY,
+    }
+  }
+
+  /// Drop the zone when the primary did not answer for longer than the SOA expire timer.
+  fn expire(&self) {
+    let mut zone = self.zone.write().unwrap();
+    if zone
+      .as_ref()
+      .is_some_and(|zone| zone.checked.elapsed() > zone.expire)
+    {
+      warn!(
+        "Zone {} expired, its queries are answered SERVFAIL",
+        self.origin
+      );
+      *zone = None;
+    }
+  }
+
+  fn authority(&self) -> Result<Arc<InMemoryAuthority>, LookupError> {
+    let zone = self.zone.read().unwrap();
+    zone
+      .as_ref()
+      .map(|zone| zone.authority.clone())
+      .ok_or(LookupError::ResponseCode(ResponseCode::ServFail))
+  }
+}
+
+#[async_trait::async_trait]
+impl Authority for SecondaryAuthority {
+  type Lookup = AuthLookup;
+
+  fn zone_type(&self) -> ZoneType {
+    ZoneType::Secondary
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    false
+  }
+
+  async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+    Err(ResponseCode::NotImp)
+  }
+
+  fn origin(&self) -> &LowerName {
+    &self.origin
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let authority = self.authority()?;
+    authority.lookup(name, query_type, lookup_options).await
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let authority = self.authority()?;
+    authority.search(request_info, lookup_options).await
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Self::Lookup, LookupError> {
+    let authority = self.authority()?;
+    authority.get_nsec_records(name, lookup_options).await
+  }
+}
+
+/// Outcome of a zone transfer.
+#[derive(Debug, PartialEq)]
+enum Transfer {
+  /// The serial of the primary is the one of the zone.
+  UpToDate,
+  /// Records of the zone, SOA included.
+  Records(Vec<Record>),
+}
+
+/// Transfer `origin` from `primary` over TCP, with IXFR from the `current` records when given.
+/// With a key, the request is signed and the responses must be.
+async fn transfer(
+  primary: SocketAddr,
+  origin: &Name,
+  key: Option<&TsigKey>,
+  current: Option<&[Record]>,
+) -> Result<Transfer> {
+  let soa = current.and_then(|records| {
+    records
+      .iter()
+      .find(|record| record.record_type() == RecordType::SOA)
+  });
+  let mut message = Message::new();
+  message
+    .set_id(RandomState::new().hash_one(Instant::now()) as u16)
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .add_query(Query::query(
+      origin.clone(),
+      match soa {
+        Some(_) => RecordType::IXFR,
+        None => RecordType::AXFR,
+      },
+    ));
+  if let Some(soa) = soa {
+    message.add_name_server(soa.clone());
+  }
+  let mut request = message.to_vec()?;
+  let mut verifier = key.map(|key| {
+    let tsig = key.sign(&request, None, false);
+    tsig.append_to(&mut request);
+    TransferVerifier::new(key, &tsig)
+  });
+  let mut stream = tokio::time::timeout(TRANSFER_TIMEOUT, TcpStream::connect(primary))
+    .await
+    .context("connection timed out")??;
+  let mut buffer = (request.len() as u16).to_be_bytes().to_vec();
+  buffer.extend(request);
+  stream.write_all(&buffer).await?;
+  let mut answers = vec![];
+  loop {
+    let read = async {
+      let length = stream.read_u16().await?;
+      let mut response = vec![0; length as usize];
+      stream.read_exact(&mut response).await?;
+      if let Some(verifier) = verifier.as_mut() {
+        verifier.verify(&response)?;
+      }
+      anyhow::Ok(Message::from_vec(&response)?)
+    };
+    let mut response = tokio::time::timeout(TRANSFER_TIMEOUT, read)
+      .await
+      .context("transfer timed out")??;
+    if response.id() != message.id() {
+      bail!("the response does not match the query");
+    }
+    if response.response_code() != ResponseCode::NoError {
+      bail!("the primary answered {}", response.response_code());
+    }
+    answers.extend(response.take_answers());
+    if let Some(transfer) = read_transfer(&answers, current)? {
+      if verifier.is_some_and(|verifier| !verifier.signed()) {
+        bail!("the last message of the transfer is not signed");
+      }
+      return Ok(transfer);
+    }
+  }
+}
+
+fn soa_serial(record: &Record) -> Option<u32> {
+  record
+    .data()
+    .and_then(RData::as_soa)
+    .map(|soa| soa.serial())
+}
+
+/// Records of a complete transfer, `None` while more messages are expected. AXFR answers are the
+/// records between two SOA, IXFR answers are sequences of deleted records starting with the old
+/// SOA and of added records starting with the new one (RFC 1995).
+fn read_transfer(answers: &[Record], current: Option<&[Record]>) -> Result<Option<Transfer>> {
+  let Some(first) = answers.first() else {
+    return Ok(None);
+  };
+  let serial = soa_serial(first).context("the transfer does not start with the SOA")?;
+  match (answers.get(1).map(soa_serial), current) {
+    (None, Some(records)) => match records.iter().find_map(soa_serial) == Some(serial) {
+      true => Ok(Some(Transfer::UpToDate)),
+      false => bail!("the primary answered the IXFR without the changes"),
+    },
+    (Some(Some(old)), Some(records)) if old != serial => incremental(answers, serial, records),
+    _ => match answers.len() > 1 && answers.last().and_then(soa_serial).is_some() {
+      true => Ok(Some(Transfer::Records(
+        answers[..answers.len() - 1].to_vec(),
+      ))),
+      false => Ok(None),
+    },
+  }
+}
+
+/// Apply the changes of an IXFR answer to the `current` records, they must be the version the
+/// changes start from.
+fn incremental(answers: &[Record], serial: u32, current: &[Record]) -> Result<Option<Transfer>> {
+  let base = answers.get(1).and_then(soa_serial);
+  let current_serial = current.iter().find_map(soa_serial);
+  if base != current_serial {
+    bail!(
+      "the IXFR starts from the serial {:?}, not from the current one {:?}",
+      base,
+      current_serial
+    );
+  }
+  let same = |a: &Record, b: &Record| {
+    a.name() == b.name() && a.record_type() == b.record_type() && a.data() == b.data()
+  };
+  let mut records: Vec<Record> = current
+    .iter()
+    .filter(|record| record.record_type() != RecordType::SOA)
+    .cloned()
+    .collect();
+  // The SOA of the first sequence is the old one, its records are deleted.
+  let mut adding = true;
+  for record in answers[1..].iter() {
+    if let Some(version) = soa_serial(record) {
+      if adding && version == serial {
+        records.insert(0, answers[0].clone());
+        return Ok(Some(Transfer::Records(records)));
+      }
+      adding = !adding;
+      continue;
+    }
+    records.retain(|existing| !same(existing, record));
+    if adding {
+      records.push(record.clone());
+    }
+  }
+  Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::rr::rdata::{A, SOA};
+
+  fn soa(serial: u32) -> Record {
+    let name = Name::from_str("example.lan.").unwrap();
+    let soa = SOA::new(
+      Name::from_str("ns.example.lan.").unwrap(),
+      Name::from_str("admin.example.lan.").unwrap(),
+      serial,
+      3600,
+      600,
+      86400,
+      300,
+    );
+    Record::from_rdata(name, 3600, RData::SOA(soa))
+  }
+
+  fn a(name: &str, ip: [u8; 4]) -> Record {
+    let name = Name::from_str(name).unwrap();
+    Record::from_rdata(name, 300, RData::A(A::from(std::net::Ipv4Addr::from(ip))))
+  }
+
+  #[test]
+  fn parse_secondary_zone() {
+    let zone = SecondaryZone::from_str("example.lan:192.0.2.1").unwrap();
+    assert_eq!(zone.origin, Name::from_str("example.lan.").unwrap());
+    assert_eq!(zone.to_string(), "example.lan.:192.0.2.1:53");
+    let zone = SecondaryZone::from_str("example.lan:[2001:db8::1]:5353").unwrap();
+    assert_eq!(zone.to_string(), "example.lan.:[2001:db8::1]:5353");
+    let zone = SecondaryZone::from_str("example.lan:2001:db8::1").unwrap();
+    assert_eq!(zone.primary.port(), 53);
+    assert!(SecondaryZone::from_str("example.lan").is_err());
+    let zone = SecondaryZone::from_str("example.lan:192.0.2.1;key=transfer-key").unwrap();
+    assert_eq!(zone.key, Some(Name::from_str("transfer-key").unwrap()));
+    assert_eq!(
+      zone.to_string(),
+      "example.lan.:192.0.2.1:53;key=transfer-key"
+    );
+    assert!(SecondaryZone::from_str("example.lan:192.0.2.1;tsig=transfer-key").is_err());
+  }
+
+  #[test]
+  fn axfr_and_ixfr() {
+    let nas = a("nas.example.lan.", [192, 168, 1, 10]);
+    let www = a("www.example.lan.", [192, 168, 1, 11]);
+    let axfr = [soa(1), nas.clone(), www.clone()];
+    assert_eq!(read_transfer(&axfr, None).unwrap(), None);
+    let axfr = [soa(1), nas.clone(), www.clone(), soa(1)];
+    let Some(Transfer::Records(records)) = read_transfer(&axfr, None).unwrap() else {
+      panic!("incomplete AXFR");
+    };
+    assert_eq!(records, [soa(1), nas.clone(), www.clone()]);
+    assert!(Zone::new(&Name::from_str("example.lan.").unwrap(), records.clone()).is_ok());
+
+    assert_eq!(
+      read_transfer(&[soa(1)], Some(&records)).unwrap(),
+      Some(Transfer::UpToDate)
+    );
+    assert!(read_transfer(&[soa(2)], Some(&records)).is_err());
+
+    // www is moved in serial 2, nas deleted in serial 3.
+    let moved = a("www.example.lan.", [192, 168, 1, 12]);
+    let ixfr = [
+      soa(3),
+      soa(1),
+      www.clone(),
+      soa(2),
+      moved.clone(),
+      soa(2),
+      nas.clone(),
+      soa(3),
+    ];
+    assert_eq!(read_transfer(&ixfr, Some(&records)).unwrap(), None);
+    let ixfr = [&ixfr[..], &[soa(3)]].concat();
+    assert_eq!(
+      read_transfer(&ixfr, Some(&records)).unwrap(),
+      Some(Transfer::Records(vec![soa(3), moved]))
+    );
+
+    // Changes from another version are not applied.
+    let ixfr = [soa(3), soa(2), nas.clone(), soa(3), soa(3)];
+    assert!(read_transfer(&ixfr, Some(&records)).is_err());
+
+    // Primaries without IXFR answer the whole zone.
+    let axfr = [soa(2), www.clone(), soa(2)];
+    assert_eq!(
+      read_transfer(&axfr, Some(&records)).unwrap(),
+      Some(Transfer::Records(vec![soa(2), www]))
+    );
+  }
+}
@@ -0,0 +1,101 @@
+use hickory_server::{
+  proto::{
+    op::Query,
+    rr::{
+      rdata::{NS, SOA},
+      LowerName, Name, RData, Record, RecordType,
+    },
+  },
+  resolver::lookup::Lookup as ResolverLookup,
+  store::forwarder::ForwardLookup,
+};
+use std::str::FromStr;
+
+/// SOA and NS records of the names answered by the server itself, the blocked names and the local
+/// names, as if each one was the apex of its own zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntheticZone {
+  /// Target of the NS records and primary server of the SOA records.
+  pub ns: Name,
+  /// Mailbox of the SOA records, `hostmaster.example.com.` for `hostmaster@example.com`.
+  pub hostmaster: Name,
+}
+
+impl Default for SyntheticZone {
+  fn default() -> Self {
+    Self {
+      ns: Name::from_str("localhost.").unwrap(),
+      hostmaster: Name::from_str("nobody.localhost.").unwrap(),
+    }
+  }
+}
+
+impl SyntheticZone {
+  /// SOA of `apex`, negative answers are cached for the smallest of its TTL and its minimum
+  /// (RFC 2308) so both are `ttl`.
+  pub fn soa(&self, apex: Name, ttl: u32) -> Record {
+    let soa = SOA::new(
+      self.ns.clone(),
+      self.hostmaster.clone(),
+      // Serial, refresh, retry and expire, unused without zone transfers.
+      1,
+      3600,
+      600,
+      86400,
+      ttl,
+    );
+    Record::from_rdata(apex, ttl, RData::SOA(soa))
+  }
+
+  pub fn ns(&self, apex: Name, ttl: u32) -> Record {
+    Record::from_rdata(apex, ttl, RData::NS(NS(self.ns.clone())))
+  }
+
+  /// Answer of the SOA and NS queries of `apex`, `None` for the other types.
+  pub fn answer(
+    &self,
+    apex: &LowerName,
+    query_type: RecordType,
+    ttl: u32,
+  ) -> Option<ForwardLookup> {
+    let record = match query_type {
+      RecordType::SOA => self.soa(apex.into(), ttl),
+      RecordType::NS => self.ns(apex.into(), ttl),
+      _ => return None,
+    };
+    let query = Query::query(apex.into(), query_type);
+    Some(ForwardLookup(ResolverLookup::new_with_max_ttl(
+      query,
+      [record].into(),
+    )))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn answers() {
+    let zone = SyntheticZone {
+      ns: Name::from_str("dns.lan.").unwrap(),
+      ..Default::default()
+    };
+    let apex = LowerName::from_str("ads.example.com.").unwrap();
+    let soa = zone.answer(&apex, RecordType::SOA, 60).unwrap();
+    let record = &soa.0.records()[0];
+    assert_eq!(record.ttl(), 60);
+    let Some(RData::SOA(soa)) = record.data() else {
+      panic!("expected a SOA record");
+    };
+    assert_eq!(soa.mname().to_string(), "dns.lan.");
+    assert_eq!(soa.rname().to_string(), "nobody.localhost.");
+    assert_eq!(soa.minimum(), 60);
+    let ns = zone.answer(&apex, RecordType::NS, 60).unwrap();
+    assert_eq!(
+      ns.0.records()[0].data(),
+      Some(&RData::NS(NS(Name::from_str("dns.lan.").unwrap())))
+    );
+    assert!(zone.answer(&apex, RecordType::A, 60).is_none());
+  }
+}
@@ -0,0 +1,304 @@
+use crate::acme::{pem_decode, pem_encode, write_private};
+use anyhow::{anyhow, Context, Result};
+use hickory_server::{
+  authority::{Authority, DnssecAuthority, ZoneType},
+  proto::{
+    rr::{
+      dnssec::{rdata::DNSSECRData, Algorithm, DigestType, KeyFormat, KeyPair, Private, SigSigner},
+      Name, RData, Record,
+    },
+    serialize::txt::Parser,
+  },
+  store::in_memory::InMemoryAuthority,
+};
+use ring::signature::Ed25519KeyPair;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Validity of the DNSSEC signatures, the zones are signed again after half of it.
+const SIGNATURE_VALIDITY: Duration = Duration::from_secs(14 * 24 * 3600);
+
+/// Algorithms of the key files, the generated keys are ECDSA P-256 ones.
+const KEY_ALGORITHMS: [Algorithm; 3] = [
+  Algorithm::ECDSAP256SHA256,
+  Algorithm::ECDSAP384SHA384,
+  Algorithm::ED25519,
+];
+
+/// TTL of the DS records given for the parent zone.
+const DS_TTL: u32 = 3600;
+
+/// Local zone as written on the command line: `zone:path` of a BIND zone file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneFile {
+  pub origin: Name,
+  pub path: PathBuf,
+}
+
+impl FromStr for ZoneFile {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (origin, path) = s
+      .split_once(':')
+      .ok_or_else(|| anyhow!("expected zone:path, got `{s}`"))?;
+    let mut origin =
+      Name::from_str(origin.trim()).with_context(|| format!("invalid zone name `{origin}`"))?;
+    origin.set_fqdn(true);
+    Ok(Self {
+      origin,
+      path: path.trim().into(),
+    })
+  }
+}
+
+impl fmt::Display for ZoneFile {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.origin, self.path.display())
+  }
+}
+
+impl Serialize for ZoneFile {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for ZoneFile {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ZoneFile::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+impl ZoneFile {
+  /// Read the zone file, it must contain the SOA of the zone.
+  pub fn load(&self) -> Result<InMemoryAuthority> {
+    let content = std::fs::read_to_string(&self.path)
+      .with_context(|| format!("could not open {}", self.path.display()))?;
+    let authority = parse(&self.origin, &content, Some(&self.path))
+      .with_context(|| format!("invalid zone file {}", self.path.display()))?;
+    info!("Loaded zone {} from {}", self.origin, self.path.display());
+    Ok(authority)
+  }
+}
+
+/// Local zone signed with DNSSEC as written on the command line: `zone[:path]`, `path` being its
+/// private key, a PKCS#8 ECDSA P-256, P-384 or Ed25519 key in PEM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnssecZone {
+  pub origin: Name,
+  pub key: Option<PathBuf>,
+}
+
+impl FromStr for DnssecZone {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (origin, key) = match s.split_once(':') {
+      Some((origin, key)) => (origin, Some(key.trim().into())),
+      None => (s, None),
+    };
+    let mut origin =
+      Name::from_str(origin.trim()).with_context(|| format!("invalid zone name `{origin}`"))?;
+    origin.set_fqdn(true);
+    Ok(Self { origin, key })
+  }
+}
+
+impl fmt::Display for DnssecZone {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.key {
+      Some(key) => write!(f, "{}:{}", self.origin, key.display()),
+      None => write!(f, "{}", self.origin),
+    }
+  }
+}
+
+impl Serialize for DnssecZone {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for DnssecZone {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    DnssecZone::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+impl DnssecZone {
+  /// Sign the zone with its key, giving the DS record of the key for the parent zone or the
+  /// trust anchors of the validating resolvers.
+  pub fn sign(&self, authority: &mut InMemoryAuthority) -> Result<Record> {
+    let (key, algorithm) = self.key()?;
+    let ds = key.to_ds(&self.origin, algorithm, DigestType::SHA256)?;
+    let dnskey = key.to_dnskey(algorithm)?;
+    let signer = SigSigner::dnssec(dnskey, key, self.origin.clone(), SIGNATURE_VALIDITY);
+    authority.add_zone_signing_key_mut(signer)?;
+    authority.secure_zone_mut()?;
+    let ds = Record::from_rdata(
+      self.origin.clone(),
+      DS_TTL,
+      RData::DNSSEC(DNSSECRData::DS(ds)),
+    );
+    info!("Signed zone {}, its DS record is {}", self.origin, ds);
+    Ok(ds)
+  }
+
+  /// Key read from the key file, created when the file does not exist. Without file, a new key
+  /// is generated on each start.
+  fn key(&self) -> Result<(KeyPair<Private>, Algorithm)> {
+    let Some(path) = &self.key else {
+      warn!(
+        "Signing zone {} with a new key, its DS record changes on each start",
+        self.origin
+      );
+      return Ok((generate_key()?, KEY_ALGORITHMS[0]));
+    };
+    let pkcs8 = match std::fs::read_to_string(path) {
+      Ok(pem) => pem_decode(&pem).with_context(|| format!("invalid key {}", path.display()))?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        let pkcs8 = KeyPair::generate_pkcs8(KEY_ALGORITHMS[0])?;
+        write_private(path, &pem_encode("PRIVATE KEY", &pkcs8))?;
+        info!("Created the key {} of zone {}", path.display(), self.origin);
+        pkcs8
+      }
+      Err(err) => return Err(err).with_context(|| format!("could not read {}", path.display())),
+    };
+    KEY_ALGORITHMS
+      .into_iter()
+      .find_map(|algorithm| {
+        let key = KeyFormat::Pkcs8.decode_key(&pkcs8, None, algorithm).ok()?;
+        Some((key, algorithm))
+      })
+      .or_else(|| {
+        // openssl writes Ed25519 keys without their public key
+        let key = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8).ok()?;
+        Some((KeyPair::from_ed25519(key), Algorithm::ED25519))
+      })
+      .ok_or_else(|| {
+        anyhow!(
+          "invalid key {}, expected a PKCS#8 ECDSA P-256, P-384 or Ed25519 key",
+          path.display()
+        )
+      })
+  }
+}
+
+fn generate_key() -> Result<KeyPair<Private>> {
+  let pkcs8 = KeyPair::generate_pkcs8(KEY_ALGORITHMS[0])?;
+  Ok(KeyFormat::Pkcs8.decode_key(&pkcs8, None, KEY_ALGORITHMS[0])?)
+}
+
+/// Sign the zone again before its signatures expire, as long as it is used.
+pub fn watch_signatures(authority: &Arc<InMemoryAuthority>) {
+  tokio::spawn(sign_periodically(Arc::downgrade(authority)));
+}
+
+async fn sign_periodically(authority: Weak<InMemoryAuthority>) {
+  loop {
+    tokio::time::sleep(SIGNATURE_VALIDITY / 2).await;
+    let Some(authority) = authority.upgrade() else {
+      return;
+    };
+    match authority.secure_zone().await {
+      Ok(()) => info!("Signed zone {} again", authority.origin()),
+      Err(err) => warn!("Could not sign zone {}: {}", authority.origin(), err),
+    }
+  }
+}
+
+fn parse(origin: &Name, content: &str, path: Option<&Path>) -> Result<InMemoryAuthority> {
+  let (origin, records) =
+    Parser::new(content, path.map(Path::to_path_buf), Some(origin.clone())).parse()?;
+  InMemoryAuthority::new(origin, records, ZoneType::Primary, false).map_err(|err| anyhow!(err))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::{
+    authority::{Authority, LookupOptions},
+    proto::rr::{LowerName, RecordType},
+  };
+
+  const ZONE: &str = "
+$TTL 3600
+@ SOA ns.example.lan. admin.example.lan. 1 3600 600 86400 300
+@ NS ns
+@ MX 10 mail
+@ TXT \"v=spf1 mx -all\"
+ns A 192.168.1.1
+mail A 192.168.1.2
+www CNAME mail
+_ldap._tcp SRV 0 0 389 ns
+";
+
+  #[tokio::test]
+  async fn zone_file() {
+    let zone = ZoneFile::from_str("example.lan:/etc/db.example.lan").unwrap();
+    assert_eq!(zone.origin, Name::from_str("example.lan.").unwrap());
+    assert_eq!(zone.path, PathBuf::from("/etc/db.example.lan"));
+    assert!(ZoneFile::from_str("/etc/db.example.lan").is_err());
+
+    let authority = parse(&zone.origin, ZONE, None).unwrap();
+    let lookup = |name: &str, query_type| {
+      let name = LowerName::from_str(name).unwrap();
+      let authority = &authority;
+      async move {
+        authority
+          .lookup(&name, query_type, LookupOptions::default())
+          .await
+          .map(|lookup| lookup.iter().count())
+          .unwrap_or(0)
+      }
+    };
+    assert_eq!(lookup("example.lan.", RecordType::MX).await, 1);
+    assert_eq!(lookup("example.lan.", RecordType::TXT).await, 1);
+    assert_eq!(lookup("_ldap._tcp.example.lan.", RecordType::SRV).await, 1);
+    assert_eq!(lookup("www.example.lan.", RecordType::CNAME).await, 1);
+    assert_eq!(lookup("nope.example.lan.", RecordType::A).await, 0);
+    assert!(parse(&zone.origin, "ns A 192.168.1.1", None).is_err());
+  }
+
+  #[tokio::test]
+  async fn dnssec_zone() {
+    let path = std::env::temp_dir().join(format!("dnssec-{}.key", std::process::id()));
+    let zone = DnssecZone::from_str(&format!("example.lan:{}", path.display())).unwrap();
+    assert_eq!(zone.origin, Name::from_str("example.lan.").unwrap());
+    assert_eq!(zone.to_string(), format!("example.lan.:{}", path.display()));
+    assert_eq!(DnssecZone::from_str("example.lan").unwrap().key, None);
+
+    let mut authority = parse(&zone.origin, ZONE, None).unwrap();
+    let ds = zone.sign(&mut authority).unwrap();
+    let mut again = parse(&zone.origin, ZONE, None).unwrap();
+    assert_eq!(zone.sign(&mut again).unwrap(), ds);
+    std::fs::remove_file(&path).unwrap();
+
+    let name = LowerName::from_str("ns.example.lan.").unwrap();
+    let lookup = authority
+      .lookup(
+        &name,
+        RecordType::A,
+        LookupOptions::for_dnssec(true, Default::default()),
+      )
+      .await
+      .unwrap();
+    assert!(lookup
+      .iter()
+      .any(|record| record.record_type() == RecordType::RRSIG));
+    let name = LowerName::from_str("example.lan.").unwrap();
+    let lookup = authority
+      .lookup(&name, RecordType::DNSKEY, LookupOptions::default())
+      .await
+      .unwrap();
+    assert_eq!(lookup.iter().count(), 1);
+  }
+}
@@ -0,0 +1,201 @@
+use crate::query::{self, TlsClient, Transport};
+use anyhow::{bail, Context, Result};
+use futures_util::FutureExt;
+use hickory_server::proto::rr::{Name, RecordType};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+
+/// Load generated by [`run`].
+#[derive(Clone)]
+pub struct BenchOptions {
+  pub server: SocketAddr,
+  pub transport: Transport,
+  pub tls: Option<TlsClient>,
+  /// Queries sent in turn.
+  pub queries: Vec<(Name, RecordType)>,
+  /// Queries sent per second.
+  pub qps: u32,
+  pub duration: Duration,
+  /// Time after which a query without answer is counted as a timeout.
+  pub timeout: Duration,
+}
+
+/// Queries of a file in the format of dnsperf: a name and an optional record type per line.
+pub fn read_queries(path: &Path) -> Result<Vec<(Name, RecordType)>> {
+  let content =
+    std::fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+  let queries: Vec<(Name, RecordType)> = content
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(|line| {
+      let mut fields = line.split_whitespace();
+      let name = fields.next().unwrap_or_default();
+      let record_type = match fields.next() {
+        Some(record_type) => query::parse_record_type(record_type)?,
+        None => RecordType::A,
+      };
+      let mut name = Name::from_str(name).with_context(|| format!("invalid name `{name}`"))?;
+      name.set_fqdn(true);
+      Ok((name, record_type))
+    })
+    .collect::<Result<_>>()
+    .with_context(|| format!("invalid query in {}", path.display()))?;
+  if queries.is_empty() {
+    bail!("no query in {}", path.display());
+  }
+  Ok(queries)
+}
+
+/// Results of a benchmark.
+#[derive(Debug, Default)]
+pub struct BenchReport {
+  /// Time spent sending the queries.
+  pub elapsed: Duration,
+  pub sent: u64,
+  /// Responses by response code.
+  pub responses: BTreeMap<String, u64>,
+  pub timeouts: u64,
+  pub errors: u64,
+  /// Latencies of the responses, sorted.
+  pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+  /// Latency under which `percentile`% of the responses were received.
+  pub fn percentile(&self, percentile: f64) -> Duration {
+    if self.latencies.is_empty() {
+      return Duration::ZERO;
+    }
+    let index = ((self.latencies.len() as f64 * percentile / 100.0).ceil() as usize).max(1);
+    self.latencies[index.min(self.latencies.len()) - 1]
+  }
+
+  fn answered(&self) -> u64 {
+    self.responses.values().sum()
+  }
+}
+
+impl fmt::Display for BenchReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let percent = |count: u64| match self.sent {
+      0 => 0.0,
+      sent => count as f64 * 100.0 / sent as f64,
+    };
+    let seconds = self.elapsed.as_secs_f64().max(f64::EPSILON);
+    writeln!(
+      f,
+      "Sent {} queries in {:.1}s ({:.0} per second)",
+      self.sent,
+      seconds,
+      self.sent as f64 / seconds
+    )?;
+    writeln!(
+      f,
+      "Answered {} ({:.1}%), {} timeouts ({:.1}%), {} errors ({:.1}%)",
+      self.answered(),
+      percent(self.answered()),
+      self.timeouts,
+      percent(self.timeouts),
+      self.errors,
+      percent(self.errors)
+    )?;
+    let codes: Vec<String> = self
+      .responses
+      .iter()
+      .map(|(code, count)| format!("{code} {count}"))
+      .collect();
+    match codes.is_empty() {
+      true => writeln!(f, "Response codes: none")?,
+      false => writeln!(f, "Response codes: {}", codes.join(", "))?,
+    }
+    let millis = |latency: Duration| latency.as_secs_f64() * 1000.0;
+    write!(
+      f,
+      "Latency: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+      millis(self.percentile(50.0)),
+      millis(self.percentile(90.0)),
+      millis(self.percentile(99.0)),
+      millis(self.latencies.last().copied().unwrap_or_default())
+    )
+  }
+}
+
+/// Send the queries at a constant rate for the duration, then wait for the last answers.
+pub async fn run(options: BenchOptions) -> Result<BenchReport> {
+  if options.qps == 0 || options.queries.is_empty() {
+    bail!("nothing to send");
+  }
+  let options = Arc::new(options);
+  let report = Arc::new(Mutex::new(BenchReport::default()));
+  let mut interval = tokio::time::interval(Duration::from_secs(1) / options.qps);
+  // Late ticks are sent at once so the rate is kept on average.
+  interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+  let mut tasks = JoinSet::new();
+  let start = Instant::now();
+  let mut sent = 0;
+  while start.elapsed() < options.duration {
+    interval.tick().await;
+    let (name, record_type) = &options.queries[sent % options.queries.len()];
+    let message = query::message(name, *record_type);
+    sent += 1;
+    let options = options.clone();
+    let report = report.clone();
+    tasks.spawn(async move {
+      let sent = Instant::now();
+      let response = query::send(
+        &message,
+        options.server,
+        options.transport,
+        options.tls.as_ref(),
+        options.timeout,
+      )
+      .await;
+      let latency = sent.elapsed();
+      let mut report = report.lock().unwrap();
+      match response {
+        Ok(response) => {
+          let code = response.response_code().to_string();
+          *report.responses.entry(code).or_default() += 1;
+          report.latencies.push(latency);
+        }
+        Err(_) if latency >= options.timeout => report.timeouts += 1,
+        Err(_) => report.errors += 1,
+      }
+    });
+    // Finished tasks are dropped as the benchmark goes.
+    while let Some(Some(_)) = tasks.join_next().now_or_never() {}
+  }
+  let elapsed = start.elapsed();
+  while tasks.join_next().await.is_some() {}
+
+  let mut report = std::mem::take(&mut *report.lock().unwrap());
+  report.elapsed = elapsed;
+  report.sent = sent as u64;
+  report.latencies.sort();
+  Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn percentiles() {
+    let report = BenchReport {
+      latencies: (1..=100).map(Duration::from_millis).collect(),
+      ..Default::default()
+    };
+    assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+    assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+    assert_eq!(report.percentile(100.0), Duration::from_millis(100));
+    assert_eq!(BenchReport::default().percentile(90.0), Duration::ZERO);
+  }
+}
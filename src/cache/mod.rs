@@ -0,0 +1,617 @@
+use crate::recursor::Recursor;
+use anyhow::{bail, Context, Result};
+use hickory_server::{
+  authority::LookupError,
+  proto::{
+    op::{LowerQuery, Message, ResponseCode},
+    rr::{LowerName, Record, RecordType},
+    serialize::binary::BinEncodable,
+  },
+  resolver::{error::ResolveErrorKind, lookup::Lookup as ResolverLookup},
+  store::forwarder::ForwardLookup,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::info;
+
+mod redis;
+
+pub use redis::RedisUrl;
+use redis::{escape_pattern, Redis};
+
+/// Default largest number of cached answers.
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+/// Memory used by an entry besides its key and records: the entry itself and its places in the
+/// map and in the order of use.
+const ENTRY_OVERHEAD: usize =
+  std::mem::size_of::<Entry>() + std::mem::size_of::<(u64, Key)>() + 2 * std::mem::size_of::<Key>();
+/// Largest TTL of the cached answers, like the resolver default.
+const MAX_TTL: u32 = 86_400;
+
+#[derive(Clone)]
+enum Answer {
+  Records(Arc<[Record]>),
+  /// NXDOMAIN, or NOERROR without records.
+  Negative(ResponseCode),
+}
+
+struct Entry {
+  answer: Answer,
+  expires: Instant,
+  /// Tick of the last use of the entry.
+  used: u64,
+  /// Approximate memory used by the entry.
+  size: usize,
+}
+
+impl Answer {
+  /// DNS message of the answer, as stored in the shared cache.
+  fn to_bytes(&self) -> Option<Vec<u8>> {
+    let mut message = Message::new();
+    match self {
+      Answer::Records(records) => {
+        message.add_answers(records.iter().cloned());
+      }
+      Answer::Negative(code) => {
+        message.set_response_code(*code);
+      }
+    }
+    message.to_vec().ok()
+  }
+
+  fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    let mut message = Message::from_vec(bytes).ok()?;
+    match message.response_code() {
+      ResponseCode::NoError if message.answer_count() > 0 => {
+        Some(Answer::Records(message.take_answers().into()))
+      }
+      code => Some(Answer::Negative(code)),
+    }
+  }
+}
+
+/// Upstreams, name and type of a cached answer.
+type Key = (Arc<str>, LowerName, RecordType);
+
+/// Approximate memory used by the answer `answer` of `key`.
+fn size(key: &Key, answer: &Answer) -> usize {
+  let records = match answer {
+    Answer::Records(records) => records
+      .iter()
+      .map(|record| {
+        std::mem::size_of::<Record>() + record.to_bytes().map_or(0, |bytes| bytes.len())
+      })
+      .sum(),
+    Answer::Negative(_) => 0,
+  };
+  ENTRY_OVERHEAD + key.0.len() + key.1.len() + records
+}
+
+/// Cached answers with the order of their last use, the least recently used ones are evicted
+/// first when the cache is full.
+struct Entries {
+  map: HashMap<Key, Entry>,
+  /// Keys of the entries by the tick of their last use.
+  used: BTreeMap<u64, Key>,
+  tick: u64,
+  /// Approximate memory used by the entries.
+  memory: usize,
+  max_entries: usize,
+  max_memory: usize,
+}
+
+impl Default for Entries {
+  fn default() -> Self {
+    Self {
+      map: HashMap::new(),
+      used: BTreeMap::new(),
+      tick: 0,
+      memory: 0,
+      max_entries: DEFAULT_MAX_ENTRIES,
+      max_memory: usize::MAX,
+    }
+  }
+}
+
+impl Entries {
+  /// The entry of `key` when it is not expired, it becomes the most recently used one.
+  fn get(&mut self, key: &Key) -> Option<&Entry> {
+    let entry = self.map.get_mut(key)?;
+    if entry.expires <= Instant::now() {
+      return None;
+    }
+    self.used.remove(&entry.used);
+    self.tick += 1;
+    entry.used = self.tick;
+    self.used.insert(self.tick, key.clone());
+    Some(entry)
+  }
+
+  /// Keep `answer` until `expires`, returns the number of answers evicted to make room for it.
+  fn insert(&mut self, key: Key, answer: Answer, expires: Instant) -> usize {
+    self.remove(&key);
+    let size = size(&key, &answer);
+    if self.max_entries == 0 || size > self.max_memory {
+      return 0;
+    }
+    let evicted = self.evict(self.max_entries - 1, self.max_memory - size);
+    self.tick += 1;
+    self.memory += size;
+    self.used.insert(self.tick, key.clone());
+    let used = self.tick;
+    self.map.insert(
+      key,
+      Entry {
+        answer,
+        expires,
+        used,
+        size,
+      },
+    );
+    evicted
+  }
+
+  fn remove(&mut self, key: &Key) -> Option<Entry> {
+    let entry = self.map.remove(key)?;
+    self.used.remove(&entry.used);
+    self.memory -= entry.size;
+    Some(entry)
+  }
+
+  /// Remove the least recently used entries until there are at most `entries` of them using at
+  /// most `memory`. Returns the number of evicted entries that were not expired yet.
+  fn evict(&mut self, entries: usize, memory: usize) -> usize {
+    let now = Instant::now();
+    let mut evicted = 0;
+    while self.map.len() > entries || self.memory > memory {
+      let Some((_, key)) = self.used.pop_first() else {
+        break;
+      };
+      if let Some(entry) = self.map.remove(&key) {
+        self.memory -= entry.size;
+        if entry.expires > now {
+          evicted += 1;
+        }
+      }
+    }
+    evicted
+  }
+
+  fn clear(&mut self) -> usize {
+    let removed = self.map.len();
+    self.map.clear();
+    self.used.clear();
+    self.memory = 0;
+    removed
+  }
+}
+
+/// Usage of the memory cache.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CacheStats {
+  pub entries: usize,
+  /// Approximate memory used by the answers, in bytes.
+  pub memory: usize,
+  pub max_entries: usize,
+  /// `None` when the memory is not limited.
+  pub max_memory: Option<usize>,
+  /// Answers removed before their expiration to respect the limits.
+  pub evictions: u64,
+}
+
+/// Key of an answer in the shared cache, the name first so the answers of a name can be
+/// flushed.
+fn shared_key(upstreams: &str, query: &LowerQuery) -> String {
+  format!(
+    "{SHARED_PREFIX}{}:{}:{}",
+    query.name(),
+    query.query_type(),
+    upstreams
+  )
+}
+
+/// Prefix of the keys of the shared cache.
+const SHARED_PREFIX: &str = "dns-server:";
+
+/// Answers of the upstreams kept until their TTL expires, positive and negative ones. They are
+/// stored with the names of the upstreams, so client groups with their own upstreams do not share
+/// answers. The cache is kept when the catalogs are rebuilt and can be flushed at runtime.
+///
+/// With a shared backend, answers are also stored in Redis for the other instances, the memory
+/// cache being checked first.
+///
+/// The number of answers and their memory are limited, the least recently used answers are
+/// evicted when the cache is full.
+#[derive(Default)]
+pub struct AnswerCache {
+  entries: Mutex<Entries>,
+  evictions: AtomicU64,
+  shared: OnceLock<Arc<Redis>>,
+  /// Recursors whose own caches are flushed with this one.
+  recursors: Mutex<Vec<Weak<Recursor>>>,
+  /// Notified on each flush, see [`AnswerCache::flushed`].
+  flushed: Notify,
+}
+
+impl AnswerCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.lock().unwrap().map.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Limit the number of cached answers and their approximate memory, `None` for no memory limit.
+  /// The least recently used answers are evicted when the current ones exceed the new limits.
+  pub fn set_limits(&self, max_entries: usize, max_memory: Option<usize>) {
+    let mut entries = self.entries.lock().unwrap();
+    let max_memory = max_memory.unwrap_or(usize::MAX);
+    entries.max_entries = max_entries;
+    entries.max_memory = max_memory;
+    let evicted = entries.evict(max_entries, max_memory);
+    self.evicted(evicted);
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    let entries = self.entries.lock().unwrap();
+    CacheStats {
+      entries: entries.map.len(),
+      memory: entries.memory,
+      max_entries: entries.max_entries,
+      max_memory: Some(entries.max_memory).filter(|max| *max != usize::MAX),
+      evictions: self.evictions.load(Ordering::Relaxed),
+    }
+  }
+
+  fn evicted(&self, count: usize) {
+    self.evictions.fetch_add(count as u64, Ordering::Relaxed);
+  }
+
+  /// Share the answers with the other instances through Redis, can only be set once.
+  pub fn set_shared(&self, url: RedisUrl) {
+    let _ = self.shared.set(Arc::new(Redis::new(url)));
+  }
+
+  /// Answer of `upstreams` to `query` when it is cached and not expired, its TTLs are the
+  /// remaining time.
+  pub fn get(
+    &self,
+    upstreams: &Arc<str>,
+    query: &LowerQuery,
+  ) -> Option<Result<ForwardLookup, LookupError>> {
+    let key = (upstreams.clone(), query.name().clone(), query.query_type());
+    let mut entries = self.entries.lock().unwrap();
+    let entry = entries.get(&key)?;
+    let remaining = entry.expires.checked_duration_since(Instant::now())?;
+    Some(lookup(query, &entry.answer, remaining))
+  }
+
+  /// Answer of `upstreams` to `query` from the memory cache, then from the shared one. Answers
+  /// found in the shared cache are kept in memory.
+  pub async fn lookup(
+    &self,
+    upstreams: &Arc<str>,
+    query: &LowerQuery,
+  ) -> Option<Result<ForwardLookup, LookupError>> {
+    if let Some(answer) = self.get(upstreams, query) {
+      return Some(answer);
+    }
+    let shared = self.shared.get()?;
+    let (bytes, remaining) = shared.get(shared_key(upstreams, query).as_bytes()).await?;
+    let answer = Answer::from_bytes(&bytes)?;
+    let key = (upstreams.clone(), query.name().clone(), query.query_type());
+    self.store(key, answer.clone(), Instant::now() + remaining);
+    Some(lookup(query, &answer, remaining))
+  }
+
+  /// Keep the answer of `upstreams` to `query` for its TTL, the lowest of its records or the
+  /// negative TTL of its SOA. Other errors and answers with a TTL of 0 are not cached.
+  pub fn insert(
+    &self,
+    upstreams: &Arc<str>,
+    query: &LowerQuery,
+    answer: &Result<ForwardLookup, LookupError>,
+  ) {
+    let (answer, ttl) = match answer {
+      Ok(lookup) => {
+        let records = lookup.0.records();
+        let Some(ttl) = records.iter().map(Record::ttl).min() else {
+          return;
+        };
+        (Answer::Records(records.into()), ttl)
+      }
+      Err(LookupError::ResolveError(err)) => match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+          negative_ttl: Some(ttl),
+          response_code,
+          ..
+        } => (Answer::Negative(*response_code), *ttl),
+        _ => return,
+      },
+      Err(_) => return,
+    };
+    if ttl == 0 {
+      return;
+    }
+    let ttl = Duration::from_secs(ttl.min(MAX_TTL).into());
+    if let (Some(shared), Some(bytes)) = (self.shared.get(), answer.to_bytes()) {
+      let shared = shared.clone();
+      let key = shared_key(upstreams, query);
+      tokio::spawn(async move { shared.set(key.as_bytes(), &bytes, ttl).await });
+    }
+    let key = (upstreams.clone(), query.name().clone(), query.query_type());
+    self.store(key, answer, Instant::now() + ttl);
+  }
+
+  fn store(&self, key: Key, answer: Answer, expires: Instant) {
+    let evicted = self.entries.lock().unwrap().insert(key, answer, expires);
+    self.evicted(evicted);
+  }
+
+  /// Flush the whole cache, the recursors caches included. Returns the number of removed answers.
+  pub fn flush(&self) -> usize {
+    let removed = self.entries.lock().unwrap().clear();
+    for recursor in self.recursors() {
+      recursor.flush(None);
+    }
+    self.flush_shared(format!("{SHARED_PREFIX}*"));
+    self.flushed.notify_one();
+    info!("Cache flushed, {} answers removed", removed);
+    removed
+  }
+
+  /// Flush the answers of `name`, of every type. Returns the number of removed answers.
+  pub fn flush_name(&self, name: &LowerName) -> usize {
+    let mut entries = self.entries.lock().unwrap();
+    let keys: Vec<Key> = entries
+      .map
+      .keys()
+      .filter(|(_, cached, _)| cached == name)
+      .cloned()
+      .collect();
+    let removed = keys
+      .iter()
+      .filter(|key| entries.remove(key).is_some())
+      .count();
+    drop(entries);
+    for recursor in self.recursors() {
+      recursor.flush(Some(&name.into()));
+    }
+    self.flush_shared(format!(
+      "{SHARED_PREFIX}{}:*",
+      escape_pattern(&name.to_string())
+    ));
+    self.flushed.notify_one();
+    info!("Cache of {} flushed, {} answers removed", name, removed);
+    removed
+  }
+
+  /// Wait until the cache is flushed, fully or for a name. A flush done while nobody waits
+  /// completes the next wait, so a single task can refill the cache without missing one.
+  pub async fn flushed(&self) {
+    self.flushed.notified().await;
+  }
+
+  /// Delete the keys of the shared cache matching `pattern` in the background.
+  fn flush_shared(&self, pattern: String) {
+    if let Some(shared) = self.shared.get() {
+      let shared = shared.clone();
+      tokio::spawn(async move {
+        let deleted = shared.delete_matching(pattern.as_bytes()).await;
+        info!("{} answers removed from the shared cache", deleted);
+      });
+    }
+  }
+
+  /// Flush the answers of `recursor` too, as long as it is in use.
+  pub fn attach(&self, recursor: &Arc<Recursor>) {
+    let mut recursors = self.recursors.lock().unwrap();
+    recursors.retain(|recursor| recursor.strong_count() > 0);
+    recursors.push(Arc::downgrade(recursor));
+  }
+
+  fn recursors(&self) -> Vec<Arc<Recursor>> {
+    let recursors = self.recursors.lock().unwrap();
+    recursors.iter().filter_map(Weak::upgrade).collect()
+  }
+}
+
+/// Lookup of a cached answer to `query`, with `remaining` as TTL.
+fn lookup(
+  query: &LowerQuery,
+  answer: &Answer,
+  remaining: Duration,
+) -> Result<ForwardLookup, LookupError> {
+  match answer {
+    Answer::Records(records) => {
+      let records: Vec<Record> = records
+        .iter()
+        .map(|record| {
+          let mut record = record.clone();
+          record.set_ttl(remaining.as_secs() as u32);
+          record
+        })
+        .collect();
+      Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+        query.original().clone(),
+        records.into(),
+      )))
+    }
+    Answer::Negative(code) => Err(LookupError::ResponseCode(*code)),
+  }
+}
+
+/// Parse a size in bytes with an optional `K`, `M` or `G` unit (powers of 1024), e.g. `64M`.
+pub fn parse_size(value: &str) -> Result<usize> {
+  let value = value.trim();
+  let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+  let number: usize = digits
+    .parse()
+    .with_context(|| format!("invalid size `{value}`"))?;
+  let unit = match value[digits.len()..]
+    .to_ascii_uppercase()
+    .trim_end_matches('B')
+  {
+    "" => 1,
+    "K" => 1 << 10,
+    "M" => 1 << 20,
+    "G" => 1 << 30,
+    unit => bail!("invalid size unit `{unit}`, expected K, M or G"),
+  };
+  number
+    .checked_mul(unit)
+    .with_context(|| format!("size `{value}` is too large"))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::{
+    op::Query,
+    rr::{rdata::A, Name, RData},
+  };
+  use hickory_server::resolver::error::ResolveError;
+  use std::str::FromStr;
+
+  fn query(name: &str, query_type: RecordType) -> LowerQuery {
+    LowerQuery::query(Query::query(Name::from_str(name).unwrap(), query_type))
+  }
+
+  fn answer(name: &str, ttl: u32) -> Result<ForwardLookup, LookupError> {
+    let name = Name::from_str(name).unwrap();
+    let record = Record::from_rdata(name.clone(), ttl, RData::A(A::new(192, 0, 2, 1)));
+    Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+      Query::query(name, RecordType::A),
+      Arc::new([record]),
+    )))
+  }
+
+  #[test]
+  fn cached_answers() {
+    let cache = AnswerCache::new();
+    let upstreams: Arc<str> = "cloudflare".into();
+    let a = query("example.com.", RecordType::A);
+    cache.insert(&upstreams, &a, &answer("example.com.", 300));
+    let cached = cache.get(&upstreams, &a).unwrap().unwrap();
+    assert_eq!(cached.0.records().len(), 1);
+    assert!(cached.0.records()[0].ttl() <= 300);
+    assert!(cache.get(&"google".into(), &a).is_none());
+    assert!(cache
+      .get(&upstreams, &query("example.com.", RecordType::AAAA))
+      .is_none());
+
+    cache.insert(
+      &upstreams,
+      &query("zero.example.", RecordType::A),
+      &answer("zero.example.", 0),
+    );
+    assert!(cache
+      .get(&upstreams, &query("zero.example.", RecordType::A))
+      .is_none());
+
+    let nx = query("nx.example.", RecordType::A);
+    let error = ResolveError::from(ResolveErrorKind::NoRecordsFound {
+      query: Box::new(nx.original().clone()),
+      soa: None,
+      negative_ttl: Some(60),
+      response_code: ResponseCode::NXDomain,
+      trusted: true,
+    });
+    cache.insert(&upstreams, &nx, &Err(LookupError::ResolveError(error)));
+    assert!(matches!(
+      cache.get(&upstreams, &nx),
+      Some(Err(LookupError::ResponseCode(ResponseCode::NXDomain)))
+    ));
+    assert_eq!(cache.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn flushes() {
+    let cache = AnswerCache::new();
+    let upstreams: Arc<str> = "cloudflare".into();
+    cache.insert(
+      &upstreams,
+      &query("example.com.", RecordType::A),
+      &answer("example.com.", 300),
+    );
+    cache.insert(
+      &upstreams,
+      &query("example.com.", RecordType::MX),
+      &answer("example.com.", 300),
+    );
+    cache.insert(
+      &upstreams,
+      &query("www.example.com.", RecordType::A),
+      &answer("www.example.com.", 300),
+    );
+    assert_eq!(
+      cache.flush_name(&LowerName::from_str("Example.com.").unwrap()),
+      2
+    );
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.flush(), 1);
+    assert!(cache.is_empty());
+    // Both flushes were notified before waiting, they complete a single wait.
+    tokio::time::timeout(Duration::from_secs(1), cache.flushed())
+      .await
+      .unwrap();
+    assert!(
+      tokio::time::timeout(Duration::from_millis(10), cache.flushed())
+        .await
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn least_recently_used_evicted() {
+    let cache = AnswerCache::new();
+    cache.set_limits(2, None);
+    let upstreams: Arc<str> = "cloudflare".into();
+    for name in ["a.example.", "b.example."] {
+      cache.insert(&upstreams, &query(name, RecordType::A), &answer(name, 300));
+    }
+    assert!(cache
+      .get(&upstreams, &query("a.example.", RecordType::A))
+      .is_some());
+    cache.insert(
+      &upstreams,
+      &query("c.example.", RecordType::A),
+      &answer("c.example.", 300),
+    );
+    assert!(cache
+      .get(&upstreams, &query("b.example.", RecordType::A))
+      .is_none());
+    assert!(cache
+      .get(&upstreams, &query("a.example.", RecordType::A))
+      .is_some());
+    assert_eq!(cache.stats().evictions, 1);
+
+    let memory = cache.stats().memory;
+    cache.set_limits(2, Some(memory / 2));
+    let stats = cache.stats();
+    assert_eq!(stats.entries, 1);
+    assert!(stats.memory <= memory / 2);
+    assert_eq!(stats.evictions, 2);
+    cache.flush();
+    assert_eq!(cache.stats().memory, 0);
+  }
+
+  #[test]
+  fn sizes() {
+    assert_eq!(parse_size("1024").unwrap(), 1024);
+    assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+    assert_eq!(parse_size("64MB").unwrap(), 64 * 1024 * 1024);
+    assert_eq!(parse_size("1g").unwrap(), 1 << 30);
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("10T").is_err());
+  }
+}
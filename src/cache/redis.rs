@@ -0,0 +1,396 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const DEFAULT_PORT: u16 = 6379;
+/// Time given to Redis to answer a command, queries are answered without it after that.
+const TIMEOUT: Duration = Duration::from_millis(500);
+/// Keys scanned by each `SCAN` command of a flush.
+const SCAN_COUNT: &str = "1000";
+/// Connections opened to Redis, queries are answered without the cache when they are all busy.
+const CONNECTIONS: usize = 4;
+
+/// Address of a Redis server: `redis://[[user]:password@]host[:port][/db]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisUrl {
+  pub host: String,
+  pub port: u16,
+  pub username: Option<String>,
+  pub password: Option<String>,
+  pub db: u32,
+}
+
+impl FromStr for RedisUrl {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let url = url::Url::parse(s).with_context(|| format!("invalid cache backend `{s}`"))?;
+    if url.scheme() != "redis" {
+      bail!("unsupported cache backend `{s}`, expected redis://host[:port][/db]");
+    }
+    let host = url
+      .host_str()
+      .with_context(|| format!("the cache backend `{s}` has no host"))?
+      .trim_start_matches('[')
+      .trim_end_matches(']')
+      .to_string();
+    let db = match url.path().trim_start_matches('/') {
+      "" => 0,
+      db => db
+        .parse()
+        .with_context(|| format!("invalid Redis database `{db}`"))?,
+    };
+    Ok(Self {
+      host,
+      port: url.port().unwrap_or(DEFAULT_PORT),
+      username: Some(url.username().to_string()).filter(|username| !username.is_empty()),
+      password: url.password().map(str::to_string),
+      db,
+    })
+  }
+}
+
+impl fmt::Display for RedisUrl {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "redis://")?;
+    match (&self.username, &self.password) {
+      (Some(username), Some(password)) => write!(f, "{username}:{password}@")?,
+      (None, Some(password)) => write!(f, ":{password}@")?,
+      (Some(username), None) => write!(f, "{username}@")?,
+      (None, None) => {}
+    }
+    match self.host.contains(':') {
+      true => write!(f, "[{}]:{}", self.host, self.port)?,
+      false => write!(f, "{}:{}", self.host, self.port)?,
+    }
+    if self.db != 0 {
+      write!(f, "/{}", self.db)?;
+    }
+    Ok(())
+  }
+}
+
+impl Serialize for RedisUrl {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for RedisUrl {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    RedisUrl::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Reply of a Redis command (RESP2).
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+  Status(String),
+  Integer(i64),
+  Bulk(Option<Vec<u8>>),
+  Array(Vec<Reply>),
+}
+
+/// Minimal Redis client on a few connections, the commands on a connection are sent one after
+/// the other. A connection is opened on its first command and opened again after an error.
+pub struct Redis {
+  url: RedisUrl,
+  connections: Vec<Mutex<Option<BufReader<TcpStream>>>>,
+  /// Whether the last command failed, so the failures are only logged once.
+  failing: AtomicBool,
+}
+
+impl Redis {
+  pub fn new(url: RedisUrl) -> Self {
+    Self {
+      url,
+      connections: (0..CONNECTIONS).map(|_| Mutex::new(None)).collect(),
+      failing: AtomicBool::new(false),
+    }
+  }
+
+  /// Run the commands in a pipeline on a free connection and return their replies, `None` when
+  /// Redis is unavailable or all the connections are busy, so a slow reply never stalls the
+  /// other queries.
+  async fn pipeline(&self, commands: &[&[&[u8]]]) -> Option<Vec<Reply>> {
+    let mut connection = self
+      .connections
+      .iter()
+      .find_map(|connection| connection.try_lock().ok())?;
+    self.run(&mut connection, commands).await
+  }
+
+  /// Like `pipeline` but waits for a connection, for the commands that must not be skipped.
+  async fn pipeline_waiting(&self, commands: &[&[&[u8]]]) -> Option<Vec<Reply>> {
+    let mut connection = self.connections[0].lock().await;
+    self.run(&mut connection, commands).await
+  }
+
+  async fn run(
+    &self,
+    connection: &mut Option<BufReader<TcpStream>>,
+    commands: &[&[&[u8]]],
+  ) -> Option<Vec<Reply>> {
+    let result = tokio::time::timeout(TIMEOUT, async {
+      if connection.is_none() {
+        *connection = Some(self.connect().await?);
+      }
+      let stream = connection.as_mut().unwrap();
+      let mut request = vec![];
+      for command in commands {
+        encode(&mut request, command);
+      }
+      stream.get_mut().write_all(&request).await?;
+      let mut replies = Vec::with_capacity(commands.len());
+      for _ in commands {
+        replies.push(read_reply(stream).await?);
+      }
+      Ok::<_, anyhow::Error>(replies)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result);
+    match result {
+      Ok(replies) => {
+        if self.failing.swap(false, Ordering::Relaxed) {
+          info!("Cache backend {} is available again", self.url.host);
+        }
+        Some(replies)
+      }
+      Err(err) => {
+        *connection = None;
+        if !self.failing.swap(true, Ordering::Relaxed) {
+          warn!("Cache backend {} is unavailable: {:#}", self.url.host, err);
+        }
+        None
+      }
+    }
+  }
+
+  async fn connect(&self) -> Result<BufReader<TcpStream>> {
+    let stream = TcpStream::connect((self.url.host.as_str(), self.url.port))
+      .await
+      .with_context(|| format!("could not connect to {}:{}", self.url.host, self.url.port))?;
+    let mut stream = BufReader::new(stream);
+    let mut commands: Vec<Vec<&[u8]>> = vec![];
+    if let Some(password) = &self.url.password {
+      let mut auth: Vec<&[u8]> = vec![b"AUTH"];
+      if let Some(username) = &self.url.username {
+        auth.push(username.as_bytes());
+      }
+      auth.push(password.as_bytes());
+      commands.push(auth);
+    }
+    let db = self.url.db.to_string();
+    if self.url.db != 0 {
+      commands.push(vec![b"SELECT", db.as_bytes()]);
+    }
+    for command in commands {
+      let mut request = vec![];
+      encode(&mut request, &command);
+      stream.get_mut().write_all(&request).await?;
+      read_reply(&mut stream).await?;
+    }
+    Ok(stream)
+  }
+
+  pub async fn get(&self, key: &[u8]) -> Option<(Vec<u8>, Duration)> {
+    let replies = self.pipeline(&[&[b"GET", key], &[b"PTTL", key]]).await?;
+    match &replies[..] {
+      [Reply::Bulk(Some(value)), Reply::Integer(ttl)] if *ttl > 0 => {
+        Some((value.clone(), Duration::from_millis(*ttl as u64)))
+      }
+      _ => None,
+    }
+  }
+
+  pub async fn set(&self, key: &[u8], value: &[u8], ttl: Duration) {
+    let ttl = ttl.as_millis().max(1).to_string();
+    self
+      .pipeline(&[&[b"SET", key, value, b"PX", ttl.as_bytes()]])
+      .await;
+  }
+
+  /// Delete the keys matching the glob `pattern`, returns how many were deleted.
+  pub async fn delete_matching(&self, pattern: &[u8]) -> usize {
+    let mut cursor = b"0".to_vec();
+    let mut deleted = 0;
+    loop {
+      let Some(replies) = self
+        .pipeline_waiting(&[&[
+          b"SCAN",
+          &cursor,
+          b"MATCH",
+          pattern,
+          b"COUNT",
+          SCAN_COUNT.as_bytes(),
+        ]])
+        .await
+      else {
+        return deleted;
+      };
+      let Some(Reply::Array(reply)) = replies.into_iter().next() else {
+        return deleted;
+      };
+      let [Reply::Bulk(Some(next)), Reply::Array(keys)] = &reply[..] else {
+        return deleted;
+      };
+      let mut command: Vec<&[u8]> = vec![b"DEL"];
+      command.extend(keys.iter().filter_map(|key| match key {
+        Reply::Bulk(Some(key)) => Some(key.as_slice()),
+        _ => None,
+      }));
+      if command.len() > 1 {
+        if let Some(replies) = self.pipeline_waiting(&[&command]).await {
+          if let [Reply::Integer(count)] = &replies[..] {
+            deleted += *count as usize;
+          }
+        }
+      }
+      if next == b"0" {
+        return deleted;
+      }
+      cursor = next.clone();
+    }
+  }
+}
+
+/// Escape the glob characters of `value` for a `SCAN MATCH` pattern.
+pub fn escape_pattern(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+fn encode(buffer: &mut Vec<u8>, command: &[&[u8]]) {
+  buffer.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+  for arg in command {
+    buffer.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+    buffer.extend_from_slice(arg);
+    buffer.extend_from_slice(b"\r\n");
+  }
+}
+
+fn read_reply<R: tokio::io::AsyncBufRead + Unpin + Send>(
+  reader: &mut R,
+) -> Pin<Box<dyn Future<Output = Result<Reply>> + Send + '_>> {
+  Box::pin(async move {
+    let mut line = vec![];
+    reader.read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\r\n") {
+      bail!("connection closed by Redis");
+    }
+    if line.len() < 3 {
+      bail!("empty Redis reply");
+    }
+    let content = String::from_utf8_lossy(&line[1..line.len() - 2]).to_string();
+    let length = || {
+      content
+        .parse::<i64>()
+        .map_err(|_| anyhow!("invalid Redis reply `{content}`"))
+    };
+    match line[0] {
+      b'+' => Ok(Reply::Status(content.clone())),
+      b'-' => bail!("Redis error: {content}"),
+      b':' => Ok(Reply::Integer(length()?)),
+      b'$' => {
+        let length = length()?;
+        if length < 0 {
+          return Ok(Reply::Bulk(None));
+        }
+        let mut value = vec![0; length as usize + 2];
+        reader.read_exact(&mut value).await?;
+        value.truncate(length as usize);
+        Ok(Reply::Bulk(Some(value)))
+      }
+      b'*' => {
+        let length = length()?;
+        let mut replies = vec![];
+        for _ in 0..length.max(0) {
+          replies.push(read_reply(reader).await?);
+        }
+        Ok(Reply::Array(replies))
+      }
+      _ => bail!("invalid Redis reply `{}`", String::from_utf8_lossy(&line)),
+    }
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn urls() {
+    let url = RedisUrl::from_str("redis://:secret@cache.lan/2").unwrap();
+    assert_eq!(url.host, "cache.lan");
+    assert_eq!(url.port, DEFAULT_PORT);
+    assert_eq!(url.password.as_deref(), Some("secret"));
+    assert_eq!(url.username, None);
+    assert_eq!(url.db, 2);
+    assert_eq!(url.to_string(), "redis://:secret@cache.lan:6379/2");
+    let url = RedisUrl::from_str("redis://[fd00::1]:6380").unwrap();
+    assert_eq!(url.host, "fd00::1");
+    assert_eq!(url.to_string(), "redis://[fd00::1]:6380");
+    assert!(RedisUrl::from_str("memcached://cache.lan").is_err());
+    assert!(RedisUrl::from_str("redis://cache.lan/db").is_err());
+  }
+
+  #[tokio::test]
+  async fn busy_connections() {
+    let redis = Redis::new(RedisUrl::from_str("redis://127.0.0.1:1").unwrap());
+    let _busy: Vec<_> = redis
+      .connections
+      .iter()
+      .map(|connection| connection.try_lock().unwrap())
+      .collect();
+    // Answered right away without the cache instead of waiting for a connection.
+    let lookup = tokio::time::timeout(Duration::from_millis(50), redis.get(b"key"));
+    assert_eq!(lookup.await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn replies() {
+    let mut request = vec![];
+    encode(&mut request, &[b"GET", b"key"]);
+    assert_eq!(request, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+
+    let mut reader: &[u8] =
+      b"+OK\r\n:42\r\n$5\r\nhello\r\n$-1\r\n*2\r\n$1\r\n0\r\n*1\r\n$1\r\na\r\n-ERR no\r\n";
+    assert_eq!(
+      read_reply(&mut reader).await.unwrap(),
+      Reply::Status("OK".into())
+    );
+    assert_eq!(read_reply(&mut reader).await.unwrap(), Reply::Integer(42));
+    assert_eq!(
+      read_reply(&mut reader).await.unwrap(),
+      Reply::Bulk(Some(b"hello".to_vec()))
+    );
+    assert_eq!(read_reply(&mut reader).await.unwrap(), Reply::Bulk(None));
+    assert_eq!(
+      read_reply(&mut reader).await.unwrap(),
+      Reply::Array(vec![
+        Reply::Bulk(Some(b"0".to_vec())),
+        Reply::Array(vec![Reply::Bulk(Some(b"a".to_vec()))])
+      ])
+    );
+    assert!(read_reply(&mut reader).await.is_err());
+    assert!(read_reply(&mut &b"\r\n"[..]).await.is_err());
+    assert_eq!(escape_pattern("a*b?.c"), "a\\*b\\?.c");
+  }
+}
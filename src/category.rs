@@ -0,0 +1,93 @@
+use crate::domain_set::DomainSet;
+use anyhow::{bail, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Exact domains blocked under a name, the category can be disabled at runtime.
+#[derive(Debug, Clone)]
+pub struct Category {
+  pub name: String,
+  pub domains: DomainSet,
+}
+
+/// Blacklist as written on the command line: `path`, or `category=path` for a named category.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlacklistFile {
+  pub category: Option<String>,
+  pub path: PathBuf,
+}
+
+/// Whether `name` can be used as a category: letters, digits, `-` and `_`.
+pub fn is_category_name(name: &str) -> bool {
+  !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl FromStr for BlacklistFile {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (category, path) = match s.split_once('=') {
+      Some((category, path)) if is_category_name(category.trim()) => {
+        (Some(category.trim().to_string()), path.trim())
+      }
+      _ => (None, s.trim()),
+    };
+    if path.is_empty() {
+      bail!("the blacklist `{s}` has no path");
+    }
+    Ok(Self {
+      category,
+      path: path.into(),
+    })
+  }
+}
+
+impl fmt::Display for BlacklistFile {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(category) = &self.category {
+      write!(f, "{category}=")?;
+    }
+    write!(f, "{}", self.path.display())
+  }
+}
+
+impl Serialize for BlacklistFile {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for BlacklistFile {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    BlacklistFile::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn blacklist_file() {
+    let file = BlacklistFile::from_str("ads=/etc/dns-server/ads.txt").unwrap();
+    assert_eq!(file.category.as_deref(), Some("ads"));
+    assert_eq!(file.path, PathBuf::from("/etc/dns-server/ads.txt"));
+    assert_eq!(file.to_string(), "ads=/etc/dns-server/ads.txt");
+
+    let file = BlacklistFile::from_str("/etc/dns-server/blacklist.txt").unwrap();
+    assert_eq!(file.category, None);
+    assert_eq!(file.to_string(), "/etc/dns-server/blacklist.txt");
+
+    let file = BlacklistFile::from_str("/etc/a=b.txt").unwrap();
+    assert_eq!(file.category, None);
+    assert_eq!(file.path, PathBuf::from("/etc/a=b.txt"));
+
+    assert!(BlacklistFile::from_str("ads=").is_err());
+  }
+}
@@ -0,0 +1,244 @@
+use anyhow::bail;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Longest name of a network interface, `IFNAMSIZ` without the final nul byte.
+const MAX_INTERFACE_LEN: usize = 15;
+
+/// Where the traffic to the upstreams goes out: a source address, or the name of a network
+/// interface (`SO_BINDTODEVICE`, Linux only).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamBind {
+  Address(IpAddr),
+  Interface(String),
+}
+
+impl FromStr for UpstreamBind {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Ok(ip) = s.parse() {
+      return Ok(UpstreamBind::Address(ip));
+    }
+    let valid = !s.is_empty()
+      && s.len() <= MAX_INTERFACE_LEN
+      && s != "."
+      && s != ".."
+      && !s.contains(|c: char| c == '/' || c == ':' || c.is_whitespace());
+    if !valid {
+      bail!("invalid address or interface `{s}`");
+    }
+    Ok(UpstreamBind::Interface(s.to_string()))
+  }
+}
+
+impl fmt::Display for UpstreamBind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UpstreamBind::Address(ip) => write!(f, "{ip}"),
+      UpstreamBind::Interface(interface) => write!(f, "{interface}"),
+    }
+  }
+}
+
+impl Serialize for UpstreamBind {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for UpstreamBind {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    UpstreamBind::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Source addresses and interface of the sockets opened to the upstreams, so multi-homed hosts
+/// can send them through a given WAN or VPN interface.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Binding {
+  ipv4: Option<IpAddr>,
+  ipv6: Option<IpAddr>,
+  interface: Option<String>,
+}
+
+impl Binding {
+  /// At most one IPv4 address, one IPv6 address and one interface can be bound.
+  pub fn new(binds: &[UpstreamBind]) -> anyhow::Result<Self> {
+    let mut binding = Self::default();
+    for bind in binds {
+      match bind {
+        UpstreamBind::Address(ip) => {
+          let address = match ip {
+            IpAddr::V4(_) => &mut binding.ipv4,
+            IpAddr::V6(_) => &mut binding.ipv6,
+          };
+          if address.replace(*ip).is_some() {
+            bail!("only one IPv4 and one IPv6 address can be bound, {ip} is one too many");
+          }
+        }
+        UpstreamBind::Interface(interface) => {
+          if !cfg!(any(target_os = "linux", target_os = "android")) {
+            bail!("binding the interface {interface} is only supported on Linux");
+          }
+          if binding.interface.replace(interface.clone()).is_some() {
+            bail!("only one interface can be bound, {interface} is one too many");
+          }
+        }
+      }
+    }
+    Ok(binding)
+  }
+
+  /// Interface the sockets are bound to, if any.
+  pub fn interface(&self) -> Option<&str> {
+    self.interface.as_deref()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ipv4.is_none() && self.ipv6.is_none() && self.interface.is_none()
+  }
+
+  /// Source address of the sockets to `server`, none when no address of its family is bound.
+  fn local_addr(&self, server: SocketAddr, port: u16) -> Option<SocketAddr> {
+    let ip = if server.is_ipv4() {
+      self.ipv4
+    } else {
+      self.ipv6
+    };
+    ip.map(|ip| SocketAddr::new(ip, port))
+  }
+
+  fn socket(&self, server: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(server), kind, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(interface) = &self.interface {
+      socket
+        .bind_device(Some(interface.as_bytes()))
+        .map_err(|err| io::Error::new(err.kind(), format!("could not bind {interface}: {err}")))?;
+    }
+    Ok(socket)
+  }
+
+  fn bind(socket: &Socket, local: SocketAddr) -> io::Result<()> {
+    socket
+      .bind(&local.into())
+      .map_err(|err| io::Error::new(err.kind(), format!("could not bind {local}: {err}")))
+  }
+
+  /// Open a TCP connection to `server` from the bound address and interface.
+  pub async fn connect_tcp(&self, server: SocketAddr) -> io::Result<TcpStream> {
+    if self.is_empty() {
+      return TcpStream::connect(server).await;
+    }
+    let socket = self.socket(server, Type::STREAM, Protocol::TCP)?;
+    if let Some(local) = self.local_addr(server, 0) {
+      Self::bind(&socket, local)?;
+    }
+    TcpSocket::from_std_stream(socket.into())
+      .connect(server)
+      .await
+  }
+
+  /// Open a TCP connection to the first address of `host` accepting it.
+  pub async fn connect_host(&self, host: &str) -> io::Result<TcpStream> {
+    if self.is_empty() {
+      return TcpStream::connect(host).await;
+    }
+    let mut last_error = None;
+    for server in tokio::net::lookup_host(host).await? {
+      match self.connect_tcp(server).await {
+        Ok(stream) => return Ok(stream),
+        Err(err) => last_error = Some(err),
+      }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::other(format!("{host} has no address"))))
+  }
+
+  /// Bind a UDP socket to the port of `local`, from the bound address and interface.
+  pub fn bind_udp(&self, local: SocketAddr, server: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = self.socket(server, Type::DGRAM, Protocol::UDP)?;
+    let local = self.local_addr(server, local.port()).unwrap_or(local);
+    Self::bind(&socket, local)?;
+    UdpSocket::from_std(socket.into())
+  }
+}
+
+/// Whether the network interface exists and is up and running.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_up(interface: &str) -> bool {
+  use std::os::fd::AsRawFd;
+
+  let Ok(socket) = Socket::new(Domain::IPV4, Type::DGRAM, None) else {
+    return false;
+  };
+  let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+  if interface.len() >= request.ifr_name.len() {
+    return false;
+  }
+  for (name, byte) in request.ifr_name.iter_mut().zip(interface.bytes()) {
+    *name = byte as libc::c_char;
+  }
+  // Fails with ENODEV once the interface is removed, e.g. by `wg-quick down`.
+  if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut request) } < 0 {
+    return false;
+  }
+  let flags = unsafe { request.ifr_ifru.ifru_flags } as libc::c_int;
+  flags & libc::IFF_UP != 0 && flags & libc::IFF_RUNNING != 0
+}
+
+/// Interfaces can only be bound on Linux.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn is_up(_interface: &str) -> bool {
+  true
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn bind_address() {
+    let bind = |s: &str| UpstreamBind::from_str(s).unwrap();
+    assert_eq!(
+      bind("192.0.2.10"),
+      UpstreamBind::Address("192.0.2.10".parse().unwrap())
+    );
+    assert_eq!(bind("wg0"), UpstreamBind::Interface("wg0".to_string()));
+    assert_eq!(bind("2001:db8::10").to_string(), "2001:db8::10");
+    assert!(UpstreamBind::from_str("192.0.2.10/24").is_err());
+    assert!(UpstreamBind::from_str("a-very-long-interface").is_err());
+    assert!(Binding::new(&[bind("192.0.2.10"), bind("192.0.2.11")]).is_err());
+
+    let binding = Binding::new(&[bind("127.0.0.1"), bind("::1")]).unwrap();
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = binding
+      .bind_udp("0.0.0.0:0".parse().unwrap(), server.local_addr().unwrap())
+      .unwrap();
+    assert_eq!(
+      socket.local_addr().unwrap().ip(),
+      "127.0.0.1".parse::<IpAddr>().unwrap()
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = binding
+      .connect_tcp(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    let (_, client) = listener.accept().await.unwrap();
+    assert_eq!(client, stream.local_addr().unwrap());
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn interface_state() {
+    assert!(is_up("lo"));
+    assert!(!is_up("nope0"));
+  }
+}
@@ -0,0 +1,196 @@
+//! Randomization of the case of the query names sent to the UDP upstreams (0x20, see
+//! draft-vixie-dnsext-dns0x20): a spoofed response must guess the case of each letter of the
+//! name besides the query id and the port.
+use hickory_server::proto::{
+  error::ProtoError,
+  op::Message,
+  rr::{Name, Record},
+  xfer::{DnsRequest, DnsResponse},
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Consecutive queries of a server answered without their random case that are tolerated, a
+/// few lost or truncated responses must not turn off the randomization.
+const TOLERATED_MISSES: u32 = 2;
+/// Delay before the names sent to a server not repeating their case are randomized again,
+/// doubled each time it still does not.
+const BACKOFF: Duration = Duration::from_secs(5 * 60);
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Name with the case of each letter drawn at random.
+pub fn randomize(name: &Name) -> Name {
+  let mut random = vec![0; name.len()];
+  // Without random bits, the name is sent as is.
+  let _ = SystemRandom::new().fill(&mut random);
+  let mut bits = random.into_iter();
+  let labels = name.iter().map(|label| {
+    label
+      .iter()
+      .zip(bits.by_ref())
+      .map(|(byte, bit)| match bit & 1 {
+        1 => byte.to_ascii_uppercase(),
+        _ => byte.to_ascii_lowercase(),
+      })
+      .collect::<Vec<u8>>()
+  });
+  let mut randomized = Name::from_labels(labels).unwrap_or_else(|_| name.clone());
+  randomized.set_fqdn(name.is_fqdn());
+  randomized
+}
+
+/// Name of the first query of a request.
+pub fn query_name(request: &DnsRequest) -> Option<Name> {
+  request.queries().first().map(|query| query.name().clone())
+}
+
+/// Replace the name of the first query of the request.
+pub fn set_query_name(request: &mut DnsRequest, name: Name) {
+  if let Some(query) = request.queries_mut().first_mut() {
+    query.set_name(name);
+  }
+}
+
+/// Whether the response repeats the query name with the exact same case.
+pub fn echoes(name: &Name, response: &Message) -> bool {
+  response
+    .queries()
+    .first()
+    .is_some_and(|query| query.name().to_ascii() == name.to_ascii())
+}
+
+/// Give back the case of the client to the query and to the records of the response, the
+/// answers would otherwise be sent with the random case.
+pub fn restore(response: DnsResponse, original: &Name) -> Result<DnsResponse, ProtoError> {
+  let mut message = response.into_message();
+  for query in message.queries_mut() {
+    if query.name() == original {
+      query.set_name(original.clone());
+    }
+  }
+  let restore_records = |records: &mut Vec<Record>| {
+    records
+      .iter_mut()
+      .filter(|record| record.name() == original)
+      .for_each(|record| {
+        record.set_name(original.clone());
+      });
+  };
+  restore_records(message.answers_mut());
+  restore_records(message.name_servers_mut());
+  restore_records(message.additionals_mut());
+  DnsResponse::from_message(message)
+}
+
+/// Servers answering without the random case of the queries, the names sent to them are not
+/// randomized until their backoff is over.
+#[derive(Debug, Default)]
+pub struct Echoes {
+  servers: Mutex<HashMap<SocketAddr, Misses>>,
+}
+
+#[derive(Debug, Default)]
+struct Misses {
+  consecutive: u32,
+  backoff: Duration,
+  /// End of the current backoff, kept once over so one more miss starts the next one.
+  until: Option<Instant>,
+}
+
+impl Echoes {
+  /// Whether the query names sent to `server` are randomized.
+  pub fn randomized(&self, server: SocketAddr) -> bool {
+    match self.servers.lock().unwrap().get(&server) {
+      Some(Misses {
+        until: Some(until), ..
+      }) => Instant::now() >= *until,
+      _ => true,
+    }
+  }
+
+  /// `server` repeated the random case of a query.
+  pub fn echoed(&self, server: SocketAddr) {
+    self.servers.lock().unwrap().remove(&server);
+  }
+
+  /// `server` did not repeat the random case of a query, returns the backoff when its names
+  /// are no longer randomized.
+  pub fn missed(&self, server: SocketAddr) -> Option<Duration> {
+    let mut servers = self.servers.lock().unwrap();
+    let misses = servers.entry(server).or_default();
+    misses.consecutive += 1;
+    if misses.until.is_none() && misses.consecutive <= TOLERATED_MISSES {
+      return None;
+    }
+    misses.backoff = match misses.backoff {
+      Duration::ZERO => BACKOFF,
+      backoff => (backoff * 2).min(MAX_BACKOFF),
+    };
+    misses.until = Some(Instant::now() + misses.backoff);
+    Some(misses.backoff)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::{
+    op::Query,
+    rr::{RData, RecordType},
+  };
+  use std::str::FromStr;
+
+  #[test]
+  fn randomize_case() {
+    let name = Name::from_str("www.example-test.com.").unwrap();
+    let randomized: Vec<Name> = (0..8).map(|_| randomize(&name)).collect();
+    assert!(randomized.iter().all(|random| *random == name));
+    assert!(randomized.iter().all(Name::is_fqdn));
+    assert!(randomized
+      .iter()
+      .any(|random| random.to_ascii() != name.to_ascii()));
+
+    let mut response = Message::new();
+    response.add_query(Query::query(randomized[0].clone(), RecordType::A));
+    response.add_answer(Record::from_rdata(
+      randomized[0].clone(),
+      60,
+      RData::A(std::net::Ipv4Addr::new(192, 0, 2, 1).into()),
+    ));
+    assert!(echoes(&randomized[0], &response));
+
+    let response = restore(DnsResponse::from_message(response).unwrap(), &name).unwrap();
+    assert!(echoes(&name, &response));
+    assert_eq!(response.answers()[0].name().to_ascii(), name.to_ascii());
+  }
+
+  #[test]
+  fn backoff() {
+    let echoes = Echoes::default();
+    let server = "192.0.2.53:53".parse().unwrap();
+    let other = "192.0.2.54:53".parse().unwrap();
+    assert_eq!(echoes.missed(server), None);
+    echoes.echoed(server);
+    assert_eq!(echoes.missed(server), None);
+    assert_eq!(echoes.missed(server), None);
+    assert_eq!(echoes.missed(server), Some(BACKOFF));
+    assert!(!echoes.randomized(server));
+    assert!(echoes.randomized(other));
+
+    // Once the backoff is over, a single miss starts a longer one.
+    let expire = || {
+      let mut servers = echoes.servers.lock().unwrap();
+      servers.get_mut(&server).unwrap().until = Some(Instant::now());
+    };
+    expire();
+    assert!(echoes.randomized(server));
+    assert_eq!(echoes.missed(server), Some(BACKOFF * 2));
+    expire();
+    echoes.echoed(server);
+    assert!(echoes.randomized(server));
+    assert_eq!(echoes.missed(server), None);
+  }
+}
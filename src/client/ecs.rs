@@ -0,0 +1,261 @@
+use crate::client::case;
+use crate::client::proxy::ProxyRuntimeProvider;
+use crate::events;
+use anyhow::{anyhow, bail};
+use futures_util::stream::{self, Stream, StreamExt};
+use hickory_server::proto::{
+  op::Edns,
+  rr::{
+    rdata::opt::{ClientSubnet, EdnsOption},
+    Name,
+  },
+  xfer::{DnsHandle, DnsRequest, DnsResponse},
+};
+use hickory_server::resolver::{
+  config::{NameServerConfig, Protocol, ResolverOpts},
+  error::ResolveError,
+  name_server::{ConnectionProvider, GenericConnection, GenericConnector},
+};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// What is sent to the upstreams as EDNS Client Subnet (RFC 7871).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ecs {
+  /// Never send a client subnet.
+  #[default]
+  Strip,
+  /// Send the client subnet of the query, if any. Answers are not cached since they depend on
+  /// the subnet.
+  Forward,
+  /// Always send this subnet.
+  Set(IpNet),
+}
+
+impl Ecs {
+  /// Subnet to add to the query currently being answered.
+  fn subnet(&self) -> Option<ClientSubnet> {
+    match self {
+      Ecs::Strip => None,
+      Ecs::Forward => events::client_subnet(),
+      Ecs::Set(net) => Some(ClientSubnet::from(*net)),
+    }
+  }
+}
+
+impl FromStr for Ecs {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "strip" => Ok(Ecs::Strip),
+      "forward" => Ok(Ecs::Forward),
+      _ => {
+        let Some(prefix) = s.strip_prefix("set:") else {
+          bail!("unknown ECS mode `{s}`, expected strip, forward or set:<prefix>");
+        };
+        let net = IpNet::from_str(prefix).map_err(|_| anyhow!("invalid ECS prefix `{prefix}`"))?;
+        // Bits beyond the prefix length must be zero.
+        Ok(Ecs::Set(net.trunc()))
+      }
+    }
+  }
+}
+
+impl fmt::Display for Ecs {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Ecs::Strip => write!(f, "strip"),
+      Ecs::Forward => write!(f, "forward"),
+      Ecs::Set(net) => write!(f, "set:{}", net),
+    }
+  }
+}
+
+impl Serialize for Ecs {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Ecs {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Ecs::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Connections of the upstream resolvers, adding the client subnet to the queries and
+/// randomizing the case of their names over UDP.
+#[derive(Clone)]
+pub struct EcsConnector {
+  inner: GenericConnector<ProxyRuntimeProvider>,
+  ecs: Ecs,
+  randomize_case: bool,
+  /// Name servers of the upstream not repeating the case of the queries.
+  echoes: Arc<case::Echoes>,
+}
+
+impl EcsConnector {
+  pub fn new(runtime: ProxyRuntimeProvider, ecs: Ecs, randomize_case: bool) -> Self {
+    Self {
+      inner: GenericConnector::new(runtime),
+      ecs,
+      randomize_case,
+      echoes: Arc::new(case::Echoes::default()),
+    }
+  }
+}
+
+impl ConnectionProvider for EcsConnector {
+  type Conn = EcsConnection;
+  type FutureConn = Pin<Box<dyn Future<Output = Result<EcsConnection, ResolveError>> + Send>>;
+  type RuntimeProvider = ProxyRuntimeProvider;
+
+  fn new_connection(&self, config: &NameServerConfig, options: &ResolverOpts) -> Self::FutureConn {
+    let connection = self.inner.new_connection(config, options);
+    let ecs = self.ecs;
+    let server = config.socket_addr;
+    // Over TCP and the encrypted transports, the responses cannot be spoofed off-path.
+    let echoes =
+      (self.randomize_case && config.protocol == Protocol::Udp).then(|| self.echoes.clone());
+    Box::pin(async move {
+      Ok(EcsConnection {
+        inner: connection.await?,
+        ecs,
+        server,
+        echoes,
+      })
+    })
+  }
+}
+
+#[derive(Clone)]
+pub struct EcsConnection {
+  inner: GenericConnection,
+  ecs: Ecs,
+  server: SocketAddr,
+  /// Servers not repeating the case of the query names, none when it is never randomized.
+  echoes: Option<Arc<case::Echoes>>,
+}
+
+impl DnsHandle for EcsConnection {
+  type Response = Pin<Box<dyn Stream<Item = Result<DnsResponse, ResolveError>> + Send>>;
+  type Error = ResolveError;
+
+  fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&self, request: R) -> Self::Response {
+    let mut request = request.into();
+    events::set_upstream_sent();
+    if let Some(subnet) = self.ecs.subnet() {
+      add_client_subnet(&mut request, subnet);
+    }
+    let echoes = self
+      .echoes
+      .clone()
+      .filter(|echoes| echoes.randomized(self.server));
+    match (echoes, case::query_name(&request)) {
+      (Some(echoes), Some(name)) => Box::pin(stream::once(
+        self.clone().send_randomized(request, name, echoes),
+      )),
+      _ => Box::pin(self.inner.send(request)),
+    }
+  }
+}
+
+impl EcsConnection {
+  /// Send the query with a random case and ignore the responses without this exact case. A
+  /// second random case is tried, then the query is sent as is when the server does not
+  /// repeat it either. After a few such queries in a row, the names sent to the server are no
+  /// longer randomized for a while.
+  async fn send_randomized(
+    self,
+    request: DnsRequest,
+    original: Name,
+    echoes: Arc<case::Echoes>,
+  ) -> Result<DnsResponse, ResolveError> {
+    for _ in 0..2 {
+      let name = case::randomize(&original);
+      let mut randomized = request.clone();
+      case::set_query_name(&mut randomized, name.clone());
+      let response = first_response(self.inner.send(randomized)).await?;
+      if case::echoes(&name, &response) {
+        echoes.echoed(self.server);
+        return Ok(case::restore(response, &original)?);
+      }
+      debug!(
+        "Ignored a response of {} to {} without the case of the query",
+        self.server, name
+      );
+    }
+    if let Some(backoff) = echoes.missed(self.server) {
+      warn!(
+        "Upstream {} does not repeat the case of the queries, their names are not randomized for {}s",
+        self.server,
+        backoff.as_secs()
+      );
+    }
+    first_response(self.inner.send(request)).await
+  }
+}
+
+async fn first_response(
+  mut responses: impl Stream<Item = Result<DnsResponse, ResolveError>> + Unpin,
+) -> Result<DnsResponse, ResolveError> {
+  responses
+    .next()
+    .await
+    .unwrap_or_else(|| Err(ResolveError::from("no response from the upstream")))
+}
+
+fn add_client_subnet(request: &mut DnsRequest, subnet: ClientSubnet) {
+  let edns = request.extensions_mut().get_or_insert_with(|| {
+    let mut edns = Edns::new();
+    edns.set_max_payload(EDNS_PAYLOAD_SIZE);
+    edns
+  });
+  edns.options_mut().insert(EdnsOption::Subnet(subnet));
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::{op::Message, rr::rdata::opt::EdnsCode};
+
+  #[test]
+  fn parse_ecs() {
+    assert_eq!(Ecs::from_str("strip").unwrap(), Ecs::Strip);
+    assert_eq!(Ecs::from_str("forward").unwrap(), Ecs::Forward);
+    assert_eq!(
+      Ecs::from_str("set:203.0.113.7/24").unwrap(),
+      Ecs::Set("203.0.113.0/24".parse().unwrap())
+    );
+    assert_eq!(
+      Ecs::from_str("set:2001:db8::/56").unwrap().to_string(),
+      "set:2001:db8::/56"
+    );
+    assert!(Ecs::from_str("set:203.0.113.0").is_err());
+    assert!(Ecs::from_str("keep").is_err());
+  }
+
+  #[test]
+  fn client_subnet_option() {
+    let mut request = DnsRequest::from(Message::new());
+    let subnet = ClientSubnet::from_str("203.0.113.0/24").unwrap();
+    add_client_subnet(&mut request, subnet);
+    let edns = request.extensions().as_ref().unwrap();
+    assert_eq!(edns.max_payload(), EDNS_PAYLOAD_SIZE);
+    assert_eq!(
+      edns.option(EdnsCode::Subnet),
+      Some(&EdnsOption::Subnet(subnet))
+    );
+  }
+}
@@ -1,11 +1,48 @@
-use anyhow::{anyhow, bail, Result};
+use crate::client::bind::Binding;
+use crate::client::proxy::ProxyRuntimeProvider;
+use anyhow::{anyhow, bail, Context, Result};
 use clap::{
   builder::{PossibleValue, TypedValueParser, ValueParserFactory},
   Arg, Command,
 };
-use hickory_server::resolver::config::NameServerConfigGroup;
+use hickory_rustls::ServerName;
+use hickory_server::resolver::{
+  config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+  name_server::GenericConnector,
+  AsyncResolver,
+};
 use regex::Regex;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+  fmt,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  str::FromStr,
+  sync::Arc,
+};
+use tracing::info;
+
+pub mod bind;
+pub mod case;
+pub mod ecs;
+pub mod proxy;
+pub mod tls;
+
+/// AdGuard DNS default servers, blocking ads and trackers.
+const ADGUARD_IPS: &[IpAddr] = &[
+  IpAddr::V4(Ipv4Addr::new(94, 140, 14, 14)),
+  IpAddr::V4(Ipv4Addr::new(94, 140, 15, 15)),
+  IpAddr::V6(Ipv6Addr::new(0x2a10, 0x50c0, 0, 0, 0, 0, 0xad1, 0xff)),
+  IpAddr::V6(Ipv6Addr::new(0x2a10, 0x50c0, 0, 0, 0, 0, 0xad2, 0xff)),
+];
+const ADGUARD_DOMAIN: &str = "dns.adguard-dns.com";
+/// Cisco OpenDNS Home servers.
+const OPENDNS_IPS: &[IpAddr] = &[
+  IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222)),
+  IpAddr::V4(Ipv4Addr::new(208, 67, 220, 220)),
+  IpAddr::V6(Ipv6Addr::new(0x2620, 0x119, 0x35, 0, 0, 0, 0, 0x35)),
+  IpAddr::V6(Ipv6Addr::new(0x2620, 0x119, 0x53, 0, 0, 0, 0, 0x53)),
+];
+const OPENDNS_DOMAIN: &str = "doh.opendns.com";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ClientType {
@@ -15,32 +52,147 @@ pub enum ClientType {
   GoogleTLS,
   CloudFlareH2,
   GoogleH2,
+  Quad9,
+  Quad9TLS,
+  Quad9H2,
+  AdGuard,
+  AdGuardTLS,
+  AdGuardH2,
+  OpenDNS,
+  OpenDNSH2,
   CustomDNS(IpAddr, u16),
-  CustomTLS(IpAddr, String, u16),
+  /// `ip:port:tls:domain[:sni=name]`, the certificate is checked against the domain while the
+  /// SNI sends the name, to reach servers behind a front.
+  CustomTLS(IpAddr, String, u16, Option<String>),
   CustomH2(IpAddr, String, u16),
+  CustomQuic(IpAddr, String, u16),
+  /// `https://domain[:port]/dns-query`, the IPs of the domain are found with
+  /// [`ClientType::bootstrap`] before the client is used.
+  CustomHttps(String, u16, Vec<IpAddr>),
 }
 
-impl Into<NameServerConfigGroup> for ClientType {
-  fn into(self) -> NameServerConfigGroup {
-    match self {
+impl ClientType {
+  /// Resolve the domain of an `https://` upstream with the `bootstrap` resolver, queried from the
+  /// bound address and interface. The other upstreams are returned unchanged.
+  pub async fn bootstrap(
+    self,
+    bootstrap: &ClientType,
+    binding: &Arc<Binding>,
+  ) -> Result<ClientType> {
+    let ClientType::CustomHttps(domain, port, ips) = self else {
+      return Ok(self);
+    };
+    if !ips.is_empty() {
+      return Ok(ClientType::CustomHttps(domain, port, ips));
+    }
+    if let Ok(ip) = domain.trim_start_matches('[').trim_end_matches(']').parse() {
+      return Ok(ClientType::CustomHttps(domain, port, vec![ip]));
+    }
+    if matches!(bootstrap, ClientType::CustomHttps(..)) {
+      bail!("The bootstrap dns server {} must use an IP", bootstrap);
+    }
+
+    let config = ResolverConfig::from_parts(None, vec![], bootstrap.clone());
+    let connector = GenericConnector::new(ProxyRuntimeProvider::new(None, binding.clone()));
+    let resolver = AsyncResolver::new(config, ResolverOpts::default(), connector);
+    let ips: Vec<IpAddr> = resolver
+      .lookup_ip(format!("{domain}."))
+      .await
+      .with_context(|| format!("could not resolve {} with {}", domain, bootstrap))?
+      .iter()
+      .collect();
+    info!("Dns server {} resolved to {:?}", domain, ips);
+    Ok(ClientType::CustomHttps(domain, port, ips))
+  }
+}
+
+impl From<ClientType> for NameServerConfigGroup {
+  fn from(client: ClientType) -> Self {
+    match client {
       ClientType::Google => NameServerConfigGroup::google(),
       ClientType::CloudFlare => NameServerConfigGroup::cloudflare(),
       ClientType::GoogleTLS => NameServerConfigGroup::google_tls(),
       ClientType::CloudFlareTLS => NameServerConfigGroup::cloudflare_tls(),
       ClientType::CloudFlareH2 => NameServerConfigGroup::cloudflare_https(),
       ClientType::GoogleH2 => NameServerConfigGroup::google_https(),
+      ClientType::Quad9 => NameServerConfigGroup::quad9(),
+      ClientType::Quad9TLS => NameServerConfigGroup::quad9_tls(),
+      ClientType::Quad9H2 => NameServerConfigGroup::quad9_https(),
+      ClientType::AdGuard => NameServerConfigGroup::from_ips_clear(ADGUARD_IPS, 53, true),
+      ClientType::AdGuardTLS => {
+        NameServerConfigGroup::from_ips_tls(ADGUARD_IPS, 853, ADGUARD_DOMAIN.to_string(), true)
+      }
+      ClientType::AdGuardH2 => {
+        NameServerConfigGroup::from_ips_https(ADGUARD_IPS, 443, ADGUARD_DOMAIN.to_string(), true)
+      }
+      ClientType::OpenDNS => NameServerConfigGroup::from_ips_clear(OPENDNS_IPS, 53, true),
+      ClientType::OpenDNSH2 => {
+        NameServerConfigGroup::from_ips_https(OPENDNS_IPS, 443, OPENDNS_DOMAIN.to_string(), true)
+      }
       ClientType::CustomDNS(ip, port) => NameServerConfigGroup::from_ips_clear(&[ip], port, true),
-      ClientType::CustomTLS(ip, domain, port) => {
-        NameServerConfigGroup::from_ips_tls(&[ip], port, domain, true)
+      // The SNI is taken from the name of the config, see [`tls::validate_as`].
+      ClientType::CustomTLS(ip, domain, port, sni) => {
+        NameServerConfigGroup::from_ips_tls(&[ip], port, sni.unwrap_or(domain), true)
       }
       ClientType::CustomH2(ip, domain, port) => {
         NameServerConfigGroup::from_ips_https(&[ip], port, domain, true)
       }
+      ClientType::CustomQuic(ip, domain, port) => {
+        NameServerConfigGroup::from_ips_quic(&[ip], port, domain, true)
+      }
+      ClientType::CustomHttps(domain, port, ips) => {
+        let domain = domain.trim_start_matches('[').trim_end_matches(']');
+        NameServerConfigGroup::from_ips_https(&ips, port, domain.to_string(), true)
+      }
+    }
+  }
+}
+
+impl fmt::Display for ClientType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ClientType::CloudFlare => write!(f, "cloudflare"),
+      ClientType::Google => write!(f, "google"),
+      ClientType::CloudFlareTLS => write!(f, "cloudflare:tls"),
+      ClientType::GoogleTLS => write!(f, "google:tls"),
+      ClientType::CloudFlareH2 => write!(f, "cloudflare:h2"),
+      ClientType::GoogleH2 => write!(f, "google:h2"),
+      ClientType::Quad9 => write!(f, "quad9"),
+      ClientType::Quad9TLS => write!(f, "quad9:tls"),
+      ClientType::Quad9H2 => write!(f, "quad9:h2"),
+      ClientType::AdGuard => write!(f, "adguard"),
+      ClientType::AdGuardTLS => write!(f, "adguard:tls"),
+      ClientType::AdGuardH2 => write!(f, "adguard:h2"),
+      ClientType::OpenDNS => write!(f, "opendns"),
+      ClientType::OpenDNSH2 => write!(f, "opendns:h2"),
+      ClientType::CustomDNS(ip, port) => write!(f, "{}", SocketAddr::new(*ip, *port)),
+      ClientType::CustomTLS(ip, domain, port, None) => {
+        write!(f, "{}:tls:{}", SocketAddr::new(*ip, *port), domain)
+      }
+      ClientType::CustomTLS(ip, domain, port, Some(sni)) => {
+        write!(
+          f,
+          "{}:tls:{}:sni={}",
+          SocketAddr::new(*ip, *port),
+          domain,
+          sni
+        )
+      }
+      ClientType::CustomH2(ip, domain, port) => {
+        write!(f, "{}:h2:{}", SocketAddr::new(*ip, *port), domain)
+      }
+      ClientType::CustomQuic(ip, domain, port) => {
+        write!(f, "{}:doq:{}", SocketAddr::new(*ip, *port), domain)
+      }
+      ClientType::CustomHttps(domain, 443, _) => write!(f, "https://{}/dns-query", domain),
+      ClientType::CustomHttps(domain, port, _) => {
+        write!(f, "https://{}:{}/dns-query", domain, port)
+      }
     }
   }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ClientTypeParser {}
 
 impl ClientTypeParser {
@@ -56,10 +208,20 @@ impl ClientTypeParser {
       "google:tls",
       "cloudflare:h2",
       "google:h2",
+      "quad9",
+      "quad9:tls",
+      "quad9:h2",
+      "adguard",
+      "adguard:tls",
+      "adguard:h2",
+      "opendns",
+      "opendns:h2",
       "ipv4:port",
       "[ipv6]:port",
-      "ipv4:port:<tls|h2>:domain",
-      "[ipv6]:port:<tls|h2>:domain",
+      "ipv4:port:<tls|h2|doq>:domain",
+      "[ipv6]:port:<tls|h2|doq>:domain",
+      "ipv4:port:tls:domain:sni=name",
+      "https://domain[:port]/dns-query",
     ]
   }
 }
@@ -74,49 +236,41 @@ impl TypedValueParser for ClientTypeParser {
     value: &std::ffi::OsStr,
   ) -> Result<Self::Value, clap::Error> {
     use clap::error::{ContextKind, ContextValue};
-    match value.to_string_lossy().to_lowercase().as_str() {
-      "cloudflare" => Ok(ClientType::CloudFlare),
-      "google" => Ok(ClientType::Google),
-      "cloudflare:tls" => Ok(ClientType::CloudFlareTLS),
-      "google:tls" => Ok(ClientType::GoogleTLS),
-      "cloudflare:h2" => Ok(ClientType::CloudFlareH2),
-      "google:h2" => Ok(ClientType::GoogleH2),
-      s => match ClientType::try_from(s) {
-        Ok(client) => Ok(client),
-        Err(client_err) => {
-          let mut error = clap::Error::new(clap::error::ErrorKind::InvalidValue).with_cmd(cmd);
-          error.insert(
-            ContextKind::InvalidArg,
-            ContextValue::String(arg.unwrap().to_string()),
-          );
+    match ClientType::from_str(&value.to_string_lossy()) {
+      Ok(client) => Ok(client),
+      Err(client_err) => {
+        let mut error = clap::Error::new(clap::error::ErrorKind::InvalidValue).with_cmd(cmd);
+        error.insert(
+          ContextKind::InvalidArg,
+          ContextValue::String(arg.unwrap().to_string()),
+        );
+        error.insert(
+          ContextKind::InvalidValue,
+          ContextValue::String(value.to_string_lossy().to_string()),
+        );
+        error.insert(
+          ContextKind::ValidValue,
+          ContextValue::Strings(
+            Self::possible_vals()
+              .iter()
+              .map(|value| value.to_string())
+              .collect(),
+          ),
+        );
+        if !client_err.to_string().is_empty() {
           error.insert(
-            ContextKind::InvalidValue,
-            ContextValue::String(value.to_string_lossy().to_string()),
+            ContextKind::SuggestedValue,
+            ContextValue::String(client_err.to_string()),
           );
-          error.insert(
-            ContextKind::ValidValue,
-            ContextValue::Strings(
-              Self::possible_vals()
-                .iter()
-                .map(|value| value.to_string())
-                .collect(),
-            ),
-          );
-          if !client_err.to_string().is_empty() {
-            error.insert(
-              ContextKind::SuggestedValue,
-              ContextValue::String(client_err.to_string()),
-            );
-          }
-          Err(error)
         }
-      },
+        Err(error)
+      }
     }
   }
 
   fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
     let vals = Self::possible_vals();
-    let values: Vec<PossibleValue> = vals.iter().map(|name| PossibleValue::new(name)).collect();
+    let values: Vec<PossibleValue> = vals.iter().map(PossibleValue::new).collect();
     Some(Box::new(values.into_iter()))
   }
 }
@@ -128,12 +282,80 @@ impl ValueParserFactory for ClientType {
   }
 }
 
+impl FromStr for ClientType {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<ClientType, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "cloudflare" => Ok(ClientType::CloudFlare),
+      "google" => Ok(ClientType::Google),
+      "cloudflare:tls" => Ok(ClientType::CloudFlareTLS),
+      "google:tls" => Ok(ClientType::GoogleTLS),
+      "cloudflare:h2" => Ok(ClientType::CloudFlareH2),
+      "google:h2" => Ok(ClientType::GoogleH2),
+      "quad9" => Ok(ClientType::Quad9),
+      "quad9:tls" => Ok(ClientType::Quad9TLS),
+      "quad9:h2" => Ok(ClientType::Quad9H2),
+      "adguard" => Ok(ClientType::AdGuard),
+      "adguard:tls" => Ok(ClientType::AdGuardTLS),
+      "adguard:h2" => Ok(ClientType::AdGuardH2),
+      "opendns" => Ok(ClientType::OpenDNS),
+      "opendns:h2" => Ok(ClientType::OpenDNSH2),
+      s if s.starts_with("https://") => ClientType::parse_url(s),
+      s => ClientType::try_from(s),
+    }
+  }
+}
+
+impl Serialize for ClientType {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for ClientType {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ClientType::from_str(&value).map_err(|err| {
+      if err.to_string().is_empty() {
+        de::Error::custom(format!("invalid dns server {value}"))
+      } else {
+        de::Error::custom(err)
+      }
+    })
+  }
+}
+
+impl ClientType {
+  fn parse_url(url: &str) -> Result<ClientType> {
+    let url = url.trim_start_matches("https://");
+    let (authority, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+    if !path.is_empty() && path != "/dns-query" {
+      bail!("Only the /dns-query path is supported, found {}", path);
+    }
+    let (domain, port) = match authority.rsplit_once(':') {
+      Some((domain, port)) if !domain.starts_with('[') || domain.ends_with(']') => {
+        let port = port.parse::<u16>()?;
+        if port == 0 {
+          bail!("Port must be greater than 0. found {}", port);
+        }
+        (domain, port)
+      }
+      _ => (authority, 443),
+    };
+    if domain.is_empty() {
+      bail!("No domain found for HTTPS connection.");
+    }
+    Ok(ClientType::CustomHttps(domain.to_string(), port, vec![]))
+  }
+}
+
 impl TryFrom<&str> for ClientType {
   type Error = anyhow::Error;
 
   fn try_from(s: &str) -> Result<ClientType, Self::Error> {
     let regex =
-      Regex::new(r"^((?<ipv4>\d+.\d+.\d+.\d+)|\[(?<ipv6>[a-fA-F0-9:]+)\])(:(?<port>\d+)?:?((?<proto>h2|tls):(?<domain>.*))?)?$")
+      Regex::new(r"^((?<ipv4>\d+.\d+.\d+.\d+)|\[(?<ipv6>[a-fA-F0-9:]+)\])(:(?<port>\d+)?:?((?<proto>h2|tls|doq):(?<domain>.*))?)?$")
         .unwrap();
     let Some(caps) = regex.captures(s) else {
       bail!("");
@@ -158,25 +380,36 @@ impl TryFrom<&str> for ClientType {
       }
     })?;
 
-    let proto = caps
-      .name("proto")
-      .map_or(None, |proto| Some(proto.as_str()));
+    let proto = caps.name("proto").map(|proto| proto.as_str());
 
     let domain = caps
       .name("domain")
-      .map_or(None, |domain| Some(domain.as_str().to_string()));
+      .map(|domain| domain.as_str().to_string());
 
     match proto {
-      Some("tls") => Ok(ClientType::CustomTLS(
-        ip,
-        domain.ok_or_else(|| anyhow!("No domain found for TLS connection."))?,
-        port.unwrap_or(853),
-      )),
+      Some("tls") => {
+        let domain = domain.ok_or_else(|| anyhow!("No domain found for TLS connection."))?;
+        let (domain, sni) = match domain.split_once(":sni=") {
+          Some((domain, sni)) => (domain.to_string(), Some(sni.to_string())),
+          None => (domain, None),
+        };
+        for name in std::iter::once(&domain).chain(sni.iter()) {
+          if ServerName::try_from(name.as_str()).is_err() {
+            bail!("Invalid TLS server name {}", name);
+          }
+        }
+        Ok(ClientType::CustomTLS(ip, domain, port.unwrap_or(853), sni))
+      }
       Some("h2") => Ok(ClientType::CustomH2(
         ip,
         domain.ok_or_else(|| anyhow!("No domain found for TLS connection."))?,
         port.unwrap_or(443),
       )),
+      Some("doq") => Ok(ClientType::CustomQuic(
+        ip,
+        domain.ok_or_else(|| anyhow!("No domain found for QUIC connection."))?,
+        port.unwrap_or(853),
+      )),
       None => Ok(ClientType::CustomDNS(ip, port.unwrap_or(53))),
       _ => bail!("The protocol {} is not supported", proto.unwrap()),
     }
@@ -242,22 +475,51 @@ mod test {
 
     assert_eq!(
       ip4.unwrap(),
-      ClientType::CustomTLS(ipv4("1.1.1.1"), cloudflare.to_string(), 853)
+      ClientType::CustomTLS(ipv4("1.1.1.1"), cloudflare.to_string(), 853, None)
     );
     assert_eq!(
       ip4_port.unwrap(),
-      ClientType::CustomTLS(ipv4("1.1.1.1"), cloudflare.to_string(), 1853)
+      ClientType::CustomTLS(ipv4("1.1.1.1"), cloudflare.to_string(), 1853, None)
     );
     assert_eq!(
       ip6.unwrap(),
-      ClientType::CustomTLS(ipv6("2606:4700:4700::1111"), cloudflare.to_string(), 853)
+      ClientType::CustomTLS(
+        ipv6("2606:4700:4700::1111"),
+        cloudflare.to_string(),
+        853,
+        None
+      )
     );
     assert_eq!(
       ip6_port.unwrap(),
-      ClientType::CustomTLS(ipv6("2606:4700:4700::1111"), cloudflare.to_string(), 1853)
+      ClientType::CustomTLS(
+        ipv6("2606:4700:4700::1111"),
+        cloudflare.to_string(),
+        1853,
+        None
+      )
+    );
+
+    let fronted = ClientType::try_from("1.1.1.1:tls:cloudflare-dns.com:sni=front.example").unwrap();
+    assert_eq!(
+      fronted,
+      ClientType::CustomTLS(
+        ipv4("1.1.1.1"),
+        cloudflare.to_string(),
+        853,
+        Some("front.example".to_string())
+      )
+    );
+    assert_eq!(
+      fronted.to_string(),
+      "1.1.1.1:853:tls:cloudflare-dns.com:sni=front.example"
     );
+    let fronted: NameServerConfigGroup = fronted.into();
+    assert_eq!(fronted[0].tls_dns_name.as_deref(), Some("front.example"));
 
     assert!(ClientType::try_from("1.1.1.1:853:tls").is_err());
+    assert!(ClientType::try_from("1.1.1.1:tls:cloudflare-dns.com:sni=").is_err());
+    assert!(ClientType::try_from("1.1.1.1:tls:cloudflare-dns.com:sni=front example").is_err());
     assert!(ClientType::try_from("1.1.1.1:-853:tls:cloudflare-dns.com").is_err());
     assert!(ClientType::try_from("1.1.1.1:0:tls:cloudflare-dns.com").is_err());
     assert!(ClientType::try_from("example.com:853:tls:cloudflare-dns.com").is_err());
@@ -301,4 +563,88 @@ mod test {
     assert!(ClientType::try_from("example.com:h2:cloudflare-dns.com").is_err());
     assert!(ClientType::try_from("256.255.254.253:h2:cloudflare-dns.com").is_err());
   }
+
+  #[test]
+  pub fn covert_custom_quic() {
+    let adguard = "dns.adguard-dns.com";
+    let ip4 = ClientType::try_from("94.140.14.14:doq:dns.adguard-dns.com");
+    let ip6_port = ClientType::try_from("[2a10:50c0::ad1:ff]:8853:doq:dns.adguard-dns.com");
+
+    assert_eq!(
+      ip4.unwrap(),
+      ClientType::CustomQuic(ipv4("94.140.14.14"), adguard.to_string(), 853)
+    );
+    assert_eq!(
+      ip6_port.unwrap(),
+      ClientType::CustomQuic(ipv6("2a10:50c0::ad1:ff"), adguard.to_string(), 8853)
+    );
+    assert_eq!(
+      ClientType::CustomQuic(ipv4("94.140.14.14"), adguard.to_string(), 853).to_string(),
+      "94.140.14.14:853:doq:dns.adguard-dns.com"
+    );
+
+    assert!(ClientType::try_from("94.140.14.14:853:doq").is_err());
+    assert!(ClientType::try_from("94.140.14.14:0:doq:dns.adguard-dns.com").is_err());
+    assert!(ClientType::try_from("example.com:doq:dns.adguard-dns.com").is_err());
+  }
+
+  #[test]
+  pub fn presets() {
+    for preset in [
+      "quad9",
+      "quad9:tls",
+      "quad9:h2",
+      "adguard",
+      "adguard:tls",
+      "adguard:h2",
+      "opendns",
+      "opendns:h2",
+    ] {
+      assert_eq!(ClientType::from_str(preset).unwrap().to_string(), preset);
+    }
+    let adguard: NameServerConfigGroup = ClientType::AdGuardTLS.into();
+    assert_eq!(adguard.len(), 4);
+    assert_eq!(adguard[0].tls_dns_name.as_deref(), Some(ADGUARD_DOMAIN));
+  }
+
+  #[test]
+  pub fn covert_custom_https() {
+    let https = |domain: &str, port| ClientType::CustomHttps(domain.to_string(), port, vec![]);
+    assert_eq!(
+      ClientType::from_str("https://dns.example.com/dns-query").unwrap(),
+      https("dns.example.com", 443)
+    );
+    assert_eq!(
+      ClientType::from_str("https://dns.example.com:8443").unwrap(),
+      https("dns.example.com", 8443)
+    );
+    assert_eq!(
+      ClientType::from_str("https://[2606:4700:4700::1111]/dns-query").unwrap(),
+      https("[2606:4700:4700::1111]", 443)
+    );
+    assert_eq!(
+      https("dns.example.com", 8443).to_string(),
+      "https://dns.example.com:8443/dns-query"
+    );
+
+    assert!(ClientType::from_str("https://dns.example.com/resolve").is_err());
+    assert!(ClientType::from_str("https://dns.example.com:0/dns-query").is_err());
+    assert!(ClientType::from_str("https:///dns-query").is_err());
+  }
+
+  #[tokio::test]
+  async fn bootstrap_ip() {
+    let client = ClientType::from_str("https://[2606:4700:4700::1111]/dns-query").unwrap();
+    assert_eq!(
+      client
+        .bootstrap(&ClientType::Google, &Arc::default())
+        .await
+        .unwrap(),
+      ClientType::CustomHttps(
+        "[2606:4700:4700::1111]".to_string(),
+        443,
+        vec![ipv6("2606:4700:4700::1111")]
+      )
+    );
+  }
 }
@@ -0,0 +1,314 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hickory_rustls::{
+  client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+  Certificate, CertificateError, ClientConfig, Error as TlsError, RootCertStore, ServerName,
+};
+use hickory_server::proto::rustls::tls_server::read_cert;
+use ring::digest::{digest, SHA256};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::warn;
+
+/// TLS configuration of the DoT, DoH and DoQ upstreams, trusting the system roots and `ca`.
+///
+/// When `pins` are set, the certificate chain must contain a public key whose SHA-256 (base64,
+/// optionally prefixed by `sha256/`) is one of them. The chain is then only checked against the
+/// roots when a `ca` is given too, so self-signed certificates can be pinned.
+pub fn client_config(ca: Option<&Path>, pins: &[String]) -> Result<Arc<ClientConfig>> {
+  Ok(config(verifier(ca, pins)?))
+}
+
+/// Verifier of the certificates of the upstreams, see [`client_config`].
+pub fn verifier(ca: Option<&Path>, pins: &[String]) -> Result<Arc<dyn ServerCertVerifier>> {
+  let mut roots = system_roots();
+  if let Some(ca) = ca {
+    let certs = read_cert(ca).map_err(|err| anyhow!("could not read {}: {}", ca.display(), err))?;
+    if certs.is_empty() {
+      bail!("no certificate found in {}", ca.display());
+    }
+    for cert in certs.iter() {
+      roots
+        .add(cert)
+        .with_context(|| format!("invalid CA certificate in {}", ca.display()))?;
+    }
+  }
+
+  if pins.is_empty() {
+    return Ok(Arc::new(WebPkiVerifier::new(roots, None)));
+  }
+  Ok(Arc::new(PinnedVerifier {
+    pins: pins
+      .iter()
+      .map(|pin| parse_pin(pin))
+      .collect::<Result<_>>()?,
+    roots: ca.map(|_| WebPkiVerifier::new(roots, None)),
+  }))
+}
+
+/// Verifier of the certificates trusting the system roots only.
+pub fn system_verifier() -> Arc<dyn ServerCertVerifier> {
+  Arc::new(WebPkiVerifier::new(system_roots(), None))
+}
+
+fn system_roots() -> RootCertStore {
+  let mut roots = RootCertStore::empty();
+  match rustls_native_certs::load_native_certs() {
+    Ok(certs) => {
+      roots.add_parsable_certificates(&certs);
+    }
+    Err(err) => warn!("Could not load the system root certificates: {}", err),
+  }
+  roots
+}
+
+/// TLS configuration checking the certificates with `verifier`.
+pub fn config(verifier: Arc<dyn ServerCertVerifier>) -> Arc<ClientConfig> {
+  let config = ClientConfig::builder()
+    .with_safe_defaults()
+    .with_custom_certificate_verifier(verifier)
+    .with_no_client_auth();
+  Arc::new(config)
+}
+
+/// TLS configuration checking the certificates against `name` whatever the server name sent in
+/// the SNI, for the upstreams reached through a front (`sni=`).
+pub fn validate_as(verifier: Arc<dyn ServerCertVerifier>, name: &str) -> Arc<ClientConfig> {
+  config(Arc::new(NameVerifier {
+    name: name.to_string(),
+    inner: verifier,
+  }))
+}
+
+/// TLS configuration accepting any certificate, to query a local server without its CA.
+pub fn insecure_client_config() -> Arc<ClientConfig> {
+  config(Arc::new(NoVerifier))
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+  let encoded = pin.strip_prefix("sha256/").unwrap_or(pin);
+  let decoded = STANDARD
+    .decode(encoded)
+    .with_context(|| format!("invalid pin `{pin}`, expected a base64 SHA-256"))?;
+  decoded
+    .try_into()
+    .map_err(|_| anyhow!("invalid pin `{pin}`, expected a base64 SHA-256"))
+}
+
+struct PinnedVerifier {
+  pins: Vec<[u8; 32]>,
+  roots: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    server_name: &ServerName,
+    scts: &mut dyn Iterator<Item = &[u8]>,
+    ocsp_response: &[u8],
+    now: SystemTime,
+  ) -> Result<ServerCertVerified, TlsError> {
+    if let Some(roots) = &self.roots {
+      roots.verify_server_cert(
+        end_entity,
+        intermediates,
+        server_name,
+        scts,
+        ocsp_response,
+        now,
+      )?;
+    }
+    let pinned = std::iter::once(end_entity)
+      .chain(intermediates)
+      .filter_map(|cert| subject_public_key_info(&cert.0))
+      .any(|spki| {
+        let hash = digest(&SHA256, spki);
+        self.pins.iter().any(|pin| pin == hash.as_ref())
+      });
+    if pinned {
+      Ok(ServerCertVerified::assertion())
+    } else {
+      Err(TlsError::InvalidCertificate(
+        CertificateError::ApplicationVerificationFailure,
+      ))
+    }
+  }
+}
+
+struct NameVerifier {
+  name: String,
+  inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for NameVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    _server_name: &ServerName,
+    scts: &mut dyn Iterator<Item = &[u8]>,
+    ocsp_response: &[u8],
+    now: SystemTime,
+  ) -> Result<ServerCertVerified, TlsError> {
+    let name = ServerName::try_from(self.name.as_str())
+      .map_err(|_| TlsError::General(format!("invalid server name {}", self.name)))?;
+    self
+      .inner
+      .verify_server_cert(end_entity, intermediates, &name, scts, ocsp_response, now)
+  }
+}
+
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &Certificate,
+    _intermediates: &[Certificate],
+    _server_name: &ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<ServerCertVerified, TlsError> {
+    Ok(ServerCertVerified::assertion())
+  }
+}
+
+/// Split the first DER element of `input`: its tag, the whole element and what follows.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+  let tag = *input.first()?;
+  let first = *input.get(1)? as usize;
+  let (header, length) = if first < 0x80 {
+    (2, first)
+  } else {
+    let bytes = first & 0x7f;
+    if bytes == 0 || bytes > 4 {
+      return None;
+    }
+    let length = input
+      .get(2..2 + bytes)?
+      .iter()
+      .fold(0, |length, byte| (length << 8) | *byte as usize);
+    (2 + bytes, length)
+  };
+  let end = header.checked_add(length)?;
+  Some((tag, input.get(..end)?, input.get(end..)?))
+}
+
+/// The DER `SubjectPublicKeyInfo` of a certificate, the value hashed by SPKI pins.
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+  let (_, certificate, _) = der_element(certificate)?;
+  let (_, tbs, _) = der_element(content(certificate)?)?;
+  let mut fields = content(tbs)?;
+  // The version is an optional explicit tag, then come the serial number, signature algorithm,
+  // issuer, validity and subject.
+  if fields.first() == Some(&0xa0) {
+    fields = der_element(fields)?.2;
+  }
+  for _ in 0..5 {
+    fields = der_element(fields)?.2;
+  }
+  let (tag, spki, _) = der_element(fields)?;
+  (tag == 0x30).then_some(spki)
+}
+
+fn content(element: &[u8]) -> Option<&[u8]> {
+  let header = if element.get(1)? & 0x80 == 0 {
+    2
+  } else {
+    2 + (element[1] & 0x7f) as usize
+  };
+  element.get(header..)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const CERTIFICATE: &str = "
+MIIBmDCCAT6gAwIBAgIUZJ1Yg43fP/VJtv4ZhVmsA0EQ974wCgYIKoZIzj0EAwIw
+EzERMA8GA1UEAwwIZG5zLnRlc3QwIBcNMjYxMDE1MDQ0MjUwWhgPMjEyNjA5MjEw
+NDQyNTBaMBMxETAPBgNVBAMMCGRucy50ZXN0MFkwEwYHKoZIzj0CAQYIKoZIzj0D
+AQcDQgAEhYP6pc+kcl4HNmyC87y4YTjHfLcSoV/0x6OFu4oDL0QvZtnrFreF5gw3
+gt9AzhAnQyElpDd/n8QN9STcbuUrmaNuMGwwHQYDVR0OBBYEFHvellH42aYonOPP
+IqADqj5MEn1KMB8GA1UdIwQYMBaAFHvellH42aYonOPPIqADqj5MEn1KMA8GA1Ud
+EwEB/wQFMAMBAf8wGQYDVR0RBBIwEIIIZG5zLnRlc3SHBH8AAAEwCgYIKoZIzj0E
+AwIDSAAwRQIhAJ+09NipjkHfBtw8CAGYKt88D/SaAdlYtQ3GTnl2RQuyAiAnZBu0
+C6aHlCgtpKcVgB1ML2PUDqqAivjLfpXBpMLNGg==";
+  const PIN: &str = "sha256/fi9Zf0lFrd9okK0Kk2OQdX+kT2kdybOhg2FzWngKMVE=";
+
+  fn verifier(pin: &str) -> PinnedVerifier {
+    PinnedVerifier {
+      pins: vec![parse_pin(pin).unwrap()],
+      roots: None,
+    }
+  }
+
+  fn verify(verifier: &PinnedVerifier) -> Result<ServerCertVerified, TlsError> {
+    let der = STANDARD.decode(CERTIFICATE.replace('\n', "")).unwrap();
+    verifier.verify_server_cert(
+      &Certificate(der),
+      &[],
+      &ServerName::try_from("dns.test").unwrap(),
+      &mut std::iter::empty(),
+      &[],
+      SystemTime::now(),
+    )
+  }
+
+  #[test]
+  fn pinned_certificate() {
+    assert!(verify(&verifier(PIN)).is_ok());
+    assert!(verify(&verifier("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")).is_err());
+    assert!(parse_pin("sha256/not-base64").is_err());
+    assert!(parse_pin("AAAA").is_err());
+  }
+
+  /// Accepts the certificates of `dns.test` only.
+  struct NameOnly;
+
+  impl ServerCertVerifier for NameOnly {
+    fn verify_server_cert(
+      &self,
+      _end_entity: &Certificate,
+      _intermediates: &[Certificate],
+      server_name: &ServerName,
+      _scts: &mut dyn Iterator<Item = &[u8]>,
+      _ocsp_response: &[u8],
+      _now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+      match server_name {
+        ServerName::DnsName(name) if name.as_ref() == "dns.test" => {
+          Ok(ServerCertVerified::assertion())
+        }
+        _ => Err(TlsError::InvalidCertificate(
+          CertificateError::NotValidForName,
+        )),
+      }
+    }
+  }
+
+  #[test]
+  fn validate_as_name() {
+    let verify = |verifier: &dyn ServerCertVerifier| {
+      verifier.verify_server_cert(
+        &Certificate(vec![]),
+        &[],
+        &ServerName::try_from("front.example").unwrap(),
+        &mut std::iter::empty(),
+        &[],
+        SystemTime::now(),
+      )
+    };
+    assert!(verify(&NameOnly).is_err());
+    let fronted = |name: &str| NameVerifier {
+      name: name.to_string(),
+      inner: Arc::new(NameOnly),
+    };
+    assert!(verify(&fronted("dns.test")).is_ok());
+    assert!(verify(&fronted("other.test")).is_err());
+  }
+}
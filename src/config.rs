@@ -0,0 +1,187 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Read a TOML or YAML configuration file, depending on its extension. Keys are the long names
+/// of the command line options.
+pub fn read_config_file(path: &Path) -> Result<Map<String, Value>> {
+  let content = std::fs::read_to_string(path)?;
+  let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+    Some("yaml") | Some("yml") => serde_yaml::from_str(&content)?,
+    Some("toml") => toml::from_str(&content)?,
+    _ => bail!("unsupported configuration format, expected a .toml, .yaml or .yml file"),
+  };
+  match value {
+    Value::Object(map) => Ok(map),
+    Value::Null => Ok(Map::new()),
+    _ => bail!("the configuration must be a table of options"),
+  }
+}
+
+/// Apply the values of the configuration file on the parsed command line. Options explicitly
+/// given on the command line or through the environment keep their value.
+pub fn merge_config<T: Serialize + DeserializeOwned>(
+  args: &T,
+  command: &Command,
+  matches: &ArgMatches,
+  config: Map<String, Value>,
+) -> Result<T> {
+  let mut merged = match serde_json::to_value(args)? {
+    Value::Object(map) => map,
+    _ => bail!("the arguments must be a struct"),
+  };
+
+  for (key, value) in config {
+    let arg = command
+      .get_arguments()
+      .find(|arg| arg.get_long() == Some(key.as_str()) && merged.contains_key(&key))
+      .ok_or_else(|| anyhow!("unknown configuration key `{key}`"))?;
+    let explicit = matches!(
+      matches.value_source(arg.get_id().as_str()),
+      Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+    );
+    if !explicit {
+      validate(arg, &value)
+        .with_context(|| format!("invalid value for configuration key `{key}`"))?;
+      merged.insert(key, value);
+    }
+  }
+
+  serde_json::from_value(Value::Object(merged)).context("invalid configuration")
+}
+
+/// Check a value of the configuration file like the command line would, with the parser of the
+/// option alone, so its ranges and formats apply to the file too.
+fn validate(arg: &Arg, value: &Value) -> Result<()> {
+  let values = match value {
+    Value::Array(values) => values.iter().collect(),
+    value => vec![value],
+  };
+  let mut argv = vec![];
+  for value in values {
+    let value = match value {
+      Value::String(value) => value.clone(),
+      Value::Number(_) | Value::Bool(_) => value.to_string(),
+      // Left to the deserialization of the option.
+      _ => continue,
+    };
+    argv.push(format!("--value={value}"));
+  }
+  Command::new("config")
+    .no_binary_name(true)
+    .arg(
+      Arg::new("value")
+        .long("value")
+        .value_parser(arg.get_value_parser().clone())
+        .action(ArgAction::Append),
+    )
+    .try_get_matches_from(argv)
+    .map_err(|err| match std::error::Error::source(&err) {
+      Some(reason) => anyhow!("{reason}"),
+      None => anyhow!("{}", err.kind()),
+    })?;
+  Ok(())
+}
+
+/// Deserialize a repeatable option written either as a single value or as a list in the
+/// configuration file.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+  D: Deserializer<'de>,
+  T: Deserialize<'de>,
+{
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+  }
+  Ok(match OneOrMany::deserialize(deserializer)? {
+    OneOrMany::One(value) => vec![value],
+    OneOrMany::Many(values) => values,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use clap::{CommandFactory, FromArgMatches, Parser};
+  use serde::Deserialize;
+
+  #[derive(Parser, Serialize, Deserialize, Debug, PartialEq)]
+  #[serde(rename_all = "kebab-case")]
+  struct Args {
+    #[arg(long = "port", default_value = "53")]
+    port: u16,
+    #[arg(long = "attempts", default_value = "2", value_parser = clap::value_parser!(u8).range(1..=5))]
+    attempts: u8,
+    #[arg(long = "listen", default_value = "0.0.0.0")]
+    listen: String,
+    #[arg(long = "blacklist")]
+    blacklist: Option<String>,
+    #[arg(long = "dns-server", default_value = "cloudflare")]
+    #[serde(deserialize_with = "one_or_many")]
+    dns_server: Vec<String>,
+    #[arg(long = "network", value_parser = clap::builder::PossibleValuesParser::new(["lan", "wan"]))]
+    #[serde(default, deserialize_with = "one_or_many")]
+    network: Vec<String>,
+  }
+
+  fn merge(cli: &[&str], config: &str) -> Result<Args> {
+    let command = Args::command();
+    let matches = command.clone().get_matches_from(cli);
+    let args = Args::from_arg_matches(&matches).unwrap();
+    let config: Value = toml::from_str(config).unwrap();
+    let Value::Object(config) = config else {
+      unreachable!()
+    };
+    merge_config(&args, &command, &matches, config)
+  }
+
+  #[test]
+  fn file_values_replace_defaults() {
+    let args = merge(&["test"], "port = 5353\nblacklist = \"list.txt\"").unwrap();
+    assert_eq!(args.port, 5353);
+    assert_eq!(args.listen, "0.0.0.0");
+    assert_eq!(args.blacklist, Some("list.txt".to_string()));
+  }
+
+  #[test]
+  fn command_line_overrides_file() {
+    let args = merge(&["test", "--port", "1053"], "port = 5353").unwrap();
+    assert_eq!(args.port, 1053);
+  }
+
+  #[test]
+  fn single_or_list_values() {
+    let args = merge(&["test"], "dns-server = \"google\"").unwrap();
+    assert_eq!(args.dns_server, ["google"]);
+    let args = merge(&["test"], "dns-server = [\"google\", \"quad9\"]").unwrap();
+    assert_eq!(args.dns_server, ["google", "quad9"]);
+  }
+
+  #[test]
+  fn unknown_keys() {
+    assert!(merge(&["test"], "prot = 5353").is_err());
+    assert!(merge(&["test"], "port = \"not a port\"").is_err());
+  }
+
+  #[test]
+  fn ranges_of_the_options() {
+    assert_eq!(merge(&["test"], "attempts = 5").unwrap().attempts, 5);
+    let err = merge(&["test"], "attempts = 0").unwrap_err();
+    assert_eq!(
+      format!("{err:#}"),
+      "invalid value for configuration key `attempts`: 0 is not in 1..=5"
+    );
+    assert_eq!(
+      merge(&["test"], "network = [\"lan\", \"wan\"]")
+        .unwrap()
+        .network,
+      ["lan", "wan"]
+    );
+    assert!(merge(&["test"], "network = [\"lan\", \"dmz\"]").is_err());
+  }
+}
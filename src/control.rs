@@ -0,0 +1,394 @@
+use crate::cache::{AnswerCache, CacheStats};
+use crate::events::{AuditLog, Stats, StatsSnapshot};
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_server::proto::rr::LowerName;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+#[cfg(unix)]
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tracing::info;
+#[cfg(unix)]
+use tracing::{error, warn};
+
+/// State of a running server that can be changed without reloading the configuration. It is kept
+/// when the catalogs are rebuilt.
+pub struct Control {
+  disabled_until: Mutex<Option<Instant>>,
+  blocked: RwLock<HashSet<LowerName>>,
+  /// Whether each blacklist category is enabled.
+  categories: RwLock<BTreeMap<String, bool>>,
+  /// Domains and zones of the blacklists, categories included.
+  listed_domains: AtomicUsize,
+  stats: Arc<Stats>,
+  cache: Arc<AnswerCache>,
+  audit_log: RwLock<Option<Arc<AuditLog>>>,
+}
+
+/// Answer of the control socket to the `stats` command.
+#[derive(Serialize, Debug)]
+pub struct ControlStats {
+  #[serde(flatten)]
+  pub queries: StatsSnapshot,
+  pub blocking: bool,
+  pub disabled_for_secs: Option<u64>,
+  pub blocked_domains: usize,
+  pub listed_domains: usize,
+  pub categories: BTreeMap<String, bool>,
+  pub cache: CacheStats,
+}
+
+impl Default for Control {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Control {
+  pub fn new() -> Self {
+    Self {
+      disabled_until: Mutex::new(None),
+      blocked: RwLock::new(HashSet::new()),
+      categories: RwLock::new(BTreeMap::new()),
+      listed_domains: AtomicUsize::new(0),
+      stats: Arc::new(Stats::new()),
+      cache: Arc::new(AnswerCache::new()),
+      audit_log: RwLock::new(None),
+    }
+  }
+
+  pub fn stats(&self) -> Arc<Stats> {
+    self.stats.clone()
+  }
+
+  /// Cache of the answers of the upstreams, shared by all the catalogs.
+  pub fn cache(&self) -> Arc<AnswerCache> {
+    self.cache.clone()
+  }
+
+  /// Record the changes of the running server in `audit_log`.
+  pub fn set_audit_log(&self, audit_log: Option<Arc<AuditLog>>) {
+    *self.audit_log.write().unwrap() = audit_log;
+  }
+
+  /// Record a change made from `source` in the audit log, if any.
+  pub fn audit(&self, source: &str, change: &str) {
+    if let Some(audit_log) = self.audit_log.read().unwrap().as_ref() {
+      audit_log.record(source, change);
+    }
+  }
+
+  /// Whether the blacklists are applied, they can be disabled for a while.
+  pub fn is_blocking(&self) -> bool {
+    let mut disabled_until = self.disabled_until.lock().unwrap();
+    match *disabled_until {
+      Some(until) if Instant::now() < until => false,
+      Some(_) => {
+        *disabled_until = None;
+        info!("Blocking enabled again");
+        true
+      }
+      None => true,
+    }
+  }
+
+  pub fn disable(&self, duration: Duration) {
+    *self.disabled_until.lock().unwrap() = Some(Instant::now() + duration);
+  }
+
+  pub fn enable(&self) {
+    *self.disabled_until.lock().unwrap() = None;
+  }
+
+  /// Block a zone, with all its subdomains, until the server restarts.
+  pub fn block(&self, zone: LowerName) -> bool {
+    self.blocked.write().unwrap().insert(zone)
+  }
+
+  pub fn unblock(&self, zone: &LowerName) -> bool {
+    self.blocked.write().unwrap().remove(zone)
+  }
+
+  /// Set the blacklist categories of the server, categories already known keep their state.
+  pub fn set_categories<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+    let mut categories = self.categories.write().unwrap();
+    let previous = std::mem::take(&mut *categories);
+    for name in names {
+      let enabled = previous.get(name).copied().unwrap_or(true);
+      categories.insert(name.to_string(), enabled);
+    }
+  }
+
+  /// Number of domains and zones of the blacklists, set when the catalogs are built.
+  pub fn set_listed_domains(&self, count: usize) {
+    self.listed_domains.store(count, Ordering::Relaxed);
+  }
+
+  /// Enable or disable a blacklist category until the server restarts.
+  pub fn set_category_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+    match self.categories.write().unwrap().get_mut(name) {
+      Some(state) => {
+        *state = enabled;
+        Ok(())
+      }
+      None => bail!("unknown category `{name}`"),
+    }
+  }
+
+  pub fn is_category_enabled(&self, name: &str) -> bool {
+    self
+      .categories
+      .read()
+      .unwrap()
+      .get(name)
+      .copied()
+      .unwrap_or(true)
+  }
+
+  /// Whether the name is in a zone blocked through the control socket.
+  pub fn blocks(&self, name: &LowerName) -> bool {
+    let blocked = self.blocked.read().unwrap();
+    if blocked.is_empty() {
+      return false;
+    }
+    let mut name = name.clone();
+    loop {
+      if blocked.contains(&name) {
+        return true;
+      }
+      if name.is_root() {
+        return false;
+      }
+      name = name.base_name();
+    }
+  }
+
+  pub fn snapshot(&self) -> ControlStats {
+    let disabled_until = *self.disabled_until.lock().unwrap();
+    let disabled_for = disabled_until
+      .map(|until| until.saturating_duration_since(Instant::now()))
+      .filter(|duration| !duration.is_zero());
+    ControlStats {
+      queries: self.stats.snapshot(),
+      blocking: disabled_for.is_none(),
+      disabled_for_secs: disabled_for.map(|duration| duration.as_secs()),
+      blocked_domains: self.blocked.read().unwrap().len(),
+      listed_domains: self.listed_domains.load(Ordering::Relaxed),
+      categories: self.categories.read().unwrap().clone(),
+      cache: self.cache.stats(),
+    }
+  }
+
+  /// Run one command received on the control socket from `source` and return the answer. The
+  /// changes are recorded in the audit log.
+  pub fn execute(&self, command: &str, source: &str) -> Result<String> {
+    let answer = self.run(command)?;
+    if command.split_whitespace().next() != Some("stats") {
+      self.audit(source, &answer);
+    }
+    Ok(answer)
+  }
+
+  fn run(&self, command: &str) -> Result<String> {
+    let mut words = command.split_whitespace();
+    let action = words.next().ok_or_else(|| anyhow!("empty command"))?;
+    let argument = words.next();
+    if words.next().is_some() {
+      bail!("too many arguments");
+    }
+
+    match (action, argument) {
+      ("block", Some(domain)) => {
+        let zone = parse_domain(domain)?;
+        info!("Zone {} blocked from the control socket", zone);
+        self.block(zone);
+        Ok(format!("{domain} blocked"))
+      }
+      ("unblock", Some(domain)) => {
+        if self.unblock(&parse_domain(domain)?) {
+          Ok(format!("{domain} unblocked"))
+        } else {
+          bail!("{domain} was not blocked from the control socket")
+        }
+      }
+      ("disable", duration) => {
+        let duration = parse_duration(duration.unwrap_or("5m"))?;
+        info!(
+          "Blocking disabled for {:?} from the control socket",
+          duration
+        );
+        self.disable(duration);
+        Ok(format!("blocking disabled for {}s", duration.as_secs()))
+      }
+      ("enable", None) => {
+        self.enable();
+        Ok("blocking enabled".to_string())
+      }
+      ("disable-category", Some(category)) => {
+        self.set_category_enabled(category, false)?;
+        info!("Category {} disabled from the control socket", category);
+        Ok(format!("category {category} disabled"))
+      }
+      ("enable-category", Some(category)) => {
+        self.set_category_enabled(category, true)?;
+        info!("Category {} enabled from the control socket", category);
+        Ok(format!("category {category} enabled"))
+      }
+      ("flush-cache", None) => {
+        let removed = self.cache.flush();
+        Ok(format!("{removed} answers flushed"))
+      }
+      ("flush-cache", Some(domain)) => {
+        let removed = self.cache.flush_name(&parse_domain(domain)?);
+        Ok(format!("{removed} answers of {domain} flushed"))
+      }
+      ("stats", None) => Ok(serde_json::to_string(&self.snapshot())?),
+      _ => bail!("unknown command `{command}`"),
+    }
+  }
+}
+
+/// Listen commands on a unix socket, one command per line. Each answer is a line starting with
+/// `ok` or `error`.
+#[cfg(unix)]
+pub fn serve(path: &Path, control: Arc<Control>) -> Result<()> {
+  if path.exists() {
+    std::fs::remove_file(path)
+      .with_context(|| format!("could not remove stale control socket {}", path.display()))?;
+  }
+  let listener = UnixListener::bind(path)
+    .with_context(|| format!("could not bind control socket {}", path.display()))?;
+  info!("Will listen control commands on {}", path.display());
+
+  tokio::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, _)) => {
+          tokio::spawn(handle_connection(stream, control.clone()));
+        }
+        Err(err) => error!("Could not accept control connection: {}", err),
+      }
+    }
+  });
+  Ok(())
+}
+
+#[cfg(unix)]
+async fn handle_connection(stream: UnixStream, control: Arc<Control>) {
+  let source = match stream.peer_cred() {
+    Ok(cred) => format!("control socket, uid {}", cred.uid()),
+    Err(_) => "control socket".to_string(),
+  };
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = BufReader::new(reader).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    let answer = match control.execute(line.trim(), &source) {
+      Ok(answer) => format!("ok {answer}\n"),
+      Err(err) => format!("error {err:#}\n"),
+    };
+    if let Err(err) = writer.write_all(answer.as_bytes()).await {
+      warn!("Could not answer on the control socket: {}", err);
+      return;
+    }
+  }
+}
+
+/// Send one command to the control socket of a running server and return its answer.
+#[cfg(unix)]
+pub async fn send(path: &Path, command: &str) -> Result<String> {
+  let stream = UnixStream::connect(path)
+    .await
+    .with_context(|| format!("could not connect to control socket {}", path.display()))?;
+  let (reader, mut writer) = stream.into_split();
+  writer.write_all(format!("{command}\n").as_bytes()).await?;
+  let answer = BufReader::new(reader)
+    .lines()
+    .next_line()
+    .await?
+    .ok_or_else(|| anyhow!("the server closed the connection"))?;
+  match answer.split_once(' ') {
+    Some(("ok", answer)) => Ok(answer.to_string()),
+    Some(("error", err)) => bail!("{err}"),
+    _ => bail!("unexpected answer `{answer}`"),
+  }
+}
+
+fn parse_domain(domain: &str) -> Result<LowerName> {
+  LowerName::from_str(&format!("{}.", domain.trim_end_matches('.')))
+    .with_context(|| format!("invalid domain {domain}"))
+}
+
+/// Parse a duration such as `500ms`, `30s`, `10m`, `2h` or `7d`, seconds when there is no unit.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+  let value = value.trim();
+  let (number, unit) = value.split_at(value.trim_end_matches(char::is_alphabetic).len());
+  let number: u64 = number
+    .parse()
+    .with_context(|| format!("invalid duration `{value}`"))?;
+  let seconds = match unit {
+    "ms" => return Ok(Duration::from_millis(number)),
+    "" | "s" => number,
+    "m" => number * 60,
+    "h" => number * 3600,
+    "d" => number * 86400,
+    _ => bail!("invalid duration unit `{unit}`, expected ms, s, m, h or d"),
+  };
+  Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn durations() {
+    assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+    assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+    assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(604800));
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert!(parse_duration("m").is_err());
+    assert!(parse_duration("10w").is_err());
+  }
+
+  #[test]
+  fn commands() {
+    let control = Control::new();
+    let name = LowerName::from_str("www.example.com.").unwrap();
+    assert!(!control.blocks(&name));
+    control.execute("block example.com", "test").unwrap();
+    assert!(control.blocks(&name));
+    control.execute("unblock example.com.", "test").unwrap();
+    assert!(!control.blocks(&name));
+    assert!(control.execute("unblock example.com", "test").is_err());
+
+    control.execute("disable 10m", "test").unwrap();
+    assert!(!control.is_blocking());
+    control.execute("enable", "test").unwrap();
+    assert!(control.is_blocking());
+
+    control.set_categories(["ads", "malware"]);
+    control.execute("disable-category ads", "test").unwrap();
+    assert!(!control.is_category_enabled("ads"));
+    assert!(control.is_category_enabled("malware"));
+    assert!(control.execute("disable-category social", "test").is_err());
+    control.set_categories(["ads"]);
+    assert!(!control.is_category_enabled("ads"));
+    control.execute("enable-category ads", "test").unwrap();
+    assert!(control.is_category_enabled("ads"));
+
+    assert!(control
+      .execute("stats", "test")
+      .unwrap()
+      .contains("\"queries\":0"));
+    assert!(control.execute("flush", "test").is_err());
+    assert!(control.execute("block", "test").is_err());
+  }
+}
@@ -0,0 +1,528 @@
+use crate::handler::{DnsHandler, ResponseBuffer, EDE_OPTION};
+use crate::ip::IpRangeVec;
+use crate::proxy_protocol;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{
+  engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+  Engine,
+};
+use bytes::Bytes;
+use h2::{server::SendResponse, Reason, RecvStream};
+use hickory_rustls::ServerConfig;
+use hickory_server::{
+  authority::MessageRequest,
+  proto::{
+    h2::h2_server,
+    op::{Edns, Message, MessageType, OpCode, Query},
+    rr::{rdata::opt::EdnsOption, Name, Record, RecordType},
+    serialize::binary::BinDecodable,
+  },
+  server::{Protocol, Request, RequestHandler},
+};
+use http::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE};
+use ring::digest::{digest, SHA256};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_graceful::WeakShutdownGuard;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error};
+
+const DNS_QUERY_PATH: &str = "/dns-query";
+/// Path of the JSON API, beside the `/dns-query` of RFC 8484.
+const JSON_PATH: &str = "/resolve";
+const MIME_APPLICATION_DNS: &str = "application/dns-message";
+const MIME_APPLICATION_DNS_JSON: &str = "application/dns-json";
+/// EDNS payload of the JSON queries, the answers are not sent over UDP.
+const JSON_MAX_PAYLOAD: u16 = 4096;
+
+/// Credential required on the DNS over HTTPS requests, in their `Authorization` header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+  /// `Bearer <token>`.
+  Bearer(String),
+  /// `Basic <base64 of user:password>`.
+  Basic { user: String, password: String },
+}
+
+impl Credential {
+  /// Whether the `Authorization` header presents this credential.
+  pub fn accepts(&self, authorization: Option<&str>) -> bool {
+    let Some((scheme, value)) = authorization.and_then(|value| value.trim().split_once(' ')) else {
+      return false;
+    };
+    let expected = match self {
+      Credential::Bearer(token) if scheme.eq_ignore_ascii_case("bearer") => token.clone(),
+      Credential::Basic { user, password } if scheme.eq_ignore_ascii_case("basic") => {
+        STANDARD.encode(format!("{user}:{password}"))
+      }
+      _ => return false,
+    };
+    // Compare digests so the time taken does not depend on the credential.
+    digest(&SHA256, value.trim().as_bytes()).as_ref()
+      == digest(&SHA256, expected.as_bytes()).as_ref()
+  }
+
+  /// Value of the `Authorization` header sending this credential.
+  pub fn authorization(&self) -> String {
+    match self {
+      Credential::Bearer(token) => format!("Bearer {token}"),
+      Credential::Basic { user, password } => {
+        format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+      }
+    }
+  }
+
+  fn challenge(&self) -> &'static str {
+    match self {
+      Credential::Bearer(_) => "Bearer",
+      Credential::Basic { .. } => "Basic realm=\"dns-server\"",
+    }
+  }
+}
+
+impl FromStr for Credential {
+  type Err = anyhow::Error;
+
+  /// `user:password` for the basic authentication, a bearer token otherwise.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.is_empty() {
+      bail!("the credential is empty");
+    }
+    Ok(match s.split_once(':') {
+      Some((user, password)) => Credential::Basic {
+        user: user.to_string(),
+        password: password.to_string(),
+      },
+      None => Credential::Bearer(s.to_string()),
+    })
+  }
+}
+
+impl fmt::Display for Credential {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Credential::Bearer(token) => write!(f, "{token}"),
+      Credential::Basic { user, password } => write!(f, "{user}:{password}"),
+    }
+  }
+}
+
+impl Serialize for Credential {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Credential {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Credential::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// DNS over HTTPS listener (RFC 8484) answering `/dns-query` over HTTP/2, and a JSON API on
+/// `/resolve`. When a credential is given, requests without it are answered `401 Unauthorized`.
+/// Connections from the `proxies` networks start with a PROXY protocol header.
+///
+/// It takes its TLS configuration instead of a certificate, so the certificate can be renewed
+/// without restarting the listener.
+pub fn serve(
+  listener: TcpListener,
+  tls: Arc<ServerConfig>,
+  handler: DnsHandler,
+  credential: Option<Credential>,
+  proxies: Arc<IpRangeVec>,
+  handshake_timeout: Duration,
+  shutdown: WeakShutdownGuard,
+) {
+  let acceptor = TlsAcceptor::from(tls);
+  let handler = Arc::new(handler);
+  let credential = credential.map(Arc::new);
+  tokio::spawn(async move {
+    loop {
+      let (stream, src) = tokio::select! {
+        accepted = listener.accept() => match accepted {
+          Ok(accepted) => accepted,
+          Err(err) => {
+            error!("Could not accept HTTPS connection: {}", err);
+            continue;
+          }
+        },
+        _ = shutdown.cancelled() => return,
+      };
+      let acceptor = acceptor.clone();
+      let handler = handler.clone();
+      let credential = credential.clone();
+      let proxies = proxies.clone();
+      tokio::spawn(async move {
+        let connection = handle_connection(
+          acceptor,
+          stream,
+          src,
+          handler,
+          credential,
+          &proxies,
+          handshake_timeout,
+        );
+        if let Err(err) = connection.await {
+          debug!("HTTPS connection of {} closed: {}", src, err);
+        }
+      });
+    }
+  });
+}
+
+async fn handle_connection(
+  acceptor: TlsAcceptor,
+  mut stream: TcpStream,
+  mut src: SocketAddr,
+  handler: Arc<DnsHandler>,
+  credential: Option<Arc<Credential>>,
+  proxies: &IpRangeVec,
+  handshake_timeout: Duration,
+) -> Result<()> {
+  if proxies.contains_sock_addr(src) {
+    let header = proxy_protocol::read_header(&mut stream);
+    if let Some(client) = tokio::time::timeout(handshake_timeout, header).await?? {
+      src = client;
+    }
+  }
+  let stream = tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await??;
+  // A client finishing the TLS handshake must not keep the connection without the HTTP/2 one.
+  let mut connection =
+    tokio::time::timeout(handshake_timeout, h2::server::handshake(stream)).await??;
+  while let Some(request) = connection.accept().await {
+    let (request, mut respond) = request?;
+    if let Some(credential) = &credential {
+      let authorization = request.headers().get(AUTHORIZATION);
+      if !credential.accepts(authorization.and_then(|value| value.to_str().ok())) {
+        debug!("Unauthorized HTTPS request from {}", src);
+        let response = http::Response::builder()
+          .status(http::StatusCode::UNAUTHORIZED)
+          .header(WWW_AUTHENTICATE, credential.challenge())
+          .body(())?;
+        respond.send_response(response, true)?;
+        continue;
+      }
+    }
+    let handler = handler.clone();
+    tokio::spawn(async move {
+      if let Err(err) = handle_request(request, respond, src, &handler).await {
+        debug!("Could not answer the HTTPS request of {}: {}", src, err);
+      }
+    });
+  }
+  Ok(())
+}
+
+async fn handle_request(
+  request: http::Request<RecvStream>,
+  mut respond: SendResponse<Bytes>,
+  src: SocketAddr,
+  handler: &DnsHandler,
+) -> Result<()> {
+  if let Some(status) = route(request.method(), request.uri().path()) {
+    debug!(
+      "Rejected HTTPS request from {}: {} {}",
+      src,
+      request.method(),
+      request.uri().path()
+    );
+    return send_status(&mut respond, status);
+  }
+  let message = read_message(request)
+    .await
+    .and_then(|(message, format)| Ok((MessageRequest::from_bytes(&message)?, format)));
+  let (message, format) = match message {
+    Ok(message) => message,
+    Err(err) => {
+      debug!("Invalid HTTPS request from {}: {}", src, err);
+      return send_status(&mut respond, http::StatusCode::BAD_REQUEST);
+    }
+  };
+  let request = Request::new(message, src, Protocol::Https);
+  let buffer = ResponseBuffer::default();
+  handler.handle_request(&request, buffer.clone()).await;
+
+  // Nothing is sent to denied clients, like on the other listeners.
+  let Some(bytes) = buffer.take() else {
+    respond.send_reset(Reason::REFUSED_STREAM);
+    return Ok(());
+  };
+  let (content_type, body) = match format {
+    Format::Wire => (MIME_APPLICATION_DNS, bytes),
+    Format::Json => {
+      let response = Message::from_vec(&bytes)?;
+      (
+        MIME_APPLICATION_DNS_JSON,
+        serde_json::to_vec(&JsonResponse::from(&response))?,
+      )
+    }
+  };
+  let response = http::Response::builder()
+    .status(http::StatusCode::OK)
+    .header(CONTENT_TYPE, content_type)
+    .header(CONTENT_LENGTH, body.len())
+    .body(())?;
+  respond
+    .send_response(response, false)?
+    .send_data(Bytes::from(body), true)?;
+  Ok(())
+}
+
+/// Error status of the requests that are not for the DoH or JSON API, `None` when the method is
+/// supported on the path.
+fn route(method: &http::Method, path: &str) -> Option<http::StatusCode> {
+  match path {
+    DNS_QUERY_PATH if method == http::Method::GET || method == http::Method::POST => None,
+    JSON_PATH if method == http::Method::GET => None,
+    DNS_QUERY_PATH | JSON_PATH => Some(http::StatusCode::METHOD_NOT_ALLOWED),
+    _ => Some(http::StatusCode::NOT_FOUND),
+  }
+}
+
+/// Answer the request with an empty response.
+fn send_status(respond: &mut SendResponse<Bytes>, status: http::StatusCode) -> Result<()> {
+  let response = http::Response::builder().status(status).body(())?;
+  respond.send_response(response, true)?;
+  Ok(())
+}
+
+/// Format of the request, the response is sent in the same one.
+enum Format {
+  Wire,
+  Json,
+}
+
+/// Wire format of the query of the request:
+/// - `POST /dns-query` with the message as body (RFC 8484)
+/// - `GET /dns-query?dns=<base64url of the message>` (RFC 8484)
+/// - `GET /resolve?name=<name>&type=<type>` or `GET /dns-query?name=...`, answered in JSON like
+///   the APIs of Google and Cloudflare
+///
+/// The method and path were checked by `route`.
+async fn read_message(request: http::Request<RecvStream>) -> Result<(Vec<u8>, Format)> {
+  if request.method() == http::Method::POST {
+    let message = h2_server::message_from(None, request)
+      .await
+      .map_err(|err| anyhow!("{}", err))?;
+    return Ok((message.to_vec(), Format::Wire));
+  }
+  let params: HashMap<String, String> =
+    url::form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+      .into_owned()
+      .collect();
+  match (request.uri().path(), params.get("dns")) {
+    (DNS_QUERY_PATH, Some(message)) => {
+      let message = URL_SAFE_NO_PAD.decode(message.trim_end_matches('='))?;
+      Ok((message, Format::Wire))
+    }
+    _ => Ok((json_query(&params)?, Format::Json)),
+  }
+}
+
+/// Query of the JSON API: `name`, `type` (name or number, `A` by default), `cd` to disable the
+/// DNSSEC validation and `do` to get the DNSSEC records.
+fn json_query(params: &HashMap<String, String>) -> Result<Vec<u8>> {
+  let enabled = |param: &str| {
+    params
+      .get(param)
+      .is_some_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+  };
+  let mut name = Name::from_str(params.get("name").context("missing name parameter")?)?;
+  name.set_fqdn(true);
+  let record_type = match params.get("type") {
+    None => RecordType::A,
+    Some(value) => match value.parse::<u16>() {
+      Ok(number) => RecordType::from(number),
+      Err(_) => RecordType::from_str(&value.to_uppercase())?,
+    },
+  };
+  let mut message = Message::new();
+  message
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(true)
+    .set_checking_disabled(enabled("cd"))
+    .add_query(Query::query(name, record_type));
+  // With EDNS, blocked answers carry the name of the list in an extended error.
+  let mut edns = Edns::new();
+  edns.set_max_payload(JSON_MAX_PAYLOAD);
+  edns.set_dnssec_ok(enabled("do"));
+  message.set_edns(edns);
+  Ok(message.to_vec()?)
+}
+
+/// Response of the JSON API, with the members of the APIs of Google and Cloudflare.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+  status: u16,
+  #[serde(rename = "TC")]
+  truncated: bool,
+  #[serde(rename = "RD")]
+  recursion_desired: bool,
+  #[serde(rename = "RA")]
+  recursion_available: bool,
+  #[serde(rename = "AD")]
+  authentic_data: bool,
+  #[serde(rename = "CD")]
+  checking_disabled: bool,
+  question: Vec<JsonQuestion>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  answer: Vec<JsonRecord>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  authority: Vec<JsonRecord>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  additional: Vec<JsonRecord>,
+  /// Text of the extended DNS errors, like the list blocking the name.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  comment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonQuestion {
+  name: String,
+  #[serde(rename = "type")]
+  record_type: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonRecord {
+  name: String,
+  #[serde(rename = "type")]
+  record_type: u16,
+  #[serde(rename = "TTL")]
+  ttl: u32,
+  data: String,
+}
+
+impl From<&Message> for JsonResponse {
+  fn from(message: &Message) -> Self {
+    let records = |records: &[Record]| {
+      records
+        .iter()
+        .map(|record| JsonRecord {
+          name: record.name().to_string(),
+          record_type: record.record_type().into(),
+          ttl: record.ttl(),
+          data: record
+            .data()
+            .map(|data| data.to_string())
+            .unwrap_or_default(),
+        })
+        .collect()
+    };
+    let comments: Vec<String> = message
+      .extensions()
+      .iter()
+      .flat_map(|edns| edns.options().as_ref().values())
+      .filter_map(|option| match option {
+        EdnsOption::Unknown(EDE_OPTION, data) if data.len() > 2 => {
+          Some(String::from_utf8_lossy(&data[2..]).to_string())
+        }
+        _ => None,
+      })
+      .collect();
+    Self {
+      status: message.response_code().into(),
+      truncated: message.truncated(),
+      recursion_desired: message.recursion_desired(),
+      recursion_available: message.recursion_available(),
+      authentic_data: message.authentic_data(),
+      checking_disabled: message.checking_disabled(),
+      question: message
+        .queries()
+        .iter()
+        .map(|query| JsonQuestion {
+          name: query.name().to_string(),
+          record_type: query.query_type().into(),
+        })
+        .collect(),
+      answer: records(message.answers()),
+      authority: records(message.name_servers()),
+      additional: records(message.additionals()),
+      comment: (!comments.is_empty()).then(|| comments.join(", ")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn credentials() {
+    let token = Credential::from_str("s3cret").unwrap();
+    assert_eq!(token, Credential::Bearer("s3cret".to_string()));
+    assert!(token.accepts(Some("Bearer s3cret")));
+    assert!(token.accepts(Some("bearer  s3cret ")));
+    assert!(!token.accepts(Some("Bearer s3cre")));
+    assert!(!token.accepts(Some("Basic s3cret")));
+    assert!(!token.accepts(None));
+    assert!(token.accepts(Some(&token.authorization())));
+
+    let basic = Credential::from_str("me:pass:word").unwrap();
+    assert_eq!(basic.to_string(), "me:pass:word");
+    assert!(basic.accepts(Some(&format!("Basic {}", STANDARD.encode("me:pass:word")))));
+    assert!(!basic.accepts(Some(&format!("Basic {}", STANDARD.encode("me:password")))));
+    assert!(basic.accepts(Some(&basic.authorization())));
+    assert!(Credential::from_str("").is_err());
+  }
+
+  #[test]
+  fn routes() {
+    assert_eq!(route(&http::Method::GET, DNS_QUERY_PATH), None);
+    assert_eq!(route(&http::Method::POST, DNS_QUERY_PATH), None);
+    assert_eq!(route(&http::Method::GET, JSON_PATH), None);
+    assert_eq!(
+      route(&http::Method::PUT, DNS_QUERY_PATH),
+      Some(http::StatusCode::METHOD_NOT_ALLOWED)
+    );
+    assert_eq!(
+      route(&http::Method::POST, JSON_PATH),
+      Some(http::StatusCode::METHOD_NOT_ALLOWED)
+    );
+    assert_eq!(
+      route(&http::Method::POST, "/"),
+      Some(http::StatusCode::NOT_FOUND)
+    );
+    assert_eq!(
+      route(&http::Method::GET, "/dns-query/other"),
+      Some(http::StatusCode::NOT_FOUND)
+    );
+  }
+
+  #[test]
+  fn json_api() {
+    let params: HashMap<String, String> = [("name", "example.com"), ("type", "aaaa"), ("do", "1")]
+      .into_iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect();
+    let query = Message::from_vec(&json_query(&params).unwrap()).unwrap();
+    assert_eq!(query.queries()[0].name().to_string(), "example.com.");
+    assert_eq!(query.queries()[0].query_type(), RecordType::AAAA);
+    assert!(query.recursion_desired());
+    assert!(query.extensions().as_ref().unwrap().dnssec_ok());
+    assert!(json_query(&HashMap::new()).is_err());
+
+    let mut response = query.clone();
+    response.add_answer(Record::from_rdata(
+      Name::from_str("example.com.").unwrap(),
+      300,
+      hickory_server::proto::rr::RData::A("192.0.2.1".parse().unwrap()),
+    ));
+    let json = serde_json::to_value(JsonResponse::from(&response)).unwrap();
+    assert_eq!(json["Status"], 0);
+    assert_eq!(json["Question"][0]["type"], 28);
+    assert_eq!(json["Answer"][0]["data"], "192.0.2.1");
+    assert_eq!(json["Answer"][0]["TTL"], 300);
+    assert!(json.get("Authority").is_none());
+  }
+}
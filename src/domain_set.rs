@@ -0,0 +1,280 @@
+use anyhow::{Context, Result};
+use hickory_server::proto::rr::{LowerName, Name};
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// Lines read between two progress messages while loading a list.
+const PROGRESS_LINES: usize = 1_000_000;
+/// Malformed lines logged while loading a list, the others are only counted.
+const LOGGED_MALFORMED_LINES: usize = 10;
+
+/// Set of domains for lists of millions of names, matching exact names and zones.
+///
+/// Each name is stored as its labels in reverse order (`com`, `example`, `www`), each label
+/// prefixed by its length, and all the names are kept sorted in a single buffer. The zones of a
+/// name are then the prefixes of its key ending on a label, found by binary search. A name takes
+/// its length plus 4 bytes, instead of more than a hundred for a `HashSet<LowerName>`.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct DomainSet {
+  keys: Vec<u8>,
+  /// End of each key in `keys`, the key `i` starts at the end of the key `i - 1`.
+  ends: Vec<u32>,
+}
+
+impl DomainSet {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Read a file of domains, one per line. Empty lines and `#` comments are ignored, malformed
+  /// lines are skipped and counted.
+  pub fn load(path: &Path) -> Result<Self> {
+    let file =
+      std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    Self::read(std::io::BufReader::new(file), &path.display().to_string())
+      .with_context(|| format!("could not read {}", path.display()))
+  }
+
+  /// Read the domains of `reader` line by line, `source` names it in the logs.
+  pub fn read(reader: impl BufRead, source: &str) -> Result<Self> {
+    let mut error = None;
+    let mut lines = 0;
+    let mut malformed = 0;
+    let names = reader
+      .split(b'\n')
+      .map_while(|line| line.map_err(|err| error = Some(err)).ok())
+      .filter_map(|line| {
+        lines += 1;
+        if lines % PROGRESS_LINES == 0 {
+          info!("{} lines read from {}", lines, source);
+        }
+        let line = String::from_utf8_lossy(&line);
+        let domain = line.trim().trim_end_matches('.');
+        if domain.is_empty() || domain.starts_with('#') {
+          return None;
+        }
+        let name = LowerName::from_str(&format!("{domain}.")).ok();
+        if name.is_none() {
+          malformed += 1;
+          if malformed <= LOGGED_MALFORMED_LINES {
+            warn!(
+              "Invalid domain `{}` at line {} of {}",
+              domain, lines, source
+            );
+          }
+        }
+        name
+      });
+    let mut set = Self::new();
+    set.extend(names);
+    if let Some(err) = error {
+      return Err(err.into());
+    }
+    info!(
+      "Loaded {} domains from {} ({} lines, {} malformed lines skipped)",
+      set.len(),
+      source,
+      lines,
+      malformed
+    );
+    Ok(set)
+  }
+
+  pub fn len(&self) -> usize {
+    self.ends.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ends.is_empty()
+  }
+
+  /// Whether `name` is in the set.
+  pub fn contains(&self, name: &LowerName) -> bool {
+    self.contains_key(&key(name))
+  }
+
+  /// Whether `name`, or one of its parent zones, is in the set.
+  pub fn contains_zone(&self, name: &LowerName) -> bool {
+    self.matching_zone(name).is_some()
+  }
+
+  /// The widest zone of the set containing `name`, `name` itself included.
+  pub fn matching_zone(&self, name: &LowerName) -> Option<LowerName> {
+    let key = key(name);
+    let mut end = 0;
+    loop {
+      if self.contains_key(&key[..end]) {
+        return Some(decode(&key[..end]));
+      }
+      if end == key.len() {
+        return None;
+      }
+      end += 1 + key[end] as usize;
+    }
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = LowerName> + '_ {
+    (0..self.len()).map(|index| decode(self.key(index)))
+  }
+
+  fn key(&self, index: usize) -> &[u8] {
+    let start = match index {
+      0 => 0,
+      _ => self.ends[index - 1] as usize,
+    };
+    &self.keys[start..self.ends[index] as usize]
+  }
+
+  fn contains_key(&self, key: &[u8]) -> bool {
+    self
+      .binary_search_by(|index| self.key(index).cmp(key))
+      .is_some()
+  }
+
+  fn binary_search_by(&self, mut compare: impl FnMut(usize) -> Ordering) -> Option<usize> {
+    let (mut low, mut high) = (0, self.len());
+    while low < high {
+      let middle = low + (high - low) / 2;
+      match compare(middle) {
+        Ordering::Less => low = middle + 1,
+        Ordering::Greater => high = middle,
+        Ordering::Equal => return Some(middle),
+      }
+    }
+    None
+  }
+}
+
+impl Extend<LowerName> for DomainSet {
+  /// Add the names and sort the set again, names should be added in as few calls as possible.
+  fn extend<T: IntoIterator<Item = LowerName>>(&mut self, names: T) {
+    let mut keys = std::mem::take(&mut self.keys);
+    let mut ranges: Vec<(usize, usize)> = Vec::with_capacity(self.ends.len());
+    let mut start = 0;
+    for end in std::mem::take(&mut self.ends) {
+      ranges.push((start, end as usize));
+      start = end as usize;
+    }
+    for name in names {
+      let start = keys.len();
+      keys.extend_from_slice(&key(&name));
+      ranges.push((start, keys.len()));
+    }
+    ranges.sort_unstable_by(|a, b| keys[a.0..a.1].cmp(&keys[b.0..b.1]));
+    ranges.dedup_by(|a, b| keys[a.0..a.1] == keys[b.0..b.1]);
+
+    self
+      .keys
+      .reserve_exact(ranges.iter().map(|(start, end)| end - start).sum());
+    self.ends.reserve_exact(ranges.len());
+    for (start, end) in ranges {
+      self.keys.extend_from_slice(&keys[start..end]);
+      self.ends.push(self.keys.len() as u32);
+    }
+  }
+}
+
+impl FromIterator<LowerName> for DomainSet {
+  fn from_iter<T: IntoIterator<Item = LowerName>>(names: T) -> Self {
+    let mut set = Self::new();
+    set.extend(names);
+    set
+  }
+}
+
+impl IntoIterator for DomainSet {
+  type Item = LowerName;
+  type IntoIter = std::vec::IntoIter<LowerName>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.iter().collect::<Vec<_>>().into_iter()
+  }
+}
+
+impl fmt::Debug for DomainSet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "DomainSet({} domains)", self.len())
+  }
+}
+
+/// Labels of the name from the root, each one prefixed by its length.
+fn key(name: &LowerName) -> Vec<u8> {
+  let name = Name::from(name);
+  let mut key = Vec::with_capacity(name.len());
+  for label in name.iter().rev() {
+    key.push(label.len() as u8);
+    key.extend_from_slice(label);
+  }
+  key
+}
+
+fn decode(key: &[u8]) -> LowerName {
+  let mut labels = vec![];
+  let mut start = 0;
+  while start < key.len() {
+    let end = start + 1 + key[start] as usize;
+    labels.push(&key[start + 1..end]);
+    start = end;
+  }
+  labels.reverse();
+  // The labels come from a valid name.
+  LowerName::new(&Name::from_labels(labels).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::str::FromStr;
+
+  fn name(name: &str) -> LowerName {
+    LowerName::from_str(name).unwrap()
+  }
+
+  #[test]
+  fn exact_and_zones() {
+    let mut set: DomainSet = [name("example.com."), name("ads.example.org.")]
+      .into_iter()
+      .collect();
+    set.extend([name("Tracker.NET."), name("example.com.")]);
+    assert_eq!(set.len(), 3);
+
+    assert!(set.contains(&name("example.com.")));
+    assert!(set.contains(&name("tracker.net.")));
+    assert!(!set.contains(&name("www.example.com.")));
+    assert!(!set.contains(&name("com.")));
+
+    assert!(set.contains_zone(&name("www.example.com.")));
+    assert!(set.contains_zone(&name("a.b.ads.example.org.")));
+    assert!(!set.contains_zone(&name("example.org.")));
+    assert!(!set.contains_zone(&name("badexample.com.")));
+    assert!(!set.contains_zone(&name("com.")));
+    assert_eq!(
+      set.matching_zone(&name("a.b.ads.example.org.")),
+      Some(name("ads.example.org."))
+    );
+
+    let mut names: Vec<String> = set.iter().map(|name| name.to_string()).collect();
+    names.sort();
+    assert_eq!(names, ["ads.example.org.", "example.com.", "tracker.net."]);
+  }
+
+  #[test]
+  fn read_lines() {
+    let content =
+      "# ads\nads.example.com\n\n  tracker.example.net.  \nbad..domain\nads.example.com\n";
+    let set = DomainSet::read(content.as_bytes(), "test").unwrap();
+    assert_eq!(set.len(), 2);
+    assert!(set.contains(&name("tracker.example.net.")));
+  }
+
+  #[test]
+  fn root_zone() {
+    let set: DomainSet = [name(".")].into_iter().collect();
+    assert!(set.contains_zone(&name("example.com.")));
+    assert!(!set.contains(&name("example.com.")));
+  }
+}
@@ -0,0 +1,77 @@
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::error;
+
+/// Appends one JSON line per change of the running server (blocking toggled, zones blocked,
+/// configuration reloaded...), with its time and where it came from. Changes are rare, each line
+/// is written before the change is answered so none is lost.
+pub struct AuditLog {
+  file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+  timestamp: String,
+  source: &'a str,
+  change: &'a str,
+}
+
+impl AuditLog {
+  pub fn open(path: &Path) -> std::io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Self {
+      file: Mutex::new(file),
+    })
+  }
+
+  pub fn record(&self, source: &str, change: &str) {
+    let entry = AuditEntry {
+      timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+      source,
+      change,
+    };
+    let mut line = match serde_json::to_vec(&entry) {
+      Ok(line) => line,
+      Err(err) => {
+        error!("Could not serialize audit entry: {}", err);
+        return;
+      }
+    };
+    line.push(b'\n');
+    // A single write per line, so concurrent servers appending to the file never mix lines.
+    if let Err(err) = self.file.lock().unwrap().write_all(&line) {
+      error!("Could not write the audit log: {}", err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn append_entries() {
+    let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    AuditLog::open(&path)
+      .unwrap()
+      .record("control socket, uid 0", "blocking enabled");
+    AuditLog::open(&path)
+      .unwrap()
+      .record("SIGHUP", "configuration reloaded");
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<serde_json::Value> = log
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["source"], "control socket, uid 0");
+    assert_eq!(lines[1]["change"], "configuration reloaded");
+    assert!(lines[1]["timestamp"].as_str().unwrap().ends_with('Z'));
+  }
+}
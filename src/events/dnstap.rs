@@ -0,0 +1,411 @@
+use crate::events::{LogPrivacy, QueryEvent, QueryObserver};
+use chrono::{DateTime, Utc};
+use hickory_server::server::Protocol;
+use std::{net::SocketAddr, path::PathBuf, sync::Mutex, time::Duration};
+use tokio::{
+  fs::OpenOptions,
+  io::{AsyncWrite, AsyncWriteExt, BufWriter},
+  sync::{
+    mpsc::{self, error::TrySendError, Receiver, Sender},
+    oneshot,
+  },
+  task::JoinHandle,
+  time::Instant,
+};
+#[cfg(unix)]
+use tokio::{
+  io::{AsyncRead, AsyncReadExt},
+  net::UnixStream,
+};
+#[cfg(unix)]
+use tracing::info;
+use tracing::{error, warn};
+
+const QUEUE_SIZE: usize = 4096;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frames.
+#[cfg(unix)]
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+#[cfg(unix)]
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// dnstap.Message.Type
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+
+/// Where the dnstap frames are written.
+#[derive(Debug, Clone)]
+pub enum DnstapOutput {
+  /// Bidirectional Frame Streams over a unix socket, e.g. to a `dnstap` collector.
+  #[cfg(unix)]
+  Socket(PathBuf),
+  /// Unidirectional Frame Streams written to a file, truncated when it is opened so it holds a
+  /// single stream.
+  File(PathBuf),
+}
+
+/// Sends a `CLIENT_QUERY` and a `CLIENT_RESPONSE` dnstap message for every query. Encoding and
+/// writing are done by a dedicated task. Events are anonymized or skipped according to the
+/// privacy options, the messages are left out when the names are hashed.
+pub struct Dnstap {
+  sender: Sender<QueryEvent>,
+  privacy: LogPrivacy,
+  /// Stops the task once the queued events are written, see [`QueryObserver::stop`].
+  task: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl Dnstap {
+  pub fn new(output: DnstapOutput, privacy: LogPrivacy) -> Self {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    let (stop, stopped) = oneshot::channel();
+    let task = tokio::spawn(Self::encode_events(output, receiver, stopped));
+    Self {
+      sender,
+      privacy,
+      task: Mutex::new(Some((stop, task))),
+    }
+  }
+
+  async fn encode_events(
+    output: DnstapOutput,
+    mut receiver: Receiver<QueryEvent>,
+    mut stopped: oneshot::Receiver<()>,
+  ) {
+    let identity = hostname();
+    let mut writer: Option<Box<dyn AsyncWrite + Send + Unpin>> = None;
+    let mut last_attempt: Option<Instant> = None;
+    let mut stopping = false;
+
+    loop {
+      let event = tokio::select! {
+        event = receiver.recv() => event,
+        _ = &mut stopped, if !stopping => {
+          // The events already queued are still written, then the stream is stopped.
+          stopping = true;
+          receiver.close();
+          continue;
+        }
+      };
+      let Some(event) = event else {
+        break;
+      };
+      if writer.is_none() && last_attempt.is_none_or(|at| at.elapsed() >= RECONNECT_DELAY) {
+        last_attempt = Some(Instant::now());
+        writer = match Self::open(&output).await {
+          Ok(writer) => Some(writer),
+          Err(err) => {
+            warn!("Could not open dnstap output {:?}: {}", output, err);
+            None
+          }
+        };
+      }
+      let Some(output_writer) = writer.as_mut() else {
+        continue;
+      };
+
+      let mut frames = Vec::new();
+      push_data_frames(&mut frames, &event, &identity);
+      // Write everything already queued before flushing, so bursts end up in a single write.
+      while let Ok(event) = receiver.try_recv() {
+        push_data_frames(&mut frames, &event, &identity);
+      }
+
+      let result = match output_writer.write_all(&frames).await {
+        Ok(_) => output_writer.flush().await,
+        Err(err) => Err(err),
+      };
+      if let Err(err) = result {
+        error!("Could not write dnstap frames, will reconnect: {}", err);
+        writer = None;
+      }
+    }
+
+    if let Some(mut writer) = writer {
+      let _ = writer.write_all(&control_frame(CONTROL_STOP, false)).await;
+      let _ = writer.flush().await;
+    }
+  }
+
+  async fn open(output: &DnstapOutput) -> std::io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+    match output {
+      #[cfg(unix)]
+      DnstapOutput::Socket(path) => {
+        let mut stream = UnixStream::connect(path).await?;
+        stream
+          .write_all(&control_frame(CONTROL_READY, true))
+          .await?;
+        let accept = read_control_frame(&mut stream).await?;
+        if accept != CONTROL_ACCEPT {
+          return Err(std::io::Error::other(format!(
+            "unexpected control frame {accept:#x} instead of ACCEPT"
+          )));
+        }
+        stream
+          .write_all(&control_frame(CONTROL_START, true))
+          .await?;
+        info!("Connected to dnstap socket {}", path.display());
+        Ok(Box::new(BufWriter::new(stream)))
+      }
+      DnstapOutput::File(path) => {
+        let mut file = OpenOptions::new()
+          .create(true)
+          .write(true)
+          .truncate(true)
+          .open(path)
+          .await?;
+        file.write_all(&control_frame(CONTROL_START, true)).await?;
+        Ok(Box::new(BufWriter::new(file)))
+      }
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl QueryObserver for Dnstap {
+  fn on_query(&self, event: &QueryEvent) {
+    let Some(event) = self.privacy.apply(event) else {
+      return;
+    };
+    if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+      warn!("dnstap queue is full, dropping event for {}", event.qname);
+    }
+  }
+
+  fn wants_messages(&self) -> bool {
+    true
+  }
+
+  async fn stop(&self) {
+    let Some((stop, task)) = self.task.lock().unwrap().take() else {
+      return;
+    };
+    let _ = stop.send(());
+    let _ = task.await;
+  }
+}
+
+fn hostname() -> Vec<u8> {
+  std::fs::read_to_string("/etc/hostname")
+    .map(|name| name.trim().as_bytes().to_vec())
+    .unwrap_or_default()
+}
+
+/// A Frame Streams control frame, with the content type field when `content_type` is set.
+fn control_frame(control_type: u32, content_type: bool) -> Vec<u8> {
+  let mut payload = control_type.to_be_bytes().to_vec();
+  if content_type {
+    payload.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+    payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    payload.extend_from_slice(CONTENT_TYPE);
+  }
+  let mut frame = 0u32.to_be_bytes().to_vec();
+  frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+  frame.extend_from_slice(&payload);
+  frame
+}
+
+#[cfg(unix)]
+async fn read_control_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<u32> {
+  if reader.read_u32().await? != 0 {
+    return Err(std::io::Error::other("expected a control frame"));
+  }
+  let length = reader.read_u32().await? as usize;
+  if !(4..=512).contains(&length) {
+    return Err(std::io::Error::other("invalid control frame length"));
+  }
+  let mut payload = vec![0; length];
+  reader.read_exact(&mut payload).await?;
+  Ok(u32::from_be_bytes([
+    payload[0], payload[1], payload[2], payload[3],
+  ]))
+}
+
+fn push_data_frames(frames: &mut Vec<u8>, event: &QueryEvent, identity: &[u8]) {
+  for message in encode_event(event, identity) {
+    frames.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frames.extend_from_slice(&message);
+  }
+}
+
+/// Encode the `CLIENT_QUERY` and `CLIENT_RESPONSE` dnstap messages of an event.
+fn encode_event(event: &QueryEvent, identity: &[u8]) -> [Vec<u8>; 2] {
+  let response_time = event.timestamp + event.latency;
+  [
+    encode_dnstap(
+      identity,
+      &encode_message(event, CLIENT_QUERY, &event.timestamp),
+    ),
+    encode_dnstap(
+      identity,
+      &encode_message(event, CLIENT_RESPONSE, &response_time),
+    ),
+  ]
+}
+
+fn encode_dnstap(identity: &[u8], message: &[u8]) -> Vec<u8> {
+  let mut buffer = Vec::with_capacity(message.len() + 64);
+  if !identity.is_empty() {
+    write_bytes(&mut buffer, 1, identity);
+  }
+  write_bytes(
+    &mut buffer,
+    2,
+    concat!("dns-server ", env!("CARGO_PKG_VERSION")).as_bytes(),
+  );
+  write_bytes(&mut buffer, 14, message);
+  // Dnstap.Type MESSAGE
+  write_varint_field(&mut buffer, 15, 1);
+  buffer
+}
+
+fn encode_message(event: &QueryEvent, message_type: u64, time: &DateTime<Utc>) -> Vec<u8> {
+  let mut buffer = Vec::with_capacity(512);
+  write_varint_field(&mut buffer, 1, message_type);
+  let (family, address) = match event.client {
+    SocketAddr::V4(addr) => (1, addr.ip().octets().to_vec()),
+    SocketAddr::V6(addr) => (2, addr.ip().octets().to_vec()),
+  };
+  write_varint_field(&mut buffer, 2, family);
+  write_varint_field(&mut buffer, 3, socket_protocol(event.protocol));
+  write_bytes(&mut buffer, 4, &address);
+  write_varint_field(&mut buffer, 6, event.client.port() as u64);
+
+  let (seconds, nanos) = (time.timestamp() as u64, time.timestamp_subsec_nanos());
+  if message_type == CLIENT_QUERY {
+    write_varint_field(&mut buffer, 8, seconds);
+    write_fixed32_field(&mut buffer, 9, nanos);
+    if let Some(message) = &event.query_message {
+      write_bytes(&mut buffer, 10, message);
+    }
+  } else {
+    // The query time is also set on responses so collectors can compute the latency.
+    let query_time = event.timestamp;
+    write_varint_field(&mut buffer, 8, query_time.timestamp() as u64);
+    write_fixed32_field(&mut buffer, 9, query_time.timestamp_subsec_nanos());
+    write_varint_field(&mut buffer, 12, seconds);
+    write_fixed32_field(&mut buffer, 13, nanos);
+    if let Some(message) = &event.response_message {
+      write_bytes(&mut buffer, 14, message);
+    }
+  }
+  buffer
+}
+
+fn socket_protocol(protocol: Protocol) -> u64 {
+  match protocol {
+    Protocol::Udp => 1,
+    Protocol::Tcp => 2,
+    Protocol::Tls => 3,
+    Protocol::Https => 4,
+    Protocol::Quic => 7,
+    _ => 4,
+  }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    buffer.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  buffer.push(value as u8);
+}
+
+fn write_varint_field(buffer: &mut Vec<u8>, field: u64, value: u64) {
+  write_varint(buffer, field << 3);
+  write_varint(buffer, value);
+}
+
+fn write_fixed32_field(buffer: &mut Vec<u8>, field: u64, value: u32) {
+  write_varint(buffer, (field << 3) | 5);
+  buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(buffer: &mut Vec<u8>, field: u64, value: &[u8]) {
+  write_varint(buffer, (field << 3) | 2);
+  write_varint(buffer, value.len() as u64);
+  buffer.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::events::QueryAction;
+  use hickory_server::proto::{op::ResponseCode, rr::RecordType};
+
+  #[tokio::test]
+  async fn file_output() {
+    let path = std::env::temp_dir().join(format!("dnstap-{}", std::process::id()));
+    std::fs::write(&path, b"previous stream").unwrap();
+    let dnstap = Dnstap::new(DnstapOutput::File(path.clone()), LogPrivacy::default());
+    dnstap.on_query(&QueryEvent {
+      timestamp: "2024-01-01T12:00:00Z".parse().unwrap(),
+      client: "192.168.1.20:5353".parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: "www.example.com.".to_string(),
+      qtype: RecordType::A,
+      rcode: ResponseCode::NoError,
+      latency: Duration::from_millis(1),
+      action: QueryAction::Forwarded,
+      upstream: None,
+      cached: false,
+      blocked_by: None,
+      group: None,
+      query_message: None,
+      response_message: None,
+    });
+    dnstap.stop().await;
+    let content = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // A single stream: START, the query and the response, then STOP.
+    let start = control_frame(CONTROL_START, true);
+    let stop = control_frame(CONTROL_STOP, false);
+    assert!(content.starts_with(&start));
+    assert!(content.ends_with(&stop));
+    let mut frames = &content[start.len()..content.len() - stop.len()];
+    for _ in 0..2 {
+      let length = u32::from_be_bytes(frames[..4].try_into().unwrap()) as usize;
+      assert!(length > 0);
+      frames = &frames[4 + length..];
+    }
+    assert!(frames.is_empty());
+  }
+
+  #[test]
+  fn varint() {
+    let mut buffer = vec![];
+    write_varint(&mut buffer, 1);
+    write_varint(&mut buffer, 300);
+    assert_eq!(buffer, vec![0x01, 0xac, 0x02]);
+  }
+
+  #[test]
+  fn control_frames() {
+    assert_eq!(
+      control_frame(CONTROL_STOP, false),
+      vec![0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]
+    );
+
+    let start = control_frame(CONTROL_START, true);
+    assert_eq!(&start[0..4], &[0, 0, 0, 0]);
+    assert_eq!(
+      &start[4..8],
+      &(12 + CONTENT_TYPE.len() as u32).to_be_bytes()
+    );
+    assert_eq!(&start[8..12], &CONTROL_START.to_be_bytes());
+    assert_eq!(&start[20..], CONTENT_TYPE);
+  }
+
+  #[test]
+  fn dnstap_message() {
+    let message = encode_dnstap(b"", &[0x08, 0x05]);
+    // version field, then the message field and the MESSAGE type.
+    assert_eq!(message[0], 0x12);
+    assert!(message.ends_with(&[0x72, 0x02, 0x08, 0x05, 0x78, 0x01]));
+  }
+}
@@ -0,0 +1,296 @@
+use crate::events::top::RollingTop;
+use crate::events::{LogPrivacy, QueryAction, QueryEvent, QueryObserver};
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Maximum number of domains or clients counted, the least seen are forgotten beyond that.
+const MAX_COUNTERS: usize = 10_000;
+/// Domains or clients counted in each bucket of the rolling windows.
+const WINDOW_COUNTERS: usize = 500;
+
+/// Keeps the last queries in memory and counts blocked domains and clients, for the dashboard.
+/// Events are anonymized or skipped according to the privacy options.
+pub struct QueryHistory {
+  capacity: usize,
+  privacy: LogPrivacy,
+  state: Mutex<HistoryState>,
+}
+
+struct HistoryState {
+  recent: VecDeque<QueryEvent>,
+  blocked_domains: HashMap<String, u64>,
+  /// Domains of the queries that were not blocked.
+  domains: HashMap<String, u64>,
+  clients: HashMap<IpAddr, u64>,
+  hour: WindowTops,
+  day: WindowTops,
+}
+
+/// Rolling window of the top domains and clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+  /// The last hour, in buckets of 5 minutes.
+  Hour,
+  /// The last 24 hours, in buckets of an hour.
+  Day,
+}
+
+impl FromStr for Window {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "hour" => Ok(Window::Hour),
+      "day" => Ok(Window::Day),
+      _ => bail!("unknown window `{s}`, expected hour or day"),
+    }
+  }
+}
+
+/// Most queried and blocked domains and busiest clients.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Tops {
+  pub domains: Vec<Counter<String>>,
+  pub blocked_domains: Vec<Counter<String>>,
+  pub clients: Vec<Counter<IpAddr>>,
+}
+
+struct WindowTops {
+  domains: RollingTop<String>,
+  blocked_domains: RollingTop<String>,
+  clients: RollingTop<IpAddr>,
+}
+
+impl WindowTops {
+  fn new(bucket: Duration, buckets: usize) -> Self {
+    Self {
+      domains: RollingTop::new(bucket, buckets, WINDOW_COUNTERS),
+      blocked_domains: RollingTop::new(bucket, buckets, WINDOW_COUNTERS),
+      clients: RollingTop::new(bucket, buckets, WINDOW_COUNTERS),
+    }
+  }
+
+  fn increment(&mut self, event: &QueryEvent) {
+    self.clients.increment(event.client.ip());
+    match event.action {
+      QueryAction::Blocked => self.blocked_domains.increment(event.qname.clone()),
+      QueryAction::Forwarded => self.domains.increment(event.qname.clone()),
+    }
+  }
+
+  fn top(&self, limit: usize) -> Tops {
+    Tops {
+      domains: self.domains.top(limit),
+      blocked_domains: self.blocked_domains.top(limit),
+      clients: self.clients.top(limit),
+    }
+  }
+}
+
+/// Number of queries for a domain or a client.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Counter<T> {
+  pub name: T,
+  pub count: u64,
+}
+
+impl QueryHistory {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      privacy: LogPrivacy::default(),
+      state: Mutex::new(HistoryState {
+        recent: VecDeque::new(),
+        blocked_domains: HashMap::new(),
+        domains: HashMap::new(),
+        clients: HashMap::new(),
+        hour: WindowTops::new(Duration::from_secs(300), 12),
+        day: WindowTops::new(Duration::from_secs(3600), 24),
+      }),
+    }
+  }
+
+  pub fn privacy(mut self, privacy: LogPrivacy) -> Self {
+    self.privacy = privacy;
+    self
+  }
+
+  /// Last queries, the most recent first.
+  pub fn recent(&self) -> Vec<QueryEvent> {
+    self.state.lock().unwrap().recent.iter().cloned().collect()
+  }
+
+  pub fn top_blocked_domains(&self, limit: usize) -> Vec<Counter<String>> {
+    top(&self.state.lock().unwrap().blocked_domains, limit)
+  }
+
+  pub fn top_clients(&self, limit: usize) -> Vec<Counter<IpAddr>> {
+    top(&self.state.lock().unwrap().clients, limit)
+  }
+
+  /// Most queried domains that were not blocked.
+  pub fn top_domains(&self, limit: usize) -> Vec<Counter<String>> {
+    top(&self.state.lock().unwrap().domains, limit)
+  }
+
+  /// Top domains and clients of the rolling `window`, or since the server started when `None`.
+  pub fn top(&self, window: Option<Window>, limit: usize) -> Tops {
+    let state = self.state.lock().unwrap();
+    match window {
+      Some(Window::Hour) => state.hour.top(limit),
+      Some(Window::Day) => state.day.top(limit),
+      None => Tops {
+        domains: top(&state.domains, limit),
+        blocked_domains: top(&state.blocked_domains, limit),
+        clients: top(&state.clients, limit),
+      },
+    }
+  }
+
+  /// Number of distinct domains and clients counted.
+  pub fn unique(&self) -> (usize, usize) {
+    let state = self.state.lock().unwrap();
+    (
+      state.domains.len() + state.blocked_domains.len(),
+      state.clients.len(),
+    )
+  }
+}
+
+impl QueryObserver for QueryHistory {
+  fn on_query(&self, event: &QueryEvent) {
+    let Some(event) = self.privacy.apply(event) else {
+      return;
+    };
+    let mut state = self.state.lock().unwrap();
+    if state.recent.len() >= self.capacity {
+      state.recent.pop_back();
+    }
+    state.hour.increment(&event);
+    state.day.increment(&event);
+
+    increment(&mut state.clients, event.client.ip());
+    match event.action {
+      QueryAction::Blocked => increment(&mut state.blocked_domains, event.qname.clone()),
+      QueryAction::Forwarded => increment(&mut state.domains, event.qname.clone()),
+    }
+    state.recent.push_front(event);
+  }
+}
+
+fn increment<T: std::hash::Hash + Eq>(counters: &mut HashMap<T, u64>, key: T) {
+  if counters.len() >= MAX_COUNTERS && !counters.contains_key(&key) {
+    counters.retain(|_, count| *count > 1);
+  }
+  *counters.entry(key).or_insert(0) += 1;
+}
+
+fn top<T: Clone + Ord>(counters: &HashMap<T, u64>, limit: usize) -> Vec<Counter<T>> {
+  let mut top: Vec<Counter<T>> = counters
+    .iter()
+    .map(|(name, count)| Counter {
+      name: name.clone(),
+      count: *count,
+    })
+    .collect();
+  top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+  top.truncate(limit);
+  top
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::events::{hash_name, LogQueries};
+  use chrono::Utc;
+  use hickory_server::{
+    proto::{op::ResponseCode, rr::RecordType},
+    server::Protocol,
+  };
+  use std::time::Duration;
+
+  fn event(client: &str, qname: &str, action: QueryAction) -> QueryEvent {
+    QueryEvent {
+      timestamp: Utc::now(),
+      client: format!("{client}:5353").parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: qname.to_string(),
+      qtype: RecordType::A,
+      rcode: ResponseCode::NoError,
+      latency: Duration::from_millis(1),
+      action,
+      upstream: None,
+      cached: false,
+      blocked_by: None,
+      group: None,
+      query_message: None,
+      response_message: None,
+    }
+  }
+
+  #[test]
+  fn recent_and_top() {
+    let history = QueryHistory::new(2);
+    history.on_query(&event("10.0.0.1", "ads.example.", QueryAction::Blocked));
+    history.on_query(&event("10.0.0.2", "example.", QueryAction::Forwarded));
+    history.on_query(&event("10.0.0.2", "ads.example.", QueryAction::Blocked));
+    history.on_query(&event("10.0.0.2", "tracker.example.", QueryAction::Blocked));
+
+    let recent: Vec<String> = history.recent().into_iter().map(|e| e.qname).collect();
+    assert_eq!(recent, ["tracker.example.", "ads.example."]);
+    assert_eq!(
+      history.top_blocked_domains(1),
+      [Counter {
+        name: "ads.example.".to_string(),
+        count: 2
+      }]
+    );
+    assert_eq!(history.top_domains(5)[0].name, "example.");
+    assert_eq!(history.unique(), (3, 2));
+    assert_eq!(
+      history.top_clients(5),
+      [
+        Counter {
+          name: "10.0.0.2".parse().unwrap(),
+          count: 3
+        },
+        Counter {
+          name: "10.0.0.1".parse().unwrap(),
+          count: 1
+        }
+      ]
+    );
+    let hour = history.top(Some(Window::Hour), 5);
+    assert_eq!(hour, history.top(Some(Window::Day), 5));
+    assert_eq!(hour, history.top(None, 5));
+    assert_eq!(hour.blocked_domains[0].count, 2);
+    assert!(Window::from_str("week").is_err());
+  }
+
+  #[test]
+  fn anonymized() {
+    let history = QueryHistory::new(2).privacy(LogPrivacy {
+      queries: LogQueries::Blocked,
+      ipv4_prefix: 24,
+      hash_names: true,
+      ..Default::default()
+    });
+    history.on_query(&event("10.0.0.1", "example.", QueryAction::Forwarded));
+    history.on_query(&event("10.0.0.2", "ads.example.", QueryAction::Blocked));
+
+    let recent = history.recent();
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].qname, hash_name("ads.example.", None));
+    assert_eq!(recent[0].client, "10.0.0.0:0".parse().unwrap());
+    assert!(history.top_domains(5).is_empty());
+    assert_eq!(
+      history.top_clients(5)[0].name,
+      "10.0.0.0".parse::<IpAddr>().unwrap()
+    );
+  }
+}
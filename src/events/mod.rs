@@ -0,0 +1,209 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use hickory_server::{
+  proto::{
+    op::ResponseCode,
+    rr::{rdata::opt::ClientSubnet, RecordType},
+  },
+  server::Protocol,
+};
+use serde::{Serialize, Serializer};
+use std::{
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+mod audit_log;
+mod dnstap;
+mod history;
+mod privacy;
+mod query_db;
+mod query_log;
+mod stats;
+mod top;
+
+pub use crate::events::audit_log::AuditLog;
+pub use crate::events::dnstap::{Dnstap, DnstapOutput};
+pub use crate::events::history::{Counter, QueryHistory, Tops, Window};
+pub use crate::events::privacy::{hash_name, LogPrivacy, LogQueries};
+pub use crate::events::query_db::{HistorySummary, QueryDb};
+pub use crate::events::query_log::QueryLog;
+pub use crate::events::stats::{Stats, StatsSnapshot, UpstreamStats};
+
+tokio::task_local! {
+  static QUERY_CONTEXT: Arc<Mutex<QueryContext>>;
+}
+
+/// What the server decided to do with a query.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryAction {
+  #[default]
+  Forwarded,
+  Blocked,
+}
+
+impl QueryAction {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      QueryAction::Forwarded => "forwarded",
+      QueryAction::Blocked => "blocked",
+    }
+  }
+}
+
+/// Information collected by the authorities while a query is being answered.
+#[derive(Debug, Clone, Default)]
+pub struct QueryContext {
+  pub action: QueryAction,
+  pub upstream: Option<String>,
+  /// List that blocked the query, sent to the client as an Extended DNS Error.
+  pub blocked_by: Option<Arc<str>>,
+  /// EDNS Client Subnet sent by the client.
+  pub client_subnet: Option<ClientSubnet>,
+  /// Whether a message was sent to an upstream, forwarded queries are otherwise answered from
+  /// the cache.
+  pub upstream_sent: bool,
+  /// Why the upstreams could not be asked, sent to the client as an Extended DNS Error of its
+  /// SERVFAIL answer.
+  pub upstream_failure: Option<Arc<str>>,
+}
+
+/// One answered query, emitted once the response has been sent to the client.
+#[derive(Serialize, Debug, Clone)]
+pub struct QueryEvent {
+  #[serde(serialize_with = "serialize_timestamp")]
+  pub timestamp: DateTime<Utc>,
+  pub client: SocketAddr,
+  #[serde(serialize_with = "serialize_display")]
+  pub protocol: Protocol,
+  pub qname: String,
+  #[serde(serialize_with = "serialize_display")]
+  pub qtype: RecordType,
+  #[serde(serialize_with = "serialize_debug")]
+  pub rcode: ResponseCode,
+  #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+  pub latency: Duration,
+  pub action: QueryAction,
+  pub upstream: Option<String>,
+  /// Whether the forwarded query was answered from the cache, without asking the upstream.
+  pub cached: bool,
+  /// List that blocked the query, if any.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub blocked_by: Option<String>,
+  /// Client group of the client, if it belongs to one.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub group: Option<String>,
+  /// Wire format of the query, only filled when an observer asked for messages.
+  #[serde(skip)]
+  pub query_message: Option<Arc<[u8]>>,
+  /// Wire format of the response, only filled when an observer asked for messages.
+  #[serde(skip)]
+  pub response_message: Option<Arc<[u8]>>,
+}
+
+/// Receives every query event produced by the request handler.
+#[async_trait::async_trait]
+pub trait QueryObserver: Send + Sync {
+  fn on_query(&self, event: &QueryEvent);
+
+  /// Whether this observer needs the wire format of the query and the response.
+  fn wants_messages(&self) -> bool {
+    false
+  }
+
+  /// Called once the server is stopped, to write what is still queued.
+  async fn stop(&self) {}
+}
+
+/// Runs `future` with a fresh query context the authorities can fill through [`set_action`],
+/// [`set_blocked`] and [`set_upstream`], and returns the context once the future is done.
+pub async fn with_query_context<F: std::future::Future>(
+  client_subnet: Option<ClientSubnet>,
+  future: F,
+) -> (F::Output, QueryContext) {
+  let context = Arc::new(Mutex::new(QueryContext {
+    client_subnet,
+    ..Default::default()
+  }));
+  let output = QUERY_CONTEXT.scope(context.clone(), future).await;
+  let context = context.lock().unwrap().clone();
+  (output, context)
+}
+
+/// EDNS Client Subnet of the query currently being answered, if any.
+pub fn client_subnet() -> Option<ClientSubnet> {
+  QUERY_CONTEXT
+    .try_with(|context| context.lock().unwrap().client_subnet)
+    .ok()
+    .flatten()
+}
+
+/// Record the action taken for the query currently being answered, if any.
+pub fn set_action(action: QueryAction) {
+  let _ = QUERY_CONTEXT.try_with(|context| context.lock().unwrap().action = action);
+}
+
+/// Record that the query currently being answered, if any, is blocked by `list`.
+pub fn set_blocked(list: impl Into<Arc<str>>) {
+  let list = list.into();
+  let _ = QUERY_CONTEXT.try_with(|context| {
+    let mut context = context.lock().unwrap();
+    context.action = QueryAction::Blocked;
+    context.blocked_by = Some(list);
+  });
+}
+
+/// List that blocked the query currently being answered, if any.
+pub fn blocked_by() -> Option<Arc<str>> {
+  QUERY_CONTEXT
+    .try_with(|context| context.lock().unwrap().blocked_by.clone())
+    .ok()
+    .flatten()
+}
+
+/// Record the upstream used to answer the query currently being answered, if any.
+pub fn set_upstream(upstream: &str) {
+  let _ = QUERY_CONTEXT.try_with(|context| {
+    let mut context = context.lock().unwrap();
+    context.action = QueryAction::Forwarded;
+    context.upstream = Some(upstream.to_string());
+  });
+}
+
+/// Record that the query currently being answered, if any, was sent to an upstream.
+pub fn set_upstream_sent() {
+  let _ = QUERY_CONTEXT.try_with(|context| context.lock().unwrap().upstream_sent = true);
+}
+
+/// Record that the query currently being answered, if any, could not be sent to the upstreams.
+pub fn set_upstream_failure(reason: impl Into<Arc<str>>) {
+  let reason = reason.into();
+  let _ = QUERY_CONTEXT.try_with(|context| context.lock().unwrap().upstream_failure = Some(reason));
+}
+
+/// Why the query currently being answered could not be sent to the upstreams, if any.
+pub fn upstream_failure() -> Option<Arc<str>> {
+  QUERY_CONTEXT
+    .try_with(|context| context.lock().unwrap().upstream_failure.clone())
+    .ok()
+    .flatten()
+}
+
+fn serialize_timestamp<S: Serializer>(value: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+  s.serialize_str(&value.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+fn serialize_display<S: Serializer, T: std::fmt::Display>(
+  value: &T,
+  s: S,
+) -> Result<S::Ok, S::Error> {
+  s.collect_str(value)
+}
+
+fn serialize_debug<S: Serializer, T: std::fmt::Debug>(value: &T, s: S) -> Result<S::Ok, S::Error> {
+  s.collect_str(&format_args!("{:?}", value))
+}
+
+fn serialize_millis<S: Serializer>(value: &Duration, s: S) -> Result<S::Ok, S::Error> {
+  s.serialize_f64(value.as_micros() as f64 / 1000.0)
+}
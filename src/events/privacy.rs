@@ -0,0 +1,224 @@
+use crate::events::{QueryAction, QueryEvent};
+use anyhow::bail;
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Bytes of the SHA-256 or HMAC-SHA256 of the query names kept in the logs.
+const HASH_LENGTH: usize = 16;
+
+/// Queries written in the query log and the query database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogQueries {
+  None,
+  Blocked,
+  #[default]
+  All,
+}
+
+impl FromStr for LogQueries {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(LogQueries::None),
+      "blocked" => Ok(LogQueries::Blocked),
+      "all" => Ok(LogQueries::All),
+      _ => bail!("unknown logged queries `{s}`, expected none, blocked or all"),
+    }
+  }
+}
+
+impl fmt::Display for LogQueries {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LogQueries::None => write!(f, "none"),
+      LogQueries::Blocked => write!(f, "blocked"),
+      LogQueries::All => write!(f, "all"),
+    }
+  }
+}
+
+/// What the query log, the query database, the history of the web dashboard and dnstap keep of
+/// the queries, to store less personal data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogPrivacy {
+  pub queries: LogQueries,
+  /// Prefix length the IPv4 clients are truncated to, 32 keeps the full address.
+  pub ipv4_prefix: u8,
+  /// Prefix length the IPv6 clients are truncated to, 128 keeps the full address.
+  pub ipv6_prefix: u8,
+  /// Replace the query names by their hash, see [`hash_name`].
+  pub hash_names: bool,
+  /// Secret key of the hashes of the names.
+  pub hash_salt: Option<String>,
+}
+
+impl Default for LogPrivacy {
+  fn default() -> Self {
+    Self {
+      queries: LogQueries::All,
+      ipv4_prefix: 32,
+      ipv6_prefix: 128,
+      hash_names: false,
+      hash_salt: None,
+    }
+  }
+}
+
+impl LogPrivacy {
+  /// The event as it must be logged, `None` when it must not be.
+  pub fn apply(&self, event: &QueryEvent) -> Option<QueryEvent> {
+    match self.queries {
+      LogQueries::None => return None,
+      LogQueries::Blocked if event.action != QueryAction::Blocked => return None,
+      _ => {}
+    }
+    let mut event = event.clone();
+    event.client = self.client(event.client);
+    if self.hash_names {
+      event.qname = hash_name(&event.qname, self.hash_salt.as_deref());
+      // The messages would still give the name away.
+      event.query_message = None;
+      event.response_message = None;
+    }
+    Some(event)
+  }
+
+  /// Network of the client, without its port once truncated.
+  fn client(&self, client: SocketAddr) -> SocketAddr {
+    let ip = match client.ip() {
+      IpAddr::V4(ip) if self.ipv4_prefix < 32 => {
+        let mask = u32::MAX
+          .checked_shl(32 - self.ipv4_prefix as u32)
+          .unwrap_or(0);
+        IpAddr::V4((ip.to_bits() & mask).into())
+      }
+      IpAddr::V6(ip) if self.ipv6_prefix < 128 => {
+        let mask = u128::MAX
+          .checked_shl(128 - self.ipv6_prefix as u32)
+          .unwrap_or(0);
+        IpAddr::V6((ip.to_bits() & mask).into())
+      }
+      _ => return client,
+    };
+    SocketAddr::new(ip, 0)
+  }
+}
+
+/// Start of the SHA-256 of the lowercase name with its final dot, in hexadecimal. A logged name
+/// can be checked with `printf example.com. | sha256sum | cut -c 1-32`, and so can any guessed
+/// name: with a `salt`, the HMAC-SHA256 keyed with it is used instead so only the ones knowing
+/// the salt can.
+pub fn hash_name(name: &str, salt: Option<&str>) -> String {
+  let mut name = name.to_ascii_lowercase();
+  if !name.ends_with('.') {
+    name.push('.');
+  }
+  let hash = match salt {
+    Some(salt) => {
+      let key = hmac::Key::new(hmac::HMAC_SHA256, salt.as_bytes());
+      hmac::sign(&key, name.as_bytes()).as_ref().to_vec()
+    }
+    None => digest(&SHA256, name.as_bytes()).as_ref().to_vec(),
+  };
+  hash[..HASH_LENGTH]
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::{
+    proto::{op::ResponseCode, rr::RecordType},
+    server::Protocol,
+  };
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  fn event(client: &str, action: QueryAction) -> QueryEvent {
+    QueryEvent {
+      timestamp: chrono::Utc::now(),
+      client: client.parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: "Ads.Example.com.".to_string(),
+      qtype: RecordType::A,
+      rcode: ResponseCode::NoError,
+      latency: Duration::from_millis(1),
+      action,
+      upstream: None,
+      cached: false,
+      blocked_by: None,
+      group: None,
+      query_message: None,
+      response_message: None,
+    }
+  }
+
+  #[test]
+  fn filter_queries() {
+    let forwarded = event("192.168.1.20:5353", QueryAction::Forwarded);
+    let blocked = event("192.168.1.20:5353", QueryAction::Blocked);
+    let privacy = LogPrivacy::default();
+    assert_eq!(privacy.apply(&forwarded).unwrap().client, forwarded.client);
+    let privacy = LogPrivacy {
+      queries: LogQueries::Blocked,
+      ..Default::default()
+    };
+    assert!(privacy.apply(&forwarded).is_none());
+    assert!(privacy.apply(&blocked).is_some());
+    let privacy = LogPrivacy {
+      queries: LogQueries::None,
+      ..Default::default()
+    };
+    assert!(privacy.apply(&blocked).is_none());
+    assert_eq!(
+      "blocked".parse::<LogQueries>().unwrap(),
+      LogQueries::Blocked
+    );
+    assert!("some".parse::<LogQueries>().is_err());
+  }
+
+  #[test]
+  fn anonymize() {
+    let privacy = LogPrivacy {
+      ipv4_prefix: 24,
+      ipv6_prefix: 56,
+      hash_names: true,
+      ..Default::default()
+    };
+    let v4 = privacy
+      .apply(&event("192.168.1.20:5353", QueryAction::Forwarded))
+      .unwrap();
+    assert_eq!(v4.client, "192.168.1.0:0".parse().unwrap());
+    assert_eq!(v4.qname, hash_name("ads.example.com", None));
+    assert_eq!(v4.qname, "dcba9da7d7f317d75c11c5a22b28a6d3");
+    let v6 = privacy
+      .apply(&event("[2001:db8:1:2345::1]:5353", QueryAction::Blocked))
+      .unwrap();
+    assert_eq!(v6.client, "[2001:db8:1:2300::]:0".parse().unwrap());
+  }
+
+  #[test]
+  fn salted_hash() {
+    let privacy = LogPrivacy {
+      hash_names: true,
+      hash_salt: Some("secret".to_string()),
+      ..Default::default()
+    };
+    let mut forwarded = event("192.168.1.20:5353", QueryAction::Forwarded);
+    forwarded.query_message = Some(Arc::from(vec![0; 12]));
+    let logged = privacy.apply(&forwarded).unwrap();
+    assert_eq!(logged.qname, hash_name("ads.example.com.", Some("secret")));
+    assert_eq!(logged.qname, "7e8e5c0f3af4094776ca03893d9c8396");
+    assert_ne!(logged.qname, hash_name("ads.example.com.", Some("other")));
+    assert_ne!(logged.qname, hash_name("ads.example.com.", None));
+    assert!(logged.query_message.is_none());
+  }
+}
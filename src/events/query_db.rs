@@ -0,0 +1,272 @@
+use crate::events::{Counter, LogPrivacy, QueryEvent, QueryObserver};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::{error, info, warn};
+
+const QUEUE_SIZE: usize = 4096;
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+const TOP_SIZE: usize = 10;
+
+const SCHEMA: &str = "
+  PRAGMA journal_mode = WAL;
+  CREATE TABLE IF NOT EXISTS queries (
+    timestamp INTEGER NOT NULL,
+    client TEXT NOT NULL,
+    client_port INTEGER NOT NULL,
+    protocol TEXT NOT NULL,
+    qname TEXT NOT NULL,
+    qtype TEXT NOT NULL,
+    rcode TEXT NOT NULL,
+    latency_ms REAL NOT NULL,
+    action TEXT NOT NULL,
+    upstream TEXT,
+    client_group TEXT
+  );
+  CREATE INDEX IF NOT EXISTS queries_timestamp ON queries (timestamp);
+";
+
+/// Stores every query in an SQLite database, rows older than the retention are deleted every
+/// hour. Inserts are done by a dedicated thread in batches. Events are anonymized or skipped
+/// according to the privacy options.
+pub struct QueryDb {
+  path: PathBuf,
+  sender: Sender<QueryEvent>,
+  privacy: LogPrivacy,
+}
+
+/// Statistics computed from the database over a period.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HistorySummary {
+  pub since_secs: u64,
+  pub queries: u64,
+  pub blocked: u64,
+  pub forwarded: u64,
+  pub failed: u64,
+  pub blocked_domains: Vec<Counter<String>>,
+  pub clients: Vec<Counter<String>>,
+}
+
+impl QueryDb {
+  pub fn open(path: &Path, retention: Duration, privacy: LogPrivacy) -> Result<Self> {
+    let connection = Connection::open(path)
+      .with_context(|| format!("could not open query database {}", path.display()))?;
+    connection
+      .execute_batch(SCHEMA)
+      .with_context(|| format!("could not create tables in {}", path.display()))?;
+
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    std::thread::Builder::new()
+      .name("query-db".to_string())
+      .spawn(move || Self::write_events(connection, receiver, retention))?;
+    Ok(Self {
+      path: path.to_path_buf(),
+      sender,
+      privacy,
+    })
+  }
+
+  fn write_events(
+    mut connection: Connection,
+    mut receiver: Receiver<QueryEvent>,
+    retention: Duration,
+  ) {
+    let mut last_purge: Option<Instant> = None;
+    loop {
+      if last_purge.is_none_or(|at| at.elapsed() >= PURGE_INTERVAL) {
+        last_purge = Some(Instant::now());
+        match purge(&connection, retention) {
+          Ok(deleted) => info!("Deleted {} queries older than {:?}", deleted, retention),
+          Err(err) => error!("Could not delete old queries: {}", err),
+        }
+      }
+
+      let Some(event) = receiver.blocking_recv() else {
+        return;
+      };
+      let mut events = vec![event];
+      while let Ok(event) = receiver.try_recv() {
+        events.push(event);
+      }
+      if let Err(err) = insert(&mut connection, &events) {
+        error!("Could not store {} queries: {}", events.len(), err);
+      }
+    }
+  }
+
+  /// Statistics of the queries received during the last `since`.
+  pub async fn summary(&self, since: Duration) -> Result<HistorySummary> {
+    let path = self.path.clone();
+    tokio::task::spawn_blocking(move || {
+      let connection = Connection::open(&path)?;
+      summary(&connection, since)
+    })
+    .await?
+  }
+}
+
+impl QueryObserver for QueryDb {
+  fn on_query(&self, event: &QueryEvent) {
+    let Some(event) = self.privacy.apply(event) else {
+      return;
+    };
+    if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+      warn!(
+        "Query database queue is full, dropping event for {}",
+        event.qname
+      );
+    }
+  }
+}
+
+fn insert(connection: &mut Connection, events: &[QueryEvent]) -> rusqlite::Result<()> {
+  let transaction = connection.transaction()?;
+  {
+    let mut statement = transaction.prepare_cached(
+      "INSERT INTO queries (timestamp, client, client_port, protocol, qname, qtype, rcode, latency_ms, action, upstream, client_group)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    )?;
+    for event in events {
+      statement.execute(params![
+        event.timestamp.timestamp_millis(),
+        event.client.ip().to_string(),
+        event.client.port(),
+        event.protocol.to_string(),
+        event.qname,
+        event.qtype.to_string(),
+        format!("{:?}", event.rcode),
+        event.latency.as_micros() as f64 / 1000.0,
+        event.action.as_str(),
+        event.upstream,
+        event.group,
+      ])?;
+    }
+  }
+  transaction.commit()
+}
+
+fn purge(connection: &Connection, retention: Duration) -> rusqlite::Result<usize> {
+  let oldest = Utc::now().timestamp_millis() - retention.as_millis() as i64;
+  connection.execute("DELETE FROM queries WHERE timestamp < ?1", [oldest])
+}
+
+fn summary(connection: &Connection, since: Duration) -> Result<HistorySummary> {
+  let oldest = Utc::now().timestamp_millis() - since.as_millis() as i64;
+  let (queries, blocked, forwarded, failed) = connection.query_row(
+    "SELECT COUNT(*),
+       COALESCE(SUM(action = 'blocked'), 0),
+       COALESCE(SUM(action = 'forwarded'), 0),
+       COALESCE(SUM(rcode = 'ServFail'), 0)
+     FROM queries WHERE timestamp >= ?1",
+    [oldest],
+    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+  )?;
+  let top = |sql: &str| -> Result<Vec<Counter<String>>> {
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map(params![oldest, TOP_SIZE], |row| {
+      Ok(Counter {
+        name: row.get(0)?,
+        count: row.get(1)?,
+      })
+    })?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+  };
+
+  Ok(HistorySummary {
+    since_secs: since.as_secs(),
+    queries,
+    blocked,
+    forwarded,
+    failed,
+    blocked_domains: top(
+      "SELECT qname, COUNT(*) AS count FROM queries WHERE timestamp >= ?1 AND action = 'blocked'
+       GROUP BY qname ORDER BY count DESC, qname LIMIT ?2",
+    )?,
+    clients: top(
+      "SELECT client, COUNT(*) AS count FROM queries WHERE timestamp >= ?1
+       GROUP BY client ORDER BY count DESC, client LIMIT ?2",
+    )?,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::events::QueryAction;
+  use hickory_server::{
+    proto::{op::ResponseCode, rr::RecordType},
+    server::Protocol,
+  };
+
+  fn event(client: &str, qname: &str, action: QueryAction, age: Duration) -> QueryEvent {
+    QueryEvent {
+      timestamp: Utc::now() - age,
+      client: format!("{client}:5353").parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: qname.to_string(),
+      qtype: RecordType::A,
+      rcode: ResponseCode::NoError,
+      latency: Duration::from_millis(1),
+      action,
+      upstream: None,
+      cached: false,
+      blocked_by: None,
+      group: None,
+      query_message: None,
+      response_message: None,
+    }
+  }
+
+  #[test]
+  fn store_and_summarize() {
+    let mut connection = Connection::open_in_memory().unwrap();
+    connection.execute_batch(SCHEMA).unwrap();
+    let day = Duration::from_secs(86400);
+    insert(
+      &mut connection,
+      &[
+        event(
+          "10.0.0.1",
+          "ads.example.",
+          QueryAction::Blocked,
+          Duration::ZERO,
+        ),
+        event(
+          "10.0.0.1",
+          "example.",
+          QueryAction::Forwarded,
+          Duration::ZERO,
+        ),
+        event(
+          "10.0.0.2",
+          "ads.example.",
+          QueryAction::Blocked,
+          Duration::ZERO,
+        ),
+        event("10.0.0.2", "old.example.", QueryAction::Blocked, 2 * day),
+      ],
+    )
+    .unwrap();
+
+    let recent = summary(&connection, day).unwrap();
+    assert_eq!(
+      (recent.queries, recent.blocked, recent.forwarded),
+      (3, 2, 1)
+    );
+    assert_eq!(
+      recent.blocked_domains,
+      [Counter {
+        name: "ads.example.".to_string(),
+        count: 2
+      }]
+    );
+    assert_eq!(recent.clients[0].name, "10.0.0.1");
+
+    assert_eq!(purge(&connection, day).unwrap(), 1);
+    assert_eq!(summary(&connection, 7 * day).unwrap().queries, 3);
+  }
+}
@@ -0,0 +1,127 @@
+use crate::events::{LogPrivacy, QueryEvent, QueryObserver};
+use std::path::Path;
+use tokio::{
+  fs::{File, OpenOptions},
+  io::{AsyncWriteExt, BufWriter},
+  sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+};
+use tracing::{error, warn};
+
+const QUEUE_SIZE: usize = 4096;
+
+/// Writes one JSON line per query in a file. Events are queued and written by a background task
+/// so the query path never waits for the disk. Events are anonymized or skipped according to the
+/// privacy options.
+pub struct QueryLog {
+  sender: Sender<QueryEvent>,
+  privacy: LogPrivacy,
+}
+
+impl QueryLog {
+  pub async fn open(path: &Path, privacy: LogPrivacy) -> std::io::Result<Self> {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .await?;
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    tokio::spawn(Self::write_events(BufWriter::new(file), receiver));
+    Ok(Self { sender, privacy })
+  }
+
+  async fn write_events(mut writer: BufWriter<File>, mut receiver: Receiver<QueryEvent>) {
+    while let Some(event) = receiver.recv().await {
+      Self::write_event(&mut writer, &event).await;
+      // Write everything already queued before flushing, so bursts end up in a single write.
+      while let Ok(event) = receiver.try_recv() {
+        Self::write_event(&mut writer, &event).await;
+      }
+      if let Err(err) = writer.flush().await {
+        error!("Could not flush the query log: {}", err);
+      }
+    }
+  }
+
+  async fn write_event(writer: &mut BufWriter<File>, event: &QueryEvent) {
+    let mut line = match serde_json::to_vec(event) {
+      Ok(line) => line,
+      Err(err) => {
+        error!("Could not serialize query event: {}", err);
+        return;
+      }
+    };
+    line.push(b'\n');
+    if let Err(err) = writer.write_all(&line).await {
+      error!("Could not write the query log: {}", err);
+    }
+  }
+}
+
+impl QueryObserver for QueryLog {
+  fn on_query(&self, event: &QueryEvent) {
+    let Some(event) = self.privacy.apply(event) else {
+      return;
+    };
+    if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+      warn!(
+        "Query log queue is full, dropping event for {}",
+        event.qname
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::events::QueryAction;
+  use hickory_server::{
+    proto::{op::ResponseCode, rr::RecordType},
+    server::Protocol,
+  };
+  use std::time::Duration;
+
+  #[tokio::test]
+  async fn json_lines() {
+    let path = std::env::temp_dir().join(format!("queries-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let query_log = QueryLog::open(&path, LogPrivacy::default()).await.unwrap();
+    query_log.on_query(&QueryEvent {
+      timestamp: "2024-01-01T12:00:00.000549Z".parse().unwrap(),
+      client: "192.168.1.20:5353".parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: "www.example.com.".to_string(),
+      qtype: RecordType::AAAA,
+      rcode: ResponseCode::NXDomain,
+      latency: Duration::from_micros(1549),
+      action: QueryAction::Forwarded,
+      upstream: Some("cloudflare".to_string()),
+      cached: false,
+      blocked_by: None,
+      group: None,
+      query_message: None,
+      response_message: None,
+    });
+    let mut content = String::new();
+    for _ in 0..50 {
+      content = std::fs::read_to_string(&path).unwrap();
+      if content.ends_with('\n') {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    let line: serde_json::Value = serde_json::from_str(content.trim_end()).unwrap();
+    assert_eq!(line["timestamp"], "2024-01-01T12:00:00.000Z");
+    assert_eq!(line["client"], "192.168.1.20:5353");
+    assert_eq!(line["protocol"], "UDP");
+    assert_eq!(line["qname"], "www.example.com.");
+    assert_eq!(line["qtype"], "AAAA");
+    assert_eq!(line["rcode"], "NXDomain");
+    assert_eq!(line["latency_ms"], 1.549);
+    assert_eq!(line["action"], "forwarded");
+    assert_eq!(line["upstream"], "cloudflare");
+    assert!(line.get("blocked_by").is_none());
+  }
+}
@@ -0,0 +1,344 @@
+use crate::events::{QueryAction, QueryEvent, QueryObserver};
+use hickory_server::{proto::op::ResponseCode, server::Protocol};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters of the queries answered since the server started.
+pub struct Stats {
+  started: Instant,
+  queries: AtomicU64,
+  blocked: AtomicU64,
+  blocked_by: Mutex<BTreeMap<String, u64>>,
+  forwarded: AtomicU64,
+  cached: AtomicU64,
+  failed: AtomicU64,
+  /// Queries of each listener protocol, in the order of [`PROTOCOLS`].
+  protocols: [AtomicU64; 4],
+  upstream_failures: AtomicU64,
+  upstream_recoveries: AtomicU64,
+  /// Counters of each upstream by name, kept on reload.
+  upstreams: Mutex<BTreeMap<String, Arc<UpstreamStats>>>,
+}
+
+const PROTOCOLS: [&str; 4] = ["udp", "tcp", "tls", "https"];
+/// Upper bounds of the latency histogram buckets in milliseconds, the last bucket counts the
+/// slower answers.
+const LATENCY_BUCKETS_MS: [u64; 10] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500];
+
+/// Counters of the queries sent to an upstream.
+#[derive(Default)]
+pub struct UpstreamStats {
+  /// Answers in each bucket of [`LATENCY_BUCKETS_MS`], then the slower ones.
+  latency: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+  timeouts: AtomicU64,
+  /// Queries failing without an answer, e.g. a refused connection.
+  errors: AtomicU64,
+  /// Answers by response code other than NOERROR, e.g. `ServFail` or `NXDomain`.
+  rcodes: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Values of the counters of an upstream at a given time.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UpstreamSnapshot {
+  pub answers: u64,
+  /// Upper bound of the bucket of the median and of the 95th percentile latency, `None` before
+  /// the first answer or when it is slower than the last bucket.
+  pub p50_ms: Option<u64>,
+  pub p95_ms: Option<u64>,
+  pub latency: Vec<LatencyBucket>,
+  pub timeouts: u64,
+  pub errors: u64,
+  pub rcodes: BTreeMap<String, u64>,
+}
+
+/// Answers of a latency histogram bucket, slower than the previous bucket and at most `le_ms`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LatencyBucket {
+  pub le_ms: Option<u64>,
+  pub count: u64,
+}
+
+impl UpstreamStats {
+  /// An answer was received after `latency`, with `rcode`.
+  pub fn answered(&self, latency: Duration, rcode: ResponseCode) {
+    let millis = latency.as_millis();
+    let bucket = LATENCY_BUCKETS_MS
+      .iter()
+      .position(|bound| millis <= *bound as u128)
+      .unwrap_or(LATENCY_BUCKETS_MS.len());
+    self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+    if rcode != ResponseCode::NoError {
+      *self
+        .rcodes
+        .lock()
+        .unwrap()
+        .entry(format!("{rcode:?}"))
+        .or_default() += 1;
+    }
+  }
+
+  /// No answer was received in time.
+  pub fn timed_out(&self) {
+    self.timeouts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// The query failed without an answer.
+  pub fn failed(&self) {
+    self.errors.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> UpstreamSnapshot {
+    let latency: Vec<LatencyBucket> = self
+      .latency
+      .iter()
+      .enumerate()
+      .map(|(index, count)| LatencyBucket {
+        le_ms: LATENCY_BUCKETS_MS.get(index).copied(),
+        count: count.load(Ordering::Relaxed),
+      })
+      .collect();
+    let answers: u64 = latency.iter().map(|bucket| bucket.count).sum();
+    let percentile = |percent: u64| {
+      let rank = (answers * percent).div_ceil(100).max(1);
+      let mut seen = 0;
+      latency
+        .iter()
+        .find(|bucket| {
+          seen += bucket.count;
+          seen >= rank
+        })
+        .and_then(|bucket| bucket.le_ms)
+    };
+    UpstreamSnapshot {
+      answers,
+      p50_ms: percentile(50),
+      p95_ms: percentile(95),
+      timeouts: self.timeouts.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+      rcodes: self.rcodes.lock().unwrap().clone(),
+      latency,
+    }
+  }
+}
+
+/// Values of the counters at a given time.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+  pub uptime_secs: u64,
+  pub queries: u64,
+  pub blocked: u64,
+  /// Blocked queries of each list, e.g. `blacklist` or the `ads category`.
+  pub blocked_by: BTreeMap<String, u64>,
+  pub forwarded: u64,
+  /// Forwarded queries answered from the cache.
+  pub cached: u64,
+  pub failed: u64,
+  /// Queries received by each listener protocol.
+  pub protocols: BTreeMap<String, u64>,
+  pub upstream_failures: u64,
+  pub upstream_recoveries: u64,
+  /// Answers, latency and errors of each upstream.
+  pub upstreams: BTreeMap<String, UpstreamSnapshot>,
+}
+
+impl fmt::Display for StatsSnapshot {
+  /// One line summary, e.g. `uptime 2d 3h 5m, 1520 queries, 230 blocked (15.1%), ...`.
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let percent = |count: u64, total: u64| match total {
+      0 => 0.0,
+      total => count as f64 * 100.0 / total as f64,
+    };
+    let (days, hours, minutes) = (
+      self.uptime_secs / 86400,
+      self.uptime_secs / 3600 % 24,
+      self.uptime_secs / 60 % 60,
+    );
+    write!(f, "uptime ")?;
+    if days > 0 {
+      write!(f, "{days}d ")?;
+    }
+    write!(
+      f,
+      "{hours}h {minutes}m, {} queries, {} blocked ({:.1}%), {} forwarded with {:.1}% from cache, {} failed",
+      self.queries,
+      self.blocked,
+      percent(self.blocked, self.queries),
+      self.forwarded,
+      percent(self.cached, self.forwarded),
+      self.failed,
+    )?;
+    for (protocol, count) in self.protocols.iter() {
+      write!(f, ", {protocol} {count}")?;
+    }
+    Ok(())
+  }
+}
+
+impl Default for Stats {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Stats {
+  pub fn new() -> Self {
+    Self {
+      started: Instant::now(),
+      queries: AtomicU64::new(0),
+      blocked: AtomicU64::new(0),
+      blocked_by: Mutex::new(BTreeMap::new()),
+      forwarded: AtomicU64::new(0),
+      cached: AtomicU64::new(0),
+      failed: AtomicU64::new(0),
+      protocols: Default::default(),
+      upstream_failures: AtomicU64::new(0),
+      upstream_recoveries: AtomicU64::new(0),
+      upstreams: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  pub fn snapshot(&self) -> StatsSnapshot {
+    StatsSnapshot {
+      uptime_secs: self.started.elapsed().as_secs(),
+      queries: self.queries.load(Ordering::Relaxed),
+      blocked: self.blocked.load(Ordering::Relaxed),
+      blocked_by: self.blocked_by.lock().unwrap().clone(),
+      forwarded: self.forwarded.load(Ordering::Relaxed),
+      cached: self.cached.load(Ordering::Relaxed),
+      failed: self.failed.load(Ordering::Relaxed),
+      protocols: PROTOCOLS
+        .iter()
+        .zip(self.protocols.iter())
+        .map(|(protocol, count)| (protocol.to_string(), count.load(Ordering::Relaxed)))
+        .collect(),
+      upstream_failures: self.upstream_failures.load(Ordering::Relaxed),
+      upstream_recoveries: self.upstream_recoveries.load(Ordering::Relaxed),
+      upstreams: self
+        .upstreams
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, stats)| (name.clone(), stats.snapshot()))
+        .collect(),
+    }
+  }
+
+  /// Counters of the upstream `name`, the same ones for the upstreams of every catalog.
+  pub fn upstream(&self, name: &str) -> Arc<UpstreamStats> {
+    self
+      .upstreams
+      .lock()
+      .unwrap()
+      .entry(name.to_string())
+      .or_default()
+      .clone()
+  }
+
+  /// An upstream failed its health check and stopped receiving queries.
+  pub fn upstream_failed(&self) {
+    self.upstream_failures.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// An unhealthy upstream passed its health check again.
+  pub fn upstream_recovered(&self) {
+    self.upstream_recoveries.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+impl QueryObserver for Stats {
+  fn on_query(&self, event: &QueryEvent) {
+    self.queries.fetch_add(1, Ordering::Relaxed);
+    match event.action {
+      QueryAction::Blocked => self.blocked.fetch_add(1, Ordering::Relaxed),
+      QueryAction::Forwarded => self.forwarded.fetch_add(1, Ordering::Relaxed),
+    };
+    if event.cached {
+      self.cached.fetch_add(1, Ordering::Relaxed);
+    }
+    let protocol = match event.protocol {
+      Protocol::Udp => Some(0),
+      Protocol::Tcp => Some(1),
+      Protocol::Tls => Some(2),
+      Protocol::Https => Some(3),
+      _ => None,
+    };
+    if let Some(index) = protocol {
+      self.protocols[index].fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(list) = &event.blocked_by {
+      *self
+        .blocked_by
+        .lock()
+        .unwrap()
+        .entry(list.clone())
+        .or_default() += 1;
+    }
+    if event.rcode == ResponseCode::ServFail {
+      self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn summary() {
+    let mut snapshot = StatsSnapshot {
+      uptime_secs: 2 * 86400 + 3 * 3600 + 5 * 60 + 7,
+      queries: 200,
+      blocked: 50,
+      blocked_by: BTreeMap::new(),
+      forwarded: 150,
+      cached: 60,
+      failed: 1,
+      protocols: BTreeMap::from([("udp".to_string(), 190), ("tls".to_string(), 10)]),
+      upstream_failures: 0,
+      upstream_recoveries: 0,
+      upstreams: BTreeMap::new(),
+    };
+    assert_eq!(
+      snapshot.to_string(),
+      "uptime 2d 3h 5m, 200 queries, 50 blocked (25.0%), 150 forwarded with 40.0% from cache, 1 failed, tls 10, udp 190"
+    );
+    snapshot = Stats::new().snapshot();
+    assert!(snapshot
+      .to_string()
+      .starts_with("uptime 0h 0m, 0 queries, 0 blocked (0.0%)"));
+  }
+
+  #[test]
+  fn upstream_latency() {
+    let stats = Stats::new();
+    let upstream = stats.upstream("cloudflare");
+    for millis in [3, 4, 8, 20, 40, 40, 60, 90, 200, 3000] {
+      upstream.answered(Duration::from_millis(millis), ResponseCode::NoError);
+    }
+    upstream.answered(Duration::from_millis(5), ResponseCode::ServFail);
+    upstream.timed_out();
+    upstream.failed();
+    stats
+      .upstream("cloudflare")
+      .answered(Duration::ZERO, ResponseCode::NXDomain);
+    let snapshot = &stats.snapshot().upstreams["cloudflare"];
+    assert_eq!(snapshot.answers, 12);
+    assert_eq!(snapshot.p50_ms, Some(25));
+    assert_eq!(snapshot.p95_ms, None);
+    assert_eq!(snapshot.latency[0].count, 1);
+    assert_eq!(snapshot.latency[1].count, 3);
+    assert_eq!(snapshot.latency[10].le_ms, None);
+    assert_eq!(snapshot.latency[10].count, 1);
+    assert_eq!(snapshot.timeouts, 1);
+    assert_eq!(snapshot.errors, 1);
+    assert_eq!(
+      snapshot.rcodes,
+      BTreeMap::from([("NXDomain".to_string(), 1), ("ServFail".to_string(), 1)])
+    );
+    assert_eq!(Stats::new().upstream("quad9").snapshot().p50_ms, None);
+  }
+}
@@ -0,0 +1,162 @@
+use crate::events::Counter;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Approximate counts of the most frequent keys over a rolling window, in a bounded memory.
+///
+/// The window is split in buckets, each one counting at most `capacity` keys with the
+/// Space-Saving algorithm: a new key replaces the least counted one and inherits its count, so the
+/// frequent keys are never forgotten and their counts are overestimated by at most the smallest
+/// count of the bucket.
+pub struct RollingTop<K> {
+  start: Instant,
+  bucket: Duration,
+  buckets: usize,
+  capacity: usize,
+  /// Index of each bucket since `start` with its counters, the most recent last.
+  counters: VecDeque<(u64, HashMap<K, u64>)>,
+}
+
+impl<K: Hash + Eq + Clone + Ord> RollingTop<K> {
+  /// Window of `buckets` times `bucket`, each bucket counting at most `capacity` keys.
+  pub fn new(bucket: Duration, buckets: usize, capacity: usize) -> Self {
+    Self {
+      start: Instant::now(),
+      bucket,
+      buckets,
+      capacity,
+      counters: VecDeque::with_capacity(buckets),
+    }
+  }
+
+  pub fn increment(&mut self, key: K) {
+    self.increment_at(key, Instant::now());
+  }
+
+  fn increment_at(&mut self, key: K, now: Instant) {
+    let index = self.index(now);
+    self.expire(index);
+    if self.counters.back().map(|(last, _)| *last) != Some(index) {
+      self.counters.push_back((index, HashMap::new()));
+    }
+    let Some((_, counters)) = self.counters.back_mut() else {
+      return;
+    };
+    if let Some(count) = counters.get_mut(&key) {
+      *count += 1;
+      return;
+    }
+    let mut count = 0;
+    if counters.len() >= self.capacity {
+      let least = counters
+        .iter()
+        .min_by_key(|(_, count)| **count)
+        .map(|(key, count)| (key.clone(), *count));
+      if let Some((least, least_count)) = least {
+        counters.remove(&least);
+        count = least_count;
+      }
+    }
+    counters.insert(key, count + 1);
+  }
+
+  /// The `limit` keys counted the most over the window.
+  pub fn top(&self, limit: usize) -> Vec<Counter<K>> {
+    self.top_at(limit, Instant::now())
+  }
+
+  fn top_at(&self, limit: usize, now: Instant) -> Vec<Counter<K>> {
+    let oldest = self.oldest(self.index(now));
+    let mut totals: HashMap<&K, u64> = HashMap::new();
+    for (_, counters) in self.counters.iter().filter(|(index, _)| *index >= oldest) {
+      for (key, count) in counters {
+        *totals.entry(key).or_insert(0) += count;
+      }
+    }
+    let mut top: Vec<Counter<K>> = totals
+      .into_iter()
+      .map(|(name, count)| Counter {
+        name: name.clone(),
+        count,
+      })
+      .collect();
+    top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    top.truncate(limit);
+    top
+  }
+
+  fn index(&self, now: Instant) -> u64 {
+    now.saturating_duration_since(self.start).as_secs() / self.bucket.as_secs().max(1)
+  }
+
+  /// First bucket still in the window when `index` is the current one.
+  fn oldest(&self, index: u64) -> u64 {
+    (index + 1).saturating_sub(self.buckets as u64)
+  }
+
+  fn expire(&mut self, index: u64) {
+    let oldest = self.oldest(index);
+    while self
+      .counters
+      .front()
+      .is_some_and(|(first, _)| *first < oldest)
+    {
+      self.counters.pop_front();
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rolling_window() {
+    let mut top = RollingTop::new(Duration::from_secs(60), 2, 3);
+    let start = top.start;
+    let at = |secs| start + Duration::from_secs(secs);
+    for _ in 0..5 {
+      top.increment_at("popular", at(0));
+    }
+    top.increment_at("rare", at(10));
+    top.increment_at("other", at(70));
+    assert_eq!(top.top_at(1, at(70))[0].name, "popular");
+    assert_eq!(top.top_at(3, at(70)).len(), 3);
+
+    // The first bucket leaves the window.
+    let names: Vec<&str> = top.top_at(5, at(130)).iter().map(|c| c.name).collect();
+    assert_eq!(names, ["other"]);
+    top.increment_at("new", at(200));
+    assert_eq!(top.counters.len(), 1);
+  }
+
+  #[test]
+  fn space_saving() {
+    let mut top = RollingTop::new(Duration::from_secs(60), 1, 2);
+    let now = top.start;
+    for _ in 0..10 {
+      top.increment_at("heavy", now);
+    }
+    for rare in ["a", "b", "c", "d"] {
+      top.increment_at(rare, now);
+    }
+    let counters = top.top_at(5, now);
+    assert_eq!(counters.len(), 2);
+    assert_eq!(
+      counters[0],
+      Counter {
+        name: "heavy",
+        count: 10
+      }
+    );
+    // The last key inherits the counts of the ones it replaced.
+    assert_eq!(
+      counters[1],
+      Counter {
+        name: "d",
+        count: 4
+      }
+    );
+  }
+}
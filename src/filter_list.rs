@@ -0,0 +1,339 @@
+use crate::domain_set::DomainSet;
+use crate::ip::{IpRange, IpRangeVec};
+use anyhow::{bail, Context, Result};
+use hickory_server::proto::rr::LowerName;
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// Unsupported or malformed rules logged while loading a list, the others are only counted.
+const LOGGED_SKIPPED_RULES: usize = 10;
+
+/// Priority of a rule, the first level with a matching rule decides: important exceptions,
+/// important blocks, exceptions and blocks.
+const LEVELS: usize = 4;
+
+/// Rules of a level applying to every client.
+#[derive(Clone, Default)]
+struct Rules {
+  exact: DomainSet,
+  zones: DomainSet,
+}
+
+/// Rule restricted to some clients with the `$client` modifier.
+#[derive(Clone)]
+struct ClientRule {
+  level: usize,
+  name: LowerName,
+  zone: bool,
+  clients: IpRangeVec,
+  excluded: IpRangeVec,
+  text: String,
+}
+
+impl ClientRule {
+  fn matches(&self, name: &LowerName, client: IpAddr) -> bool {
+    let name_matches = match self.zone {
+      true => self.name.zone_of(name),
+      false => &self.name == name,
+    };
+    name_matches
+      && (self.clients.is_empty() || self.clients.contains(client))
+      && !self.excluded.contains(client)
+  }
+}
+
+/// Rule of a list deciding whether a name is blocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+  pub blocked: bool,
+  /// The matching rule, as written in AdGuard syntax.
+  pub rule: String,
+}
+
+/// Blocklist in the AdGuard Home filtering syntax: `||example.com^` blocks a zone, `|example.com^`,
+/// `example.com` and `0.0.0.0 example.com` block a name, and `@@` exception rules unblock them.
+/// Rules with `$important` win over exceptions, and `$client=192.168.1.0/24|~192.168.1.1` restricts
+/// a rule to some clients. Other modifiers, wildcards and regular expressions are skipped.
+#[derive(Clone, Default)]
+pub struct FilterList {
+  levels: [Rules; LEVELS],
+  client_rules: Vec<ClientRule>,
+}
+
+/// Rule of a line, or the names of a hosts line.
+enum Line {
+  Rule(ParsedRule),
+  Hosts(Vec<LowerName>),
+}
+
+struct ParsedRule {
+  level: usize,
+  name: LowerName,
+  zone: bool,
+  clients: Option<(IpRangeVec, IpRangeVec)>,
+}
+
+impl FilterList {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Read a filter list file, comments are ignored, unsupported and malformed rules are skipped
+  /// and counted.
+  pub fn load(path: &Path) -> Result<Self> {
+    let file =
+      std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    Self::read(std::io::BufReader::new(file), &path.display().to_string())
+      .with_context(|| format!("could not read {}", path.display()))
+  }
+
+  /// Read the rules of `reader` line by line, `source` names it in the logs.
+  pub fn read(reader: impl BufRead, source: &str) -> Result<Self> {
+    let mut names: [(Vec<LowerName>, Vec<LowerName>); LEVELS] = Default::default();
+    let mut list = Self::new();
+    let mut skipped = 0;
+    for (number, line) in reader.lines().enumerate() {
+      let line = line?;
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('!') || line.starts_with('#') {
+        continue;
+      }
+      match parse_line(line) {
+        Ok(Line::Hosts(hosts)) => names[LEVELS - 1].0.extend(hosts),
+        Ok(Line::Rule(ParsedRule {
+          level,
+          name,
+          zone,
+          clients: Some((clients, excluded)),
+        })) => list.client_rules.push(ClientRule {
+          level,
+          name,
+          zone,
+          clients,
+          excluded,
+          text: line.to_string(),
+        }),
+        Ok(Line::Rule(rule)) => match rule.zone {
+          true => names[rule.level].1.push(rule.name),
+          false => names[rule.level].0.push(rule.name),
+        },
+        Err(err) => {
+          skipped += 1;
+          if skipped <= LOGGED_SKIPPED_RULES {
+            warn!(
+              "Rule `{}` at line {} of {} skipped: {:#}",
+              line,
+              number + 1,
+              source,
+              err
+            );
+          }
+        }
+      }
+    }
+    for (rules, (exact, zones)) in list.levels.iter_mut().zip(names) {
+      rules.exact.extend(exact);
+      rules.zones.extend(zones);
+    }
+    info!(
+      "Loaded {} rules from {} ({} rules skipped)",
+      list.len(),
+      source,
+      skipped
+    );
+    Ok(list)
+  }
+
+  /// Number of rules.
+  pub fn len(&self) -> usize {
+    self
+      .levels
+      .iter()
+      .map(|rules| rules.exact.len() + rules.zones.len())
+      .sum::<usize>()
+      + self.client_rules.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Add the rules of `other`.
+  pub fn merge(&mut self, other: FilterList) {
+    for (rules, other) in self.levels.iter_mut().zip(other.levels) {
+      rules.exact.extend(other.exact);
+      rules.zones.extend(other.zones);
+    }
+    self.client_rules.extend(other.client_rules);
+  }
+
+  /// Rule deciding whether `name` is blocked for `client`, `None` when no rule matches.
+  pub fn verdict(&self, name: &LowerName, client: IpAddr) -> Option<Verdict> {
+    for (level, rules) in self.levels.iter().enumerate() {
+      if rules.exact.contains(name) {
+        return Some(verdict(level, name, false));
+      }
+      if let Some(zone) = rules.zones.matching_zone(name) {
+        return Some(verdict(level, &zone, true));
+      }
+      let rule = self
+        .client_rules
+        .iter()
+        .find(|rule| rule.level == level && rule.matches(name, client));
+      if let Some(rule) = rule {
+        return Some(Verdict {
+          blocked: level % 2 == 1,
+          rule: rule.text.clone(),
+        });
+      }
+    }
+    None
+  }
+}
+
+/// Verdict of a rule of all the clients, written back in AdGuard syntax.
+fn verdict(level: usize, name: &LowerName, zone: bool) -> Verdict {
+  let blocked = level % 2 == 1;
+  let important = level < 2;
+  Verdict {
+    blocked,
+    rule: format!(
+      "{}{}{}^{}",
+      if blocked { "" } else { "@@" },
+      if zone { "||" } else { "|" },
+      name.to_string().trim_end_matches('.'),
+      if important { "$important" } else { "" }
+    ),
+  }
+}
+
+fn parse_line(line: &str) -> Result<Line> {
+  let mut words = line.split_whitespace();
+  if let Some(ip) = words.next().filter(|word| IpAddr::from_str(word).is_ok()) {
+    let hosts = words
+      .take_while(|word| !word.starts_with('#'))
+      .map(|host| parse_name(host).with_context(|| format!("invalid host of {ip}")))
+      .collect::<Result<_>>()?;
+    return Ok(Line::Hosts(hosts));
+  }
+  let (allow, rule) = match line.strip_prefix("@@") {
+    Some(rule) => (true, rule),
+    None => (false, line),
+  };
+  let (pattern, modifiers) = rule.split_once('$').unwrap_or((rule, ""));
+  let mut important = false;
+  let mut clients = None;
+  for modifier in modifiers.split(',').filter(|modifier| !modifier.is_empty()) {
+    match modifier.split_once('=') {
+      None if modifier == "important" => important = true,
+      Some(("client", values)) => clients = Some(parse_clients(values)?),
+      _ => bail!("unsupported modifier `{modifier}`"),
+    }
+  }
+  let (zone, domain) = match pattern.strip_prefix("||") {
+    Some(domain) => (true, domain),
+    None => (false, pattern.strip_prefix('|').unwrap_or(pattern)),
+  };
+  let domain = domain
+    .strip_suffix("^|")
+    .or_else(|| domain.strip_suffix('^'))
+    .unwrap_or(domain);
+  Ok(Line::Rule(ParsedRule {
+    level: match (important, allow) {
+      (true, true) => 0,
+      (true, false) => 1,
+      (false, true) => 2,
+      (false, false) => 3,
+    },
+    name: parse_name(domain)?,
+    zone,
+    clients,
+  }))
+}
+
+fn parse_name(domain: &str) -> Result<LowerName> {
+  let domain = domain.trim_end_matches('.');
+  if domain.is_empty()
+    || !domain
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+  {
+    bail!("unsupported pattern `{domain}`");
+  }
+  LowerName::from_str(&format!("{domain}.")).with_context(|| format!("invalid domain `{domain}`"))
+}
+
+/// Clients of a `$client` modifier: IPs and networks separated by `|`, prefixed with `~` to
+/// exclude them. Client names are not supported.
+fn parse_clients(values: &str) -> Result<(IpRangeVec, IpRangeVec)> {
+  let (mut clients, mut excluded) = (vec![], vec![]);
+  for value in values.split('|') {
+    let (list, value) = match value.strip_prefix('~') {
+      Some(value) => (&mut excluded, value),
+      None => (&mut clients, value),
+    };
+    let value = value.trim_matches(|c| c == '\'' || c == '"');
+    list.push(IpRange::try_from(value).with_context(|| format!("unsupported client `{value}`"))?);
+  }
+  Ok((IpRangeVec::new(clients), IpRangeVec::new(excluded)))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn name(name: &str) -> LowerName {
+    LowerName::from_str(name).unwrap()
+  }
+
+  #[test]
+  fn adguard_rules() {
+    let content = "! AdGuard list
+||ads.example^
+@@||good.ads.example^
+|tracker.example^
+0.0.0.0 hosts.example other.example
+||social.example^$important
+@@||social.example^
+@@||www.social.example^$important
+||kids.example^$client=192.168.1.0/24|~192.168.1.1
+/ads[0-9]+/
+||cosmetic.example^$dnstype=AAAA
+example.com##.banner
+";
+    let list = FilterList::read(content.as_bytes(), "test").unwrap();
+    assert_eq!(list.len(), 9);
+    let client: IpAddr = "10.0.0.1".parse().unwrap();
+    let blocked = |domain: &str, client: IpAddr| {
+      list
+        .verdict(&name(domain), client)
+        .map(|verdict| verdict.blocked)
+    };
+    assert_eq!(blocked("www.ads.example.", client), Some(true));
+    assert_eq!(blocked("good.ads.example.", client), Some(false));
+    assert_eq!(blocked("tracker.example.", client), Some(true));
+    assert_eq!(blocked("www.tracker.example.", client), None);
+    assert_eq!(blocked("other.example.", client), Some(true));
+    assert_eq!(blocked("social.example.", client), Some(true));
+    assert_eq!(blocked("www.social.example.", client), Some(false));
+    assert_eq!(blocked("kids.example.", client), None);
+    assert_eq!(
+      blocked("kids.example.", "192.168.1.7".parse().unwrap()),
+      Some(true)
+    );
+    assert_eq!(
+      blocked("kids.example.", "192.168.1.1".parse().unwrap()),
+      None
+    );
+    assert_eq!(
+      list.verdict(&name("good.ads.example."), client),
+      Some(Verdict {
+        blocked: false,
+        rule: "@@||good.ads.example^".to_string()
+      })
+    );
+  }
+}
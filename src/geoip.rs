@@ -0,0 +1,456 @@
+use crate::snapshot::Snapshot;
+use anyhow::{anyhow, bail, Context, Result};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+/// Start of the metadata section of a MaxMind database, it is followed by a map.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// The metadata section is in the last 128 KiB of the file.
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+/// Zeros between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+
+/// Country database in the MaxMind DB format, like GeoLite2 Country or City, reloaded when the
+/// file changes.
+#[derive(Debug)]
+pub struct GeoIp {
+  path: PathBuf,
+  state: Snapshot<(Option<SystemTime>, Mmdb)>,
+}
+
+impl GeoIp {
+  pub fn load(path: &Path) -> Result<Self> {
+    let (modified, database) = read(path)?;
+    info!(
+      "Loaded the GeoIP database {} ({} nodes)",
+      path.display(),
+      database.node_count
+    );
+    Ok(Self {
+      path: path.to_path_buf(),
+      state: Snapshot::new((modified, database)),
+    })
+  }
+
+  /// ISO 3166-1 code of the country of `ip`, or of the country where its network is registered.
+  /// `None` for the IPs missing from the database, like the private networks.
+  pub fn country(&self, ip: IpAddr) -> Option<String> {
+    let state = self.state.load();
+    let database = &state.1;
+    let offset = database.lookup(ip)?;
+    ["country", "registered_country"]
+      .iter()
+      .find_map(|key| database.find_string(offset, &[key, "iso_code"]))
+      .map(str::to_uppercase)
+  }
+
+  /// Read the database again when its modification time changed.
+  pub fn refresh(&self) -> Result<bool> {
+    let modified = modified(&self.path);
+    if modified.is_some() && modified == self.state.load().0 {
+      return Ok(false);
+    }
+    self.state.store(read(&self.path)?);
+    Ok(true)
+  }
+
+  /// Refresh the database every `interval` (`0` disables it), as long as it is used.
+  pub fn watch(self: &Arc<Self>, interval: Duration) {
+    if !interval.is_zero() {
+      tokio::spawn(Self::refresh_every(Arc::downgrade(self), interval));
+    }
+  }
+
+  async fn refresh_every(geoip: Weak<Self>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+      ticker.tick().await;
+      let Some(geoip) = geoip.upgrade() else {
+        break;
+      };
+      match geoip.refresh() {
+        Ok(true) => info!("Reloaded the GeoIP database {}", geoip.path.display()),
+        Ok(false) => {}
+        Err(err) => error!("GeoIP database not reloaded: {err:#}"),
+      }
+    }
+  }
+}
+
+/// Countries allowed or denied to query the server, the clients missing from the database are
+/// neither allowed nor denied by their country.
+#[derive(Debug, Clone)]
+pub struct CountryAcl {
+  pub geoip: Arc<GeoIp>,
+  pub allow: Vec<String>,
+  pub deny: Vec<String>,
+}
+
+impl CountryAcl {
+  pub fn allows(&self, ip: IpAddr) -> bool {
+    match self.geoip.country(ip) {
+      Some(country) => {
+        (self.allow.is_empty() || self.allow.contains(&country)) && !self.deny.contains(&country)
+      }
+      None => true,
+    }
+  }
+}
+
+/// Parse ISO 3166-1 alpha-2 country codes, in any case.
+pub fn countries(codes: &[String]) -> Result<Vec<String>> {
+  codes
+    .iter()
+    .map(|code| {
+      let code = code.trim();
+      if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        bail!("invalid country code `{code}`, expected two letters like FR");
+      }
+      Ok(code.to_uppercase())
+    })
+    .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path)
+    .and_then(|metadata| metadata.modified())
+    .ok()
+}
+
+fn read(path: &Path) -> Result<(Option<SystemTime>, Mmdb)> {
+  let modified = modified(path);
+  let data = std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+  let database =
+    Mmdb::new(data).with_context(|| format!("invalid GeoIP database {}", path.display()))?;
+  Ok((modified, database))
+}
+
+/// Value of the data section, maps and arrays are followed by their entries.
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+  Pointer(usize),
+  String(&'a str),
+  Uint(u128),
+  Map(usize),
+  Array(usize),
+  Other,
+}
+
+/// Reader of the MaxMind DB format (https://maxmind.github.io/MaxMind-DB/): a binary search tree
+/// on the bits of the IPs whose leaves point to the records of the data section.
+#[derive(Debug)]
+struct Mmdb {
+  data: Vec<u8>,
+  node_count: usize,
+  record_size: usize,
+  ip_version: usize,
+  /// Node of `::0.0.0.0/96` where the IPv4 are looked up in an IPv6 tree.
+  ipv4_start: usize,
+}
+
+impl Mmdb {
+  fn new(data: Vec<u8>) -> Result<Self> {
+    let search = data.len().saturating_sub(METADATA_MAX_SIZE);
+    let metadata = data[search..]
+      .windows(METADATA_MARKER.len())
+      .rposition(|window| window == METADATA_MARKER)
+      .map(|position| search + position + METADATA_MARKER.len())
+      .ok_or_else(|| anyhow!("no MaxMind DB metadata"))?;
+    let uint = |key: &str| -> Result<usize> {
+      let decoder = Decoder {
+        data: &data,
+        base: metadata,
+      };
+      match decoder.find(metadata, &[key]) {
+        Some(Value::Uint(value)) => Ok(value as usize),
+        _ => bail!("no {key} in the metadata"),
+      }
+    };
+    let mut database = Mmdb {
+      node_count: uint("node_count")?,
+      record_size: uint("record_size")?,
+      ip_version: uint("ip_version")?,
+      ipv4_start: 0,
+      data: vec![],
+    };
+    if ![24, 28, 32].contains(&database.record_size) {
+      bail!("unsupported record size {}", database.record_size);
+    }
+    if database.tree_size() + DATA_SEPARATOR > metadata {
+      bail!("the search tree is larger than the file");
+    }
+    database.data = data;
+    if database.ip_version == 6 {
+      for _ in 0..96 {
+        if database.ipv4_start >= database.node_count {
+          break;
+        }
+        database.ipv4_start = database.record(database.ipv4_start, 0);
+      }
+    }
+    Ok(database)
+  }
+
+  fn tree_size(&self) -> usize {
+    self.node_count * self.record_size / 4
+  }
+
+  /// Left (`bit` 0) or right record of a node.
+  fn record(&self, node: usize, bit: usize) -> usize {
+    let bytes = &self.data[node * self.record_size / 4..];
+    let int = |bytes: &[u8]| {
+      bytes
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as usize)
+    };
+    match (self.record_size, bit) {
+      (24, _) => int(&bytes[bit * 3..bit * 3 + 3]),
+      (28, 0) => ((bytes[3] as usize & 0xf0) << 20) | int(&bytes[0..3]),
+      (28, _) => ((bytes[3] as usize & 0x0f) << 24) | int(&bytes[4..7]),
+      (_, _) => int(&bytes[bit * 4..bit * 4 + 4]),
+    }
+  }
+
+  /// Offset of the record of `ip` in the file.
+  fn lookup(&self, ip: IpAddr) -> Option<usize> {
+    let (bits, length, mut node) = match ip.to_canonical() {
+      IpAddr::V4(ip) => (ip.to_bits() as u128, 32, self.ipv4_start),
+      IpAddr::V6(_) if self.ip_version == 4 => return None,
+      IpAddr::V6(ip) => (ip.to_bits(), 128, 0),
+    };
+    for index in (0..length).rev() {
+      if node >= self.node_count {
+        break;
+      }
+      node = self.record(node, (bits >> index) as usize & 1);
+    }
+    // Records past the node count point to the data section, after the separator.
+    let offset = (node > self.node_count).then(|| self.tree_size() + node - self.node_count)?;
+    (offset < self.data.len()).then_some(offset)
+  }
+
+  /// String at `path` in the map of the record at `offset`.
+  fn find_string(&self, offset: usize, path: &[&str]) -> Option<&str> {
+    let decoder = Decoder {
+      data: &self.data,
+      base: self.tree_size() + DATA_SEPARATOR,
+    };
+    match decoder.find(offset, path)? {
+      Value::String(value) => Some(value),
+      _ => None,
+    }
+  }
+}
+
+/// Decoder of a data section, the pointers are relative to `base`.
+struct Decoder<'a> {
+  data: &'a [u8],
+  base: usize,
+}
+
+impl<'a> Decoder<'a> {
+  fn bytes(&self, offset: usize, size: usize) -> Option<&'a [u8]> {
+    self.data.get(offset..offset.checked_add(size)?)
+  }
+
+  fn uint(&self, offset: usize, size: usize) -> Option<u128> {
+    let bytes = self.bytes(offset, size)?;
+    (size <= 16).then(|| {
+      bytes
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as u128)
+    })
+  }
+
+  /// Value at `offset` and the offset following it, or its first entry for maps and arrays.
+  fn decode(&self, offset: usize) -> Option<(Value<'a>, usize)> {
+    let control = *self.data.get(offset)?;
+    let mut offset = offset + 1;
+    let mut kind = control >> 5;
+    if kind == 1 {
+      let size = (control as usize >> 3) & 3;
+      let value = control as usize & 7;
+      let pointer = self.uint(offset, size + 1)? as usize;
+      let pointer = match size {
+        0 => value << 8 | pointer,
+        1 => (value << 16 | pointer) + 2048,
+        2 => (value << 24 | pointer) + 526_336,
+        _ => pointer,
+      };
+      return Some((Value::Pointer(pointer), offset + size + 1));
+    }
+    if kind == 0 {
+      kind = 7 + *self.data.get(offset)?;
+      offset += 1;
+    }
+    let mut size = control as usize & 0x1f;
+    if size >= 29 {
+      let bytes = size - 28;
+      size = [29, 285, 65_821][bytes - 1] + self.uint(offset, bytes)? as usize;
+      offset += bytes;
+    }
+    let value = match kind {
+      2 => Value::String(std::str::from_utf8(self.bytes(offset, size)?).ok()?),
+      5 | 6 | 9 | 10 => Value::Uint(self.uint(offset, size)?),
+      7 => return Some((Value::Map(size), offset)),
+      11 => return Some((Value::Array(size), offset)),
+      // A boolean is its size.
+      14 => return Some((Value::Other, offset)),
+      3 | 4 | 8 | 15 => Value::Other,
+      _ => return None,
+    };
+    Some((value, offset + size))
+  }
+
+  /// Value at `offset`, following a pointer.
+  fn resolve(&self, offset: usize) -> Option<(Value<'a>, usize)> {
+    match self.decode(offset)? {
+      (Value::Pointer(pointer), next) => {
+        let (value, entries) = self.decode(self.base + pointer)?;
+        // The entries of a map or an array are where the pointer leads.
+        match value {
+          Value::Map(_) | Value::Array(_) => Some((value, entries)),
+          value => Some((value, next)),
+        }
+      }
+      decoded => Some(decoded),
+    }
+  }
+
+  /// Offset following the value at `offset`.
+  fn skip(&self, offset: usize) -> Option<usize> {
+    let (value, mut next) = self.decode(offset)?;
+    let entries = match value {
+      Value::Map(size) => size * 2,
+      Value::Array(size) => size,
+      _ => 0,
+    };
+    for _ in 0..entries {
+      next = self.skip(next)?;
+    }
+    Some(next)
+  }
+
+  /// Value at `path` in the map at `offset`.
+  fn find(&self, offset: usize, path: &[&str]) -> Option<Value<'a>> {
+    let (value, mut next) = self.resolve(offset)?;
+    let Some((key, path)) = path.split_first() else {
+      return Some(value);
+    };
+    let Value::Map(size) = value else {
+      return None;
+    };
+    for _ in 0..size {
+      let (Value::String(entry), value) = self.resolve(next)? else {
+        return None;
+      };
+      if entry == *key {
+        return self.find(value, path);
+      }
+      next = self.skip(value)?;
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn string(value: &str) -> Vec<u8> {
+    [vec![2 << 5 | value.len() as u8], value.as_bytes().to_vec()].concat()
+  }
+
+  fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut map = vec![7 << 5 | entries.len() as u8];
+    for (key, value) in entries {
+      map.extend(string(key));
+      map.extend(value);
+    }
+    map
+  }
+
+  /// IPv4 database where `0.0.0.0/1` is in France and `128.0.0.0/2` registered in the US.
+  fn database() -> Vec<u8> {
+    let france = map(&[("country", map(&[("iso_code", string("fr"))]))]);
+    let continent = map(&[
+      ("code", string("NA")),
+      ("geoname_id", vec![6 << 5 | 3, 0x5b, 0x8f, 0x4f]),
+    ]);
+    // The registered country is a pointer to the map following the record.
+    let mut us = map(&[
+      ("continent", continent),
+      ("registered_country", vec![1 << 5, 0]),
+    ]);
+    let pointer = us.len() - 1;
+    us[pointer] = (france.len() + us.len()) as u8;
+    let us_country = map(&[("iso_code", string("US"))]);
+    let node_count = 2;
+    let record = |offset: usize| (node_count + DATA_SEPARATOR + offset).to_be_bytes()[5..].to_vec();
+    let tree = [
+      record(0),
+      vec![0, 0, 1],
+      record(france.len()),
+      vec![0, 0, node_count as u8],
+    ]
+    .concat();
+    let metadata = map(&[
+      ("node_count", vec![6 << 5 | 1, node_count as u8]),
+      ("record_size", vec![5 << 5 | 1, 24]),
+      ("ip_version", vec![5 << 5 | 1, 4]),
+      ("database_type", string("Test")),
+    ]);
+    [
+      tree,
+      vec![0; DATA_SEPARATOR],
+      france,
+      us,
+      us_country,
+      METADATA_MARKER.to_vec(),
+      metadata,
+    ]
+    .concat()
+  }
+
+  #[test]
+  fn countries_of_ips() {
+    let database = Mmdb::new(database()).unwrap();
+    let country = |ip: &str| {
+      let offset = database.lookup(ip.parse().unwrap())?;
+      ["country", "registered_country"]
+        .iter()
+        .find_map(|key| database.find_string(offset, &[key, "iso_code"]))
+    };
+    assert_eq!(country("10.0.0.1"), Some("fr"));
+    assert_eq!(country("::ffff:10.0.0.1"), Some("fr"));
+    assert_eq!(country("150.0.0.1"), Some("US"));
+    assert_eq!(country("200.0.0.1"), None);
+    assert_eq!(country("2001:db8::1"), None);
+    assert!(Mmdb::new(b"not a database".to_vec()).is_err());
+  }
+
+  #[test]
+  fn country_acl() {
+    let path = std::env::temp_dir().join(format!("geoip-{}.mmdb", std::process::id()));
+    std::fs::write(&path, database()).unwrap();
+    let geoip = Arc::new(GeoIp::load(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+      geoip.country("10.0.0.1".parse().unwrap()).as_deref(),
+      Some("FR")
+    );
+    let acl = CountryAcl {
+      geoip,
+      allow: countries(&["fr".to_string()]).unwrap(),
+      deny: vec![],
+    };
+    assert!(acl.allows("10.0.0.1".parse().unwrap()));
+    assert!(!acl.allows("150.0.0.1".parse().unwrap()));
+    assert!(acl.allows("200.0.0.1".parse().unwrap()));
+    assert!(countries(&["FRA".to_string()]).is_err());
+  }
+}
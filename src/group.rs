@@ -0,0 +1,331 @@
+use crate::client::ClientType;
+use crate::domain_set::DomainSet;
+use crate::geoip;
+use crate::ip::IpRange;
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_server::proto::rr::LowerName;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Policy applied to the clients of a group. Unset options are inherited from the server.
+#[derive(Debug, Clone, Default)]
+pub struct GroupPolicy {
+  pub blacklist: Option<Arc<DomainSet>>,
+  pub zone_blacklist: Option<Arc<DomainSet>>,
+  pub default_ip: Option<Ipv4Addr>,
+  pub upstream: Option<ClientType>,
+  /// Countries of the clients in the group besides its networks, see [`crate::geoip`].
+  pub countries: Vec<String>,
+}
+
+/// Client group as written on the command line:
+/// `name=cidr[,cidr...][;blacklist=path][;zone-blacklist=path][;default-ip=ip][;dns-server=client][;countries=code[,code...]]`.
+/// The networks can be empty when the group has countries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientGroup {
+  pub name: String,
+  pub networks: Vec<String>,
+  pub blacklist: Option<PathBuf>,
+  pub zone_blacklist: Option<PathBuf>,
+  pub default_ip: Option<Ipv4Addr>,
+  pub dns_server: Option<ClientType>,
+  pub countries: Vec<String>,
+}
+
+impl ClientGroup {
+  pub fn ip_ranges(&self) -> Result<Vec<IpRange>> {
+    self
+      .networks
+      .iter()
+      .map(|network| {
+        IpRange::try_from(network.as_str()).with_context(|| format!("invalid IP range {network}"))
+      })
+      .collect()
+  }
+}
+
+impl FromStr for ClientGroup {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut options = s.split(';').map(str::trim);
+    let (name, networks) = options
+      .next()
+      .and_then(|group| group.split_once('='))
+      .ok_or_else(|| anyhow!("expected name=cidr[,cidr...], got `{s}`"))?;
+    let name = name.trim();
+    if name.is_empty() {
+      bail!("the client group name is empty");
+    }
+
+    let mut group = ClientGroup {
+      name: name.to_string(),
+      networks: networks
+        .split(',')
+        .map(|network| network.trim().to_string())
+        .filter(|network| !network.is_empty())
+        .collect(),
+      blacklist: None,
+      zone_blacklist: None,
+      default_ip: None,
+      dns_server: None,
+      countries: vec![],
+    };
+    group.ip_ranges()?;
+
+    for option in options.filter(|option| !option.is_empty()) {
+      let (key, value) = option
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected key=value, got `{option}`"))?;
+      let value = value.trim();
+      match key.trim() {
+        "blacklist" => group.blacklist = Some(value.into()),
+        "zone-blacklist" => group.zone_blacklist = Some(value.into()),
+        "default-ip" => {
+          group.default_ip = Some(
+            value
+              .parse()
+              .with_context(|| format!("invalid IP {value}"))?,
+          )
+        }
+        "dns-server" => {
+          group.dns_server =
+            Some(ClientType::from_str(value).map_err(|_| anyhow!("invalid dns server {value}"))?)
+        }
+        "countries" => {
+          let countries: Vec<String> = value.split(',').map(str::to_string).collect();
+          group.countries = geoip::countries(&countries)?;
+        }
+        key => bail!("unknown client group option `{key}`"),
+      }
+    }
+    if group.networks.is_empty() && group.countries.is_empty() {
+      bail!("the client group {name} has no network");
+    }
+
+    Ok(group)
+  }
+}
+
+impl fmt::Display for ClientGroup {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}={}", self.name, self.networks.join(","))?;
+    if let Some(path) = &self.blacklist {
+      write!(f, ";blacklist={}", path.display())?;
+    }
+    if let Some(path) = &self.zone_blacklist {
+      write!(f, ";zone-blacklist={}", path.display())?;
+    }
+    if let Some(ip) = &self.default_ip {
+      write!(f, ";default-ip={}", ip)?;
+    }
+    if let Some(client) = &self.dns_server {
+      write!(f, ";dns-server={}", client)?;
+    }
+    if !self.countries.is_empty() {
+      write!(f, ";countries={}", self.countries.join(","))?;
+    }
+    Ok(())
+  }
+}
+
+impl Serialize for ClientGroup {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for ClientGroup {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ClientGroup::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Upstream of the clients of some networks as written on the command line:
+/// `cidr[,cidr...]->resolver`, e.g. `192.168.2.0/24->9.9.9.9:tls:dns.quad9.net`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamRoute {
+  pub networks: Vec<String>,
+  pub upstream: ClientType,
+}
+
+impl UpstreamRoute {
+  pub fn ip_ranges(&self) -> Result<Vec<IpRange>> {
+    self
+      .networks
+      .iter()
+      .map(|network| {
+        IpRange::try_from(network.as_str()).with_context(|| format!("invalid IP range {network}"))
+      })
+      .collect()
+  }
+}
+
+impl FromStr for UpstreamRoute {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (networks, upstream) = s
+      .split_once("->")
+      .ok_or_else(|| anyhow!("expected cidr[,cidr...]->resolver, got `{s}`"))?;
+    let route = UpstreamRoute {
+      networks: networks
+        .split(',')
+        .map(|network| network.trim().to_string())
+        .filter(|network| !network.is_empty())
+        .collect(),
+      upstream: ClientType::from_str(upstream.trim())
+        .map_err(|_| anyhow!("invalid dns server {}", upstream.trim()))?,
+    };
+    if route.networks.is_empty() {
+      bail!("the upstream route to {} has no network", route.upstream);
+    }
+    route.ip_ranges()?;
+    Ok(route)
+  }
+}
+
+impl fmt::Display for UpstreamRoute {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}->{}", self.networks.join(","), self.upstream)
+  }
+}
+
+impl Serialize for UpstreamRoute {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for UpstreamRoute {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    UpstreamRoute::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Upstream of the names of some zones as written on the command line:
+/// `zone[,zone...]->resolver`, e.g. `lan,168.192.in-addr.arpa->192.168.1.1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardZone {
+  pub zones: Vec<LowerName>,
+  pub upstream: ClientType,
+}
+
+impl FromStr for ForwardZone {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (zones, upstream) = s
+      .split_once("->")
+      .ok_or_else(|| anyhow!("expected zone[,zone...]->resolver, got `{s}`"))?;
+    let zones = zones
+      .split(',')
+      .map(|zone| zone.trim().trim_end_matches('.'))
+      .filter(|zone| !zone.is_empty())
+      .map(|zone| {
+        LowerName::from_str(&format!("{zone}.")).with_context(|| format!("invalid zone {zone}"))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let upstream = ClientType::from_str(upstream.trim())
+      .map_err(|_| anyhow!("invalid dns server {}", upstream.trim()))?;
+    if zones.is_empty() {
+      bail!("the forward zone to {} has no zone", upstream);
+    }
+    Ok(ForwardZone { zones, upstream })
+  }
+}
+
+impl fmt::Display for ForwardZone {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let zones: Vec<String> = self.zones.iter().map(LowerName::to_string).collect();
+    write!(f, "{}->{}", zones.join(","), self.upstream)
+  }
+}
+
+impl Serialize for ForwardZone {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for ForwardZone {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ForwardZone::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_networks_only() {
+    let group = ClientGroup::from_str("kids=192.168.1.0/28, 192.168.1.64/30").unwrap();
+    assert_eq!(group.name, "kids");
+    assert_eq!(group.networks, ["192.168.1.0/28", "192.168.1.64/30"]);
+    assert_eq!(group.blacklist, None);
+    assert_eq!(group.dns_server, None);
+  }
+
+  #[test]
+  fn parse_options() {
+    let value =
+      "kids=192.168.1.0/28;blacklist=/etc/kids.txt;default-ip=0.0.0.0;dns-server=google:h2";
+    let group = ClientGroup::from_str(value).unwrap();
+    assert_eq!(group.blacklist, Some(PathBuf::from("/etc/kids.txt")));
+    assert_eq!(group.default_ip, Some(Ipv4Addr::UNSPECIFIED));
+    assert_eq!(group.dns_server, Some(ClientType::GoogleH2));
+    assert_eq!(group.to_string(), value);
+  }
+
+  #[test]
+  fn parse_countries() {
+    let group = ClientGroup::from_str("abroad=;countries=us, de").unwrap();
+    assert!(group.networks.is_empty());
+    assert_eq!(group.countries, ["US", "DE"]);
+    assert_eq!(group.to_string(), "abroad=;countries=US,DE");
+    assert!(ClientGroup::from_str("abroad=;countries=USA").is_err());
+  }
+
+  #[test]
+  fn parse_routes() {
+    let route = UpstreamRoute::from_str("192.168.2.0/24, fd00:2::/64 -> quad9:tls").unwrap();
+    assert_eq!(route.networks, ["192.168.2.0/24", "fd00:2::/64"]);
+    assert_eq!(route.upstream, ClientType::Quad9TLS);
+    assert_eq!(route.to_string(), "192.168.2.0/24,fd00:2::/64->quad9:tls");
+    let route = UpstreamRoute::from_str("192.168.2.0/24->9.9.9.9:tls:dns.quad9.net").unwrap();
+    assert_eq!(UpstreamRoute::from_str(&route.to_string()).unwrap(), route);
+    assert!(UpstreamRoute::from_str("192.168.2.0/24").is_err());
+    assert!(UpstreamRoute::from_str("->quad9").is_err());
+    assert!(UpstreamRoute::from_str("192.168.2.0/24->nope").is_err());
+  }
+
+  #[test]
+  fn parse_forward_zones() {
+    let zone = ForwardZone::from_str("lan, 168.192.in-addr.arpa. -> 192.168.1.1:5353").unwrap();
+    assert_eq!(zone.zones.len(), 2);
+    assert_eq!(
+      zone.to_string(),
+      "lan.,168.192.in-addr.arpa.->192.168.1.1:5353"
+    );
+    assert_eq!(ForwardZone::from_str(&zone.to_string()).unwrap(), zone);
+    assert!(ForwardZone::from_str("->192.168.1.1").is_err());
+    assert!(ForwardZone::from_str("lan").is_err());
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert!(ClientGroup::from_str("192.168.1.0/28").is_err());
+    assert!(ClientGroup::from_str("kids=").is_err());
+    assert!(ClientGroup::from_str("kids=192.168.1.300/28").is_err());
+    assert!(ClientGroup::from_str("kids=192.168.1.0/28;unknown=1").is_err());
+    assert!(ClientGroup::from_str("kids=192.168.1.0/28;dns-server=nope").is_err());
+  }
+}
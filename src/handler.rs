@@ -0,0 +1,739 @@
+use crate::acl::{Access, DenyAction};
+use crate::authority::{LocalDnsUpdater, SyntheticZone, DEFAULT_BLOCK_TTL};
+use crate::control::Control;
+use crate::events::{self, with_query_context, QueryEvent, QueryObserver};
+use crate::geoip::GeoIp;
+use crate::ip::IpRangeVec;
+use crate::padding::{Padding, PaddingResponseHandler};
+use crate::rrl::{RateLimiter, RrlResponseHandler};
+use crate::snapshot::Snapshot;
+use chrono::Utc;
+use futures_util::FutureExt;
+use hickory_server::{
+  authority::{Catalog, MessageRequest, MessageResponse, MessageResponseBuilder},
+  proto::{
+    op::{Header, Message, OpCode, ResponseCode},
+    rr::{
+      rdata::{
+        opt::{EdnsCode, EdnsOption},
+        HINFO,
+      },
+      RData, Record, RecordType,
+    },
+    serialize::binary::{BinDecodable, BinEncodable, BinEncoder},
+  },
+  server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+use std::{
+  cell::OnceCell,
+  io,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Instant,
+};
+use tokio_graceful::WeakShutdownGuard;
+use tracing::{debug, error};
+
+/// TTL of the minimal answers to the ANY queries.
+const ANY_TTL: u32 = 3600;
+/// Largest UDP response of the clients without EDNS (RFC 1035).
+const UDP_MAX_PAYLOAD: u16 = 512;
+/// Default EDNS UDP payload size of the server, the one of the DNS flag day 2020.
+pub const DEFAULT_EDNS_UDP_SIZE: u16 = 1232;
+/// EDNS option code of the Extended DNS Errors.
+pub(crate) const EDE_OPTION: u16 = 15;
+const EDE_BLOCKED: u16 = 15;
+/// Extended DNS Error of the queries the upstreams could not be asked.
+const EDE_NETWORK_ERROR: u16 = 23;
+
+/// Catalogs of a client: the one applying the blacklists, and the one forwarding every query
+/// while blocking is disabled.
+pub struct ClientCatalog {
+  pub catalog: Catalog,
+  pub unfiltered: Catalog,
+}
+
+/// Catalog of each client group, the first group containing the client address or its country
+/// answers its queries, the default catalog answers the others.
+pub struct Catalogs {
+  default: ClientCatalog,
+  groups: Vec<(String, IpRangeVec, Vec<String>, ClientCatalog)>,
+  geoip: Option<Arc<GeoIp>>,
+  updater: Option<LocalDnsUpdater>,
+  refuse_any: bool,
+  block_ttl: u32,
+  synthetic_zone: Arc<SyntheticZone>,
+  control: Arc<Control>,
+}
+
+impl Catalogs {
+  pub fn new(default: ClientCatalog, control: Arc<Control>) -> Self {
+    Self {
+      default,
+      groups: vec![],
+      geoip: None,
+      updater: None,
+      refuse_any: false,
+      block_ttl: DEFAULT_BLOCK_TTL,
+      synthetic_zone: Arc::default(),
+      control,
+    }
+  }
+
+  /// Apply the dynamic updates to the local names instead of giving them to the catalogs.
+  pub fn set_updater(&mut self, updater: LocalDnsUpdater) {
+    self.updater = Some(updater);
+  }
+
+  /// Answer the ANY queries with a minimal HINFO record (RFC 8482) instead of forwarding them.
+  pub fn set_refuse_any(&mut self, refuse_any: bool) {
+    self.refuse_any = refuse_any;
+  }
+
+  /// TTL of the SOA added to the responses of the blocked queries without answer.
+  pub fn set_block_ttl(&mut self, ttl: u32) {
+    self.block_ttl = ttl;
+  }
+
+  /// Names of the SOA added to the responses of the blocked queries without answer.
+  pub fn set_synthetic_zone(&mut self, zone: Arc<SyntheticZone>) {
+    self.synthetic_zone = zone;
+  }
+
+  /// Database of the countries of the clients, required by the groups of countries.
+  pub fn set_geoip(&mut self, geoip: Arc<GeoIp>) {
+    self.geoip = Some(geoip);
+  }
+
+  /// Add a group of the clients in `ips` or located in one of the `countries`.
+  pub fn add_group(
+    &mut self,
+    name: String,
+    ips: IpRangeVec,
+    countries: Vec<String>,
+    catalog: ClientCatalog,
+  ) {
+    self.groups.push((name, ips, countries, catalog));
+  }
+
+  /// Catalog used for a client and the name of its group.
+  pub fn select(&self, src: SocketAddr) -> (&Catalog, Option<&str>) {
+    // The country is only looked up once, when a group has countries.
+    let country = OnceCell::new();
+    let in_countries = |countries: &[String]| {
+      let country = country.get_or_init(|| {
+        self
+          .geoip
+          .as_ref()
+          .and_then(|geoip| geoip.country(src.ip()))
+      });
+      country
+        .as_ref()
+        .is_some_and(|country| countries.contains(country))
+    };
+    let (catalog, group) = self
+      .groups
+      .iter()
+      .find(|(_, ips, countries, _)| {
+        ips.contains_sock_addr(src) || (!countries.is_empty() && in_countries(countries))
+      })
+      .map_or((&self.default, None), |(name, _, _, catalog)| {
+        (catalog, Some(name.as_str()))
+      });
+    if self.control.is_blocking() {
+      (&catalog.catalog, group)
+    } else {
+      (&catalog.unfiltered, group)
+    }
+  }
+}
+
+/// Shared access to the catalogs currently used by the handler, so they can be replaced while the
+/// server is running. Requests being answered keep the catalogs they started with.
+#[derive(Clone)]
+pub struct CatalogHandle(Arc<Snapshot<Catalogs>>);
+
+impl CatalogHandle {
+  pub fn new(catalogs: Catalogs) -> Self {
+    Self(Arc::new(Snapshot::new(catalogs)))
+  }
+
+  pub fn get(&self) -> Arc<Catalogs> {
+    self.0.load()
+  }
+
+  pub fn replace(&self, catalogs: Catalogs) {
+    self.0.store(catalogs);
+  }
+}
+
+/// Entry point of every request received by the listeners. It forwards the request to the
+/// catalog and reports the answered query to the registered observers.
+#[derive(Clone)]
+pub struct DnsHandler {
+  catalog: CatalogHandle,
+  access: Access,
+  rate_limiter: Option<Arc<RateLimiter>>,
+  observers: Vec<Arc<dyn QueryObserver>>,
+  capture_messages: bool,
+  edns_udp_size: u16,
+  padding: Padding,
+  shutdown: Option<WeakShutdownGuard>,
+  in_flight: Arc<AtomicUsize>,
+}
+
+impl DnsHandler {
+  pub fn new(catalogs: Catalogs) -> Self {
+    Self {
+      catalog: CatalogHandle::new(catalogs),
+      access: Access::default(),
+      rate_limiter: None,
+      observers: vec![],
+      capture_messages: false,
+      edns_udp_size: DEFAULT_EDNS_UDP_SIZE,
+      padding: Padding::default(),
+      shutdown: None,
+      in_flight: Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  /// Hold a shutdown guard while each request is answered, and refuse new requests once the
+  /// shutdown is triggered, so the server can wait for the requests in flight before exiting.
+  pub fn set_shutdown(&mut self, shutdown: WeakShutdownGuard) {
+    self.shutdown = Some(shutdown);
+  }
+
+  /// Networks allowed to query each listener, and what is done with the requests of the other
+  /// clients.
+  pub fn set_access(&mut self, access: Access) {
+    self.access = access;
+  }
+
+  /// Largest UDP response sent to the EDNS clients, and the payload size announced to them.
+  /// Larger responses are truncated so the clients retry over TCP.
+  pub fn set_edns_udp_size(&mut self, size: u16) {
+    self.edns_udp_size = size.max(UDP_MAX_PAYLOAD);
+  }
+
+  /// Padding of the responses sent over DoT and DoH to the clients asking for it.
+  pub fn set_padding(&mut self, padding: Padding) {
+    self.padding = padding;
+  }
+
+  /// Limit the rate of the answers sent over UDP.
+  pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+    self.rate_limiter = Some(Arc::new(rate_limiter));
+  }
+
+  /// Number of requests currently being answered.
+  pub fn in_flight(&self) -> Arc<AtomicUsize> {
+    self.in_flight.clone()
+  }
+
+  pub fn catalog(&self) -> CatalogHandle {
+    self.catalog.clone()
+  }
+
+  pub fn add_observer(&mut self, observer: Arc<dyn QueryObserver>) {
+    self.capture_messages |= observer.wants_messages();
+    self.observers.push(observer);
+  }
+
+  /// Stop the observers once no query is answered anymore.
+  pub async fn stop_observers(&self) {
+    for observer in &self.observers {
+      observer.stop().await;
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for DnsHandler {
+  async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    if !self.access.allows(request.protocol(), request.src().ip()) {
+      debug!("Denied {} request of {}", request.protocol(), request.src());
+      if self.access.deny_action == DenyAction::Refused {
+        return refuse(request, response_handle).await;
+      }
+      let mut header = *request.header();
+      header.set_response_code(ResponseCode::Refused);
+      return header.into();
+    }
+    let _guard = match &self.shutdown {
+      Some(shutdown) if shutdown.cancelled().now_or_never().is_some() => {
+        return refuse(request, response_handle).await;
+      }
+      Some(shutdown) => Some(shutdown.clone().upgrade()),
+      None => None,
+    };
+    self.in_flight.fetch_add(1, Ordering::Relaxed);
+    let response_info = if matches!(request.protocol(), Protocol::Udp) {
+      let response_handle =
+        UdpSizeResponseHandler::new(response_handle, self.edns_udp_size, request);
+      match &self.rate_limiter {
+        Some(limiter) => {
+          let response_handle = RrlResponseHandler::new(response_handle, limiter.clone(), request);
+          self.answer(request, response_handle).await
+        }
+        None => self.answer(request, response_handle).await,
+      }
+    } else if let Some(block_size) = self.padding.block_size(request) {
+      let response_handle = PaddingResponseHandler::new(response_handle, block_size, request);
+      self.answer(request, response_handle).await
+    } else {
+      self.answer(request, response_handle).await
+    };
+    self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    response_info
+  }
+}
+
+impl DnsHandler {
+  async fn answer<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    let catalogs = self.catalog.get();
+    if let Some(updater) = catalogs.updater.as_ref() {
+      if request.op_code() == OpCode::Update {
+        return updater.handle_request(request, response_handle).await;
+      }
+    }
+    let (catalog, group) = catalogs.select(request.src());
+    let client_subnet = request
+      .edns()
+      .and_then(|edns| match edns.option(EdnsCode::Subnet) {
+        Some(EdnsOption::Subnet(subnet)) => Some(*subnet),
+        _ => None,
+      });
+    if self.observers.is_empty() {
+      let handle = handle_query(&catalogs, catalog, request, response_handle);
+      return with_query_context(client_subnet, handle).await.0;
+    }
+
+    let start = Instant::now();
+    let timestamp = Utc::now();
+    let query_message = if self.capture_messages {
+      request.to_bytes().ok()
+    } else {
+      None
+    };
+    let capture = query_message
+      .as_ref()
+      .and_then(|bytes| MessageRequest::from_bytes(bytes).ok())
+      .map(|message| CaptureResponseHandler::new(response_handle.clone(), message));
+
+    let ((response_info, response_message), context) = with_query_context(client_subnet, async {
+      match capture {
+        Some(capture) => {
+          let captured = capture.captured.clone();
+          let response_info = handle_query(&catalogs, catalog, request, capture).await;
+          let response_message = captured.lock().unwrap().take();
+          (response_info, response_message)
+        }
+        None => (
+          handle_query(&catalogs, catalog, request, response_handle).await,
+          None,
+        ),
+      }
+    })
+    .await;
+
+    let query = request.query();
+    let event = QueryEvent {
+      timestamp,
+      client: request.src(),
+      protocol: request.protocol(),
+      qname: query.name().to_string(),
+      qtype: query.query_type(),
+      rcode: response_info.response_code(),
+      latency: start.elapsed(),
+      action: context.action,
+      cached: context.upstream.is_some() && !context.upstream_sent,
+      upstream: context.upstream,
+      blocked_by: context.blocked_by.as_deref().map(str::to_string),
+      group: group.map(str::to_string),
+      query_message: query_message.map(Into::into),
+      response_message: response_message.map(Into::into),
+    };
+    self
+      .observers
+      .iter()
+      .for_each(|observer| observer.on_query(&event));
+
+    response_info
+  }
+}
+
+/// Answer a query with `catalog`, or with the minimal answer of RFC 8482 when it is an ANY query
+/// and the catalogs refuse them.
+async fn handle_query<R: ResponseHandler>(
+  catalogs: &Catalogs,
+  catalog: &Catalog,
+  request: &Request,
+  mut response_handle: R,
+) -> ResponseInfo {
+  let query = request.query();
+  if !catalogs.refuse_any || !matches!(query.query_type(), RecordType::ANY | RecordType::RRSIG) {
+    let response_handle = ExtendedErrorResponseHandler {
+      inner: response_handle,
+      block_ttl: catalogs.block_ttl,
+      synthetic_zone: catalogs.synthetic_zone.clone(),
+    };
+    return catalog.handle_request(request, response_handle).await;
+  }
+  let mut header = Header::response_from_request(request.header());
+  header.set_recursion_available(true);
+  let hinfo = HINFO::new("RFC8482".to_string(), String::new());
+  let answer = Record::from_rdata(query.name().into(), ANY_TTL, RData::HINFO(hinfo));
+  let response = MessageResponseBuilder::from_message_request(request);
+  let result = response_handle
+    .send_response(response.build(header, [&answer], [], [], []))
+    .await;
+  result.unwrap_or_else(|err| {
+    error!("failed to send response: {}", err);
+    header.set_response_code(ResponseCode::ServFail);
+    header.into()
+  })
+}
+
+async fn refuse<R: ResponseHandler>(request: &Request, mut response_handle: R) -> ResponseInfo {
+  let response = MessageResponseBuilder::from_message_request(request);
+  let result = response_handle
+    .send_response(response.error_msg(request.header(), ResponseCode::Refused))
+    .await;
+  result.unwrap_or_else(|err| {
+    error!("failed to send response: {}", err);
+    let mut header = *request.header();
+    header.set_response_code(ResponseCode::ServFail);
+    header.into()
+  })
+}
+
+/// Adds an Extended DNS Error (RFC 8914) to the responses of the blocked queries, when the client
+/// supports EDNS, and a SOA to the ones without answer so the clients cache them for `block_ttl`
+/// (RFC 2308). Queries the upstreams could not be asked are answered SERVFAIL.
+#[derive(Clone)]
+struct ExtendedErrorResponseHandler<R: ResponseHandler> {
+  inner: R,
+  block_ttl: u32,
+  synthetic_zone: Arc<SyntheticZone>,
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for ExtendedErrorResponseHandler<R> {
+  async fn send_response<'a>(
+    &mut self,
+    mut response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    if let (Some(list), Some(edns)) = (events::blocked_by(), response.get_edns()) {
+      let mut edns = edns.clone();
+      edns.options_mut().insert(extended_error(
+        EDE_BLOCKED,
+        &format!("Blocked by the {list}"),
+      ));
+      response.set_edns(edns);
+    }
+    if let Some(reason) = events::upstream_failure() {
+      response
+        .header_mut()
+        .set_response_code(ResponseCode::ServFail);
+      if let Some(edns) = response.get_edns() {
+        let mut edns = edns.clone();
+        edns
+          .options_mut()
+          .insert(extended_error(EDE_NETWORK_ERROR, &reason));
+        response.set_edns(edns);
+      }
+    }
+    if events::blocked_by().is_none() {
+      return self.inner.send_response(response).await;
+    }
+    // The records of a response cannot be changed, it is built again from its wire format.
+    let mut buffer = Vec::new();
+    let mut encoder = BinEncoder::new(&mut buffer);
+    response
+      .destructive_emit(&mut encoder)
+      .map_err(io::Error::other)?;
+    let message = MessageRequest::from_bytes(&buffer).map_err(io::Error::other)?;
+    let soa = message.answers().is_empty().then(|| {
+      // As if the blocked name was the apex of its own zone.
+      let name = message.query().name().into();
+      self.synthetic_zone.soa(name, self.block_ttl)
+    });
+    let mut response = MessageResponseBuilder::from_message_request(&message);
+    if let Some(edns) = message.edns() {
+      response.edns(edns.clone());
+    }
+    let response = response.build(
+      *message.header(),
+      message.answers(),
+      message.name_servers().iter().chain(&soa),
+      &[],
+      message.additionals().iter().chain(message.sig0()),
+    );
+    self.inner.send_response(response).await
+  }
+}
+
+/// EDNS option of an Extended DNS Error.
+fn extended_error(info_code: u16, text: &str) -> EdnsOption {
+  let mut data = info_code.to_be_bytes().to_vec();
+  data.extend_from_slice(text.as_bytes());
+  EdnsOption::Unknown(EDE_OPTION, data)
+}
+
+/// Limits the size of the UDP responses to the EDNS payload size of the client and of the server,
+/// or to 512 bytes for the clients without EDNS. Larger responses are truncated with TC set.
+#[derive(Clone)]
+struct UdpSizeResponseHandler<R: ResponseHandler> {
+  inner: R,
+  edns_udp_size: u16,
+  /// Request of a client without EDNS, to build its truncated response again.
+  request: Option<Arc<MessageRequest>>,
+}
+
+impl<R: ResponseHandler> UdpSizeResponseHandler<R> {
+  fn new(inner: R, edns_udp_size: u16, request: &Request) -> Self {
+    let request = match request.edns() {
+      Some(_) => None,
+      None => request
+        .to_bytes()
+        .ok()
+        .and_then(|bytes| MessageRequest::from_bytes(&bytes).ok())
+        .map(Arc::new),
+    };
+    Self {
+      inner,
+      edns_udp_size,
+      request,
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for UdpSizeResponseHandler<R> {
+  async fn send_response<'a>(
+    &mut self,
+    mut response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    // The listener truncates the responses to their EDNS payload size, the one of the client.
+    if let Some(edns) = response.get_edns() {
+      let mut edns = edns.clone();
+      edns.set_max_payload(edns.max_payload().min(self.edns_udp_size));
+      response.set_edns(edns);
+      return self.inner.send_response(response).await;
+    }
+    let Some(request) = &self.request else {
+      return self.inner.send_response(response).await;
+    };
+    // Without EDNS, the listener would send up to 4096 bytes.
+    let mut buffer = Vec::with_capacity(UDP_MAX_PAYLOAD as usize);
+    let mut encoder = BinEncoder::new(&mut buffer);
+    encoder.set_max_size(UDP_MAX_PAYLOAD);
+    response
+      .destructive_emit(&mut encoder)
+      .map_err(io::Error::other)?;
+    let message = Message::from_vec(&buffer).map_err(io::Error::other)?;
+    let response = MessageResponseBuilder::from_message_request(request).build(
+      *message.header(),
+      message.answers(),
+      message.name_servers(),
+      &[],
+      // Hickory decodes the TSIG records as signatures.
+      message.additionals().iter().chain(message.signature()),
+    );
+    self.inner.send_response(response).await
+  }
+}
+
+/// Keeps a copy of the wire format of the response before sending it through the real handler.
+#[derive(Clone)]
+struct CaptureResponseHandler<R: ResponseHandler> {
+  inner: R,
+  request: Arc<MessageRequest>,
+  captured: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl<R: ResponseHandler> CaptureResponseHandler<R> {
+  fn new(inner: R, request: MessageRequest) -> Self {
+    Self {
+      inner,
+      request: Arc::new(request),
+      captured: Arc::new(Mutex::new(None)),
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for CaptureResponseHandler<R> {
+  async fn send_response<'a>(
+    &mut self,
+    response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    let mut buffer = Vec::with_capacity(512);
+    response
+      .destructive_emit(&mut BinEncoder::new(&mut buffer))
+      .map_err(io::Error::other)?;
+    // The response iterators are consumed by the encoding, decode it back to hand it over.
+    let message = Message::from_vec(&buffer).map_err(io::Error::other)?;
+    *self.captured.lock().unwrap() = Some(buffer);
+
+    let mut builder = MessageResponseBuilder::from_message_request(&self.request);
+    if let Some(edns) = message.extensions() {
+      builder.edns(edns.clone());
+    }
+    let response = builder.build(
+      *message.header(),
+      message.answers(),
+      message.name_servers(),
+      &[],
+      // Hickory decodes the TSIG records as signatures.
+      message.additionals().iter().chain(message.signature()),
+    );
+    self.inner.send_response(response).await
+  }
+}
+
+/// Keeps the wire format of the response, for the listeners sending it themselves.
+#[derive(Clone, Default)]
+pub(crate) struct ResponseBuffer(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl ResponseBuffer {
+  /// Response of the request, none when the client was denied.
+  pub(crate) fn take(&self) -> Option<Vec<u8>> {
+    self.0.lock().unwrap().take()
+  }
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for ResponseBuffer {
+  async fn send_response<'a>(
+    &mut self,
+    response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    let mut buffer = Vec::with_capacity(512);
+    let info = response
+      .destructive_emit(&mut BinEncoder::new(&mut buffer))
+      .map_err(io::Error::other)?;
+    *self.0.lock().unwrap() = Some(buffer);
+    Ok(info)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::ip::IpRange;
+
+  fn client_catalog() -> ClientCatalog {
+    ClientCatalog {
+      catalog: Catalog::new(),
+      unfiltered: Catalog::new(),
+    }
+  }
+
+  #[test]
+  fn select_group() {
+    let mut catalogs = Catalogs::new(client_catalog(), Arc::new(Control::new()));
+    let kids = IpRangeVec::new(vec![IpRange::try_from("192.168.1.0/28").unwrap()]);
+    let office = IpRangeVec::new(vec![IpRange::try_from("192.168.1.0/24").unwrap()]);
+    catalogs.add_group("kids".to_string(), kids, vec![], client_catalog());
+    catalogs.add_group("office".to_string(), office, vec![], client_catalog());
+
+    let group = |ip: &str| catalogs.select(SocketAddr::new(ip.parse().unwrap(), 53)).1;
+    assert_eq!(group("192.168.1.3"), Some("kids"));
+    assert_eq!(group("192.168.1.20"), Some("office"));
+    assert_eq!(group("10.0.0.1"), None);
+  }
+
+  #[tokio::test]
+  async fn udp_size() {
+    use hickory_server::proto::op::{Edns, Query};
+    use hickory_server::proto::rr::Name;
+    use std::str::FromStr;
+
+    let name = Name::from_str("many.example.com.").unwrap();
+    let answers: Vec<Record> = (0..100)
+      .map(|i| {
+        Record::from_rdata(
+          name.clone(),
+          60,
+          RData::A(std::net::Ipv4Addr::new(10, 0, 0, i).into()),
+        )
+      })
+      .collect();
+    let answer = |edns: Option<u16>| {
+      let answers = answers.clone();
+      let name = name.clone();
+      async move {
+        let mut query = Message::new();
+        query.add_query(Query::query(name, RecordType::A));
+        if let Some(size) = edns {
+          let mut edns = Edns::new();
+          edns.set_max_payload(size);
+          query.set_edns(edns);
+        }
+        let request = MessageRequest::from_bytes(&query.to_vec().unwrap()).unwrap();
+        let request = Request::new(request, "127.0.0.1:5353".parse().unwrap(), Protocol::Udp);
+        let buffer = ResponseBuffer::default();
+        let mut handler = UdpSizeResponseHandler::new(buffer.clone(), 1232, &request);
+        let mut builder = MessageResponseBuilder::from_message_request(&request);
+        if let Some(edns) = request.edns() {
+          builder.edns(edns.clone());
+        }
+        let header = Header::response_from_request(request.header());
+        let response = builder.build(header, answers.iter(), [], [], []);
+        handler.send_response(response).await.unwrap();
+        buffer.take().unwrap()
+      }
+    };
+
+    let response = answer(None).await;
+    assert!(response.len() <= 512);
+    assert!(Message::from_vec(&response).unwrap().truncated());
+    // The listener truncates the EDNS responses to the payload size they announce.
+    let payload_size = |response: Vec<u8>| {
+      let message = Message::from_vec(&response).unwrap();
+      message.extensions().as_ref().unwrap().max_payload()
+    };
+    assert_eq!(payload_size(answer(Some(4096)).await), 1232);
+    assert_eq!(payload_size(answer(Some(800)).await), 800);
+  }
+}
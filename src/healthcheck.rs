@@ -0,0 +1,64 @@
+use crate::query::{self, Transport};
+use anyhow::{bail, Context, Result};
+use hickory_server::proto::{
+  op::ResponseCode,
+  rr::{Name, RecordType},
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// Name looked up by the health checks, its NS records are answered by any working resolver.
+pub const DEFAULT_NAME: &str = ".";
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Address to reach a listener from the same host: the unspecified address (`0.0.0.0` or `::`) is
+/// replaced by the loopback.
+pub fn local_addr(addr: SocketAddr) -> SocketAddr {
+  match addr.ip() {
+    IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+    IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+    _ => addr,
+  }
+}
+
+/// Send a recursive NS query for `name` to the server over UDP and return its response code.
+pub async fn lookup(server: SocketAddr, name: &Name, timeout: Duration) -> Result<ResponseCode> {
+  let message = query::message(name, RecordType::NS);
+  let response = query::send(&message, server, Transport::Udp, None, timeout).await?;
+  Ok(response.response_code())
+}
+
+/// Look up `name` on the server, it is healthy when it answers with `NOERROR` or `NXDOMAIN`.
+pub async fn check(server: SocketAddr, name: &Name, timeout: Duration) -> Result<()> {
+  let code = lookup(server, name, timeout)
+    .await
+    .with_context(|| format!("{server} is unhealthy"))?;
+  match code {
+    ResponseCode::NoError | ResponseCode::NXDomain => Ok(()),
+    code => bail!("{server} answered {name} with {code}"),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tokio::net::UdpSocket;
+
+  #[test]
+  fn local_addrs() {
+    let addr = local_addr("0.0.0.0:53".parse().unwrap());
+    assert_eq!(addr, "127.0.0.1:53".parse().unwrap());
+    let addr = local_addr("[::]:5353".parse().unwrap());
+    assert_eq!(addr, "[::1]:5353".parse().unwrap());
+    let addr = local_addr("192.0.2.1:53".parse().unwrap());
+    assert_eq!(addr, "192.0.2.1:53".parse().unwrap());
+  }
+
+  #[tokio::test]
+  async fn unreachable() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    let check = check(server, &Name::root(), Duration::from_millis(50)).await;
+    assert!(format!("{:#}", check.unwrap_err()).contains("no answer after 50ms"));
+  }
+}
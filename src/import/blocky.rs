@@ -0,0 +1,323 @@
+use super::{upstream, zone, Import, DNS_PORT};
+use crate::client::ClientType;
+use crate::group::ForwardZone;
+use anyhow::{bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Port of the `tcp-tls` upstreams without port.
+const TLS_PORT: u16 = 853;
+
+/// Convert a Blocky YAML configuration: the `default` upstreams become the dns servers,
+/// `conditional.mapping` the forward zones, `customDNS.mapping` rewrites of the names and their
+/// subdomains, the local files of `blocking.denylists` filter lists and their inline entries the
+/// blacklists. Other settings, downloaded lists and allowlists are skipped.
+pub fn import(content: &str) -> Result<Import> {
+  let config: Mapping = serde_yaml::from_str(content).context("invalid Blocky configuration")?;
+  let mut import = Import::default();
+  for (key, value) in config.iter() {
+    let key = text(key).unwrap_or_default();
+    match key.as_str() {
+      "upstreams" => upstreams(&mut import, value, "upstreams", true),
+      // Before Blocky 0.23, the groups were directly under `upstream`.
+      "upstream" => upstreams(&mut import, value, "upstream", false),
+      "conditional" => conditional(&mut import, value),
+      "customDNS" => custom_dns(&mut import, value),
+      "blocking" => blocking(&mut import, value),
+      key => import.skipped.push(key.to_string()),
+    }
+  }
+  Ok(import)
+}
+
+/// String of a scalar value.
+fn text(value: &Value) -> Option<String> {
+  match value {
+    Value::String(value) => Some(value.clone()),
+    Value::Number(value) => Some(value.to_string()),
+    Value::Bool(value) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+/// Entries of a mapping with their key as string, the other values are skipped as `path`.
+fn entries<'a>(
+  import: &mut Import,
+  value: &'a Value,
+  path: &str,
+) -> impl Iterator<Item = (String, &'a Value)> {
+  let mapping = match value.as_mapping() {
+    Some(mapping) => mapping.iter().collect(),
+    None => {
+      import.skipped.push(path.to_string());
+      vec![]
+    }
+  };
+  mapping
+    .into_iter()
+    .map(|(key, value)| (text(key).unwrap_or_default(), value))
+}
+
+/// Strings of a list, or of a comma separated value.
+fn list(value: &Value) -> Vec<String> {
+  match value {
+    Value::Sequence(values) => values.iter().filter_map(text).collect(),
+    value => text(value)
+      .map(|value| {
+        value
+          .split(',')
+          .map(|item| item.trim().to_string())
+          .collect()
+      })
+      .unwrap_or_default(),
+  }
+}
+
+fn upstreams(import: &mut Import, value: &Value, path: &str, grouped: bool) {
+  for (key, value) in entries(import, value, path).collect::<Vec<_>>() {
+    let groups = match (grouped, key.as_str()) {
+      (true, "groups") => value,
+      (false, _) => {
+        upstream_group(import, &key, value, path);
+        continue;
+      }
+      _ => {
+        import.skipped.push(format!("{path}.{key}"));
+        continue;
+      }
+    };
+    let path = format!("{path}.groups");
+    for (group, value) in entries(import, groups, &path).collect::<Vec<_>>() {
+      upstream_group(import, &group, value, &path);
+    }
+  }
+}
+
+/// Upstreams of a group, only the `default` one applies to every client.
+fn upstream_group(import: &mut Import, group: &str, value: &Value, path: &str) {
+  if group != "default" {
+    import.skipped.push(format!("{path}.{group}"));
+    return;
+  }
+  for value in list(value) {
+    match blocky_upstream(&value) {
+      Ok(upstream) => import.dns_servers.push(upstream),
+      Err(_) => import.skipped.push(format!("{path}.{group}: {value}")),
+    }
+  }
+}
+
+/// Upstream written `[net:]ip[:port][#name]`, `net` being `tcp+udp` or `tcp-tls`, or an
+/// `https://` URL.
+fn blocky_upstream(value: &str) -> Result<ClientType> {
+  if value.starts_with("https://") {
+    return ClientType::from_str(value.split('#').next().unwrap_or(value));
+  }
+  let (tls, address) = match value.split_once(':') {
+    Some(("tcp-tls", address)) => (true, address),
+    Some(("tcp+udp", address)) => (false, address),
+    _ => (false, value),
+  };
+  let (address, name) = match address.split_once('#') {
+    Some((address, name)) => (address, Some(name)),
+    None => (address, None),
+  };
+  let (ip, port) = match (address.parse::<SocketAddr>(), address.parse::<IpAddr>()) {
+    (Ok(address), _) => (address.ip(), address.port()),
+    (_, Ok(ip)) => (ip, if tls { TLS_PORT } else { DNS_PORT }),
+    _ => bail!("the upstream {value} is not an IP"),
+  };
+  match (tls, name) {
+    (true, None) => bail!("the TLS upstream {value} has no name"),
+    (true, name) => upstream(ip, port, name),
+    (false, _) => upstream(ip, port, None),
+  }
+}
+
+/// `conditional.mapping`: `zone[,zone...]: upstream[,upstream...]`, only the first upstream of a
+/// zone is used.
+fn conditional(import: &mut Import, value: &Value) {
+  for (key, value) in entries(import, value, "conditional").collect::<Vec<_>>() {
+    if key != "mapping" {
+      import.skipped.push(format!("conditional.{key}"));
+      continue;
+    }
+    for (zones, value) in entries(import, value, "conditional.mapping").collect::<Vec<_>>() {
+      let upstreams = list(value);
+      let forward = zones
+        .split(',')
+        .map(zone)
+        .collect::<Result<Vec<_>>>()
+        .and_then(|zones| {
+          let upstream = upstreams.first().context("no upstream")?;
+          Ok(ForwardZone {
+            zones,
+            upstream: blocky_upstream(upstream)?,
+          })
+        });
+      match forward {
+        Ok(forward) => {
+          for upstream in upstreams.iter().skip(1) {
+            import
+              .skipped
+              .push(format!("conditional.mapping.{zones}: {upstream}"));
+          }
+          import.forward_zones.push(forward);
+        }
+        Err(_) => import.skipped.push(format!(
+          "conditional.mapping.{zones}: {}",
+          upstreams.join(",")
+        )),
+      }
+    }
+  }
+}
+
+/// `customDNS.mapping`: `name: ip[,ip...]`, answered for the name and its subdomains like Blocky.
+fn custom_dns(import: &mut Import, value: &Value) {
+  for (key, value) in entries(import, value, "customDNS").collect::<Vec<_>>() {
+    if key != "mapping" {
+      import.skipped.push(format!("customDNS.{key}"));
+      continue;
+    }
+    for (name, value) in entries(import, value, "customDNS.mapping").collect::<Vec<_>>() {
+      let answers = list(value);
+      let ips = answers
+        .iter()
+        .map(|answer| answer.parse::<IpAddr>())
+        .collect::<Result<Vec<_>, _>>();
+      match (zone(&name), ips) {
+        (Ok(zone), Ok(ips)) if !ips.is_empty() => import.zone_answer(zone, ips),
+        _ => import
+          .skipped
+          .push(format!("customDNS.mapping.{name}: {}", answers.join(","))),
+      }
+    }
+  }
+}
+
+/// `blocking.denylists` (`blackLists` before Blocky 0.24): local files and inline entries of
+/// every group.
+fn blocking(import: &mut Import, value: &Value) {
+  for (key, value) in entries(import, value, "blocking").collect::<Vec<_>>() {
+    if key != "denylists" && key != "blackLists" {
+      import.skipped.push(format!("blocking.{key}"));
+      continue;
+    }
+    let path = format!("blocking.{key}");
+    for (group, value) in entries(import, value, &path).collect::<Vec<_>>() {
+      for source in list(value) {
+        denylist(import, &source, &format!("{path}.{group}"));
+      }
+    }
+  }
+}
+
+/// Source of a denylist: inline entries when it has several lines, an URL or a local file.
+fn denylist(import: &mut Import, source: &str, path: &str) {
+  if !source.contains('\n') {
+    match source.starts_with("http://") || source.starts_with("https://") {
+      true => import.skipped.push(format!("{path}: {source}")),
+      false => import.filter_lists.push(source.into()),
+    }
+    return;
+  }
+  for entry in source.lines().map(str::trim) {
+    if entry.is_empty() || entry.starts_with('#') {
+      continue;
+    }
+    let converted = match entry.strip_prefix("*.") {
+      Some(domain) => zone(domain).map(|zone| import.zone_blacklist.push(zone)),
+      None if entry.starts_with('/') => Err(anyhow::anyhow!("regular expression")),
+      None => zone(entry).map(|name| import.blacklist.push(name)),
+    };
+    if converted.is_err() {
+      import.skipped.push(format!("{path}: {entry}"));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn blocky_config() {
+    let content = r#"
+upstreams:
+  groups:
+    default:
+      - 9.9.9.9
+      - tcp+udp:[2620:fe::fe]:53
+      - tcp-tls:1.1.1.1:853#cloudflare-dns.com
+      - https://dns.quad9.net/dns-query
+      - tcp-tls:dns.example
+    guests:
+      - 1.1.1.1
+  strategy: parallel_best
+conditional:
+  mapping:
+    lan,168.192.in-addr.arpa: 192.168.1.1
+    corp.example: 10.0.0.53,10.0.0.54
+customDNS:
+  customTTL: 1h
+  mapping:
+    nas.lan: 192.168.1.10, fd00::10
+blocking:
+  denylists:
+    ads:
+      - /etc/blocky/ads.txt
+      - https://example.com/hosts
+      - |
+        tracker.example
+        *.telemetry.example
+        /^ads[0-9]+\./
+  allowlists:
+    ads:
+      - /etc/blocky/allow.txt
+ports:
+  dns: 53
+"#;
+    let import = import(content).unwrap();
+    let strings = |values: Vec<String>| values.join(" ");
+    assert_eq!(
+      strings(import.dns_servers.iter().map(ToString::to_string).collect()),
+      "9.9.9.9:53 [2620:fe::fe]:53 1.1.1.1:853:tls:cloudflare-dns.com https://dns.quad9.net/dns-query"
+    );
+    assert_eq!(
+      strings(
+        import
+          .forward_zones
+          .iter()
+          .map(ToString::to_string)
+          .collect()
+      ),
+      "lan.,168.192.in-addr.arpa.->192.168.1.1:53 corp.example.->10.0.0.53:53"
+    );
+    assert_eq!(
+      strings(import.rewrites.iter().map(ToString::to_string).collect()),
+      "name=nas.lan;answer=192.168.1.10,fd00::10 name=*.nas.lan;answer=192.168.1.10,fd00::10"
+    );
+    assert_eq!(
+      import.filter_lists,
+      [std::path::PathBuf::from("/etc/blocky/ads.txt")]
+    );
+    assert_eq!(import.blacklist[0].to_string(), "tracker.example.");
+    assert_eq!(import.zone_blacklist[0].to_string(), "telemetry.example.");
+    assert_eq!(
+      import.skipped,
+      [
+        "upstreams.groups.default: tcp-tls:dns.example",
+        "upstreams.groups.guests",
+        "upstreams.strategy",
+        "conditional.mapping.corp.example: 10.0.0.54",
+        "customDNS.customTTL",
+        "blocking.denylists.ads: https://example.com/hosts",
+        r"blocking.denylists.ads: /^ads[0-9]+\./",
+        "blocking.allowlists",
+        "ports",
+      ]
+    );
+  }
+}
@@ -0,0 +1,249 @@
+use super::{upstream, zone, Import, DNS_PORT};
+use crate::authority::{Rewrite, RewriteAction};
+use crate::client::ClientType;
+use crate::group::ForwardZone;
+use anyhow::{bail, Context, Result};
+use hickory_server::proto::rr::{LowerName, Name};
+use std::net::IpAddr;
+
+/// Convert a dnsmasq configuration: `address=/domain/ip` becomes a rewrite of the domain and its
+/// subdomains, or blocks them with `0.0.0.0`, `::` or `#`, `server=/domain/ip` a forward zone,
+/// `server=/domain/` and `local=/domain/` a local only zone, `server=ip` an upstream, `host-record`
+/// a local DNS name and `cname` a rewrite to the target. Other directives are skipped.
+pub fn import(content: &str) -> Import {
+  let mut import = Import::default();
+  let mut addresses: Vec<(LowerName, Vec<IpAddr>)> = vec![];
+  for (number, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let (key, value) = line.split_once('=').unwrap_or((line, ""));
+    let converted = match key.trim() {
+      "address" => address(&mut import, &mut addresses, value.trim()),
+      "server" | "local" => server(&mut import, value.trim()),
+      "host-record" => host_record(&mut import, value.trim()),
+      "cname" => cname(&mut import, value.trim()),
+      _ => Ok(false),
+    };
+    if !matches!(converted, Ok(true)) {
+      import
+        .skipped
+        .push(format!("line {}: {}", number + 1, line));
+    }
+  }
+  for (zone, ips) in addresses {
+    import.zone_answer(zone, ips);
+  }
+  import
+}
+
+/// Domains of `/domain[/domain...]/target`, and the target.
+fn domains(value: &str) -> Result<(Vec<&str>, &str)> {
+  let Some(value) = value.strip_prefix('/') else {
+    bail!("expected /domain/");
+  };
+  let (domains, target) = value.rsplit_once('/').context("expected /domain/")?;
+  Ok((domains.split('/').collect(), target.trim()))
+}
+
+/// `address=/domain/[ip|#]`, the IPs of a domain are gathered in a single rewrite.
+fn address(
+  import: &mut Import,
+  addresses: &mut Vec<(LowerName, Vec<IpAddr>)>,
+  value: &str,
+) -> Result<bool> {
+  let (domains, target) = domains(value)?;
+  // `#` as domain matches every name.
+  if domains.contains(&"#") {
+    return Ok(false);
+  }
+  let zones = domains.into_iter().map(zone).collect::<Result<Vec<_>>>()?;
+  match target {
+    "" => import.local_only_zones.extend(zones),
+    "#" => import.zone_blacklist.extend(zones),
+    ip => {
+      let ip: IpAddr = ip.parse().with_context(|| format!("invalid ip {ip}"))?;
+      if ip.is_unspecified() {
+        import.zone_blacklist.extend(zones);
+        return Ok(true);
+      }
+      for zone in zones {
+        match addresses.iter_mut().find(|(domain, _)| domain == &zone) {
+          Some((_, ips)) => ips.push(ip),
+          None => addresses.push((zone, vec![ip])),
+        }
+      }
+    }
+  }
+  Ok(true)
+}
+
+/// `server=/domain/[ip[#port]]`, `local=/domain/` and `server=ip[#port]`, the `@` source address
+/// or interface is ignored.
+fn server(import: &mut Import, value: &str) -> Result<bool> {
+  if !value.starts_with('/') {
+    import.dns_servers.push(server_upstream(value)?);
+    return Ok(true);
+  }
+  let (domains, target) = domains(value)?;
+  let zones = domains.into_iter().map(zone).collect::<Result<Vec<_>>>()?;
+  match target {
+    "" => import.local_only_zones.extend(zones),
+    // Back to the default upstreams, for a subdomain of a forwarded zone.
+    "#" => return Ok(false),
+    target => import.forward_zones.push(ForwardZone {
+      zones,
+      upstream: server_upstream(target)?,
+    }),
+  }
+  Ok(true)
+}
+
+fn server_upstream(value: &str) -> Result<ClientType> {
+  let address = value.split('@').next().unwrap_or(value);
+  let (ip, port) = match address.split_once('#') {
+    Some((ip, port)) => (ip, port.parse().context("invalid port")?),
+    None => (address, DNS_PORT),
+  };
+  upstream(
+    ip.parse().with_context(|| format!("invalid ip {ip}"))?,
+    port,
+    None,
+  )
+}
+
+/// `host-record=name[,name...],ip[,ip...][,ttl]`, the TTL is ignored.
+fn host_record(import: &mut Import, value: &str) -> Result<bool> {
+  let (mut names, mut ips) = (vec![], vec![]);
+  for field in value.split(',').map(str::trim) {
+    match field.parse::<IpAddr>() {
+      Ok(ip) => ips.push(ip),
+      Err(_) if field.parse::<u32>().is_ok() => {}
+      Err(_) => names.push(zone(field)?.to_string()),
+    }
+  }
+  if names.is_empty() || ips.is_empty() {
+    bail!("expected names and ips");
+  }
+  for ip in ips {
+    let names: Vec<&str> = names
+      .iter()
+      .map(|name| name.trim_end_matches('.'))
+      .collect();
+    import.local_dns.push(format!("{} {}", ip, names.join(" ")));
+  }
+  Ok(true)
+}
+
+/// `cname=alias[,alias...],target[,ttl]`, the TTL is ignored.
+fn cname(import: &mut Import, value: &str) -> Result<bool> {
+  let mut fields: Vec<&str> = value.split(',').map(str::trim).collect();
+  if fields.len() > 2 && fields[fields.len() - 1].parse::<u32>().is_ok() {
+    fields.pop();
+  }
+  let Some((target, aliases)) = fields
+    .split_last()
+    .filter(|(_, aliases)| !aliases.is_empty())
+  else {
+    bail!("expected alias,target");
+  };
+  let target = Name::from(zone(target)?);
+  for alias in aliases {
+    import.rewrites.push(Rewrite {
+      name: Name::from(zone(alias)?),
+      action: RewriteAction::To(target.clone()),
+    });
+  }
+  Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn dnsmasq_directives() {
+    let content = "# dnsmasq.conf
+domain-needed
+server=9.9.9.9
+server=1.1.1.1#5353@eth0
+server=/lan/168.192.in-addr.arpa/192.168.1.1
+server=/corp.example/2001:db8::53#5300
+server=/home/
+local=/internal/
+server=/public.lan/#
+address=/nas.lan/192.168.1.10
+address=/nas.lan/fd00::10
+address=/ads.example/0.0.0.0
+address=/tracker.example/#
+address=/blocked.example/
+address=/#/127.0.0.1
+host-record=router,router.lan,192.168.1.1,fd00::1,3600
+cname=www.lan,web.lan,nas.lan
+cname=invalid
+";
+    let import = import(content);
+    let strings = |values: Vec<String>| values.join(" ");
+    assert_eq!(
+      strings(import.dns_servers.iter().map(ToString::to_string).collect()),
+      "9.9.9.9:53 1.1.1.1:5353"
+    );
+    assert_eq!(
+      strings(
+        import
+          .forward_zones
+          .iter()
+          .map(ToString::to_string)
+          .collect()
+      ),
+      "lan.,168.192.in-addr.arpa.->192.168.1.1:53 corp.example.->[2001:db8::53]:5300"
+    );
+    assert_eq!(
+      strings(
+        import
+          .local_only_zones
+          .iter()
+          .map(ToString::to_string)
+          .collect()
+      ),
+      "home. internal. blocked.example."
+    );
+    assert_eq!(
+      strings(
+        import
+          .zone_blacklist
+          .iter()
+          .map(ToString::to_string)
+          .collect()
+      ),
+      "ads.example. tracker.example."
+    );
+    assert_eq!(
+      import.local_dns,
+      ["192.168.1.1 router router.lan", "fd00::1 router router.lan"]
+    );
+    assert_eq!(
+      import
+        .rewrites
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>(),
+      [
+        "name=www.lan;to=nas.lan",
+        "name=web.lan;to=nas.lan",
+        "name=nas.lan;answer=192.168.1.10,fd00::10",
+        "name=*.nas.lan;answer=192.168.1.10,fd00::10",
+      ]
+    );
+    assert_eq!(
+      import.skipped,
+      [
+        "line 2: domain-needed",
+        "line 9: server=/public.lan/#",
+        "line 15: address=/#/127.0.0.1",
+        "line 18: cname=invalid",
+      ]
+    );
+  }
+}
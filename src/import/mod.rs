@@ -0,0 +1,204 @@
+//! Conversion of the configuration of other DNS servers, to ease the migration to this one.
+use crate::authority::{Rewrite, RewriteAction};
+use crate::client::ClientType;
+use crate::group::ForwardZone;
+use anyhow::{bail, Context, Result};
+use hickory_server::proto::rr::{LowerName, Name};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::{Table, Value};
+
+pub mod blocky;
+pub mod dnsmasq;
+pub mod unbound;
+
+const CONFIG_FILE: &str = "config.toml";
+const LOCAL_DNS_FILE: &str = "local-dns.hosts";
+const BLACKLIST_FILE: &str = "blacklist.txt";
+const ZONE_BLACKLIST_FILE: &str = "zone-blacklist.txt";
+
+/// Port of the upstreams written without port.
+const DNS_PORT: u16 = 53;
+
+/// Configuration converted from another DNS server, written as a configuration file and the
+/// lists it uses.
+#[derive(Debug, Default)]
+pub struct Import {
+  pub dns_servers: Vec<ClientType>,
+  pub forward_zones: Vec<ForwardZone>,
+  /// Zones answered NXDOMAIN, besides the default ones.
+  pub local_only_zones: Vec<LowerName>,
+  pub rewrites: Vec<Rewrite>,
+  /// Lines of the local DNS file.
+  pub local_dns: Vec<String>,
+  pub blacklist: Vec<LowerName>,
+  pub zone_blacklist: Vec<LowerName>,
+  /// Blocklists used as they are, in the AdGuard Home syntax.
+  pub filter_lists: Vec<PathBuf>,
+  /// Settings without equivalent, with their line number or key.
+  pub skipped: Vec<String>,
+}
+
+impl Import {
+  /// Answer `ips` for `zone` and all its names.
+  fn zone_answer(&mut self, zone: LowerName, ips: Vec<IpAddr>) {
+    let name = Name::from(zone);
+    let wildcard = Name::from_ascii("*")
+      .and_then(|wildcard| wildcard.append_domain(&name))
+      .ok();
+    for name in std::iter::once(name).chain(wildcard) {
+      self.rewrites.push(Rewrite {
+        name,
+        action: RewriteAction::Answer(ips.clone()),
+      });
+    }
+  }
+
+  /// Options of the configuration file, the lists being written in `dir`.
+  pub fn config(&self, dir: &Path) -> Table {
+    let strings = |values: Vec<String>| Value::Array(values.into_iter().map(Value::from).collect());
+    let path = |file: &str| Value::from(dir.join(file).display().to_string());
+    let mut config = Table::new();
+    if !self.dns_servers.is_empty() {
+      config.insert(
+        "dns-server".to_string(),
+        strings(self.dns_servers.iter().map(ToString::to_string).collect()),
+      );
+    }
+    if !self.forward_zones.is_empty() {
+      config.insert(
+        "forward-zone".to_string(),
+        strings(self.forward_zones.iter().map(ToString::to_string).collect()),
+      );
+    }
+    if !self.local_only_zones.is_empty() {
+      let zones = std::iter::once("default".to_string())
+        .chain(self.local_only_zones.iter().map(ToString::to_string))
+        .collect();
+      config.insert("local-only-zones".to_string(), strings(zones));
+    }
+    if !self.rewrites.is_empty() {
+      config.insert(
+        "rewrite".to_string(),
+        strings(self.rewrites.iter().map(ToString::to_string).collect()),
+      );
+    }
+    if !self.local_dns.is_empty() {
+      config.insert(
+        "local-dns".to_string(),
+        Value::Array(vec![path(LOCAL_DNS_FILE)]),
+      );
+    }
+    if !self.blacklist.is_empty() {
+      config.insert(
+        "blacklist".to_string(),
+        Value::Array(vec![path(BLACKLIST_FILE)]),
+      );
+    }
+    if !self.zone_blacklist.is_empty() {
+      config.insert("zone-blacklist".to_string(), path(ZONE_BLACKLIST_FILE));
+    }
+    if !self.filter_lists.is_empty() {
+      config.insert(
+        "filter-list".to_string(),
+        strings(
+          self
+            .filter_lists
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+        ),
+      );
+    }
+    config
+  }
+
+  /// Write the configuration file and its lists in `dir`, existing files are only replaced with
+  /// `force`. Returns the written files.
+  pub fn write(&self, dir: &Path, force: bool) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+    let dir = dir
+      .canonicalize()
+      .with_context(|| format!("could not read {}", dir.display()))?;
+    let names = |names: &[LowerName]| -> Vec<String> {
+      names
+        .iter()
+        .map(|name| name.to_string().trim_end_matches('.').to_string())
+        .collect()
+    };
+    let files = [
+      (
+        CONFIG_FILE,
+        vec![toml::to_string(&self.config(&dir))?.trim_end().to_string()],
+      ),
+      (LOCAL_DNS_FILE, self.local_dns.clone()),
+      (BLACKLIST_FILE, names(&self.blacklist)),
+      (ZONE_BLACKLIST_FILE, names(&self.zone_blacklist)),
+    ];
+    let files: Vec<(PathBuf, Vec<String>)> = files
+      .into_iter()
+      .filter(|(_, lines)| !lines.is_empty())
+      .map(|(file, lines)| (dir.join(file), lines))
+      .collect();
+    if !force {
+      if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        bail!(
+          "{} already exists, use --force to replace it",
+          path.display()
+        );
+      }
+    }
+    for (path, lines) in files.iter() {
+      std::fs::write(path, lines.join("\n") + "\n")
+        .with_context(|| format!("could not write {}", path.display()))?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+  }
+}
+
+/// Upstream at `ip` and `port`, over TLS when the name of its certificate is given.
+fn upstream(ip: IpAddr, port: u16, tls_name: Option<&str>) -> Result<ClientType> {
+  let mut address = match ip {
+    IpAddr::V4(ip) => format!("{ip}:{port}"),
+    IpAddr::V6(ip) => format!("[{ip}]:{port}"),
+  };
+  if let Some(name) = tls_name {
+    address = format!("{address}:tls:{name}");
+  }
+  ClientType::try_from(address.as_str()).with_context(|| format!("invalid upstream {address}"))
+}
+
+/// Zone of `domain`, written with or without its final dot.
+fn zone(domain: &str) -> Result<LowerName> {
+  let domain = domain.trim().trim_end_matches('.');
+  if domain.is_empty() {
+    bail!("empty domain");
+  }
+  LowerName::from_str(&format!("{domain}.")).with_context(|| format!("invalid domain {domain}"))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn config_file() {
+    let import = Import {
+      dns_servers: vec![ClientType::Quad9],
+      local_only_zones: vec![zone("lan").unwrap()],
+      rewrites: vec![Rewrite::from_str("name=old.example;to=new.example").unwrap()],
+      zone_blacklist: vec![zone("ads.example.").unwrap()],
+      ..Default::default()
+    };
+    let config = toml::to_string(&import.config(Path::new("/etc/dns-server"))).unwrap();
+    assert_eq!(
+      config,
+      r#"dns-server = ["quad9"]
+local-only-zones = ["default", "lan."]
+rewrite = ["name=old.example;to=new.example"]
+zone-blacklist = "/etc/dns-server/zone-blacklist.txt"
+"#
+    );
+  }
+}
@@ -0,0 +1,325 @@
+use super::{upstream, zone, Import, DNS_PORT};
+use crate::authority::{Rewrite, RewriteAction};
+use crate::client::ClientType;
+use crate::group::ForwardZone;
+use anyhow::{bail, Context, Result};
+use hickory_server::proto::rr::{LowerName, Name};
+use std::net::IpAddr;
+
+/// Port of the TLS upstreams without `@port`.
+const TLS_PORT: u16 = 853;
+
+/// `forward-zone:` clause being read.
+#[derive(Default)]
+struct Forward {
+  line: usize,
+  name: Option<String>,
+  addresses: Vec<String>,
+  tls: bool,
+}
+
+/// Convert the `local-zone:`, `local-data:` and `forward-zone:` statements of an Unbound
+/// configuration. Blocking local zones (`always_nxdomain`, `refuse`, `always_null`...) go to the
+/// zone blacklist, `static` ones are local only, `redirect` ones answer their A and AAAA records
+/// for all their names. A and AAAA local data become local DNS names, like TXT, MX and SRV
+/// records, and CNAME a rewrite. `forward-zone` of `.` gives the dns servers, the others forward
+/// zones. Other statements are skipped.
+pub fn import(content: &str) -> Import {
+  let lines: Vec<(usize, &str, &str)> = content
+    .lines()
+    .enumerate()
+    .map(|(number, line)| (number + 1, line.trim()))
+    .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+    .map(|(number, line)| {
+      let (key, value) = line.split_once(':').unwrap_or((line, ""));
+      (number, key.trim(), unquote(value.trim()))
+    })
+    .collect();
+  let redirects: Vec<LowerName> = lines
+    .iter()
+    .filter(|(_, key, _)| *key == "local-zone")
+    .filter_map(|(_, _, value)| match local_zone_type(value) {
+      Some((zone, "redirect")) => Some(zone),
+      _ => None,
+    })
+    .collect();
+  let mut import = Import::default();
+  let mut redirected: Vec<(LowerName, Vec<IpAddr>)> = vec![];
+  let mut section = "";
+  let mut forward: Option<Forward> = None;
+  for (number, key, value) in lines.iter().copied() {
+    // Clauses are written without value.
+    if value.is_empty() {
+      if let Some(forward) = forward.take() {
+        forward_zone(&mut import, forward);
+      }
+      section = key;
+      if key == "forward-zone" {
+        forward = Some(Forward {
+          line: number,
+          ..Default::default()
+        });
+      } else if key != "server" {
+        import.skipped.push(format!("line {number}: {key}:"));
+      }
+      continue;
+    }
+    let converted = match (section, key, &mut forward) {
+      ("server", "local-zone", _) => local_zone(&mut import, value),
+      ("server", "local-data", _) => local_data(&mut import, &redirects, &mut redirected, value),
+      ("forward-zone", "name", Some(forward)) => {
+        forward.name = Some(value.to_string());
+        Ok(true)
+      }
+      ("forward-zone", "forward-addr", Some(forward)) => {
+        forward.addresses.push(value.to_string());
+        Ok(true)
+      }
+      ("forward-zone", "forward-tls-upstream" | "forward-ssl-upstream", Some(forward)) => {
+        forward.tls = value == "yes";
+        Ok(true)
+      }
+      _ => Ok(false),
+    };
+    if !matches!(converted, Ok(true)) {
+      import
+        .skipped
+        .push(format!("line {number}: {key}: {value}"));
+    }
+  }
+  if let Some(forward) = forward.take() {
+    forward_zone(&mut import, forward);
+  }
+  for (zone, ips) in redirected {
+    import.zone_answer(zone, ips);
+  }
+  import
+}
+
+/// Value without its quotes.
+fn unquote(value: &str) -> &str {
+  for quote in ['"', '\''] {
+    if let Some(value) = value
+      .strip_prefix(quote)
+      .and_then(|value| value.strip_suffix(quote))
+    {
+      return value;
+    }
+  }
+  value
+}
+
+/// Zone and type of `"zone" type`.
+fn local_zone_type(value: &str) -> Option<(LowerName, &str)> {
+  let mut words = value.split_whitespace();
+  let zone = zone(unquote(words.next()?)).ok()?;
+  Some((zone, words.next()?))
+}
+
+fn local_zone(import: &mut Import, value: &str) -> Result<bool> {
+  let (zone, kind) = local_zone_type(value).context("expected zone and type")?;
+  match kind {
+    "always_nxdomain" | "always_null" | "always_refuse" | "refuse" | "deny" | "always_deny" => {
+      import.zone_blacklist.push(zone)
+    }
+    "static" => import.local_only_zones.push(zone),
+    // Answered from the local data of the zone.
+    "redirect" => {}
+    _ => return Ok(false),
+  }
+  Ok(true)
+}
+
+/// `name [ttl] [class] type data`, the TTL is ignored.
+fn local_data(
+  import: &mut Import,
+  redirects: &[LowerName],
+  redirected: &mut Vec<(LowerName, Vec<IpAddr>)>,
+  value: &str,
+) -> Result<bool> {
+  let mut words = value.split_whitespace().peekable();
+  let name = zone(words.next().context("expected name")?)?;
+  words.next_if(|word| word.parse::<u32>().is_ok());
+  words.next_if(|word| word.eq_ignore_ascii_case("IN"));
+  let kind = words.next().context("expected type")?.to_uppercase();
+  let data: Vec<&str> = words.collect();
+  let host = name.to_string().trim_end_matches('.').to_string();
+  match (kind.as_str(), data.as_slice()) {
+    ("A" | "AAAA", [ip]) => {
+      let ip: IpAddr = ip.parse().with_context(|| format!("invalid ip {ip}"))?;
+      if redirects.contains(&name) {
+        match redirected.iter_mut().find(|(zone, _)| zone == &name) {
+          Some((_, ips)) => ips.push(ip),
+          None => redirected.push((name, vec![ip])),
+        }
+      } else {
+        import.local_dns.push(format!("{ip} {host}"));
+      }
+    }
+    ("CNAME", [target]) => import.rewrites.push(Rewrite {
+      name: Name::from(name),
+      action: RewriteAction::To(Name::from(zone(target)?)),
+    }),
+    ("TXT", [_, ..]) => import
+      .local_dns
+      .push(format!("txt {} {}", host, data.join(" "))),
+    ("MX", [preference, exchange]) => import.local_dns.push(format!(
+      "mx {} {} {}",
+      host,
+      preference,
+      exchange.trim_end_matches('.')
+    )),
+    ("SRV", [priority, weight, port, target]) => import.local_dns.push(format!(
+      "srv {} {} {} {} {}",
+      host,
+      priority,
+      weight,
+      port,
+      target.trim_end_matches('.')
+    )),
+    _ => return Ok(false),
+  }
+  Ok(true)
+}
+
+/// Upstream written `ip[@port][#name]`, over TLS when the clause has `forward-tls-upstream: yes`.
+fn forward_upstream(address: &str, tls: bool) -> Result<ClientType> {
+  let (address, name) = match address.split_once('#') {
+    Some((address, name)) => (address, Some(name)),
+    None => (address, None),
+  };
+  let (ip, port) = match address.split_once('@') {
+    Some((ip, port)) => (ip, port.parse().context("invalid port")?),
+    None => (address, if tls { TLS_PORT } else { DNS_PORT }),
+  };
+  let ip = ip.parse().with_context(|| format!("invalid ip {ip}"))?;
+  match (tls, name) {
+    (true, None) => bail!("the TLS upstream {address} has no name"),
+    (true, name) => upstream(ip, port, name),
+    (false, _) => upstream(ip, port, None),
+  }
+}
+
+/// `forward-zone` clause, the root zone gives the dns servers and the others a forward zone to
+/// their first address.
+fn forward_zone(import: &mut Import, forward: Forward) {
+  let line = forward.line;
+  let Some(name) = forward.name else {
+    import
+      .skipped
+      .push(format!("line {line}: forward-zone without name"));
+    return;
+  };
+  let mut upstreams = vec![];
+  for address in forward.addresses {
+    match forward_upstream(&address, forward.tls) {
+      Ok(upstream) => upstreams.push(upstream),
+      Err(_) => import
+        .skipped
+        .push(format!("line {line}: forward-zone {name}: {address}")),
+    }
+  }
+  if name.trim_end_matches('.').is_empty() {
+    import.dns_servers.extend(upstreams);
+    return;
+  }
+  let Ok(zone) = zone(&name) else {
+    import
+      .skipped
+      .push(format!("line {line}: forward-zone {name}"));
+    return;
+  };
+  let mut upstreams = upstreams.into_iter();
+  if let Some(upstream) = upstreams.next() {
+    import.forward_zones.push(ForwardZone {
+      zones: vec![zone],
+      upstream,
+    });
+  }
+  for upstream in upstreams {
+    import
+      .skipped
+      .push(format!("line {line}: forward-zone {name}: {upstream}"));
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn unbound_statements() {
+    let content = r#"# unbound.conf
+server:
+  interface: 0.0.0.0
+  local-zone: "ads.example." always_nxdomain
+  local-zone: "lan." static
+  local-zone: "nas.lan." redirect
+  local-zone: "example.com." transparent
+  local-data: "nas.lan. A 192.168.1.10"
+  local-data: "router.lan. 3600 IN A 192.168.1.1"
+  local-data: "www.lan. CNAME nas.lan."
+  local-data: 'lan. TXT "v=spf1 -all"'
+  local-data: "lan. MX 10 mail.lan."
+  local-data: "_ldap._tcp.lan. SRV 0 5 389 ldap.lan."
+  local-data: "lan. SOA ns.lan. admin.lan. 1 3600 600 86400 60"
+
+forward-zone:
+  name: "."
+  forward-tls-upstream: yes
+  forward-addr: 1.1.1.1@853#cloudflare-dns.com
+  forward-addr: 9.9.9.9
+
+forward-zone:
+  name: "corp.example."
+  forward-addr: 10.0.0.53
+  forward-addr: 10.0.0.54@5353
+
+remote-control:
+  control-enable: yes
+"#;
+    let import = import(content);
+    let strings = |values: Vec<String>| values.join(" ");
+    assert_eq!(
+      strings(import.dns_servers.iter().map(ToString::to_string).collect()),
+      "1.1.1.1:853:tls:cloudflare-dns.com"
+    );
+    assert_eq!(
+      strings(
+        import
+          .forward_zones
+          .iter()
+          .map(ToString::to_string)
+          .collect()
+      ),
+      "corp.example.->10.0.0.53:53"
+    );
+    assert_eq!(import.zone_blacklist[0].to_string(), "ads.example.");
+    assert_eq!(import.local_only_zones[0].to_string(), "lan.");
+    assert_eq!(
+      import.local_dns,
+      [
+        "192.168.1.1 router.lan",
+        r#"txt lan "v=spf1 -all""#,
+        "mx lan 10 mail.lan",
+        "srv _ldap._tcp.lan 0 5 389 ldap.lan",
+      ]
+    );
+    assert_eq!(
+      strings(import.rewrites.iter().map(ToString::to_string).collect()),
+      "name=www.lan;to=nas.lan name=nas.lan;answer=192.168.1.10 name=*.nas.lan;answer=192.168.1.10"
+    );
+    assert_eq!(
+      import.skipped,
+      [
+        "line 3: interface: 0.0.0.0",
+        "line 7: local-zone: \"example.com.\" transparent",
+        "line 14: local-data: lan. SOA ns.lan. admin.lan. 1 3600 600 86400 60",
+        "line 16: forward-zone .: 9.9.9.9",
+        "line 22: forward-zone corp.example.: 10.0.0.54:5353",
+        "line 27: remote-control:",
+        "line 28: control-enable: yes",
+      ]
+    );
+  }
+}
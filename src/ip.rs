@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use ipnet::IpNet;
 use std::{
   cmp::Ordering,
@@ -18,8 +18,25 @@ pub struct IpRangeVec {
 
 impl TryFrom<&str> for IpRange {
   type Error = anyhow::Error;
+  /// A network (`192.168.1.0/24`), a single IP (`192.168.1.1`) or the first and last IPs of a
+  /// range (`192.168.1.10-192.168.1.50`).
   fn try_from(s: &str) -> Result<IpRange, Self::Error> {
-    let range = match s.parse::<IpNet>()? {
+    if let Some((start, end)) = s.split_once('-') {
+      let (start, end) = match (start.trim().parse()?, end.trim().parse()?) {
+        (IpAddr::V4(start), IpAddr::V4(end)) => (start.to_bits() as u128, end.to_bits() as u128),
+        (IpAddr::V6(start), IpAddr::V6(end)) => (start.to_bits(), end.to_bits()),
+        _ => bail!("the IPs of the range {s} are not of the same version"),
+      };
+      if start > end {
+        bail!("the range {s} ends before its start");
+      }
+      return Ok(IpRange { start, end });
+    }
+    let net = match s.parse::<IpAddr>() {
+      Ok(ip) => IpNet::from(ip),
+      Err(_) => s.parse::<IpNet>()?,
+    };
+    let range = match net {
       IpNet::V4(ip) => IpRange {
         start: ip.network().to_bits() as u128,
         end: ip.broadcast().to_bits() as u128,
@@ -34,10 +51,33 @@ impl TryFrom<&str> for IpRange {
 }
 
 impl IpRangeVec {
-  pub fn new(ranges: Vec<IpRange>) -> Self {
-    let mut ranges = ranges.clone();
+  /// Sorted ranges, the overlapping and adjacent ones are merged so each IP is in one range at
+  /// most.
+  pub fn new(mut ranges: Vec<IpRange>) -> Self {
     ranges.sort();
-    Self { ranges }
+    let mut merged: Vec<IpRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+      match merged.last_mut() {
+        Some(last) if range.start <= last.end.saturating_add(1) => {
+          last.end = last.end.max(range.end);
+        }
+        _ => merged.push(range),
+      }
+    }
+    Self { ranges: merged }
+  }
+
+  /// Number of ranges once merged.
+  pub fn len(&self) -> usize {
+    self.ranges.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ranges.is_empty()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &IpRange> {
+    self.ranges.iter()
   }
 
   pub fn contains_sock_addr(&self, socket: SocketAddr) -> bool {
@@ -83,6 +123,34 @@ mod test {
     IpRangeVec::new(ranges)
   }
 
+  #[test]
+  fn single_ip() {
+    let ip_range_vec = IpRangeVec::new(vec![
+      IpRange::try_from("192.0.2.1").unwrap(),
+      IpRange::try_from("2001:db8::1").unwrap(),
+    ]);
+    assert!(ip_range_vec.contains("192.0.2.1".parse().unwrap()));
+    assert!(!ip_range_vec.contains("192.0.2.2".parse().unwrap()));
+    assert!(ip_range_vec.contains("2001:db8::1".parse().unwrap()));
+    assert!(IpRange::try_from("192.0.2.300").is_err());
+  }
+
+  #[test]
+  fn start_end() {
+    let range = IpRangeVec::new(vec![
+      IpRange::try_from("192.168.1.10-192.168.1.50").unwrap(),
+      IpRange::try_from("2001:db8::1 - 2001:db8::ff").unwrap(),
+    ]);
+    assert!(range.contains("192.168.1.10".parse().unwrap()));
+    assert!(range.contains("192.168.1.50".parse().unwrap()));
+    assert!(!range.contains("192.168.1.51".parse().unwrap()));
+    assert!(range.contains("2001:db8::80".parse().unwrap()));
+    assert!(!range.contains("2001:db8::100".parse().unwrap()));
+    assert!(IpRange::try_from("192.168.1.50-192.168.1.10").is_err());
+    assert!(IpRange::try_from("192.168.1.10-2001:db8::1").is_err());
+    assert!(IpRange::try_from("192.168.1.10-").is_err());
+  }
+
   #[test]
   fn ordering() {
     let ranges: Vec<IpRange> = vec!["fd00::/8", "172.16.0.0/12", "10.0.0.0/8", "192.168.0.0/16"]
@@ -94,11 +162,74 @@ mod test {
     assert_eq!(expected, IpRangeVec::new(ranges).ranges);
   }
 
+  #[test]
+  fn merging() {
+    let ranges = |ranges: &[&str]| -> IpRangeVec {
+      IpRangeVec::new(
+        ranges
+          .iter()
+          .map(|range| IpRange::try_from(*range).unwrap())
+          .collect(),
+      )
+    };
+    let merged = ranges(&[
+      "10.0.0.0/8",
+      "10.1.0.0/16",
+      "192.168.0.0/24",
+      "192.168.1.0/24",
+    ]);
+    assert_eq!(
+      merged,
+      ranges(&["10.0.0.0/8", "192.168.0.0/23"]),
+      "overlapping and adjacent ranges"
+    );
+    assert_eq!(merged.len(), 2);
+    assert!(merged.contains("192.168.1.255".parse().unwrap()));
+    assert!(!merged.contains("192.168.2.0".parse().unwrap()));
+    assert_eq!(ranges(&["::/0", "0.0.0.0/0"]).len(), 1);
+    assert!(ranges(&[]).is_empty());
+  }
+
+  /// Merged ranges contain the same IPs as the ranges they come from, on random ranges.
+  #[test]
+  fn merging_keeps_ips() {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut random = move |max: u128| {
+      seed ^= seed << 13;
+      seed ^= seed >> 7;
+      seed ^= seed << 17;
+      seed as u128 % max
+    };
+    for _ in 0..200 {
+      let ranges: Vec<IpRange> = (0..random(20))
+        .map(|_| {
+          let start = random(1000);
+          IpRange {
+            start,
+            end: start + random(50),
+          }
+        })
+        .collect();
+      let merged = IpRangeVec::new(ranges.clone());
+      assert!(merged
+        .ranges
+        .windows(2)
+        .all(|pair| pair[0].end + 1 < pair[1].start));
+      for bits in 0..1100 {
+        let expected = ranges
+          .iter()
+          .any(|range| range.start <= bits && bits <= range.end);
+        let ip = IpAddr::V6(std::net::Ipv6Addr::from_bits(bits));
+        assert_eq!(merged.contains(ip), expected, "{ip} in {ranges:?}");
+      }
+    }
+  }
+
   #[test]
   fn contains_all() {
     let ip_range_vec = get_private_range_vec();
 
-    vec![
+    [
       "10.0.0.0",
       "10.128.128.128",
       "10.255.255.255",
@@ -121,7 +252,7 @@ mod test {
   fn does_not_contain_all() {
     let ip_range_vec = get_private_range_vec();
 
-    vec![
+    [
       "9.255.255.255",
       "11.0.0.0",
       "172.15.255.255",
@@ -0,0 +1,45 @@
+//! DNS server blocking domains and zones, forwarding the other queries to a trusted resolver. The
+//! server can be embedded with [`DnsServerBuilder`].
+pub mod acl;
+pub mod acme;
+pub mod authority;
+pub mod bench;
+pub mod cache;
+pub mod category;
+pub mod client;
+pub mod config;
+pub mod control;
+pub mod doh;
+pub mod domain_set;
+pub mod events;
+pub mod filter_list;
+pub mod geoip;
+pub mod group;
+pub mod handler;
+pub mod healthcheck;
+pub mod import;
+pub mod ip;
+pub mod logging;
+pub mod nat64;
+pub mod padding;
+#[cfg(unix)]
+pub mod privileges;
+pub mod proxy_protocol;
+pub mod query;
+pub mod recursor;
+pub mod rrl;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod schedule;
+pub mod server;
+pub mod service;
+pub mod snapshot;
+pub mod stream;
+pub mod systemd;
+pub mod tls;
+pub mod tsig;
+pub mod upstream;
+pub mod warmup;
+pub mod web;
+
+pub use crate::server::{DnsServerBuilder, ServerHandle};
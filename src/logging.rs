@@ -0,0 +1,431 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+#[cfg(unix)]
+const IDENTIFIER: &str = "dns-server";
+/// Facility of the syslog messages, `daemon`.
+#[cfg(unix)]
+const FACILITY_DAEMON: u8 = 3;
+/// Events 1 to 1000 of `EventCreate.exe`, the message file of the event source, are shown as is.
+#[cfg(windows)]
+const EVENT_ID: u32 = 1;
+
+/// Where the logs are written.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LogOutput {
+  #[default]
+  Stderr,
+  /// The local syslog daemon, the fields of the events are appended as `key=value`.
+  Syslog,
+  /// The systemd journal, the fields of the events are journal fields.
+  Journald,
+  /// The Windows Application event log, under the source registered by `service install`.
+  EventLog,
+  /// Lines appended to a file.
+  File(PathBuf),
+}
+
+impl FromStr for LogOutput {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "stderr" => Ok(Self::Stderr),
+      "syslog" => Ok(Self::Syslog),
+      "journald" => Ok(Self::Journald),
+      "eventlog" => Ok(Self::EventLog),
+      s => match s.strip_prefix("file:") {
+        Some(path) if !path.is_empty() => Ok(Self::File(PathBuf::from(path))),
+        _ => Err(anyhow!(
+          "invalid log output `{s}`, expected stderr, syslog, journald, eventlog or file:<path>"
+        )),
+      },
+    }
+  }
+}
+
+impl fmt::Display for LogOutput {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Stderr => write!(f, "stderr"),
+      Self::Syslog => write!(f, "syslog"),
+      Self::Journald => write!(f, "journald"),
+      Self::EventLog => write!(f, "eventlog"),
+      Self::File(path) => write!(f, "file:{}", path.display()),
+    }
+  }
+}
+
+impl Serialize for LogOutput {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for LogOutput {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    LogOutput::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+impl LogOutput {
+  /// Layer writing the events to this output, the socket of the daemon must exist.
+  pub fn layer<S>(&self) -> Result<Box<dyn Layer<S> + Send + Sync>>
+  where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+  {
+    let layer = match self {
+      // Colors are only written to terminals, not to the journal or log files of services.
+      Self::Stderr => tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .boxed(),
+      Self::File(path) => {
+        let file = OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(path)
+          .with_context(|| format!("could not open the log file {}", path.display()))?;
+        tracing_subscriber::fmt::layer()
+          .with_writer(Arc::new(file))
+          .with_ansi(false)
+          .boxed()
+      }
+      #[cfg(unix)]
+      Self::Syslog => SocketLayer::connect(Format::Syslog, Path::new(SYSLOG_SOCKET))?.boxed(),
+      #[cfg(unix)]
+      Self::Journald => SocketLayer::connect(Format::Journald, Path::new(JOURNALD_SOCKET))?.boxed(),
+      #[cfg(not(unix))]
+      Self::Syslog | Self::Journald => bail!("{self} is only available on Unix"),
+      #[cfg(windows)]
+      Self::EventLog => EventLogLayer::register()?.boxed(),
+      #[cfg(not(windows))]
+      Self::EventLog => bail!("the event log is only available on Windows"),
+    };
+    Ok(layer)
+  }
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+enum Format {
+  /// RFC 3164 messages, as written by `syslog(3)`.
+  Syslog,
+  /// Native protocol of the journal, see `systemd.journal-fields(7)`.
+  Journald,
+}
+
+/// Sends each event as a datagram to the socket of a log daemon.
+#[cfg(unix)]
+struct SocketLayer {
+  format: Format,
+  path: PathBuf,
+  socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl SocketLayer {
+  fn connect(format: Format, path: &Path) -> Result<Self> {
+    let socket = UnixDatagram::unbound()?;
+    socket
+      .connect(path)
+      .with_context(|| format!("could not connect to {}", path.display()))?;
+    Ok(Self {
+      format,
+      path: path.to_path_buf(),
+      socket,
+    })
+  }
+
+  fn send(&self, message: &[u8]) {
+    // The daemon may have been restarted since the last message, nowhere to report other errors.
+    if self.socket.send(message).is_err() && self.socket.connect(&self.path).is_ok() {
+      let _ = self.socket.send(message);
+    }
+  }
+}
+
+#[cfg(unix)]
+impl<S: Subscriber> Layer<S> for SocketLayer {
+  fn on_event(&self, event: &Event<'_>, _: LayerContext<'_, S>) {
+    let mut fields = Fields::default();
+    event.record(&mut fields);
+    let message = match self.format {
+      Format::Syslog => syslog_message(event.metadata(), &fields),
+      Format::Journald => journald_message(event.metadata(), &fields),
+    };
+    self.send(&message);
+  }
+}
+
+/// Message and other fields of an event.
+#[derive(Default)]
+struct Fields {
+  message: String,
+  fields: Vec<(&'static str, String)>,
+}
+
+impl Fields {
+  fn record(&mut self, field: &Field, value: String) {
+    match field.name() {
+      "message" => self.message = value,
+      // Metadata of the events of the `log` crate, already in the event metadata.
+      name if name.starts_with("log.") => {}
+      name => self.fields.push((name, value)),
+    }
+  }
+}
+
+impl Visit for Fields {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    self.record(field, value.to_string());
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    self.record(field, format!("{value:?}"));
+  }
+}
+
+#[cfg(unix)]
+fn severity(level: &Level) -> u8 {
+  match *level {
+    Level::ERROR => 3,
+    Level::WARN => 4,
+    Level::INFO => 6,
+    _ => 7,
+  }
+}
+
+/// `<priority>timestamp dns-server[pid]: target: message key=value...`, the daemon adds the host.
+#[cfg(unix)]
+fn syslog_message(metadata: &Metadata, fields: &Fields) -> Vec<u8> {
+  format!(
+    "<{}>{} {IDENTIFIER}[{}]: {}",
+    FACILITY_DAEMON * 8 + severity(metadata.level()),
+    chrono::Local::now().format("%b %e %H:%M:%S"),
+    std::process::id(),
+    text(metadata, fields)
+  )
+  .into_bytes()
+}
+
+/// `target: message key=value...`
+fn text(metadata: &Metadata, fields: &Fields) -> String {
+  let mut text = format!("{}: {}", metadata.target(), fields.message);
+  for (name, value) in &fields.fields {
+    if value.contains(char::is_whitespace) {
+      text.push_str(&format!(" {name}={value:?}"));
+    } else {
+      text.push_str(&format!(" {name}={value}"));
+    }
+  }
+  text
+}
+
+/// Reports each event to the Windows event log, its level is the type of the event.
+#[cfg(windows)]
+struct EventLogLayer(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl EventLogLayer {
+  fn register() -> Result<Self> {
+    use windows_sys::Win32::System::EventLog::RegisterEventSourceW;
+
+    let source = wide(crate::service::EVENT_SOURCE);
+    // SAFETY: the name of the source is a null-terminated UTF-16 string.
+    let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+    if handle == 0 {
+      return Err(io::Error::last_os_error()).context("could not register the event source");
+    }
+    Ok(Self(handle))
+  }
+}
+
+#[cfg(windows)]
+impl<S: Subscriber> Layer<S> for EventLogLayer {
+  fn on_event(&self, event: &Event<'_>, _: LayerContext<'_, S>) {
+    use windows_sys::Win32::System::EventLog::{
+      ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+    };
+
+    let mut fields = Fields::default();
+    event.record(&mut fields);
+    let event_type = match *event.metadata().level() {
+      Level::ERROR => EVENTLOG_ERROR_TYPE,
+      Level::WARN => EVENTLOG_WARNING_TYPE,
+      _ => EVENTLOG_INFORMATION_TYPE,
+    };
+    let message = wide(&text(event.metadata(), &fields));
+    let strings = [message.as_ptr()];
+    // Nowhere to report the errors.
+    // SAFETY: the handle comes from `RegisterEventSourceW` and the string is null-terminated.
+    unsafe {
+      ReportEventW(
+        self.0,
+        event_type,
+        0,
+        EVENT_ID,
+        std::ptr::null_mut(),
+        1,
+        0,
+        strings.as_ptr(),
+        std::ptr::null(),
+      )
+    };
+  }
+}
+
+#[cfg(windows)]
+impl Drop for EventLogLayer {
+  fn drop(&mut self) {
+    // SAFETY: the handle comes from `RegisterEventSourceW`.
+    unsafe { windows_sys::Win32::System::EventLog::DeregisterEventSource(self.0) };
+  }
+}
+
+/// Null-terminated UTF-16 string of the Windows API.
+#[cfg(windows)]
+fn wide(value: &str) -> Vec<u16> {
+  value.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+#[cfg(unix)]
+fn journald_message(metadata: &Metadata, fields: &Fields) -> Vec<u8> {
+  let mut message = Vec::new();
+  let mut put = |name: &str, value: &str| {
+    if value.contains('\n') {
+      // Values with new lines are sent with their length instead of `=`.
+      message.extend_from_slice(name.as_bytes());
+      message.push(b'\n');
+      message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+      message.extend_from_slice(value.as_bytes());
+    } else {
+      message.extend_from_slice(format!("{name}={value}").as_bytes());
+    }
+    message.push(b'\n');
+  };
+  put("MESSAGE", &fields.message);
+  put("PRIORITY", &severity(metadata.level()).to_string());
+  put("SYSLOG_IDENTIFIER", IDENTIFIER);
+  put("TARGET", metadata.target());
+  if let Some(file) = metadata.file() {
+    put("CODE_FILE", file);
+  }
+  if let Some(line) = metadata.line() {
+    put("CODE_LINE", &line.to_string());
+  }
+  for (name, value) in &fields.fields {
+    put(&journald_name(name), value);
+  }
+  message
+}
+
+/// Journal field names are uppercase letters, digits and underscores, starting with a letter.
+#[cfg(unix)]
+fn journald_name(name: &str) -> String {
+  let name: String = name
+    .chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+      _ => '_',
+    })
+    .collect();
+  if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+    name
+  } else {
+    format!("F{name}")
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  #[cfg(unix)]
+  use tracing_subscriber::layer::SubscriberExt;
+
+  #[test]
+  fn parse() {
+    for output in [
+      "stderr",
+      "syslog",
+      "journald",
+      "eventlog",
+      "file:/var/log/dns-server.log",
+    ] {
+      assert_eq!(output.parse::<LogOutput>().unwrap().to_string(), output);
+    }
+    assert_eq!(
+      "file:dns.log".parse::<LogOutput>().unwrap(),
+      LogOutput::File(PathBuf::from("dns.log"))
+    );
+    assert!("file:".parse::<LogOutput>().is_err());
+    assert!("stdout".parse::<LogOutput>().is_err());
+  }
+
+  /// Datagrams sent by a layer of `format` for a warning with fields.
+  #[cfg(unix)]
+  fn receive(format: Format) -> Vec<u8> {
+    let path = std::env::temp_dir().join(format!("log-{format:?}-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let daemon = UnixDatagram::bind(&path).unwrap();
+    let layer = SocketLayer::connect(format, &path).unwrap();
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+      tracing::warn!(
+        target: "upstream",
+        upstream = "cloudflare",
+        failures = 3,
+        "Upstream is unhealthy\nsince 2 probes"
+      )
+    });
+    let mut buffer = [0; 4096];
+    let length = daemon.recv(&mut buffer).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    buffer[..length].to_vec()
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn syslog() {
+    let message = String::from_utf8(receive(Format::Syslog)).unwrap();
+    assert!(message.starts_with("<28>"), "{message}");
+    assert!(message.contains(&format!(" dns-server[{}]: upstream: ", std::process::id())));
+    assert!(
+      message.ends_with("Upstream is unhealthy\nsince 2 probes upstream=cloudflare failures=3")
+    );
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn journald() {
+    let message = receive(Format::Journald);
+    let text = String::from_utf8_lossy(&message);
+    let mut multiline = b"MESSAGE\n".to_vec();
+    multiline.extend_from_slice(&36u64.to_le_bytes());
+    multiline.extend_from_slice(b"Upstream is unhealthy\nsince 2 probes\n");
+    assert!(message.starts_with(&multiline));
+    assert!(text.contains("\nPRIORITY=4\nSYSLOG_IDENTIFIER=dns-server\nTARGET=upstream\n"));
+    assert!(text.ends_with("\nUPSTREAM=cloudflare\nFAILURES=3\n"));
+    assert_eq!(journald_name("query.name"), "QUERY_NAME");
+    assert_eq!(journald_name("_private"), "F_PRIVATE");
+  }
+}
@@ -1,71 +1,308 @@
-use crate::authority::{BlacklistAuthority, NoneAuthority};
-use crate::client::*;
-use clap::{builder::ArgPredicate, Parser};
-use hickory_server::{
-  authority::Catalog,
-  proto::rr::LowerName,
-  proto::rustls::tls_server::{read_cert, read_key},
-  resolver::Name,
-  ServerFuture,
+use anyhow::{bail, Context, Result};
+use clap::{builder::ArgPredicate, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use dns_server::acl::{self, Acl, DenyAction, ListenerAcl};
+use dns_server::acme::{self, AcmeOptions};
+use dns_server::authority::{
+  canary_domains, doh_servers, local_only_zones, DnssecZone, LocalDns, LocalSource, Policy,
+  ResponsePolicyZone, Rewrite, RewriteAction, SecondaryAuthority, SecondaryZone, SyntheticZone,
+  ZoneFile, DEFAULT_BLOCK_TTL, DEFAULT_LOCAL_TTL, SYSTEM_HOSTS,
 };
-use ip::{IpRange, IpRangeVec};
-use std::collections::HashSet;
+use dns_server::bench::{self, BenchOptions, BenchReport};
+use dns_server::cache::{self, RedisUrl};
+use dns_server::category::BlacklistFile;
+use dns_server::client::bind::{Binding, UpstreamBind};
+use dns_server::client::ecs::Ecs;
+use dns_server::client::proxy::Proxy;
+use dns_server::client::*;
+use dns_server::config;
+use dns_server::control::{self, Control};
+use dns_server::doh::Credential;
+use dns_server::domain_set::DomainSet;
+use dns_server::events::{
+  AuditLog, Dnstap, DnstapOutput, LogPrivacy, LogQueries, QueryDb, QueryLog,
+};
+use dns_server::filter_list::FilterList;
+use dns_server::geoip::{self, GeoIp};
+use dns_server::group::{ClientGroup, ForwardZone, GroupPolicy, UpstreamRoute};
+#[cfg(unix)]
+use dns_server::handler::CatalogHandle;
+use dns_server::healthcheck;
+use dns_server::import;
+use dns_server::ip::{IpRange, IpRangeVec};
+use dns_server::logging::LogOutput;
+use dns_server::nat64::{self, Nat64, Nat64Prefix};
+use dns_server::padding::Padding;
+#[cfg(unix)]
+use dns_server::privileges::Account;
+use dns_server::query::{self, TlsClient, Transport};
+use dns_server::rrl::RrlOptions;
+#[cfg(target_os = "linux")]
+use dns_server::sandbox::Sandbox;
+use dns_server::schedule::ScheduledList;
+use dns_server::service::{self, Service};
+use dns_server::systemd::ActivatedSockets;
+use dns_server::tls::TlsVersion;
+use dns_server::tsig::{self, TsigKey};
+use dns_server::upstream::Strategy;
+use dns_server::warmup;
+use dns_server::web::BlockPage;
+use dns_server::DnsServerBuilder;
+use hickory_server::authority::Authority;
+use hickory_server::proto::rr::{LowerName, Name};
+use serde::{Deserialize, Serialize};
 use std::io::Read;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::{
-  net::{TcpListener, UdpSocket},
-  runtime,
-};
-use tokio_graceful::Shutdown;
-use tracing::info;
+use std::time::{Duration, Instant};
+use tokio::runtime;
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-pub mod authority;
-pub mod client;
-pub mod ip;
+#[cfg(unix)]
+const DEFAULT_CONTROL_SOCKET: &str = "/run/dns-server.sock";
 
 /// Create a DNS server you can configure to block some domain and zones. You can use UDP or DNS over TLS/TCP (DoT) or DNS over HTTPS/H2 (DoH) as listeners (frontend) and resolver (backend).
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 #[structopt(name = "dns-server", author, version, about)]
+#[serde(rename_all = "kebab-case")]
 pub struct DNSServer {
+  /// Configuration file (TOML or YAML) using the long option names as keys. Options given on the command line take precedence.
+  #[arg(long = "config", short = 'c', env = "DNS_SERVER_CONFIG")]
+  #[serde(skip)]
+  config: Option<PathBuf>,
   /// Listen port of the classic DNS server over UDP.
   #[arg(long = "port", short = 'p', default_value = "53")]
   port: u16,
   /// Listen adress of the server.
   #[arg(long = "listen", short = 'l', default_value = "0.0.0.0")]
   listen: String,
+  /// Listen address of the classic DNS server over UDP and TCP instead of `--listen`, can be repeated. The port is `--port` unless given (`192.168.1.1:5353`, `[::1]:53`).
+  #[arg(long = "listen-udp")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  listen_udp: Vec<String>,
+  /// Listen address of the Dns over TLS (TCP) server instead of `--listen`, can be repeated. The port is `--tls-port` unless given. Activates the server.
+  #[arg(long = "listen-tls")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  listen_tls: Vec<String>,
+  /// Listen address of the https/h2 server instead of `--listen`, can be repeated. The port is `--h2-port` unless given. Activates the server.
+  #[arg(long = "listen-h2")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  listen_h2: Vec<String>,
   /// Number of workers to setup
   #[arg(long = "workers", default_value = "4")]
+  #[serde(rename = "workers")]
   worker: usize,
-  /// File containing a list of exact domains to block.
+  /// File containing a list of exact domains to block, can be repeated. Prefix the path with a category name (`ads=/etc/dns-server/ads.txt`) to enable or disable the list at runtime and count its blocked queries separately.
   #[arg(long = "blacklist")]
-  blacklist: Option<PathBuf>,
+  #[serde(deserialize_with = "config::one_or_many")]
+  blacklist: Vec<BlacklistFile>,
+  /// Filter list in the AdGuard Home syntax, checked before the blacklists, can be repeated: `||example.com^` blocks a zone, `@@` exception rules unblock the names of every list, `$important` rules win over exceptions and `$client=192.168.1.0/24|~192.168.1.1` restricts a rule to some clients.
+  #[arg(long = "filter-list")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  filter_list: Vec<PathBuf>,
   /// Default IP address to return when the domain is blocked instead of an empty NoError response.
   #[arg(long = "default-ip")]
   default_ip: Option<Ipv4Addr>,
+  /// TTL in seconds of the `--default-ip` answers of the blocked domains, and of the SOA added to their empty and NXDOMAIN responses so the clients cache them as long.
+  #[arg(long = "block-ttl", default_value_t = DEFAULT_BLOCK_TTL)]
+  block_ttl: u32,
+  /// Name server of the SOA and NS records answered for the blocked names, the local names and the zones of the dynamic updates, which are not delegated to this server.
+  #[arg(long = "synthetic-ns", default_value = "localhost")]
+  synthetic_ns: String,
+  /// Mailbox of the SOA records of `--synthetic-ns`, `hostmaster.example.com` for hostmaster@example.com.
+  #[arg(long = "synthetic-hostmaster", default_value = "nobody.localhost")]
+  synthetic_hostmaster: String,
+  /// Serve a page telling that the domain is blocked, and by which list, on port 80 of `--default-ip`, which must be an IP of this host. It is served on port 443 too with the certificate of the TLS listeners, which browsers warn about.
+  #[arg(long = "block-page")]
+  block_page: bool,
+  /// HTML template of `--block-page`, `{domain}` and `{list}` are replaced by the blocked domain and its list.
+  #[arg(long = "block-page-template")]
+  block_page_template: Option<PathBuf>,
   /// File containing a list of zone of domains to block, this will block the domain and all subdomains.
   #[arg(long = "zone-blacklist")]
   zone_blacklist: Option<PathBuf>,
-  /// Setup your trusted dns resolver, could be cloudflare or google with UDP, TLS or H2. The port is optional when you are using custom IP. When you use TLS or H2 protocols, you must add the domain name too.
+  /// File containing a list of zones unblocked from the zone blacklists with all their subdomains, e.g. `graph.facebook.com` while `facebook.com` is blocked. The blacklists of exact domains still apply.
+  #[arg(long = "zone-blacklist-exceptions")]
+  zone_blacklist_exceptions: Option<PathBuf>,
+  /// Block well-known public DNS over HTTPS resolvers (e.g. dns.google, cloudflare-dns.com) and answer NXDOMAIN to the canary domains of the browsers (use-application-dns.net), so clients can't bypass the blacklists, unless blocking is disabled.
+  #[arg(long = "block-doh-bypass")]
+  block_doh_bypass: bool,
+  /// File containing a list of zones of DNS over HTTPS resolvers replacing the built-in list of `--block-doh-bypass`.
+  #[arg(long = "doh-bypass-list")]
+  doh_bypass_list: Option<PathBuf>,
+  /// Zone answered NXDOMAIN instead of being forwarded to the dns servers, unless its names are local DNS names, a zone file or a response policy. Can be repeated, `default` is the reverse zones of the private addresses (RFC 6303), `home.arpa` and `local`, `none` forwards them all.
+  #[arg(long = "local-only-zones", default_value = "default")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  local_only_zones: Vec<String>,
+  /// Answer the AAAA queries with NODATA while still forwarding A, for networks where IPv6 is advertised but broken. Without value every name is filtered, otherwise only the given zones: `--filter-aaaa=example.com,corp`.
+  #[arg(long = "filter-aaaa", num_args = 0..=1, require_equals = true, value_delimiter = ',', default_missing_value = ".")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  filter_aaaa: Vec<String>,
+  /// Answer Google, YouTube, Bing and DuckDuckGo with their safe search servers (e.g. forcesafesearch.google.com), unless blocking is disabled.
+  #[arg(long = "force-safe-search")]
+  force_safe_search: bool,
+  /// File containing a list of IPs, networks (CIDR) or ranges (`first-last`), answers resolving to one of them are blocked like a blacklisted domain.
+  #[arg(long = "ip-blacklist")]
+  ip_blacklist: Option<PathBuf>,
+  /// File containing a list of zone of domains to block only during the given local time windows, can be repeated: `path@HH:MM-HH:MM[,HH:MM-HH:MM...]`.
+  #[arg(long = "scheduled-zone-blacklist")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  scheduled_zone_blacklist: Vec<ScheduledList>,
+  /// Response Policy Zone file (RPZ) applied before the blacklists, can be repeated, the first zone with a matching trigger wins. Only QNAME triggers are supported.
+  #[arg(long = "rpz")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  rpz: Vec<PathBuf>,
+  /// Rewrite rule applied after the local DNS, even when blocking is disabled, can be repeated: `name=old.example.com;to=new.example.com` answers the name of `to` behind a CNAME, `name=*.corp;answer=ip[,ip...]` answers these IPs, also for the CNAME targets of forwarded answers. The first matching rule wins.
+  #[arg(long = "rewrite")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  rewrite: Vec<Rewrite>,
+  /// Local zone answered authoritatively from a BIND zone file, can be repeated: `zone:path` (e.g. `example.lan:/etc/dns/db.example.lan`). The file must contain the SOA of the zone.
+  #[arg(long = "zone-file")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  zone_file: Vec<ZoneFile>,
+  /// Zone of a `--zone-file` signed with DNSSEC, can be repeated: `zone[:path]` of its private key, a PKCS#8 ECDSA P-256, P-384 or Ed25519 key in PEM created on the first start when the file does not exist. Without path, a new key is generated on each start. The DS record of the key is logged.
+  #[arg(long = "dnssec-sign-zone")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  dnssec_sign_zone: Vec<DnssecZone>,
+  /// Zone transferred from a primary and answered authoritatively, can be repeated: `zone:ip[:port][;key=name]` (e.g. `example.com:192.0.2.1`). It is transferred with AXFR, then refreshed with IXFR according to its SOA timers, its queries are answered SERVFAIL until the first transfer and once it expired. With `key`, the transfers are signed with this `--tsig-key`.
+  #[arg(long = "secondary-zone")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  secondary_zone: Vec<SecondaryZone>,
+  /// Key shared with other DNS servers to sign zone transfers and dynamic updates (TSIG), can be repeated: `name:algorithm:secret` with the secret in base64 like in BIND (e.g. `transfer-key:hmac-sha256:c2VjcmV0`). The algorithms are hmac-sha1, hmac-sha256, hmac-sha384 and hmac-sha512. Dynamic updates are verified against the update encoded again like `nsupdate` does, signers compressing the names differently are refused with BADSIG.
+  #[arg(long = "tsig-key")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  tsig_key: Vec<TsigKey>,
+  /// File of local names answered before the blacklists: `ip name...` lines like /etc/hosts, `txt name "text"`, `mx name preference exchange` and `srv name priority weight port target`, prefixed with `@network,...` to answer these clients only. Can be repeated, the first file defining a type for a name wins.
+  #[arg(long = "local-dns")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  local_dns: Vec<PathBuf>,
+  /// Rotate the order of the IPs of local names having several of them on each query.
+  #[arg(long = "local-dns-rotate")]
+  local_dns_rotate: bool,
+  /// TTL in seconds of the answers of the local names, from the `--local-dns`, `--dhcp-leases` and system hosts files.
+  #[arg(long = "local-ttl", default_value_t = DEFAULT_LOCAL_TTL)]
+  local_ttl: u32,
+  /// Rotate the order of the IPs of forwarded and cached answers having several of them on each query, so clients spread their connections across multi-IP services.
+  #[arg(long = "rotate-answers")]
+  rotate_answers: bool,
+  /// Redis server sharing the cached answers between instances, the memory cache is checked first: `redis://[[user]:password@]host[:port][/db]`.
+  #[arg(long = "cache-backend")]
+  cache_backend: Option<RedisUrl>,
+  /// Largest number of cached answers, the least recently used ones are evicted first. `0` disables the cache.
+  #[arg(long = "cache-max-entries", default_value_t = cache::DEFAULT_MAX_ENTRIES)]
+  cache_max_entries: usize,
+  /// Approximate memory of the cached answers, in bytes or with a K, M or G unit (e.g. `16M`). The least recently used answers are evicted first.
+  #[arg(long = "cache-max-memory")]
+  cache_max_memory: Option<String>,
+  /// File of domains, one per line, resolved through the first UDP listener at startup and after each cache flush, so their answers are always cached.
+  #[arg(long = "warmup-list")]
+  warmup_list: Option<PathBuf>,
+  /// Zone of local names accepting dynamic updates (RFC 2136, e.g. from a DHCP server), can be repeated. Updated names are written to the first `--local-dns` file.
+  #[arg(long = "local-dns-update-zone")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  local_dns_update_zone: Vec<String>,
+  /// Network allowed to send dynamic updates, can be repeated.
+  #[arg(long = "local-dns-update-from", default_values = ["127.0.0.0/8", "::1/128"])]
+  #[serde(deserialize_with = "config::one_or_many")]
+  local_dns_update_from: Vec<String>,
+  /// Name of a `--tsig-key` the dynamic updates must be signed with, can be repeated. Unsigned updates are refused.
+  #[arg(long = "local-dns-update-key")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  local_dns_update_key: Vec<String>,
+  /// Lease file of a DHCP server (dnsmasq, ISC dhcpd or Kea CSV) whose hostnames are answered with their IP, after the `--local-dns` files. Can be repeated.
+  #[arg(long = "dhcp-leases")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  dhcp_leases: Vec<PathBuf>,
+  /// Domain added to the DHCP hostnames without a dot (e.g. `lan` answers `laptop.lan`).
+  #[arg(long = "dhcp-domain")]
+  dhcp_domain: Option<String>,
+  /// Answer the names of the system hosts file too, after the `--local-dns` and `--dhcp-leases` files.
+  #[arg(long = "use-system-hosts")]
+  use_system_hosts: bool,
+  /// Interval between the checks of the local DNS file, changes are also applied within seconds on systems notifying file changes. `0` disables the periodic checks.
+  #[arg(long = "local-dns-refresh", default_value = "5m")]
+  local_dns_refresh: String,
+  /// Answer the ANY queries with a minimal HINFO record (RFC 8482) instead of forwarding them, they are mostly used for amplification attacks.
+  #[arg(long = "refuse-any")]
+  refuse_any: bool,
+  /// Largest UDP response sent to the EDNS clients, larger responses are truncated so the clients retry over TCP. Clients without EDNS get at most 512 bytes.
+  #[arg(long = "edns-udp-size", default_value = "1232", value_parser = clap::value_parser!(u16).range(512..=4096))]
+  edns_udp_size: u16,
+  /// Padding of the responses sent over DoT and DoH to the clients asking for it (RFC 7830), so their size does not reveal the queried names: `block-size:size` pads them to a multiple of `size` bytes, `none` disables it.
+  #[arg(long = "padding", default_value = "block-size:468")]
+  padding: Padding,
+  /// Setup your trusted dns resolver, could be cloudflare, google, quad9, adguard or opendns with UDP, TLS or H2 (opendns has no TLS). The port is optional when you are using custom IP. When you use TLS or H2 protocols, you must add the domain name too. Can be repeated, queries go to the first healthy one.
   #[arg(long = "dns-server", default_value = "cloudflare:h2")]
-  dns_server: ClientType,
+  #[serde(deserialize_with = "config::one_or_many")]
+  dns_server: Vec<ClientType>,
+  /// Resolve the queries from the root servers instead of forwarding them to `--dns-server`. Client groups with their own dns server still forward their queries.
+  #[arg(long = "recursive")]
+  recursive: bool,
+  /// How queries are distributed across the dns servers: first, round-robin, random or fastest (lowest average latency).
+  #[arg(long = "upstream-strategy", default_value = "first")]
+  upstream_strategy: Strategy,
+  /// Send each query to two dns servers at the same time and answer with the first successful response.
+  #[arg(long = "upstream-race")]
+  upstream_race: bool,
+  /// Interval between the health checks of the dns servers, unhealthy ones do not receive queries until they answer again. `0` disables the health checks.
+  #[arg(long = "upstream-health-check", default_value = "30s")]
+  upstream_health_check: String,
+  /// Time to wait for the answer of a dns server before the query is sent again or fails (e.g. 500ms, 2s).
+  #[arg(long = "upstream-timeout", default_value = "5s")]
+  upstream_timeout: String,
+  /// Number of times a query is sent to a dns server before it fails, raise it on lossy links.
+  #[arg(long = "upstream-attempts", default_value = "2", value_parser = clap::value_parser!(u8).range(1..))]
+  upstream_attempts: u8,
+  /// PEM file of the certificate authorities trusted for TLS, H2 and QUIC dns servers, beside the system ones.
+  #[arg(long = "upstream-ca")]
+  upstream_ca: Option<PathBuf>,
+  /// SHA-256 of a public key (SPKI, base64, optionally prefixed by `sha256/`) of the TLS, H2 and QUIC dns servers certificates, can be repeated. Pinned certificates are only checked against the certificate authorities when `--upstream-ca` is set.
+  #[arg(long = "upstream-pin")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  upstream_pin: Vec<String>,
+  /// Proxy of the connections to the dns servers: `socks5://[user:password@]host:port` or `http://[user:password@]host:port` (HTTP CONNECT). UDP dns servers are then queried over TCP, QUIC ones are not proxied.
+  #[arg(long = "upstream-proxy")]
+  upstream_proxy: Option<Proxy>,
+  /// Randomize the case of the query names sent to UDP dns servers (0x20) and ignore the responses without the same case, so spoofed responses must also guess it. Dns servers not repeating the case of the queries get them as is.
+  #[arg(long = "upstream-randomize-case")]
+  upstream_randomize_case: bool,
+  /// Source address or network interface of the traffic to the dns servers and of the recursive queries, can be repeated for an IPv4 address, an IPv6 address and an interface (Linux only, e.g. `wg0`).
+  #[arg(long = "upstream-bind")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  upstream_bind: Vec<UpstreamBind>,
+  /// Answer SERVFAIL with an Extended DNS Error instead of querying the dns servers while the interface of `--upstream-bind` is down or removed, so DNS never leaks outside a VPN. Cached answers are still sent.
+  #[arg(long = "upstream-kill-switch")]
+  upstream_kill_switch: bool,
+  /// EDNS Client Subnet sent to the dns servers: `strip` never sends one, `forward` sends the one of the client query (answers are then not cached), `set:<prefix>` always sends this prefix (e.g. `set:203.0.113.0/24`).
+  #[arg(long = "ecs", default_value = "strip")]
+  ecs: Ecs,
+  /// Resolver used to find the IPs of `https://` dns servers, it must not be an `https://` dns server itself.
+  #[arg(long = "bootstrap-dns-server", default_value = "cloudflare")]
+  bootstrap_dns_server: ClientType,
   /// Activate https/h2 server beside classic DNS server over UDP.
   #[arg(
     long = "h2",
-    default_value_if("h2_port", ArgPredicate::IsPresent, Some("true"))
+    default_value_ifs([
+      ("h2_port", ArgPredicate::IsPresent, Some("true")),
+      ("listen_h2", ArgPredicate::IsPresent, Some("true")),
+    ])
   )]
   h2: bool,
   /// Listen port of the https/h2 server.
   #[arg(long = "h2-port", default_value("443"))]
   h2_port: u16,
+  /// Credential required on the https/h2 requests, others are answered 401 Unauthorized: a token sent as `Authorization: Bearer <token>`, or `user:password` for the basic authentication.
+  #[arg(long = "h2-auth-token")]
+  h2_auth_token: Option<Credential>,
   /// Activate DNS over TLS (TCP) server beside classic DNS server over UDP.
   #[arg(
     long = "tls",
-    default_value_if("tls_port", ArgPredicate::IsPresent, Some("true"))
+    default_value_ifs([
+      ("tls_port", ArgPredicate::IsPresent, Some("true")),
+      ("listen_tls", ArgPredicate::IsPresent, Some("true")),
+    ])
   )]
   tls: bool,
   /// Listen port of the Dns over TLS (TCP) server.
@@ -77,14 +314,432 @@ pub struct DNSServer {
   /// Path of the private key for the https/h2 server.
   #[arg(long = "tls-private-key")]
   tls_private_key: Option<PathBuf>,
-  /// IP using Local-Use IPv4/IPv6 Translation Prefix (rfc8215).
+  /// PEM file of the certificate authorities of the client certificates. When set, the TLS and https/h2 servers only answer clients presenting a certificate signed by one of them.
+  #[arg(long = "tls-client-ca")]
+  tls_client_ca: Option<PathBuf>,
+  /// Oldest TLS version accepted by the TLS and https/h2 servers: 1.2 or 1.3.
+  #[arg(long = "tls-min-version", default_value = "1.2")]
+  tls_min_version: TlsVersion,
+  /// Cipher suite allowed on the TLS and https/h2 servers (e.g. `TLS13_AES_256_GCM_SHA384` or `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`), can be repeated. All the suites supported are allowed by default.
+  #[arg(long = "tls-cipher-suite")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  tls_cipher_suite: Vec<String>,
+  /// Domain of a certificate obtained and renewed automatically from Let's Encrypt (ACME) for the TLS and https/h2 servers, instead of `--tls-certificate`. Can be repeated, the domains must resolve to this server which must be reachable on `--acme-http-port` to answer the HTTP-01 challenges.
+  #[arg(long = "acme-domain")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  acme_domain: Vec<String>,
+  /// Contact email of the ACME account, warned by the certificate authority about expiring certificates.
+  #[arg(long = "acme-email")]
+  acme_email: Option<String>,
+  /// Directory URL of the ACME certificate authority, e.g. `https://acme-staging-v02.api.letsencrypt.org/directory` to test with the staging environment of Let's Encrypt.
+  #[arg(long = "acme-directory", default_value = acme::LETS_ENCRYPT)]
+  acme_directory: String,
+  /// Directory where the ACME account key, the certificate and its private key are kept.
+  #[arg(long = "acme-storage", default_value = "/var/lib/dns-server/acme")]
+  acme_storage: PathBuf,
+  /// Listen port of the HTTP server answering the ACME HTTP-01 challenges, the certificate authority connects to port 80.
+  #[arg(long = "acme-http-port", default_value = "80")]
+  acme_http_port: u16,
+  /// Network allowed to query the server, can be repeated. Every client is allowed when no network is given.
+  #[arg(long = "allow-networks")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  allow_networks: Vec<String>,
+  /// Network denied to query the server, even when it is in an allowed network. Can be repeated.
+  #[arg(long = "deny-networks")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  deny_networks: Vec<String>,
+  /// MaxMind database (GeoLite2 Country or City, `.mmdb`) locating the clients for `--allow-countries`, `--deny-countries` and the `countries` of the client groups.
+  #[arg(long = "geoip-database")]
+  geoip_database: Option<PathBuf>,
+  /// Interval between the checks for a new GeoIP database, `0` disables them.
+  #[arg(long = "geoip-refresh", default_value = "1h")]
+  geoip_refresh: String,
+  /// Country allowed to query the server (ISO code, e.g. `FR,BE`), can be repeated. Clients missing from the GeoIP database, like the private networks, are not filtered by country.
+  #[arg(long = "allow-countries", value_delimiter = ',')]
+  #[serde(deserialize_with = "config::one_or_many")]
+  allow_countries: Vec<String>,
+  /// Country denied to query the server, even when it is allowed. Can be repeated.
+  #[arg(long = "deny-countries", value_delimiter = ',')]
+  #[serde(deserialize_with = "config::one_or_many")]
+  deny_countries: Vec<String>,
+  /// Networks of a listener replacing `--allow-networks` and `--deny-networks`, can be repeated: `listener:allow=cidr[,cidr...][;deny=cidr[,cidr...]]` where listener is udp, tls or h2. An unset list is inherited.
+  #[arg(long = "listener-acl")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  listener_acl: Vec<ListenerAcl>,
+  /// What is done with the requests of denied clients: `drop` them (clients time out) or answer `refused`.
+  #[arg(long = "deny-action", default_value = "drop")]
+  deny_action: DenyAction,
+  /// Maximum number of identical answers (same name and response code) sent per second over UDP to a client network, like BIND Response Rate Limiting. `0` disables the limit.
+  #[arg(long = "rrl-responses-per-second", default_value = "0")]
+  rrl_responses_per_second: u32,
+  /// How long the answers over the rate are remembered, a client keeps being limited until its rate stayed low during this window.
+  #[arg(long = "rrl-window", default_value = "15s")]
+  rrl_window: String,
+  /// One answer over the rate out of `slip` is sent empty and truncated so genuine clients retry over TCP, the others are dropped. `0` drops them all.
+  #[arg(long = "rrl-slip", default_value = "2")]
+  rrl_slip: u32,
+  /// Prefix length of the IPv4 clients sharing a rate limit.
+  #[arg(long = "rrl-ipv4-prefix", default_value = "24", value_parser = clap::value_parser!(u8).range(0..=32))]
+  rrl_ipv4_prefix: u8,
+  /// Prefix length of the IPv6 clients sharing a rate limit.
+  #[arg(long = "rrl-ipv6-prefix", default_value = "56", value_parser = clap::value_parser!(u8).range(0..=128))]
+  rrl_ipv6_prefix: u8,
+  /// File of the clients using Local-Use IPv4/IPv6 Translation Prefix (rfc8215): IPs, networks (CIDR) or ranges (`first-last`), one per line.
   #[arg(long = "rfc8215-ips")]
   rfc8215_ips: Option<PathBuf>,
+  /// NAT64 prefix of the IPv6 synthesized for the `--rfc8215-ips` clients, `ipv6/length` with a length of 32, 40, 48, 56, 64 or 96 (RFC 6052). Can be repeated with `@cidr[,cidr...]` to use a prefix for these clients only, e.g. `64:ff9b:1:a::/96@10.0.0.0/8`.
+  #[arg(long = "nat64-prefix", default_value = nat64::WELL_KNOWN_PREFIX)]
+  #[serde(deserialize_with = "config::one_or_many")]
+  nat64_prefix: Vec<Nat64Prefix>,
+  /// Network of the AAAA records ignored for the `--rfc8215-ips` clients, an IPv6 is synthesized when a name has no other one (RFC 6147). Can be repeated.
+  #[arg(long = "dns64-exclude", default_value = nat64::EXCLUDED_PREFIX)]
+  #[serde(deserialize_with = "config::one_or_many")]
+  dns64_exclude: Vec<String>,
+  /// File where each query is logged as a JSON line (timestamp, client, protocol, qname, qtype, rcode, latency, action, upstream).
+  #[arg(long = "query-log")]
+  query_log: Option<PathBuf>,
+  /// SQLite database where each query is stored, its statistics are shown in the web dashboard.
+  #[arg(long = "query-db")]
+  query_db: Option<PathBuf>,
+  /// How long queries are kept in the database (e.g. 12h, 7d, 30d).
+  #[arg(long = "query-db-retention", default_value = "7d")]
+  query_db_retention: String,
+  /// Queries written in the query log, the query database, the history of the web dashboard and dnstap: none, blocked or all.
+  #[arg(long = "log-queries", default_value = "all")]
+  log_queries: LogQueries,
+  /// Prefix length the IPv4 clients are truncated to in the query log, the query database, the history of the web dashboard and dnstap (e.g. 24), their port is then removed.
+  #[arg(long = "log-ipv4-prefix", default_value = "32", value_parser = clap::value_parser!(u8).range(0..=32))]
+  log_ipv4_prefix: u8,
+  /// Prefix length the IPv6 clients are truncated to in the query log, the query database, the history of the web dashboard and dnstap (e.g. 56), their port is then removed.
+  #[arg(long = "log-ipv6-prefix", default_value = "128", value_parser = clap::value_parser!(u8).range(0..=128))]
+  log_ipv6_prefix: u8,
+  /// Replace the query names by the start of their SHA-256 in the query log, the query database, the history of the web dashboard and dnstap (without the messages).
+  #[arg(long = "log-hash-names")]
+  log_hash_names: bool,
+  /// Secret key of the hashes of the query names, an HMAC-SHA256 is used instead of the SHA-256 so the names cannot be guessed without it.
+  #[arg(long = "log-hash-salt", requires = "log_hash_names")]
+  log_hash_salt: Option<String>,
+  /// Unix socket of a dnstap collector receiving client queries and responses (Frame Streams).
+  #[cfg(unix)]
+  #[arg(long = "dnstap-socket", conflicts_with = "dnstap_file")]
+  dnstap_socket: Option<PathBuf>,
+  /// File where client queries and responses are written as dnstap frames, it is truncated when opened on startup or after a write error.
+  #[arg(long = "dnstap-file")]
+  dnstap_file: Option<PathBuf>,
+  /// Client group with its own policy, can be repeated: `name=cidr[,cidr...]` followed by optional `;blacklist=path`, `;zone-blacklist=path`, `;default-ip=ip`, `;dns-server=resolver` and `;countries=code[,code...]` (with `--geoip-database`). Unset options are inherited.
+  #[arg(long = "client-group")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  client_group: Vec<ClientGroup>,
+  /// Upstream of the clients of some networks in every client group, can be repeated: `cidr[,cidr...]->resolver` (e.g. `192.168.2.0/24->9.9.9.9:tls:dns.quad9.net`). The first route containing the client is used.
+  #[arg(long = "upstream-route")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  upstream_route: Vec<UpstreamRoute>,
+  /// Upstream of the names of some zones, for every client, can be repeated: `zone[,zone...]->resolver` (e.g. `lan,168.192.in-addr.arpa->192.168.1.1`). The most specific zone is used, and local-only zones are forwarded too.
+  #[arg(long = "forward-zone")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  forward_zone: Vec<ForwardZone>,
+  /// Where the logs are written: stderr, syslog, journald (with the fields of the events as journal fields), eventlog (Windows) or file:<path>.
+  #[arg(long = "log-output", default_value = "stderr")]
+  log_output: LogOutput,
+  /// Interval between the statistics summaries written in the logs (e.g. 10m, 1h), they are also written on SIGUSR1. `0` disables the periodic summary.
+  #[arg(long = "stats-interval", default_value = "0")]
+  stats_interval: String,
+  /// Maximum number of seconds to wait for in-flight queries on SIGTERM or Ctrl-C.
+  #[arg(long = "shutdown-timeout", default_value = "5")]
+  shutdown_timeout: u64,
+  /// Unix socket receiving the commands of `dns-server ctl`.
+  #[cfg(unix)]
+  #[arg(long = "control-socket", env = "DNS_SERVER_CONTROL_SOCKET")]
+  control_socket: Option<PathBuf>,
+  /// File where each change of the running server is appended as a JSON line (timestamp, source, change): commands of the control socket, changes from the web dashboard, reloads and cache flushes on signals.
+  #[arg(long = "audit-log")]
+  audit_log: Option<PathBuf>,
+  /// Network of the load balancers sending a PROXY protocol header (v1 or v2) on the TCP, TLS and H2 connections, can be repeated. Their queries are answered as coming from the client in the header.
+  #[arg(long = "proxy-protocol")]
+  #[serde(deserialize_with = "config::one_or_many")]
+  proxy_protocol: Vec<String>,
+  /// User (name or uid) the server runs as once its listeners are bound, to start as root and bind privileged ports.
+  #[cfg(unix)]
+  #[arg(long = "user")]
+  user: Option<String>,
+  /// Group (name or gid) the server runs as once its listeners are bound, the primary group of `--user` by default.
+  #[cfg(unix)]
+  #[arg(long = "group")]
+  group: Option<String>,
+  /// Restrict the server once started, after its privileges are dropped (Linux only): Landlock limits the filesystem to the files of the options and the system files the server reads, a seccomp filter refuses the system calls it never needs (running programs, debugging, mounting...).
+  #[arg(long = "sandbox")]
+  sandbox: bool,
+  /// Address of the web dashboard (e.g. 127.0.0.1:8080) showing the last queries and statistics. It has no authentication, only expose it on a trusted network.
+  #[arg(long = "web-ui")]
+  web_ui: Option<SocketAddr>,
+  #[command(subcommand)]
+  #[serde(skip)]
+  command: Option<SubCommand>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SubCommand {
+  /// Manage a running server through its control socket.
+  #[cfg(unix)]
+  Ctl(Ctl),
+  /// Validate the options and load every file they use (lists, zones, certificates...) without binding any socket, then print a summary. Exits with 1 on the first error.
+  Check,
+  /// Send a query to a server and print its response, over UDP, TCP, DNS over TLS or DNS over HTTPS.
+  Query(QueryCommand),
+  /// Send queries at a constant rate to a server and report the latency percentiles and error rates.
+  Bench(Bench),
+  /// Load the lists of the server options and tell whether a name would be blocked, by which list and rule, and the response.
+  TestBlock(TestBlock),
+  /// Install the server as a launchd daemon (macOS), a systemd service (Linux) or a Windows service, with the server options given before `service`.
+  Service(ServiceCommand),
+  /// Check that the server answers a real lookup, for Docker `HEALTHCHECK` or Kubernetes probes. Exits with 1 when unhealthy.
+  Healthcheck(Healthcheck),
+  /// Convert the configuration of another DNS server into a configuration file and the lists it uses, to ease the migration.
+  Import(ImportCommand),
+}
+
+#[derive(Args, Debug, Clone)]
+struct QueryCommand {
+  /// Name to look up.
+  name: Name,
+  /// Type of the records (e.g. A, AAAA, MX, 65).
+  #[arg(default_value = "A")]
+  record_type: String,
+  #[command(flatten)]
+  target: QueryTarget,
+  /// Time to wait for the answer (e.g. 5, 1m).
+  #[arg(long = "timeout", default_value = "5")]
+  timeout: String,
+}
+
+/// Server and protocol of the `query` and `bench` subcommands.
+#[derive(Args, Debug, Clone)]
+struct QueryTarget {
+  /// Address of the server, defaults to the listener of the protocol in the server options.
+  #[arg(long = "server", short = 's')]
+  server: Option<SocketAddr>,
+  /// Protocol of the query: udp (retried over TCP when truncated), tcp, tls or h2.
+  #[arg(long = "protocol", default_value = "udp")]
+  protocol: Transport,
+  /// Name checked in the certificate of the server, defaults to the first `--acme-domain` of the server options or the IP of the server.
+  #[arg(long = "tls-name")]
+  tls_name: Option<String>,
+  /// CA (PEM) of the certificate of the server, trusted in addition to the system roots.
+  #[arg(long = "ca")]
+  ca: Option<PathBuf>,
+  /// SHA-256 of a public key of the certificate chain of the server, like `--upstream-pin`. Can be repeated.
+  #[arg(long = "pin")]
+  pin: Vec<String>,
+  /// Accept any certificate from the server.
+  #[arg(long = "insecure", short = 'k')]
+  insecure: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+struct Bench {
+  /// Names to query in turn, when `--queries` is not given.
+  #[arg(default_value = "example.com")]
+  names: Vec<Name>,
+  /// Type of the records of the names.
+  #[arg(long = "type", default_value = "A")]
+  record_type: String,
+  /// File of queries in the dnsperf format, a name and an optional record type per line.
+  #[arg(long = "queries")]
+  queries: Option<PathBuf>,
+  /// Queries sent per second.
+  #[arg(long = "qps", default_value_t = 100)]
+  qps: u32,
+  /// Duration of the benchmark (e.g. 30, 5m).
+  #[arg(long = "duration", default_value = "10")]
+  duration: String,
+  /// Time after which a query without answer is a timeout (e.g. 2, 5s).
+  #[arg(long = "timeout", default_value = "2")]
+  timeout: String,
+  /// Start a server from the server options on a local UDP port and query it, instead of a running server.
+  #[arg(long = "in-process")]
+  in_process: bool,
+  #[command(flatten)]
+  target: QueryTarget,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ServiceCommand {
+  #[command(subcommand)]
+  action: ServiceAction,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ServiceAction {
+  /// Install and start the service, it is then started at boot. Paths in the server options must be absolute.
+  Install {
+    /// Label of the launchd daemon or name of the systemd unit or of the Windows service.
+    #[arg(long = "label", default_value = service::DEFAULT_LABEL)]
+    label: String,
+    /// Print the service definition instead of installing it.
+    #[arg(long = "print")]
+    print: bool,
+  },
+  /// Stop the service and remove it.
+  Uninstall {
+    /// Label of the launchd daemon or name of the systemd unit or of the Windows service.
+    #[arg(long = "label", default_value = service::DEFAULT_LABEL)]
+    label: String,
+  },
+  /// Run the server in the foreground, like without subcommand. This is what the service starts, on Windows it then answers the service control manager and logs to the event log by default.
+  Run,
+}
+
+#[derive(Args, Debug, Clone)]
+struct TestBlock {
+  /// Name to test.
+  name: Name,
+  /// IP of the client, the lists of its `--client-group` are used.
+  #[arg(long = "client")]
+  client: Option<IpAddr>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct Healthcheck {
+  /// Address of the server, defaults to the first UDP listener of the server options.
+  #[arg(long = "server", short = 's')]
+  server: Option<SocketAddr>,
+  /// Name to look up, its NS records are queried.
+  #[arg(long = "name", default_value = healthcheck::DEFAULT_NAME)]
+  name: Name,
+  /// Time to wait for the answer (e.g. 2, 5s).
+  #[arg(long = "timeout", default_value = "2")]
+  timeout: String,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ImportCommand {
+  /// Directory of the written `config.toml` and lists, use it with `--config`.
+  #[arg(long = "output", short = 'o', default_value = ".", global = true)]
+  output: PathBuf,
+  /// Replace the existing files.
+  #[arg(long = "force", global = true)]
+  force: bool,
+  #[command(subcommand)]
+  format: ImportFormat,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ImportFormat {
+  /// dnsmasq configuration: `address`, `server`, `local`, `host-record` and `cname` directives.
+  Dnsmasq { file: PathBuf },
+  /// Blocky YAML configuration: default upstreams, conditional forwarding, custom DNS mapping and local denylists.
+  Blocky { file: PathBuf },
+  /// Unbound configuration: `local-zone`, `local-data` and `forward-zone` statements.
+  Unbound { file: PathBuf },
+}
+
+#[cfg(unix)]
+#[derive(Args, Debug, Clone)]
+struct Ctl {
+  /// Control socket of the server, defaults to the `--control-socket` of the server options.
+  #[arg(long = "socket", short = 's')]
+  socket: Option<PathBuf>,
+  #[command(subcommand)]
+  action: CtlAction,
+}
+
+#[cfg(unix)]
+#[derive(Subcommand, Debug, Clone)]
+enum CtlAction {
+  /// Block a domain and all its subdomains until the server restarts.
+  Block { domain: String },
+  /// Remove a domain blocked with `ctl block`.
+  Unblock { domain: String },
+  /// Disable blocking for a while (e.g. 30s, 10m, 1h), 5 minutes by default.
+  Disable { duration: Option<String> },
+  /// Enable blocking again.
+  Enable,
+  /// Disable a blacklist category until the server restarts.
+  DisableCategory { category: String },
+  /// Enable a blacklist category again.
+  EnableCategory { category: String },
+  /// Flush the cached answers, only the ones of a name when it is given.
+  FlushCache { domain: Option<String> },
+  /// Show the query counters of the server.
+  Stats,
+}
+
+#[cfg(unix)]
+impl CtlAction {
+  fn command(&self) -> String {
+    match self {
+      CtlAction::Block { domain } => format!("block {domain}"),
+      CtlAction::Unblock { domain } => format!("unblock {domain}"),
+      CtlAction::Disable { duration: None } => "disable".to_string(),
+      CtlAction::Disable {
+        duration: Some(duration),
+      } => format!("disable {duration}"),
+      CtlAction::Enable => "enable".to_string(),
+      CtlAction::DisableCategory { category } => format!("disable-category {category}"),
+      CtlAction::EnableCategory { category } => format!("enable-category {category}"),
+      CtlAction::FlushCache { domain: None } => "flush-cache".to_string(),
+      CtlAction::FlushCache {
+        domain: Some(domain),
+      } => format!("flush-cache {domain}"),
+      CtlAction::Stats => "stats".to_string(),
+    }
+  }
 }
 
 fn main() {
-  logger();
-  let args = DNSServer::parse();
+  let args = DNSServer::parse_with_config();
+  let windows_service = cfg!(windows)
+    && matches!(
+      &args.command,
+      Some(SubCommand::Service(ServiceCommand {
+        action: ServiceAction::Run
+      }))
+    );
+  match &args.log_output {
+    // Services have no console, their logs go to the event log.
+    LogOutput::Stderr if windows_service => logger(&LogOutput::EventLog),
+    output => logger(output),
+  }
+  match &args.command {
+    #[cfg(unix)]
+    Some(SubCommand::Ctl(ctl)) => ctl.run(&args),
+    Some(SubCommand::Check) => check(&args),
+    Some(SubCommand::Query(query)) => query.run(&args),
+    Some(SubCommand::TestBlock(test)) => test.run(&args),
+    Some(SubCommand::Bench(bench)) => bench.run(&args),
+    Some(SubCommand::Service(service)) => service.run(),
+    Some(SubCommand::Healthcheck(healthcheck)) => healthcheck.run(&args),
+    Some(SubCommand::Import(import)) => import.run(),
+    None => {}
+  }
+  #[cfg(windows)]
+  if windows_service {
+    let stop_timeout = Duration::from_secs(args.shutdown_timeout);
+    let server_args = args.clone();
+    let status = service::windows::run(stop_timeout, move |stop| {
+      serve(server_args, async {
+        let _ = stop.await;
+      })
+    })
+    .unwrap_or_else(|err| panic!("could not run the service : {err:#}"));
+    // Not started by the service control manager, e.g. from a console.
+    if let Some(status) = status {
+      std::process::exit(status);
+    }
+  }
+  std::process::exit(serve(args, tokio_graceful::default_signal()));
+}
+
+/// Run the server until `stop` is done, returns the exit status.
+fn serve(args: DNSServer, stop: impl std::future::Future<Output = ()>) -> i32 {
+  #[cfg(unix)]
+  let account = (args.user.is_some() || args.group.is_some()).then(|| {
+    Account::lookup(args.user.as_deref(), args.group.as_deref())
+      .unwrap_or_else(|err| panic!("invalid user or group : {err:#}"))
+  });
+  // Before the runtime threads, the environment variables of systemd are removed.
+  let activated = ActivatedSockets::from_env()
+    .unwrap_or_else(|err| panic!("invalid sockets from systemd : {err:#}"));
 
   let runtime = runtime::Builder::new_multi_thread()
     .enable_all()
@@ -93,143 +748,1577 @@ fn main() {
     .build()
     .expect("failed to initialize Tokio Runtime");
 
-  let catalog = runtime.block_on(args.generate_catalog());
+  let status = runtime.block_on(async {
+    let mut server = args
+      .generate_builder(activated)
+      .await
+      .and_then(DnsServerBuilder::build)
+      .unwrap_or_else(|err| panic!("could not create the server : {err:#}"));
+    #[cfg(unix)]
+    reload_on_sighup(args.clone(), server.catalog(), server.control());
+    let stats_interval = control::parse_duration(&args.stats_interval)
+      .unwrap_or_else(|err| panic!("invalid statistics interval : {err:#}"));
+    log_stats(server.control(), stats_interval);
+    #[cfg(unix)]
+    flush_cache_on_sigusr2(server.control());
+    server
+      .start()
+      .await
+      .unwrap_or_else(|err| panic!("could not start the server : {err:#}"));
+    #[cfg(unix)]
+    if let Some(account) = &account {
+      account
+        .switch()
+        .unwrap_or_else(|err| panic!("could not drop privileges : {err:#}"));
+      info!("Running as uid {} and gid {}", account.uid, account.gid);
+    }
+    if args.sandbox {
+      sandbox(&args);
+    }
 
-  let mut server = ServerFuture::new(catalog);
+    tokio::select! {
+      _ = stop => {},
+      result = server.block_until_done() => {
+        error!("The server stopped unexpectedly: {:?}", result);
+        return 1;
+      }
+    }
 
-  info!("Will listen UDP resquests on {}:{}", args.listen, args.port);
-  let udp_socket = runtime
-    .block_on(UdpSocket::bind((args.listen.clone(), args.port)))
-    .unwrap_or_else(|err| {
-      panic!(
-        "could not bind to UDP socket {}:{} : {err}",
-        args.listen, args.port
-      )
-    });
+    match server
+      .shutdown(Duration::from_secs(args.shutdown_timeout))
+      .await
+    {
+      Ok(_) => 0,
+      Err(err) => {
+        warn!("{err:#}");
+        1
+      }
+    }
+  });
+  info!("Server stopped");
+  status
+}
 
-  let _guard = runtime.enter();
-  server.register_socket(udp_socket);
+#[cfg(target_os = "linux")]
+fn sandbox(args: &DNSServer) {
+  args
+    .sandbox()
+    .apply()
+    .unwrap_or_else(|err| panic!("could not apply the sandbox : {err:#}"));
+  info!("Sandbox applied");
+}
 
-  if args.h2 {
-    info!(
-      "Will listen HTTPS/H2 resquests on {}:{}",
-      args.listen, args.h2_port
-    );
-    let https_listener = runtime
-      .block_on(TcpListener::bind((args.listen.clone(), args.h2_port)))
-      .unwrap();
+#[cfg(not(target_os = "linux"))]
+fn sandbox(_: &DNSServer) {
+  panic!("the sandbox is only available on Linux");
+}
 
-    let _guard = runtime.enter();
-    let certs = read_cert(args.tls_certificate.clone().unwrap().as_path()).unwrap();
-    let private_key = read_key(args.tls_private_key.clone().unwrap().as_path()).unwrap();
-    server
-      .register_https_listener(
-        https_listener,
-        Duration::from_secs(2),
-        (certs, private_key),
-        None,
-      )
-      .expect("could not register HTTPS listener");
+/// Build the server like on startup without starting it, print what it would serve and exit.
+fn check(args: &DNSServer) -> ! {
+  let runtime = runtime::Builder::new_current_thread()
+    .enable_all()
+    .build()
+    .expect("failed to initialize Tokio Runtime");
+  match runtime.block_on(args.check()) {
+    Ok(report) => {
+      for line in report {
+        println!("{line}");
+      }
+      println!("The configuration is valid");
+      std::process::exit(0)
+    }
+    Err(err) => {
+      eprintln!("{err:#}");
+      std::process::exit(1)
+    }
   }
+}
 
-  if args.tls {
-    info!(
-      "Will listen TLS/TCP resquests on {}:{}",
-      args.listen, args.tls_port
-    );
-    let tls_listener = runtime
-      .block_on(TcpListener::bind((args.listen.clone(), args.tls_port)))
-      .unwrap();
+/// Warnings and the statistics summaries, which use the `stats` target.
+const DEFAULT_LOG_FILTER: &str = "warn,stats=info";
 
-    let _guard = runtime.enter();
-    let certs = read_cert(args.tls_certificate.clone().unwrap().as_path()).unwrap();
-    let private_key = read_key(args.tls_private_key.clone().unwrap().as_path()).unwrap();
-    server
-      .register_tls_listener(tls_listener, Duration::from_secs(2), (certs, private_key))
-      .expect("could not register TLS listener");
+/// Write a summary of the statistics in the logs on each SIGUSR1, and every `interval` unless it
+/// is zero.
+fn log_stats(control: Arc<Control>, interval: Duration) {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_defined =
+      signal(SignalKind::user_defined1()).expect("could not listen to SIGUSR1");
+    let control = control.clone();
+    tokio::spawn(async move {
+      while user_defined.recv().await.is_some() {
+        info!(target: "stats", "{}", control.stats().snapshot());
+      }
+    });
+  }
+  if !interval.is_zero() {
+    tokio::spawn(async move {
+      let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+      loop {
+        ticks.tick().await;
+        info!(target: "stats", "{}", control.stats().snapshot());
+      }
+    });
   }
+}
+
+/// Flush the cache of the answers each time the process receives a SIGUSR2.
+#[cfg(unix)]
+fn flush_cache_on_sigusr2(control: Arc<Control>) {
+  use tokio::signal::unix::{signal, SignalKind};
+
+  let mut user_defined = signal(SignalKind::user_defined2()).expect("could not listen to SIGUSR2");
+  tokio::spawn(async move {
+    while user_defined.recv().await.is_some() {
+      info!("SIGUSR2 received, flushing the cache");
+      let removed = control.cache().flush();
+      control.audit("SIGUSR2", &format!("{removed} answers flushed"));
+    }
+  });
+}
+
+/// Rebuild the catalog from the command line and the configuration file each time the process
+/// receives a SIGHUP. Queries already being answered keep using the previous catalog.
+#[cfg(unix)]
+fn reload_on_sighup(mut current: DNSServer, catalog: CatalogHandle, control: Arc<Control>) {
+  use tokio::signal::unix::{signal, SignalKind};
 
-  let shutdown = Shutdown::default();
-  runtime
-    .block_on(shutdown.shutdown_with_limit(Duration::from_secs(5)))
-    .unwrap();
+  let mut hangup = signal(SignalKind::hangup()).expect("could not listen to SIGHUP");
+  tokio::spawn(async move {
+    while hangup.recv().await.is_some() {
+      info!("SIGHUP received, reloading configuration");
+      let args = match DNSServer::try_parse_with_config() {
+        Ok(args) => args,
+        Err(err) => {
+          error!("Configuration not reloaded: {err:#}");
+          control.audit("SIGHUP", &format!("configuration not reloaded: {err:#}"));
+          continue;
+        }
+      };
+      if args.requires_restart(&current) {
+        warn!("Listeners, workers and query logs changes will only be applied after a restart");
+      }
+      match args.generate_catalog_builder().await {
+        Ok(builder) => {
+          let builder = builder.control(control.clone());
+          catalog.replace(builder.build_catalogs());
+          info!("Configuration reloaded");
+          control.audit("SIGHUP", "configuration reloaded");
+          current = args;
+        }
+        Err(err) => {
+          error!("Configuration not reloaded: {err:#}");
+          control.audit("SIGHUP", &format!("configuration not reloaded: {err:#}"));
+        }
+      }
+    }
+  });
 }
 
 impl DNSServer {
-  async fn generate_catalog(&self) -> Catalog {
-    let mut catalog = Catalog::new();
-    let name = Name::root();
+  fn parse_with_config() -> Self {
+    Self::try_parse_with_config().unwrap_or_else(|err| panic!("{err:#}"))
+  }
 
-    for domain in self.get_blacklist(&self.zone_blacklist).iter() {
-      let authority = NoneAuthority::new(domain.clone(), self.default_ip.clone());
-      catalog.upsert(domain.clone(), Box::new(Arc::new(authority)));
+  fn try_parse_with_config() -> Result<Self> {
+    let command = Self::command();
+    let matches = command.clone().get_matches();
+    let args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let Some(path) = args.config.clone() else {
+      return Ok(args);
+    };
+
+    let config = config::read_config_file(&path)
+      .with_context(|| format!("could not read configuration {}", path.display()))?;
+    let mut merged: Self = config::merge_config(&args, &command, &matches, config)
+      .with_context(|| format!("could not load configuration {}", path.display()))?;
+    merged.config = Some(path);
+    // Subcommands are not part of the configuration file.
+    merged.command = args.command;
+    Ok(merged)
+  }
+
+  /// Whether the options that can only be applied at startup (listeners, workers, query
+  /// observers) are different.
+  #[cfg(unix)]
+  fn requires_restart(&self, other: &Self) -> bool {
+    self.port != other.port
+      || self.listen != other.listen
+      || self.listen_udp != other.listen_udp
+      || self.listen_tls != other.listen_tls
+      || self.listen_h2 != other.listen_h2
+      || self.worker != other.worker
+      || self.sandbox != other.sandbox
+      || self.h2 != other.h2
+      || self.h2_port != other.h2_port
+      || self.h2_auth_token != other.h2_auth_token
+      || self.tls != other.tls
+      || self.tls_port != other.tls_port
+      || self.tls_certificate != other.tls_certificate
+      || self.tls_private_key != other.tls_private_key
+      || self.tls_client_ca != other.tls_client_ca
+      || self.tls_min_version != other.tls_min_version
+      || self.tls_cipher_suite != other.tls_cipher_suite
+      || self.acme_domain != other.acme_domain
+      || self.acme_email != other.acme_email
+      || self.acme_directory != other.acme_directory
+      || self.acme_storage != other.acme_storage
+      || self.acme_http_port != other.acme_http_port
+      || self.allow_networks != other.allow_networks
+      || self.deny_networks != other.deny_networks
+      || self.allow_countries != other.allow_countries
+      || self.deny_countries != other.deny_countries
+      || self.geoip_database != other.geoip_database
+      || self.listener_acl != other.listener_acl
+      || self.deny_action != other.deny_action
+      || self.cache_backend != other.cache_backend
+      || self.warmup_list != other.warmup_list
+      || self.rrl_responses_per_second != other.rrl_responses_per_second
+      || self.rrl_window != other.rrl_window
+      || self.rrl_slip != other.rrl_slip
+      || self.rrl_ipv4_prefix != other.rrl_ipv4_prefix
+      || self.rrl_ipv6_prefix != other.rrl_ipv6_prefix
+      || self.query_log != other.query_log
+      || self.query_db != other.query_db
+      || self.query_db_retention != other.query_db_retention
+      || self.log_queries != other.log_queries
+      || self.log_ipv4_prefix != other.log_ipv4_prefix
+      || self.log_ipv6_prefix != other.log_ipv6_prefix
+      || self.log_hash_names != other.log_hash_names
+      || self.log_hash_salt != other.log_hash_salt
+      || self.dnstap_socket != other.dnstap_socket
+      || self.dnstap_file != other.dnstap_file
+      || self.shutdown_timeout != other.shutdown_timeout
+      || self.stats_interval != other.stats_interval
+      || self.log_output != other.log_output
+      || self.proxy_protocol != other.proxy_protocol
+      || self.edns_udp_size != other.edns_udp_size
+      || self.padding != other.padding
+      || self.user != other.user
+      || self.group != other.group
+      || self.control_socket != other.control_socket
+      || self.audit_log != other.audit_log
+      || self.web_ui != other.web_ui
+      || self.block_page != other.block_page
+      || self.block_page_template != other.block_page_template
+  }
+
+  /// Summary of the listeners, upstreams and lists once the server was built.
+  async fn check(&self) -> Result<Vec<String>> {
+    #[cfg(unix)]
+    if self.user.is_some() || self.group.is_some() {
+      Account::lookup(self.user.as_deref(), self.group.as_deref())?;
+    }
+    control::parse_duration(&self.stats_interval).context("invalid statistics interval")?;
+    self
+      .generate_builder(ActivatedSockets::default())
+      .await?
+      .build()?;
+
+    let mut report = vec![];
+    let listeners = [
+      ("UDP and TCP", &self.listen_udp, self.port, true),
+      ("TLS", &self.listen_tls, self.tls_port, self.tls),
+      ("H2", &self.listen_h2, self.h2_port, self.h2),
+    ];
+    for (name, addrs, port, enabled) in listeners {
+      if enabled {
+        for addr in self.listen_addrs(addrs, port)? {
+          report.push(format!("Listen {name} on {addr}"));
+        }
+      }
+    }
+    if self.recursive {
+      report.push("Resolve recursively from the root servers".to_string());
+    } else {
+      for upstream in self.bootstrap_all(&self.dns_server).await? {
+        report.push(format!("Forward to {upstream}"));
+      }
+    }
+    if !self.upstream_bind.is_empty() {
+      let binds: Vec<String> = self.upstream_bind.iter().map(ToString::to_string).collect();
+      let kill_switch = match self.upstream_binding()?.interface() {
+        Some(interface) if self.upstream_kill_switch => format!(", only while {interface} is up"),
+        _ => String::new(),
+      };
+      report.push(format!(
+        "Send the upstream queries from {}{kill_switch}",
+        binds.join(", ")
+      ));
     }
+    for zone in self.secondary_zone.iter() {
+      let authority = SecondaryAuthority::new(zone, self.transfer_key(zone)?);
+      authority.refresh().await?;
+      if let Some((serial, records)) = authority.serial() {
+        let signed = match &zone.key {
+          Some(key) => format!(" signed with the key {key}"),
+          None => String::new(),
+        };
+        report.push(format!(
+          "Transfer {} from {}{} (serial {}, {} records)",
+          zone.origin, zone.primary, signed, serial, records
+        ));
+      }
+    }
+    for zone in self.zone_file.iter() {
+      if let Some(dnssec) = self.dnssec_zone(zone) {
+        let ds = dnssec.sign(&mut zone.load()?)?;
+        report.push(format!(
+          "Sign {} with DNSSEC, DS record: {}",
+          zone.origin, ds
+        ));
+      }
+    }
+    for forward in self.forward_zone.iter() {
+      let zones: Vec<String> = forward.zones.iter().map(ToString::to_string).collect();
+      report.push(format!(
+        "Forward {} to {}",
+        zones.join(", "),
+        forward.upstream
+      ));
+    }
+    for path in self.filter_list.iter() {
+      let rules = FilterList::load(path)?;
+      report.push(format!(
+        "Filter with {} rules of {}",
+        rules.len(),
+        path.display()
+      ));
+    }
+    for file in self.blacklist.iter() {
+      let domains = DomainSet::load(&file.path)?;
+      let category = file
+        .category
+        .as_ref()
+        .map_or(String::new(), |category| format!(" ({category} category)"));
+      report.push(format!(
+        "Block {} domains of {}{}",
+        domains.len(),
+        file.path.display(),
+        category
+      ));
+    }
+    if let Some(path) = &self.zone_blacklist {
+      let zones = DomainSet::load(path)?;
+      report.push(format!("Block {} zones of {}", zones.len(), path.display()));
+    }
+    if let Some(path) = &self.zone_blacklist_exceptions {
+      let zones = DomainSet::load(path)?;
+      report.push(format!(
+        "Unblock {} zones of {} from the zone blacklists",
+        zones.len(),
+        path.display()
+      ));
+    }
+    Ok(report)
+  }
 
-    let authority = BlacklistAuthority::new(
-      name.clone(),
-      self.get_blacklist(&self.blacklist),
-      self.dns_server.clone().into(),
-      self.default_ip.clone(),
-      self.get_rfc8215_ips(),
+  /// Server with the listeners and query observers, they can only be created at startup.
+  /// The sockets `activated` by systemd replace the listen addresses of their listener.
+  async fn generate_builder(&self, activated: ActivatedSockets) -> Result<DnsServerBuilder> {
+    let mut builder = self.generate_catalog_builder().await?;
+    let (udp, tls, h2) = (
+      activated.udp.is_empty(),
+      self.tls && activated.tls.is_empty(),
+      self.h2 && activated.h2.is_empty(),
     );
-    catalog.upsert(LowerName::new(&name), Box::new(Arc::new(authority)));
+    if !activated.is_empty() {
+      info!("Using the sockets passed by systemd");
+      builder = builder.activated_sockets(activated);
+    }
+    if udp {
+      for addr in self.listen_addrs(&self.listen_udp, self.port)? {
+        builder = builder.listen_udp(addr).listen_tcp(addr);
+      }
+    }
+    if h2 {
+      for addr in self.listen_addrs(&self.listen_h2, self.h2_port)? {
+        builder = builder.listen_h2(addr);
+      }
+    }
+    if let Some(credential) = &self.h2_auth_token {
+      builder = builder.h2_credential(credential.clone());
+    }
+    if tls {
+      for addr in self.listen_addrs(&self.listen_tls, self.tls_port)? {
+        builder = builder.listen_tls(addr);
+      }
+    }
+    if let (Some(certificate), Some(private_key)) = (&self.tls_certificate, &self.tls_private_key) {
+      builder = builder.tls_certificate(certificate, private_key);
+    }
+    if let Some(ca) = &self.tls_client_ca {
+      builder = builder.tls_client_ca(ca);
+    }
+    builder = builder
+      .tls_min_version(self.tls_min_version)
+      .tls_cipher_suites(self.tls_cipher_suite.clone());
+    if !self.acme_domain.is_empty() {
+      let mut options = AcmeOptions::new(self.acme_domain.clone(), &self.acme_storage);
+      options.email = self.acme_email.clone();
+      options.directory = self.acme_directory.clone();
+      options.http_listen = self.socket_addr(self.acme_http_port)?;
+      builder = builder.acme(options);
+    }
+    builder = builder.acl(Acl::new(
+      acl::ip_ranges(&self.allow_networks)?,
+      acl::ip_ranges(&self.deny_networks)?,
+    ));
+    if let Some(geoip) = self.geoip()? {
+      builder = builder
+        .geoip(geoip)
+        .geoip_refresh(control::parse_duration(&self.geoip_refresh)?);
+    }
+    builder = builder
+      .countries(
+        geoip::countries(&self.allow_countries)?,
+        geoip::countries(&self.deny_countries)?,
+      )
+      .deny_action(self.deny_action)
+      .edns_udp_size(self.edns_udp_size)
+      .padding(self.padding);
+    if !self.proxy_protocol.is_empty() {
+      builder = builder.proxy_protocol(IpRangeVec::new(acl::ip_ranges(&self.proxy_protocol)?));
+    }
+    for listener_acl in self.listener_acl.iter() {
+      info!(
+        "The {} listener uses {}",
+        listener_acl.listener, listener_acl
+      );
+      builder = builder.listener_acl(listener_acl.listener, listener_acl.acl()?);
+    }
+    if self.rrl_responses_per_second > 0 {
+      builder = builder.rrl(RrlOptions {
+        responses_per_second: self.rrl_responses_per_second,
+        window: control::parse_duration(&self.rrl_window).context("invalid RRL window")?,
+        slip: self.rrl_slip,
+        ipv4_prefix: self.rrl_ipv4_prefix,
+        ipv6_prefix: self.rrl_ipv6_prefix,
+      });
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &self.control_socket {
+      builder = builder.control_socket(path);
+    }
+    if let Some(addr) = self.web_ui {
+      builder = builder.web_ui(addr);
+    }
+    if self.block_page {
+      let page = match &self.block_page_template {
+        Some(path) => BlockPage::new(
+          std::fs::read_to_string(path)
+            .with_context(|| format!("could not read the block page {}", path.display()))?,
+        ),
+        None => BlockPage::default(),
+      };
+      builder = builder.block_page(page);
+    }
+
+    if let Some(path) = &self.audit_log {
+      info!("Will record the changes in {}", path.display());
+      let audit_log = AuditLog::open(path)
+        .with_context(|| format!("could not open audit log {}", path.display()))?;
+      builder = builder.audit_log(Arc::new(audit_log));
+    }
+
+    let privacy = LogPrivacy {
+      queries: self.log_queries,
+      ipv4_prefix: self.log_ipv4_prefix,
+      ipv6_prefix: self.log_ipv6_prefix,
+      hash_names: self.log_hash_names,
+      hash_salt: self.log_hash_salt.clone(),
+    };
+    builder = builder.history_privacy(privacy.clone());
+    if let Some(path) = &self.query_log {
+      info!("Will log queries in {}", path.display());
+      let query_log = QueryLog::open(path, privacy.clone())
+        .await
+        .with_context(|| format!("could not open query log {}", path.display()))?;
+      builder = builder.observer(Arc::new(query_log));
+    }
+
+    if let Some(path) = &self.query_db {
+      let retention = control::parse_duration(&self.query_db_retention)
+        .context("invalid query database retention")?;
+      info!(
+        "Will store queries in {} for {}",
+        path.display(),
+        self.query_db_retention
+      );
+      builder = builder.query_db(Arc::new(QueryDb::open(path, retention, privacy.clone())?));
+    }
+
+    #[cfg(unix)]
+    let socket = self.dnstap_socket.clone().map(DnstapOutput::Socket);
+    #[cfg(not(unix))]
+    let socket = None;
+    let dnstap_output = socket.or_else(|| self.dnstap_file.clone().map(DnstapOutput::File));
+    if let Some(output) = dnstap_output {
+      info!("Will send dnstap messages to {:?}", output);
+      builder = builder.observer(Arc::new(Dnstap::new(output, privacy)));
+    }
+
+    Ok(builder)
+  }
+
+  /// Server with the upstream and the blacklists, enough to build the catalog on reload.
+  async fn generate_catalog_builder(&self) -> Result<DnsServerBuilder> {
+    let mut builder = DnsServerBuilder::new()
+      .upstreams(self.bootstrap_all(&self.dns_server).await?)
+      .strategy(self.upstream_strategy)
+      .race(self.upstream_race)
+      .recursive(self.recursive)
+      .ecs(self.ecs)
+      .upstream_bind(self.upstream_binding()?)
+      .upstream_kill_switch(self.upstream_kill_switch)
+      .randomize_case(self.upstream_randomize_case)
+      .health_check(control::parse_duration(&self.upstream_health_check)?)
+      .upstream_timeout(self.upstream_timeout()?)
+      .upstream_attempts(self.upstream_attempts.into())
+      .local_dns_refresh(control::parse_duration(&self.local_dns_refresh)?)
+      .local_dns_rotate(self.local_dns_rotate)
+      .local_ttl(self.local_ttl)
+      .block_ttl(self.block_ttl)
+      .synthetic_zone(self.synthetic_zone()?)
+      .rotate_answers(self.rotate_answers)
+      .cache_limits(
+        self.cache_max_entries,
+        self
+          .cache_max_memory
+          .as_deref()
+          .map(cache::parse_size)
+          .transpose()
+          .context("invalid cache memory")?,
+      )
+      .refuse_any(self.refuse_any)
+      .safe_search(self.force_safe_search)
+      .zone_blacklist(self.get_blacklist(&self.zone_blacklist)?)
+      .zone_blacklist_exceptions(self.get_blacklist(&self.zone_blacklist_exceptions)?)
+      .ip_blacklist(self.get_ip_ranges(&self.ip_blacklist)?)
+      .rfc8215_ips(self.get_ip_ranges(&self.rfc8215_ips)?)
+      .nat64(Nat64::new(&self.nat64_prefix)?.excluded(self.dns64_exclude()?));
+    if let Some(default_ip) = self.default_ip {
+      builder = builder.default_ip(default_ip);
+    }
+    if let Some(url) = &self.cache_backend {
+      builder = builder.cache_backend(url.clone());
+    }
+    if let Some(path) = &self.warmup_list {
+      builder = builder.warmup(warmup::load(path)?);
+    }
+    for path in self.filter_list.iter() {
+      builder = builder.filter_list(FilterList::load(path)?);
+    }
+    for file in self.blacklist.iter() {
+      let domains = self.get_blacklist(&Some(file.path.clone()))?;
+      builder = match &file.category {
+        Some(category) => builder.category(category, domains),
+        None => builder.blacklist(domains),
+      };
+    }
+    builder = builder.local_only_zones(self.local_only_zones()?);
+    builder = builder.filter_aaaa(self.filter_aaaa_zones()?);
+    if self.block_doh_bypass {
+      builder = match &self.doh_bypass_list {
+        Some(_) => builder.block_doh_bypass(self.get_blacklist(&self.doh_bypass_list)?),
+        None => builder.block_doh_bypass(doh_servers()),
+      };
+    }
+    if let Some(proxy) = &self.upstream_proxy {
+      info!("Will connect to the dns servers through {}", proxy.address);
+      builder = builder.upstream_proxy(proxy.clone());
+    }
+    if self.upstream_ca.is_some() || !self.upstream_pin.is_empty() {
+      builder = builder.upstream_tls(tls::verifier(
+        self.upstream_ca.as_deref(),
+        &self.upstream_pin,
+      )?);
+    }
+    for list in self.scheduled_zone_blacklist.iter() {
+      info!(
+        "Zones in {} are blocked at {}",
+        list.path.display(),
+        list.schedule
+      );
+      let zones = self.get_blacklist(&Some(list.path.clone()))?;
+      builder = builder.scheduled_zone_blacklist(list.schedule.clone(), zones);
+    }
+    for zone in self.zone_file.iter() {
+      let mut authority = zone.load()?;
+      match self.dnssec_zone(zone) {
+        Some(dnssec) => {
+          dnssec.sign(&mut authority)?;
+          builder = builder.signed_zone(authority);
+        }
+        None => builder = builder.zone(authority),
+      }
+    }
+    for dnssec in self.dnssec_sign_zone.iter() {
+      if !self
+        .zone_file
+        .iter()
+        .any(|zone| zone.origin == dnssec.origin)
+      {
+        bail!(
+          "--dnssec-sign-zone {} requires a --zone-file of this zone",
+          dnssec.origin
+        );
+      }
+    }
+    for zone in self.secondary_zone.iter() {
+      let authority = Arc::new(SecondaryAuthority::new(zone, self.transfer_key(zone)?));
+      if let Err(err) = authority.refresh().await {
+        warn!("{:#}", err);
+      }
+      builder = builder.secondary_zone(authority);
+    }
+    let local_dns = self.local_sources()?;
+    if !local_dns.is_empty() {
+      builder = builder.local_dns(LocalDns::load(&local_dns)?);
+    }
+    if !self.local_dns_update_zone.is_empty() {
+      if self.local_dns.is_empty() {
+        bail!("--local-dns-update-zone requires a --local-dns file");
+      }
+      let zones = self
+        .local_dns_update_zone
+        .iter()
+        .map(|zone| {
+          let mut name =
+            Name::from_str(zone).with_context(|| format!("invalid zone name `{zone}`"))?;
+          name.set_fqdn(true);
+          Ok(LowerName::new(&name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+      let clients = self
+        .local_dns_update_from
+        .iter()
+        .map(|network| {
+          IpRange::try_from(network.as_str()).with_context(|| format!("invalid IP range {network}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+      builder = builder.local_dns_updates(zones, IpRangeVec::new(clients));
+      for name in self.local_dns_update_key.iter() {
+        let name = Name::from_str(name).with_context(|| format!("invalid key name `{name}`"))?;
+        builder = builder.local_dns_update_key(tsig::find(&self.tsig_key, &name)?.clone());
+      }
+    } else if !self.local_dns_update_key.is_empty() {
+      bail!("--local-dns-update-key requires --local-dns-update-zone");
+    }
+    for path in self.rpz.iter() {
+      builder = builder.rpz(ResponsePolicyZone::load(path)?);
+    }
+    for rewrite in self.rewrite.iter() {
+      builder = builder.rewrite(rewrite.clone());
+    }
+    for group in self.client_group.iter() {
+      let policy = GroupPolicy {
+        blacklist: self.get_optional_blacklist(&group.blacklist)?.map(Arc::new),
+        zone_blacklist: self
+          .get_optional_blacklist(&group.zone_blacklist)?
+          .map(Arc::new),
+        default_ip: group.default_ip,
+        upstream: match &group.dns_server {
+          Some(dns_server) => Some(self.bootstrap(dns_server).await?),
+          None => None,
+        },
+        countries: group.countries.clone(),
+      };
+      info!("Client group {} uses {}", group.name, group);
+      builder = builder.client_group(
+        group.name.clone(),
+        IpRangeVec::new(group.ip_ranges()?),
+        policy,
+      );
+    }
+    for forward in self.forward_zone.iter() {
+      info!("Forward zone {}", forward);
+      let upstream = self.bootstrap(&forward.upstream).await?;
+      for zone in forward.zones.iter() {
+        builder = builder.forward_zone(zone.clone(), upstream.clone());
+      }
+    }
+    for route in self.upstream_route.iter() {
+      info!("Upstream route {}", route);
+      builder = builder.upstream_route(
+        IpRangeVec::new(route.ip_ranges()?),
+        self.bootstrap(&route.upstream).await?,
+      );
+    }
+    Ok(builder)
+  }
 
-    catalog
+  /// DNSSEC signing of a zone file.
+  fn dnssec_zone(&self, zone: &ZoneFile) -> Option<&DnssecZone> {
+    self
+      .dnssec_sign_zone
+      .iter()
+      .find(|dnssec| dnssec.origin == zone.origin)
   }
 
-  fn get_rfc8215_ips(&self) -> IpRangeVec {
-    let ip_ranges: Vec<IpRange> = if let Some(path) = &self.rfc8215_ips {
-      let mut file = std::fs::File::open(path).unwrap();
+  /// TSIG key of the transfers of a secondary zone.
+  fn transfer_key(&self, zone: &SecondaryZone) -> Result<Option<TsigKey>> {
+    zone
+      .key
+      .as_ref()
+      .map(|name| tsig::find(&self.tsig_key, name).cloned())
+      .transpose()
+  }
+
+  /// Hosts files and DHCP leases answered by the local DNS.
+  fn local_sources(&self) -> Result<Vec<LocalSource>> {
+    let mut sources: Vec<LocalSource> = self
+      .local_dns
+      .iter()
+      .cloned()
+      .map(LocalSource::File)
+      .collect();
+    let dhcp_domain = match &self.dhcp_domain {
+      Some(domain) => {
+        Some(Name::from_str(domain).with_context(|| format!("invalid domain `{domain}`"))?)
+      }
+      None => None,
+    };
+    for path in self.dhcp_leases.iter() {
+      sources.push(LocalSource::Leases(path.clone(), dhcp_domain.clone()));
+    }
+    if self.use_system_hosts {
+      sources.push(LocalSource::File(SYSTEM_HOSTS.into()));
+    }
+    Ok(sources)
+  }
+
+  /// GeoIP database of `--geoip-database`, required by the countries of the options.
+  fn geoip(&self) -> Result<Option<GeoIp>> {
+    let countries = !self.allow_countries.is_empty()
+      || !self.deny_countries.is_empty()
+      || self
+        .client_group
+        .iter()
+        .any(|group| !group.countries.is_empty());
+    match &self.geoip_database {
+      Some(path) => Ok(Some(GeoIp::load(path)?)),
+      None if countries => bail!("the countries of the clients require --geoip-database"),
+      None => Ok(None),
+    }
+  }
+
+  fn dns64_exclude(&self) -> Result<IpRangeVec> {
+    let networks = self
+      .dns64_exclude
+      .iter()
+      .map(|network| {
+        IpRange::try_from(network.as_str()).with_context(|| format!("invalid IP range {network}"))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    Ok(IpRangeVec::new(networks))
+  }
+
+  /// How `name` would be answered to `client`, following the order of the authorities: zone
+  /// files, local DNS, response policy zones, then the blacklists. Blocking disabled through the
+  /// control socket or the web UI is not known here.
+  fn test_block(&self, name: &LowerName, client: Option<IpAddr>) -> Result<Vec<String>> {
+    let mut report = vec![];
+    let mut group = None;
+    if let Some(client) = client {
+      let geoip = self.geoip()?;
+      let country = geoip.and_then(|geoip| geoip.country(client));
+      if let Some(country) = &country {
+        report.push(format!("{client} is located in {country}"));
+      }
+      for client_group in self.client_group.iter() {
+        let in_countries = country
+          .as_ref()
+          .is_some_and(|country| client_group.countries.contains(country));
+        if IpRangeVec::new(client_group.ip_ranges()?).contains(client) || in_countries {
+          report.push(format!(
+            "{client} is in the client group {}",
+            client_group.name
+          ));
+          group = Some(client_group);
+          break;
+        }
+      }
+    }
+
+    for zone in self.zone_file.iter() {
+      if zone.load()?.origin().zone_of(name) {
+        report.push(format!(
+          "{name} is not blocked, it is answered by the zone file {}",
+          zone.path.display()
+        ));
+        return Ok(report);
+      }
+    }
+    for zone in self.secondary_zone.iter() {
+      if LowerName::new(&zone.origin).zone_of(name) {
+        report.push(format!(
+          "{name} is not blocked, it is answered by the secondary zone {} from {}",
+          zone.origin, zone.primary
+        ));
+        return Ok(report);
+      }
+    }
+    if self.block_doh_bypass {
+      if let Some(canary) = canary_domains().into_iter().find(|zone| zone.zone_of(name)) {
+        report.push(format!(
+          "{name} is blocked by the DoH bypass canary domain {canary}"
+        ));
+        report.push("Response: NXDOMAIN".to_string());
+        return Ok(report);
+      }
+    }
+    let local_sources = self.local_sources()?;
+    if !local_sources.is_empty() {
+      if let Some(records) = LocalDns::load(&local_sources)?.find_for(name, client) {
+        report.push(format!(
+          "{name} is not blocked, it is answered by the local DNS"
+        ));
+        report.extend(records.iter().map(|record| format!("Response: {record}")));
+        return Ok(report);
+      }
+    }
+    let rewritten = Name::from(name);
+    if let Some(rewrite) = self
+      .rewrite
+      .iter()
+      .find(|rewrite| rewrite.matches(&rewritten))
+    {
+      report.push(format!("{name} is rewritten by the rule {rewrite}"));
+      match &rewrite.action {
+        RewriteAction::To(target) => report.push(format!("Response: {name} CNAME {target}")),
+        RewriteAction::Answer(ips) => report.extend(ips.iter().map(|ip| match ip {
+          IpAddr::V4(ip) => format!("Response: {name} A {ip}"),
+          IpAddr::V6(ip) => format!("Response: {name} AAAA {ip}"),
+        })),
+      }
+      return Ok(report);
+    }
+    for path in self.rpz.iter() {
+      match ResponsePolicyZone::load(path)?.find(name) {
+        Some(Policy::Passthru) => {
+          report.push(format!(
+            "{name} passes through the response policy zone {}",
+            path.display()
+          ));
+          break;
+        }
+        Some(policy) => {
+          report.push(format!(
+            "{name} is blocked by the response policy zone {}",
+            path.display()
+          ));
+          report.push(format!("Response: {policy}"));
+          return Ok(report);
+        }
+        None => {}
+      }
+    }
+
+    let mut filters = FilterList::new();
+    for path in self.filter_list.iter() {
+      filters.merge(FilterList::load(path)?);
+    }
+    let client_ip = client.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let blocked_by = match filters.verdict(name, client_ip) {
+      Some(verdict) if verdict.blocked => Some(("the filter lists".to_string(), verdict.rule)),
+      Some(verdict) => {
+        report.push(format!(
+          "{name} is allowed by the exception {} of the filter lists",
+          verdict.rule
+        ));
+        None
+      }
+      None => self
+        .blocked_by(name, group)?
+        .map(|(list, rule)| (list, rule.to_string())),
+    };
+    if let (None, Some(path)) = (&blocked_by, &self.zone_blacklist_exceptions) {
+      if let Some(zone) = DomainSet::load(path)?.matching_zone(name) {
+        report.push(format!(
+          "{name} is unblocked from the zone blacklists by the exception {zone} of {}",
+          path.display()
+        ));
+      }
+    }
+    match blocked_by {
+      Some((list, rule)) => {
+        report.push(format!("{name} is blocked by {list}, rule {rule}"));
+        let default_ip = group.and_then(|group| group.default_ip).or(self.default_ip);
+        report.push(match default_ip {
+          Some(ip) => format!("Response: {name} A {ip}"),
+          None => "Response: NOERROR without answer".to_string(),
+        });
+      }
+      None => match self
+        .local_only_zones()?
+        .iter()
+        .find(|zone| zone.zone_of(name))
+        .filter(|_| self.forward_zone(name).is_none())
+      {
+        Some(zone) => {
+          report.push(format!(
+            "{name} is not blocked, it is in the local-only zone {zone}"
+          ));
+          report.push("Response: NXDOMAIN".to_string());
+        }
+        None => {
+          // The networks of the routes are checked when they are parsed.
+          let route = client.and_then(|client| {
+            self.upstream_route.iter().find(|route| {
+              route
+                .ip_ranges()
+                .is_ok_and(|ips| IpRangeVec::new(ips).contains(client))
+            })
+          });
+          match (self.forward_zone(name), route) {
+            (Some((zone, forward)), _) => report.push(format!(
+              "{name} is not blocked, it is forwarded to {} by the forward zone {zone}",
+              forward.upstream
+            )),
+            (None, Some(route)) => report.push(format!(
+              "{name} is not blocked, it is forwarded to {} by the upstream route {route}",
+              route.upstream
+            )),
+            (None, None) => report.push(format!(
+              "{name} is not blocked, it is forwarded to the upstreams"
+            )),
+          }
+        }
+      },
+    }
+    Ok(report)
+  }
+
+  /// First list blocking `name` with its matching rule, in the order of the blocklist of the
+  /// server. The lists of the client `group` replace the blacklist and the zone blacklist.
+  fn blocked_by(
+    &self,
+    name: &LowerName,
+    group: Option<&ClientGroup>,
+  ) -> Result<Option<(String, LowerName)>> {
+    let blacklists: Vec<(String, &PathBuf)> = match group {
+      Some(group) if group.blacklist.is_some() => group
+        .blacklist
+        .iter()
+        .map(|path| (format!("the blacklist of the group {}", group.name), path))
+        .collect(),
+      _ => self
+        .blacklist
+        .iter()
+        .filter(|file| file.category.is_none())
+        .map(|file| ("the blacklist".to_string(), &file.path))
+        .collect(),
+    };
+    for (list, path) in blacklists {
+      if DomainSet::load(path)?.contains(name) {
+        return Ok(Some((format!("{list} {}", path.display()), name.clone())));
+      }
+    }
+
+    let zone_blacklist = match group {
+      Some(group) if group.zone_blacklist.is_some() => group.zone_blacklist.as_ref().map(|path| {
+        (
+          format!("the zone blacklist of the group {}", group.name),
+          path,
+        )
+      }),
+      _ => self
+        .zone_blacklist
+        .as_ref()
+        .map(|path| ("the zone blacklist".to_string(), path)),
+    };
+    let excepted = self
+      .get_blacklist(&self.zone_blacklist_exceptions)?
+      .contains_zone(name);
+    if let Some((list, path)) = zone_blacklist.filter(|_| !excepted) {
+      if let Some(zone) = DomainSet::load(path)?.matching_zone(name) {
+        return Ok(Some((format!("{list} {}", path.display()), zone)));
+      }
+    }
+
+    for file in self.blacklist.iter() {
+      let Some(category) = &file.category else {
+        continue;
+      };
+      if DomainSet::load(&file.path)?.contains(name) {
+        let list = format!("the {category} category {}", file.path.display());
+        return Ok(Some((list, name.clone())));
+      }
+    }
+
+    for list in self.scheduled_zone_blacklist.iter() {
+      if !list.schedule.is_active() || excepted {
+        continue;
+      }
+      if let Some(zone) = DomainSet::load(&list.path)?.matching_zone(name) {
+        let list = format!(
+          "the scheduled zone blacklist {} ({})",
+          list.path.display(),
+          list.schedule
+        );
+        return Ok(Some((list, zone)));
+      }
+    }
+
+    if self.block_doh_bypass {
+      let servers = match &self.doh_bypass_list {
+        Some(path) => DomainSet::load(path)?,
+        None => doh_servers().into_iter().collect(),
+      };
+      if let Some(zone) = servers.matching_zone(name) {
+        return Ok(Some(("the DoH bypass list".to_string(), zone)));
+      }
+    }
+    Ok(None)
+  }
+
+  async fn bootstrap_all(&self, dns_servers: &[ClientType]) -> Result<Vec<ClientType>> {
+    let mut upstreams = Vec::with_capacity(dns_servers.len());
+    for dns_server in dns_servers {
+      upstreams.push(self.bootstrap(dns_server).await?);
+    }
+    Ok(upstreams)
+  }
+
+  async fn bootstrap(&self, dns_server: &ClientType) -> Result<ClientType> {
+    dns_server
+      .clone()
+      .bootstrap(
+        &self.bootstrap_dns_server,
+        &Arc::new(self.upstream_binding()?),
+      )
+      .await
+  }
+
+  fn upstream_binding(&self) -> Result<Binding> {
+    let binding = Binding::new(&self.upstream_bind).context("invalid --upstream-bind")?;
+    if self.upstream_kill_switch && binding.interface().is_none() {
+      bail!("--upstream-kill-switch requires an interface in --upstream-bind");
+    }
+    Ok(binding)
+  }
+
+  /// Most specific forward zone of `name`, with its forward option.
+  fn forward_zone(&self, name: &LowerName) -> Option<(&LowerName, &ForwardZone)> {
+    self
+      .forward_zone
+      .iter()
+      .flat_map(|forward| forward.zones.iter().map(move |zone| (zone, forward)))
+      .filter(|(zone, _)| zone.zone_of(name))
+      .max_by_key(|(zone, _)| zone.num_labels())
+  }
+
+  /// Zones of `--local-only-zones`, with the built-in ones for `default`.
+  fn local_only_zones(&self) -> Result<Vec<LowerName>> {
+    let mut zones = vec![];
+    for zone in self.local_only_zones.iter() {
+      match zone.as_str() {
+        "default" => zones.extend(local_only_zones()),
+        "none" if self.local_only_zones.len() == 1 => {}
+        "none" => bail!("--local-only-zones none can not be combined with other zones"),
+        zone => zones.push(
+          LowerName::from_str(&format!("{}.", zone.trim_end_matches('.')))
+            .with_context(|| format!("invalid local-only zone {zone}"))?,
+        ),
+      }
+    }
+    Ok(zones)
+  }
+
+  fn synthetic_zone(&self) -> Result<SyntheticZone> {
+    let name = |name: &str| {
+      Name::from_str(&format!("{}.", name.trim().trim_end_matches('.')))
+        .with_context(|| format!("invalid synthetic zone name {name}"))
+    };
+    Ok(SyntheticZone {
+      ns: name(&self.synthetic_ns)?,
+      hostmaster: name(&self.synthetic_hostmaster)?,
+    })
+  }
+
+  fn filter_aaaa_zones(&self) -> Result<Vec<LowerName>> {
+    self
+      .filter_aaaa
+      .iter()
+      .map(|zone| {
+        LowerName::from_str(&format!("{}.", zone.trim().trim_end_matches('.')))
+          .with_context(|| format!("invalid filter-aaaa zone {zone}"))
+      })
+      .collect()
+  }
+
+  fn upstream_timeout(&self) -> Result<Duration> {
+    let timeout = control::parse_duration(&self.upstream_timeout)?;
+    if timeout.is_zero() {
+      bail!("the upstream timeout must be greater than 0");
+    }
+    Ok(timeout)
+  }
+
+  /// Sandbox allowing the files of the options. The directories of the lists, zones and
+  /// certificates are allowed since they are read again on reload, maybe replaced by new files.
+  #[cfg(target_os = "linux")]
+  fn sandbox(&self) -> Sandbox {
+    let directory = |path: &PathBuf| match path.parent() {
+      Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+      _ => PathBuf::from("."),
+    };
+    let (updated, local_dns) = match self.local_dns.split_first() {
+      Some((first, others)) if !self.local_dns_update_zone.is_empty() => (Some(first), others),
+      _ => (None, self.local_dns.as_slice()),
+    };
+    let read = self
+      .config
+      .iter()
+      .chain(self.blacklist.iter().map(|file| &file.path))
+      .chain(&self.zone_blacklist)
+      .chain(&self.zone_blacklist_exceptions)
+      .chain(&self.doh_bypass_list)
+      .chain(&self.ip_blacklist)
+      .chain(self.scheduled_zone_blacklist.iter().map(|list| &list.path))
+      .chain(&self.rpz)
+      .chain(self.zone_file.iter().map(|zone| &zone.path))
+      .chain(
+        self
+          .dnssec_sign_zone
+          .iter()
+          .filter_map(|zone| zone.key.as_ref()),
+      )
+      .chain(local_dns)
+      .chain(&self.dhcp_leases)
+      .chain(&self.upstream_ca)
+      .chain(&self.tls_certificate)
+      .chain(&self.tls_private_key)
+      .chain(&self.tls_client_ca)
+      .chain(&self.block_page_template)
+      .chain(&self.warmup_list)
+      .chain(&self.rfc8215_ips)
+      .chain(&self.geoip_database)
+      .chain(
+        self
+          .client_group
+          .iter()
+          .flat_map(|group| group.blacklist.iter().chain(&group.zone_blacklist)),
+      )
+      .map(directory);
+    let write = updated
+      .into_iter()
+      .chain(&self.query_log)
+      .chain(&self.audit_log)
+      .chain(&self.query_db)
+      .chain(&self.dnstap_file)
+      .chain(&self.control_socket)
+      .map(directory)
+      .chain((!self.acme_domain.is_empty()).then(|| self.acme_storage.clone()));
+    let sandbox = read.fold(Sandbox::new(), Sandbox::read);
+    write.fold(sandbox, Sandbox::write)
+  }
+
+  /// Addresses of a listener, `--listen` with its `port` when none are given.
+  fn listen_addrs(&self, addrs: &[String], port: u16) -> Result<Vec<SocketAddr>> {
+    if addrs.is_empty() {
+      return Ok(vec![self.socket_addr(port)?]);
+    }
+    addrs
+      .iter()
+      .map(|addr| {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+          return Ok(addr);
+        }
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+          return Ok(SocketAddr::new(ip, port));
+        }
+        let resolved = match addr.rsplit_once(':') {
+          Some((_, port)) if port.parse::<u16>().is_ok() => addr.to_socket_addrs(),
+          _ => (addr.as_str(), port).to_socket_addrs(),
+        };
+        resolved?
+          .next()
+          .with_context(|| format!("could not resolve listen address {}", addr))
+      })
+      .collect()
+  }
+
+  fn socket_addr(&self, port: u16) -> Result<SocketAddr> {
+    (self.listen.as_str(), port)
+      .to_socket_addrs()?
+      .next()
+      .with_context(|| format!("could not resolve listen address {}", self.listen))
+  }
+
+  /// IPs and networks of a file, one per line.
+  fn get_ip_ranges(&self, list: &Option<PathBuf>) -> Result<IpRangeVec> {
+    let ip_ranges: Vec<IpRange> = if let Some(path) = list {
+      let mut file =
+        std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
       let mut buffer = String::new();
-      file.read_to_string(&mut buffer).unwrap();
+      file.read_to_string(&mut buffer)?;
 
       buffer
         .split("\n")
         .map(|ip_range| ip_range.trim())
-        .filter(|ip_range| !ip_range.is_empty())
-        .map(|ip_range| IpRange::try_from(ip_range).unwrap())
-        .collect()
+        .filter(|ip_range| !ip_range.is_empty() && !ip_range.starts_with('#'))
+        .map(|ip_range| {
+          IpRange::try_from(ip_range)
+            .with_context(|| format!("invalid IP range {ip_range} in {}", path.display()))
+        })
+        .collect::<Result<_>>()?
     } else {
       vec![]
     };
 
-    IpRangeVec::new(ip_ranges)
+    Ok(IpRangeVec::new(ip_ranges))
+  }
+
+  fn get_optional_blacklist(&self, list: &Option<PathBuf>) -> Result<Option<DomainSet>> {
+    match list {
+      Some(_) => Ok(Some(self.get_blacklist(list)?)),
+      None => Ok(None),
+    }
   }
 
-  fn get_blacklist(&self, list: &Option<PathBuf>) -> HashSet<LowerName> {
+  fn get_blacklist(&self, list: &Option<PathBuf>) -> Result<DomainSet> {
     match &list {
-      Some(path) => {
-        let mut file = std::fs::File::open(path).unwrap();
-        let mut buffer = String::new();
-        file.read_to_string(&mut buffer).unwrap();
-        let mut set: HashSet<LowerName> = HashSet::new();
-
-        buffer
-          .split("\n")
-          .map(|domain| domain.trim().trim_end_matches("."))
-          .filter(|domain| !domain.is_empty())
-          .for_each(|domain| {
-            let lower_name = LowerName::from_str(&format!("{}.", domain)).unwrap();
-            set.insert(lower_name);
-          });
+      Some(path) => DomainSet::load(path),
+      None => Ok(DomainSet::new()),
+    }
+  }
+}
+
+#[cfg(unix)]
+impl Ctl {
+  /// Send the command to the control socket, print the answer and exit.
+  fn run(&self, args: &DNSServer) -> ! {
+    let socket = self
+      .socket
+      .clone()
+      .or_else(|| args.control_socket.clone())
+      .unwrap_or_else(|| PathBuf::from(DEFAULT_CONTROL_SOCKET));
+    let runtime = runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .expect("failed to initialize Tokio Runtime");
+    match runtime.block_on(control::send(&socket, &self.action.command())) {
+      Ok(answer) => {
+        println!("{answer}");
+        std::process::exit(0)
+      }
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
+      }
+    }
+  }
+}
 
-        set
+impl QueryCommand {
+  /// Send the query, print the response and exit.
+  fn run(&self, args: &DNSServer) -> ! {
+    let runtime = runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .expect("failed to initialize Tokio Runtime");
+    match runtime.block_on(self.send(args)) {
+      Ok(response) => {
+        println!("{response}");
+        std::process::exit(0)
+      }
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
       }
-      None => HashSet::new(),
     }
   }
+
+  async fn send(&self, args: &DNSServer) -> Result<String> {
+    let mut name = self.name.clone();
+    name.set_fqdn(true);
+    let record_type = query::parse_record_type(&self.record_type)?;
+    let timeout = control::parse_duration(&self.timeout)?;
+    let server = self.target.server(args)?;
+    let tls = self.target.tls(args, server)?;
+
+    let message = query::message(&name, record_type);
+    let start = Instant::now();
+    let protocol = self.target.protocol;
+    let response = query::send(&message, server, protocol, Some(&tls), timeout).await?;
+    Ok(format!(
+      "{response};; from {server} over {protocol} in {} ms",
+      start.elapsed().as_millis()
+    ))
+  }
+}
+
+impl QueryTarget {
+  /// Address of the server, the first listener of the protocol by default.
+  fn server(&self, args: &DNSServer) -> Result<SocketAddr> {
+    if let Some(server) = self.server {
+      return Ok(server);
+    }
+    let addrs = match self.protocol {
+      Transport::Udp | Transport::Tcp => args.listen_addrs(&args.listen_udp, args.port)?,
+      Transport::Tls => args.listen_addrs(&args.listen_tls, args.tls_port)?,
+      Transport::H2 => args.listen_addrs(&args.listen_h2, args.h2_port)?,
+    };
+    addrs
+      .first()
+      .copied()
+      .map(healthcheck::local_addr)
+      .context("no listener to query")
+  }
+
+  fn tls(&self, args: &DNSServer, server: SocketAddr) -> Result<TlsClient> {
+    Ok(TlsClient {
+      name: match &self.tls_name {
+        Some(name) => name.clone(),
+        None => args
+          .acme_domain
+          .first()
+          .cloned()
+          .unwrap_or_else(|| server.ip().to_string()),
+      },
+      config: if self.insecure {
+        tls::insecure_client_config()
+      } else {
+        tls::client_config(self.ca.as_deref(), &self.pin)?
+      },
+      credential: args.h2_auth_token.clone(),
+    })
+  }
 }
 
-fn logger() {
-  let filter = tracing_subscriber::EnvFilter::builder()
-    .with_default_directive(tracing::Level::WARN.into())
-    .from_env()
-    .expect("Fail to create logger");
+impl Bench {
+  /// Run the benchmark, print its report and exit.
+  fn run(&self, args: &DNSServer) -> ! {
+    let runtime = runtime::Builder::new_multi_thread()
+      .enable_all()
+      .worker_threads(args.worker)
+      .thread_name("dns-server-bench")
+      .build()
+      .expect("failed to initialize Tokio Runtime");
+    match runtime.block_on(self.bench(args)) {
+      Ok(report) => {
+        println!("{report}");
+        std::process::exit(0)
+      }
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
+      }
+    }
+  }
+
+  async fn bench(&self, args: &DNSServer) -> Result<BenchReport> {
+    let queries = match &self.queries {
+      Some(path) => bench::read_queries(path)?,
+      None => {
+        let record_type = query::parse_record_type(&self.record_type)?;
+        self
+          .names
+          .iter()
+          .map(|name| {
+            let mut name = name.clone();
+            name.set_fqdn(true);
+            (name, record_type)
+          })
+          .collect()
+      }
+    };
+    // Kept until the end of the benchmark.
+    let mut local_server = None;
+    let server = if self.in_process {
+      if self.target.protocol != Transport::Udp {
+        bail!("the server started with --in-process only listens on UDP");
+      }
+      let mut server = args
+        .generate_catalog_builder()
+        .await?
+        .listen_udp((Ipv4Addr::LOCALHOST, 0).into())
+        .build()?;
+      server.start().await?;
+      let addr = server.udp_addrs()[0];
+      local_server = Some(server);
+      addr
+    } else {
+      self.target.server(args)?
+    };
+    let tls = match self.target.protocol {
+      Transport::Tls | Transport::H2 => Some(self.target.tls(args, server)?),
+      Transport::Udp | Transport::Tcp => None,
+    };
+    println!(
+      "Sending {} queries per second to {} over {}",
+      self.qps, server, self.target.protocol
+    );
+    let report = bench::run(BenchOptions {
+      server,
+      transport: self.target.protocol,
+      tls,
+      queries,
+      qps: self.qps,
+      duration: control::parse_duration(&self.duration)?,
+      timeout: control::parse_duration(&self.timeout)?,
+    })
+    .await;
+    drop(local_server);
+    report
+  }
+}
+
+impl ServiceCommand {
+  /// Install or uninstall the service and exit, `run` goes on with the server.
+  fn run(&self) {
+    let (label, install, print) = match &self.action {
+      ServiceAction::Run => return,
+      ServiceAction::Install { label, print } => (label, true, *print),
+      ServiceAction::Uninstall { label } => (label, false, false),
+    };
+    let result = std::env::current_exe()
+      .context("could not find the path of dns-server")
+      .and_then(|program| {
+        let service = Service {
+          label: label.clone(),
+          program,
+          args: installed_options(),
+        };
+        if print {
+          let (_, definition) = service.definition()?;
+          print!("{definition}");
+          Ok(())
+        } else if install {
+          let path = service.install()?;
+          println!("Installed and started {}", path.display());
+          Ok(())
+        } else {
+          let path = service.uninstall()?;
+          println!("Stopped and removed {}", path.display());
+          Ok(())
+        }
+      });
+    match result {
+      Ok(()) => std::process::exit(0),
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
+      }
+    }
+  }
+}
+
+/// Server options of the command line, the ones given before `service install`.
+fn installed_options() -> Vec<String> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let end = args
+    .windows(2)
+    .position(|pair| pair[0] == "service" && pair[1] == "install")
+    .unwrap_or(args.len());
+  args[..end].to_vec()
+}
+
+impl TestBlock {
+  /// Print how the name would be answered and exit.
+  fn run(&self, args: &DNSServer) -> ! {
+    let mut name = self.name.clone();
+    name.set_fqdn(true);
+    match args.test_block(&LowerName::new(&name), self.client) {
+      Ok(report) => {
+        for line in report {
+          println!("{line}");
+        }
+        std::process::exit(0)
+      }
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
+      }
+    }
+  }
+}
+
+impl ImportCommand {
+  /// Convert the file, print the written files and the skipped lines, and exit.
+  fn run(&self) -> ! {
+    let (ImportFormat::Dnsmasq { file }
+    | ImportFormat::Blocky { file }
+    | ImportFormat::Unbound { file }) = &self.format;
+    let converted = std::fs::read_to_string(file)
+      .with_context(|| format!("could not read {}", file.display()))
+      .and_then(|content| match self.format {
+        ImportFormat::Dnsmasq { .. } => Ok(import::dnsmasq::import(&content)),
+        ImportFormat::Blocky { .. } => import::blocky::import(&content),
+        ImportFormat::Unbound { .. } => Ok(import::unbound::import(&content)),
+      })
+      .and_then(|import| Ok((import.write(&self.output, self.force)?, import.skipped)));
+    match converted {
+      Ok((written, skipped)) => {
+        for path in written {
+          println!("Wrote {}", path.display());
+        }
+        for setting in skipped {
+          println!("Skipped {setting}");
+        }
+        std::process::exit(0)
+      }
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
+      }
+    }
+  }
+}
+
+impl Healthcheck {
+  /// Look up the name on the server, print the result and exit.
+  fn run(&self, args: &DNSServer) -> ! {
+    let check = control::parse_duration(&self.timeout).and_then(|timeout| {
+      let server = match self.server {
+        Some(server) => server,
+        None => args
+          .listen_addrs(&args.listen_udp, args.port)?
+          .first()
+          .copied()
+          .map(healthcheck::local_addr)
+          .context("no UDP listener to check")?,
+      };
+      let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to initialize Tokio Runtime");
+      runtime.block_on(healthcheck::check(server, &self.name, timeout))?;
+      Ok(server)
+    });
+    match check {
+      Ok(server) => {
+        println!("{server} is healthy");
+        std::process::exit(0)
+      }
+      Err(err) => {
+        eprintln!("{err:#}");
+        std::process::exit(1)
+      }
+    }
+  }
+}
 
-  let formatter = tracing_subscriber::fmt::layer();
+fn logger(output: &LogOutput) {
+  let filter = match std::env::var_os(tracing_subscriber::EnvFilter::DEFAULT_ENV) {
+    Some(_) => tracing_subscriber::EnvFilter::builder()
+      .with_default_directive(tracing::Level::WARN.into())
+      .from_env()
+      .expect("Fail to create logger"),
+    // The statistics summaries are only written when asked for.
+    None => tracing_subscriber::EnvFilter::new(DEFAULT_LOG_FILTER),
+  };
+  let output = output
+    .layer()
+    .unwrap_or_else(|err| panic!("could not open the log output : {err:#}"));
 
   tracing_subscriber::registry()
-    .with(formatter)
+    .with(output)
     .with(filter)
     .init();
 }
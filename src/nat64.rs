@@ -0,0 +1,263 @@
+use crate::ip::{IpRange, IpRangeVec};
+use anyhow::{bail, Context, Result};
+use hickory_server::proto::rr::Name;
+use ipnet::{IpNet, Ipv6Net};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Well-known prefix of the IPv4/IPv6 translators (RFC 6052).
+pub const WELL_KNOWN_PREFIX: &str = "64:ff9b::/96";
+
+/// IPv6 never used by the clients behind NAT64, the IPv4-mapped addresses (RFC 6147).
+pub const EXCLUDED_PREFIX: &str = "::ffff:0:0/96";
+
+/// Lengths of the prefixes where an IPv4 can be embedded (RFC 6052 section 2.2).
+const PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+/// Prefix of the NAT64 translator synthesizing the IPv6 of IPv4 addresses, used for the clients
+/// of `networks` or for all clients when there is none: `prefix[@cidr,...]`, e.g.
+/// `64:ff9b:1:a::/96@10.0.0.0/8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nat64Prefix {
+  pub prefix: Ipv6Net,
+  pub networks: Vec<String>,
+}
+
+impl Nat64Prefix {
+  pub fn new(prefix: Ipv6Net) -> Result<Self> {
+    if !PREFIX_LENGTHS.contains(&prefix.prefix_len()) {
+      bail!("the length of the NAT64 prefix {prefix} must be 32, 40, 48, 56, 64 or 96 (RFC 6052)");
+    }
+    Ok(Self {
+      prefix: prefix.trunc(),
+      networks: vec![],
+    })
+  }
+
+  pub fn well_known() -> Self {
+    Self::new(WELL_KNOWN_PREFIX.parse().unwrap()).unwrap()
+  }
+
+  /// IPv6 of `ip` behind the translator, the bits 64 to 71 are left to zero.
+  pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = self.prefix.network().octets();
+    let mut index = (self.prefix.prefix_len() / 8) as usize;
+    for octet in ip.octets() {
+      if index == 8 {
+        index += 1;
+      }
+      octets[index] = octet;
+      index += 1;
+    }
+    Ipv6Addr::from(octets)
+  }
+
+  /// IPv4 embedded in `ip`, `None` when it is not in the prefix.
+  pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if !self.prefix.contains(&ip) {
+      return None;
+    }
+    let octets = ip.octets();
+    let mut index = (self.prefix.prefix_len() / 8) as usize;
+    let mut ipv4 = [0; 4];
+    for octet in ipv4.iter_mut() {
+      if index == 8 {
+        index += 1;
+      }
+      *octet = octets[index];
+      index += 1;
+    }
+    Some(Ipv4Addr::from(ipv4))
+  }
+
+  pub fn ip_ranges(&self) -> Result<Vec<IpRange>> {
+    self
+      .networks
+      .iter()
+      .map(|network| {
+        IpRange::try_from(network.as_str()).with_context(|| format!("invalid IP range {network}"))
+      })
+      .collect()
+  }
+}
+
+impl FromStr for Nat64Prefix {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (prefix, networks) = match s.split_once('@') {
+      Some((prefix, networks)) => (prefix, Some(networks)),
+      None => (s, None),
+    };
+    let prefix = prefix
+      .trim()
+      .parse::<Ipv6Net>()
+      .with_context(|| format!("invalid NAT64 prefix `{prefix}`, expected ipv6/length"))?;
+    let mut nat64 = Nat64Prefix::new(prefix)?;
+    if let Some(networks) = networks {
+      nat64.networks = networks.split(',').map(|n| n.trim().to_string()).collect();
+      nat64.ip_ranges()?;
+    }
+    Ok(nat64)
+  }
+}
+
+impl fmt::Display for Nat64Prefix {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.prefix)?;
+    if !self.networks.is_empty() {
+      write!(f, "@{}", self.networks.join(","))?;
+    }
+    Ok(())
+  }
+}
+
+impl Serialize for Nat64Prefix {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Nat64Prefix {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Nat64Prefix::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// NAT64 prefixes by client network, the first prefix whose networks contain the client is
+/// used, then the prefix without networks, then the well-known prefix. AAAA records in the
+/// excluded networks are ignored, as if the name had no IPv6.
+#[derive(Debug, Clone)]
+pub struct Nat64 {
+  prefixes: Vec<(IpRangeVec, Nat64Prefix)>,
+  default: Nat64Prefix,
+  excluded: IpRangeVec,
+}
+
+impl Default for Nat64 {
+  fn default() -> Self {
+    Self {
+      prefixes: vec![],
+      default: Nat64Prefix::well_known(),
+      excluded: IpRangeVec::new(vec![IpRange::try_from(EXCLUDED_PREFIX).unwrap()]),
+    }
+  }
+}
+
+impl Nat64 {
+  pub fn new(prefixes: &[Nat64Prefix]) -> Result<Self> {
+    let mut nat64 = Self::default();
+    for prefix in prefixes {
+      if prefix.networks.is_empty() {
+        nat64.default = prefix.clone();
+      } else {
+        let ranges = IpRangeVec::new(prefix.ip_ranges()?);
+        nat64.prefixes.push((ranges, prefix.clone()));
+      }
+    }
+    Ok(nat64)
+  }
+
+  /// Networks of the ignored AAAA records, `::ffff:0:0/96` by default.
+  pub fn excluded(mut self, networks: IpRangeVec) -> Self {
+    self.excluded = networks;
+    self
+  }
+
+  pub fn is_excluded(&self, ip: Ipv6Addr) -> bool {
+    self.excluded.contains(ip.into())
+  }
+
+  /// IPv4 embedded in the IPv6 of a reverse name (`ip6.arpa`), `None` when the IPv6 is not in
+  /// one of the prefixes.
+  pub fn reverse_ipv4(&self, name: &Name) -> Option<Ipv4Addr> {
+    let IpNet::V6(network) = name.parse_arpa_name().ok()? else {
+      return None;
+    };
+    if network.prefix_len() != 128 {
+      return None;
+    }
+    self
+      .prefixes
+      .iter()
+      .map(|(_, prefix)| prefix)
+      .chain([&self.default])
+      .find_map(|prefix| prefix.extract(network.addr()))
+  }
+
+  /// Prefix used for `client`.
+  pub fn prefix(&self, client: IpAddr) -> &Nat64Prefix {
+    let client = client.to_canonical();
+    self
+      .prefixes
+      .iter()
+      .find(|(ranges, _)| ranges.contains(client))
+      .map(|(_, prefix)| prefix)
+      .unwrap_or(&self.default)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn embedded_ipv4() {
+    let ipv4 = Ipv4Addr::new(192, 0, 2, 33);
+    // Examples of RFC 6052 section 2.4.
+    for (prefix, ipv6) in [
+      ("2001:db8::/32", "2001:db8:c000:221::"),
+      ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+      ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+      ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+      ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+      ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+      (WELL_KNOWN_PREFIX, "64:ff9b::192.0.2.33"),
+    ] {
+      let prefix = Nat64Prefix::from_str(prefix).unwrap();
+      let ipv6 = Ipv6Addr::from_str(ipv6).unwrap();
+      assert_eq!(prefix.embed(ipv4), ipv6, "{prefix}");
+      assert_eq!(prefix.extract(ipv6), Some(ipv4), "{prefix}");
+    }
+    let prefix = Nat64Prefix::well_known();
+    assert_eq!(prefix.extract("64:ff9b:1::1".parse().unwrap()), None);
+    assert!(Nat64Prefix::from_str("64:ff9b:1::/80").is_err());
+  }
+
+  #[test]
+  fn prefixes() {
+    let site = Nat64Prefix::from_str("64:ff9b:1:a::/96@10.0.0.0/8, fd00::/8").unwrap();
+    assert_eq!(site.to_string(), "64:ff9b:1:a::/96@10.0.0.0/8,fd00::/8");
+    let default = Nat64Prefix::from_str("64:ff9b:1::/48").unwrap();
+    let nat64 = Nat64::new(&[site.clone(), default.clone()]).unwrap();
+    assert_eq!(nat64.prefix("10.1.2.3".parse().unwrap()), &site);
+    assert_eq!(nat64.prefix("::ffff:10.1.2.3".parse().unwrap()), &site);
+    assert_eq!(nat64.prefix("192.168.1.2".parse().unwrap()), &default);
+    let nat64 = Nat64::default();
+    assert_eq!(
+      nat64.prefix("10.1.2.3".parse().unwrap()),
+      &Nat64Prefix::well_known()
+    );
+    assert!(Nat64Prefix::from_str("64:ff9b::/96@10.0.0.0/33").is_err());
+    assert!(nat64.is_excluded("::ffff:192.0.2.33".parse().unwrap()));
+
+    let reverse = |ip: &str| Name::from(ip.parse::<IpAddr>().unwrap());
+    let nat64 = Nat64::new(&[site]).unwrap();
+    assert_eq!(
+      nat64.reverse_ipv4(&reverse("64:ff9b:1:a::192.0.2.33")),
+      Some(Ipv4Addr::new(192, 0, 2, 33))
+    );
+    assert_eq!(
+      nat64.reverse_ipv4(&reverse("64:ff9b::198.51.100.1")),
+      Some(Ipv4Addr::new(198, 51, 100, 1))
+    );
+    assert_eq!(nat64.reverse_ipv4(&reverse("2001:db8::1")), None);
+    assert_eq!(nat64.reverse_ipv4(&reverse("192.0.2.33")), None);
+    let partial = Name::from_str("b.9.f.f.4.6.0.0.ip6.arpa.").unwrap();
+    assert_eq!(nat64.reverse_ipv4(&partial), None);
+    assert!(!nat64.is_excluded("2001:db8::1".parse().unwrap()));
+  }
+}
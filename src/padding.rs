@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Context};
+use hickory_server::{
+  authority::{MessageRequest, MessageResponse, MessageResponseBuilder},
+  proto::{
+    op::{Message, OpCode},
+    rr::{
+      rdata::opt::{EdnsCode, EdnsOption},
+      Record,
+    },
+    serialize::binary::{BinDecodable, BinEncodable, BinEncoder},
+  },
+  server::{Protocol, Request, ResponseHandler, ResponseInfo},
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Block size recommended for the responses by RFC 8467.
+pub const DEFAULT_BLOCK_SIZE: u16 = 468;
+
+/// Size of the option code and length of the padding option.
+const OPTION_HEADER: usize = 4;
+
+/// Padding of the responses sent over the encrypted transports (RFC 7830), so their size does not
+/// tell which name was queried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+  None,
+  /// Pad the responses to a multiple of this size, as recommended by RFC 8467.
+  BlockSize(u16),
+}
+
+impl Default for Padding {
+  fn default() -> Self {
+    Self::BlockSize(DEFAULT_BLOCK_SIZE)
+  }
+}
+
+impl FromStr for Padding {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim().split_once(':') {
+      None if s.trim() == "none" => Ok(Self::None),
+      Some(("block-size", size)) => {
+        let size = size
+          .trim()
+          .parse::<u16>()
+          .with_context(|| format!("invalid block size `{size}`"))?;
+        match size {
+          0 => Err(anyhow!("the block size must be positive")),
+          size => Ok(Self::BlockSize(size)),
+        }
+      }
+      _ => Err(anyhow!(
+        "invalid padding `{s}`, expected `none` or `block-size:size`"
+      )),
+    }
+  }
+}
+
+impl fmt::Display for Padding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::None => write!(f, "none"),
+      Self::BlockSize(size) => write!(f, "block-size:{size}"),
+    }
+  }
+}
+
+impl Serialize for Padding {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Padding {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Padding::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+impl Padding {
+  /// Block size of the response to `request`: only the queries received over DoT or DoH with a
+  /// padding option are padded, the servers must not pad the other responses.
+  pub fn block_size(&self, request: &Request) -> Option<u16> {
+    let Self::BlockSize(size) = self else {
+      return None;
+    };
+    let encrypted = matches!(
+      request.protocol(),
+      Protocol::Tls | Protocol::Https | Protocol::Quic | Protocol::H3
+    );
+    let requested = request
+      .edns()
+      .is_some_and(|edns| edns.option(EdnsCode::Padding).is_some());
+    (encrypted && requested && request.op_code() == OpCode::Query).then_some(*size)
+  }
+}
+
+/// Adds a padding option to the responses so their size is a multiple of the block size.
+#[derive(Clone)]
+pub struct PaddingResponseHandler<R: ResponseHandler> {
+  inner: R,
+  block_size: usize,
+  /// Request to build the padded response again.
+  request: Option<Arc<MessageRequest>>,
+}
+
+impl<R: ResponseHandler> PaddingResponseHandler<R> {
+  pub fn new(inner: R, block_size: u16, request: &Request) -> Self {
+    let request = request
+      .to_bytes()
+      .ok()
+      .and_then(|bytes| MessageRequest::from_bytes(&bytes).ok())
+      .map(Arc::new);
+    Self {
+      inner,
+      block_size: block_size as usize,
+      request,
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for PaddingResponseHandler<R> {
+  async fn send_response<'a>(
+    &mut self,
+    response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    let (Some(request), Some(edns)) = (&self.request, response.get_edns().clone()) else {
+      return self.inner.send_response(response).await;
+    };
+    // The size of the response is only known once encoded.
+    let mut buffer = Vec::with_capacity(self.block_size);
+    response
+      .destructive_emit(&mut BinEncoder::new(&mut buffer))
+      .map_err(io::Error::other)?;
+    let message = Message::from_vec(&buffer).map_err(io::Error::other)?;
+    let length = buffer.len() + OPTION_HEADER;
+    let padding = (self.block_size - length % self.block_size) % self.block_size;
+    let mut edns = edns;
+    if length + padding <= u16::MAX as usize {
+      edns.options_mut().insert(EdnsOption::Unknown(
+        EdnsCode::Padding.into(),
+        vec![0; padding],
+      ));
+    }
+    let mut response = MessageResponseBuilder::from_message_request(request).build(
+      *message.header(),
+      message.answers(),
+      message.name_servers(),
+      &[],
+      message.additionals(),
+    );
+    response.set_edns(edns);
+    self.inner.send_response(response).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::handler::ResponseBuffer;
+  use hickory_server::proto::op::{Edns, Header, Query};
+  use hickory_server::proto::rr::{Name, RData, RecordType};
+
+  #[test]
+  fn parse() {
+    assert_eq!(Padding::from_str("none").unwrap(), Padding::None);
+    let padding = Padding::from_str("block-size:468").unwrap();
+    assert_eq!(padding, Padding::BlockSize(468));
+    assert_eq!(padding.to_string(), "block-size:468");
+    assert!(Padding::from_str("block-size:0").is_err());
+    assert!(Padding::from_str("468").is_err());
+  }
+
+  #[tokio::test]
+  async fn pad_responses() {
+    let name = Name::from_str("www.example.com.").unwrap();
+    let answer = Record::from_rdata(
+      name.clone(),
+      60,
+      RData::A(std::net::Ipv4Addr::new(192, 0, 2, 1).into()),
+    );
+    let request = |protocol, padding: bool| {
+      let mut query = Message::new();
+      query.add_query(Query::query(name.clone(), RecordType::A));
+      let mut edns = Edns::new();
+      if padding {
+        edns
+          .options_mut()
+          .insert(EdnsOption::Unknown(EdnsCode::Padding.into(), vec![]));
+      }
+      query.set_edns(edns);
+      let request = MessageRequest::from_bytes(&query.to_vec().unwrap()).unwrap();
+      Request::new(request, "127.0.0.1:853".parse().unwrap(), protocol)
+    };
+
+    let padding = Padding::default();
+    assert_eq!(padding.block_size(&request(Protocol::Tls, true)), Some(468));
+    assert_eq!(padding.block_size(&request(Protocol::Tls, false)), None);
+    assert_eq!(padding.block_size(&request(Protocol::Udp, true)), None);
+    assert_eq!(
+      Padding::None.block_size(&request(Protocol::Https, true)),
+      None
+    );
+
+    let request = request(Protocol::Tls, true);
+    let buffer = ResponseBuffer::default();
+    let mut handler = PaddingResponseHandler::new(buffer.clone(), 468, &request);
+    let mut builder = MessageResponseBuilder::from_message_request(&request);
+    builder.edns(Edns::new());
+    let header = Header::response_from_request(request.header());
+    let response = builder.build(header, [&answer], [], [], []);
+    handler.send_response(response).await.unwrap();
+    let response = buffer.take().unwrap();
+    assert_eq!(response.len(), 468);
+    let message = Message::from_vec(&response).unwrap();
+    assert_eq!(message.answers(), [answer]);
+  }
+}
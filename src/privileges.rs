@@ -0,0 +1,148 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::{CStr, CString};
+use std::io;
+
+/// Unprivileged account the server runs as once its listeners are bound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+  pub uid: libc::uid_t,
+  pub gid: libc::gid_t,
+  /// Name of the user, its supplementary groups are set from it.
+  pub name: Option<CString>,
+}
+
+impl Account {
+  /// Account of `user` (name or uid) and `group` (name or gid). The group defaults to the primary
+  /// group of the user, at least one of them must be given.
+  pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Self> {
+    let (uid, primary_gid, name) = match user {
+      Some(user) => lookup_user(user)?,
+      None => (unsafe { libc::getuid() }, None, None),
+    };
+    let gid = match (group, primary_gid, user) {
+      (Some(group), _, _) => lookup_group(group)?,
+      (None, Some(gid), _) => gid,
+      (None, None, Some(user)) => bail!("the user {user} has no account, its group must be given"),
+      (None, None, None) => bail!("a user or a group is required to drop privileges"),
+    };
+    Ok(Self { uid, gid, name })
+  }
+
+  /// Switch the process to this account, all the threads included. It can not go back.
+  pub fn switch(&self) -> Result<()> {
+    // Groups first, they can not be changed anymore once the user is not root.
+    let groups = match &self.name {
+      Some(name) => unsafe { libc::initgroups(name.as_ptr(), self.gid as _) },
+      None => unsafe { libc::setgroups(1, &self.gid) },
+    };
+    if groups != 0 {
+      return Err(io::Error::last_os_error()).context("could not set the supplementary groups");
+    }
+    if unsafe { libc::setgid(self.gid) } != 0 {
+      return Err(io::Error::last_os_error())
+        .with_context(|| format!("could not set the group {}", self.gid));
+    }
+    if unsafe { libc::setuid(self.uid) } != 0 {
+      return Err(io::Error::last_os_error())
+        .with_context(|| format!("could not set the user {}", self.uid));
+    }
+    if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+      bail!(
+        "root privileges could be restored after switching to user {}",
+        self.uid
+      );
+    }
+    Ok(())
+  }
+}
+
+/// Uid, primary gid and name of a user.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>, Option<CString>)> {
+  let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+  let mut result = std::ptr::null_mut();
+  let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+  let found = match user.parse::<libc::uid_t>() {
+    Ok(uid) => unsafe {
+      libc::getpwuid_r(
+        uid,
+        &mut passwd,
+        buffer.as_mut_ptr(),
+        buffer.len(),
+        &mut result,
+      )
+    },
+    Err(_) => {
+      let name = CString::new(user).context("invalid user name")?;
+      unsafe {
+        libc::getpwnam_r(
+          name.as_ptr(),
+          &mut passwd,
+          buffer.as_mut_ptr(),
+          buffer.len(),
+          &mut result,
+        )
+      }
+    }
+  };
+  if found != 0 {
+    return Err(io::Error::from_raw_os_error(found))
+      .with_context(|| format!("could not look up the user {user}"));
+  }
+  if result.is_null() {
+    // An unknown uid can still be used, without groups of its own.
+    return match user.parse() {
+      Ok(uid) => Ok((uid, None, None)),
+      Err(_) => bail!("unknown user {user}"),
+    };
+  }
+  let name = unsafe { CStr::from_ptr(passwd.pw_name) }.to_owned();
+  Ok((passwd.pw_uid, Some(passwd.pw_gid), Some(name)))
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+  if let Ok(gid) = group.parse() {
+    return Ok(gid);
+  }
+  let name = CString::new(group).context("invalid group name")?;
+  let mut entry: libc::group = unsafe { std::mem::zeroed() };
+  let mut result = std::ptr::null_mut();
+  let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+  let found = unsafe {
+    libc::getgrnam_r(
+      name.as_ptr(),
+      &mut entry,
+      buffer.as_mut_ptr(),
+      buffer.len(),
+      &mut result,
+    )
+  };
+  if found != 0 {
+    return Err(io::Error::from_raw_os_error(found))
+      .with_context(|| format!("could not look up the group {group}"));
+  }
+  if result.is_null() {
+    bail!("unknown group {group}");
+  }
+  Ok(entry.gr_gid)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn lookup() {
+    let root = Account::lookup(Some("root"), None).unwrap();
+    assert_eq!((root.uid, root.gid), (0, 0));
+    assert_eq!(root.name.as_deref(), CString::new("root").ok().as_deref());
+    let numeric = Account::lookup(Some("65000"), Some("65001")).unwrap();
+    assert_eq!(
+      (numeric.uid, numeric.gid, numeric.name),
+      (65000, 65001, None)
+    );
+    assert!(Account::lookup(Some("65000"), None).is_err());
+    assert!(Account::lookup(Some("no-such-user-dns-server"), None).is_err());
+    assert!(Account::lookup(Some("root"), Some("no-such-group-dns-server")).is_err());
+    assert!(Account::lookup(None, None).is_err());
+  }
+}
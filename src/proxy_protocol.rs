@@ -0,0 +1,135 @@
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// First bytes of a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, with its `\r\n`.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read the PROXY protocol header (version 1 or 2) a load balancer sends before the data of a
+/// connection, and return the address of its client. `None` is returned when the load balancer
+/// does not know it (`UNKNOWN` or `LOCAL` connections, like its health checks).
+///
+/// Only the header is read from the stream, the bytes following it are left for the listener.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+  let mut start = [0u8; 12];
+  stream
+    .read_exact(&mut start)
+    .await
+    .context("could not read the PROXY protocol header")?;
+  if start == V2_SIGNATURE {
+    read_v2(stream).await
+  } else if start.starts_with(b"PROXY ") {
+    read_v1(stream, &start).await
+  } else {
+    bail!("missing PROXY protocol header");
+  }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+  let mut header = start.to_vec();
+  while !header.ends_with(b"\r\n") {
+    if header.len() >= V1_MAX_LENGTH {
+      bail!("PROXY protocol header too long");
+    }
+    header.push(stream.read_u8().await?);
+  }
+  parse_v1(std::str::from_utf8(&header[..header.len() - 2])?)
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>`, or `PROXY UNKNOWN ...`.
+fn parse_v1(header: &str) -> Result<Option<SocketAddr>> {
+  let fields: Vec<&str> = header.split(' ').collect();
+  match fields.as_slice() {
+    ["PROXY", "UNKNOWN", ..] => Ok(None),
+    ["PROXY", "TCP4" | "TCP6", src, _, port, _] => {
+      let ip: IpAddr = src
+        .parse()
+        .with_context(|| format!("invalid PROXY protocol source {src}"))?;
+      let port = port
+        .parse()
+        .with_context(|| format!("invalid PROXY protocol port {port}"))?;
+      Ok(Some(SocketAddr::new(ip, port)))
+    }
+    _ => bail!("invalid PROXY protocol header `{header}`"),
+  }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+  let mut fixed = [0u8; 4];
+  stream.read_exact(&mut fixed).await?;
+  let mut addresses = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+  stream.read_exact(&mut addresses).await?;
+  parse_v2(fixed[0], fixed[1], &addresses)
+}
+
+/// Source address of a version 2 header from its command, family and address block.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>> {
+  if version_command >> 4 != 2 {
+    bail!(
+      "unsupported PROXY protocol version {}",
+      version_command >> 4
+    );
+  }
+  match version_command & 0x0f {
+    // LOCAL: the connection comes from the load balancer itself.
+    0 => return Ok(None),
+    1 => {}
+    command => bail!("unsupported PROXY protocol command {command}"),
+  }
+  match family >> 4 {
+    1 if addresses.len() >= 12 => {
+      let ip: [u8; 4] = addresses[0..4].try_into()?;
+      let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+      Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+    }
+    2 if addresses.len() >= 36 => {
+      let ip: [u8; 16] = addresses[0..16].try_into()?;
+      let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+      Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+    }
+    // Unspecified or unix sockets, the address of the connection is kept.
+    0 | 3 => Ok(None),
+    _ => bail!("invalid PROXY protocol addresses"),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn version_1() {
+    let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 853\r\n\x00\x1d";
+    let src = read_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(stream, b"\x00\x1d");
+
+    let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n";
+    let src = read_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("[2001:db8::1]:4000".parse().unwrap()));
+    let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(read_header(&mut stream).await.unwrap(), None);
+    let mut stream: &[u8] = b"\x00\x1d\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00";
+    assert!(read_header(&mut stream).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn version_2() {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend([
+      0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x03, 0x55,
+    ]);
+    header.extend(b"query");
+    let mut stream = header.as_slice();
+    let src = read_header(&mut stream).await.unwrap();
+    assert_eq!(src, Some("192.0.2.1:56324".parse().unwrap()));
+    assert_eq!(stream, b"query");
+
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend([0x20, 0x00, 0, 0]);
+    assert_eq!(read_header(&mut header.as_slice()).await.unwrap(), None);
+    assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+  }
+}
@@ -0,0 +1,266 @@
+use crate::doh::Credential;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use hickory_rustls::{ClientConfig, ServerName};
+use hickory_server::proto::{
+  op::{Edns, Message, MessageType, OpCode, Query},
+  rr::{Name, RecordType},
+};
+use http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+
+/// Payload size announced in the queries, the one of DNS flag day 2020.
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+const MIME_APPLICATION_DNS: &str = "application/dns-message";
+
+/// Protocol used to send a query to a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+  /// UDP, retried over TCP when the response is truncated.
+  #[default]
+  Udp,
+  Tcp,
+  Tls,
+  H2,
+}
+
+impl FromStr for Transport {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "udp" => Ok(Transport::Udp),
+      "tcp" => Ok(Transport::Tcp),
+      "tls" => Ok(Transport::Tls),
+      "h2" => Ok(Transport::H2),
+      _ => bail!("unknown protocol `{s}`, expected udp, tcp, tls or h2"),
+    }
+  }
+}
+
+impl fmt::Display for Transport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Transport::Udp => write!(f, "udp"),
+      Transport::Tcp => write!(f, "tcp"),
+      Transport::Tls => write!(f, "tls"),
+      Transport::H2 => write!(f, "h2"),
+    }
+  }
+}
+
+impl Serialize for Transport {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Transport {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    Transport::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// TLS settings of the `tls` and `h2` transports.
+#[derive(Clone)]
+pub struct TlsClient {
+  /// Name checked in the certificate of the server, also the authority of the H2 requests.
+  pub name: String,
+  pub config: Arc<ClientConfig>,
+  /// Credential sent in the `Authorization` header of the H2 requests.
+  pub credential: Option<Credential>,
+}
+
+/// Record type from its name, in any case, or its number.
+pub fn parse_record_type(value: &str) -> Result<RecordType> {
+  match value.parse::<u16>() {
+    Ok(number) => Ok(RecordType::from(number)),
+    Err(_) => RecordType::from_str(&value.to_uppercase())
+      .with_context(|| format!("invalid record type `{value}`")),
+  }
+}
+
+/// Recursive query for the `record_type` records of `name`, announcing EDNS.
+pub fn message(name: &Name, record_type: RecordType) -> Message {
+  let mut message = Message::new();
+  message
+    .set_id(RandomState::new().hash_one(Instant::now()) as u16)
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(true)
+    .add_query(Query::query(name.clone(), record_type));
+  let mut edns = Edns::new();
+  edns.set_max_payload(UDP_PAYLOAD_SIZE);
+  message.set_edns(edns);
+  message
+}
+
+/// Send the query to `server` and wait for its response at most `timeout`. The `tls` settings
+/// are required by the `tls` and `h2` transports.
+pub async fn send(
+  message: &Message,
+  server: SocketAddr,
+  transport: Transport,
+  tls: Option<&TlsClient>,
+  timeout: Duration,
+) -> Result<Message> {
+  let request = message.to_vec()?;
+  let exchange = async {
+    match (transport, tls) {
+      (Transport::Udp, _) => {
+        let response = send_udp(server, message.id(), &request).await?;
+        if response.truncated() {
+          send_stream(TcpStream::connect(server).await?, &request).await
+        } else {
+          Ok(response)
+        }
+      }
+      (Transport::Tcp, _) => send_stream(TcpStream::connect(server).await?, &request).await,
+      (Transport::Tls, Some(tls)) => {
+        let stream = connect_tls(server, tls, vec![]).await?;
+        send_stream(stream, &request).await
+      }
+      (Transport::H2, Some(tls)) => send_h2(server, tls, request).await,
+      (Transport::Tls | Transport::H2, None) => bail!("the {transport} protocol requires TLS"),
+    }
+  };
+  let response = tokio::time::timeout(timeout, exchange)
+    .await
+    .with_context(|| format!("no answer after {timeout:?}"))??;
+  if response.id() != message.id() {
+    bail!("the response does not match the query");
+  }
+  Ok(response)
+}
+
+/// Send the query over UDP, late answers of other queries are ignored.
+async fn send_udp(server: SocketAddr, id: u16, request: &[u8]) -> Result<Message> {
+  let local: SocketAddr = if server.is_ipv4() {
+    (Ipv4Addr::UNSPECIFIED, 0).into()
+  } else {
+    (Ipv6Addr::UNSPECIFIED, 0).into()
+  };
+  let socket = UdpSocket::bind(local).await?;
+  socket.connect(server).await?;
+  socket.send(request).await?;
+  let mut buffer = vec![0; u16::MAX as usize];
+  loop {
+    let read = socket.recv(&mut buffer).await?;
+    match Message::from_vec(&buffer[..read]) {
+      Ok(response) if response.id() == id => return Ok(response),
+      _ => continue,
+    }
+  }
+}
+
+/// Send the length-prefixed query on a TCP or TLS stream.
+async fn send_stream<S: AsyncRead + AsyncWrite + Unpin>(
+  mut stream: S,
+  request: &[u8],
+) -> Result<Message> {
+  let mut message = (request.len() as u16).to_be_bytes().to_vec();
+  message.extend(request);
+  stream.write_all(&message).await?;
+  let length = stream.read_u16().await?;
+  let mut response = vec![0; length as usize];
+  stream.read_exact(&mut response).await?;
+  Ok(Message::from_vec(&response)?)
+}
+
+async fn connect_tls(
+  server: SocketAddr,
+  tls: &TlsClient,
+  alpn: Vec<Vec<u8>>,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+  let mut config = (*tls.config).clone();
+  config.alpn_protocols = alpn;
+  let name = ServerName::try_from(tls.name.as_str())
+    .with_context(|| format!("invalid TLS name {}", tls.name))?;
+  let stream = TcpStream::connect(server).await?;
+  let stream = TlsConnector::from(Arc::new(config))
+    .connect(name, stream)
+    .await?;
+  Ok(stream)
+}
+
+/// Send the query in a `POST /dns-query` request (RFC 8484).
+async fn send_h2(server: SocketAddr, tls: &TlsClient, request: Vec<u8>) -> Result<Message> {
+  let stream = connect_tls(server, tls, vec![b"h2".to_vec()]).await?;
+  let (client, connection) = h2::client::handshake(stream).await?;
+  tokio::spawn(connection);
+  let authority = match tls.name.parse::<Ipv6Addr>() {
+    Ok(ip) => format!("[{ip}]"),
+    Err(_) => tls.name.clone(),
+  };
+  let mut builder = http::Request::builder()
+    .method("POST")
+    .uri(format!("https://{authority}/dns-query"))
+    .header(CONTENT_TYPE, MIME_APPLICATION_DNS)
+    .header(ACCEPT, MIME_APPLICATION_DNS);
+  if let Some(credential) = &tls.credential {
+    builder = builder.header(AUTHORIZATION, credential.authorization());
+  }
+  let (response, mut body) = client
+    .ready()
+    .await?
+    .send_request(builder.body(())?, false)?;
+  body.send_data(Bytes::from(request), true)?;
+
+  let response = response.await?;
+  if !response.status().is_success() {
+    bail!("the server answered HTTP {}", response.status());
+  }
+  let mut body = response.into_body();
+  let mut message = vec![];
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    body.flow_control().release_capacity(chunk.len())?;
+    message.extend(chunk);
+  }
+  Ok(Message::from_vec(&message)?)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn transports() {
+    assert_eq!(Transport::from_str("h2").unwrap(), Transport::H2);
+    assert_eq!(Transport::Tls.to_string(), "tls");
+    assert!(Transport::from_str("doq").is_err());
+  }
+
+  #[test]
+  fn record_types() {
+    assert_eq!(parse_record_type("aaaa").unwrap(), RecordType::AAAA);
+    assert_eq!(parse_record_type("65").unwrap(), RecordType::HTTPS);
+    assert!(parse_record_type("nope").is_err());
+  }
+
+  #[tokio::test]
+  async fn tls_required() {
+    let message = message(&Name::root(), RecordType::NS);
+    let server = "127.0.0.1:853".parse().unwrap();
+    let response = send(
+      &message,
+      server,
+      Transport::Tls,
+      None,
+      Duration::from_secs(1),
+    )
+    .await;
+    assert!(response.is_err());
+  }
+}
@@ -0,0 +1,582 @@
+use crate::client::bind::Binding;
+use crate::events;
+use futures_util::future::{BoxFuture, FutureExt};
+use hickory_server::proto::{
+  op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+  rr::{rdata::SOA, Name, RData, Record, RecordType},
+};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+/// IPv4 then IPv6 addresses of the root servers, a to m.
+const ROOT_SERVERS: &[IpAddr] = &[
+  IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+  IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+  IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),
+  IpAddr::V4(Ipv4Addr::new(199, 7, 91, 13)),
+  IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)),
+  IpAddr::V4(Ipv4Addr::new(192, 5, 5, 241)),
+  IpAddr::V4(Ipv4Addr::new(192, 112, 36, 4)),
+  IpAddr::V4(Ipv4Addr::new(198, 97, 190, 53)),
+  IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),
+  IpAddr::V4(Ipv4Addr::new(192, 58, 128, 30)),
+  IpAddr::V4(Ipv4Addr::new(193, 0, 14, 129)),
+  IpAddr::V4(Ipv4Addr::new(199, 7, 83, 42)),
+  IpAddr::V4(Ipv4Addr::new(202, 12, 27, 33)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+  IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35)),
+];
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Servers of a zone asked before giving up.
+const MAX_SERVERS: usize = 3;
+const MAX_REFERRALS: usize = 16;
+const MAX_CNAMES: usize = 8;
+/// Nested resolutions of name servers without glue.
+const MAX_DEPTH: usize = 4;
+const MAX_CACHE_SIZE: usize = 65536;
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+/// TTL of the negative answers without SOA.
+const NEGATIVE_TTL: u32 = 300;
+
+/// Why a name could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+  NxDomain,
+  NoData,
+  /// No name server answered, or the delegations are broken.
+  ServFail,
+}
+
+impl From<Failure> for ResponseCode {
+  fn from(failure: Failure) -> Self {
+    match failure {
+      Failure::NxDomain => ResponseCode::NXDomain,
+      Failure::NoData => ResponseCode::NoError,
+      Failure::ServFail => ResponseCode::ServFail,
+    }
+  }
+}
+
+/// What a response of an authoritative server tells about a query.
+#[derive(Debug, PartialEq)]
+enum Step {
+  Answer(Vec<Record>),
+  /// The chain of CNAMEs and its target, which is outside the zone of the server.
+  Cname(Vec<Record>, Name),
+  Referral {
+    zone: Name,
+    ttl: u32,
+    name_servers: Vec<Name>,
+    glue: Vec<IpAddr>,
+  },
+  NxDomain(u32),
+  NoData(u32),
+}
+
+type Answer = Result<Vec<Record>, Failure>;
+
+struct Cached<T> {
+  expires: Instant,
+  value: T,
+}
+
+/// Iterative resolver starting from the root servers, answers and delegations are cached
+/// according to their TTL.
+pub struct Recursor {
+  roots: Vec<SocketAddr>,
+  answers: Mutex<HashMap<(Name, RecordType), Cached<Answer>>>,
+  delegations: Mutex<HashMap<Name, Cached<Vec<SocketAddr>>>>,
+  random: RandomState,
+  binding: Arc<Binding>,
+}
+
+impl Default for Recursor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Recursor {
+  pub fn new() -> Self {
+    Self {
+      roots: ROOT_SERVERS
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, 53))
+        .collect(),
+      answers: Mutex::new(HashMap::new()),
+      delegations: Mutex::new(HashMap::new()),
+      random: RandomState::new(),
+      binding: Arc::default(),
+    }
+  }
+
+  /// Send the queries from the bound address and interface.
+  pub fn with_binding(binding: Arc<Binding>) -> Self {
+    Self {
+      binding,
+      ..Self::new()
+    }
+  }
+
+  /// Records of `name`, with the CNAMEs leading to them.
+  pub async fn resolve(&self, name: &Name, query_type: RecordType) -> Result<Vec<Record>, Failure> {
+    self.resolve_with_depth(name.clone(), query_type, 0).await
+  }
+
+  /// Ask the root servers for their names, without cache.
+  pub async fn probe(&self) -> bool {
+    self
+      .query(&self.roots, &Name::root(), RecordType::NS)
+      .await
+      .is_ok()
+  }
+
+  fn resolve_with_depth(
+    &self,
+    mut name: Name,
+    query_type: RecordType,
+    depth: usize,
+  ) -> BoxFuture<'_, Result<Vec<Record>, Failure>> {
+    async move {
+      let mut records = vec![];
+      for _ in 0..MAX_CNAMES {
+        if let Some(answer) = self.cached_answer(&name, query_type) {
+          return answer.map(|answer| [records, answer].concat());
+        }
+        match self.iterate(&name, query_type, depth).await? {
+          (chain, None) => {
+            records.extend(chain);
+            return Ok(records);
+          }
+          (chain, Some(target)) => {
+            records.extend(chain);
+            name = target;
+          }
+        }
+      }
+      Err(Failure::ServFail)
+    }
+    .boxed()
+  }
+
+  /// Follow the delegations from the closest known zone of `name` until a server answers.
+  async fn iterate(
+    &self,
+    name: &Name,
+    query_type: RecordType,
+    depth: usize,
+  ) -> Result<(Vec<Record>, Option<Name>), Failure> {
+    let (mut zone, mut servers) = self.closest_zone(name);
+    for _ in 0..MAX_REFERRALS {
+      let response = self.query(&servers, name, query_type).await?;
+      match classify(&response, name, query_type, &zone) {
+        Step::Answer(records) => {
+          let ttl = records.iter().map(Record::ttl).min().unwrap_or(0);
+          self.cache_answer(name, query_type, Ok(records.clone()), ttl);
+          return Ok((records, None));
+        }
+        Step::Cname(chain, target) => return Ok((chain, Some(target))),
+        Step::NxDomain(ttl) => {
+          self.cache_answer(name, query_type, Err(Failure::NxDomain), ttl);
+          return Err(Failure::NxDomain);
+        }
+        Step::NoData(ttl) => {
+          self.cache_answer(name, query_type, Err(Failure::NoData), ttl);
+          return Err(Failure::NoData);
+        }
+        Step::Referral {
+          zone: next,
+          ttl,
+          name_servers,
+          glue,
+        } => {
+          let mut addresses: Vec<SocketAddr> =
+            glue.into_iter().map(|ip| SocketAddr::new(ip, 53)).collect();
+          if addresses.is_empty() && depth < MAX_DEPTH {
+            for name_server in name_servers.iter() {
+              if let Ok(records) = self
+                .resolve_with_depth(name_server.clone(), RecordType::A, depth + 1)
+                .await
+              {
+                addresses.extend(
+                  records
+                    .iter()
+                    .filter_map(|record| record.data().and_then(RData::ip_addr))
+                    .map(|ip| SocketAddr::new(ip, 53)),
+                );
+              }
+              if !addresses.is_empty() {
+                break;
+              }
+            }
+          }
+          if addresses.is_empty() {
+            debug!("No address for the name servers of {}", next);
+            return Err(Failure::ServFail);
+          }
+          self.cache_delegation(&next, addresses.clone(), ttl);
+          zone = next;
+          servers = addresses;
+        }
+      }
+    }
+    Err(Failure::ServFail)
+  }
+
+  /// The deepest cached zone containing `name`, the root when none is cached.
+  fn closest_zone(&self, name: &Name) -> (Name, Vec<SocketAddr>) {
+    let delegations = self.delegations.lock().unwrap();
+    let now = Instant::now();
+    let mut zone = name.clone();
+    while !zone.is_root() {
+      if let Some(cached) = delegations.get(&zone).filter(|cached| cached.expires > now) {
+        return (zone, cached.value.clone());
+      }
+      zone = zone.base_name();
+    }
+    (Name::root(), self.roots.clone())
+  }
+
+  /// Forget the cached answers of `name`, or all the answers and delegations when `None`.
+  pub fn flush(&self, name: Option<&Name>) {
+    let mut answers = self.answers.lock().unwrap();
+    match name {
+      Some(name) => answers.retain(|(cached, _), _| cached != name),
+      None => {
+        answers.clear();
+        self.delegations.lock().unwrap().clear();
+      }
+    }
+  }
+
+  fn cached_answer(&self, name: &Name, query_type: RecordType) -> Option<Answer> {
+    let answers = self.answers.lock().unwrap();
+    let cached = answers.get(&(name.clone(), query_type))?;
+    let remaining = cached.expires.checked_duration_since(Instant::now())?;
+    Some(cached.value.clone().map(|mut records| {
+      for record in records.iter_mut() {
+        record.set_ttl(remaining.as_secs() as u32);
+      }
+      records
+    }))
+  }
+
+  fn cache_answer(&self, name: &Name, query_type: RecordType, answer: Answer, ttl: u32) {
+    let mut answers = self.answers.lock().unwrap();
+    purge(&mut answers);
+    answers.insert(
+      (name.clone(), query_type),
+      Cached {
+        expires: Instant::now() + Duration::from_secs(ttl.into()),
+        value: answer,
+      },
+    );
+  }
+
+  fn cache_delegation(&self, zone: &Name, servers: Vec<SocketAddr>, ttl: u32) {
+    let mut delegations = self.delegations.lock().unwrap();
+    purge(&mut delegations);
+    delegations.insert(
+      zone.clone(),
+      Cached {
+        expires: Instant::now() + Duration::from_secs(ttl.into()),
+        value: servers,
+      },
+    );
+  }
+
+  /// Send the query to the servers in turn, IPv4 first, until one of them answers.
+  async fn query(
+    &self,
+    servers: &[SocketAddr],
+    name: &Name,
+    query_type: RecordType,
+  ) -> Result<Message, Failure> {
+    let mut servers = servers.to_vec();
+    servers.sort_by_key(SocketAddr::is_ipv6);
+    for server in servers.iter().take(MAX_SERVERS) {
+      match self.query_server(*server, name, query_type).await {
+        Ok(response)
+          if matches!(
+            response.response_code(),
+            ResponseCode::NoError | ResponseCode::NXDomain
+          ) =>
+        {
+          return Ok(response)
+        }
+        Ok(response) => debug!(
+          "{} answered {} for {} {}",
+          server,
+          response.response_code(),
+          name,
+          query_type
+        ),
+        Err(err) => debug!("{} failed for {} {}: {}", server, name, query_type, err),
+      }
+    }
+    Err(Failure::ServFail)
+  }
+
+  async fn query_server(
+    &self,
+    server: SocketAddr,
+    name: &Name,
+    query_type: RecordType,
+  ) -> io::Result<Message> {
+    let id = self.random.hash_one(Instant::now()) as u16;
+    let mut message = Message::new();
+    message
+      .set_id(id)
+      .set_message_type(MessageType::Query)
+      .set_op_code(OpCode::Query)
+      .set_recursion_desired(false)
+      .add_query(Query::query(name.clone(), query_type));
+    let mut edns = Edns::new();
+    edns.set_max_payload(UDP_PAYLOAD_SIZE);
+    message.set_edns(edns);
+    let request = message.to_vec().map_err(io::Error::other)?;
+
+    events::set_upstream_sent();
+    let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+      let response = send_udp(&self.binding, server, &request).await?;
+      if response.truncated() {
+        send_tcp(&self.binding, server, &request).await
+      } else {
+        Ok(response)
+      }
+    })
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    if response.id() != id || response.queries() != message.queries() {
+      return Err(io::Error::other("the response does not match the query"));
+    }
+    Ok(response)
+  }
+}
+
+async fn send_udp(binding: &Binding, server: SocketAddr, request: &[u8]) -> io::Result<Message> {
+  let local: SocketAddr = if server.is_ipv4() {
+    (Ipv4Addr::UNSPECIFIED, 0).into()
+  } else {
+    (Ipv6Addr::UNSPECIFIED, 0).into()
+  };
+  let socket = binding.bind_udp(local, server)?;
+  socket.connect(server).await?;
+  socket.send(request).await?;
+  let mut buffer = vec![0; UDP_PAYLOAD_SIZE as usize];
+  let read = socket.recv(&mut buffer).await?;
+  Message::from_vec(&buffer[..read]).map_err(io::Error::other)
+}
+
+async fn send_tcp(binding: &Binding, server: SocketAddr, request: &[u8]) -> io::Result<Message> {
+  let mut stream = binding.connect_tcp(server).await?;
+  stream.write_u16(request.len() as u16).await?;
+  stream.write_all(request).await?;
+  let length = stream.read_u16().await?;
+  let mut buffer = vec![0; length as usize];
+  stream.read_exact(&mut buffer).await?;
+  Message::from_vec(&buffer).map_err(io::Error::other)
+}
+
+fn purge<K, V>(cache: &mut HashMap<K, Cached<V>>) {
+  if cache.len() < MAX_CACHE_SIZE {
+    return;
+  }
+  let now = Instant::now();
+  cache.retain(|_, cached| cached.expires > now);
+  if cache.len() >= MAX_CACHE_SIZE {
+    cache.clear();
+  }
+}
+
+/// Interpret the response of a server of `zone` to a query of `name`.
+fn classify(response: &Message, name: &Name, query_type: RecordType, zone: &Name) -> Step {
+  let negative_ttl = || {
+    response
+      .name_servers()
+      .iter()
+      .find_map(|record| match record.data() {
+        Some(RData::SOA(soa)) => Some(record.ttl().min(SOA::minimum(soa))),
+        _ => None,
+      })
+      .unwrap_or(NEGATIVE_TTL)
+  };
+  if response.response_code() == ResponseCode::NXDomain {
+    return Step::NxDomain(negative_ttl());
+  }
+
+  // Follow the CNAMEs given in the answer.
+  let mut chain = vec![];
+  let mut target = name.clone();
+  if query_type != RecordType::CNAME && query_type != RecordType::ANY {
+    while let Some(record) = response
+      .answers()
+      .iter()
+      .find(|record| record.record_type() == RecordType::CNAME && *record.name() == target)
+    {
+      if chain.len() >= MAX_CNAMES {
+        break;
+      }
+      chain.push(record.clone());
+      match record.data() {
+        Some(RData::CNAME(cname)) => target = cname.0.clone(),
+        _ => break,
+      }
+    }
+  }
+  let answers: Vec<Record> = response
+    .answers()
+    .iter()
+    .filter(|record| {
+      *record.name() == target
+        && (record.record_type() == query_type || query_type == RecordType::ANY)
+    })
+    .cloned()
+    .collect();
+  if !answers.is_empty() {
+    return Step::Answer([chain, answers].concat());
+  }
+  if !chain.is_empty() {
+    return Step::Cname(chain, target);
+  }
+
+  // A delegation to a zone below the one of the server.
+  let name_servers: Vec<&Record> = response
+    .name_servers()
+    .iter()
+    .filter(|record| {
+      record.record_type() == RecordType::NS
+        && record.name().zone_of(name)
+        && zone.zone_of(record.name())
+        && record.name() != zone
+    })
+    .collect();
+  if let Some(first) = name_servers.first() {
+    let names: Vec<Name> = name_servers
+      .iter()
+      .filter_map(|record| match record.data() {
+        Some(RData::NS(ns)) => Some(ns.0.clone()),
+        _ => None,
+      })
+      .collect();
+    // Only trust the glue of names within the zone of the server.
+    let glue = response
+      .additionals()
+      .iter()
+      .filter(|record| names.contains(record.name()) && zone.zone_of(record.name()))
+      .filter_map(|record| record.data().and_then(RData::ip_addr))
+      .collect();
+    return Step::Referral {
+      zone: first.name().clone(),
+      ttl: name_servers
+        .iter()
+        .map(|record| record.ttl())
+        .min()
+        .unwrap_or(0),
+      name_servers: names,
+      glue,
+    };
+  }
+  Step::NoData(negative_ttl())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::rr::rdata::{A, CNAME, NS};
+  use std::str::FromStr;
+
+  fn name(name: &str) -> Name {
+    Name::from_str(name).unwrap()
+  }
+
+  fn response(answers: Vec<Record>, authority: Vec<Record>, additionals: Vec<Record>) -> Message {
+    let mut message = Message::new();
+    message.set_message_type(MessageType::Response);
+    message.insert_answers(answers);
+    message.insert_name_servers(authority);
+    message.insert_additionals(additionals);
+    message
+  }
+
+  #[test]
+  fn referrals_and_answers() {
+    let www = name("www.example.com.");
+    let referral = response(
+      vec![],
+      vec![Record::from_rdata(
+        name("com."),
+        172800,
+        RData::NS(NS(name("a.gtld-servers.net."))),
+      )],
+      vec![
+        Record::from_rdata(
+          name("a.gtld-servers.net."),
+          172800,
+          RData::A(A::new(192, 5, 6, 30)),
+        ),
+        Record::from_rdata(name("evil.org."), 172800, RData::A(A::new(6, 6, 6, 6))),
+      ],
+    );
+    assert_eq!(
+      classify(&referral, &www, RecordType::A, &Name::root()),
+      Step::Referral {
+        zone: name("com."),
+        ttl: 172800,
+        name_servers: vec![name("a.gtld-servers.net.")],
+        glue: vec![IpAddr::V4(Ipv4Addr::new(192, 5, 6, 30))],
+      }
+    );
+    // A server cannot delegate a zone above its own.
+    assert_eq!(
+      classify(&referral, &www, RecordType::A, &name("com.")),
+      Step::NoData(NEGATIVE_TTL)
+    );
+
+    let cname = Record::from_rdata(www.clone(), 300, RData::CNAME(CNAME(name("example.net."))));
+    let a = Record::from_rdata(name("example.net."), 60, RData::A(A::new(10, 0, 0, 1)));
+    assert_eq!(
+      classify(
+        &response(vec![cname.clone(), a.clone()], vec![], vec![]),
+        &www,
+        RecordType::A,
+        &name("example.com.")
+      ),
+      Step::Answer(vec![cname.clone(), a])
+    );
+    assert_eq!(
+      classify(
+        &response(vec![cname.clone()], vec![], vec![]),
+        &www,
+        RecordType::A,
+        &name("example.com.")
+      ),
+      Step::Cname(vec![cname], name("example.net."))
+    );
+
+    let mut nxdomain = response(vec![], vec![], vec![]);
+    nxdomain.set_response_code(ResponseCode::NXDomain);
+    assert_eq!(
+      classify(&nxdomain, &www, RecordType::A, &name("example.com.")),
+      Step::NxDomain(NEGATIVE_TTL)
+    );
+  }
+}
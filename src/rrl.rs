@@ -0,0 +1,270 @@
+use hickory_server::{
+  authority::{MessageRequest, MessageResponse, MessageResponseBuilder},
+  proto::{
+    op::ResponseCode,
+    rr::{LowerName, Record},
+    serialize::binary::{BinDecodable, BinEncodable},
+  },
+  server::{Request, ResponseHandler, ResponseInfo},
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Response Rate Limiting of the UDP answers, like BIND: each client network gets at most
+/// `responses_per_second` identical answers (same name and response code) per second.
+#[derive(Debug, Clone)]
+pub struct RrlOptions {
+  pub responses_per_second: u32,
+  /// Limited answers are counted this long, a client exceeding the rate during the whole window
+  /// has to stop for the same duration before being answered again.
+  pub window: Duration,
+  /// One limited answer out of `slip` is sent truncated so genuine clients retry over TCP, `0`
+  /// drops them all.
+  pub slip: u32,
+  /// Prefix length of the IPv4 clients sharing a limit.
+  pub ipv4_prefix: u8,
+  /// Prefix length of the IPv6 clients sharing a limit.
+  pub ipv6_prefix: u8,
+}
+
+impl Default for RrlOptions {
+  fn default() -> Self {
+    Self {
+      responses_per_second: 10,
+      window: Duration::from_secs(15),
+      slip: 2,
+      ipv4_prefix: 24,
+      ipv6_prefix: 56,
+    }
+  }
+}
+
+/// What is done with an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+  Send,
+  /// Send an empty truncated answer.
+  Slip,
+  Drop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+  network: IpAddr,
+  qname: LowerName,
+  rcode: ResponseCode,
+}
+
+struct Bucket {
+  balance: f64,
+  last: Instant,
+  limited: u32,
+}
+
+struct Buckets {
+  buckets: HashMap<Key, Bucket>,
+  cleaned: Instant,
+}
+
+pub struct RateLimiter {
+  options: RrlOptions,
+  buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+  pub fn new(options: RrlOptions) -> Self {
+    Self {
+      options,
+      buckets: Mutex::new(Buckets {
+        buckets: HashMap::new(),
+        cleaned: Instant::now(),
+      }),
+    }
+  }
+
+  /// Count an answer to `client` and tell whether it can be sent.
+  pub fn check(
+    &self,
+    client: IpAddr,
+    qname: &LowerName,
+    rcode: ResponseCode,
+    now: Instant,
+  ) -> Verdict {
+    let rate = self.options.responses_per_second as f64;
+    let window = self.options.window.as_secs_f64();
+    let key = Key {
+      network: self.network(client),
+      qname: qname.clone(),
+      rcode,
+    };
+    let mut buckets = self.buckets.lock().unwrap();
+    if now.duration_since(buckets.cleaned) >= self.options.window {
+      buckets
+        .buckets
+        .retain(|_, bucket| now.duration_since(bucket.last) < self.options.window);
+      buckets.cleaned = now;
+    }
+
+    let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+      balance: rate,
+      last: now,
+      limited: 0,
+    });
+    let elapsed = now.duration_since(bucket.last).as_secs_f64();
+    bucket.balance = (bucket.balance + elapsed * rate).min(rate) - 1.;
+    bucket.balance = bucket.balance.max(-rate * window);
+    bucket.last = now;
+    if bucket.balance >= 0. {
+      bucket.limited = 0;
+      return Verdict::Send;
+    }
+    bucket.limited += 1;
+    if self.options.slip > 0 && bucket.limited.is_multiple_of(self.options.slip) {
+      Verdict::Slip
+    } else {
+      Verdict::Drop
+    }
+  }
+
+  fn network(&self, ip: IpAddr) -> IpAddr {
+    match ip {
+      IpAddr::V4(ip) => {
+        let prefix = self.options.ipv4_prefix.min(32) as u32;
+        let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+        IpAddr::V4((ip.to_bits() & mask).into())
+      }
+      IpAddr::V6(ip) => {
+        let prefix = self.options.ipv6_prefix.min(128) as u32;
+        let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+        IpAddr::V6((ip.to_bits() & mask).into())
+      }
+    }
+  }
+}
+
+/// Sends the answers of a UDP request allowed by the rate limiter.
+#[derive(Clone)]
+pub struct RrlResponseHandler<R: ResponseHandler> {
+  inner: R,
+  limiter: Arc<RateLimiter>,
+  client: IpAddr,
+  qname: LowerName,
+  /// Wire format of the request, to build the truncated answers.
+  request: Option<Arc<Vec<u8>>>,
+}
+
+impl<R: ResponseHandler> RrlResponseHandler<R> {
+  pub fn new(inner: R, limiter: Arc<RateLimiter>, request: &Request) -> Self {
+    Self {
+      inner,
+      limiter,
+      client: request.src().ip(),
+      qname: request.query().name().clone(),
+      request: request.to_bytes().ok().map(Arc::new),
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for RrlResponseHandler<R> {
+  async fn send_response<'a>(
+    &mut self,
+    response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    let mut header = *response.header();
+    let verdict = self.limiter.check(
+      self.client,
+      &self.qname,
+      header.response_code(),
+      Instant::now(),
+    );
+    if verdict == Verdict::Send {
+      return self.inner.send_response(response).await;
+    }
+    let request = self
+      .request
+      .as_ref()
+      .filter(|_| verdict == Verdict::Slip)
+      .and_then(|bytes| MessageRequest::from_bytes(bytes).ok());
+    let Some(request) = request else {
+      debug!("Rate limited {} for {}, dropped", self.qname, self.client);
+      return Ok(header.into());
+    };
+    debug!("Rate limited {} for {}, truncated", self.qname, self.client);
+    header.set_truncated(true);
+    let response = MessageResponseBuilder::from_message_request(&request);
+    self
+      .inner
+      .send_response(response.build_no_records(header))
+      .await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::str::FromStr;
+
+  #[test]
+  fn rate_limit() {
+    let limiter = RateLimiter::new(RrlOptions {
+      responses_per_second: 2,
+      window: Duration::from_secs(2),
+      slip: 2,
+      ..RrlOptions::default()
+    });
+    let qname = LowerName::from_str("example.com.").unwrap();
+    let start = Instant::now();
+    let check = |ip: &str, rcode, seconds: f64| {
+      let now = start + Duration::from_secs_f64(seconds);
+      limiter.check(ip.parse().unwrap(), &qname, rcode, now)
+    };
+
+    assert_eq!(check("192.0.2.1", ResponseCode::NoError, 0.), Verdict::Send);
+    assert_eq!(check("192.0.2.2", ResponseCode::NoError, 0.), Verdict::Send);
+    assert_eq!(check("192.0.2.3", ResponseCode::NoError, 0.), Verdict::Drop);
+    assert_eq!(check("192.0.2.3", ResponseCode::NoError, 0.), Verdict::Slip);
+    // Another network, or another response code, has its own limit.
+    assert_eq!(check("192.0.3.1", ResponseCode::NoError, 0.), Verdict::Send);
+    assert_eq!(
+      check("192.0.2.1", ResponseCode::NXDomain, 0.),
+      Verdict::Send
+    );
+    // Limited answers are counted too, the balance takes longer to come back above zero.
+    assert_eq!(
+      check("192.0.2.1", ResponseCode::NoError, 0.2),
+      Verdict::Drop
+    );
+    assert_eq!(
+      check("192.0.2.1", ResponseCode::NoError, 1.5),
+      Verdict::Slip
+    );
+    assert_eq!(
+      check("192.0.2.1", ResponseCode::NoError, 2.5),
+      Verdict::Send
+    );
+    assert_eq!(
+      check("192.0.2.1", ResponseCode::NoError, 10.),
+      Verdict::Send
+    );
+  }
+
+  #[test]
+  fn client_network() {
+    let limiter = RateLimiter::new(RrlOptions::default());
+    let network = |ip: &str| limiter.network(ip.parse().unwrap()).to_string();
+    assert_eq!(network("192.0.2.77"), "192.0.2.0");
+    assert_eq!(network("2001:db8:1:2ff:1::1"), "2001:db8:1:200::");
+  }
+}
@@ -0,0 +1,513 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Files the system libraries read after startup: the root certificates of the upstreams (read
+/// again on reload), the time zone of the schedules, the name resolution and the randomness.
+const SYSTEM_PATHS: &[&str] = &[
+  "/etc/ssl",
+  "/etc/pki",
+  "/etc/ca-certificates",
+  "/usr/share/ca-certificates",
+  "/usr/local/share/ca-certificates",
+  "/usr/lib/ssl",
+  "/etc/localtime",
+  "/usr/share/zoneinfo",
+  "/etc/resolv.conf",
+  "/etc/hosts",
+  "/etc/nsswitch.conf",
+  "/etc/host.conf",
+  "/etc/gai.conf",
+  "/dev/urandom",
+  "/dev/null",
+];
+
+// Filesystem rights of Landlock (linux/landlock.h).
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+/// Every right of the first version of Landlock, up to `LANDLOCK_ACCESS_FS_MAKE_SYM`.
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+/// Added by the third version of Landlock.
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// Rights allowed on a file, the others only apply to directories.
+const ACCESS_FILE: u64 =
+  ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+const ACCESS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+const ACCESS_WRITE: u64 = ACCESS_READ
+  | ACCESS_FS_WRITE_FILE
+  | ACCESS_FS_TRUNCATE
+  | ACCESS_FS_REMOVE_FILE
+  | ACCESS_FS_REMOVE_DIR
+  | ACCESS_FS_MAKE_REG
+  | ACCESS_FS_MAKE_DIR
+  | ACCESS_FS_MAKE_SOCK;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+  handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+  allowed_access: u64,
+  parent_fd: i32,
+}
+
+/// Architecture checked by the seccomp filter, system calls of other ABIs are refused.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// System calls a DNS server never needs once started: running programs, debugging other
+/// processes, changing the system or the credentials, creating namespaces, loading BPF or modules.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+  libc::SYS_execve,
+  libc::SYS_execveat,
+  libc::SYS_ptrace,
+  libc::SYS_process_vm_readv,
+  libc::SYS_process_vm_writev,
+  libc::SYS_mount,
+  libc::SYS_umount2,
+  libc::SYS_pivot_root,
+  libc::SYS_chroot,
+  libc::SYS_move_mount,
+  libc::SYS_open_tree,
+  libc::SYS_fsopen,
+  libc::SYS_fsconfig,
+  libc::SYS_fsmount,
+  libc::SYS_fspick,
+  libc::SYS_unshare,
+  libc::SYS_setns,
+  libc::SYS_swapon,
+  libc::SYS_swapoff,
+  libc::SYS_reboot,
+  libc::SYS_kexec_load,
+  libc::SYS_kexec_file_load,
+  libc::SYS_init_module,
+  libc::SYS_finit_module,
+  libc::SYS_delete_module,
+  libc::SYS_bpf,
+  libc::SYS_perf_event_open,
+  libc::SYS_userfaultfd,
+  libc::SYS_keyctl,
+  libc::SYS_add_key,
+  libc::SYS_request_key,
+  libc::SYS_personality,
+  libc::SYS_acct,
+  libc::SYS_quotactl,
+  libc::SYS_syslog,
+  libc::SYS_vhangup,
+  libc::SYS_settimeofday,
+  libc::SYS_clock_settime,
+  libc::SYS_clock_adjtime,
+  libc::SYS_adjtimex,
+  libc::SYS_sethostname,
+  libc::SYS_setdomainname,
+  libc::SYS_open_by_handle_at,
+  libc::SYS_name_to_handle_at,
+  libc::SYS_setuid,
+  libc::SYS_setgid,
+  libc::SYS_setreuid,
+  libc::SYS_setregid,
+  libc::SYS_setresuid,
+  libc::SYS_setresgid,
+  libc::SYS_setfsuid,
+  libc::SYS_setfsgid,
+  libc::SYS_setgroups,
+  libc::SYS_capset,
+  #[cfg(target_arch = "x86_64")]
+  libc::SYS_iopl,
+  #[cfg(target_arch = "x86_64")]
+  libc::SYS_ioperm,
+  #[cfg(target_arch = "x86_64")]
+  libc::SYS_uselib,
+];
+
+/// Restrictions applied to the whole process once the server is started, so a bug in the parsing
+/// of the queries can not be used to reach the rest of the system:
+/// - Landlock limits the filesystem to reading `read` paths and writing `write` paths, nothing
+///   can be executed. It is skipped with a warning on kernels without Landlock.
+/// - A seccomp filter refuses the system calls of [`DENIED_SYSCALLS`] with `EPERM`.
+///
+/// Rules apply to the files and directories existing when the sandbox is applied, give the
+/// directories of the files replaced while the server runs (lists updated by a script, zones...).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sandbox {
+  read: Vec<PathBuf>,
+  write: Vec<PathBuf>,
+}
+
+impl Default for Sandbox {
+  fn default() -> Self {
+    Self {
+      read: SYSTEM_PATHS.iter().map(PathBuf::from).collect(),
+      write: vec![],
+    }
+  }
+}
+
+impl Sandbox {
+  /// Sandbox allowing to read the system files used by the server.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Allow reading the file or the directory and its content.
+  pub fn read<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.read.push(path.into());
+    self
+  }
+
+  /// Allow reading, writing, creating and removing the file or the content of the directory.
+  pub fn write<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.write.push(path.into());
+    self
+  }
+
+  /// Restrict every thread of the process, it can not be undone. Privileges must be dropped
+  /// before, changing the credentials is refused afterwards.
+  pub fn apply(&self) -> Result<()> {
+    match landlock_abi() {
+      Some(abi) => {
+        let ruleset = self.ruleset(abi)?;
+        restrict_threads(&ruleset).context("could not restrict the filesystem")?;
+      }
+      None => warn!("Landlock is not supported by this kernel, the filesystem is not restricted"),
+    }
+    seccomp().context("could not filter the system calls")
+  }
+
+  /// Landlock ruleset of the paths, the missing ones are skipped.
+  fn ruleset(&self, abi: i32) -> Result<OwnedFd> {
+    let handled = match abi {
+      1 | 2 => ACCESS_FS_V1,
+      _ => ACCESS_FS_V1 | ACCESS_FS_TRUNCATE,
+    };
+    let attr = RulesetAttr {
+      handled_access_fs: handled,
+    };
+    let fd = unsafe {
+      libc::syscall(
+        libc::SYS_landlock_create_ruleset,
+        &attr,
+        std::mem::size_of::<RulesetAttr>(),
+        0,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error()).context("could not create the Landlock ruleset");
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    let rules = self
+      .read
+      .iter()
+      .map(|path| (path, ACCESS_READ))
+      .chain(self.write.iter().map(|path| (path, ACCESS_WRITE)));
+    for (path, access) in rules {
+      let Some(file) = open_path(path)? else {
+        debug!("{} does not exist, it is not allowed", path.display());
+        continue;
+      };
+      let mut access = access & handled;
+      if !path.is_dir() {
+        access &= ACCESS_FILE;
+      }
+      let rule = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+      };
+      let added = unsafe {
+        libc::syscall(
+          libc::SYS_landlock_add_rule,
+          ruleset.as_raw_fd(),
+          LANDLOCK_RULE_PATH_BENEATH,
+          &rule,
+          0,
+        )
+      };
+      if added != 0 {
+        return Err(io::Error::last_os_error())
+          .with_context(|| format!("could not allow {}", path.display()));
+      }
+    }
+    Ok(ruleset)
+  }
+}
+
+/// Version of Landlock supported by the kernel, `None` when it is not available.
+fn landlock_abi() -> Option<i32> {
+  let abi = unsafe {
+    libc::syscall(
+      libc::SYS_landlock_create_ruleset,
+      std::ptr::null::<RulesetAttr>(),
+      0,
+      LANDLOCK_CREATE_RULESET_VERSION,
+    )
+  };
+  (abi > 0).then_some(abi as i32)
+}
+
+fn open_path(path: &Path) -> Result<Option<OwnedFd>> {
+  let name = CString::new(path.as_os_str().as_bytes())
+    .with_context(|| format!("invalid path {}", path.display()))?;
+  let fd = unsafe { libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+  if fd < 0 {
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::NotFound {
+      return Ok(None);
+    }
+    return Err(err).with_context(|| format!("could not open {}", path.display()));
+  }
+  Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Ruleset applied by the threads receiving [`restrict_signal`].
+static RULESET: AtomicI32 = AtomicI32::new(-1);
+/// Ids of the threads that applied the ruleset, negative when it failed.
+static RESTRICTED: [AtomicI32; 1024] = [const { AtomicI32::new(0) }; 1024];
+static RESTRICTED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Signal asking a thread to restrict itself, Landlock only applies to the calling thread.
+fn restrict_signal() -> libc::c_int {
+  libc::SIGRTMAX() - 1
+}
+
+/// Forbid new privileges and apply the ruleset to the calling thread.
+fn restrict_self(ruleset: RawFd) -> bool {
+  unsafe {
+    libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+      && libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) == 0
+  }
+}
+
+extern "C" fn on_restrict_signal(_: libc::c_int) {
+  let errno = unsafe { *libc::__errno_location() };
+  let tid = unsafe { libc::gettid() };
+  let restricted = restrict_self(RULESET.load(Ordering::SeqCst));
+  let index = RESTRICTED_COUNT.fetch_add(1, Ordering::SeqCst);
+  if let Some(slot) = RESTRICTED.get(index) {
+    slot.store(if restricted { tid } else { -tid }, Ordering::SeqCst);
+  }
+  unsafe { *libc::__errno_location() = errno };
+}
+
+/// Apply the ruleset to every thread of the process, like the C libraries do for `setuid`: each
+/// thread restricts itself when it receives [`restrict_signal`]. Threads created meanwhile are
+/// restricted in another round, or inherit the restriction of their parent.
+fn restrict_threads(ruleset: &OwnedFd) -> Result<()> {
+  RULESET.store(ruleset.as_raw_fd(), Ordering::SeqCst);
+  let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+  action.sa_sigaction = on_restrict_signal as *const () as libc::sighandler_t;
+  action.sa_flags = libc::SA_RESTART;
+  let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+  if unsafe { libc::sigaction(restrict_signal(), &action, &mut previous) } != 0 {
+    return Err(io::Error::last_os_error()).context("could not handle the signal");
+  }
+  let result = signal_threads();
+  unsafe { libc::sigaction(restrict_signal(), &previous, std::ptr::null_mut()) };
+  result?;
+  if !restrict_self(ruleset.as_raw_fd()) {
+    return Err(io::Error::last_os_error()).context("could not restrict the main thread");
+  }
+  Ok(())
+}
+
+fn signal_threads() -> Result<()> {
+  let pid = unsafe { libc::getpid() };
+  let current = unsafe { libc::gettid() };
+  let mut signaled = HashSet::from([current]);
+  loop {
+    let threads: Vec<libc::pid_t> = threads()?
+      .into_iter()
+      .filter(|tid| !signaled.contains(tid))
+      .collect();
+    if threads.is_empty() {
+      return Ok(());
+    }
+    let mut waiting = HashSet::new();
+    for tid in threads {
+      signaled.insert(tid);
+      if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, restrict_signal()) } == 0 {
+        waiting.insert(tid);
+      }
+    }
+    let start = Instant::now();
+    while !waiting.is_empty() {
+      let count = RESTRICTED_COUNT.load(Ordering::SeqCst);
+      if count > RESTRICTED.len() {
+        bail!("too many threads");
+      }
+      for slot in &RESTRICTED[..count] {
+        match slot.load(Ordering::SeqCst) {
+          0 => {}
+          tid if tid < 0 && waiting.contains(&-tid) => bail!("the thread {} failed", -tid),
+          tid => {
+            waiting.remove(&tid);
+          }
+        }
+      }
+      // Threads exiting before handling the signal are not waited.
+      waiting.retain(|tid| Path::new(&format!("/proc/self/task/{tid}")).exists());
+      if start.elapsed() > Duration::from_secs(5) {
+        bail!("{} threads did not answer", waiting.len());
+      }
+      std::thread::sleep(Duration::from_millis(1));
+    }
+  }
+}
+
+/// Ids of the threads of the process.
+fn threads() -> Result<Vec<libc::pid_t>> {
+  let entries = std::fs::read_dir("/proc/self/task").context("could not list the threads")?;
+  Ok(
+    entries
+      .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+      .collect(),
+  )
+}
+
+/// Classic BPF program of the seccomp filter.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn filter() -> Vec<libc::sock_filter> {
+  let statement = |code: u32, k: u32| libc::sock_filter {
+    code: code as u16,
+    jt: 0,
+    jf: 0,
+    k,
+  };
+  let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+    code: code as u16,
+    jt,
+    jf,
+    k,
+  };
+  let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+  let denied = DENIED_SYSCALLS.len() as u8;
+  let mut program = vec![
+    // seccomp_data.arch
+    statement(load, 4),
+    jump(
+      libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+      AUDIT_ARCH,
+      1,
+      0,
+    ),
+    statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+    // seccomp_data.nr
+    statement(load, 0),
+  ];
+  // The x32 system calls of x86_64 have the same numbers with this bit set.
+  #[cfg(target_arch = "x86_64")]
+  program.push(jump(
+    libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+    0x4000_0000,
+    denied + 1,
+    0,
+  ));
+  for (index, syscall) in DENIED_SYSCALLS.iter().enumerate() {
+    // To the `EPERM` return, after the other comparisons and the `ALLOW` return.
+    let to_denied = denied - index as u8;
+    program.push(jump(
+      libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+      *syscall as u32,
+      to_denied,
+      0,
+    ));
+  }
+  program.push(statement(
+    libc::BPF_RET | libc::BPF_K,
+    libc::SECCOMP_RET_ALLOW,
+  ));
+  program.push(statement(
+    libc::BPF_RET | libc::BPF_K,
+    libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+  ));
+  program
+}
+
+/// Install the seccomp filter on every thread.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp() -> Result<()> {
+  let mut program = filter();
+  let program = libc::sock_fprog {
+    len: program.len() as u16,
+    filter: program.as_mut_ptr(),
+  };
+  if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+    return Err(io::Error::last_os_error()).context("could not forbid new privileges");
+  }
+  let installed = unsafe {
+    libc::syscall(
+      libc::SYS_seccomp,
+      libc::SECCOMP_SET_MODE_FILTER,
+      libc::SECCOMP_FILTER_FLAG_TSYNC,
+      &program,
+    )
+  };
+  if installed != 0 {
+    return Err(io::Error::last_os_error()).context("could not install the filter");
+  }
+  Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp() -> Result<()> {
+  bail!("the seccomp filter is only available on x86_64 and aarch64")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+  fn filter_jumps() {
+    let program = filter();
+    let allow = program.len() - 2;
+    assert_eq!(program[allow].k, libc::SECCOMP_RET_ALLOW);
+    let comparisons = program
+      .iter()
+      .enumerate()
+      .filter(|(_, instruction)| instruction.jt > 0 && instruction.k != AUDIT_ARCH);
+    for (index, instruction) in comparisons {
+      assert_eq!(index + 1 + instruction.jt as usize, allow + 1);
+    }
+    let execve = program
+      .iter()
+      .filter(|instruction| instruction.k == libc::SYS_execve as u32)
+      .count();
+    assert_eq!(execve, 1);
+  }
+
+  #[test]
+  fn paths() {
+    let sandbox = Sandbox::new()
+      .read("/etc/dns-server")
+      .write("/var/lib/dns-server");
+    assert!(sandbox.read.contains(&PathBuf::from("/etc/localtime")));
+    assert!(sandbox.read.contains(&PathBuf::from("/etc/dns-server")));
+    assert_eq!(sandbox.write, vec![PathBuf::from("/var/lib/dns-server")]);
+    assert!(open_path(Path::new("/nonexistent/dns-server"))
+      .unwrap()
+      .is_none());
+  }
+}
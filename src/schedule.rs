@@ -0,0 +1,174 @@
+use crate::domain_set::DomainSet;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, NaiveTime};
+use hickory_server::proto::rr::LowerName;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Time windows in local time, `21:00-07:00` ends the next day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+  windows: Vec<(NaiveTime, NaiveTime)>,
+}
+
+impl Schedule {
+  pub fn is_active(&self) -> bool {
+    self.is_active_at(Local::now().time())
+  }
+
+  pub fn is_active_at(&self, time: NaiveTime) -> bool {
+    self.windows.iter().any(|(start, end)| {
+      if start <= end {
+        *start <= time && time < *end
+      } else {
+        *start <= time || time < *end
+      }
+    })
+  }
+}
+
+impl FromStr for Schedule {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let windows = s
+      .split(',')
+      .map(str::trim)
+      .filter(|window| !window.is_empty())
+      .map(|window| {
+        let (start, end) = window
+          .split_once('-')
+          .ok_or_else(|| anyhow!("expected HH:MM-HH:MM, got `{window}`"))?;
+        let parse = |time: &str| {
+          NaiveTime::parse_from_str(time.trim(), "%H:%M")
+            .with_context(|| format!("invalid time `{time}`, expected HH:MM"))
+        };
+        Ok((parse(start)?, parse(end)?))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    if windows.is_empty() {
+      bail!("the schedule has no time window");
+    }
+    Ok(Self { windows })
+  }
+}
+
+impl fmt::Display for Schedule {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let windows: Vec<String> = self
+      .windows
+      .iter()
+      .map(|(start, end)| format!("{}-{}", start.format("%H:%M"), end.format("%H:%M")))
+      .collect();
+    write!(f, "{}", windows.join(","))
+  }
+}
+
+/// Zones blocked, with all their subdomains, only while the schedule is active.
+#[derive(Debug, Clone)]
+pub struct ScheduledBlacklist {
+  pub schedule: Schedule,
+  pub zones: DomainSet,
+}
+
+impl ScheduledBlacklist {
+  pub fn blocks(&self, name: &LowerName) -> bool {
+    self.schedule.is_active() && self.zones.contains_zone(name)
+  }
+}
+
+/// Scheduled blacklist as written on the command line: `path@HH:MM-HH:MM[,HH:MM-HH:MM...]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledList {
+  pub path: PathBuf,
+  pub schedule: Schedule,
+}
+
+impl FromStr for ScheduledList {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (path, schedule) = s
+      .rsplit_once('@')
+      .ok_or_else(|| anyhow!("expected path@HH:MM-HH:MM, got `{s}`"))?;
+    Ok(Self {
+      path: path.trim().into(),
+      schedule: schedule.parse()?,
+    })
+  }
+}
+
+impl fmt::Display for ScheduledList {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}@{}", self.path.display(), self.schedule)
+  }
+}
+
+impl Serialize for ScheduledList {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for ScheduledList {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    ScheduledList::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn time(s: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+  }
+
+  #[test]
+  fn windows() {
+    let schedule = Schedule::from_str("12:00-13:30, 21:00-07:00").unwrap();
+    assert!(schedule.is_active_at(time("12:00")));
+    assert!(!schedule.is_active_at(time("13:30")));
+    assert!(schedule.is_active_at(time("23:59")));
+    assert!(schedule.is_active_at(time("00:00")));
+    assert!(schedule.is_active_at(time("06:59")));
+    assert!(!schedule.is_active_at(time("07:00")));
+    assert!(!schedule.is_active_at(time("20:59")));
+    assert_eq!(schedule.to_string(), "12:00-13:30,21:00-07:00");
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert!(Schedule::from_str("").is_err());
+    assert!(Schedule::from_str("21:00").is_err());
+    assert!(Schedule::from_str("25:00-07:00").is_err());
+    assert!(ScheduledList::from_str("/etc/social.txt").is_err());
+  }
+
+  #[test]
+  fn scheduled_list() {
+    let list = ScheduledList::from_str("/etc/social.txt@21:00-07:00").unwrap();
+    assert_eq!(list.path, PathBuf::from("/etc/social.txt"));
+    assert_eq!(list.to_string(), "/etc/social.txt@21:00-07:00");
+  }
+
+  #[test]
+  fn subdomains() {
+    let blacklist = ScheduledBlacklist {
+      schedule: Schedule::from_str("00:00-00:00").unwrap(),
+      zones: DomainSet::from_iter([LowerName::from_str("social.example.").unwrap()]),
+    };
+    assert!(blacklist
+      .zones
+      .contains_zone(&LowerName::from_str("social.example.").unwrap()));
+    assert!(blacklist
+      .zones
+      .contains_zone(&LowerName::from_str("www.social.example.").unwrap()));
+    assert!(!blacklist
+      .zones
+      .contains_zone(&LowerName::from_str("example.").unwrap()));
+  }
+}
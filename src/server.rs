@@ -0,0 +1,1544 @@
+use crate::acl::{Access, Acl, DenyAction, Listener};
+use crate::acme::{self, AcmeOptions};
+use crate::authority::{
+  canary_domains, watch_signatures, BlacklistAuthority, Blocklist, FilterAaaaAuthority, LocalDns,
+  LocalDnsAuthority, LocalDnsUpdater, NoneAuthority, ResponsePolicyZone, Rewrite, RewriteAuthority,
+  RpzAuthority, SafeSearchAuthority, SecondaryAuthority, SyntheticZone, DEFAULT_BLOCK_TTL,
+  DEFAULT_LOCAL_TTL,
+};
+use crate::cache::{self, RedisUrl};
+use crate::category::Category;
+use crate::client::bind::Binding;
+use crate::client::ecs::Ecs;
+use crate::client::proxy::Proxy;
+use crate::client::ClientType;
+#[cfg(unix)]
+use crate::control;
+use crate::control::Control;
+use crate::doh::{self, Credential};
+use crate::domain_set::DomainSet;
+use crate::events::{AuditLog, LogPrivacy, QueryDb, QueryHistory, QueryObserver};
+use crate::filter_list::FilterList;
+use crate::geoip::{CountryAcl, GeoIp};
+use crate::group::GroupPolicy;
+use crate::handler::{CatalogHandle, Catalogs, ClientCatalog, DnsHandler, DEFAULT_EDNS_UDP_SIZE};
+use crate::healthcheck;
+use crate::ip::IpRangeVec;
+use crate::nat64::Nat64;
+use crate::padding::Padding;
+use crate::recursor::Recursor;
+use crate::rrl::{RateLimiter, RrlOptions};
+use crate::schedule::{Schedule, ScheduledBlacklist};
+use crate::stream;
+use crate::systemd::ActivatedSockets;
+use crate::tls::{self, CertificateStore, TlsOptions, TlsVersion};
+use crate::tsig::TsigKey;
+use crate::upstream::{Strategy, UpstreamOptions, Upstreams};
+use crate::warmup;
+use crate::web::{block_page, BlockPage, WebUi};
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_server::{
+  authority::{Authority, Catalog},
+  proto::rr::LowerName,
+  resolver::Name,
+  store::in_memory::InMemoryAuthority,
+  ServerFuture,
+};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc,
+};
+use std::time::Duration;
+use tokio::{
+  net::{TcpListener, UdpSocket},
+  sync::oneshot,
+};
+use tokio_graceful::Shutdown;
+use tracing::{error, info};
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+const WEB_UI_HISTORY: usize = 100;
+const LOCAL_DNS_REFRESH: Duration = Duration::from_secs(300);
+const GEOIP_REFRESH: Duration = Duration::from_secs(3600);
+
+/// Configure a DNS server: the upstream resolver, what to block and where to listen.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use dns_server::{client::ClientType, DnsServerBuilder};
+/// use std::str::FromStr;
+///
+/// let mut server = DnsServerBuilder::new()
+///   .upstream(ClientType::GoogleH2)
+///   .blacklist([hickory_server::proto::rr::LowerName::from_str("ads.example.com.")?])
+///   .listen_udp("127.0.0.1:5353".parse()?)
+///   .build()?;
+/// server.start().await?;
+/// server.shutdown(std::time::Duration::from_secs(5)).await
+/// # }
+/// ```
+pub struct DnsServerBuilder {
+  upstreams: Vec<ClientType>,
+  upstream_options: UpstreamOptions,
+  recursor: Option<Arc<Recursor>>,
+  filters: Arc<FilterList>,
+  blacklist: Arc<DomainSet>,
+  categories: Arc<Vec<Category>>,
+  zone_blacklist: Arc<DomainSet>,
+  zone_exceptions: Arc<DomainSet>,
+  doh_bypass: Option<Arc<DomainSet>>,
+  local_only: Arc<DomainSet>,
+  filter_aaaa: Arc<DomainSet>,
+  scheduled: Vec<ScheduledBlacklist>,
+  rpz: Vec<Arc<ResponsePolicyZone>>,
+  rewrites: Vec<Rewrite>,
+  zones: Vec<Arc<InMemoryAuthority>>,
+  signed_zones: Vec<Arc<InMemoryAuthority>>,
+  secondary_zones: Vec<Arc<SecondaryAuthority>>,
+  local_dns: Arc<LocalDns>,
+  local_dns_refresh: Duration,
+  local_dns_rotate: bool,
+  local_ttl: u32,
+  rotate_answers: bool,
+  cache_backend: Option<RedisUrl>,
+  cache_max_entries: usize,
+  cache_max_memory: Option<usize>,
+  local_dns_updates: Option<(Vec<LowerName>, IpRangeVec)>,
+  local_dns_update_keys: Vec<TsigKey>,
+  refuse_any: bool,
+  safe_search: bool,
+  default_ip: Option<Ipv4Addr>,
+  block_ttl: u32,
+  synthetic_zone: Arc<SyntheticZone>,
+  rfc8215_ips: IpRangeVec,
+  nat64: Arc<Nat64>,
+  ip_blacklist: IpRangeVec,
+  groups: Vec<(String, IpRangeVec, GroupPolicy)>,
+  routes: Vec<(IpRangeVec, ClientType)>,
+  forward_zones: Vec<(LowerName, ClientType)>,
+  udp: Vec<SocketAddr>,
+  tcp: Vec<SocketAddr>,
+  tls: Vec<SocketAddr>,
+  h2: Vec<SocketAddr>,
+  activated: ActivatedSockets,
+  tls_certificate: Option<(PathBuf, PathBuf)>,
+  acme: Option<AcmeOptions>,
+  tls_options: TlsOptions,
+  h2_credential: Option<Credential>,
+  proxies: IpRangeVec,
+  acl: Acl,
+  listener_acls: Vec<(Listener, Acl)>,
+  geoip: Option<Arc<GeoIp>>,
+  geoip_refresh: Duration,
+  countries: (Vec<String>, Vec<String>),
+  deny_action: DenyAction,
+  rrl: Option<RrlOptions>,
+  edns_udp_size: u16,
+  padding: Padding,
+  observers: Vec<Arc<dyn QueryObserver>>,
+  control: Arc<Control>,
+  #[cfg(unix)]
+  control_socket: Option<PathBuf>,
+  audit_log: Option<Arc<AuditLog>>,
+  web_ui: Option<SocketAddr>,
+  block_page: Option<BlockPage>,
+  query_db: Option<Arc<QueryDb>>,
+  history_privacy: LogPrivacy,
+  warmup: Vec<Name>,
+}
+
+impl Default for DnsServerBuilder {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DnsServerBuilder {
+  /// A server forwarding every query to Cloudflare over H2, without listeners.
+  pub fn new() -> Self {
+    Self {
+      upstreams: vec![ClientType::CloudFlareH2],
+      upstream_options: UpstreamOptions::default(),
+      recursor: None,
+      filters: Arc::new(FilterList::new()),
+      blacklist: Arc::new(DomainSet::new()),
+      categories: Arc::new(vec![]),
+      zone_blacklist: Arc::new(DomainSet::new()),
+      zone_exceptions: Arc::new(DomainSet::new()),
+      doh_bypass: None,
+      local_only: Arc::new(DomainSet::new()),
+      filter_aaaa: Arc::new(DomainSet::new()),
+      scheduled: vec![],
+      rpz: vec![],
+      rewrites: vec![],
+      zones: vec![],
+      signed_zones: vec![],
+      secondary_zones: vec![],
+      local_dns: Arc::new(LocalDns::default()),
+      local_dns_refresh: LOCAL_DNS_REFRESH,
+      local_dns_rotate: false,
+      local_ttl: DEFAULT_LOCAL_TTL,
+      rotate_answers: false,
+      cache_backend: None,
+      cache_max_entries: cache::DEFAULT_MAX_ENTRIES,
+      cache_max_memory: None,
+      local_dns_updates: None,
+      local_dns_update_keys: vec![],
+      refuse_any: false,
+      safe_search: false,
+      default_ip: None,
+      block_ttl: DEFAULT_BLOCK_TTL,
+      synthetic_zone: Arc::default(),
+      rfc8215_ips: IpRangeVec::new(vec![]),
+      nat64: Arc::new(Nat64::default()),
+      ip_blacklist: IpRangeVec::new(vec![]),
+      groups: vec![],
+      routes: vec![],
+      forward_zones: vec![],
+      udp: vec![],
+      tcp: vec![],
+      tls: vec![],
+      h2: vec![],
+      activated: ActivatedSockets::default(),
+      tls_certificate: None,
+      acme: None,
+      tls_options: TlsOptions::default(),
+      h2_credential: None,
+      proxies: IpRangeVec::new(vec![]),
+      acl: Acl::default(),
+      listener_acls: vec![],
+      geoip: None,
+      geoip_refresh: GEOIP_REFRESH,
+      countries: (vec![], vec![]),
+      deny_action: DenyAction::default(),
+      rrl: None,
+      edns_udp_size: DEFAULT_EDNS_UDP_SIZE,
+      padding: Padding::default(),
+      observers: vec![],
+      control: Arc::new(Control::new()),
+      #[cfg(unix)]
+      control_socket: None,
+      audit_log: None,
+      web_ui: None,
+      block_page: None,
+      query_db: None,
+      history_privacy: LogPrivacy::default(),
+      warmup: vec![],
+    }
+  }
+
+  /// Resolver used for the domains that are not blocked.
+  pub fn upstream(mut self, upstream: ClientType) -> Self {
+    self.upstreams = vec![upstream];
+    self
+  }
+
+  /// Resolvers used for the domains that are not blocked, in order of preference. Nothing is
+  /// changed when `upstreams` is empty.
+  pub fn upstreams(mut self, upstreams: impl IntoIterator<Item = ClientType>) -> Self {
+    let upstreams: Vec<ClientType> = upstreams.into_iter().collect();
+    if !upstreams.is_empty() {
+      self.upstreams = upstreams;
+    }
+    self
+  }
+
+  /// How queries are distributed across the upstreams, the first healthy one by default.
+  pub fn strategy(mut self, strategy: Strategy) -> Self {
+    self.upstream_options.strategy = strategy;
+    self
+  }
+
+  /// Send each query to two upstreams at the same time and answer with the first successful
+  /// response, when at least two upstreams are healthy.
+  pub fn race(mut self, race: bool) -> Self {
+    self.upstream_options.race = race;
+    self
+  }
+
+  /// Verifier of the certificates of the DoT, DoH and DoQ upstreams, see
+  /// [`crate::client::tls::verifier`].
+  pub fn upstream_tls(
+    mut self,
+    verifier: Arc<dyn hickory_rustls::client::ServerCertVerifier>,
+  ) -> Self {
+    self.upstream_options.tls = Some(verifier);
+    self
+  }
+
+  /// Open the TCP connections of the upstreams through a SOCKS5 or HTTP proxy, plain DNS
+  /// upstreams are then queried over TCP. QUIC upstreams cannot be proxied.
+  pub fn upstream_proxy(mut self, proxy: Proxy) -> Self {
+    self.upstream_options.proxy = Some(Arc::new(proxy));
+    self
+  }
+
+  /// Time to wait for the answer of an upstream before the query is sent again or fails, 5
+  /// seconds by default.
+  pub fn upstream_timeout(mut self, timeout: Duration) -> Self {
+    self.upstream_options.timeout = Some(timeout);
+    self
+  }
+
+  /// Number of times a query is sent to an upstream before it fails, 2 by default.
+  pub fn upstream_attempts(mut self, attempts: usize) -> Self {
+    self.upstream_options.attempts = Some(attempts);
+    self
+  }
+
+  /// Randomize the case of the query names sent to the UDP upstreams (0x20) and ignore the
+  /// responses without the same case. Upstreams not repeating the case get the names as is.
+  pub fn randomize_case(mut self, randomize_case: bool) -> Self {
+    self.upstream_options.randomize_case = randomize_case;
+    self
+  }
+
+  /// EDNS Client Subnet sent to the upstreams, none by default.
+  pub fn ecs(mut self, ecs: Ecs) -> Self {
+    self.upstream_options.ecs = ecs;
+    self
+  }
+
+  /// Resolve the queries from the root servers instead of forwarding them to the upstreams. The
+  /// client groups with their own upstream still forward their queries.
+  pub fn recursive(mut self, recursive: bool) -> Self {
+    self.recursor = recursive.then(|| {
+      Arc::new(Recursor::with_binding(
+        self.upstream_options.binding.clone(),
+      ))
+    });
+    self
+  }
+
+  /// Send the traffic to the upstreams, and the recursive queries, from this source address and
+  /// interface, e.g. through a VPN on multi-homed hosts.
+  pub fn upstream_bind(mut self, binding: Binding) -> Self {
+    self.upstream_options.binding = Arc::new(binding);
+    let recursive = self.recursor.is_some();
+    self.recursive(recursive)
+  }
+
+  /// Answer SERVFAIL with an Extended DNS Error instead of forwarding the queries while the
+  /// interface of [`DnsServerBuilder::upstream_bind`] is down. Cached answers are still sent.
+  pub fn upstream_kill_switch(mut self, kill_switch: bool) -> Self {
+    self.upstream_options.kill_switch = kill_switch;
+    self
+  }
+
+  /// Probe the upstreams at this interval, unhealthy upstreams do not receive queries until they
+  /// answer again.
+  pub fn health_check(mut self, interval: Duration) -> Self {
+    self.upstream_options.health_check = Some(interval);
+    self
+  }
+
+  /// Rules of a filter list in the AdGuard syntax, checked before the other lists. Can be called
+  /// several times.
+  pub fn filter_list(mut self, list: FilterList) -> Self {
+    Arc::make_mut(&mut self.filters).merge(list);
+    self
+  }
+
+  /// Exact domains to block.
+  pub fn blacklist(mut self, domains: impl IntoIterator<Item = LowerName>) -> Self {
+    Arc::make_mut(&mut self.blacklist).extend(domains);
+    self
+  }
+
+  /// Block exact domains under a category name, it can be disabled at runtime through the control
+  /// socket or the web UI and has its own counter in the stats. Can be called several times.
+  pub fn category(
+    mut self,
+    name: impl Into<String>,
+    domains: impl IntoIterator<Item = LowerName>,
+  ) -> Self {
+    let name = name.into();
+    let categories = Arc::make_mut(&mut self.categories);
+    match categories.iter_mut().find(|category| category.name == name) {
+      Some(category) => category.domains.extend(domains),
+      None => categories.push(Category {
+        name,
+        domains: domains.into_iter().collect(),
+      }),
+    }
+    self
+  }
+
+  /// Zones to block, with all their subdomains.
+  pub fn zone_blacklist(mut self, zones: impl IntoIterator<Item = LowerName>) -> Self {
+    Arc::make_mut(&mut self.zone_blacklist).extend(zones);
+    self
+  }
+
+  /// Zones unblocked from the zone blacklists, with all their subdomains, whatever the client.
+  pub fn zone_blacklist_exceptions(mut self, zones: impl IntoIterator<Item = LowerName>) -> Self {
+    Arc::make_mut(&mut self.zone_exceptions).extend(zones);
+    self
+  }
+
+  /// Zones to block, with all their subdomains, only while `schedule` is active.
+  pub fn scheduled_zone_blacklist(
+    mut self,
+    schedule: Schedule,
+    zones: impl IntoIterator<Item = LowerName>,
+  ) -> Self {
+    self.scheduled.push(ScheduledBlacklist {
+      schedule,
+      zones: zones.into_iter().collect(),
+    });
+    self
+  }
+
+  /// Apply the policies of a Response Policy Zone before the blacklists, can be called several
+  /// times, the first zone with a matching trigger wins.
+  pub fn rpz(mut self, zone: ResponsePolicyZone) -> Self {
+    self.rpz.push(Arc::new(zone));
+    self
+  }
+
+  /// Rewrite the questions and answers matching a rule, even when blocking is disabled, can be
+  /// called several times, the first matching rule wins.
+  pub fn rewrite(mut self, rewrite: Rewrite) -> Self {
+    self.rewrites.push(rewrite);
+    self
+  }
+
+  /// Answer authoritatively for a local zone, e.g. loaded with [`crate::authority::ZoneFile`].
+  /// Its domains are neither blocked nor forwarded.
+  pub fn zone(mut self, authority: InMemoryAuthority) -> Self {
+    self.zones.push(Arc::new(authority));
+    self
+  }
+
+  /// Answer authoritatively for a local zone signed with DNSSEC, e.g. with
+  /// [`crate::authority::DnssecZone`]. It is signed again before its signatures expire while the
+  /// catalogs are used.
+  pub fn signed_zone(mut self, authority: InMemoryAuthority) -> Self {
+    let authority = Arc::new(authority);
+    self.signed_zones.push(authority.clone());
+    self.zones.push(authority);
+    self
+  }
+
+  /// Answer authoritatively for a zone transferred from a primary, it is refreshed in the
+  /// background while the catalogs are used.
+  pub fn secondary_zone(mut self, authority: Arc<SecondaryAuthority>) -> Self {
+    self.secondary_zones.push(authority);
+    self
+  }
+
+  /// Answer the names of a local DNS file before the response policies and the blacklists.
+  pub fn local_dns(mut self, local: LocalDns) -> Self {
+    self.local_dns = Arc::new(local);
+    self
+  }
+
+  /// Interval between the checks of the local DNS file, `0` only applies the changes notified by
+  /// the file system.
+  pub fn local_dns_refresh(mut self, interval: Duration) -> Self {
+    self.local_dns_refresh = interval;
+    self
+  }
+
+  /// Rotate the IPs of the local names having several of them, for a basic load balancing.
+  pub fn local_dns_rotate(mut self, rotate: bool) -> Self {
+    self.local_dns_rotate = rotate;
+    self
+  }
+
+  /// TTL of the answers of the local names.
+  pub fn local_ttl(mut self, ttl: u32) -> Self {
+    self.local_ttl = ttl;
+    self
+  }
+
+  /// Rotate the IPs of the forwarded answers having several of them, cached answers included, so
+  /// clients spread their connections across multi-IP services.
+  pub fn rotate_answers(mut self, rotate: bool) -> Self {
+    self.rotate_answers = rotate;
+    self
+  }
+
+  /// Limit the number of cached answers and their approximate memory in bytes, the least recently
+  /// used answers are evicted first.
+  pub fn cache_limits(mut self, max_entries: usize, max_memory: Option<usize>) -> Self {
+    self.cache_max_entries = max_entries;
+    self.cache_max_memory = max_memory;
+    self
+  }
+
+  /// Share the cached answers with other instances through a Redis server, the memory cache is
+  /// still checked first.
+  pub fn cache_backend(mut self, url: RedisUrl) -> Self {
+    self.cache_backend = Some(url);
+    self
+  }
+
+  /// Accept dynamic updates (RFC 2136) of the local names of `zones` from the `clients`, they
+  /// are written to the first local DNS file.
+  pub fn local_dns_updates(
+    mut self,
+    zones: impl IntoIterator<Item = LowerName>,
+    clients: IpRangeVec,
+  ) -> Self {
+    self.local_dns_updates = Some((zones.into_iter().collect(), clients));
+    self
+  }
+
+  /// Only accept the dynamic updates signed with one of these keys (TSIG), can be repeated.
+  pub fn local_dns_update_key(mut self, key: TsigKey) -> Self {
+    self.local_dns_update_keys.push(key);
+    self
+  }
+
+  /// IP address returned for blocked domains instead of an empty NoError response.
+  pub fn default_ip(mut self, ip: Ipv4Addr) -> Self {
+    self.default_ip = Some(ip);
+    self
+  }
+
+  /// TTL of the default IP of the blocked domains, and of the SOA added to their responses
+  /// without answer so the clients cache them as long.
+  pub fn block_ttl(mut self, ttl: u32) -> Self {
+    self.block_ttl = ttl;
+    self
+  }
+
+  /// SOA and NS records of the blocked names, of the local names and of the zones of the dynamic
+  /// updates, which are not delegated to the server.
+  pub fn synthetic_zone(mut self, zone: SyntheticZone) -> Self {
+    self.synthetic_zone = Arc::new(zone);
+    self
+  }
+
+  /// Block the forwarded answers with an A or AAAA record in these networks, like a blocked
+  /// domain.
+  pub fn ip_blacklist(mut self, ips: IpRangeVec) -> Self {
+    self.ip_blacklist = ips;
+    self
+  }
+
+  /// IP using Local-Use IPv4/IPv6 Translation Prefix (rfc8215).
+  pub fn rfc8215_ips(mut self, ips: IpRangeVec) -> Self {
+    self.rfc8215_ips = ips;
+    self
+  }
+
+  /// NAT64 prefixes of the AAAA records synthesized for the `rfc8215_ips`, the well-known
+  /// `64:ff9b::/96` by default.
+  pub fn nat64(mut self, nat64: Nat64) -> Self {
+    self.nat64 = Arc::new(nat64);
+    self
+  }
+
+  /// Apply a different policy to the clients in `ips`. Groups are checked in the order they are
+  /// added, clients outside of all groups use the server options.
+  pub fn client_group(
+    mut self,
+    name: impl Into<String>,
+    ips: IpRangeVec,
+    policy: GroupPolicy,
+  ) -> Self {
+    self.groups.push((name.into(), ips, policy));
+    self
+  }
+
+  /// Forward the queries of the clients in `ips` to `upstream`, in every group. Routes are checked
+  /// in the order they are added.
+  pub fn upstream_route(mut self, ips: IpRangeVec, upstream: ClientType) -> Self {
+    self.routes.push((ips, upstream));
+    self
+  }
+
+  /// Forward the names of `zone` to `upstream`, in every group and for every client. Forward zones
+  /// are also forwarded when they are local-only zones.
+  pub fn forward_zone(mut self, zone: LowerName, upstream: ClientType) -> Self {
+    self.forward_zones.push((zone, upstream));
+    self
+  }
+
+  /// Block the zones of public DNS over HTTPS resolvers (see [`crate::authority::doh_servers`] for the built-in
+  /// ones) and answer NXDOMAIN to the canary domains of the browsers, so clients can't bypass the
+  /// blacklists with their own resolver. Applied to every client group.
+  pub fn block_doh_bypass(mut self, servers: impl IntoIterator<Item = LowerName>) -> Self {
+    self.doh_bypass = Some(Arc::new(servers.into_iter().collect()));
+    self
+  }
+
+  /// Answer NXDOMAIN to the names of these zones instead of forwarding them, unless they are local
+  /// names, zone files or response policies. See [`crate::authority::local_only_zones`] for the
+  /// reverse zones of the private addresses and the local zones of RFC 6303.
+  pub fn local_only_zones(mut self, zones: impl IntoIterator<Item = LowerName>) -> Self {
+    Arc::make_mut(&mut self.local_only).extend(zones);
+    self
+  }
+
+  /// Answer the AAAA queries of these zones with NODATA and remove the AAAA records of their
+  /// other answers, even when blocking is disabled. The root zone filters every name.
+  pub fn filter_aaaa(mut self, zones: impl IntoIterator<Item = LowerName>) -> Self {
+    Arc::make_mut(&mut self.filter_aaaa).extend(zones);
+    self
+  }
+
+  /// Answer the ANY queries with a minimal HINFO record (RFC 8482) instead of forwarding them,
+  /// they are mostly used for amplification attacks.
+  pub fn refuse_any(mut self, refuse_any: bool) -> Self {
+    self.refuse_any = refuse_any;
+    self
+  }
+
+  /// Largest UDP response to the EDNS clients (1232 bytes by default), larger responses are
+  /// truncated so the clients retry over TCP. Clients without EDNS get at most 512 bytes.
+  pub fn edns_udp_size(mut self, size: u16) -> Self {
+    self.edns_udp_size = size;
+    self
+  }
+
+  /// Pad the responses sent over DoT and DoH to the clients asking for it (RFC 7830), to a
+  /// multiple of 468 bytes by default.
+  pub fn padding(mut self, padding: Padding) -> Self {
+    self.padding = padding;
+    self
+  }
+
+  /// Answer Google, YouTube, Bing and DuckDuckGo with their safe search servers, unless blocking
+  /// is disabled.
+  pub fn safe_search(mut self, safe_search: bool) -> Self {
+    self.safe_search = safe_search;
+    self
+  }
+
+  /// Listen classic DNS requests over UDP, can be called several times.
+  pub fn listen_udp(mut self, addr: SocketAddr) -> Self {
+    self.udp.push(addr);
+    self
+  }
+
+  /// Listen classic DNS requests over TCP, where the clients retry the truncated UDP responses.
+  pub fn listen_tcp(mut self, addr: SocketAddr) -> Self {
+    self.tcp.push(addr);
+    self
+  }
+
+  /// Listen DNS over TLS (TCP) requests, requires [`DnsServerBuilder::tls_certificate`] or
+  /// [`DnsServerBuilder::acme`].
+  pub fn listen_tls(mut self, addr: SocketAddr) -> Self {
+    self.tls.push(addr);
+    self
+  }
+
+  /// Listen DNS over HTTPS/H2 requests, requires [`DnsServerBuilder::tls_certificate`] or
+  /// [`DnsServerBuilder::acme`].
+  pub fn listen_h2(mut self, addr: SocketAddr) -> Self {
+    self.h2.push(addr);
+    self
+  }
+
+  /// Use the sockets passed by systemd with socket activation, in addition to the listen
+  /// addresses. Stream sockets not named `tls` or `h2` answer DNS over TCP.
+  pub fn activated_sockets(mut self, sockets: ActivatedSockets) -> Self {
+    self.activated = sockets;
+    self
+  }
+
+  /// Read the PROXY protocol header (version 1 or 2) on the TCP, TLS and H2 connections from
+  /// these networks, and answer their queries as coming from the client it conveys.
+  pub fn proxy_protocol(mut self, networks: IpRangeVec) -> Self {
+    self.proxies = networks;
+    self
+  }
+
+  /// Only answer the H2 requests presenting `credential` in their `Authorization` header.
+  pub fn h2_credential(mut self, credential: Credential) -> Self {
+    self.h2_credential = Some(credential);
+    self
+  }
+
+  /// Certificate and private key (PEM) of the TLS and H2 listeners.
+  pub fn tls_certificate(
+    mut self,
+    certificate: impl Into<PathBuf>,
+    private_key: impl Into<PathBuf>,
+  ) -> Self {
+    self.tls_certificate = Some((certificate.into(), private_key.into()));
+    self
+  }
+
+  /// Only answer the clients of the TLS and H2 listeners presenting a certificate signed by one of
+  /// the certificate authorities of `ca` (PEM).
+  pub fn tls_client_ca(mut self, ca: impl Into<PathBuf>) -> Self {
+    self.tls_options.client_ca = Some(ca.into());
+    self
+  }
+
+  /// Oldest TLS version accepted by the TLS and H2 listeners.
+  pub fn tls_min_version(mut self, version: TlsVersion) -> Self {
+    self.tls_options.min_version = version;
+    self
+  }
+
+  /// Cipher suites allowed on the TLS and H2 listeners, by their names (e.g.
+  /// `TLS13_AES_256_GCM_SHA384`). All the suites of rustls are allowed by default.
+  pub fn tls_cipher_suites(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+    self.tls_options.cipher_suites = names.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// Obtain the certificate of the TLS and H2 listeners from an ACME certificate authority, and
+  /// renew it while the server is running.
+  pub fn acme(mut self, options: AcmeOptions) -> Self {
+    self.acme = Some(options);
+    self
+  }
+
+  /// Networks allowed to query the listeners, see [`DnsServerBuilder::deny_action`] for the
+  /// requests of the other clients.
+  pub fn acl(mut self, acl: Acl) -> Self {
+    self.acl = acl;
+    self
+  }
+
+  /// Networks allowed to query one kind of listener instead of the ones of
+  /// [`DnsServerBuilder::acl`], the unset lists of `acl` are inherited.
+  pub fn listener_acl(mut self, listener: Listener, acl: Acl) -> Self {
+    self.listener_acls.push((listener, acl));
+    self
+  }
+
+  /// Drop the requests of the denied clients (default) or answer them REFUSED.
+  pub fn deny_action(mut self, action: DenyAction) -> Self {
+    self.deny_action = action;
+    self
+  }
+
+  /// Limit the rate of the identical answers sent to each client network over UDP.
+  pub fn rrl(mut self, options: RrlOptions) -> Self {
+    self.rrl = Some(options);
+    self
+  }
+
+  /// Receive an event for each answered query.
+  pub fn observer(mut self, observer: Arc<dyn QueryObserver>) -> Self {
+    self.observers.push(observer);
+    self
+  }
+
+  /// Share the runtime state (blocked zones, disabled blocking, statistics) with a previous
+  /// server or catalogs, e.g. when they are rebuilt after a reload.
+  pub fn control(mut self, control: Arc<Control>) -> Self {
+    self.control = control;
+    self
+  }
+
+  /// Unix socket receiving the commands of `dns-server ctl`.
+  #[cfg(unix)]
+  pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+    self.control_socket = Some(path.into());
+    self
+  }
+
+  /// Record the changes made through the control socket and the dashboard in `audit_log`.
+  pub fn audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+    self.audit_log = Some(audit_log);
+    self
+  }
+
+  /// Serve the dashboard on this address. It has no authentication, only listen on a trusted
+  /// network.
+  pub fn web_ui(mut self, addr: SocketAddr) -> Self {
+    self.web_ui = Some(addr);
+    self
+  }
+
+  /// Serve `page` over HTTP on the default IP, and over HTTPS with the certificate of the TLS
+  /// listeners, so the blocked websites show why they are blocked.
+  pub fn block_page(mut self, page: BlockPage) -> Self {
+    self.block_page = Some(page);
+    self
+  }
+
+  /// Resolve `names` through the first UDP listener once the server is started and after each
+  /// cache flush, so their answers are always cached.
+  pub fn warmup(mut self, names: impl IntoIterator<Item = Name>) -> Self {
+    self.warmup.extend(names);
+    self
+  }
+
+  /// Store the queries in a database, its statistics are also served by the dashboard.
+  pub fn query_db(mut self, query_db: Arc<QueryDb>) -> Self {
+    self.query_db = Some(query_db);
+    self
+  }
+
+  /// Anonymize or skip the last queries and top lists kept in memory for the dashboard.
+  pub fn history_privacy(mut self, privacy: LogPrivacy) -> Self {
+    self.history_privacy = privacy;
+    self
+  }
+
+  /// Database locating the clients, used by the countries of the ACL and of the client groups.
+  pub fn geoip(mut self, geoip: GeoIp) -> Self {
+    self.geoip = Some(Arc::new(geoip));
+    self
+  }
+
+  /// Check the GeoIP database for changes every `interval`, `0` disables it.
+  pub fn geoip_refresh(mut self, interval: Duration) -> Self {
+    self.geoip_refresh = interval;
+    self
+  }
+
+  /// Only answer the clients located in the `allow` countries when there are some, and never the
+  /// ones of the `deny` countries. Clients missing from the GeoIP database are not filtered.
+  pub fn countries(mut self, allow: Vec<String>, deny: Vec<String>) -> Self {
+    self.countries = (allow, deny);
+    self
+  }
+
+  /// Create the catalogs answering the queries, this must be called within a Tokio runtime.
+  pub fn build_catalogs(&self) -> Catalogs {
+    self.local_dns.watch(self.local_dns_refresh);
+    for zone in self.secondary_zones.iter() {
+      zone.watch();
+    }
+    for zone in self.signed_zones.iter() {
+      watch_signatures(zone);
+    }
+    if let Some(geoip) = &self.geoip {
+      geoip.watch(self.geoip_refresh);
+    }
+    self.control.set_categories(
+      self
+        .categories
+        .iter()
+        .map(|category| category.name.as_str()),
+    );
+    self.control.set_listed_domains(
+      self.filters.len()
+        + self.blacklist.len()
+        + self.zone_blacklist.len()
+        + self
+          .categories
+          .iter()
+          .map(|category| category.domains.len())
+          .sum::<usize>(),
+    );
+    let mut catalogs = Catalogs::new(
+      self.build_catalog(
+        &self.blacklist,
+        &self.zone_blacklist,
+        self.default_ip,
+        self.build_upstreams(None),
+      ),
+      self.control.clone(),
+    );
+    for (name, ips, policy) in self.groups.iter() {
+      let catalog = self.build_catalog(
+        policy.blacklist.as_ref().unwrap_or(&self.blacklist),
+        policy
+          .zone_blacklist
+          .as_ref()
+          .unwrap_or(&self.zone_blacklist),
+        policy.default_ip.or(self.default_ip),
+        self.build_upstreams(policy.upstream.as_ref()),
+      );
+      catalogs.add_group(name.clone(), ips.clone(), policy.countries.clone(), catalog);
+    }
+    if let Some(geoip) = &self.geoip {
+      catalogs.set_geoip(geoip.clone());
+    }
+    catalogs.set_refuse_any(self.refuse_any);
+    catalogs.set_block_ttl(self.block_ttl);
+    catalogs.set_synthetic_zone(self.synthetic_zone.clone());
+    if let Some((zones, clients)) = &self.local_dns_updates {
+      catalogs.set_updater(LocalDnsUpdater::new(
+        self.local_dns.clone(),
+        zones.clone(),
+        clients.clone(),
+        self.local_dns_update_keys.clone(),
+      ));
+    }
+    catalogs
+  }
+
+  /// Upstreams of the root zone, `upstream` replaces the configured ones and the recursion.
+  fn build_upstreams(&self, upstream: Option<&ClientType>) -> Arc<Upstreams> {
+    let root = Name::root();
+    // Answers depending on the client subnet can not be shared.
+    let cache = self.control.cache();
+    cache.set_limits(self.cache_max_entries, self.cache_max_memory);
+    if let Some(url) = &self.cache_backend {
+      cache.set_shared(url.clone());
+    }
+    let options = UpstreamOptions {
+      cache: (self.upstream_options.ecs != Ecs::Forward).then(|| cache.clone()),
+      ..self.upstream_options.clone()
+    };
+    match (upstream, &self.recursor) {
+      (None, Some(recursor)) => {
+        cache.attach(recursor);
+        Upstreams::recursive(&root, recursor.clone(), &options, self.control.stats())
+      }
+      (upstream, _) => Upstreams::new(
+        &root,
+        upstream.map_or(&self.upstreams[..], std::slice::from_ref),
+        &options,
+        self.control.stats(),
+      ),
+    }
+  }
+
+  fn build_catalog(
+    &self,
+    blacklist: &Arc<DomainSet>,
+    zone_blacklist: &Arc<DomainSet>,
+    default_ip: Option<Ipv4Addr>,
+    upstreams: Arc<Upstreams>,
+  ) -> ClientCatalog {
+    let mut catalog = Catalog::new();
+    let mut unfiltered = Catalog::new();
+    let name = Name::root();
+
+    for zone in self.zones.iter() {
+      catalog.upsert(zone.origin().clone(), Box::new(zone.clone()));
+      unfiltered.upsert(zone.origin().clone(), Box::new(zone.clone()));
+    }
+    for zone in self.secondary_zones.iter() {
+      catalog.upsert(zone.origin().clone(), Box::new(zone.clone()));
+      unfiltered.upsert(zone.origin().clone(), Box::new(zone.clone()));
+    }
+
+    if self.doh_bypass.is_some() {
+      for domain in canary_domains() {
+        let authority = NoneAuthority::new(domain.clone(), None)
+          .list("DoH bypass list")
+          .nxdomain();
+        catalog.upsert(domain, Box::new(Arc::new(authority)));
+      }
+    }
+
+    let authority = BlacklistAuthority::new(
+      Blocklist {
+        filters: self.filters.clone(),
+        domains: blacklist.clone(),
+        zones: zone_blacklist.clone(),
+        zone_exceptions: self.zone_exceptions.clone(),
+        doh_servers: self.doh_bypass.clone().unwrap_or_default(),
+        categories: self.categories.clone(),
+        scheduled: self.scheduled.clone(),
+        ips: self.ip_blacklist.clone(),
+        control: self.control.clone(),
+      },
+      upstreams,
+      default_ip,
+      self.rfc8215_ips.clone(),
+    )
+    .block_ttl(self.block_ttl)
+    .synthetic_zone(self.synthetic_zone.clone())
+    .local_only(self.local_only.clone())
+    .nat64(self.nat64.clone())
+    .rotate_answers(self.rotate_answers)
+    .routes(
+      self
+        .routes
+        .iter()
+        .map(|(ips, upstream)| (ips.clone(), self.build_upstreams(Some(upstream))))
+        .collect(),
+    )
+    .forward_zones(
+      self
+        .forward_zones
+        .iter()
+        .map(|(zone, upstream)| (zone.clone(), self.build_upstreams(Some(upstream))))
+        .collect(),
+    );
+    // The root authority checks itself whether blocking is disabled, it is shared with the
+    // catalog used in that case, without the response policies.
+    let authority = Arc::new(authority);
+    let filtered = SafeSearchAuthority::new(self.safe_search, authority.clone());
+    let filtered = RpzAuthority::new(self.rpz.clone(), Arc::new(filtered));
+    let rewrites: Arc<[Rewrite]> = self.rewrites.clone().into();
+    let filtered = RewriteAuthority::new(rewrites.clone(), Arc::new(filtered));
+    let authority = RewriteAuthority::new(rewrites, authority);
+    let local_dns = self.local_dns.clone();
+    let update_zones = self
+      .local_dns_updates
+      .as_ref()
+      .map(|(zones, _)| zones.clone())
+      .unwrap_or_default();
+    let filtered =
+      LocalDnsAuthority::new(local_dns.clone(), self.local_dns_rotate, Arc::new(filtered))
+        .ttl(self.local_ttl)
+        .synthetic_zone(self.synthetic_zone.clone(), update_zones.clone());
+    let authority = LocalDnsAuthority::new(local_dns, self.local_dns_rotate, Arc::new(authority))
+      .ttl(self.local_ttl)
+      .synthetic_zone(self.synthetic_zone.clone(), update_zones);
+    let filtered = FilterAaaaAuthority::new(self.filter_aaaa.clone(), Arc::new(filtered));
+    let authority = FilterAaaaAuthority::new(self.filter_aaaa.clone(), Arc::new(authority));
+    catalog.upsert(LowerName::new(&name), Box::new(Arc::new(filtered)));
+    unfiltered.upsert(LowerName::new(&name), Box::new(Arc::new(authority)));
+
+    ClientCatalog {
+      catalog,
+      unfiltered,
+    }
+  }
+
+  /// Create the server, this must be called within a Tokio runtime. Nothing is listening until
+  /// [`ServerHandle::start`] is called.
+  pub fn build(self) -> Result<ServerHandle> {
+    let tls_listeners =
+      self.tls.len() + self.h2.len() + self.activated.tls.len() + self.activated.h2.len();
+    if tls_listeners > 0 && self.tls_certificate.is_none() && self.acme.is_none() {
+      bail!("TLS and H2 listeners require a certificate and a private key, or ACME");
+    }
+    if self.tls_certificate.is_some() && self.acme.is_some() {
+      bail!("the TLS certificate is either given or obtained with ACME, not both");
+    }
+    let certificates = Arc::new(CertificateStore::default());
+    if let Some((certificate, private_key)) = &self.tls_certificate {
+      certificates.load(certificate, private_key)?;
+    }
+    if tls_listeners > 0 {
+      // Invalid cipher suites or client CA are reported before starting.
+      tls::server_config(certificates.clone(), &self.tls_options, &[])?;
+    }
+
+    let mut handler = DnsHandler::new(self.build_catalogs());
+    let mut access = Access::new(self.acl);
+    for (listener, acl) in self.listener_acls {
+      access.add_listener(listener, acl);
+    }
+    let (allow, deny) = self.countries;
+    if !allow.is_empty() || !deny.is_empty() {
+      let geoip = self
+        .geoip
+        .clone()
+        .ok_or_else(|| anyhow!("the countries of the clients require a GeoIP database"))?;
+      access.countries = Some(CountryAcl { geoip, allow, deny });
+    }
+    access.deny_action = self.deny_action;
+    handler.set_access(access);
+    handler.set_edns_udp_size(self.edns_udp_size);
+    handler.set_padding(self.padding);
+    if let Some(options) = self.rrl {
+      handler.set_rate_limiter(RateLimiter::new(options));
+    }
+    handler.add_observer(self.control.stats());
+    for observer in self.observers {
+      handler.add_observer(observer);
+    }
+    if let Some(query_db) = &self.query_db {
+      handler.add_observer(query_db.clone());
+    }
+    let web_ui = self.web_ui.map(|addr| {
+      let history = Arc::new(QueryHistory::new(WEB_UI_HISTORY).privacy(self.history_privacy));
+      handler.add_observer(history.clone());
+      (
+        addr,
+        WebUi::new(self.control.clone(), history, self.query_db.clone()),
+      )
+    });
+    let block_page = match (self.block_page, self.default_ip) {
+      (None, _) => None,
+      (Some(page), Some(ip)) if !ip.is_unspecified() => {
+        let page = Arc::new(page);
+        handler.add_observer(page.clone());
+        let https = self.tls_certificate.is_some() || self.acme.is_some();
+        Some((ip, https, page))
+      }
+      (Some(_), _) => bail!("the block page is served on the default IP, which must be set"),
+    };
+    if let Some(audit_log) = self.audit_log {
+      self.control.set_audit_log(Some(audit_log));
+    }
+    let (trigger, signal) = oneshot::channel::<()>();
+    let shutdown = Shutdown::new(async move {
+      let _ = signal.await;
+    });
+    handler.set_shutdown(shutdown.guard_weak());
+
+    Ok(ServerHandle {
+      catalog: handler.catalog(),
+      in_flight: handler.in_flight(),
+      stream_handler: handler.clone(),
+      server: ServerFuture::new(handler),
+      shutdown,
+      trigger,
+      listeners: Listeners {
+        udp: self.udp,
+        tcp: self.tcp,
+        tls: self.tls,
+        h2: self.h2,
+        activated: self.activated,
+        certificates,
+        acme: self.acme,
+        tls_options: self.tls_options,
+        h2_credential: self.h2_credential,
+        proxies: Arc::new(self.proxies),
+      },
+      udp_addrs: vec![],
+      control: self.control,
+      #[cfg(unix)]
+      control_socket: self.control_socket,
+      web_ui,
+      block_page,
+      warmup: self.warmup,
+    })
+  }
+}
+
+struct Listeners {
+  udp: Vec<SocketAddr>,
+  tcp: Vec<SocketAddr>,
+  tls: Vec<SocketAddr>,
+  h2: Vec<SocketAddr>,
+  activated: ActivatedSockets,
+  certificates: Arc<CertificateStore>,
+  acme: Option<AcmeOptions>,
+  tls_options: TlsOptions,
+  h2_credential: Option<Credential>,
+  proxies: Arc<IpRangeVec>,
+}
+
+/// A server created by [`DnsServerBuilder`].
+pub struct ServerHandle {
+  server: ServerFuture<DnsHandler>,
+  /// Handler of the TCP, TLS and H2 listeners, served outside of `server` to renew their
+  /// certificate and read the PROXY protocol.
+  stream_handler: DnsHandler,
+  catalog: CatalogHandle,
+  in_flight: Arc<AtomicUsize>,
+  shutdown: Shutdown,
+  trigger: oneshot::Sender<()>,
+  listeners: Listeners,
+  udp_addrs: Vec<SocketAddr>,
+  control: Arc<Control>,
+  #[cfg(unix)]
+  control_socket: Option<PathBuf>,
+  web_ui: Option<(SocketAddr, WebUi)>,
+  /// Default IP serving the block page, and whether it is served over HTTPS too.
+  block_page: Option<(Ipv4Addr, bool, Arc<BlockPage>)>,
+  /// Names resolved once started, see [`DnsServerBuilder::warmup`].
+  warmup: Vec<Name>,
+}
+
+impl ServerHandle {
+  /// Bind and register all the listeners.
+  pub async fn start(&mut self) -> Result<()> {
+    let certificates = self.listeners.certificates.clone();
+    if let Some(options) = self.listeners.acme.take() {
+      acme::start(options, certificates.clone()).await?;
+    }
+
+    let activated = std::mem::take(&mut self.listeners.activated);
+    let mut udp_sockets = vec![];
+    for addr in std::mem::take(&mut self.listeners.udp) {
+      info!("Will listen UDP resquests on {}", addr);
+      let udp_socket = UdpSocket::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to UDP socket {addr}"))?;
+      udp_sockets.push(udp_socket);
+    }
+    for socket in activated.udp {
+      let udp_socket = UdpSocket::from_std(socket)?;
+      info!(
+        "Will listen UDP resquests on {} from systemd",
+        udp_socket.local_addr()?
+      );
+      udp_sockets.push(udp_socket);
+    }
+    for udp_socket in udp_sockets {
+      self.udp_addrs.push(udp_socket.local_addr()?);
+      self.server.register_socket(udp_socket);
+    }
+
+    let mut tcp_listeners = vec![];
+    for addr in std::mem::take(&mut self.listeners.tcp) {
+      info!("Will listen TCP resquests on {}", addr);
+      let tcp_listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to TCP socket {addr}"))?;
+      tcp_listeners.push(tcp_listener);
+    }
+    for socket in activated.tcp {
+      let tcp_listener = TcpListener::from_std(socket)?;
+      info!(
+        "Will listen TCP resquests on {} from systemd",
+        tcp_listener.local_addr()?
+      );
+      tcp_listeners.push(tcp_listener);
+    }
+    for tcp_listener in tcp_listeners {
+      stream::serve(
+        tcp_listener,
+        None,
+        self.stream_handler.clone(),
+        self.listeners.proxies.clone(),
+        TCP_TIMEOUT,
+        self.shutdown.guard_weak(),
+      );
+    }
+
+    let mut h2_listeners = vec![];
+    for addr in std::mem::take(&mut self.listeners.h2) {
+      info!("Will listen HTTPS/H2 resquests on {}", addr);
+      let https_listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to TCP socket {addr}"))?;
+      h2_listeners.push(https_listener);
+    }
+    for socket in activated.h2 {
+      let https_listener = TcpListener::from_std(socket)?;
+      info!(
+        "Will listen HTTPS/H2 resquests on {} from systemd",
+        https_listener.local_addr()?
+      );
+      h2_listeners.push(https_listener);
+    }
+    for https_listener in h2_listeners {
+      doh::serve(
+        https_listener,
+        tls::server_config(certificates.clone(), &self.listeners.tls_options, &[b"h2"])?,
+        self.stream_handler.clone(),
+        self.listeners.h2_credential.clone(),
+        self.listeners.proxies.clone(),
+        TCP_TIMEOUT,
+        self.shutdown.guard_weak(),
+      );
+    }
+
+    let mut tls_listeners = vec![];
+    for addr in std::mem::take(&mut self.listeners.tls) {
+      info!("Will listen TLS/TCP resquests on {}", addr);
+      let tls_listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to TCP socket {addr}"))?;
+      tls_listeners.push(tls_listener);
+    }
+    for socket in activated.tls {
+      let tls_listener = TcpListener::from_std(socket)?;
+      info!(
+        "Will listen TLS/TCP resquests on {} from systemd",
+        tls_listener.local_addr()?
+      );
+      tls_listeners.push(tls_listener);
+    }
+    for tls_listener in tls_listeners {
+      stream::serve(
+        tls_listener,
+        Some(tls::server_config(
+          certificates.clone(),
+          &self.listeners.tls_options,
+          &[],
+        )?),
+        self.stream_handler.clone(),
+        self.listeners.proxies.clone(),
+        TCP_TIMEOUT,
+        self.shutdown.guard_weak(),
+      );
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &self.control_socket {
+      control::serve(path, self.control.clone())?;
+    }
+
+    if let Some((addr, mut web_ui)) = self.web_ui.take() {
+      info!("Will serve the web UI on http://{}", addr);
+      if let Some(dns_addr) = self.udp_addrs.first() {
+        web_ui = web_ui.health_check(*dns_addr);
+      }
+      let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to TCP socket {addr}"))?;
+      web_ui.serve(listener);
+    }
+
+    if let Some((ip, https, page)) = self.block_page.take() {
+      let addr = SocketAddr::new(ip.into(), block_page::HTTP_PORT);
+      info!("Will serve the block page on http://{}", addr);
+      let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("could not bind to TCP socket {addr}"))?;
+      page.clone().serve(listener, None);
+      if https {
+        let addr = SocketAddr::new(ip.into(), block_page::HTTPS_PORT);
+        info!("Will serve the block page on https://{}", addr);
+        let listener = TcpListener::bind(addr)
+          .await
+          .with_context(|| format!("could not bind to TCP socket {addr}"))?;
+        // Browsers never present a client certificate to a blocked website.
+        let options = TlsOptions {
+          client_ca: None,
+          ..self.listeners.tls_options.clone()
+        };
+        page.serve(
+          listener,
+          Some(tls::server_config(certificates, &options, &[b"http/1.1"])?),
+        );
+      }
+    }
+
+    let names = std::mem::take(&mut self.warmup);
+    if !names.is_empty() {
+      let Some(addr) = self.udp_addrs.first() else {
+        bail!("the warm-up list is resolved through a UDP listener, there is none");
+      };
+      info!("Will warm up the cache with {} names", names.len());
+      warmup::spawn(healthcheck::local_addr(*addr), names, self.control.cache());
+    }
+
+    Ok(())
+  }
+
+  /// Runtime state of the server, changed by the commands of the control socket.
+  pub fn control(&self) -> Arc<Control> {
+    self.control.clone()
+  }
+
+  /// Addresses of the UDP listeners once started, useful when binding on port 0.
+  pub fn udp_addrs(&self) -> &[SocketAddr] {
+    &self.udp_addrs
+  }
+
+  /// Catalogs currently answering the queries, they can be replaced while the server is running.
+  pub fn catalog(&self) -> CatalogHandle {
+    self.catalog.clone()
+  }
+
+  /// Number of requests currently being answered.
+  pub fn in_flight(&self) -> usize {
+    self.in_flight.load(Ordering::Relaxed)
+  }
+
+  /// Wait until a listener stops, which only happens on error.
+  pub async fn block_until_done(&mut self) -> Result<()> {
+    self.server.block_until_done().await?;
+    Ok(())
+  }
+
+  /// Refuse new queries, wait at most `limit` for the queries in flight and stop the listeners.
+  pub async fn shutdown(mut self, limit: Duration) -> Result<()> {
+    let draining = self.in_flight();
+    let _ = self.trigger.send(());
+    info!(
+      "Shutdown requested, new queries are refused while draining {} in-flight requests",
+      draining
+    );
+    let drained = match self.shutdown.shutdown_with_limit(limit).await {
+      Ok(elapsed) => {
+        info!("Drained {} requests in {:?}", draining, elapsed);
+        Ok(())
+      }
+      Err(err) => Err(anyhow!(
+        "{} requests still in flight after the shutdown timeout: {}",
+        self.in_flight.load(Ordering::Relaxed),
+        err
+      )),
+    };
+
+    #[cfg(unix)]
+    if let Some(path) = &self.control_socket {
+      let _ = std::fs::remove_file(path);
+    }
+    if let Err(err) = self.server.shutdown_gracefully().await {
+      error!("Could not stop the listeners: {}", err);
+      return Err(err).context("could not stop the listeners");
+    }
+    self.stream_handler.stop_observers().await;
+    drained
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::{
+    op::{Edns, Message, Query, ResponseCode},
+    rr::{
+      rdata::{
+        opt::{EdnsCode, EdnsOption},
+        NS,
+      },
+      RData, RecordType,
+    },
+    serialize::binary::BinDecodable,
+  };
+  use std::str::FromStr;
+
+  async fn query(server: &ServerHandle, name: &str, query_type: RecordType) -> Message {
+    let mut query = Message::new();
+    query
+      .set_id(42)
+      .set_recursion_desired(true)
+      .add_query(Query::query(Name::from_str(name).unwrap(), query_type))
+      .set_edns(Edns::new());
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket
+      .send_to(&query.to_vec().unwrap(), server.udp_addrs()[0])
+      .await
+      .unwrap();
+    let mut buffer = [0; 512];
+    let (length, _) = socket.recv_from(&mut buffer).await.unwrap();
+    Message::from_bytes(&buffer[..length]).unwrap()
+  }
+
+  #[tokio::test]
+  async fn blocked_domain() {
+    let mut server = DnsServerBuilder::new()
+      .blacklist([LowerName::from_str("blocked.example.com.").unwrap()])
+      .default_ip(Ipv4Addr::new(10, 0, 0, 1))
+      .listen_udp("127.0.0.1:0".parse().unwrap())
+      .build()
+      .unwrap();
+    server.start().await.unwrap();
+
+    let response = query(&server, "blocked.example.com.", RecordType::A).await;
+    assert_eq!(response.id(), 42);
+    assert_eq!(
+      response.answers()[0].data(),
+      Some(&RData::A(Ipv4Addr::new(10, 0, 0, 1).into()))
+    );
+    assert_eq!(response.answers()[0].ttl(), DEFAULT_BLOCK_TTL);
+    assert!(response.name_servers().is_empty());
+    let mut extended_error = 15u16.to_be_bytes().to_vec();
+    extended_error.extend_from_slice(b"Blocked by the blacklist");
+    assert_eq!(
+      response
+        .extensions()
+        .as_ref()
+        .unwrap()
+        .option(EdnsCode::Unknown(15)),
+      Some(&EdnsOption::Unknown(15, extended_error))
+    );
+    server.shutdown(Duration::from_secs(1)).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn blocked_soa() {
+    let mut server = DnsServerBuilder::new()
+      .blacklist([LowerName::from_str("blocked.example.com.").unwrap()])
+      .block_ttl(60)
+      .listen_udp("127.0.0.1:0".parse().unwrap())
+      .build()
+      .unwrap();
+    server.start().await.unwrap();
+
+    let response = query(&server, "blocked.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    let soa = &response.name_servers()[0];
+    assert_eq!(soa.name(), &Name::from_str("blocked.example.com.").unwrap());
+    assert_eq!(soa.ttl(), 60);
+    let Some(RData::SOA(soa)) = soa.data() else {
+      panic!("expected a SOA record");
+    };
+    assert_eq!(soa.minimum(), 60);
+    // The Extended DNS Error is kept.
+    assert!(response
+      .extensions()
+      .as_ref()
+      .unwrap()
+      .option(EdnsCode::Unknown(15))
+      .is_some());
+
+    // The blocked name is the apex of its own zone.
+    let response = query(&server, "blocked.example.com.", RecordType::NS).await;
+    assert_eq!(
+      response.answers()[0].data(),
+      Some(&RData::NS(NS(Name::from_str("localhost.").unwrap())))
+    );
+    assert!(response.name_servers().is_empty());
+    server.shutdown(Duration::from_secs(1)).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn local_synthetic_zone() {
+    let mut server = DnsServerBuilder::new()
+      .local_dns(LocalDns::parse("192.168.1.10 nas.lan").unwrap())
+      .local_ttl(60)
+      .synthetic_zone(SyntheticZone {
+        ns: Name::from_str("dns.lan.").unwrap(),
+        hostmaster: Name::from_str("admin.lan.").unwrap(),
+      })
+      .listen_udp("127.0.0.1:0".parse().unwrap())
+      .build()
+      .unwrap();
+    server.start().await.unwrap();
+
+    let response = query(&server, "nas.lan.", RecordType::A).await;
+    assert_eq!(response.answers()[0].ttl(), 60);
+    let response = query(&server, "nas.lan.", RecordType::SOA).await;
+    let Some(RData::SOA(soa)) = response.answers()[0].data() else {
+      panic!("expected a SOA record");
+    };
+    assert_eq!(soa.mname(), &Name::from_str("dns.lan.").unwrap());
+    assert_eq!(soa.rname(), &Name::from_str("admin.lan.").unwrap());
+    let response = query(&server, "nas.lan.", RecordType::MX).await;
+    assert!(response.answers().is_empty());
+    server.shutdown(Duration::from_secs(1)).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn block_page_default_ip() {
+    let build = |ip| {
+      DnsServerBuilder::new()
+        .default_ip(ip)
+        .block_page(BlockPage::default())
+        .build()
+    };
+    assert!(build(Ipv4Addr::UNSPECIFIED).is_err());
+    assert!(build(Ipv4Addr::new(10, 0, 0, 1)).is_ok());
+    assert!(DnsServerBuilder::new()
+      .block_page(BlockPage::default())
+      .build()
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn doh_bypass() {
+    let mut server = DnsServerBuilder::new()
+      .block_doh_bypass([LowerName::from_str("dns.example.com.").unwrap()])
+      .default_ip(Ipv4Addr::new(10, 0, 0, 1))
+      .listen_udp("127.0.0.1:0".parse().unwrap())
+      .build()
+      .unwrap();
+    server.start().await.unwrap();
+
+    let response = query(&server, "doh.dns.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+      response.answers()[0].data(),
+      Some(&RData::A(Ipv4Addr::new(10, 0, 0, 1).into()))
+    );
+    let response = query(&server, "use-application-dns.net.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert!(response.answers().is_empty());
+    assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+    server.shutdown(Duration::from_secs(1)).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn refuse_any() {
+    let mut server = DnsServerBuilder::new()
+      .refuse_any(true)
+      .listen_udp("127.0.0.1:0".parse().unwrap())
+      .build()
+      .unwrap();
+    server.start().await.unwrap();
+
+    let response = query(&server, "example.com.", RecordType::ANY).await;
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].record_type(), RecordType::HINFO);
+    server.shutdown(Duration::from_secs(1)).await.unwrap();
+  }
+
+  #[test]
+  fn tls_requires_certificate() {
+    let builder = DnsServerBuilder::new().listen_tls("127.0.0.1:0".parse().unwrap());
+    assert!(builder.build().is_err());
+  }
+}
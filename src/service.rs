@@ -0,0 +1,468 @@
+use anyhow::{bail, Context, Result};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Registry key of the Windows services.
+const WINDOWS_SERVICES: &str = r"HKLM\SYSTEM\CurrentControlSet\Services";
+/// Registry key of the sources of the Windows Application event log.
+const WINDOWS_EVENT_SOURCES: &str = r"HKLM\SYSTEM\CurrentControlSet\Services\EventLog\Application";
+/// Source of the events written with `--log-output eventlog`, whatever the service label.
+pub const EVENT_SOURCE: &str = "dns-server";
+
+/// Label of the service when none is given, reverse DNS like the other launchd services.
+#[cfg(target_os = "macos")]
+pub const DEFAULT_LABEL: &str = "com.github.joxit.dns-server";
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_LABEL: &str = "dns-server";
+
+/// Service running the server in the background, started at boot and restarted when it stops.
+/// It is a launchd daemon on macOS, a systemd unit on Linux and a service of the service control
+/// manager on Windows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+  pub label: String,
+  /// Absolute path of the binary.
+  pub program: PathBuf,
+  /// Options of the server, `service run` is added after them.
+  pub args: Vec<String>,
+}
+
+impl Service {
+  /// Property list of a launchd daemon, its output is written in `/var/log/<label>.log`.
+  pub fn launchd_plist(&self) -> String {
+    let mut arguments = String::new();
+    let program = self.program.to_string_lossy();
+    let run = ["service".to_string(), "run".to_string()];
+    for arg in
+      std::iter::once(program.as_ref()).chain(self.args.iter().chain(&run).map(String::as_str))
+    {
+      let _ = writeln!(arguments, "    <string>{}</string>", xml_escape(arg));
+    }
+    let label = xml_escape(&self.label);
+    format!(
+      r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+{arguments}  </array>
+  <key>RunAtLoad</key>
+  <true/>
+  <key>KeepAlive</key>
+  <true/>
+  <key>StandardOutPath</key>
+  <string>/var/log/{label}.log</string>
+  <key>StandardErrorPath</key>
+  <string>/var/log/{label}.log</string>
+</dict>
+</plist>
+"#
+    )
+  }
+
+  /// Unit of a systemd service, its output goes to the journal.
+  pub fn systemd_unit(&self) -> String {
+    let command: Vec<String> = std::iter::once(self.program.to_string_lossy().to_string())
+      .chain(self.args.iter().cloned())
+      .chain(["service".to_string(), "run".to_string()])
+      .map(|arg| systemd_quote(&arg))
+      .collect();
+    format!(
+      "[Unit]
+Description=DNS server blocking domains and zones
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+ExecStart={}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+",
+      command.join(" ")
+    )
+  }
+
+  /// Command line of a Windows service, the options are quoted for `CommandLineToArgvW`.
+  pub fn windows_command(&self) -> String {
+    std::iter::once(self.program.to_string_lossy().to_string())
+      .chain(self.args.iter().cloned())
+      .chain(["service".to_string(), "run".to_string()])
+      .map(|arg| windows_quote(&arg))
+      .collect::<Vec<String>>()
+      .join(" ")
+  }
+
+  /// Path and content of the service definition on this system. Windows services are kept in
+  /// the registry, their definition is the command line.
+  pub fn definition(&self) -> Result<(PathBuf, String)> {
+    if cfg!(target_os = "macos") {
+      let path = Path::new("/Library/LaunchDaemons").join(format!("{}.plist", self.label));
+      Ok((path, self.launchd_plist()))
+    } else if cfg!(target_os = "linux") {
+      let path = Path::new("/etc/systemd/system").join(format!("{}.service", self.label));
+      Ok((path, self.systemd_unit()))
+    } else if cfg!(windows) {
+      let path = Path::new(WINDOWS_SERVICES).join(&self.label);
+      Ok((path, self.windows_command()))
+    } else {
+      bail!("services are only supported with launchd, systemd and Windows")
+    }
+  }
+
+  /// Write the service definition and start it, it is then started at boot.
+  pub fn install(&self) -> Result<PathBuf> {
+    let (path, definition) = self.definition()?;
+    if cfg!(windows) {
+      self.install_windows(&definition)?;
+      return Ok(path);
+    }
+    std::fs::write(&path, definition)
+      .with_context(|| format!("could not write {}", path.display()))?;
+    if cfg!(target_os = "macos") {
+      run(Command::new("launchctl").arg("load").arg("-w").arg(&path))?;
+    } else {
+      run(Command::new("systemctl").arg("daemon-reload"))?;
+      run(Command::new("systemctl").args(["enable", "--now", &self.label]))?;
+    }
+    Ok(path)
+  }
+
+  /// Stop the service and remove its definition.
+  pub fn uninstall(&self) -> Result<PathBuf> {
+    let (path, _) = self.definition()?;
+    if cfg!(windows) {
+      self.uninstall_windows()?;
+      return Ok(path);
+    }
+    if !path.exists() {
+      bail!("the service {} is not installed", self.label);
+    }
+    if cfg!(target_os = "macos") {
+      run(Command::new("launchctl").arg("unload").arg("-w").arg(&path))?;
+    } else {
+      run(Command::new("systemctl").args(["disable", "--now", &self.label]))?;
+    }
+    std::fs::remove_file(&path).with_context(|| format!("could not remove {}", path.display()))?;
+    if cfg!(target_os = "linux") {
+      run(Command::new("systemctl").arg("daemon-reload"))?;
+    }
+    Ok(path)
+  }
+
+  /// Create the service with `sc.exe` and register the event source of `--log-output eventlog`.
+  fn install_windows(&self, command: &str) -> Result<()> {
+    run(Command::new("sc.exe").args([
+      "create",
+      &self.label,
+      "binPath=",
+      command,
+      "start=",
+      "auto",
+      "DisplayName=",
+      "DNS server",
+    ]))?;
+    run(Command::new("sc.exe").args([
+      "description",
+      &self.label,
+      "DNS server blocking domains and zones",
+    ]))?;
+    // Events reported with the IDs 1 to 1000 are shown as is with the messages of EventCreate.
+    let source = format!(r"{WINDOWS_EVENT_SOURCES}\{EVENT_SOURCE}");
+    run(Command::new("reg.exe").args([
+      "add",
+      &source,
+      "/v",
+      "EventMessageFile",
+      "/t",
+      "REG_EXPAND_SZ",
+      "/d",
+      r"%SystemRoot%\System32\EventCreate.exe",
+      "/f",
+    ]))?;
+    run(Command::new("reg.exe").args([
+      "add",
+      &source,
+      "/v",
+      "TypesSupported",
+      "/t",
+      "REG_DWORD",
+      "/d",
+      "7",
+      "/f",
+    ]))?;
+    run(Command::new("sc.exe").args(["start", &self.label]))
+  }
+
+  fn uninstall_windows(&self) -> Result<()> {
+    if Command::new("sc.exe")
+      .args(["query", &self.label])
+      .output()
+      .map_or(true, |output| !output.status.success())
+    {
+      bail!("the service {} is not installed", self.label);
+    }
+    // Already stopped services cannot be stopped again.
+    let _ = Command::new("sc.exe").args(["stop", &self.label]).status();
+    run(Command::new("sc.exe").args(["delete", &self.label]))?;
+    let source = format!(r"{WINDOWS_EVENT_SOURCES}\{EVENT_SOURCE}");
+    run(Command::new("reg.exe").args(["delete", &source, "/f"]))
+  }
+}
+
+fn run(command: &mut Command) -> Result<()> {
+  let status = command
+    .status()
+    .with_context(|| format!("could not run {:?}", command.get_program()))?;
+  if !status.success() {
+    bail!("{:?} failed with {}", command, status);
+  }
+  Ok(())
+}
+
+fn xml_escape(value: &str) -> String {
+  value
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Argument of `ExecStart=`, quoted when needed. `%` and `$` are escaped so systemd does not
+/// expand them.
+fn systemd_quote(arg: &str) -> String {
+  let escaped = arg.replace('%', "%%").replace('$', "$$");
+  if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+    return escaped;
+  }
+  format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Argument of a Windows command line: quoted when needed, with the backslashes before a quote
+/// doubled, see `CommandLineToArgvW`.
+fn windows_quote(arg: &str) -> String {
+  if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"') {
+    return arg.to_string();
+  }
+  let mut quoted = String::from("\"");
+  let mut backslashes = 0;
+  for c in arg.chars() {
+    match c {
+      '\\' => backslashes += 1,
+      '"' => {
+        quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+        backslashes = 0;
+      }
+      _ => {
+        quoted.push_str(&"\\".repeat(backslashes));
+        backslashes = 0;
+      }
+    }
+    if c != '\\' {
+      quoted.push(c);
+    }
+  }
+  quoted.push_str(&"\\".repeat(backslashes * 2));
+  quoted.push('"');
+  quoted
+}
+
+/// Windows service control: the service control manager starts `service run` and asks it to
+/// stop through a control handler instead of a signal.
+#[cfg(windows)]
+pub mod windows {
+  use anyhow::{bail, Result};
+  use std::ffi::c_void;
+  use std::sync::atomic::{AtomicI32, AtomicIsize, AtomicU32, Ordering};
+  use std::sync::Mutex;
+  use std::time::Duration;
+  use tokio::sync::oneshot;
+  use tracing::error;
+  use windows_sys::core::PWSTR;
+  use windows_sys::Win32::Foundation::{
+    GetLastError, ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT,
+    ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+  };
+  use windows_sys::Win32::System::Services::{
+    RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+    SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+    SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_STATUS,
+    SERVICE_STATUS_CURRENT_STATE, SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW,
+    SERVICE_WIN32_OWN_PROCESS,
+  };
+
+  type Server = Box<dyn FnOnce(oneshot::Receiver<()>) -> i32 + Send>;
+
+  /// The service main function and the control handler are called by the service control
+  /// manager without any context, they share the server through these.
+  static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+  static STOP: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
+  static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+  /// Milliseconds the service control manager waits for the server to stop.
+  static STOP_WAIT_HINT: AtomicU32 = AtomicU32::new(0);
+  static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
+  /// Run `server` as the Windows service the process was started for, until it returns. The
+  /// receiver given to it is notified when the service is stopped, the server has `stop_timeout`
+  /// to shut down. Returns `None` when the process was not started by the service control
+  /// manager, e.g. from a console.
+  pub fn run(
+    stop_timeout: Duration,
+    server: impl FnOnce(oneshot::Receiver<()>) -> i32 + Send + 'static,
+  ) -> Result<Option<i32>> {
+    *SERVER.lock().unwrap() = Some(Box::new(server));
+    STOP_WAIT_HINT.store(
+      u32::try_from(stop_timeout.as_millis()).unwrap_or(u32::MAX),
+      Ordering::Relaxed,
+    );
+    // The name is ignored for the services having their own process.
+    let mut name = [0u16];
+    let table = [
+      SERVICE_TABLE_ENTRYW {
+        lpServiceName: name.as_mut_ptr(),
+        lpServiceProc: Some(service_main),
+      },
+      SERVICE_TABLE_ENTRYW {
+        lpServiceName: std::ptr::null_mut(),
+        lpServiceProc: None,
+      },
+    ];
+    // SAFETY: the table ends with a null entry and outlives the call, which returns once the
+    // service is stopped.
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+      return match unsafe { GetLastError() } {
+        ERROR_FAILED_SERVICE_CONTROLLER_CONNECT => Ok(None),
+        code => bail!(
+          "could not connect to the service control manager: {}",
+          std::io::Error::from_raw_os_error(code as i32)
+        ),
+      };
+    }
+    Ok(Some(EXIT_CODE.load(Ordering::Relaxed)))
+  }
+
+  /// Called by the service control manager on its own thread, the first argument is the name of
+  /// the service.
+  unsafe extern "system" fn service_main(argc: u32, argv: *mut PWSTR) {
+    if argc == 0 {
+      return;
+    }
+    let handle = RegisterServiceCtrlHandlerExW(*argv, Some(control_handler), std::ptr::null());
+    if handle == 0 {
+      error!(
+        "Could not register the service control handler: {}",
+        std::io::Error::last_os_error()
+      );
+      return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::Relaxed);
+    let (stop, stopped) = oneshot::channel();
+    *STOP.lock().unwrap() = Some(stop);
+    set_status(SERVICE_RUNNING, 0);
+    let server = SERVER.lock().unwrap().take();
+    let code = server.map_or(1, |server| server(stopped));
+    EXIT_CODE.store(code, Ordering::Relaxed);
+    set_status(SERVICE_STOPPED, code);
+  }
+
+  unsafe extern "system" fn control_handler(
+    control: u32,
+    _: u32,
+    _: *mut c_void,
+    _: *mut c_void,
+  ) -> u32 {
+    match control {
+      SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+        set_status(SERVICE_STOP_PENDING, 0);
+        if let Some(stop) = STOP.lock().unwrap().take() {
+          let _ = stop.send(());
+        }
+        NO_ERROR
+      }
+      SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+      _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+  }
+
+  fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: i32) {
+    let status = SERVICE_STATUS {
+      dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+      dwCurrentState: state,
+      dwControlsAccepted: match state {
+        SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+        _ => 0,
+      },
+      dwWin32ExitCode: match exit_code {
+        0 => NO_ERROR,
+        _ => ERROR_SERVICE_SPECIFIC_ERROR,
+      },
+      dwServiceSpecificExitCode: exit_code as u32,
+      dwCheckPoint: 0,
+      dwWaitHint: match state {
+        SERVICE_STOP_PENDING => STOP_WAIT_HINT.load(Ordering::Relaxed),
+        _ => 0,
+      },
+    };
+    // SAFETY: the handle was returned by `RegisterServiceCtrlHandlerExW`.
+    if unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::Relaxed), &status) } == 0 {
+      error!(
+        "Could not report the service status: {}",
+        std::io::Error::last_os_error()
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn service() -> Service {
+    Service {
+      label: "dns-server".to_string(),
+      program: "/usr/local/bin/dns-server".into(),
+      args: vec![
+        "--blacklist".to_string(),
+        "/etc/dns server/a&b.txt".to_string(),
+      ],
+    }
+  }
+
+  #[test]
+  fn launchd() {
+    let plist = service().launchd_plist();
+    assert!(plist.contains("<string>/usr/local/bin/dns-server</string>\n    <string>--blacklist</string>\n    <string>/etc/dns server/a&amp;b.txt</string>\n    <string>service</string>\n    <string>run</string>\n"));
+    assert!(plist.contains("<string>/var/log/dns-server.log</string>"));
+  }
+
+  #[test]
+  fn systemd() {
+    let unit = service().systemd_unit();
+    assert!(unit.contains(
+      "ExecStart=/usr/local/bin/dns-server --blacklist \"/etc/dns server/a&b.txt\" service run\n"
+    ));
+    assert_eq!(systemd_quote("50%"), "50%%");
+  }
+
+  #[test]
+  fn windows() {
+    let command = Service {
+      program: r"C:\Program Files\dns-server\dns-server.exe".into(),
+      ..service()
+    }
+    .windows_command();
+    assert_eq!(
+      command,
+      r#""C:\Program Files\dns-server\dns-server.exe" --blacklist "/etc/dns server/a&b.txt" service run"#
+    );
+    assert_eq!(windows_quote(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+    assert_eq!(windows_quote(r"C:\dns\"), r"C:\dns\");
+    assert_eq!(windows_quote(r"C:\my dns\"), r#""C:\my dns\\""#);
+  }
+}
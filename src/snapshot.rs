@@ -0,0 +1,99 @@
+use arc_swap::ArcSwap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Data read on every query and replaced as a whole on reload, like the catalogs or the local
+/// names. The current value is an `Arc` in an `arc_swap::ArcSwap`: a load never takes a lock,
+/// a store swaps the value without waiting for the queries in flight, readers holding the
+/// previous value keep it until they drop it.
+pub struct Snapshot<T> {
+  value: ArcSwap<T>,
+}
+
+impl<T> Snapshot<T> {
+  pub fn new(value: T) -> Self {
+    Self {
+      value: ArcSwap::from_pointee(value),
+    }
+  }
+
+  /// Current value.
+  pub fn load(&self) -> Arc<T> {
+    self.value.load_full()
+  }
+
+  /// Replace the value, readers holding the previous one keep it until they drop it.
+  pub fn store(&self, value: T) {
+    self.value.store(Arc::new(value));
+  }
+
+  /// Replace the value and return the previous one.
+  pub fn swap(&self, value: Arc<T>) -> Arc<T> {
+    self.value.swap(value)
+  }
+}
+
+impl<T: Default> Default for Snapshot<T> {
+  fn default() -> Self {
+    Self::new(T::default())
+  }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Snapshot<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_tuple("Snapshot").field(&self.load()).finish()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn load_and_store() {
+    let snapshot = Snapshot::new(1);
+    let before = snapshot.load();
+    snapshot.store(2);
+    assert_eq!(*before, 1);
+    assert_eq!(*snapshot.load(), 2);
+    assert_eq!(*snapshot.swap(Arc::new(3)), 2);
+    assert_eq!(*snapshot.load(), 3);
+  }
+
+  #[test]
+  fn concurrent_loads() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Value(usize);
+    impl Drop for Value {
+      fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+
+    let snapshot = Arc::new(Snapshot::new(Value(0)));
+    let readers: Vec<_> = (0..4)
+      .map(|_| {
+        let snapshot = snapshot.clone();
+        std::thread::spawn(move || {
+          let mut last = 0;
+          for _ in 0..10_000 {
+            let value = snapshot.load().0;
+            assert!(value >= last);
+            last = value;
+          }
+        })
+      })
+      .collect();
+    for value in 1..=1000 {
+      snapshot.store(Value(value));
+    }
+    for reader in readers {
+      reader.join().unwrap();
+    }
+    assert_eq!(snapshot.load().0, 1000);
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 1000);
+    drop(snapshot);
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 1001);
+  }
+}
@@ -0,0 +1,132 @@
+use crate::handler::{DnsHandler, ResponseBuffer};
+use crate::ip::IpRangeVec;
+use crate::proxy_protocol;
+use anyhow::Result;
+use hickory_rustls::ServerConfig;
+use hickory_server::{
+  authority::MessageRequest,
+  proto::serialize::binary::BinDecodable,
+  server::{Protocol, Request, RequestHandler},
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_graceful::WeakShutdownGuard;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error};
+
+/// Responses waiting to be written on a connection.
+const PENDING_RESPONSES: usize = 32;
+
+/// DNS over TCP listener (RFC 7766), or DNS over TLS (RFC 7858) with a TLS configuration. A
+/// connection is closed when it stays idle for `timeout`, its queries are answered concurrently.
+///
+/// Connections from the `proxies` networks start with a PROXY protocol header, their queries are
+/// answered as coming from the client address it carries.
+pub fn serve(
+  listener: TcpListener,
+  tls: Option<Arc<ServerConfig>>,
+  handler: DnsHandler,
+  proxies: Arc<IpRangeVec>,
+  timeout: Duration,
+  shutdown: WeakShutdownGuard,
+) {
+  let acceptor = tls.map(TlsAcceptor::from);
+  let handler = Arc::new(handler);
+  tokio::spawn(async move {
+    loop {
+      let (stream, src) = tokio::select! {
+        accepted = listener.accept() => match accepted {
+          Ok(accepted) => accepted,
+          Err(err) => {
+            error!("Could not accept TCP connection: {}", err);
+            continue;
+          }
+        },
+        _ = shutdown.cancelled() => return,
+      };
+      let acceptor = acceptor.clone();
+      let handler = handler.clone();
+      let proxies = proxies.clone();
+      tokio::spawn(async move {
+        let connection = handle_connection(stream, src, acceptor, handler, &proxies, timeout);
+        if let Err(err) = connection.await {
+          debug!("TCP connection of {} closed: {}", src, err);
+        }
+      });
+    }
+  });
+}
+
+async fn handle_connection(
+  mut stream: TcpStream,
+  mut src: SocketAddr,
+  acceptor: Option<TlsAcceptor>,
+  handler: Arc<DnsHandler>,
+  proxies: &IpRangeVec,
+  idle: Duration,
+) -> Result<()> {
+  if proxies.contains_sock_addr(src) {
+    if let Some(client) = timeout(idle, proxy_protocol::read_header(&mut stream)).await?? {
+      src = client;
+    }
+  }
+  match acceptor {
+    Some(acceptor) => {
+      let stream = timeout(idle, acceptor.accept(stream)).await??;
+      answer(stream, src, Protocol::Tls, handler, idle).await
+    }
+    None => answer(stream, src, Protocol::Tcp, handler, idle).await,
+  }
+}
+
+/// Answer the length-prefixed messages of a connection.
+async fn answer<S: AsyncRead + AsyncWrite + Send + 'static>(
+  stream: S,
+  src: SocketAddr,
+  protocol: Protocol,
+  handler: Arc<DnsHandler>,
+  idle: Duration,
+) -> Result<()> {
+  let (mut reader, mut writer) = tokio::io::split(stream);
+  let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(PENDING_RESPONSES);
+  let writing = tokio::spawn(async move {
+    while let Some(response) = receiver.recv().await {
+      let mut message = (response.len() as u16).to_be_bytes().to_vec();
+      message.extend(response);
+      writer.write_all(&message).await?;
+    }
+    writer.shutdown().await
+  });
+
+  // The connection is closed by the client or after being idle.
+  while let Ok(Ok(length)) = timeout(idle, reader.read_u16()).await {
+    let mut message = vec![0; length as usize];
+    timeout(idle, reader.read_exact(&mut message)).await??;
+    let request = match MessageRequest::from_bytes(&message) {
+      Ok(request) => request,
+      Err(err) => {
+        debug!("Invalid message from {}: {}", src, err);
+        break;
+      }
+    };
+    let handler = handler.clone();
+    let sender = sender.clone();
+    tokio::spawn(async move {
+      let request = Request::new(request, src, protocol);
+      let buffer = ResponseBuffer::default();
+      handler.handle_request(&request, buffer.clone()).await;
+      if let Some(response) = buffer.take() {
+        let _ = sender.send(response).await;
+      }
+    });
+  }
+  // The responses of the queries in flight are still written.
+  drop(sender);
+  writing.await??;
+  Ok(())
+}
@@ -0,0 +1,152 @@
+use anyhow::Result;
+#[cfg(unix)]
+use anyhow::{bail, Context};
+#[cfg(unix)]
+use socket2::{Socket, Type};
+#[cfg(unix)]
+use std::env;
+use std::net::{TcpListener, UdpSocket};
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, RawFd};
+
+/// First file descriptor passed by systemd, see `sd_listen_fds(3)`.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets bound by systemd and passed to the server with socket activation. They are used
+/// instead of binding the listen addresses, so the server can run unprivileged on port 53 or be
+/// started on the first query.
+///
+/// A socket is used by the listener named by its `FileDescriptorName=` (`udp`, `tcp`, `tls` or
+/// `h2`), unnamed datagram sockets are UDP listeners and unnamed stream sockets TCP listeners.
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+  pub udp: Vec<UdpSocket>,
+  pub tcp: Vec<TcpListener>,
+  pub tls: Vec<TcpListener>,
+  pub h2: Vec<TcpListener>,
+}
+
+impl ActivatedSockets {
+  /// Take the sockets passed with `LISTEN_FDS` and `LISTEN_FDNAMES`, none when the process was
+  /// not socket activated. The variables are removed so children do not inherit them, this must
+  /// be called before starting other threads.
+  #[cfg(unix)]
+  pub fn from_env() -> Result<Self> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+      return Ok(Self::default());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+      return Ok(Self::default());
+    }
+    let count: RawFd = fds
+      .parse()
+      .with_context(|| format!("invalid LISTEN_FDS {fds}"))?;
+    let names: Vec<&str> = names
+      .as_deref()
+      .map_or(vec![], |names| names.split(':').collect());
+
+    let mut sockets = Self::default();
+    for (index, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+      // Safety: systemd passes these descriptors to this process only, they are owned from now.
+      let socket = unsafe { Socket::from_raw_fd(fd) };
+      sockets.add(socket, names.get(index).copied())?;
+    }
+    Ok(sockets)
+  }
+
+  /// Socket activation is only available on unix.
+  #[cfg(not(unix))]
+  pub fn from_env() -> Result<Self> {
+    Ok(Self::default())
+  }
+
+  #[cfg(unix)]
+  fn add(&mut self, socket: Socket, name: Option<&str>) -> Result<()> {
+    let kind = socket
+      .r#type()
+      .context("passed file descriptor is not a socket")?;
+    socket.set_cloexec(true)?;
+    socket.set_nonblocking(true)?;
+    match Listener::new(name, kind)? {
+      Listener::Udp => self.udp.push(socket.into()),
+      Listener::Tcp => self.tcp.push(socket.into()),
+      Listener::Tls => self.tls.push(socket.into()),
+      Listener::H2 => self.h2.push(socket.into()),
+    }
+    Ok(())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.udp.is_empty() && self.tcp.is_empty() && self.tls.is_empty() && self.h2.is_empty()
+  }
+}
+
+#[cfg(unix)]
+#[derive(Debug, PartialEq)]
+enum Listener {
+  Udp,
+  Tcp,
+  Tls,
+  H2,
+}
+
+#[cfg(unix)]
+impl Listener {
+  /// Listener of a socket from its name and type.
+  fn new(name: Option<&str>, kind: Type) -> Result<Self> {
+    let listener = match name {
+      Some("udp") => Listener::Udp,
+      Some("tcp") => Listener::Tcp,
+      Some("tls") => Listener::Tls,
+      Some("h2") => Listener::H2,
+      _ if kind == Type::DGRAM => Listener::Udp,
+      _ => Listener::Tcp,
+    };
+    let expected = match listener {
+      Listener::Udp => Type::DGRAM,
+      _ => Type::STREAM,
+    };
+    if kind != expected {
+      bail!(
+        "socket {} passed by systemd should be a {} socket",
+        name.unwrap_or("unknown"),
+        if expected == Type::DGRAM {
+          "datagram"
+        } else {
+          "stream"
+        }
+      );
+    }
+    Ok(listener)
+  }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+  use super::*;
+
+  #[test]
+  fn listeners() {
+    assert_eq!(Listener::new(None, Type::DGRAM).unwrap(), Listener::Udp);
+    let unnamed = Listener::new(Some("dns.socket"), Type::STREAM).unwrap();
+    assert_eq!(unnamed, Listener::Tcp);
+    assert_eq!(
+      Listener::new(Some("tls"), Type::STREAM).unwrap(),
+      Listener::Tls
+    );
+    assert_eq!(
+      Listener::new(Some("h2"), Type::STREAM).unwrap(),
+      Listener::H2
+    );
+    assert!(Listener::new(Some("h2"), Type::DGRAM).is_err());
+    assert!(Listener::new(Some("udp"), Type::STREAM).is_err());
+  }
+}
@@ -0,0 +1,225 @@
+use crate::snapshot::Snapshot;
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_rustls::{
+  server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert},
+  sign::{any_supported_type, CertifiedKey},
+  version::{TLS12, TLS13},
+  Certificate, PrivateKey, RootCertStore, ServerConfig, SupportedCipherSuite,
+  SupportedProtocolVersion, ALL_CIPHER_SUITES,
+};
+use hickory_server::proto::rustls::tls_server::{read_cert, read_key};
+use rustls::crypto::aws_lc_rs;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::sign::CertifiedKey as CertifiedKey23;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// TLS options of the TLS and H2 listeners.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+  /// Certificate authorities (PEM) of the client certificates. When set, clients must present a
+  /// certificate signed by one of them.
+  pub client_ca: Option<PathBuf>,
+  /// Oldest TLS version accepted.
+  pub min_version: TlsVersion,
+  /// Names of the cipher suites allowed (e.g. `TLS13_AES_256_GCM_SHA384`), all the suites of
+  /// rustls when empty.
+  pub cipher_suites: Vec<String>,
+}
+
+/// Version of the TLS protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum TlsVersion {
+  #[default]
+  Tls12,
+  Tls13,
+}
+
+impl FromStr for TlsVersion {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "1.2" => Ok(TlsVersion::Tls12),
+      "1.3" => Ok(TlsVersion::Tls13),
+      _ => bail!("unknown TLS version `{s}`, expected 1.2 or 1.3"),
+    }
+  }
+}
+
+impl fmt::Display for TlsVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TlsVersion::Tls12 => write!(f, "1.2"),
+      TlsVersion::Tls13 => write!(f, "1.3"),
+    }
+  }
+}
+
+impl Serialize for TlsVersion {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for TlsVersion {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    TlsVersion::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Certificate of the TLS and H2 listeners. It can be replaced while they are running, new
+/// connections then get the new one.
+#[derive(Default)]
+pub struct CertificateStore(Snapshot<Option<Arc<CertifiedKey>>>);
+
+impl CertificateStore {
+  /// Read a certificate chain and its private key (PEM) and use them for the next connections.
+  pub fn load(&self, certificate: &Path, private_key: &Path) -> Result<()> {
+    let certs = read_cert(certificate).map_err(|err| {
+      anyhow!(
+        "could not read certificate {}: {}",
+        certificate.display(),
+        err
+      )
+    })?;
+    if certs.is_empty() {
+      bail!("no certificate found in {}", certificate.display());
+    }
+    let key = read_key(private_key).map_err(|err| {
+      anyhow!(
+        "could not read private key {}: {}",
+        private_key.display(),
+        err
+      )
+    })?;
+    keys_match(&certs, &key).with_context(|| {
+      format!(
+        "the private key {} does not match the certificate {}",
+        private_key.display(),
+        certificate.display()
+      )
+    })?;
+    let key = any_supported_type(&key)
+      .with_context(|| format!("unsupported private key {}", private_key.display()))?;
+    self.0.store(Some(Arc::new(CertifiedKey::new(certs, key))));
+    Ok(())
+  }
+
+  /// Whether a certificate was loaded, handshakes fail until then.
+  pub fn is_loaded(&self) -> bool {
+    self.0.load().is_some()
+  }
+}
+
+impl ResolvesServerCert for CertificateStore {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    self.0.load().as_ref().clone()
+  }
+}
+
+/// Check that the private key is the one of the first certificate, rustls 0.21 does not.
+fn keys_match(certs: &[Certificate], key: &PrivateKey) -> Result<()> {
+  let chain = certs
+    .iter()
+    .map(|cert| CertificateDer::from(cert.0.clone()))
+    .collect();
+  let key = PrivateKeyDer::try_from(key.0.clone()).map_err(|err| anyhow!(err))?;
+  CertifiedKey23::from_der(chain, key, &aws_lc_rs::default_provider())?;
+  Ok(())
+}
+
+/// TLS configuration of a listener using the certificate of `certificates`, announcing the
+/// `alpn` protocols.
+pub fn server_config(
+  certificates: Arc<CertificateStore>,
+  options: &TlsOptions,
+  alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>> {
+  let versions: &[&'static SupportedProtocolVersion] = match options.min_version {
+    TlsVersion::Tls12 => &[&TLS13, &TLS12],
+    TlsVersion::Tls13 => &[&TLS13],
+  };
+  let builder = ServerConfig::builder()
+    .with_cipher_suites(&cipher_suites(&options.cipher_suites)?)
+    .with_safe_default_kx_groups()
+    .with_protocol_versions(versions)
+    .context("no cipher suite of the allowed TLS versions")?;
+  let builder = match &options.client_ca {
+    Some(ca) => {
+      builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_roots(ca)?).boxed())
+    }
+    None => builder.with_no_client_auth(),
+  };
+  let mut config = builder.with_cert_resolver(certificates);
+  config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+  Ok(Arc::new(config))
+}
+
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>> {
+  if names.is_empty() {
+    return Ok(ALL_CIPHER_SUITES.to_vec());
+  }
+  names
+    .iter()
+    .map(|name| {
+      ALL_CIPHER_SUITES
+        .iter()
+        .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+        .copied()
+        .with_context(|| {
+          let names: Vec<String> = ALL_CIPHER_SUITES
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect();
+          format!(
+            "unknown cipher suite `{}`, expected one of {}",
+            name,
+            names.join(", ")
+          )
+        })
+    })
+    .collect()
+}
+
+fn client_roots(ca: &Path) -> Result<RootCertStore> {
+  let certs = read_cert(ca).map_err(|err| anyhow!("could not read {}: {}", ca.display(), err))?;
+  if certs.is_empty() {
+    bail!("no certificate found in {}", ca.display());
+  }
+  let mut roots = RootCertStore::empty();
+  for cert in certs.iter() {
+    roots
+      .add(cert)
+      .with_context(|| format!("invalid CA certificate in {}", ca.display()))?;
+  }
+  Ok(roots)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn versions_and_cipher_suites() {
+    let options = TlsOptions {
+      min_version: TlsVersion::from_str("1.3").unwrap(),
+      cipher_suites: vec!["tls13_aes_256_gcm_sha384".to_string()],
+      ..Default::default()
+    };
+    assert!(server_config(Arc::default(), &options, &[]).is_ok());
+
+    let options = TlsOptions {
+      min_version: TlsVersion::Tls13,
+      cipher_suites: vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".to_string()],
+      ..Default::default()
+    };
+    assert!(server_config(Arc::default(), &options, &[]).is_err());
+    assert!(cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5".to_string()]).is_err());
+    assert!(TlsVersion::from_str("1.1").is_err());
+  }
+}
@@ -0,0 +1,583 @@
+//! Transaction signatures (TSIG, RFC 8945) authenticating zone transfers and dynamic updates with
+//! a key shared with the other server.
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hickory_server::{
+  authority::MessageRequest,
+  proto::{
+    error::ProtoResult,
+    op::{Header, Query},
+    rr::{rdata::NULL, DNSClass, Name, RData, Record, RecordType},
+    serialize::binary::{BinDecodable, BinDecoder, BinEncodable, BinEncoder},
+  },
+};
+use ring::hmac;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Allowed difference between the clocks of the servers, in seconds.
+const FUDGE: u16 = 300;
+
+/// Unsigned messages allowed between two signed ones of a transfer (RFC 8945 section 5.3.1).
+const MAX_UNSIGNED: usize = 99;
+
+/// TSIG error codes of RFC 8945.
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+/// HMAC algorithm of a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+  HmacSha1,
+  HmacSha256,
+  HmacSha384,
+  HmacSha512,
+}
+
+impl Algorithm {
+  fn hmac(self) -> hmac::Algorithm {
+    match self {
+      Algorithm::HmacSha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+      Algorithm::HmacSha256 => hmac::HMAC_SHA256,
+      Algorithm::HmacSha384 => hmac::HMAC_SHA384,
+      Algorithm::HmacSha512 => hmac::HMAC_SHA512,
+    }
+  }
+
+  /// Name of the algorithm in the TSIG records.
+  fn name(self) -> Name {
+    Name::from_ascii(format!("{self}.")).unwrap()
+  }
+}
+
+impl FromStr for Algorithm {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim_end_matches('.').to_lowercase().as_str() {
+      "hmac-sha1" => Ok(Algorithm::HmacSha1),
+      "hmac-sha256" => Ok(Algorithm::HmacSha256),
+      "hmac-sha384" => Ok(Algorithm::HmacSha384),
+      "hmac-sha512" => Ok(Algorithm::HmacSha512),
+      _ => bail!("unsupported TSIG algorithm `{s}`, expected hmac-sha1, hmac-sha256, hmac-sha384 or hmac-sha512"),
+    }
+  }
+}
+
+impl fmt::Display for Algorithm {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Algorithm::HmacSha1 => "hmac-sha1",
+      Algorithm::HmacSha256 => "hmac-sha256",
+      Algorithm::HmacSha384 => "hmac-sha384",
+      Algorithm::HmacSha512 => "hmac-sha512",
+    };
+    f.write_str(name)
+  }
+}
+
+/// Key shared with another server, written `name:algorithm:secret` with the secret in base64
+/// like in the `key` statements of BIND (e.g. `transfer-key:hmac-sha256:c2VjcmV0`).
+#[derive(Clone, PartialEq)]
+pub struct TsigKey {
+  pub name: Name,
+  pub algorithm: Algorithm,
+  secret: Vec<u8>,
+}
+
+impl TsigKey {
+  pub fn new(name: Name, algorithm: Algorithm, secret: Vec<u8>) -> Self {
+    Self {
+      name,
+      algorithm,
+      secret,
+    }
+  }
+
+  /// Signature of `message`, chained to the MAC of the request or of the previous message.
+  /// Messages after the first one of a transfer only sign the timers.
+  pub fn sign(&self, message: &[u8], previous: Option<&[u8]>, timers_only: bool) -> Tsig {
+    let mut tsig = Tsig {
+      key: self.name.clone(),
+      algorithm: self.algorithm.name(),
+      time: now(),
+      fudge: FUDGE,
+      mac: vec![],
+      original_id: u16::from_be_bytes([message[0], message[1]]),
+      error: 0,
+      other: vec![],
+    };
+    let key = hmac::Key::new(self.algorithm.hmac(), &self.secret);
+    let data = digest(previous, message, &tsig, timers_only);
+    tsig.mac = hmac::sign(&key, &data).as_ref().to_vec();
+    tsig
+  }
+
+  /// Check the signature of `message`, given without its TSIG record. The error is the TSIG
+  /// error code to answer.
+  pub fn verify(
+    &self,
+    message: &[u8],
+    tsig: &Tsig,
+    previous: Option<&[u8]>,
+    timers_only: bool,
+  ) -> Result<(), u16> {
+    if tsig.key != self.name || tsig.algorithm != self.algorithm.name() {
+      return Err(BADKEY);
+    }
+    // The ID may have been changed by a forwarder.
+    let mut message = message.to_vec();
+    message[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+    let key = hmac::Key::new(self.algorithm.hmac(), &self.secret);
+    let data = digest(previous, &message, tsig, timers_only);
+    hmac::verify(&key, &data, &tsig.mac).map_err(|_| BADSIG)?;
+    if now().abs_diff(tsig.time) > tsig.fudge as u64 {
+      return Err(BADTIME);
+    }
+    Ok(())
+  }
+}
+
+// The secret is not printed in the logs.
+impl fmt::Debug for TsigKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.name, self.algorithm)
+  }
+}
+
+impl FromStr for TsigKey {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(name), Some(algorithm), Some(secret)) = (parts.next(), parts.next(), parts.next())
+    else {
+      bail!(
+        "expected name:algorithm:secret, got `{}`",
+        s.split(':').next().unwrap_or("")
+      );
+    };
+    let name = Name::from_str(name).with_context(|| format!("invalid key name `{name}`"))?;
+    let secret = STANDARD
+      .decode(secret.trim())
+      .with_context(|| format!("invalid base64 secret of the key {name}"))?;
+    Ok(Self::new(name, algorithm.parse()?, secret))
+  }
+}
+
+impl fmt::Display for TsigKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{}:{}:{}",
+      self.name,
+      self.algorithm,
+      STANDARD.encode(&self.secret)
+    )
+  }
+}
+
+impl Serialize for TsigKey {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for TsigKey {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    TsigKey::from_str(&value).map_err(de::Error::custom)
+  }
+}
+
+/// Key of `name` among `keys`.
+pub fn find<'a>(keys: &'a [TsigKey], name: &Name) -> Result<&'a TsigKey> {
+  keys
+    .iter()
+    .find(|key| &key.name == name)
+    .ok_or_else(|| anyhow!("unknown TSIG key {name}, it must be given with --tsig-key"))
+}
+
+/// TSIG record of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tsig {
+  pub key: Name,
+  pub algorithm: Name,
+  pub time: u64,
+  pub fudge: u16,
+  pub mac: Vec<u8>,
+  pub original_id: u16,
+  pub error: u16,
+  pub other: Vec<u8>,
+}
+
+impl Tsig {
+  fn read(key: Name, rdata: &[u8]) -> Result<Self> {
+    let mut decoder = BinDecoder::new(rdata);
+    let mut read = || -> Result<Self, hickory_server::proto::error::ProtoError> {
+      let algorithm = Name::read(&mut decoder)?;
+      let high = decoder.read_u16()?.unverified() as u64;
+      let time = high << 32 | decoder.read_u32()?.unverified() as u64;
+      let fudge = decoder.read_u16()?.unverified();
+      let size = decoder.read_u16()?.unverified() as usize;
+      let mac = decoder.read_vec(size)?.unverified();
+      let original_id = decoder.read_u16()?.unverified();
+      let error = decoder.read_u16()?.unverified();
+      let size = decoder.read_u16()?.unverified() as usize;
+      let other = decoder.read_vec(size)?.unverified();
+      Ok(Self {
+        key: key.clone(),
+        algorithm,
+        time,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+      })
+    };
+    read().context("invalid TSIG record")
+  }
+
+  /// Signature answering an invalid one, with the error and without MAC.
+  pub fn error(&self, error: u16) -> Self {
+    Self {
+      mac: vec![],
+      error,
+      other: vec![],
+      ..self.clone()
+    }
+  }
+
+  fn rdata(&self) -> Vec<u8> {
+    let mut rdata = canonical(&self.algorithm);
+    rdata.extend_from_slice(&self.time.to_be_bytes()[2..]);
+    rdata.extend_from_slice(&self.fudge.to_be_bytes());
+    rdata.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&self.mac);
+    rdata.extend_from_slice(&self.original_id.to_be_bytes());
+    rdata.extend_from_slice(&self.error.to_be_bytes());
+    rdata.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&self.other);
+    rdata
+  }
+
+  pub fn record(&self) -> Record {
+    let rdata = RData::Unknown {
+      code: RecordType::TSIG,
+      rdata: NULL::with(self.rdata()),
+    };
+    let mut record = Record::from_rdata(self.key.clone(), 0, rdata);
+    record.set_dns_class(DNSClass::ANY);
+    record
+  }
+
+  /// Append the TSIG record to a message.
+  pub fn append_to(&self, message: &mut Vec<u8>) {
+    let additionals = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additionals.to_be_bytes());
+    message.extend(canonical(&self.key));
+    message.extend_from_slice(&u16::from(RecordType::TSIG).to_be_bytes());
+    message.extend_from_slice(&u16::from(DNSClass::ANY).to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes());
+    let rdata = self.rdata();
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend(rdata);
+  }
+}
+
+/// Name of a TSIG error code.
+pub fn error_name(error: u16) -> String {
+  match error {
+    BADSIG => "BADSIG".to_string(),
+    BADKEY => "BADKEY".to_string(),
+    BADTIME => "BADTIME".to_string(),
+    error => error.to_string(),
+  }
+}
+
+/// Message as received without its TSIG record, the last one, and this record.
+pub fn split(message: &[u8]) -> Result<(Vec<u8>, Option<Tsig>)> {
+  let mut decoder = BinDecoder::new(message);
+  let header = Header::read(&mut decoder)?;
+  if header.additional_count() == 0 {
+    return Ok((message.to_vec(), None));
+  }
+  for _ in 0..header.query_count() {
+    Query::read(&mut decoder)?;
+  }
+  // The counts come from the peer, their sum does not fit in a u16.
+  let records = header.answer_count() as usize
+    + header.name_server_count() as usize
+    + header.additional_count() as usize
+    - 1;
+  for _ in 0..records {
+    Record::read(&mut decoder)?;
+  }
+  let start = decoder.index();
+  let key = Name::read(&mut decoder)?;
+  if decoder.read_u16()?.unverified() != u16::from(RecordType::TSIG) {
+    return Ok((message.to_vec(), None));
+  }
+  // Class and TTL.
+  decoder.read_u16()?;
+  decoder.read_u32()?;
+  let length = decoder.read_u16()?.unverified() as usize;
+  let tsig = Tsig::read(key, decoder.read_slice(length)?.unverified())?;
+  let mut unsigned = message[..start].to_vec();
+  unsigned[10..12].copy_from_slice(&(header.additional_count() - 1).to_be_bytes());
+  Ok((unsigned, Some(tsig)))
+}
+
+/// Wire format of a decoded update without its TSIG record, and this record. The received bytes
+/// are not kept by the server, so the records are encoded again with the names compressed like
+/// the signers do: SRV targets are not compressed (RFC 2782). Updates of signers compressing the
+/// names differently are refused with BADSIG.
+pub fn split_request(request: &MessageRequest) -> Result<(Vec<u8>, Option<Tsig>)> {
+  let (signatures, additionals): (Vec<&Record>, Vec<&Record>) = request
+    .additionals()
+    .iter()
+    .chain(request.sig0())
+    .partition(|record| record.record_type() == RecordType::TSIG);
+  let edns = request.edns().map(Record::from);
+  let records: Vec<&Record> = request
+    .answers()
+    .iter()
+    .chain(request.name_servers())
+    .chain(additionals)
+    .chain(edns.as_ref())
+    .chain(signatures.last().copied())
+    .collect();
+  let mut header = *request.header();
+  header
+    .set_query_count(1)
+    .set_answer_count(request.answers().len() as u16)
+    .set_name_server_count(request.name_servers().len() as u16)
+    .set_additional_count(
+      (records.len() - request.answers().len() - request.name_servers().len()) as u16,
+    );
+  let mut message = vec![];
+  let mut encoder = BinEncoder::new(&mut message);
+  header.emit(&mut encoder)?;
+  request.query().original().emit(&mut encoder)?;
+  for record in records {
+    emit_record(&mut encoder, record)?;
+  }
+  split(&message)
+}
+
+fn emit_record(encoder: &mut BinEncoder<'_>, record: &Record) -> ProtoResult<()> {
+  let Some(RData::SRV(srv)) = record.data() else {
+    return record.emit(encoder);
+  };
+  record.name().emit(encoder)?;
+  record.record_type().emit(encoder)?;
+  record.dns_class().emit(encoder)?;
+  encoder.emit_u32(record.ttl())?;
+  let place = encoder.place::<u16>()?;
+  encoder.emit_u16(srv.priority())?;
+  encoder.emit_u16(srv.weight())?;
+  encoder.emit_u16(srv.port())?;
+  srv.target().emit_as_canonical(encoder, true)?;
+  let length = encoder.len_since_place(&place);
+  place.replace(encoder, length as u16)
+}
+
+/// Checks the signatures of the messages of a transfer: every message signs the previous ones
+/// since the last signed message, the last one must be signed.
+pub struct TransferVerifier<'a> {
+  key: &'a TsigKey,
+  previous: Vec<u8>,
+  first: bool,
+  unsigned: Vec<u8>,
+  count: usize,
+}
+
+impl<'a> TransferVerifier<'a> {
+  /// Verifier of the responses to a request signed with `request`.
+  pub fn new(key: &'a TsigKey, request: &Tsig) -> Self {
+    Self {
+      key,
+      previous: request.mac.clone(),
+      first: true,
+      unsigned: vec![],
+      count: 0,
+    }
+  }
+
+  /// Check the next message of the transfer.
+  pub fn verify(&mut self, message: &[u8]) -> Result<()> {
+    let (message, tsig) = split(message)?;
+    let Some(tsig) = tsig else {
+      if self.first {
+        bail!("the response is not signed with the key {}", self.key.name);
+      }
+      self.count += 1;
+      if self.count > MAX_UNSIGNED {
+        bail!("too many unsigned messages in the transfer");
+      }
+      self.unsigned.extend(message);
+      return Ok(());
+    };
+    if tsig.error != 0 {
+      bail!(
+        "the primary rejected the key {}: {}",
+        self.key.name,
+        error_name(tsig.error)
+      );
+    }
+    self.unsigned.extend(message);
+    self
+      .key
+      .verify(&self.unsigned, &tsig, Some(&self.previous), !self.first)
+      .map_err(|error| anyhow!("invalid signature of the response: {}", error_name(error)))?;
+    self.previous = tsig.mac;
+    self.first = false;
+    self.unsigned.clear();
+    self.count = 0;
+    Ok(())
+  }
+
+  /// Whether the last message was signed.
+  pub fn signed(&self) -> bool {
+    !self.first && self.count == 0
+  }
+}
+
+/// Data signed by the MAC (RFC 8945 section 4.3).
+fn digest(previous: Option<&[u8]>, message: &[u8], tsig: &Tsig, timers_only: bool) -> Vec<u8> {
+  let mut data = vec![];
+  if let Some(previous) = previous {
+    data.extend_from_slice(&(previous.len() as u16).to_be_bytes());
+    data.extend_from_slice(previous);
+  }
+  data.extend_from_slice(message);
+  if !timers_only {
+    data.extend(canonical(&tsig.key));
+    data.extend_from_slice(&u16::from(DNSClass::ANY).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend(canonical(&tsig.algorithm));
+  }
+  data.extend_from_slice(&tsig.time.to_be_bytes()[2..]);
+  data.extend_from_slice(&tsig.fudge.to_be_bytes());
+  if !timers_only {
+    data.extend_from_slice(&tsig.error.to_be_bytes());
+    data.extend_from_slice(&(tsig.other.len() as u16).to_be_bytes());
+    data.extend_from_slice(&tsig.other);
+  }
+  data
+}
+
+/// Uncompressed lowercase wire format of a name.
+fn canonical(name: &Name) -> Vec<u8> {
+  let mut bytes = vec![];
+  for label in name.to_lowercase().iter() {
+    bytes.push(label.len() as u8);
+    bytes.extend_from_slice(label);
+  }
+  bytes.push(0);
+  bytes
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::op::{Message, MessageType, OpCode};
+
+  fn query() -> Vec<u8> {
+    let mut message = Message::new();
+    message
+      .set_id(4242)
+      .set_message_type(MessageType::Query)
+      .set_op_code(OpCode::Query)
+      .add_query(Query::query(
+        Name::from_str("example.lan.").unwrap(),
+        RecordType::AXFR,
+      ));
+    message.to_vec().unwrap()
+  }
+
+  #[test]
+  fn parse_key() {
+    let key = TsigKey::from_str("transfer-key:hmac-sha256:c2VjcmV0").unwrap();
+    assert_eq!(key.name, Name::from_str("transfer-key").unwrap());
+    assert_eq!(key.algorithm, Algorithm::HmacSha256);
+    assert_eq!(key.secret, b"secret");
+    assert_eq!(key.to_string(), "transfer-key:hmac-sha256:c2VjcmV0");
+    assert!(TsigKey::from_str("transfer-key:hmac-md5:c2VjcmV0").is_err());
+    assert!(TsigKey::from_str("transfer-key:hmac-sha256:not base64").is_err());
+    assert!(TsigKey::from_str("transfer-key").is_err());
+  }
+
+  #[test]
+  fn sign_and_verify() {
+    let key = TsigKey::from_str("transfer-key:hmac-sha256:c2VjcmV0").unwrap();
+    let request = query();
+    let tsig = key.sign(&request, None, false);
+    let mut signed = request.clone();
+    tsig.append_to(&mut signed);
+    assert!(Message::from_vec(&signed).is_ok());
+
+    let (unsigned, received) = split(&signed).unwrap();
+    assert_eq!(unsigned, request);
+    let received = received.unwrap();
+    assert_eq!(received, tsig);
+    assert_eq!(key.verify(&unsigned, &received, None, false), Ok(()));
+
+    let other = TsigKey::from_str("transfer-key:hmac-sha256:b3RoZXI=").unwrap();
+    assert_eq!(other.verify(&unsigned, &received, None, false), Err(BADSIG));
+    let other = TsigKey::from_str("other-key:hmac-sha256:c2VjcmV0").unwrap();
+    assert_eq!(other.verify(&unsigned, &received, None, false), Err(BADKEY));
+    let mut late = received.clone();
+    late.time -= 3600;
+    assert_eq!(key.verify(&unsigned, &late, None, false), Err(BADSIG));
+
+    // A transfer of three messages, the second one is not signed.
+    let mut verifier = TransferVerifier::new(&key, &tsig);
+    let first = key.sign(&request, Some(&tsig.mac), false);
+    let mut message = request.clone();
+    first.append_to(&mut message);
+    verifier.verify(&message).unwrap();
+    assert!(verifier.signed());
+    verifier.verify(&request).unwrap();
+    assert!(!verifier.signed());
+    let both = [request.clone(), request.clone()].concat();
+    let last = key.sign(&both, Some(&first.mac), true);
+    let mut message = request.clone();
+    last.append_to(&mut message);
+    verifier.verify(&message).unwrap();
+    assert!(verifier.signed());
+    assert!(TransferVerifier::new(&key, &tsig).verify(&request).is_err());
+
+    // Counts of a truncated message adding up to more than a u16.
+    let mut truncated = request.clone();
+    truncated[6..12].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 1]);
+    assert!(split(&truncated).is_err());
+  }
+
+  #[test]
+  fn update_wire_format() {
+    let key = TsigKey::from_str("update-key:hmac-sha256:c2VjcmV0").unwrap();
+    // Update of lan. adding new.lan. and an SRV record, names compressed like nsupdate does.
+    let mut update = vec![0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 0];
+    update.extend(b"\x03lan\x00\x00\x06\x00\x01");
+    update.extend(b"\x03new\xc0\x0c\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\xc0\xa8\x01\x32");
+    update.extend(b"\x05_ldap\x04_tcp\xc0\x0c\x00\x21\x00\x01\x00\x00\x01\x2c\x00\x10");
+    update.extend(b"\x00\x00\x00\x05\x01\x85\x04ldap\x03lan\x00");
+    let tsig = key.sign(&update, None, false);
+    let mut signed = update.clone();
+    tsig.append_to(&mut signed);
+    let request = MessageRequest::from_bytes(&signed).unwrap();
+    assert_eq!(split_request(&request).unwrap(), (update, Some(tsig)));
+  }
+}
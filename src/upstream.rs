@@ -0,0 +1,688 @@
+use crate::cache::AnswerCache;
+use crate::client::bind::{self, Binding};
+use crate::client::ecs::{Ecs, EcsConnector};
+use crate::client::proxy::{Proxy, ProxyRuntimeProvider};
+use crate::client::{tls, ClientType};
+use crate::events::{self, Stats, UpstreamStats};
+use crate::recursor::Recursor;
+use anyhow::bail;
+use futures_util::future::{select_ok, FutureExt};
+use hickory_rustls::client::ServerCertVerifier;
+use hickory_server::{
+  authority::{LookupError, LookupOptions},
+  proto::{
+    op::{Query, ResponseCode},
+    rr::{LowerName, Name, RecordType},
+  },
+  resolver::{
+    config::{NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    lookup::Lookup as ResolverLookup,
+    AsyncResolver,
+  },
+  server::RequestInfo,
+  store::forwarder::ForwardLookup,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Latency added to the average for a timeout or an error, so the fastest strategy moves away
+/// from a failing upstream.
+const FAILURE_LATENCY: Duration = Duration::from_secs(2);
+
+type Resolver = AsyncResolver<EcsConnector>;
+
+/// How queries are distributed across the healthy upstreams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+  /// The first upstream, the others are only used when it is unhealthy.
+  #[default]
+  First,
+  RoundRobin,
+  Random,
+  /// The upstream with the lowest average latency, timeouts and errors count as slow answers.
+  Fastest,
+}
+
+impl FromStr for Strategy {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "first" => Ok(Strategy::First),
+      "round-robin" => Ok(Strategy::RoundRobin),
+      "random" => Ok(Strategy::Random),
+      "fastest" => Ok(Strategy::Fastest),
+      _ => bail!("unknown strategy `{s}`, expected first, round-robin, random or fastest"),
+    }
+  }
+}
+
+impl fmt::Display for Strategy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Strategy::First => write!(f, "first"),
+      Strategy::RoundRobin => write!(f, "round-robin"),
+      Strategy::Random => write!(f, "random"),
+      Strategy::Fastest => write!(f, "fastest"),
+    }
+  }
+}
+
+/// Options of the upstreams, shared by all the catalogs.
+#[derive(Clone, Default)]
+pub struct UpstreamOptions {
+  pub strategy: Strategy,
+  /// Send each query to two upstreams, see [`Upstreams::search`].
+  pub race: bool,
+  /// Interval between two probes of each upstream, no probes when `None` or zero.
+  pub health_check: Option<Duration>,
+  /// Verifier of the certificates of the DoT, DoH and DoQ upstreams instead of the resolver
+  /// default.
+  pub tls: Option<Arc<dyn ServerCertVerifier>>,
+  /// Proxy of the TCP connections, plain DNS upstreams are then queried over TCP.
+  pub proxy: Option<Arc<Proxy>>,
+  /// Source address and interface of the connections.
+  pub binding: Arc<Binding>,
+  /// Answer SERVFAIL instead of forwarding the queries while the bound interface is down, so they
+  /// never leave through another one.
+  pub kill_switch: bool,
+  /// EDNS Client Subnet sent with the forwarded queries.
+  pub ecs: Ecs,
+  /// Randomize the case of the query names sent over UDP, see [`crate::client::case`].
+  pub randomize_case: bool,
+  /// Time to wait for the answer of a name server, the resolver default (5s) when `None`.
+  pub timeout: Option<Duration>,
+  /// Number of times a query is sent before failing, the resolver default (2) when `None`.
+  pub attempts: Option<usize>,
+  /// Cache of the answers, nothing is cached when `None`.
+  pub cache: Option<Arc<AnswerCache>>,
+}
+
+enum Backend {
+  Forward {
+    resolver: Box<Resolver>,
+    probe: Box<Resolver>,
+  },
+  Recursive(Arc<Recursor>),
+}
+
+/// A resolver queries can be forwarded to.
+pub struct Upstream {
+  name: String,
+  backend: Backend,
+  healthy: AtomicBool,
+  /// Moving average of the answers latency in microseconds, 0 until the first answer.
+  latency_micros: AtomicU64,
+  stats: Arc<UpstreamStats>,
+}
+
+impl Upstream {
+  fn new(client: &ClientType, options: &UpstreamOptions, stats: &Stats) -> Self {
+    let mut name_servers: NameServerConfigGroup = client.clone().into();
+    if options.proxy.is_some() {
+      let (tcp, udp): (Vec<_>, Vec<_>) = name_servers
+        .into_inner()
+        .into_iter()
+        .partition(|config| config.protocol != Protocol::Udp && config.protocol != Protocol::Quic);
+      if tcp.is_empty() {
+        warn!(
+          "Upstream {} uses QUIC, its queries do not go through the proxy",
+          client
+        );
+        name_servers = udp.into();
+      } else {
+        name_servers = tcp.into();
+      }
+    }
+    if let ClientType::CustomTLS(_, domain, _, Some(_)) = client {
+      let verifier = options.tls.clone().unwrap_or_else(tls::system_verifier);
+      name_servers = name_servers.with_client_config(tls::validate_as(verifier, domain));
+    } else if let Some(verifier) = &options.tls {
+      name_servers = name_servers.with_client_config(tls::config(verifier.clone()));
+    }
+    let connector = EcsConnector::new(
+      ProxyRuntimeProvider::new(options.proxy.clone(), options.binding.clone()),
+      options.ecs,
+      options.randomize_case,
+    );
+    let config = ResolverConfig::from_parts(None, vec![], name_servers);
+    // Forwarders must return the CNAMEs of the answers (RFC 1034, Section 4.3.2).
+    // Answers are cached by the upstreams so they can be flushed.
+    let mut resolver_options = ResolverOpts::default();
+    resolver_options.preserve_intermediates = true;
+    resolver_options.cache_size = 0;
+    if let Some(timeout) = options.timeout {
+      resolver_options.timeout = timeout;
+    }
+    if let Some(attempts) = options.attempts {
+      resolver_options.attempts = attempts;
+    }
+    let resolver = AsyncResolver::new(config.clone(), resolver_options, connector.clone());
+    // Probes must reach the upstream, they are never answered from a cache.
+    let mut probe_options = ResolverOpts::default();
+    probe_options.cache_size = 0;
+    probe_options.attempts = 1;
+    probe_options.timeout = PROBE_TIMEOUT;
+    let probe = AsyncResolver::new(config, probe_options, connector);
+    let name = client.to_string();
+    Self {
+      stats: stats.upstream(&name),
+      name,
+      backend: Backend::Forward {
+        resolver: Box::new(resolver),
+        probe: Box::new(probe),
+      },
+      healthy: AtomicBool::new(true),
+      latency_micros: AtomicU64::new(0),
+    }
+  }
+
+  /// Resolve the queries from the root servers instead of forwarding them.
+  fn recursive(recursor: Arc<Recursor>, stats: &Stats) -> Self {
+    Self {
+      name: "recursive".to_string(),
+      backend: Backend::Recursive(recursor),
+      healthy: AtomicBool::new(true),
+      latency_micros: AtomicU64::new(0),
+      stats: stats.upstream("recursive"),
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn is_healthy(&self) -> bool {
+    self.healthy.load(Ordering::Relaxed)
+  }
+
+  pub fn latency(&self) -> Duration {
+    Duration::from_micros(self.latency_micros.load(Ordering::Relaxed))
+  }
+
+  /// Add an answer latency to the moving average, recent answers weigh a quarter.
+  pub fn record_latency(&self, latency: Duration) {
+    let sample = latency.as_micros().max(1) as u64;
+    let _ = self
+      .latency_micros
+      .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+        Some(if average == 0 {
+          sample
+        } else {
+          (average * 3 + sample) / 4
+        })
+      });
+  }
+
+  pub async fn lookup(
+    &self,
+    name: &LowerName,
+    query_type: RecordType,
+  ) -> Result<ForwardLookup, LookupError> {
+    match &self.backend {
+      Backend::Forward { resolver, .. } => {
+        debug!("forwarding lookup: {} {}", name, query_type);
+        resolver
+          .lookup(name.clone(), query_type)
+          .await
+          .map(ForwardLookup)
+          .map_err(LookupError::from)
+      }
+      Backend::Recursive(recursor) => {
+        debug!("recursive lookup: {} {}", name, query_type);
+        let name = Name::from(name);
+        let records = recursor
+          .resolve(&name, query_type)
+          .await
+          .map_err(|failure| LookupError::ResponseCode(failure.into()))?;
+        Ok(ForwardLookup(ResolverLookup::new_with_max_ttl(
+          Query::query(name, query_type),
+          records.into(),
+        )))
+      }
+    }
+  }
+
+  /// Forward the query, the latency of the answer is added to the average and counted with its
+  /// response code in the stats of the upstream.
+  pub async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    _lookup_options: LookupOptions,
+  ) -> Result<ForwardLookup, LookupError> {
+    let started = Instant::now();
+    let query = request_info.query;
+    let lookup = self.lookup(query.name(), query.query_type()).await;
+    self.record_answer(started.elapsed(), &lookup);
+    let lookup = lookup?;
+    events::set_upstream(&self.name);
+    Ok(lookup)
+  }
+
+  fn record_answer(&self, latency: Duration, lookup: &Result<ForwardLookup, LookupError>) {
+    let rcode = match lookup {
+      Ok(_) => ResponseCode::NoError,
+      Err(LookupError::ResponseCode(rcode)) => *rcode,
+      Err(LookupError::ResolveError(err)) => match err.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => *response_code,
+        ResolveErrorKind::Timeout => {
+          self.stats.timed_out();
+          self.record_latency(latency.max(FAILURE_LATENCY));
+          return;
+        }
+        _ => {
+          self.stats.failed();
+          self.record_latency(latency.max(FAILURE_LATENCY));
+          return;
+        }
+      },
+      Err(_) => ResponseCode::ServFail,
+    };
+    self.stats.answered(latency, rcode);
+    if matches!(rcode, ResponseCode::ServFail | ResponseCode::Refused) {
+      self.record_latency(latency.max(FAILURE_LATENCY));
+    } else {
+      self.record_latency(latency);
+    }
+  }
+
+  /// Ask the name servers of the root zone, the answer is small and always exists. The root
+  /// servers are asked directly by recursive upstreams.
+  async fn check(&self) -> bool {
+    let started = Instant::now();
+    let healthy = match &self.backend {
+      Backend::Forward { probe, .. } => probe.lookup(Name::root(), RecordType::NS).await.is_ok(),
+      Backend::Recursive(recursor) => recursor.probe().await,
+    };
+    if healthy {
+      self.record_latency(started.elapsed());
+    }
+    healthy
+  }
+}
+
+/// Upstreams of a catalog, in order of preference. Queries are distributed across the healthy
+/// upstreams according to the strategy, or across all of them when none is healthy.
+pub struct Upstreams {
+  origin: LowerName,
+  upstreams: Vec<Upstream>,
+  strategy: Strategy,
+  race: bool,
+  next: AtomicUsize,
+  random: RandomState,
+  stats: Arc<Stats>,
+  /// Cache of the answers and the names of the upstreams the answers are stored with.
+  cache: Option<(Arc<AnswerCache>, Arc<str>)>,
+  /// Interface that must be up to forward the queries, and whether it was up last time.
+  kill_switch: Option<(String, AtomicBool)>,
+}
+
+impl Upstreams {
+  /// Create the upstreams, `clients` must not be empty. When the health check is set, each
+  /// upstream is probed at this interval while the upstreams are in use.
+  pub fn new(
+    origin: &Name,
+    clients: &[ClientType],
+    options: &UpstreamOptions,
+    stats: Arc<Stats>,
+  ) -> Arc<Self> {
+    assert!(!clients.is_empty(), "at least one upstream is required");
+    let upstreams = clients
+      .iter()
+      .map(|client| Upstream::new(client, options, &stats))
+      .collect();
+    Self::from_upstreams(origin, upstreams, options, stats)
+  }
+
+  /// A single upstream resolving the queries itself with `recursor`.
+  pub fn recursive(
+    origin: &Name,
+    recursor: Arc<Recursor>,
+    options: &UpstreamOptions,
+    stats: Arc<Stats>,
+  ) -> Arc<Self> {
+    let upstream = Upstream::recursive(recursor, &stats);
+    Self::from_upstreams(origin, vec![upstream], options, stats)
+  }
+
+  fn from_upstreams(
+    origin: &Name,
+    upstreams: Vec<Upstream>,
+    options: &UpstreamOptions,
+    stats: Arc<Stats>,
+  ) -> Arc<Self> {
+    let names: Vec<&str> = upstreams.iter().map(Upstream::name).collect();
+    let cache = options
+      .cache
+      .clone()
+      .map(|cache| (cache, Arc::from(names.join(","))));
+    let upstreams = Arc::new(Self {
+      origin: origin.into(),
+      upstreams,
+      strategy: options.strategy,
+      race: options.race,
+      next: AtomicUsize::new(0),
+      random: RandomState::new(),
+      stats,
+      cache,
+      kill_switch: options
+        .binding
+        .interface()
+        .filter(|_| options.kill_switch)
+        .map(|interface| (interface.to_string(), AtomicBool::new(true))),
+    });
+    if let Some(interval) = options.health_check.filter(|interval| !interval.is_zero()) {
+      tokio::spawn(Self::check_health(Arc::downgrade(&upstreams), interval));
+    }
+    upstreams
+  }
+
+  /// Zone forwarded to these upstreams.
+  pub fn origin(&self) -> &LowerName {
+    &self.origin
+  }
+
+  fn candidates(&self) -> Vec<&Upstream> {
+    let candidates: Vec<&Upstream> = self
+      .upstreams
+      .iter()
+      .filter(|upstream| upstream.is_healthy())
+      .collect();
+    if candidates.is_empty() {
+      self.upstreams.iter().collect()
+    } else {
+      candidates
+    }
+  }
+
+  pub fn select(&self) -> &Upstream {
+    self.select_from(self.candidates())
+  }
+
+  fn select_from<'a>(&self, candidates: Vec<&'a Upstream>) -> &'a Upstream {
+    match self.strategy {
+      Strategy::First => candidates[0],
+      Strategy::RoundRobin => {
+        candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+      }
+      Strategy::Random => {
+        let draw = self
+          .random
+          .hash_one(self.next.fetch_add(1, Ordering::Relaxed));
+        candidates[draw as usize % candidates.len()]
+      }
+      // Upstreams without answers yet have a latency of 0, they are tried first.
+      Strategy::Fastest => candidates
+        .into_iter()
+        .min_by_key(|upstream| upstream.latency())
+        .unwrap(),
+    }
+  }
+
+  /// Upstreams receiving the query: the selected one, and another one when racing.
+  fn select_race(&self) -> (&Upstream, Option<&Upstream>) {
+    let first = self.select();
+    if !self.race {
+      return (first, None);
+    }
+    let others: Vec<&Upstream> = self
+      .candidates()
+      .into_iter()
+      .filter(|upstream| !std::ptr::eq(*upstream, first))
+      .collect();
+    if others.is_empty() {
+      return (first, None);
+    }
+    let second = self.select_from(others);
+    (first, Some(second))
+  }
+
+  /// Answer the query from the cache, or forward it and cache the answer.
+  pub async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<ForwardLookup, LookupError> {
+    let Some((cache, names)) = &self.cache else {
+      return self.forward(request_info, lookup_options).await;
+    };
+    if let Some(answer) = cache.lookup(names, request_info.query).await {
+      events::set_upstream(names);
+      return answer;
+    }
+    let query = request_info.query.clone();
+    let answer = self.forward(request_info, lookup_options).await;
+    cache.insert(names, &query, &answer);
+    answer
+  }
+
+  /// Forward the query to the selected upstream. When racing, it is sent to two upstreams at
+  /// the same time and the first successful answer is returned, the other query is cancelled.
+  async fn forward(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<ForwardLookup, LookupError> {
+    if let Some((interface, was_up)) = &self.kill_switch {
+      let up = bind::is_up(interface);
+      if was_up.swap(up, Ordering::Relaxed) != up {
+        if up {
+          info!("Interface {interface} is up again, the queries are forwarded");
+        } else {
+          warn!("Interface {interface} is down, the queries are not forwarded");
+        }
+      }
+      if !up {
+        events::set_upstream_failure(format!("The interface {interface} is down"));
+        return Err(LookupError::ResponseCode(ResponseCode::ServFail));
+      }
+    }
+    match self.select_race() {
+      (upstream, None) => {
+        events::set_upstream(&upstream.name);
+        upstream.search(request_info, lookup_options).await
+      }
+      (first, Some(second)) => {
+        events::set_upstream(&first.name);
+        let searches = [
+          first.search(request_info.clone(), lookup_options).boxed(),
+          second.search(request_info, lookup_options).boxed(),
+        ];
+        select_ok(searches).await.map(|(lookup, _)| lookup)
+      }
+    }
+  }
+
+  /// Probe the upstreams until they are dropped, e.g. when the catalogs are reloaded.
+  async fn check_health(upstreams: Weak<Self>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+      ticker.tick().await;
+      let Some(upstreams) = upstreams.upgrade() else {
+        return;
+      };
+      let checks = upstreams.upstreams.iter().map(|upstream| upstream.check());
+      let results = futures_util::future::join_all(checks).await;
+      for (upstream, healthy) in upstreams.upstreams.iter().zip(results) {
+        if upstream.healthy.swap(healthy, Ordering::Relaxed) == healthy {
+          continue;
+        }
+        if healthy {
+          info!("Upstream {} is healthy again", upstream.name);
+          upstreams.stats.upstream_recovered();
+        } else {
+          warn!("Upstream {} is unhealthy", upstream.name);
+          upstreams.stats.upstream_failed();
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn upstreams(strategy: Strategy) -> Arc<Upstreams> {
+    Upstreams::new(
+      &Name::root(),
+      &[
+        ClientType::CloudFlare,
+        ClientType::Google,
+        ClientType::Quad9,
+      ],
+      &UpstreamOptions {
+        strategy,
+        ..Default::default()
+      },
+      Arc::new(Stats::new()),
+    )
+  }
+
+  #[tokio::test]
+  async fn select_healthy() {
+    let upstreams = upstreams(Strategy::First);
+    assert_eq!(upstreams.select().name(), "cloudflare");
+    upstreams.upstreams[0]
+      .healthy
+      .store(false, Ordering::Relaxed);
+    assert_eq!(upstreams.select().name(), "google");
+    for upstream in upstreams.upstreams.iter() {
+      upstream.healthy.store(false, Ordering::Relaxed);
+    }
+    assert_eq!(upstreams.select().name(), "cloudflare");
+  }
+
+  #[tokio::test]
+  async fn strategies() {
+    let round_robin = upstreams(Strategy::RoundRobin);
+    round_robin.upstreams[1]
+      .healthy
+      .store(false, Ordering::Relaxed);
+    let names: Vec<&str> = (0..4).map(|_| round_robin.select().name()).collect();
+    assert_eq!(names, ["cloudflare", "quad9", "cloudflare", "quad9"]);
+
+    let fastest = upstreams(Strategy::Fastest);
+    fastest.upstreams[0].record_latency(Duration::from_millis(30));
+    fastest.upstreams[2].record_latency(Duration::from_millis(10));
+    assert_eq!(fastest.select().name(), "google");
+    fastest.upstreams[1].record_latency(Duration::from_millis(20));
+    assert_eq!(fastest.select().name(), "quad9");
+    fastest.upstreams[2].record_latency(Duration::from_millis(90));
+    assert_eq!(fastest.upstreams[2].latency(), Duration::from_millis(30));
+    assert_eq!(fastest.select().name(), "google");
+
+    let random = upstreams(Strategy::Random);
+    random.upstreams[0].healthy.store(false, Ordering::Relaxed);
+    random.upstreams[2].healthy.store(false, Ordering::Relaxed);
+    assert!((0..10).all(|_| random.select().name() == "google"));
+    assert_eq!(
+      "round-robin".parse::<Strategy>().unwrap(),
+      Strategy::RoundRobin
+    );
+    assert!("slowest".parse::<Strategy>().is_err());
+  }
+
+  #[tokio::test]
+  async fn record_failures() {
+    let stats = Arc::new(Stats::new());
+    let fastest = Upstreams::new(
+      &Name::root(),
+      &[ClientType::CloudFlare, ClientType::Google],
+      &UpstreamOptions {
+        strategy: Strategy::Fastest,
+        ..Default::default()
+      },
+      stats.clone(),
+    );
+    let servfail = Err(LookupError::ResponseCode(ResponseCode::ServFail));
+    fastest.upstreams[0].record_answer(Duration::from_millis(5), &servfail);
+    fastest.upstreams[1].record_latency(Duration::from_millis(50));
+    assert_eq!(fastest.select().name(), "google");
+    let timeout = Err(LookupError::ResolveError(ResolveErrorKind::Timeout.into()));
+    fastest.upstreams[1].record_answer(Duration::from_secs(5), &timeout);
+    fastest.upstreams[1].record_answer(Duration::from_secs(5), &timeout);
+    assert_eq!(fastest.select().name(), "cloudflare");
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.upstreams["cloudflare"].answers, 1);
+    assert_eq!(snapshot.upstreams["cloudflare"].rcodes["ServFail"], 1);
+    assert_eq!(snapshot.upstreams["google"].answers, 0);
+    assert_eq!(snapshot.upstreams["google"].timeouts, 2);
+  }
+
+  #[tokio::test]
+  async fn race_two_upstreams() {
+    let upstreams = Upstreams::new(
+      &Name::root(),
+      &[ClientType::CloudFlare, ClientType::Google],
+      &UpstreamOptions {
+        race: true,
+        ..Default::default()
+      },
+      Arc::new(Stats::new()),
+    );
+    let (first, second) = upstreams.select_race();
+    assert_eq!(first.name(), "cloudflare");
+    assert_eq!(second.map(Upstream::name), Some("google"));
+
+    upstreams.upstreams[1]
+      .healthy
+      .store(false, Ordering::Relaxed);
+    let (first, second) = upstreams.select_race();
+    assert_eq!(first.name(), "cloudflare");
+    assert!(second.is_none());
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn kill_switch() {
+    use crate::client::bind::UpstreamBind;
+    use hickory_server::proto::op::Header;
+    use hickory_server::server::Protocol as RequestProtocol;
+
+    let binding = Binding::new(&[UpstreamBind::Interface("nope0".to_string())]).unwrap();
+    let upstreams = Upstreams::new(
+      &Name::root(),
+      &[ClientType::CloudFlare],
+      &UpstreamOptions {
+        binding: Arc::new(binding),
+        kill_switch: true,
+        ..Default::default()
+      },
+      Arc::new(Stats::new()),
+    );
+    let query = Query::query(Name::from_str("example.com.").unwrap(), RecordType::A).into();
+    let header = Header::new();
+    let request_info = RequestInfo::new(
+      "127.0.0.1:53".parse().unwrap(),
+      RequestProtocol::Udp,
+      &header,
+      &query,
+    );
+    let (answer, context) = events::with_query_context(None, async {
+      upstreams
+        .search(request_info, LookupOptions::default())
+        .await
+    })
+    .await;
+    assert!(matches!(
+      answer,
+      Err(LookupError::ResponseCode(ResponseCode::ServFail))
+    ));
+    assert_eq!(
+      context.upstream_failure.as_deref(),
+      Some("The interface nope0 is down")
+    );
+  }
+}
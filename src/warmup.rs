@@ -0,0 +1,119 @@
+use crate::cache::AnswerCache;
+use crate::domain_set::DomainSet;
+use crate::query::{self, Transport};
+use anyhow::Result;
+use futures_util::{future, stream, StreamExt};
+use hickory_server::proto::rr::{Name, RecordType};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Queries sent at the same time, so a long list does not flood the upstreams.
+const CONCURRENCY: usize = 16;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const RECORD_TYPES: [RecordType; 2] = [RecordType::A, RecordType::AAAA];
+
+/// Read the names of a warm-up list, one domain per line like the blacklists.
+pub fn load(path: &Path) -> Result<Vec<Name>> {
+  Ok(DomainSet::load(path)?.iter().map(Name::from).collect())
+}
+
+/// Resolve `names` through the server listening on `server` now and after each flush of `cache`,
+/// so their answers are cached before the clients ask for them.
+pub fn spawn(server: SocketAddr, names: Vec<Name>, cache: Arc<AnswerCache>) {
+  tokio::spawn(async move {
+    loop {
+      let started = Instant::now();
+      let answered = warm(server, &names, TIMEOUT).await;
+      info!(
+        "Warm-up of {} names done in {:?}, {} of {} queries answered",
+        names.len(),
+        started.elapsed(),
+        answered,
+        names.len() * RECORD_TYPES.len()
+      );
+      cache.flushed().await;
+    }
+  });
+}
+
+/// Send the A and AAAA queries of `names` to `server`, returns the number of answered queries.
+pub async fn warm(server: SocketAddr, names: &[Name], timeout: Duration) -> usize {
+  let queries: Vec<(Name, RecordType)> = names
+    .iter()
+    .flat_map(|name| RECORD_TYPES.map(|record_type| (name.clone(), record_type)))
+    .collect();
+  stream::iter(queries)
+    .map(|(name, record_type)| resolve(server, name, record_type, timeout))
+    .buffer_unordered(CONCURRENCY)
+    .fold(0, |answered, ok| future::ready(answered + usize::from(ok)))
+    .await
+}
+
+async fn resolve(
+  server: SocketAddr,
+  name: Name,
+  record_type: RecordType,
+  timeout: Duration,
+) -> bool {
+  let message = query::message(&name, record_type);
+  match query::send(&message, server, Transport::Udp, None, timeout).await {
+    Ok(_) => true,
+    Err(err) => {
+      debug!("Warm-up of {} {} failed: {:#}", name, record_type, err);
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::proto::op::Message;
+  use std::str::FromStr;
+  use tokio::net::UdpSocket;
+
+  #[tokio::test]
+  async fn warm_names() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = socket.local_addr().unwrap();
+    // Answers the queries of the first name only.
+    let answering = tokio::spawn(async move {
+      let mut types = vec![];
+      let mut buffer = [0; 512];
+      for _ in 0..4 {
+        let (length, client) = socket.recv_from(&mut buffer).await.unwrap();
+        let mut message = Message::from_vec(&buffer[..length]).unwrap();
+        let query = message.queries()[0].clone();
+        types.push(query.query_type());
+        if query.name().to_string() == "intranet.example." {
+          message.set_message_type(hickory_server::proto::op::MessageType::Response);
+          socket
+            .send_to(&message.to_vec().unwrap(), client)
+            .await
+            .unwrap();
+        }
+      }
+      types
+    });
+    let names = [
+      Name::from_str("intranet.example.").unwrap(),
+      Name::from_str("down.example.").unwrap(),
+    ];
+    let timeout = Duration::from_millis(200);
+    assert_eq!(warm(server, &names, timeout).await, 2);
+    let mut types = answering.await.unwrap();
+    types.sort();
+    assert_eq!(
+      types,
+      [
+        RecordType::A,
+        RecordType::A,
+        RecordType::AAAA,
+        RecordType::AAAA
+      ]
+    );
+  }
+}
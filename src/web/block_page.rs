@@ -0,0 +1,211 @@
+use super::read_head;
+use crate::events::{QueryAction, QueryEvent, QueryObserver};
+use hickory_rustls::ServerConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error};
+
+const TEMPLATE: &str = include_str!("blocked.html");
+/// Number of blocked domains whose list is remembered.
+const CAPACITY: usize = 1024;
+pub const HTTP_PORT: u16 = 80;
+pub const HTTPS_PORT: u16 = 443;
+
+/// Page served on the default IP in place of the blocked websites, so browsers tell why instead
+/// of failing to connect. `{domain}` and `{list}` are replaced in the template by the domain of
+/// the request and the list that blocked it.
+pub struct BlockPage {
+  template: String,
+  blocked: Mutex<Blocked>,
+}
+
+/// Lists of the last blocked domains.
+#[derive(Default)]
+struct Blocked {
+  lists: HashMap<String, Option<String>>,
+  order: VecDeque<String>,
+}
+
+impl Default for BlockPage {
+  fn default() -> Self {
+    Self::new(TEMPLATE.to_string())
+  }
+}
+
+impl BlockPage {
+  pub fn new(template: String) -> Self {
+    Self {
+      template,
+      blocked: Mutex::default(),
+    }
+  }
+
+  /// Page of the request for `host`, its `Host` header.
+  pub fn render(&self, host: &str) -> String {
+    let domain = domain(host);
+    let list = self
+      .blocked
+      .lock()
+      .unwrap()
+      .lists
+      .get(&domain)
+      .cloned()
+      .flatten();
+    self
+      .template
+      .replace("{domain}", &escape(&domain))
+      .replace("{list}", &escape(list.as_deref().unwrap_or("unknown")))
+  }
+
+  /// Answer the HTTP requests of `listener`, or the HTTPS ones with a TLS configuration.
+  pub fn serve(self: Arc<Self>, listener: TcpListener, tls: Option<Arc<ServerConfig>>) {
+    let acceptor = tls.map(TlsAcceptor::from);
+    tokio::spawn(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, _)) => {
+            let page = self.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+              if let Err(err) = page.handle_connection(stream, acceptor).await {
+                debug!("Block page connection closed: {}", err);
+              }
+            });
+          }
+          Err(err) => error!("Could not accept block page connection: {}", err),
+        }
+      }
+    });
+  }
+
+  async fn handle_connection(
+    &self,
+    stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+  ) -> std::io::Result<()> {
+    match acceptor {
+      Some(acceptor) => {
+        let stream = tokio::time::timeout(super::REQUEST_TIMEOUT, acceptor.accept(stream))
+          .await
+          .map_err(std::io::Error::other)??;
+        self.answer(stream).await
+      }
+      None => self.answer(stream).await,
+    }
+  }
+
+  async fn answer<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S) -> std::io::Result<()> {
+    let head = read_head(&mut stream).await?;
+    let host = head
+      .lines()
+      .skip(1)
+      .find_map(|line| {
+        line
+          .split_once(':')
+          .filter(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+          .map(|(_, value)| value.trim())
+      })
+      .unwrap_or_default();
+    let body = self.render(host);
+    let message = format!(
+      "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+      body.len(),
+      body
+    );
+    stream.write_all(message.as_bytes()).await?;
+    stream.shutdown().await
+  }
+}
+
+impl QueryObserver for BlockPage {
+  fn on_query(&self, event: &QueryEvent) {
+    if event.action != QueryAction::Blocked {
+      return;
+    }
+    let domain = domain(&event.qname);
+    let mut blocked = self.blocked.lock().unwrap();
+    if blocked
+      .lists
+      .insert(domain.clone(), event.blocked_by.clone())
+      .is_none()
+    {
+      blocked.order.push_back(domain);
+      if blocked.order.len() > CAPACITY {
+        if let Some(oldest) = blocked.order.pop_front() {
+          blocked.lists.remove(&oldest);
+        }
+      }
+    }
+  }
+}
+
+/// Lowercase domain of a query name or of a `Host` header, without its port and final dot.
+fn domain(host: &str) -> String {
+  let host = match host.rsplit_once(':') {
+    Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+    _ => host,
+  };
+  host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use hickory_server::{
+    proto::{op::ResponseCode, rr::RecordType},
+    server::Protocol,
+  };
+  use std::time::Duration;
+
+  fn blocked(qname: &str, list: Option<&str>) -> QueryEvent {
+    QueryEvent {
+      timestamp: chrono::Utc::now(),
+      client: "10.0.0.1:5353".parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: qname.to_string(),
+      qtype: RecordType::A,
+      rcode: ResponseCode::NoError,
+      latency: Duration::from_millis(1),
+      action: QueryAction::Blocked,
+      upstream: None,
+      cached: false,
+      blocked_by: list.map(str::to_string),
+      group: None,
+      query_message: None,
+      response_message: None,
+    }
+  }
+
+  #[test]
+  fn render() {
+    let page = BlockPage::new("{domain} by {list}".to_string());
+    page.on_query(&blocked("ads.example.com.", Some("/etc/ads.txt")));
+    assert_eq!(
+      page.render("ads.example.com"),
+      "ads.example.com by /etc/ads.txt"
+    );
+    assert_eq!(
+      page.render("ADS.example.com:8080"),
+      "ads.example.com by /etc/ads.txt"
+    );
+    assert_eq!(
+      page.render("other.example.com"),
+      "other.example.com by unknown"
+    );
+    assert_eq!(page.render("<b>"), "&lt;b&gt; by unknown");
+    assert!(BlockPage::default()
+      .render("ads.example.com")
+      .contains("<code>ads.example.com</code>"));
+  }
+}
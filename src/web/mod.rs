@@ -0,0 +1,400 @@
+use crate::control::{parse_duration, Control};
+use crate::events::{QueryDb, QueryHistory, Window};
+use crate::healthcheck;
+use hickory_server::proto::rr::{LowerName, Name};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error};
+
+pub mod block_page;
+mod pihole;
+
+pub use block_page::BlockPage;
+
+const INDEX: &str = include_str!("index.html");
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const TOP_SIZE: usize = 10;
+/// Header required on POST requests. Browsers only send it cross-origin after a CORS preflight,
+/// which is never accepted, so other websites cannot toggle blocking.
+const ACTION_HEADER: &str = "x-dns-server-action";
+
+/// Small dashboard showing the last queries, the top blocked domains and clients, with buttons to
+/// disable and enable blocking. It has no authentication and should only listen on a trusted
+/// network.
+pub struct WebUi {
+  control: Arc<Control>,
+  history: Arc<QueryHistory>,
+  query_db: Option<Arc<QueryDb>>,
+  /// UDP listener looked up by `/healthz`.
+  dns_addr: Option<SocketAddr>,
+}
+
+struct Response {
+  status: &'static str,
+  content_type: &'static str,
+  body: String,
+}
+
+/// Number of answers removed from the cache.
+#[derive(Serialize)]
+struct Flushed {
+  flushed: usize,
+}
+
+impl WebUi {
+  pub fn new(
+    control: Arc<Control>,
+    history: Arc<QueryHistory>,
+    query_db: Option<Arc<QueryDb>>,
+  ) -> Self {
+    Self {
+      control,
+      history,
+      query_db,
+      dns_addr: None,
+    }
+  }
+
+  /// Check the DNS listener at `addr` on `/healthz`, only the web UI is checked otherwise.
+  pub fn health_check(mut self, addr: SocketAddr) -> Self {
+    self.dns_addr = Some(healthcheck::local_addr(addr));
+    self
+  }
+
+  pub fn serve(self, listener: TcpListener) {
+    let web_ui = Arc::new(self);
+    tokio::spawn(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, _)) => {
+            let web_ui = web_ui.clone();
+            tokio::spawn(async move {
+              if let Err(err) = web_ui.handle_connection(stream).await {
+                debug!("Web UI connection closed: {}", err);
+              }
+            });
+          }
+          Err(err) => error!("Could not accept web UI connection: {}", err),
+        }
+      }
+    });
+  }
+
+  async fn handle_connection(&self, mut stream: TcpStream) -> std::io::Result<()> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let action_header = lines.any(|line| {
+      line
+        .split_once(':')
+        .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case(ACTION_HEADER))
+    });
+    let response = if matches!(method, "POST" | "DELETE") && !action_header {
+      Response {
+        status: "403 Forbidden",
+        content_type: "text/plain; charset=utf-8",
+        body: format!("missing {ACTION_HEADER} header"),
+      }
+    } else {
+      self.route(method, target).await
+    };
+    if matches!(method, "POST" | "DELETE") && response.status == "200 OK" {
+      let source = match stream.peer_addr() {
+        Ok(peer) => format!("web UI, {}", peer.ip()),
+        Err(_) => "web UI".to_string(),
+      };
+      self.control.audit(&source, &format!("{method} {target}"));
+    }
+
+    let message = format!(
+      "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+      response.status,
+      response.content_type,
+      response.body.len(),
+      response.body
+    );
+    stream.write_all(message.as_bytes()).await?;
+    stream.shutdown().await
+  }
+
+  async fn route(&self, method: &str, target: &str) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match (method, path) {
+      ("GET", "/") => Response {
+        status: "200 OK",
+        content_type: "text/html; charset=utf-8",
+        body: INDEX.to_string(),
+      },
+      ("GET", "/healthz") => self.healthz().await,
+      ("GET", "/api/stats") => json(&self.control.snapshot()),
+      ("GET", "/api/queries") => json(&self.history.recent()),
+      ("GET", "/api/top") => match parameter(query, "window").map(Window::from_str).transpose() {
+        Ok(window) => json(&self.history.top(window, TOP_SIZE)),
+        Err(err) => bad_request(err),
+      },
+      ("GET", "/admin/api.php") => pihole::api(&self.control, &self.history, query),
+      ("GET", "/api/history") => {
+        let Some(query_db) = &self.query_db else {
+          return Response {
+            status: "404 Not Found",
+            content_type: "text/plain; charset=utf-8",
+            body: "the query database is not enabled".to_string(),
+          };
+        };
+        let since = parameter(query, "since").unwrap_or("24h");
+        match parse_duration(since) {
+          Ok(since) => match query_db.summary(since).await {
+            Ok(summary) => json(&summary),
+            Err(err) => Response {
+              status: "500 Internal Server Error",
+              content_type: "text/plain; charset=utf-8",
+              body: format!("{err:#}"),
+            },
+          },
+          Err(err) => bad_request(err),
+        }
+      }
+      ("POST", "/api/enable") => {
+        self.control.enable();
+        json(&self.control.snapshot())
+      }
+      ("POST", "/api/disable") => {
+        let duration = parameter(query, "duration").unwrap_or("5m");
+        match parse_duration(duration) {
+          Ok(duration) => {
+            self.control.disable(duration);
+            json(&self.control.snapshot())
+          }
+          Err(err) => bad_request(err),
+        }
+      }
+      ("POST", "/api/categories/enable" | "/api/categories/disable") => {
+        let enabled = path.ends_with("/enable");
+        let name = parameter(query, "name").unwrap_or_default();
+        match self.control.set_category_enabled(name, enabled) {
+          Ok(()) => json(&self.control.snapshot()),
+          Err(err) => bad_request(err),
+        }
+      }
+      ("POST", "/api/cache/flush") => json(&Flushed {
+        flushed: self.control.cache().flush(),
+      }),
+      ("DELETE", name) if name.starts_with("/api/cache/") => {
+        let name = name.trim_start_matches("/api/cache/").trim_end_matches('.');
+        match LowerName::from_str(&format!("{name}.")) {
+          Ok(name) if !name.is_root() => json(&Flushed {
+            flushed: self.control.cache().flush_name(&name),
+          }),
+          _ => bad_request(anyhow::anyhow!("invalid domain `{name}`")),
+        }
+      }
+      (
+        _,
+        "/"
+        | "/healthz"
+        | "/api/stats"
+        | "/api/queries"
+        | "/api/top"
+        | "/api/history"
+        | "/admin/api.php"
+        | "/api/enable"
+        | "/api/disable"
+        | "/api/categories/enable"
+        | "/api/categories/disable"
+        | "/api/cache/flush",
+      ) => Response {
+        status: "405 Method Not Allowed",
+        content_type: "text/plain; charset=utf-8",
+        body: "method not allowed".to_string(),
+      },
+      _ => Response {
+        status: "404 Not Found",
+        content_type: "text/plain; charset=utf-8",
+        body: "not found".to_string(),
+      },
+    }
+  }
+
+  /// `200 OK` when the server answers a real lookup, `503 Service Unavailable` otherwise.
+  async fn healthz(&self) -> Response {
+    let check = match self.dns_addr {
+      Some(addr) => {
+        let name = Name::root();
+        healthcheck::check(addr, &name, healthcheck::DEFAULT_TIMEOUT).await
+      }
+      None => Ok(()),
+    };
+    match check {
+      Ok(()) => Response {
+        status: "200 OK",
+        content_type: "text/plain; charset=utf-8",
+        body: "ok".to_string(),
+      },
+      Err(err) => Response {
+        status: "503 Service Unavailable",
+        content_type: "text/plain; charset=utf-8",
+        body: format!("{err:#}"),
+      },
+    }
+  }
+}
+
+/// Read the request line and the headers of an HTTP request.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<String> {
+  let mut buffer = Vec::with_capacity(1024);
+  tokio::time::timeout(REQUEST_TIMEOUT, async {
+    loop {
+      let mut chunk = [0; 1024];
+      let read = stream.read(&mut chunk).await?;
+      buffer.extend_from_slice(&chunk[..read]);
+      if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        return Ok(String::from_utf8_lossy(&buffer[..end]).to_string());
+      }
+      if read == 0 || buffer.len() > MAX_REQUEST_SIZE {
+        return Err(std::io::Error::other("incomplete request"));
+      }
+    }
+  })
+  .await
+  .map_err(std::io::Error::other)?
+}
+
+fn parameter<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+  query.split('&').find_map(|param| {
+    param
+      .split_once('=')
+      .filter(|(key, _)| *key == name)
+      .map(|(_, value)| value)
+  })
+}
+
+fn bad_request(err: anyhow::Error) -> Response {
+  Response {
+    status: "400 Bad Request",
+    content_type: "text/plain; charset=utf-8",
+    body: format!("{err:#}"),
+  }
+}
+
+fn json<T: Serialize>(value: &T) -> Response {
+  match serde_json::to_string(value) {
+    Ok(body) => Response {
+      status: "200 OK",
+      content_type: "application/json",
+      body,
+    },
+    Err(err) => Response {
+      status: "500 Internal Server Error",
+      content_type: "text/plain; charset=utf-8",
+      body: err.to_string(),
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn web_ui() -> WebUi {
+    WebUi::new(
+      Arc::new(Control::new()),
+      Arc::new(QueryHistory::new(10)),
+      None,
+    )
+  }
+
+  #[tokio::test]
+  async fn routes() {
+    let web_ui = web_ui();
+    assert_eq!(web_ui.route("GET", "/").await.status, "200 OK");
+    assert_eq!(web_ui.route("GET", "/api/queries").await.body, "[]");
+    assert_eq!(
+      web_ui.route("GET", "/api/top").await.body,
+      r#"{"domains":[],"blocked_domains":[],"clients":[]}"#
+    );
+    assert_eq!(
+      web_ui.route("GET", "/api/top?window=hour").await.status,
+      "200 OK"
+    );
+    assert_eq!(
+      web_ui.route("GET", "/api/top?window=week").await.status,
+      "400 Bad Request"
+    );
+    assert_eq!(
+      web_ui.route("GET", "/api/disable").await.status,
+      "405 Method Not Allowed"
+    );
+    assert_eq!(web_ui.route("GET", "/healthz").await.body, "ok");
+    assert_eq!(web_ui.route("GET", "/nope").await.status, "404 Not Found");
+    assert_eq!(
+      web_ui.route("GET", "/api/history").await.status,
+      "404 Not Found"
+    );
+  }
+
+  #[tokio::test]
+  async fn toggle_blocking() {
+    let web_ui = web_ui();
+    web_ui.route("POST", "/api/disable?duration=10m").await;
+    assert!(!web_ui.control.is_blocking());
+    assert_eq!(
+      web_ui
+        .route("POST", "/api/disable?duration=never")
+        .await
+        .status,
+      "400 Bad Request"
+    );
+    web_ui.route("POST", "/api/enable").await;
+    assert!(web_ui.control.is_blocking());
+  }
+
+  #[tokio::test]
+  async fn toggle_category() {
+    let web_ui = web_ui();
+    web_ui.control.set_categories(["ads"]);
+    web_ui
+      .route("POST", "/api/categories/disable?name=ads")
+      .await;
+    assert!(!web_ui.control.is_category_enabled("ads"));
+    assert_eq!(
+      web_ui
+        .route("POST", "/api/categories/enable?name=social")
+        .await
+        .status,
+      "400 Bad Request"
+    );
+    web_ui
+      .route("POST", "/api/categories/enable?name=ads")
+      .await;
+    assert!(web_ui.control.is_category_enabled("ads"));
+  }
+
+  #[tokio::test]
+  async fn flush_cache() {
+    let web_ui = web_ui();
+    assert_eq!(
+      web_ui.route("POST", "/api/cache/flush").await.body,
+      r#"{"flushed":0}"#
+    );
+    assert_eq!(
+      web_ui.route("DELETE", "/api/cache/example.com").await.body,
+      r#"{"flushed":0}"#
+    );
+    assert_eq!(
+      web_ui.route("DELETE", "/api/cache/").await.status,
+      "400 Bad Request"
+    );
+    assert_eq!(
+      web_ui.route("GET", "/api/cache/flush").await.status,
+      "405 Method Not Allowed"
+    );
+  }
+}
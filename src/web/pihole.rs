@@ -0,0 +1,178 @@
+use super::{json, Response};
+use crate::control::Control;
+use crate::events::{Counter, QueryAction, QueryHistory};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::fmt::Display;
+
+/// Number of items of `topItems` and `topClients` when it is not given.
+const TOP_SIZE: usize = 10;
+
+/// `summaryRaw` of Pi-hole, the counters are the ones since the server started.
+#[derive(Serialize)]
+struct Summary {
+  domains_being_blocked: usize,
+  dns_queries_today: u64,
+  ads_blocked_today: u64,
+  ads_percentage_today: f64,
+  unique_domains: usize,
+  queries_forwarded: u64,
+  queries_cached: u64,
+  clients_ever_seen: usize,
+  unique_clients: usize,
+  dns_queries_all_types: u64,
+  privacy_level: u8,
+  status: &'static str,
+}
+
+#[derive(Serialize)]
+struct Status {
+  status: &'static str,
+}
+
+#[derive(Serialize)]
+struct TopItems<T: Display> {
+  top_queries: Counts<T>,
+  top_ads: Counts<T>,
+}
+
+#[derive(Serialize)]
+struct TopClients<T: Display> {
+  top_sources: Counts<T>,
+}
+
+/// Counters serialized as a `{"name": count}` object, in their order.
+struct Counts<T>(Vec<Counter<T>>);
+
+impl<T: Display> Serialize for Counts<T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(self.0.len()))?;
+    for counter in self.0.iter() {
+      map.serialize_entry(
+        &counter.name.to_string().trim_end_matches('.'),
+        &counter.count,
+      )?;
+    }
+    map.end()
+  }
+}
+
+/// Read only subset of the Pi-hole v5 API (`/admin/api.php`), so the dashboards, Home Assistant
+/// and mobile apps made for Pi-hole can monitor this server. The `auth` parameter is ignored.
+pub(super) fn api(control: &Control, history: &QueryHistory, query: &str) -> Response {
+  for param in query.split('&') {
+    let (key, value) = param.split_once('=').unwrap_or((param, ""));
+    let limit = || value.parse().unwrap_or(TOP_SIZE);
+    match key {
+      "summaryRaw" => return json(&summary(control, history)),
+      "status" => {
+        return json(&Status {
+          status: status(control),
+        })
+      }
+      "topItems" => {
+        return json(&TopItems {
+          top_queries: Counts(history.top_domains(limit())),
+          top_ads: Counts(history.top_blocked_domains(limit())),
+        })
+      }
+      "topClients" => {
+        return json(&TopClients {
+          top_sources: Counts(history.top_clients(limit())),
+        })
+      }
+      "recentBlocked" => {
+        let domain = history
+          .recent()
+          .into_iter()
+          .find(|event| event.action == QueryAction::Blocked)
+          .map(|event| event.qname.trim_end_matches('.').to_string())
+          .unwrap_or_default();
+        return Response {
+          status: "200 OK",
+          content_type: "text/plain; charset=utf-8",
+          body: domain,
+        };
+      }
+      _ => {}
+    }
+  }
+  // Like Pi-hole for unknown or missing parameters.
+  json(&[(); 0])
+}
+
+fn status(control: &Control) -> &'static str {
+  match control.is_blocking() {
+    true => "enabled",
+    false => "disabled",
+  }
+}
+
+fn summary(control: &Control, history: &QueryHistory) -> Summary {
+  let stats = control.snapshot();
+  let (unique_domains, unique_clients) = history.unique();
+  let queries = stats.queries;
+  Summary {
+    domains_being_blocked: stats.listed_domains + stats.blocked_domains,
+    dns_queries_today: queries.queries,
+    ads_blocked_today: queries.blocked,
+    ads_percentage_today: match queries.queries {
+      0 => 0.0,
+      total => queries.blocked as f64 * 100.0 / total as f64,
+    },
+    unique_domains,
+    queries_forwarded: queries.forwarded - queries.cached,
+    queries_cached: queries.cached,
+    clients_ever_seen: unique_clients,
+    unique_clients,
+    dns_queries_all_types: queries.queries,
+    privacy_level: 0,
+    status: status(control),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::events::{QueryEvent, QueryObserver};
+  use chrono::Utc;
+  use hickory_server::{
+    proto::{op::ResponseCode, rr::RecordType},
+    server::Protocol,
+  };
+  use std::time::Duration;
+
+  #[test]
+  fn pihole_api() {
+    let control = Control::new();
+    let history = QueryHistory::new(10);
+    history.on_query(&QueryEvent {
+      timestamp: Utc::now(),
+      client: "10.0.0.1:5353".parse().unwrap(),
+      protocol: Protocol::Udp,
+      qname: "ads.example.".to_string(),
+      qtype: RecordType::A,
+      rcode: ResponseCode::NoError,
+      latency: Duration::from_millis(1),
+      action: QueryAction::Blocked,
+      upstream: None,
+      cached: false,
+      blocked_by: None,
+      group: None,
+      query_message: None,
+      response_message: None,
+    });
+    assert_eq!(
+      api(&control, &history, "topItems=5&auth=token").body,
+      r#"{"top_queries":{},"top_ads":{"ads.example":1}}"#
+    );
+    assert_eq!(api(&control, &history, "recentBlocked").body, "ads.example");
+    assert_eq!(
+      api(&control, &history, "status").body,
+      r#"{"status":"enabled"}"#
+    );
+    assert!(api(&control, &history, "summaryRaw")
+      .body
+      .contains(r#""unique_clients":1"#));
+    assert_eq!(api(&control, &history, "").body, "[]");
+  }
+}